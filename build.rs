@@ -90,6 +90,12 @@ fn main() {
     }
     cc_video.compile("video");
 
+    println!("cargo:rerun-if-changed=lib/encode_audio.c");
+    cc::Build::new()
+        .file("lib/encode_audio.c")
+        .include(dist_dir.join("include"))
+        .compile("audio");
+
     println!("cargo:rerun-if-changed=lib/error.h");
     println!("cargo:rerun-if-changed=lib/error.c");
     println!("cargo:rerun-if-changed=lib/log.h");
@@ -146,18 +152,21 @@ fn linux() {
     println!("cargo:rerun-if-changed=lib/linux/uniput.c");
     println!("cargo:rerun-if-changed=lib/linux/xcapture.c");
     println!("cargo:rerun-if-changed=lib/linux/xhelper.c");
+    println!("cargo:rerun-if-changed=lib/linux/hotkey.c");
     println!("cargo:rerun-if-changed=lib/linux/xhelper.h");
 
     cc::Build::new()
         .file("lib/linux/uinput.c")
         .file("lib/linux/xcapture.c")
         .file("lib/linux/xhelper.c")
+        .file("lib/linux/hotkey.c")
         .compile("linux");
 
     println!("cargo:rustc-link-lib=X11");
     println!("cargo:rustc-link-lib=Xext");
     println!("cargo:rustc-link-lib=Xrandr");
     println!("cargo:rustc-link-lib=Xfixes");
+    println!("cargo:rustc-link-lib=Xdamage");
     println!("cargo:rustc-link-lib=Xcomposite");
     println!("cargo:rustc-link-lib=Xi");
     let va_link_kind = if env::var("CARGO_FEATURE_VA_STATIC").is_ok() {
@@ -0,0 +1,210 @@
+#include <stdint.h>
+#include <stdlib.h>
+
+#include <libavcodec/avcodec.h>
+
+#include <libavutil/audio_fifo.h>
+#include <libavutil/channel_layout.h>
+#include <libavutil/error.h>
+#include <libavutil/frame.h>
+#include <libavutil/mem.h>
+#include <libavutil/samplefmt.h>
+
+#include <libswresample/swresample.h>
+
+#include "error.h"
+#include "log.h"
+
+// Opus always runs at 48 kHz, whatever the rate of the input is
+#define SAMPLE_RATE 48000
+#define CHANNELS 2
+
+// input that is off by more than this from where the samples before it end, because capturing
+// paused or the clocks drifted apart, moves the timestamps instead of being played back late
+#define MAX_DRIFT_MICROS 40000
+
+typedef struct AudioContext
+{
+	AVCodecContext* c;
+	SwrContext* swr;
+	AVAudioFifo* fifo;
+	AVFrame* frame;
+	AVPacket* pkt;
+	void* rust_ctx;
+	// samples converted to the format of the encoder before they go into fifo
+	uint8_t** converted;
+	int converted_capacity;
+	// samples passed to the encoder so far, the pts of the next frame
+	int64_t next_pts;
+	// capture time of the first sample in fifo
+	int64_t fifo_micros;
+	// pts and capture time of the last frame passed to the encoder, packets are timestamped
+	// relative to it as the encoder delays them a little
+	int64_t anchor_pts;
+	int64_t anchor_micros;
+} AudioContext;
+
+// this is a rust function and lives in src/audio/mod.rs
+void write_audio_packet(void* rust_ctx, const uint8_t* buf, int buf_size, int64_t micros);
+
+void destroy_audio_encoder(AudioContext* ctx)
+{
+	avcodec_free_context(&ctx->c);
+	swr_free(&ctx->swr);
+	if (ctx->fifo)
+		av_audio_fifo_free(ctx->fifo);
+	av_frame_free(&ctx->frame);
+	av_packet_free(&ctx->pkt);
+	if (ctx->converted)
+		av_freep(&ctx->converted[0]);
+	av_freep(&ctx->converted);
+	free(ctx);
+}
+
+void open_audio(AudioContext* ctx, int sample_rate_in, int channels_in, int bitrate_kbps, Error* err)
+{
+	int ret;
+	const AVCodec* codec = avcodec_find_encoder(AV_CODEC_ID_OPUS);
+	if (!codec)
+		ERROR(err, 1, "Opus encoder not found");
+
+	ctx->c = avcodec_alloc_context3(codec);
+	if (!ctx->c)
+		ERROR(err, 1, "Could not allocate audio codec context");
+	ctx->c->sample_rate = SAMPLE_RATE;
+	ctx->c->sample_fmt = codec->sample_fmts ? codec->sample_fmts[0] : AV_SAMPLE_FMT_FLTP;
+	av_channel_layout_default(&ctx->c->ch_layout, CHANNELS);
+	ctx->c->bit_rate = bitrate_kbps * 1000;
+	ctx->c->time_base = (AVRational){1, SAMPLE_RATE};
+	// the encoder built into ffmpeg is marked experimental
+	ctx->c->strict_std_compliance = FF_COMPLIANCE_EXPERIMENTAL;
+	ret = avcodec_open2(ctx->c, codec, NULL);
+	if (ret < 0)
+		ERROR(err, 1, "Could not open %s: %s", codec->name, av_err2str(ret));
+	if (ctx->c->frame_size <= 0)
+		ERROR(err, 1, "%s does not have a fixed frame size", codec->name);
+
+	AVChannelLayout layout_in;
+	av_channel_layout_default(&layout_in, channels_in);
+	ret = swr_alloc_set_opts2(
+		&ctx->swr,
+		&ctx->c->ch_layout,
+		ctx->c->sample_fmt,
+		SAMPLE_RATE,
+		&layout_in,
+		AV_SAMPLE_FMT_FLT,
+		sample_rate_in,
+		0,
+		NULL);
+	av_channel_layout_uninit(&layout_in);
+	if (ret < 0)
+		ERROR(err, 1, "Could not allocate resampler: %s", av_err2str(ret));
+	ret = swr_init(ctx->swr);
+	if (ret < 0)
+		ERROR(err, 1, "Could not initialize resampler: %s", av_err2str(ret));
+
+	ctx->fifo = av_audio_fifo_alloc(ctx->c->sample_fmt, CHANNELS, ctx->c->frame_size * 4);
+	if (!ctx->fifo)
+		ERROR(err, 1, "Could not allocate audio fifo");
+
+	ctx->frame = av_frame_alloc();
+	if (!ctx->frame)
+		ERROR(err, 1, "Could not allocate audio frame");
+	ctx->frame->nb_samples = ctx->c->frame_size;
+	ctx->frame->format = ctx->c->sample_fmt;
+	ctx->frame->sample_rate = SAMPLE_RATE;
+	ret = av_channel_layout_copy(&ctx->frame->ch_layout, &ctx->c->ch_layout);
+	if (ret < 0)
+		ERROR(err, 1, "Could not set channel layout: %s", av_err2str(ret));
+	ret = av_frame_get_buffer(ctx->frame, 0);
+	if (ret < 0)
+		ERROR(err, 1, "Could not allocate audio frame data: %s", av_err2str(ret));
+
+	ctx->pkt = av_packet_alloc();
+	if (!ctx->pkt)
+		ERROR(err, 1, "Could not allocate packet");
+	log_debug("Encoding audio with %s.", codec->name);
+}
+
+AudioContext* init_audio_encoder(
+	void* rust_ctx, int sample_rate_in, int channels_in, int bitrate_kbps, Error* err)
+{
+	AudioContext* ctx = calloc(1, sizeof(AudioContext));
+	ctx->rust_ctx = rust_ctx;
+	open_audio(ctx, sample_rate_in, channels_in, bitrate_kbps, err);
+	if (err->code)
+	{
+		destroy_audio_encoder(ctx);
+		return NULL;
+	}
+	return ctx;
+}
+
+void encode_frames(AudioContext* ctx, Error* err)
+{
+	int ret;
+	while (av_audio_fifo_size(ctx->fifo) >= ctx->c->frame_size)
+	{
+		ret = av_frame_make_writable(ctx->frame);
+		if (ret < 0)
+			ERROR(err, 1, "Audio frame is not writable: %s", av_err2str(ret));
+		av_audio_fifo_read(ctx->fifo, (void**)ctx->frame->data, ctx->c->frame_size);
+		ctx->frame->pts = ctx->next_pts;
+		ctx->anchor_pts = ctx->next_pts;
+		ctx->anchor_micros = ctx->fifo_micros;
+		ctx->next_pts += ctx->c->frame_size;
+		ctx->fifo_micros += (int64_t)ctx->c->frame_size * 1000000 / SAMPLE_RATE;
+
+		ret = avcodec_send_frame(ctx->c, ctx->frame);
+		if (ret < 0)
+			ERROR(err, 1, "Error sending audio frame for encoding: %s", av_err2str(ret));
+		while (1)
+		{
+			ret = avcodec_receive_packet(ctx->c, ctx->pkt);
+			if (ret == AVERROR(EAGAIN) || ret == AVERROR_EOF)
+				break;
+			else if (ret < 0)
+				ERROR(err, 1, "Error during audio encoding: %s", av_err2str(ret));
+			int64_t micros =
+				ctx->anchor_micros + (ctx->pkt->pts - ctx->anchor_pts) * 1000000 / SAMPLE_RATE;
+			write_audio_packet(ctx->rust_ctx, ctx->pkt->data, ctx->pkt->size, micros);
+			av_packet_unref(ctx->pkt);
+		}
+	}
+}
+
+// samples are interleaved floats, micros is the time the first one has been captured at
+void encode_audio(AudioContext* ctx, const float* samples, int num_samples, int64_t micros, Error* err)
+{
+	int ret;
+	// samples that are not encoded yet, resampling holds back a few as well
+	int64_t pending = av_audio_fifo_size(ctx->fifo) + swr_get_delay(ctx->swr, SAMPLE_RATE);
+	int64_t expected = ctx->fifo_micros + pending * 1000000 / SAMPLE_RATE;
+	if (pending == 0 || llabs(expected - micros) > MAX_DRIFT_MICROS)
+		ctx->fifo_micros = micros - pending * 1000000 / SAMPLE_RATE;
+
+	int out_samples = swr_get_out_samples(ctx->swr, num_samples);
+	if (out_samples < 0)
+		ERROR(err, 1, "Could not compute number of resampled samples");
+	if (out_samples > ctx->converted_capacity)
+	{
+		if (ctx->converted)
+			av_freep(&ctx->converted[0]);
+		av_freep(&ctx->converted);
+		ctx->converted_capacity = 0;
+		ret = av_samples_alloc_array_and_samples(
+			&ctx->converted, NULL, CHANNELS, out_samples, ctx->c->sample_fmt, 0);
+		if (ret < 0)
+			ERROR(err, 1, "Could not allocate resampled samples: %s", av_err2str(ret));
+		ctx->converted_capacity = out_samples;
+	}
+
+	const uint8_t* in[] = {(const uint8_t*)samples};
+	ret = swr_convert(ctx->swr, ctx->converted, out_samples, in, num_samples);
+	if (ret < 0)
+		ERROR(err, 1, "Could not resample audio: %s", av_err2str(ret));
+	if (av_audio_fifo_write(ctx->fifo, (void**)ctx->converted, ret) < ret)
+		ERROR(err, 1, "Could not queue audio samples");
+
+	encode_frames(ctx, err);
+}
@@ -1,3 +1,4 @@
+#include <limits.h>
 #include <stdint.h>
 #include <stdlib.h>
 #include <string.h>
@@ -23,10 +24,56 @@
 #include "log.h"
 
 #ifdef HAS_VAAPI
+#include <glob.h>
+
 #include <libavutil/hwcontext_vaapi.h>
 #include <va/va.h>
 #endif
 
+// must match VideoEncoder::new in src/video.rs, CODEC_H264 leaves the profile to the encoder
+enum VideoCodec
+{
+	CODEC_H264,
+	CODEC_H264_BASELINE,
+	CODEC_H264_MAIN,
+	CODEC_VP9,
+	CODEC_HEVC
+};
+
+// names of the encoders for a codec in the order they are tried, NULL if there is none
+typedef struct Encoders
+{
+	const char* vaapi;
+	const char* mediafoundation;
+	const char* nvenc;
+	const char* videotoolbox;
+	const char* software;
+} Encoders;
+
+// HEVC is left to hardware encoders, their vendors pay for the patent licenses
+const Encoders encoders[] = {
+	[CODEC_H264] = {"h264_vaapi", "h264_mf", "h264_nvenc", "h264_videotoolbox", "libx264"},
+	[CODEC_H264_BASELINE] = {"h264_vaapi", "h264_mf", "h264_nvenc", "h264_videotoolbox", "libx264"},
+	[CODEC_H264_MAIN] = {"h264_vaapi", "h264_mf", "h264_nvenc", "h264_videotoolbox", "libx264"},
+	[CODEC_VP9] = {"vp9_vaapi", NULL, NULL, NULL, "libvpx-vp9"},
+	[CODEC_HEVC] = {"hevc_vaapi", "hevc_mf", "hevc_nvenc", "hevc_videotoolbox", NULL},
+};
+
+// must match EncoderPreset in src/video.rs, from the fastest to the best quality
+enum EncoderPreset
+{
+	PRESET_FASTEST,
+	PRESET_FAST,
+	PRESET_BALANCED,
+	PRESET_QUALITY
+};
+
+// what the presets are called by each encoder, VAAPI's quality is faster the higher it is
+const char* x264_presets[] = {"ultrafast", "superfast", "veryfast", "medium"};
+const char* nvenc_presets[] = {"p1", "p3", "p5", "p7"};
+const char* vp9_cpu_used[] = {"8", "7", "6", "4"};
+const char* vaapi_quality[] = {"7", "5", "3", "1"};
+
 typedef struct VideoContext
 {
 	AVFormatContext* oc;
@@ -44,18 +91,34 @@ typedef struct VideoContext
 	size_t buf_size;
 	void* buf;
 	void* rust_ctx;
-	int pts;
+	int force_keyframe;
 	struct SwsContext* sws_rgb;
 	struct SwsContext* sws_rgb0;
 	struct SwsContext* sws_bgr0;
+	struct SwsContext* sws_nv12;
+	struct SwsContext* sws_i420;
 	int initialized;
 	int frame_allocated;
 	int frame_hw_allocated;
 	int using_vaapi;
 	int try_vaapi;
 	int try_nvenc;
+	// CUDA device index NVENC is opened on, -1 leaves it to the driver
+	int nvenc_gpu;
 	int try_videotoolbox;
 	int try_mediafoundation;
+	enum VideoCodec codec;
+	// 0 and -1 respectively mean the defaults of the encoder are used
+	int bitrate_kbps;
+	int crf;
+	enum EncoderPreset preset;
+	int gop_size;
+	int intra_refresh;
+	// the encoded stream is additionally written to a file while this is set
+	AVFormatContext* rec_oc;
+	AVStream* rec_st;
+	AVPacket* rec_pkt;
+	int64_t rec_start_pts;
 } VideoContext;
 
 // this is a rust function and lives in src/video.rs
@@ -114,17 +177,77 @@ void log_callback(void* _ptr, int level, const char* fmt_orig, va_list args)
 // called in src/log.rs
 void init_ffmpeg_logger() { av_log_set_callback(log_callback); }
 
+void set_bitrate(VideoContext* ctx)
+{
+	ctx->c->bit_rate = ctx->bitrate_kbps * 1000LL;
+	ctx->c->rc_max_rate = ctx->c->bit_rate;
+	ctx->c->rc_buffer_size = ctx->c->bit_rate;
+}
+
 void set_codec_params(VideoContext* ctx)
 {
 	/* resolution must be a multiple of two */
 	ctx->c->width = ctx->width_out;
 	ctx->c->height = ctx->height_out;
-	ctx->c->time_base = (AVRational){1, 1000};
+	// frames are timestamped with the time they have been captured at in microseconds
+	ctx->c->time_base = (AVRational){1, 1000000};
 	ctx->c->framerate = (AVRational){0, 1};
 
-	ctx->c->gop_size = 12;
+	ctx->c->gop_size = ctx->gop_size;
 	// no B-frames to reduce latency
 	ctx->c->max_b_frames = 0;
+	// keyframes requested by the client have to be IDR frames so decoding can restart from them,
+	// encoders without this option ignore it
+	av_opt_set(ctx->c->priv_data, "forced-idr", "1", 0);
+
+	// Settings requested by the client override the defaults chosen for every encoder. The bitrate
+	// is a limit, encoders that support constant quality only reach it for complex content.
+	if (ctx->bitrate_kbps > 0)
+		set_bitrate(ctx);
+	const char* name = ctx->c->codec->name;
+	if (ctx->crf >= 0)
+	{
+		if (strcmp(name, "libx264") == 0 || strcmp(name, "libvpx-vp9") == 0)
+			av_opt_set_int(ctx->c->priv_data, "crf", ctx->crf, 0);
+		else if (strcmp(name, "h264_vaapi") == 0 || strcmp(name, "hevc_vaapi") == 0)
+			av_opt_set_int(ctx->c->priv_data, "qp", ctx->crf, 0);
+		else if (strcmp(name, "h264_nvenc") == 0 || strcmp(name, "hevc_nvenc") == 0)
+			av_opt_set_int(ctx->c->priv_data, "cq", ctx->crf, 0);
+		else
+			log_debug("Encoder %s does not support setting constant quality, ignoring it.", name);
+	}
+	// browsers may only play back some profiles, the encoders name them differently though
+	if (ctx->codec == CODEC_H264_BASELINE || ctx->codec == CODEC_H264_MAIN)
+	{
+		const char* profile = ctx->codec == CODEC_H264_MAIN ? "main" : "baseline";
+		if (ctx->codec == CODEC_H264_BASELINE && strcmp(name, "h264_vaapi") == 0)
+			profile = "constrained_baseline";
+		if (av_opt_set(ctx->c->priv_data, "profile", profile, 0) < 0)
+			log_debug("Encoder %s does not support setting the profile, ignoring it.", name);
+	}
+	// Intra refresh spreads the refresh of the picture over gop_size frames instead of sending a
+	// large keyframe every gop_size frames, so the bitrate stays flat. Forced keyframes are still
+	// IDR frames as the client can not start decoding otherwise and the refresh cycle starts over
+	// with them.
+	if (ctx->intra_refresh)
+	{
+		if (strcmp(name, "libx264") == 0 || strcmp(name, "h264_nvenc") == 0 ||
+			strcmp(name, "hevc_nvenc") == 0)
+			av_opt_set(ctx->c->priv_data, "intra-refresh", "1", 0);
+		else if (strcmp(name, "libvpx-vp9") == 0)
+		{
+			// there is no column based refresh in VP9, cyclic refresh of blocks is the closest
+			av_opt_set(ctx->c->priv_data, "aq-mode", "3", 0);
+			// keyframes only on request
+			ctx->c->gop_size = INT_MAX;
+		}
+		else
+			log_info(
+				"Encoder %s does not support intra refresh, sending keyframes every %d frames "
+				"instead.",
+				name,
+				ctx->gop_size);
+	}
 	if (ctx->oc->oformat->flags & AVFMT_GLOBALHEADER)
 		ctx->c->flags |= AV_CODEC_FLAG_GLOBAL_HEADER;
 }
@@ -165,6 +288,230 @@ void set_frame_params(VideoContext* ctx)
 	ctx->frame->height = ctx->c->height;
 }
 
+#ifdef HAS_VAAPI
+void open_vaapi_encoder(VideoContext* ctx, const char* device, Error* err)
+{
+	int ret = av_hwdevice_ctx_create(&ctx->hw_device_ctx, AV_HWDEVICE_TYPE_VAAPI, device, NULL, 0);
+	if (ret < 0)
+		ERROR(err, 1, "Failed to create VAAPI device: %s", av_err2str(ret));
+
+	const char* name = encoders[ctx->codec].vaapi;
+	const AVCodec* codec = avcodec_find_encoder_by_name(name);
+	if (!codec)
+		ERROR(err, 1, "Codec '%s' not found!", name);
+
+	ctx->c = avcodec_alloc_context3(codec);
+	if (!ctx->c)
+		ERROR(err, 1, "Could not allocate video codec context for '%s'!", name);
+
+	ctx->c->pix_fmt = AV_PIX_FMT_VAAPI;
+	av_opt_set(ctx->c->priv_data, "quality", vaapi_quality[ctx->preset], 0);
+	av_opt_set(ctx->c->priv_data, "qp", "23", 0);
+	set_codec_params(ctx);
+	set_hwframe_ctx(ctx, err);
+	OK_OR_ABORT(err);
+
+	// Some drivers incorrectly report to support some pixel formats that actually are
+	// not supported. Using these formats leads to a crash and that's why the following
+	// workaround detects the drivers mentioned and if it finds them forces the pixel
+	// format to NV12 as this seems to work so far.
+	VADisplay dpy =
+		((AVVAAPIDeviceContext*)((AVHWDeviceContext*)ctx->hw_device_ctx->data)->hwctx)->display;
+	const char* vendor_string = vaQueryVendorString(dpy);
+	log_debug("VA-API vendor: %s", vendor_string);
+	// currently only some AMD drivers/hardware seem to be affected, this list may need
+	// to be refined in the future
+	const char* drivers_force_nv12[] = {"Radeon", "AMD RAVEN", "DIMGREY_CAVEFISH", NULL};
+	int force_nv12 = 0;
+	for (const char** pattern = drivers_force_nv12; *pattern; pattern++)
+		if (strstr(vendor_string, *pattern) != NULL)
+		{
+			force_nv12 = 1;
+			log_debug("'%s' is blacklisted and NV12 is forced as pixel format.", vendor_string);
+			break;
+		}
+
+	AVHWFramesConstraints* cst = av_hwdevice_get_hwframe_constraints(ctx->hw_device_ctx, NULL);
+	if (!force_nv12 && cst)
+	{
+		// If bgr0 is supported choose it as this avoids the overhead of calling
+		// sws_scale otherwise choose the first supported format.
+		int has_bgr0 = 0;
+		for (enum AVPixelFormat* fmt = cst->valid_sw_formats; *fmt != AV_PIX_FMT_NONE; ++fmt)
+			if (*fmt == AV_PIX_FMT_BGR0)
+			{
+				has_bgr0 = 1;
+				break;
+			}
+		ctx->sw_pix_fmt = has_bgr0 ? AV_PIX_FMT_BGR0 : cst->valid_sw_formats[0];
+	}
+	else
+		ctx->sw_pix_fmt = AV_PIX_FMT_NV12;
+
+	if (cst)
+		av_hwframe_constraints_free(&cst);
+
+	ret = avcodec_open2(ctx->c, codec, NULL);
+	if (ret < 0)
+		ERROR(err, 1, "Could not open codec: %s!", av_err2str(ret));
+}
+
+// Try to open the VAAPI encoder on the device given via WEYLUS_VAAPI_DEVICE, if it is not set
+// every render node is probed until one works. Returns 1 on success and 0 otherwise.
+int open_vaapi(VideoContext* ctx)
+{
+	const char* vaapi_device = getenv("WEYLUS_VAAPI_DEVICE");
+	glob_t render_nodes;
+	size_t num_devices = 1;
+	if (!vaapi_device)
+	{
+		if (glob("/dev/dri/renderD*", 0, NULL, &render_nodes) != 0)
+		{
+			globfree(&render_nodes);
+			log_warn("VAAPI: no render node found in /dev/dri, falling back to software encoding.");
+			return 0;
+		}
+		num_devices = render_nodes.gl_pathc;
+	}
+
+	int success = 0;
+	for (size_t i = 0; i < num_devices && !success; ++i)
+	{
+		const char* device = vaapi_device ? vaapi_device : render_nodes.gl_pathv[i];
+		Error err = {0};
+		open_vaapi_encoder(ctx, device, &err);
+		if (err.code)
+		{
+			log_warn("VAAPI: failed to use %s: %s", device, err.error_str);
+			avcodec_free_context(&ctx->c);
+			av_buffer_unref(&ctx->hw_device_ctx);
+		}
+		else
+		{
+			log_debug("VAAPI: using %s.", device);
+			success = 1;
+		}
+	}
+
+	if (!vaapi_device)
+		globfree(&render_nodes);
+	if (!success)
+		log_warn("VAAPI: no usable device found, falling back to software encoding.");
+	return success;
+}
+#endif
+
+#ifdef HAS_MEDIAFOUNDATION
+// Open the MediaFoundation encoder, which picks the hardware encoder of the GPU. It only takes
+// NV12, BGR0 frames are converted by the caller.
+void open_mediafoundation_encoder(VideoContext* ctx, const char* name, Error* err)
+{
+	const AVCodec* codec = avcodec_find_encoder_by_name(name);
+	if (!codec)
+		ERROR(err, 1, "Codec '%s' not found, FFmpeg has been built without it.", name);
+
+	ctx->c = avcodec_alloc_context3(codec);
+	if (!ctx->c)
+		ERROR(err, 1, "Could not allocate video codec context for '%s'!", name);
+
+	ctx->sw_pix_fmt = ctx->c->pix_fmt = AV_PIX_FMT_NV12;
+	av_opt_set(ctx->c->priv_data, "rate_control", "ld_vbr", 0);
+	av_opt_set(ctx->c->priv_data, "scenario", "display_remoting", 0);
+	av_opt_set(ctx->c->priv_data, "quality", "100", 0);
+	// the point is not to encode on the CPU, without a hardware encoder the next one is tried
+	av_opt_set(ctx->c->priv_data, "hw_encoding", "1", 0);
+	set_codec_params(ctx);
+
+	int ret = avcodec_open2(ctx->c, codec, NULL);
+	if (ret < 0)
+		ERROR(
+			err,
+			1,
+			"Failed to open %s: %s! There may be no hardware encoder for the codec.",
+			name,
+			av_err2str(ret));
+}
+#endif
+
+#ifdef HAS_VIDEOTOOLBOX
+// Open VideoToolbox in real time mode, which lowers the latency at the cost of quality. Apple's
+// software encoder is allowed for Macs without hardware encoder for the codec.
+void open_videotoolbox_encoder(VideoContext* ctx, const char* name, Error* err)
+{
+	const AVCodec* codec = avcodec_find_encoder_by_name(name);
+	if (!codec)
+		ERROR(err, 1, "Codec '%s' not found, FFmpeg has been built without it.", name);
+
+	ctx->c = avcodec_alloc_context3(codec);
+	if (!ctx->c)
+		ERROR(err, 1, "Could not allocate video codec context for '%s'!", name);
+
+	ctx->sw_pix_fmt = ctx->c->pix_fmt = AV_PIX_FMT_YUV420P;
+	av_opt_set(ctx->c->priv_data, "realtime", "true", 0);
+	av_opt_set(ctx->c->priv_data, "prio_speed", "true", 0);
+	av_opt_set(ctx->c->priv_data, "allow_sw", "true", 0);
+	av_opt_set(ctx->c->priv_data, "profile", "extended", 0);
+	av_opt_set(ctx->c->priv_data, "level", "5.2", 0);
+	set_codec_params(ctx);
+
+	int ret = avcodec_open2(ctx->c, codec, NULL);
+	if (ret < 0)
+		ERROR(err, 1, "Failed to open %s: %s!", name, av_err2str(ret));
+}
+#endif
+
+#ifdef HAS_NVENC
+// Open NVENC tuned for the lowest latency: no lookahead, no B-frames and every frame is output as
+// soon as it is encoded. Frames are uploaded as NV12, which is what NVENC encodes anyway, so BGR0
+// frames are converted on the CPU along with scaling them and only 12 instead of 32 bits per
+// pixel have to be copied to the GPU.
+void open_nvenc_encoder(VideoContext* ctx, const char* name, Error* err)
+{
+	char gpu[32] = "the default GPU";
+	if (ctx->nvenc_gpu >= 0)
+		snprintf(gpu, sizeof(gpu), "GPU %d", ctx->nvenc_gpu);
+
+	const AVCodec* codec = avcodec_find_encoder_by_name(name);
+	if (!codec)
+		ERROR(err, 1, "Codec '%s' not found, FFmpeg has been built without it.", name);
+
+	ctx->c = avcodec_alloc_context3(codec);
+	if (!ctx->c)
+		ERROR(err, 1, "Could not allocate video codec context for '%s'!", name);
+
+	ctx->sw_pix_fmt = ctx->c->pix_fmt = AV_PIX_FMT_NV12;
+	av_opt_set(ctx->c->priv_data, "preset", nvenc_presets[ctx->preset], 0);
+	av_opt_set(ctx->c->priv_data, "tune", "ull", 0);
+	av_opt_set(ctx->c->priv_data, "zerolatency", "1", 0);
+	av_opt_set(ctx->c->priv_data, "delay", "0", 0);
+	av_opt_set(ctx->c->priv_data, "rc-lookahead", "0", 0);
+	// constant quality unless there is a bitrate to keep, without one CBR falls back to the
+	// default bitrate of the driver
+	if (ctx->bitrate_kbps > 0)
+		av_opt_set(ctx->c->priv_data, "rc", "cbr", 0);
+	else
+	{
+		av_opt_set(ctx->c->priv_data, "rc", "vbr", 0);
+		av_opt_set(ctx->c->priv_data, "cq", "21", 0);
+	}
+	if (ctx->nvenc_gpu >= 0)
+		av_opt_set_int(ctx->c->priv_data, "gpu", ctx->nvenc_gpu, 0);
+	set_codec_params(ctx);
+
+	int ret = avcodec_open2(ctx->c, codec, NULL);
+	if (ret < 0)
+		ERROR(
+			err,
+			1,
+			"Failed to open %s on %s: %s! The driver may be too old, the GPU may not exist or "
+			"may already encode as many videos as it can at once.",
+			name,
+			gpu,
+			av_err2str(ret));
+	log_debug("NVENC: using %s.", gpu);
+}
+#endif
+
 void open_video(VideoContext* ctx, Error* err)
 {
 	if (ctx->width_out <= 1 || ctx->height_out <= 1)
@@ -184,186 +531,72 @@ void open_video(VideoContext* ctx, Error* err)
 		ERROR(err, 1, "Could not find output format mp4.");
 	}
 
+	const Encoders* names = &encoders[ctx->codec];
 	int using_hw = 0;
 
 #ifdef HAS_VAAPI
-	char* vaapi_device = getenv("WEYLUS_VAAPI_DEVICE");
-
-	if (ctx->try_vaapi &&
-		av_hwdevice_ctx_create(
-			&ctx->hw_device_ctx, AV_HWDEVICE_TYPE_VAAPI, vaapi_device, NULL, 0) == 0)
+	if (ctx->try_vaapi)
 	{
-		codec = avcodec_find_encoder_by_name("h264_vaapi");
-		if (codec)
-		{
-			ctx->c = avcodec_alloc_context3(codec);
-			if (ctx->c)
-			{
-				ctx->c->pix_fmt = AV_PIX_FMT_VAAPI;
-				av_opt_set(ctx->c->priv_data, "quality", "7", 0);
-				av_opt_set(ctx->c->priv_data, "qp", "23", 0);
-				set_codec_params(ctx);
-				Error err = {0};
-				set_hwframe_ctx(ctx, &err);
-
-				// Some drivers incorrectly report to support some pixel formats that actually are
-				// not supported. Using these formats leads to a crash and that's why the following
-				// workaround detects the drivers mentioned and if it finds them forces the pixel
-				// format to NV12 as this seems to work so far.
-				VADisplay dpy =
-					((AVVAAPIDeviceContext*)((AVHWDeviceContext*)ctx->hw_device_ctx->data)->hwctx)
-						->display;
-				const char* vendor_string = vaQueryVendorString(dpy);
-				log_debug("VA-API vendor: %s", vendor_string);
-				// currently only some AMD drivers/hardware seem to be affected, this list may need
-				// to be refined in the future
-				const char* drivers_force_nv12[] = {
-					"Radeon", "AMD RAVEN", "DIMGREY_CAVEFISH", NULL};
-				int force_nv12 = 0;
-				for (const char** pattern = drivers_force_nv12; *pattern; pattern++)
-					if (strstr(vendor_string, *pattern) != NULL)
-					{
-						force_nv12 = 1;
-						log_debug(
-							"'%s' is blacklisted and NV12 is forced as pixel format.",
-							vendor_string);
-						break;
-					}
-
-				AVHWFramesConstraints* cst =
-					av_hwdevice_get_hwframe_constraints(ctx->hw_device_ctx, NULL);
-				if (!force_nv12 && cst)
-				{
-					// If bgr0 is supported choose it as this avoids the overhead of calling
-					// sws_scale otherwise choose the first supported format.
-					int has_bgr0 = 0;
-					for (enum AVPixelFormat* fmt = cst->valid_sw_formats; *fmt != AV_PIX_FMT_NONE;
-						 ++fmt)
-						if (*fmt == AV_PIX_FMT_BGR0)
-						{
-							has_bgr0 = 1;
-							break;
-						}
-					ctx->sw_pix_fmt = has_bgr0 ? AV_PIX_FMT_BGR0 : cst->valid_sw_formats[0];
-				}
-				else
-					ctx->sw_pix_fmt = AV_PIX_FMT_NV12;
-
-				if (cst)
-					av_hwframe_constraints_free(&cst);
-
-				if (err.code == 0 && avcodec_open2(ctx->c, codec, NULL) == 0)
-				{
-					using_hw = 1;
-					ctx->using_vaapi = 1;
-				}
-				else
-				{
-					avcodec_free_context(&ctx->c);
-					av_buffer_unref(&ctx->hw_device_ctx);
-				}
-			}
-		}
-		else
-			av_buffer_unref(&ctx->hw_device_ctx);
+		using_hw = open_vaapi(ctx);
+		ctx->using_vaapi = using_hw;
 	}
 #endif
 
 #ifdef HAS_MEDIAFOUNDATION
-	if (ctx->try_mediafoundation && !using_hw)
+	if (ctx->try_mediafoundation && !using_hw && names->mediafoundation)
 	{
-		codec = avcodec_find_encoder_by_name("h264_mf");
-		if (codec)
+		Error mf_err = {0};
+		open_mediafoundation_encoder(ctx, names->mediafoundation, &mf_err);
+		if (mf_err.code)
 		{
-			ctx->c = avcodec_alloc_context3(codec);
-			if (ctx->c)
-			{
-				ctx->sw_pix_fmt = ctx->c->pix_fmt = AV_PIX_FMT_NV12;
-				av_opt_set(ctx->c->priv_data, "rate_control", "ld_vbr", 0);
-				av_opt_set(ctx->c->priv_data, "scenario", "display_remoting", 0);
-				av_opt_set(ctx->c->priv_data, "quality", "100", 0);
-				set_codec_params(ctx);
-				int ret = avcodec_open2(ctx->c, codec, NULL);
-				if (ret == 0)
-					using_hw = 1;
-				else
-				{
-					log_debug("Could not open codec: %s!", av_err2str(ret));
-					avcodec_free_context(&ctx->c);
-				}
-			}
-			else
-				log_debug("Could not allocate video codec context for 'h264_mf'!");
+			log_warn("MediaFoundation: %s Trying the next encoder.", mf_err.error_str);
+			avcodec_free_context(&ctx->c);
 		}
 		else
-			log_debug("Codec 'h264_mf' not found!");
+			using_hw = 1;
 	}
 #endif
 
 #ifdef HAS_NVENC
-	if (ctx->try_nvenc && !using_hw)
+	if (ctx->try_nvenc && !using_hw && names->nvenc)
 	{
-		codec = avcodec_find_encoder_by_name("h264_nvenc");
-		if (codec)
+		Error nvenc_err = {0};
+		open_nvenc_encoder(ctx, names->nvenc, &nvenc_err);
+		if (nvenc_err.code)
 		{
-			ctx->c = avcodec_alloc_context3(codec);
-			if (ctx->c)
-			{
-				ctx->sw_pix_fmt = ctx->c->pix_fmt = AV_PIX_FMT_BGR0;
-				av_opt_set(ctx->c->priv_data, "preset", "p1", 0);
-				av_opt_set(ctx->c->priv_data, "zerolatency", "1", 0);
-				av_opt_set(ctx->c->priv_data, "tune", "ull", 0);
-				av_opt_set(ctx->c->priv_data, "rc", "cbr", 0);
-				av_opt_set(ctx->c->priv_data, "cq", "21", 0);
-				av_opt_set(ctx->c->priv_data, "delay", "0", 0);
-				set_codec_params(ctx);
-				int ret = avcodec_open2(ctx->c, codec, NULL);
-				if (ret == 0)
-					using_hw = 1;
-				else
-				{
-					log_debug("Could not open codec: %s!", av_err2str(ret));
-					avcodec_free_context(&ctx->c);
-				}
-			}
-			else
-				log_debug("Could not allocate video codec context for 'h264_nvenc'!");
+			log_warn("NVENC: %s Trying the next encoder.", nvenc_err.error_str);
+			avcodec_free_context(&ctx->c);
 		}
 		else
-			log_debug("Codec 'h264_nvenc' not found!");
+			using_hw = 1;
 	}
 #endif
 
 #ifdef HAS_VIDEOTOOLBOX
-	if (ctx->try_videotoolbox && !using_hw)
+	if (ctx->try_videotoolbox && !using_hw && names->videotoolbox)
 	{
-		codec = avcodec_find_encoder_by_name("h264_videotoolbox");
-		if (codec)
+		Error vt_err = {0};
+		open_videotoolbox_encoder(ctx, names->videotoolbox, &vt_err);
+		if (vt_err.code)
 		{
-			ctx->c = avcodec_alloc_context3(codec);
-			if (ctx->c)
-			{
-				ctx->sw_pix_fmt = ctx->c->pix_fmt = AV_PIX_FMT_YUV420P;
-				av_opt_set(ctx->c->priv_data, "realtime", "true", 0);
-				av_opt_set(ctx->c->priv_data, "allow_sw", "true", 0);
-				av_opt_set(ctx->c->priv_data, "profile", "extended", 0);
-				av_opt_set(ctx->c->priv_data, "level", "5.2", 0);
-				set_codec_params(ctx);
-				if (avcodec_open2(ctx->c, codec, NULL) == 0)
-					using_hw = 1;
-				else
-					avcodec_free_context(&ctx->c);
-			}
+			log_warn("VideoToolbox: %s Trying the next encoder.", vt_err.error_str);
+			avcodec_free_context(&ctx->c);
 		}
+		else
+			using_hw = 1;
 	}
 #endif
 
 	if (!using_hw)
 	{
-		codec = avcodec_find_encoder_by_name("libx264");
+		if (!names->software)
+		{
+			ERROR(err, 1, "None of the hardware encoders for the codec works!");
+		}
+		codec = avcodec_find_encoder_by_name(names->software);
 		if (!codec)
 		{
-			ERROR(err, 1, "Codec 'libx264' not found");
+			ERROR(err, 1, "Codec '%s' not found", names->software);
 		}
 
 		ctx->c = avcodec_alloc_context3(codec);
@@ -372,9 +605,20 @@ void open_video(VideoContext* ctx, Error* err)
 			ERROR(err, 1, "Could not allocate video codec context");
 		}
 		ctx->sw_pix_fmt = ctx->c->pix_fmt = AV_PIX_FMT_YUV420P;
-		av_opt_set(ctx->c->priv_data, "preset", "ultrafast", 0);
-		av_opt_set(ctx->c->priv_data, "tune", "zerolatency", 0);
-		av_opt_set(ctx->c->priv_data, "crf", "23", 0);
+		if (ctx->codec == CODEC_VP9)
+		{
+			av_opt_set(ctx->c->priv_data, "deadline", "realtime", 0);
+			av_opt_set(ctx->c->priv_data, "cpu-used", vp9_cpu_used[ctx->preset], 0);
+			av_opt_set(ctx->c->priv_data, "lag-in-frames", "0", 0);
+			av_opt_set(ctx->c->priv_data, "row-mt", "1", 0);
+			av_opt_set(ctx->c->priv_data, "crf", "32", 0);
+		}
+		else
+		{
+			av_opt_set(ctx->c->priv_data, "preset", x264_presets[ctx->preset], 0);
+			av_opt_set(ctx->c->priv_data, "tune", "zerolatency", 0);
+			av_opt_set(ctx->c->priv_data, "crf", "23", 0);
+		}
 		set_codec_params(ctx);
 
 		ret = avcodec_open2(ctx->c, codec, NULL);
@@ -386,6 +630,12 @@ void open_video(VideoContext* ctx, Error* err)
 
 	ctx->st = avformat_new_stream(ctx->oc, NULL);
 	avcodec_parameters_from_context(ctx->st->codecpar, ctx->c);
+	// Safari only plays back HEVC tagged as hvc1 instead of hev1, which is what FFmpeg defaults to
+	if (ctx->codec == CODEC_HEVC)
+		ctx->st->codecpar->codec_tag = MKTAG('h', 'v', 'c', '1');
+	// older versions of FFmpeg consider VP9 in MP4 experimental
+	if (ctx->codec == CODEC_VP9)
+		ctx->oc->strict_std_compliance = FF_COMPLIANCE_EXPERIMENTAL;
 
 	ctx->frame = av_frame_alloc();
 	if (!ctx->frame)
@@ -452,6 +702,30 @@ void open_video(VideoContext* ctx, Error* err)
 		NULL,
 		NULL);
 
+	ctx->sws_nv12 = sws_getContext(
+		ctx->width_in,
+		ctx->height_in,
+		AV_PIX_FMT_NV12,
+		ctx->width_out,
+		ctx->height_out,
+		ctx->sw_pix_fmt,
+		SWS_FAST_BILINEAR,
+		NULL,
+		NULL,
+		NULL);
+
+	ctx->sws_i420 = sws_getContext(
+		ctx->width_in,
+		ctx->height_in,
+		AV_PIX_FMT_YUV420P,
+		ctx->width_out,
+		ctx->height_out,
+		ctx->sw_pix_fmt,
+		SWS_FAST_BILINEAR,
+		NULL,
+		NULL,
+		NULL);
+
 	ctx->initialized = 1;
 	log_info(
 		"Video: %dx%d@%s pix_fmt: %s",
@@ -461,8 +735,188 @@ void open_video(VideoContext* ctx, Error* err)
 		av_get_pix_fmt_name(ctx->sw_pix_fmt));
 }
 
+const char* get_video_codec_name(VideoContext* ctx) { return ctx->c->codec->name; }
+
+// Returns the bytes of the SPS starting at profile_idc, with the constraint flags and level_idc
+// following, NULL if there is no SPS in the extradata of an H.264 stream.
+const uint8_t* find_h264_sps(const uint8_t* data, int size)
+{
+	// avcC starts with a version of 1 followed by the same three bytes
+	if (size >= 4 && data[0] == 1)
+		return data + 1;
+	// Annex B
+	for (int i = 0; i + 6 < size; ++i)
+		if (data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 && (data[i + 3] & 0x1f) == 7)
+			return data + i + 4;
+	return NULL;
+}
+
+// Write the codec string of the video as used in MIME types to str, like avc1.42E01F.
+void get_video_codec_string(VideoContext* ctx, char* str, size_t size)
+{
+	const AVCodecParameters* par = ctx->st->codecpar;
+	// encoders do not necessarily tell the level, so it is guessed from the number of pixels
+	// otherwise, browsers do not check it anyway
+	int pixels = par->width * par->height;
+	switch (par->codec_id)
+	{
+	case AV_CODEC_ID_VP9:
+		snprintf(
+			str,
+			size,
+			"vp09.%02d.%02d.08",
+			par->profile > 0 ? par->profile : 0,
+			par->level > 0 ? par->level : (pixels <= 2228224 ? 41 : (pixels <= 8912896 ? 51 : 61)));
+		return;
+	case AV_CODEC_ID_HEVC:
+		snprintf(
+			str,
+			size,
+			"hvc1.1.6.L%d.B0",
+			par->level > 0 ? par->level
+						   : (pixels <= 2228224 ? 123 : (pixels <= 8912896 ? 153 : 183)));
+		return;
+	default:
+	{
+		const uint8_t* sps = find_h264_sps(par->extradata, par->extradata_size);
+		if (sps)
+			snprintf(str, size, "avc1.%02X%02X%02X", sps[0], sps[1], sps[2]);
+		else
+			// main profile at level 6.1, which covers any resolution
+			snprintf(str, size, "avc1.4D403D");
+		return;
+	}
+	}
+}
+
+// 1 if the encoder takes YUV420P and 2 if it takes NV12 without scaling, 0 otherwise
+int get_video_input_format(VideoContext* ctx)
+{
+	if (ctx->width_in != ctx->width_out || ctx->height_in != ctx->height_out)
+		return 0;
+	switch (ctx->sw_pix_fmt)
+	{
+	case AV_PIX_FMT_YUV420P:
+		return 1;
+	case AV_PIX_FMT_NV12:
+		return 2;
+	default:
+		return 0;
+	}
+}
+
+void request_keyframe(VideoContext* ctx) { ctx->force_keyframe = 1; }
+
+// Change the bitrate of an open encoder, returns 0 if the encoder does not support that. Only
+// libx264 and nvenc pick up the changed settings with the next frame, the others keep using the
+// bitrate they have been opened with. The encoder must have been opened with a bitrate, as VBV can
+// not be turned on afterwards.
+int set_video_bitrate(VideoContext* ctx, int bitrate_kbps)
+{
+	const char* name = ctx->c->codec->name;
+	if (ctx->bitrate_kbps <= 0
+		|| (strcmp(name, "libx264") != 0 && strcmp(name, "h264_nvenc") != 0
+			&& strcmp(name, "hevc_nvenc") != 0))
+		return 0;
+	ctx->bitrate_kbps = bitrate_kbps;
+	set_bitrate(ctx);
+	return 1;
+}
+
+void close_recording(VideoContext* ctx, Error* err)
+{
+	if (!ctx->rec_oc)
+		return;
+	int ret = 0;
+	// the header is only written once the first keyframe arrives
+	if (ctx->rec_start_pts != AV_NOPTS_VALUE)
+		ret = av_write_trailer(ctx->rec_oc);
+	int ret_close = avio_closep(&ctx->rec_oc->pb);
+	avformat_free_context(ctx->rec_oc);
+	av_packet_free(&ctx->rec_pkt);
+	ctx->rec_oc = NULL;
+	ctx->rec_st = NULL;
+	if (ret < 0 || ret_close < 0)
+		ERROR(
+			err, 1, "Failed to finish recording: %s", av_err2str(ret < 0 ? ret : ret_close));
+}
+
+void start_recording(VideoContext* ctx, const char* path, Error* err)
+{
+	if (ctx->rec_oc)
+		ERROR(err, 1, "Already recording!");
+
+	// the container is chosen based on the extension of path
+	int ret = avformat_alloc_output_context2(&ctx->rec_oc, NULL, NULL, path);
+	if (ret < 0)
+		ERROR(err, 1, "Could not find output format for %s: %s", path, av_err2str(ret));
+
+	ctx->rec_st = avformat_new_stream(ctx->rec_oc, NULL);
+	ctx->rec_pkt = av_packet_alloc();
+	if (!ctx->rec_st || !ctx->rec_pkt)
+	{
+		avformat_free_context(ctx->rec_oc);
+		av_packet_free(&ctx->rec_pkt);
+		ctx->rec_oc = NULL;
+		ERROR(err, 1, "Failed to allocate recording stream!");
+	}
+	avcodec_parameters_copy(ctx->rec_st->codecpar, ctx->st->codecpar);
+	// let the container choose the codec tag, not every container supports the one of mp4
+	ctx->rec_st->codecpar->codec_tag = 0;
+	ctx->rec_st->time_base = ctx->c->time_base;
+
+	ret = avio_open(&ctx->rec_oc->pb, path, AVIO_FLAG_WRITE);
+	if (ret < 0)
+	{
+		avformat_free_context(ctx->rec_oc);
+		av_packet_free(&ctx->rec_pkt);
+		ctx->rec_oc = NULL;
+		ERROR(err, 1, "Could not open %s: %s", path, av_err2str(ret));
+	}
+	// the recording has to start with a keyframe
+	ctx->rec_start_pts = AV_NOPTS_VALUE;
+	ctx->force_keyframe = 1;
+}
+
+void stop_recording(VideoContext* ctx, Error* err) { close_recording(ctx, err); }
+
+void write_recording_packet(VideoContext* ctx, AVPacket* pkt, Error* err)
+{
+	if (ctx->rec_start_pts == AV_NOPTS_VALUE)
+	{
+		if (!(pkt->flags & AV_PKT_FLAG_KEY))
+			return;
+		AVDictionary* opt = NULL;
+		// fragmented mp4 keeps the file playable in case Weylus is not stopped cleanly
+		av_dict_set(&opt, "movflags", "frag_keyframe+empty_moov", 0);
+		int ret = avformat_write_header(ctx->rec_oc, &opt);
+		av_dict_free(&opt);
+		if (ret < 0)
+			ERROR(err, 1, "Failed to write recording header: %s", av_err2str(ret));
+		ctx->rec_start_pts = pkt->pts;
+	}
+
+	int ret = av_packet_ref(ctx->rec_pkt, pkt);
+	if (ret < 0)
+		ERROR(err, 1, "Failed to copy packet for recording: %s", av_err2str(ret));
+	// the timestamps are derived from the capture time, so the recording plays at the right
+	// speed no matter how irregular frames have been captured
+	ctx->rec_pkt->pts -= ctx->rec_start_pts;
+	ctx->rec_pkt->dts -= ctx->rec_start_pts;
+	ctx->rec_pkt->stream_index = ctx->rec_st->index;
+	av_packet_rescale_ts(ctx->rec_pkt, ctx->c->time_base, ctx->rec_st->time_base);
+	ret = av_write_frame(ctx->rec_oc, ctx->rec_pkt);
+	av_packet_unref(ctx->rec_pkt);
+	if (ret < 0)
+		ERROR(err, 1, "Failed to write recording: %s", av_err2str(ret));
+}
+
 void destroy_video_encoder(VideoContext* ctx)
 {
+	Error err = {0};
+	close_recording(ctx, &err);
+	if (err.code)
+		log_warn("%s", err.error_str);
 	if (ctx->initialized)
 	{
 		av_write_trailer(ctx->oc);
@@ -477,18 +931,27 @@ void destroy_video_encoder(VideoContext* ctx)
 		sws_freeContext(ctx->sws_rgb);
 		sws_freeContext(ctx->sws_rgb0);
 		sws_freeContext(ctx->sws_bgr0);
+		sws_freeContext(ctx->sws_nv12);
+		sws_freeContext(ctx->sws_i420);
 	}
 	if (ctx->using_vaapi)
 		av_buffer_unref(&ctx->hw_device_ctx);
 	free(ctx);
 }
 
-void encode_video_frame(VideoContext* ctx, int millis, Error* err)
+void encode_video_frame(VideoContext* ctx, int64_t micros, Error* err, Error* rec_err)
 {
 	int ret;
 	AVFrame* frame = ctx->using_vaapi ? ctx->frame_hw : ctx->frame;
 
-	frame->pts = millis;
+	frame->pts = micros;
+	if (ctx->force_keyframe)
+	{
+		frame->pict_type = AV_PICTURE_TYPE_I;
+		ctx->force_keyframe = 0;
+	}
+	else
+		frame->pict_type = AV_PICTURE_TYPE_NONE;
 
 	ret = avcodec_send_frame(ctx->c, frame);
 	if (ret < 0)
@@ -504,6 +967,18 @@ void encode_video_frame(VideoContext* ctx, int millis, Error* err)
 			ERROR(err, 1, "Error during encoding");
 		}
 
+		if (ctx->rec_oc)
+		{
+			// a failing recording, for example because the disk is full, must not stop the
+			// stream
+			write_recording_packet(ctx, ctx->pkt, rec_err);
+			if (rec_err->code)
+			{
+				Error close_err = {0};
+				close_recording(ctx, &close_err);
+			}
+		}
+
 		av_packet_rescale_ts(ctx->pkt, ctx->c->time_base, ctx->st->time_base);
 		av_write_frame(ctx->oc, ctx->pkt);
 		av_packet_unref(ctx->pkt);
@@ -521,24 +996,43 @@ VideoContext* init_video_encoder(
 	int height_out,
 	int try_vaapi,
 	int try_nvenc,
+	int nvenc_gpu,
 	int try_videotoolbox,
-	int try_mediafoundation)
+	int try_mediafoundation,
+	int codec,
+	int bitrate_kbps,
+	int crf,
+	int preset,
+	int gop_size,
+	int intra_refresh)
 {
 	VideoContext* ctx = malloc(sizeof(VideoContext));
 	ctx->rust_ctx = rust_ctx;
+	ctx->c = NULL;
+	ctx->hw_device_ctx = NULL;
 	ctx->width_out = width_out - width_out % 2;
 	ctx->height_out = height_out - height_out % 2;
 	ctx->width_in = width_in;
 	ctx->height_in = height_in;
-	ctx->pts = 0;
+	ctx->force_keyframe = 0;
 	ctx->initialized = 0;
 	ctx->frame_allocated = 0;
 	ctx->frame_hw_allocated = 0;
 	ctx->using_vaapi = 0;
 	ctx->try_vaapi = try_vaapi;
 	ctx->try_nvenc = try_nvenc;
+	ctx->nvenc_gpu = nvenc_gpu;
 	ctx->try_videotoolbox = try_videotoolbox;
 	ctx->try_mediafoundation = try_mediafoundation;
+	ctx->codec = codec;
+	ctx->bitrate_kbps = bitrate_kbps;
+	ctx->crf = crf;
+	ctx->preset = preset;
+	ctx->gop_size = gop_size;
+	ctx->intra_refresh = intra_refresh;
+	ctx->rec_oc = NULL;
+	ctx->rec_st = NULL;
+	ctx->rec_pkt = NULL;
 	return ctx;
 }
 
@@ -563,10 +1057,26 @@ void alloc_frame_buffer_hw(VideoContext* ctx, Error* err)
 	if (ret < 0)
 		ERROR(err, 1, "Could not allocate video hardware frame data: %s", av_err2str(ret));
 	if (!ctx->frame_hw->hw_frames_ctx)
-		ERROR(err, 2, "Could not allocate video hardware frame data");
+		ERROR(err, 1, "Could not allocate video hardware frame data");
 	ctx->frame_hw_allocated = 1;
 }
 
+// copy the software frame to the hardware frame if VAAPI is used
+void upload_frame_hw(VideoContext* ctx, Error* err)
+{
+	if (!ctx->using_vaapi)
+		return;
+	if (!ctx->frame_hw_allocated)
+	{
+		alloc_frame_buffer_hw(ctx, err);
+		OK_OR_ABORT(err);
+	}
+	av_frame_make_writable(ctx->frame_hw);
+	int ret = av_hwframe_transfer_data(ctx->frame_hw, ctx->frame, 0);
+	if (ret < 0)
+		ERROR(err, 1, "Could not upload video frame to hardware: %s", av_err2str(ret));
+}
+
 void fill_bgr0(VideoContext* ctx, const void* data, int stride, Error* err)
 {
 	if (ctx->frame->format == AV_PIX_FMT_BGR0 && ctx->width_in == ctx->width_out &&
@@ -597,18 +1107,7 @@ void fill_bgr0(VideoContext* ctx, const void* data, int stride, Error* err)
 			ctx->frame->data,
 			ctx->frame->linesize);
 	}
-	if (ctx->using_vaapi)
-	{
-		if (!ctx->frame_hw_allocated)
-		{
-			alloc_frame_buffer_hw(ctx, err);
-			OK_OR_ABORT(err);
-		}
-		av_frame_make_writable(ctx->frame_hw);
-		int ret = av_hwframe_transfer_data(ctx->frame_hw, ctx->frame, 0);
-		if (ret < 0)
-			ERROR(err, 1, "Could not upload video frame to hardware: %s", av_err2str(ret));
-	}
+	upload_frame_hw(ctx, err);
 }
 
 void fill_rgb(VideoContext* ctx, const void* data, Error* err)
@@ -624,18 +1123,7 @@ void fill_rgb(VideoContext* ctx, const void* data, Error* err)
 	av_frame_make_writable(ctx->frame);
 	sws_scale(
 		ctx->sws_rgb, src, src_stride, 0, ctx->height_in, ctx->frame->data, ctx->frame->linesize);
-	if (ctx->using_vaapi)
-	{
-		if (!ctx->frame_hw_allocated)
-		{
-			alloc_frame_buffer_hw(ctx, err);
-			OK_OR_ABORT(err);
-		}
-		av_frame_make_writable(ctx->frame_hw);
-		int ret = av_hwframe_transfer_data(ctx->frame_hw, ctx->frame, 0);
-		if (ret < 0)
-			ERROR(err, 1, "Could not upload video frame to hardware: %s", av_err2str(ret));
-	}
+	upload_frame_hw(ctx, err);
 }
 
 void fill_rgb0(VideoContext* ctx, const void* data, Error* err)
@@ -651,16 +1139,62 @@ void fill_rgb0(VideoContext* ctx, const void* data, Error* err)
 	av_frame_make_writable(ctx->frame);
 	sws_scale(
 		ctx->sws_rgb0, src, src_stride, 0, ctx->height_in, ctx->frame->data, ctx->frame->linesize);
-	if (ctx->using_vaapi)
+	upload_frame_hw(ctx, err);
+}
+
+// planes of pix_fmt are used as is if the encoder takes them, otherwise they are converted via sws
+void fill_planes(
+	VideoContext* ctx,
+	enum AVPixelFormat pix_fmt,
+	struct SwsContext* sws,
+	const uint8_t* const planes[4],
+	const int strides[4],
+	Error* err)
+{
+	if (ctx->frame->format == pix_fmt && ctx->width_in == ctx->width_out &&
+		ctx->height_in == ctx->height_out)
+	{
+		if (ctx->frame_allocated)
+			dealloc_frame_buffer(ctx);
+		for (int i = 0; i < 4; ++i)
+		{
+			ctx->frame->data[i] = (uint8_t*)planes[i];
+			ctx->frame->linesize[i] = strides[i];
+		}
+	}
+	else
 	{
-		if (!ctx->frame_hw_allocated)
+		if (!ctx->frame_allocated)
 		{
-			alloc_frame_buffer_hw(ctx, err);
+			alloc_frame_buffer(ctx, err);
 			OK_OR_ABORT(err);
 		}
-		av_frame_make_writable(ctx->frame_hw);
-		int ret = av_hwframe_transfer_data(ctx->frame_hw, ctx->frame, 0);
-		if (ret < 0)
-			ERROR(err, 1, "Could not upload video frame to hardware: %s", av_err2str(ret));
+		av_frame_make_writable(ctx->frame);
+		sws_scale(
+			sws, planes, strides, 0, ctx->height_in, ctx->frame->data, ctx->frame->linesize);
 	}
+	upload_frame_hw(ctx, err);
+}
+
+void fill_nv12(
+	VideoContext* ctx, const void* y, int y_stride, const void* uv, int uv_stride, Error* err)
+{
+	const uint8_t* const planes[4] = {y, uv, NULL, NULL};
+	const int strides[4] = {y_stride, uv_stride, 0, 0};
+	fill_planes(ctx, AV_PIX_FMT_NV12, ctx->sws_nv12, planes, strides, err);
+}
+
+void fill_i420(
+	VideoContext* ctx,
+	const void* y,
+	int y_stride,
+	const void* u,
+	int u_stride,
+	const void* v,
+	int v_stride,
+	Error* err)
+{
+	const uint8_t* const planes[4] = {y, u, v, NULL};
+	const int strides[4] = {y_stride, u_stride, v_stride, 0};
+	fill_planes(ctx, AV_PIX_FMT_YUV420P, ctx->sws_i420, planes, strides, err);
 }
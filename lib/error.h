@@ -11,6 +11,24 @@ struct Error
 
 typedef struct Error Error;
 
+// Codes of errors that callers may handle on their own, mirrored by CErrorCode in src/cerror.rs.
+enum ErrorCode
+{
+	ERROR_GENERIC = 1,
+	ERROR_UINPUT_NOT_ACCESSIBLE = 101,
+	// the window that is captured no longer exists
+	ERROR_WINDOW_GONE = 102,
+	// there is no client list, only the desktop and monitors can be captured
+	ERROR_NO_WINDOW_MANAGER = 103,
+	// listing the monitors failed, the other capturables are fine
+	ERROR_MONITORS_UNAVAILABLE = 104,
+	ERROR_SHM_UNAVAILABLE = 105,
+	ERROR_XINPUT_MISSING = 106,
+	ERROR_DEVICE_NOT_FOUND = 107,
+	// the capture failed the same way the previous one did, which has been reported already
+	ERROR_CAPTURE_FAILED_AGAIN = 108,
+};
+
 #if defined(__clang__) || defined(__GNUC__)
 __attribute__((__format__ (__printf__, 3, 4)))
 #endif
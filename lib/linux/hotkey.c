@@ -0,0 +1,115 @@
+#include <X11/XKBlib.h>
+#include <X11/Xlib.h>
+#include <poll.h>
+#include <stdlib.h>
+
+#include "../error.h"
+#include "../log.h"
+#include "xhelper.h"
+
+typedef struct Hotkey
+{
+	Display* disp;
+	Window root;
+	KeyCode keycode;
+	unsigned int modifiers;
+	// the key is held down, further presses are auto repeat
+	int down;
+} Hotkey;
+
+// grabs only match the exact set of modifiers, so grab the key with every combination of the lock
+// keys as well, otherwise the hotkey does nothing while Caps Lock or Num Lock are on
+static const unsigned int lock_masks[] = {0, LockMask, Mod2Mask, LockMask | Mod2Mask};
+
+Hotkey* hotkey_grab(const char* key, unsigned int modifiers, Error* err)
+{
+	KeySym keysym = XStringToKeysym(key);
+	if (keysym == NoSymbol)
+	{
+		fill_error(err, 1, "Unknown key: %s.", key);
+		return NULL;
+	}
+
+	// the hotkey gets a connection of its own to wait on its events without locking the display
+	// used for capturing
+	Display* disp = XOpenDisplay(NULL);
+	if (!disp)
+	{
+		fill_error(err, 1, "Failed to open display.");
+		return NULL;
+	}
+	x11_watch_connection(disp);
+
+	KeyCode keycode = XKeysymToKeycode(disp, keysym);
+	if (!keycode)
+	{
+		XCloseDisplay(disp);
+		fill_error(err, 1, "The keyboard has no key %s.", key);
+		return NULL;
+	}
+
+	Hotkey* hotkey = malloc(sizeof(Hotkey));
+	hotkey->disp = disp;
+	hotkey->root = DefaultRootWindow(disp);
+	hotkey->keycode = keycode;
+	hotkey->modifiers = modifiers;
+	hotkey->down = 0;
+
+	// report auto repeat as repeated key presses without releases in between
+	XkbSetDetectableAutoRepeat(disp, True, NULL);
+	for (size_t i = 0; i < sizeof(lock_masks) / sizeof(lock_masks[0]); ++i)
+		XGrabKey(
+			disp,
+			keycode,
+			modifiers | lock_masks[i],
+			hotkey->root,
+			False,
+			GrabModeAsync,
+			GrabModeAsync);
+	// failing grabs, for example because another application uses the same hotkey, only show up
+	// in the log via the error handler
+	XSync(disp, False);
+	log_debug("Grabbed key %s with modifiers 0x%x.", key, modifiers);
+	return hotkey;
+}
+
+// Returns 1 if the hotkey has been pressed within timeout_ms milliseconds, 0 otherwise and -1 if
+// the connection to the X server has been lost.
+int hotkey_wait(Hotkey* hotkey, int timeout_ms)
+{
+	if (x11_connection_lost(hotkey->disp))
+		return -1;
+	// events may have been read into the queue of Xlib already
+	if (!XPending(hotkey->disp))
+	{
+		struct pollfd fd = {ConnectionNumber(hotkey->disp), POLLIN, 0};
+		if (poll(&fd, 1, timeout_ms) <= 0)
+			return 0;
+	}
+
+	int pressed = 0;
+	while (XPending(hotkey->disp))
+	{
+		XEvent event;
+		XNextEvent(hotkey->disp, &event);
+		if (event.xkey.keycode != hotkey->keycode)
+			continue;
+		if (event.type == KeyPress)
+		{
+			if (!hotkey->down)
+				pressed = 1;
+			hotkey->down = 1;
+		}
+		else if (event.type == KeyRelease)
+			hotkey->down = 0;
+	}
+	return pressed;
+}
+
+void hotkey_ungrab(Hotkey* hotkey)
+{
+	for (size_t i = 0; i < sizeof(lock_masks) / sizeof(lock_masks[0]); ++i)
+		XUngrabKey(hotkey->disp, hotkey->keycode, hotkey->modifiers | lock_masks[i], hotkey->root);
+	XCloseDisplay(hotkey->disp);
+	free(hotkey);
+}
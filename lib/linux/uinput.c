@@ -136,7 +136,40 @@ void init_mouse(int fd, const char* name, Error* err)
 		ERROR(err, 1, "error: ioctl");
 }
 
-void init_stylus(int fd, const char* name, Error* err)
+// Mouse that moves the cursor by relative motion, not flagged as direct input device so the pointer
+// acceleration of the host applies.
+void init_relative_mouse(int fd, const char* name, Error* err)
+{
+	// enable synchronization
+	if (ioctl(fd, UI_SET_EVBIT, EV_SYN) < 0)
+		ERROR(err, 1, "error: ioctl UI_SET_EVBIT EV_SYN");
+
+	// enable buttons
+	if (ioctl(fd, UI_SET_EVBIT, EV_KEY) < 0)
+		ERROR(err, 1, "error: ioctl UI_SET_EVBIT EV_KEY");
+	if (ioctl(fd, UI_SET_KEYBIT, BTN_LEFT) < 0)
+		ERROR(err, 1, "error: ioctl UI_SET_KEYBIT BTN_LEFT");
+	if (ioctl(fd, UI_SET_KEYBIT, BTN_RIGHT) < 0)
+		ERROR(err, 1, "error: ioctl UI_SET_KEYBIT BTN_RIGHT");
+	if (ioctl(fd, UI_SET_KEYBIT, BTN_MIDDLE) < 0)
+		ERROR(err, 1, "error: ioctl UI_SET_KEYBIT BTN_MIDDLE");
+
+	// enable motion
+	if (ioctl(fd, UI_SET_EVBIT, EV_REL) < 0)
+		ERROR(err, 1, "error: ioctl UI_SET_EVBIT EV_REL");
+	if (ioctl(fd, UI_SET_RELBIT, REL_X) < 0)
+		ERROR(err, 1, "error: ioctl UI_SET_RELBIT REL_X");
+	if (ioctl(fd, UI_SET_RELBIT, REL_Y) < 0)
+		ERROR(err, 1, "error: ioctl UI_SET_RELBIT REL_Y");
+
+	setup(fd, name, err);
+	OK_OR_ABORT(err);
+
+	if (ioctl(fd, UI_DEV_CREATE) < 0)
+		ERROR(err, 1, "error: ioctl");
+}
+
+void init_stylus(int fd, const char* name, int pressure_max, Error* err)
 {
 	// enable synchronization
 	if (ioctl(fd, UI_SET_EVBIT, EV_SYN) < 0)
@@ -154,6 +187,11 @@ void init_stylus(int fd, const char* name, Error* err)
 		ERROR(err, 1, "error: ioctl UI_SET_KEYBIT BTN_TOOL_RUBBER");
 	if (ioctl(fd, UI_SET_KEYBIT, BTN_TOUCH) < 0)
 		ERROR(err, 1, "error: ioctl UI_SET_KEYBIT BTN_TOUCH");
+	// only pressed if buttons are mapped to them
+	if (ioctl(fd, UI_SET_KEYBIT, BTN_STYLUS) < 0)
+		ERROR(err, 1, "error: ioctl UI_SET_KEYBIT BTN_STYLUS");
+	if (ioctl(fd, UI_SET_KEYBIT, BTN_STYLUS2) < 0)
+		ERROR(err, 1, "error: ioctl UI_SET_KEYBIT BTN_STYLUS2");
 
 	// setup sending timestamps
 	if (ioctl(fd, UI_SET_EVBIT, EV_MSC) < 0)
@@ -168,12 +206,15 @@ void init_stylus(int fd, const char* name, Error* err)
 	OK_OR_ABORT(err);
 	setup_abs(fd, ABS_Y, 0, ABS_MAXVAL, 12, err);
 	OK_OR_ABORT(err);
-	setup_abs(fd, ABS_PRESSURE, 0, ABS_MAXVAL, 12, err);
+	setup_abs(fd, ABS_PRESSURE, 0, pressure_max, 12, err);
 	OK_OR_ABORT(err);
 	setup_abs(fd, ABS_TILT_X, -90, 90, 12, err);
 	OK_OR_ABORT(err);
 	setup_abs(fd, ABS_TILT_Y, -90, 90, 12, err);
 	OK_OR_ABORT(err);
+	// rotation of the pen around its own axis in degrees, as reported by art pens
+	setup_abs(fd, ABS_Z, 0, 359, 0, err);
+	OK_OR_ABORT(err);
 
 	setup(fd, name, err);
 	OK_OR_ABORT(err);
@@ -219,11 +260,14 @@ void init_touch(int fd, const char* name, Error* err)
 	OK_OR_ABORT(err);
 	setup_abs(fd, ABS_Y, 0, ABS_MAXVAL, 200, err);
 	OK_OR_ABORT(err);
+	// single touch equivalent of ABS_MT_TOUCH_MAJOR
+	setup_abs(fd, ABS_TOOL_WIDTH, 0, ABS_MAXVAL, 200, err);
+	OK_OR_ABORT(err);
 
-	// 5 fingers 5 multitouch slots.
-	setup_abs(fd, ABS_MT_SLOT, 0, 4, 0, err);
+	// 10 multitouch slots, this has to match MAX_TOUCHES in src/input/uinput_device.rs
+	setup_abs(fd, ABS_MT_SLOT, 0, 9, 0, err);
 	OK_OR_ABORT(err);
-	setup_abs(fd, ABS_MT_TRACKING_ID, 0, 4, 0, err);
+	setup_abs(fd, ABS_MT_TRACKING_ID, 0, 0xffff, 0, err);
 	OK_OR_ABORT(err);
 	setup_abs(fd, ABS_MT_POSITION_X, 0, ABS_MAXVAL, 200, err);
 	OK_OR_ABORT(err);
@@ -231,9 +275,10 @@ void init_touch(int fd, const char* name, Error* err)
 	OK_OR_ABORT(err);
 	setup_abs(fd, ABS_MT_PRESSURE, 0, ABS_MAXVAL, 0, err);
 	OK_OR_ABORT(err);
-	setup_abs(fd, ABS_MT_TOUCH_MAJOR, 0, ABS_MAXVAL, 12, err);
+	// the size of contacts is given in the same units as their position
+	setup_abs(fd, ABS_MT_TOUCH_MAJOR, 0, ABS_MAXVAL, 200, err);
 	OK_OR_ABORT(err);
-	setup_abs(fd, ABS_MT_TOUCH_MINOR, 0, ABS_MAXVAL, 12, err);
+	setup_abs(fd, ABS_MT_TOUCH_MINOR, 0, ABS_MAXVAL, 200, err);
 	OK_OR_ABORT(err);
 	// PointerEvent only gives partial orientation of the touch ellipse
 	setup_abs(fd, ABS_MT_ORIENTATION, 0, 1, 0, err);
@@ -251,7 +296,7 @@ int init_uinput_keyboard(const char* name, Error* err)
 	int device;
 
 	if ((device = open("/dev/uinput", O_WRONLY | O_NONBLOCK)) < 0)
-		fill_error(err, 101, "error: failed to open /dev/uinput");
+		fill_error(err, ERROR_UINPUT_NOT_ACCESSIBLE, "error: failed to open /dev/uinput");
 	else
 	{
 		init_keyboard(device, name, err);
@@ -259,15 +304,15 @@ int init_uinput_keyboard(const char* name, Error* err)
 	return device;
 }
 
-int init_uinput_stylus(const char* name, Error* err)
+int init_uinput_stylus(const char* name, int pressure_max, Error* err)
 {
 	int device;
 
 	if ((device = open("/dev/uinput", O_WRONLY | O_NONBLOCK)) < 0)
-		fill_error(err, 101, "error: failed to open /dev/uinput");
+		fill_error(err, ERROR_UINPUT_NOT_ACCESSIBLE, "error: failed to open /dev/uinput");
 	else
 	{
-		init_stylus(device, name, err);
+		init_stylus(device, name, pressure_max, err);
 	}
 	return device;
 }
@@ -277,7 +322,7 @@ int init_uinput_mouse(const char* name, Error* err)
 	int device;
 
 	if ((device = open("/dev/uinput", O_WRONLY | O_NONBLOCK)) < 0)
-		fill_error(err, 101, "error: failed to open /dev/uinput");
+		fill_error(err, ERROR_UINPUT_NOT_ACCESSIBLE, "error: failed to open /dev/uinput");
 	else
 	{
 		init_mouse(device, name, err);
@@ -285,12 +330,25 @@ int init_uinput_mouse(const char* name, Error* err)
 	return device;
 }
 
+int init_uinput_relative_mouse(const char* name, Error* err)
+{
+	int device;
+
+	if ((device = open("/dev/uinput", O_WRONLY | O_NONBLOCK)) < 0)
+		fill_error(err, ERROR_UINPUT_NOT_ACCESSIBLE, "error: failed to open /dev/uinput");
+	else
+	{
+		init_relative_mouse(device, name, err);
+	}
+	return device;
+}
+
 int init_uinput_touch(const char* name, Error* err)
 {
 	int device;
 
 	if ((device = open("/dev/uinput", O_WRONLY | O_NONBLOCK)) < 0)
-		fill_error(err, 101, "error: failed to open /dev/uinput");
+		fill_error(err, ERROR_UINPUT_NOT_ACCESSIBLE, "error: failed to open /dev/uinput");
 	else
 	{
 		init_touch(device, name, err);
@@ -4,6 +4,7 @@
 
 #include <X11/extensions/XShm.h>
 #include <X11/extensions/Xcomposite.h>
+#include <X11/extensions/Xdamage.h>
 #include <X11/extensions/Xfixes.h>
 #include <stdlib.h>
 #include <string.h>
@@ -16,24 +17,57 @@
 #include "../log.h"
 #include "xhelper.h"
 
-int clamp(int x, int lb, int ub)
+// must match X11WindowCapture in src/capturable/mod.rs
+enum WindowCapture
 {
-	if (x < lb)
-		return lb;
-	if (x > ub)
-		return ub;
-	return x;
-}
+	WINDOW_CAPTURE_AUTO,
+	WINDOW_CAPTURE_COMPOSITE,
+	WINDOW_CAPTURE_CROP
+};
 
 struct CaptureContext
 {
 	Capturable cap;
+	enum WindowCapture window_capture;
 	XImage* ximg;
+	// if MIT-SHM is unavailable, for example for remote displays, images are transferred via
+	// XGetImage instead
+	int use_shm;
 	XShmSegmentInfo shminfo;
 	int has_xfixes;
+	int xfixes_event_base;
 	int has_offscreen;
+	// pixmap of the redirected window for WINDOW_CAPTURE_COMPOSITE, None until it is named
+	Pixmap pixmap;
+	unsigned int pixmap_width;
+	unsigned int pixmap_height;
+	int pixmap_viewable;
 	int wayland;
 	Bool last_img_return;
+	// XDamage is used to find out if anything changed since the last frame, damage_root tracks the
+	// screen, damage_win the captured window, which may be redirected offscreen.
+	int has_xdamage;
+	int damage_event_base;
+	Damage damage_root;
+	Damage damage_win;
+	int last_cursor_x;
+	int last_cursor_y;
+	// count of cursor changes on the display when capture_has_changed looked last
+	unsigned long last_cursor_changes;
+	// position of the area captured last on the root window
+	int x;
+	int y;
+	// cursor image converted to 32 bits per pixel, fetched again only once the cursor changed
+	uint32_t* cursor_pixels;
+	size_t cursor_pixels_size;
+	int cursor_cached;
+	// count of cursor changes on the display when the image has been fetched
+	unsigned long cursor_changes;
+	unsigned int cursor_width;
+	unsigned int cursor_height;
+	unsigned int cursor_xhot;
+	unsigned int cursor_yhot;
+	unsigned long cursor_serial;
 };
 
 typedef struct CaptureContext CaptureContext;
@@ -43,24 +77,80 @@ struct Image
 	char* data;
 	unsigned int width;
 	unsigned int height;
+	// bytes from the start of one row to the next, rows may be padded
+	unsigned int stride;
 };
 
-void* start_capture(Capturable* cap, CaptureContext* ctx, Error* err)
+struct CursorImage
 {
-	if (XShmQueryExtension(cap->disp) != True)
+	// position of the top left corner of the image relative to the captured area
+	int x;
+	int y;
+	unsigned int width;
+	unsigned int height;
+	// position of the hotspot within the image
+	unsigned int xhot;
+	unsigned int yhot;
+	// changes whenever the image does, for every frame of animated cursors too
+	unsigned long serial;
+	// premultiplied ARGB
+	const uint32_t* pixels;
+};
+
+// Create ctx->ximg backed by a shared memory segment matching the size of the capturable.
+int create_shm_image(CaptureContext* ctx, Error* err)
+{
+	Capturable* cap = &ctx->cap;
+	int x, y;
+	unsigned int width, height;
+	get_geometry(cap, &x, &y, &width, &height, err);
+	ctx->ximg = XShmCreateImage(
+		cap->disp,
+		DefaultVisualOfScreen(cap->screen),
+		DefaultDepthOfScreen(cap->screen),
+		ZPixmap,
+		NULL,
+		&ctx->shminfo,
+		width,
+		height);
+
+	ctx->shminfo.shmid =
+		shmget(IPC_PRIVATE, ctx->ximg->bytes_per_line * ctx->ximg->height, IPC_CREAT | 0777);
+	ctx->shminfo.shmaddr = ctx->ximg->data = (char*)shmat(ctx->shminfo.shmid, 0, 0);
+	ctx->shminfo.readOnly = False;
+	if (ctx->shminfo.shmid < 0)
 	{
-		fill_error(err, 1, "XShmExtension is not available but required!");
-		return NULL;
+		fill_error(err, ERROR_SHM_UNAVAILABLE, "Fatal shminfo error!");
+		return 0;
 	}
+	if (!XShmAttach(cap->disp, &ctx->shminfo))
+	{
+		fill_error(err, ERROR_SHM_UNAVAILABLE, "XShmAttach() failed");
+		return 0;
+	}
+	return 1;
+}
 
+void* start_capture(Capturable* cap, CaptureContext* ctx, int window_capture, Error* err)
+{
+	int is_new_ctx = !ctx;
 	if (!ctx)
 	{
 		ctx = malloc(sizeof(CaptureContext));
+		ctx->window_capture = window_capture;
+		ctx->pixmap = None;
+		ctx->use_shm = XShmQueryExtension(cap->disp) == True;
+		if (!ctx->use_shm)
+			log_info("MIT-SHM is not available, falling back to XGetImage.");
 
 		int major, minor;
 		Bool pixmaps = False;
 		XShmQueryVersion(cap->disp, &major, &minor, &pixmaps);
-		ctx->has_offscreen = pixmaps == True;
+		// XGetImage can read from pixmaps in any case
+		ctx->has_offscreen = !ctx->use_shm || pixmaps == True;
+		// the screen under the window is all that is captured, nothing to redirect
+		if (ctx->window_capture == WINDOW_CAPTURE_CROP)
+			ctx->has_offscreen = 0;
 		if (ctx->has_offscreen && cap->type == WINDOW && cap->c.winfo.is_regular_window)
 		{
 			int event_base, error_base;
@@ -69,6 +159,11 @@ void* start_capture(Capturable* cap, CaptureContext* ctx, Error* err)
 			if (ctx->has_offscreen)
 				XCompositeRedirectWindow(cap->disp, cap->c.winfo.win, False);
 		}
+		if (ctx->window_capture == WINDOW_CAPTURE_COMPOSITE && !ctx->has_offscreen &&
+			cap->type == WINDOW && cap->c.winfo.is_regular_window)
+			log_warn(
+				"Xcomposite is unavailable, capturing the screen under the window '%s' instead.",
+				cap->name);
 		const char* session_type = getenv("XDG_SESSION_TYPE");
 		if (session_type && strcmp(session_type, "wayland") == 0)
 			ctx->wayland = 1;
@@ -81,71 +176,243 @@ void* start_capture(Capturable* cap, CaptureContext* ctx, Error* err)
 	if (&ctx->cap != cap)
 		strncpy(ctx->cap.name, cap->name, sizeof(ctx->cap.name));
 
-	int event_base, error_base;
-	ctx->has_xfixes = XFixesQueryExtension(cap->disp, &event_base, &error_base) == True;
-
-	int x, y;
-	unsigned int width, height;
-	get_geometry(cap, &x, &y, &width, &height, err);
-	ctx->ximg = XShmCreateImage(
-		cap->disp,
-		DefaultVisualOfScreen(cap->screen),
-		DefaultDepthOfScreen(cap->screen),
-		ZPixmap,
-		NULL,
-		&ctx->shminfo,
-		width,
-		height);
+	int error_base;
+	ctx->has_xfixes =
+		XFixesQueryExtension(cap->disp, &ctx->xfixes_event_base, &error_base) == True;
 
-	ctx->shminfo.shmid =
-		shmget(IPC_PRIVATE, ctx->ximg->bytes_per_line * ctx->ximg->height, IPC_CREAT | 0777);
-	ctx->shminfo.shmaddr = ctx->ximg->data = (char*)shmat(ctx->shminfo.shmid, 0, 0);
-	ctx->shminfo.readOnly = False;
-	if (ctx->shminfo.shmid < 0)
+	if (!ctx->use_shm)
+		// images are allocated by XGetImage on every capture
+		ctx->ximg = NULL;
+	else if (!create_shm_image(ctx, err))
 	{
-		fill_error(err, 1, "Fatal shminfo error!");
 		free(ctx);
 		return NULL;
 	}
-	if (!XShmAttach(cap->disp, &ctx->shminfo))
+
+	if (is_new_ctx)
 	{
-		fill_error(err, 1, "XShmAttach() failed");
-		free(ctx);
-		return NULL;
+		int damage_error_base;
+		ctx->has_xdamage =
+			XDamageQueryExtension(cap->disp, &ctx->damage_event_base, &damage_error_base) == True;
+		ctx->damage_root = None;
+		ctx->damage_win = None;
+		ctx->last_cursor_x = -1;
+		ctx->last_cursor_y = -1;
+		ctx->last_cursor_changes = 0;
+		ctx->x = 0;
+		ctx->y = 0;
+		ctx->cursor_pixels = NULL;
+		ctx->cursor_pixels_size = 0;
+		ctx->cursor_cached = 0;
+		if (ctx->has_xdamage)
+		{
+			ctx->damage_root =
+				XDamageCreate(cap->disp, DefaultRootWindow(cap->disp), XDamageReportNonEmpty);
+			if (cap->type == WINDOW)
+				ctx->damage_win = XDamageCreate(cap->disp, cap->c.winfo.win, XDamageReportNonEmpty);
+		}
+		else
+			log_debug("XDamage is not available, every frame will be encoded.");
 	}
 
 	return ctx;
 }
 
+int capture_uses_shm(CaptureContext* ctx) { return ctx->use_shm; }
+
 void stop_capture(CaptureContext* ctx, Error* err)
 {
-	XShmDetach(ctx->cap.disp, &ctx->shminfo);
-	XDestroyImage(ctx->ximg);
-	if (shmdt(ctx->shminfo.shmaddr) != 0)
+	if (ctx->use_shm)
 	{
-		fill_error(err, 1, "Failed to detach shared memory!");
+		XShmDetach(ctx->cap.disp, &ctx->shminfo);
+		XDestroyImage(ctx->ximg);
+		if (shmdt(ctx->shminfo.shmaddr) != 0)
+		{
+			fill_error(err, 1, "Failed to detach shared memory!");
+		}
+		shmctl(ctx->shminfo.shmid, IPC_RMID, NULL);
 	}
-	shmctl(ctx->shminfo.shmid, IPC_RMID, NULL);
+	else if (ctx->ximg)
+		XDestroyImage(ctx->ximg);
+	if (ctx->pixmap != None)
+		XFreePixmap(ctx->cap.disp, ctx->pixmap);
 	if (ctx->has_offscreen && ctx->cap.type == WINDOW && ctx->cap.c.winfo.is_regular_window)
 		XCompositeUnredirectWindow(ctx->cap.disp, ctx->cap.c.winfo.win, False);
+	if (ctx->damage_root != None)
+		XDamageDestroy(ctx->cap.disp, ctx->damage_root);
+	if (ctx->damage_win != None)
+		XDamageDestroy(ctx->cap.disp, ctx->damage_win);
+	free(ctx->cursor_pixels);
 	free(ctx);
 }
 
-void capture_screen(CaptureContext* ctx, struct Image* img, int capture_cursor, Error* err)
+// Fetch and reset the damage accumulated by damage, returns 1 if any of it lies within the
+// rectangle x, y, width, height or if width is 0.
+int take_damage(
+	Display* disp, Damage damage, int x, int y, unsigned int width, unsigned int height)
+{
+	XserverRegion region = XFixesCreateRegion(disp, NULL, 0);
+	XDamageSubtract(disp, damage, None, region);
+	int num_rects = 0;
+	XRectangle* rects = XFixesFetchRegion(disp, region, &num_rects);
+	XFixesDestroyRegion(disp, region);
+	int damaged = 0;
+	for (int i = 0; i < num_rects && !damaged; ++i)
+	{
+		damaged = width == 0 ||
+				  (rects[i].x < x + (int)width && rects[i].x + rects[i].width > x &&
+				   rects[i].y < y + (int)height && rects[i].y + rects[i].height > y);
+	}
+	if (rects)
+		XFree(rects);
+	return damaged;
+}
+
+// Select the DisplayCursorNotify events that tell when the cursor changed its shape or stop
+// selecting them, all captures on a display share the selection.
+void watch_cursor(Display* disp, int watch)
+{
+	int event_base, error_base;
+	if (XFixesQueryExtension(disp, &event_base, &error_base) == True)
+		XFixesSelectCursorInput(
+			disp, DefaultRootWindow(disp), watch ? XFixesDisplayCursorNotifyMask : 0);
+}
+
+// Add the DisplayCursorNotify events that arrived to changes. The events are taken from the queue
+// of the display, so all captures on it have to share changes.
+static void count_cursor_changes(CaptureContext* ctx, unsigned long* changes)
+{
+	XEvent ev;
+	while (XCheckTypedEvent(ctx->cap.disp, ctx->xfixes_event_base + XFixesCursorNotify, &ev))
+		++*changes;
+}
+
+// Position of the hotspot of the cursor on the root window, returns 0 if it is on another screen.
+// Events about changes of the cursor that happened before are in the queue once this returns.
+static int query_pointer(CaptureContext* ctx, int* x, int* y)
+{
+	Window root, child;
+	int win_x, win_y;
+	unsigned int mask;
+	return XQueryPointer(
+		ctx->cap.disp, DefaultRootWindow(ctx->cap.disp), &root, &child, x, y, &win_x, &win_y,
+		&mask);
+}
+
+int capture_has_changed(CaptureContext* ctx, int capture_cursor, unsigned long* cursor_changes,
+	Error* err)
+{
+	if (!ctx->has_xdamage)
+		return 1;
+
+	// the damage objects are queried directly, the notify events are not needed but still have to
+	// be removed from the queue
+	XEvent ev;
+	while (XCheckTypedEvent(ctx->cap.disp, ctx->damage_event_base + XDamageNotify, &ev))
+		;
+
+	int x, y;
+	unsigned int width, height;
+	get_geometry(&ctx->cap, &x, &y, &width, &height, err);
+	if (err->code)
+		return 1;
+
+	// always take damage from both objects, to not keep stale damage around for the next frame
+	int changed = take_damage(ctx->cap.disp, ctx->damage_root, x, y, width, height);
+	if (ctx->damage_win != None)
+		changed |= take_damage(ctx->cap.disp, ctx->damage_win, 0, 0, 0, 0);
+
+	// moving the cursor or changing its shape does not cause any damage, every frame of animated
+	// cursors counts as a change too
+	int cursor_x, cursor_y;
+	if (capture_cursor && ctx->has_xfixes && query_pointer(ctx, &cursor_x, &cursor_y))
+	{
+		count_cursor_changes(ctx, cursor_changes);
+		changed |= cursor_x != ctx->last_cursor_x || cursor_y != ctx->last_cursor_y ||
+				   *cursor_changes != ctx->last_cursor_changes;
+		ctx->last_cursor_x = cursor_x;
+		ctx->last_cursor_y = cursor_y;
+		ctx->last_cursor_changes = *cursor_changes;
+	}
+
+	return changed;
+}
+
+// Copy the given area of drawable into ctx->ximg.
+Bool get_image(
+	CaptureContext* ctx, Drawable d, int x, int y, unsigned int width, unsigned int height)
+{
+	if (ctx->use_shm)
+		return XShmGetImage(ctx->cap.disp, d, ctx->ximg, x, y, 0x00ffffff);
+
+	XImage* ximg = XGetImage(ctx->cap.disp, d, x, y, width, height, 0x00ffffff, ZPixmap);
+	if (!ximg)
+		return False;
+	if (ctx->ximg)
+		XDestroyImage(ctx->ximg);
+	ctx->ximg = ximg;
+	return True;
+}
+
+// Copy the window from its pixmap, which shows it even if it is covered by other windows. The
+// pixmap keeps the contents of the window while it is unmapped, for example on another workspace,
+// but is replaced by the X server once the window is resized or mapped again, so it is named again
+// whenever that happened or reading from it fails.
+Bool get_window_pixmap_image(CaptureContext* ctx, unsigned int width, unsigned int height)
+{
+	XWindowAttributes attr;
+	int viewable = XGetWindowAttributes(ctx->cap.disp, ctx->cap.c.winfo.win, &attr) &&
+				   attr.map_state == IsViewable;
+	for (int attempt = 0; attempt < 2; ++attempt)
+	{
+		if (ctx->pixmap != None && viewable &&
+			(!ctx->pixmap_viewable || ctx->pixmap_width != width || ctx->pixmap_height != height))
+		{
+			XFreePixmap(ctx->cap.disp, ctx->pixmap);
+			ctx->pixmap = None;
+		}
+		if (ctx->pixmap == None)
+		{
+			ctx->pixmap = XCompositeNameWindowPixmap(ctx->cap.disp, ctx->cap.c.winfo.win);
+			ctx->pixmap_width = width;
+			ctx->pixmap_height = height;
+			ctx->pixmap_viewable = viewable;
+		}
+		// an unmapped window keeps showing what its old pixmap holds, which may be smaller
+		if (get_image(
+				ctx,
+				ctx->pixmap,
+				0,
+				0,
+				width < ctx->pixmap_width ? width : ctx->pixmap_width,
+				height < ctx->pixmap_height ? height : ctx->pixmap_height))
+			return True;
+		XFreePixmap(ctx->cap.disp, ctx->pixmap);
+		ctx->pixmap = None;
+		// the error of the invalid pixmap is dealt with by naming it again
+		x11_trap_errors(ctx->cap.disp);
+	}
+	return False;
+}
+
+void capture_screen_untrapped(CaptureContext* ctx, struct Image* img, Error* err)
 {
 	Window root = DefaultRootWindow(ctx->cap.disp);
 	int x, y;
 	unsigned int width, height;
 	get_geometry(&ctx->cap, &x, &y, &width, &height, err);
 	OK_OR_ABORT(err);
+	ctx->x = x;
+	ctx->y = y;
 	// if window resized, create new cap...
-	if (width != (unsigned int)ctx->ximg->width || height != (unsigned int)ctx->ximg->height)
+	if (ctx->use_shm &&
+		(width != (unsigned int)ctx->ximg->width || height != (unsigned int)ctx->ximg->height))
 	{
 		XShmDetach(ctx->cap.disp, &ctx->shminfo);
 		XDestroyImage(ctx->ximg);
 		shmdt(ctx->shminfo.shmaddr);
 		shmctl(ctx->shminfo.shmid, IPC_RMID, NULL);
-		CaptureContext* new_ctx = start_capture(&ctx->cap, ctx, err);
+		CaptureContext* new_ctx = start_capture(&ctx->cap, ctx, ctx->window_capture, err);
 		if (!new_ctx)
 		{
 			return;
@@ -165,14 +432,30 @@ void capture_screen(CaptureContext* ctx, struct Image* img, int capture_cursor,
 						   (x < 0 || y < 0 || x + (int)width > ctx->cap.screen->width ||
 							y + (int)height > ctx->cap.screen->height);
 
+		if (ctx->cap.c.winfo.is_regular_window && ctx->window_capture != WINDOW_CAPTURE_AUTO)
+		{
+			if (ctx->has_offscreen)
+				get_img_ret = get_window_pixmap_image(ctx, width, height);
+			else if (is_offscreen)
+				ERROR(
+					err,
+					1,
+					"Can not capture window as it is off screen and only the screen under it is "
+					"captured!");
+			else
+				get_img_ret = get_image(ctx, root, x, y, width, height);
+			break;
+		}
+
 		active_window =
 			(Window*)get_property(ctx->cap.disp, root, XA_WINDOW, "_NET_ACTIVE_WINDOW", &size, err);
-		if (!ctx->wayland && *active_window == ctx->cap.c.winfo.win && !is_offscreen)
+		if (!ctx->wayland && active_window && *active_window == ctx->cap.c.winfo.win &&
+			!is_offscreen)
 		{
 			// cap window within its root so menus are visible as strictly speaking menus do not
 			// belong to the window itself ...
 			// But don't do this on (X)Wayland as the root window is just black in that case.
-			get_img_ret = XShmGetImage(ctx->cap.disp, root, ctx->ximg, x, y, 0x00ffffff);
+			get_img_ret = get_image(ctx, root, x, y, width, height);
 		}
 		else
 		{
@@ -183,11 +466,7 @@ void capture_screen(CaptureContext* ctx, struct Image* img, int capture_cursor,
 			if (is_offscreen)
 			{
 				if (ctx->has_offscreen)
-				{
-					Pixmap pm = XCompositeNameWindowPixmap(ctx->cap.disp, ctx->cap.c.winfo.win);
-					get_img_ret = XShmGetImage(ctx->cap.disp, pm, ctx->ximg, 0, 0, 0x00ffffff);
-					XFreePixmap(ctx->cap.disp, pm);
-				}
+					get_img_ret = get_window_pixmap_image(ctx, width, height);
 				else
 					ERROR(
 						err,
@@ -196,14 +475,13 @@ void capture_screen(CaptureContext* ctx, struct Image* img, int capture_cursor,
 						"unavailable!");
 			}
 			else
-				get_img_ret =
-					XShmGetImage(ctx->cap.disp, ctx->cap.c.winfo.win, ctx->ximg, 0, 0, 0x00ffffff);
+				get_img_ret = get_image(ctx, ctx->cap.c.winfo.win, 0, 0, width, height);
 		}
 		free(active_window);
 		break;
 	}
 	case RECT:
-		get_img_ret = XShmGetImage(ctx->cap.disp, root, ctx->ximg, x, y, 0x00ffffff);
+		get_img_ret = get_image(ctx, root, x, y, width, height);
 		break;
 	}
 
@@ -215,66 +493,95 @@ void capture_screen(CaptureContext* ctx, struct Image* img, int capture_cursor,
 	{
 		if (last_img_return != get_img_ret)
 		{
-			ERROR(err, 1, "XShmGetImage failed!");
+			ERROR(err, 1, "%s failed!", ctx->use_shm ? "XShmGetImage" : "XGetImage");
 		}
 		else
 		{
-			ERROR(err, 2, "XShmGetImage failed!");
+			ERROR(
+				err,
+				ERROR_CAPTURE_FAILED_AGAIN,
+				"%s failed!",
+				ctx->use_shm ? "XShmGetImage" : "XGetImage");
 		}
 	}
 
-	// capture cursor if requested and if XFixes is available
-	if (capture_cursor && ctx->has_xfixes)
+	img->width = ctx->ximg->width;
+	img->height = ctx->ximg->height;
+	img->stride = ctx->ximg->bytes_per_line;
+	img->data = ctx->ximg->data;
+}
+
+void capture_screen(CaptureContext* ctx, struct Image* img, Error* err)
+{
+	// a window that is closed while being captured only fails the requests with an X11 error
+	x11_trap_errors(ctx->cap.disp);
+	capture_screen_untrapped(ctx, img, err);
+	x11_untrap_errors(ctx->cap.disp, err);
+}
+
+// Fetch the image of the cursor into ctx, returns 0 if it could not be obtained.
+static int fetch_cursor_image(CaptureContext* ctx)
+{
+	XFixesCursorImage* cursor_img = XFixesGetCursorImage(ctx->cap.disp);
+	if (!cursor_img)
 	{
-		XFixesCursorImage* cursor_img = XFixesGetCursorImage(ctx->cap.disp);
-		if (cursor_img != NULL)
-		{
-			uint32_t* data = (uint32_t*)ctx->ximg->data;
-
-			// coordinates of cursor inside ximg
-			int x0 = cursor_img->x - cursor_img->xhot - x;
-			int y0 = cursor_img->y - cursor_img->yhot - y;
-
-			// clamp part of cursor image to draw to the part of the cursor that is inside
-			// the captured area
-			int i0 = clamp(0, -x0, width - x0);
-			int i1 = clamp(cursor_img->width, -x0, width - x0);
-			int j0 = clamp(0, -y0, height - y0);
-			int j1 = clamp(cursor_img->height, -y0, height - y0);
-			// paint cursor image into captured image
-			for (int j = j0; j < j1; ++j)
-				for (int i = i0; i < i1; ++i)
-				{
-					uint32_t c_pixel = cursor_img->pixels[j * cursor_img->width + i];
-					unsigned char a = (c_pixel & 0xff000000) >> 24;
-					if (a)
-					{
-						uint32_t d_pixel = data[(j + y0) * width + i + x0];
-
-						unsigned char c1 = (c_pixel & 0x00ff0000) >> 16;
-						unsigned char c2 = (c_pixel & 0x0000ff00) >> 8;
-						unsigned char c3 = (c_pixel & 0x000000ff) >> 0;
-						unsigned char d1 = (d_pixel & 0x00ff0000) >> 16;
-						unsigned char d2 = (d_pixel & 0x0000ff00) >> 8;
-						unsigned char d3 = (d_pixel & 0x000000ff) >> 0;
-						// colors from the cursor image are premultiplied with the alpha channel
-						unsigned char f1 = c1 + d1 * (255 - a) / 255;
-						unsigned char f2 = c2 + d2 * (255 - a) / 255;
-						unsigned char f3 = c3 + d3 * (255 - a) / 255;
-						data[(j + y0) * width + i + x0] = (f1 << 16) | (f2 << 8) | (f3 << 0);
-					}
-				}
+		log_warn("Failed to obtain cursor image, XFixesGetCursorImage returned a null pointer.");
+		return 0;
+	}
 
-			XFree(cursor_img);
-		}
-		else
+	// XFixes hands out one pixel per unsigned long, which are 64 bits wide on most systems
+	size_t size = (size_t)cursor_img->width * cursor_img->height;
+	if (size > ctx->cursor_pixels_size)
+	{
+		uint32_t* pixels = realloc(ctx->cursor_pixels, size * sizeof(uint32_t));
+		if (!pixels)
 		{
-			log_warn(
-				"Failed to obtain cursor image, XFixesGetCursorImage has returned a null pointer.");
+			XFree(cursor_img);
+			return 0;
 		}
+		ctx->cursor_pixels = pixels;
+		ctx->cursor_pixels_size = size;
 	}
+	for (size_t i = 0; i < size; ++i)
+		ctx->cursor_pixels[i] = (uint32_t)cursor_img->pixels[i];
 
-	img->width = ctx->ximg->width;
-	img->height = ctx->ximg->height;
-	img->data = ctx->ximg->data;
+	ctx->cursor_width = cursor_img->width;
+	ctx->cursor_height = cursor_img->height;
+	ctx->cursor_xhot = cursor_img->xhot;
+	ctx->cursor_yhot = cursor_img->yhot;
+	ctx->cursor_serial = cursor_img->cursor_serial;
+	ctx->cursor_cached = 1;
+	XFree(cursor_img);
+	return 1;
+}
+
+// Fill cursor with the current cursor image, positioned relative to the area captured last, the
+// hotspot is taken into account already. The image is only fetched again if the count of cursor
+// changes on the display, see watch_cursor, differs from the one it has been fetched at. Returns 0
+// if XFixes is unavailable or the image could not be obtained. The pixels stay valid until the
+// next call.
+int get_cursor_image(CaptureContext* ctx, unsigned long* cursor_changes, struct CursorImage* cursor)
+{
+	if (!ctx->has_xfixes)
+		return 0;
+	int x, y;
+	if (!query_pointer(ctx, &x, &y))
+		return 0;
+	count_cursor_changes(ctx, cursor_changes);
+	if (!ctx->cursor_cached || ctx->cursor_changes != *cursor_changes)
+	{
+		if (!fetch_cursor_image(ctx))
+			return 0;
+		ctx->cursor_changes = *cursor_changes;
+	}
+
+	cursor->x = x - (int)ctx->cursor_xhot - ctx->x;
+	cursor->y = y - (int)ctx->cursor_yhot - ctx->y;
+	cursor->width = ctx->cursor_width;
+	cursor->height = ctx->cursor_height;
+	cursor->xhot = ctx->cursor_xhot;
+	cursor->yhot = ctx->cursor_yhot;
+	cursor->serial = ctx->cursor_serial;
+	cursor->pixels = ctx->cursor_pixels;
+	return 1;
 }
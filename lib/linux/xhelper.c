@@ -1,5 +1,6 @@
 #include <X11/X.h>
 #include <X11/Xlib.h>
+#include <X11/Xlibint.h>
 #include <X11/extensions/XInput.h>
 #include <X11/extensions/XInput2.h>
 #include <X11/extensions/Xrandr.h>
@@ -11,22 +12,67 @@
 #include "../log.h"
 #include "xhelper.h"
 
+// display whose errors are trapped by the current thread, errors are handled by the thread that
+// reads them from the connection, which is the one waiting for them in x11_untrap_errors
+static __thread Display* trap_disp = NULL;
+static __thread Error trapped_error;
+
 int x11_error_handler(Display* disp, XErrorEvent* err)
 {
 	char buf1[128], buf2[128], message_selector[64];
 	XGetErrorText(disp, err->error_code, buf1, sizeof(buf1));
 	snprintf(message_selector, sizeof(message_selector), "XRequest.%d", err->request_code);
 	XGetErrorDatabaseText(disp, "", message_selector, message_selector, buf2, sizeof(buf2));
-	log_debug("X11 error: %s: %s 0x%lx", buf1, buf2, err->resourceid);
+	if (disp == trap_disp && !trapped_error.code)
+		fill_error(&trapped_error, 1, "X11 error: %s: %s 0x%lx", buf1, buf2, err->resourceid);
+	else
+		log_debug("X11 error: %s: %s 0x%lx", buf1, buf2, err->resourceid);
+	return 0;
+}
+
+void x11_trap_errors(Display* disp)
+{
+	// errors of earlier requests are not ours
+	XSync(disp, False);
+	trapped_error.code = 0;
+	trap_disp = disp;
+}
+
+void x11_untrap_errors(Display* disp, Error* err)
+{
+	XSync(disp, False);
+	trap_disp = NULL;
+	if (trapped_error.code && !err->code)
+		*err = trapped_error;
+}
+
+int x11_io_error_handler(Display* disp)
+{
+	log_warn("Lost connection to the X server %s.", DisplayString(disp));
 	return 0;
 }
 
+// called after the IO error handler, the default one exits the process
+void x11_io_error_exit_handler(Display* disp, void* data)
+{
+	(void)disp;
+	(void)data;
+}
+
 void x11_set_error_handler() {
 	// setting an error handler is required as otherwise xlib may just exit the process, even though
 	// the error was recoverable.
 	XSetErrorHandler(x11_error_handler);
+	XSetIOErrorHandler(x11_io_error_handler);
+}
+
+void x11_watch_connection(Display* disp)
+{
+	XSetIOErrorExitHandler(disp, x11_io_error_exit_handler, NULL);
 }
 
+int x11_connection_lost(Display* disp) { return (disp->flags & XlibDisplayIOError) != 0; }
+
 int locale_to_utf8(char* src, char* dest, size_t size)
 {
 	iconv_t icd = iconv_open("UTF-8//IGNORE", "");
@@ -164,7 +210,7 @@ Window* get_client_list(Display* disp, unsigned long* size, Error* err)
 		{
 			fill_error(
 				err,
-				2,
+				ERROR_NO_WINDOW_MANAGER,
 				"Cannot get client list properties. "
 				"_NET_CLIENT_LIST: %s or _WIN_CLIENT_LIST: %s",
 				err_net.error_str,
@@ -176,48 +222,188 @@ Window* get_client_list(Display* disp, unsigned long* size, Error* err)
 	return client_list;
 }
 
-int create_capturables(Display* disp, Capturable** capturables, int* num_monitors, int size, Error* err)
+// 1 if a window manager announces itself as EWMH compliant, without one the client list windows
+// are taken from is missing or does not reflect what is on the screen
+int x11_has_ewmh_wm(Display* disp)
 {
-	if (size <= 0)
+	unsigned long size = 0;
+	Window* check = (Window*)get_property(
+		disp, DefaultRootWindow(disp), XA_WINDOW, "_NET_SUPPORTING_WM_CHECK", &size, NULL);
+	if (!check || size < sizeof(Window))
+	{
+		free(check);
 		return 0;
+	}
+	// the property is left behind on the root window if the window manager went away, its own
+	// window points to itself as long as it is running
+	Window* check_self =
+		(Window*)get_property(disp, *check, XA_WINDOW, "_NET_SUPPORTING_WM_CHECK", &size, NULL);
+	int ret = check_self && size >= sizeof(Window) && *check_self == *check;
+	free(check);
+	free(check_self);
+	return ret;
+}
+
+// The whole root window of the default screen.
+Capturable* create_desktop_capturable(Display* disp, const char* name)
+{
+	int screen = DefaultScreen(disp);
+	Capturable* c = malloc(sizeof(Capturable));
+	c->disp = disp;
+	c->screen = ScreenOfDisplay(disp, screen);
+	strncpy(c->name, name, sizeof(c->name) - 1);
+	c->name[sizeof(c->name) - 1] = '\0';
+	c->type = WINDOW;
+	c->c.winfo.win = RootWindow(disp, screen);
+	c->c.winfo.is_regular_window = 0;
+	return c;
+}
+
+// Physical size of the screen connected to output in millimeters from its EDID, which gives it in
+// centimeters, returns 0 if there is no EDID or it does not tell, like for projectors.
+static int get_edid_size(
+	Display* disp, RROutput output, unsigned int* width_mm, unsigned int* height_mm)
+{
+	Atom edid_atom = XInternAtom(disp, RR_PROPERTY_RANDR_EDID, True);
+	if (edid_atom == None)
+		return 0;
+	Atom type;
+	int format;
+	unsigned long nitems, bytes_after;
+	unsigned char* edid = NULL;
+	int status = XRRGetOutputProperty(
+		disp,
+		output,
+		edid_atom,
+		0,
+		32,
+		False,
+		False,
+		AnyPropertyType,
+		&type,
+		&format,
+		&nitems,
+		&bytes_after,
+		&edid);
+	if (status != Success || !edid)
+		return 0;
+	// bytes 21 and 22 of the base block are the maximum horizontal and vertical image size
+	int found = format == 8 && nitems >= 23 && edid[21] != 0 && edid[22] != 0;
+	if (found)
+	{
+		*width_mm = edid[21] * 10;
+		*height_mm = edid[22] * 10;
+	}
+	XFree(edid);
+	return found;
+}
+
+// Refresh rate in millihertz of the mode the crtc showing output is set to, 0 if it is disabled.
+static unsigned int get_refresh_mhz(Display* disp, XRRScreenResources* resources, RROutput output)
+{
+	XRROutputInfo* output_info = XRRGetOutputInfo(disp, resources, output);
+	if (!output_info)
+		return 0;
+	unsigned int refresh_mhz = 0;
+	XRRCrtcInfo* crtc =
+		output_info->crtc ? XRRGetCrtcInfo(disp, resources, output_info->crtc) : NULL;
+	for (int i = 0; crtc && i < resources->nmode; ++i)
+	{
+		XRRModeInfo* mode = &resources->modes[i];
+		if (mode->id != crtc->mode)
+			continue;
+		// the same as xrandr does
+		double v_total = mode->vTotal;
+		if (mode->modeFlags & RR_DoubleScan)
+			v_total *= 2;
+		if (mode->modeFlags & RR_Interlace)
+			v_total /= 2;
+		if (mode->hTotal && v_total > 0)
+			refresh_mhz = mode->dotClock * 1000.0 / (mode->hTotal * v_total) + 0.5;
+		break;
+	}
+	if (crtc)
+		XRRFreeCrtcInfo(crtc);
+	XRRFreeOutputInfo(output_info);
+	return refresh_mhz;
+}
+
+// Scale of the crtc showing the output of monitor, the size it covers on the screen divided by the
+// size of the monitor. Some X servers report monitors with the size of the mode even if the crtc
+// has a scaling transform, like the one xrandr --scale sets for fractional scaling. Stays 1 if
+// there is no crtc.
+static void get_output_scale(
+	Display* disp,
+	XRRScreenResources* resources,
+	XRRMonitorInfo* monitor,
+	float* scale_x,
+	float* scale_y)
+{
+	*scale_x = 1;
+	*scale_y = 1;
+	XRROutputInfo* output_info = XRRGetOutputInfo(disp, resources, monitor->outputs[0]);
+	if (!output_info)
+		return;
+	XRRCrtcInfo* crtc =
+		output_info->crtc ? XRRGetCrtcInfo(disp, resources, output_info->crtc) : NULL;
+	// the size of the crtc is the one after rotating and scaling
+	if (crtc && crtc->width > 0 && crtc->height > 0 && monitor->width > 0 && monitor->height > 0)
+	{
+		*scale_x = crtc->width / (float)monitor->width;
+		*scale_y = crtc->height / (float)monitor->height;
+	}
+	if (crtc)
+		XRRFreeCrtcInfo(crtc);
+	XRRFreeOutputInfo(output_info);
+}
 
+// Fills capturables with up to size capturables and returns how many there are in total, which is
+// more than size if they did not fit. Call again with a larger array in that case, the ones filled
+// in so far have to be destroyed by the caller either way.
+int create_capturables(
+	Display* disp,
+	Capturable** capturables,
+	int* num_monitors,
+	int size,
+	int with_windows,
+	Error* err)
+{
 	int screen = DefaultScreen(disp);
 	Window root = RootWindow(disp, screen);
 
 	int event_base, error_base, major, minor;
 	*num_monitors = 0;
 	XRRMonitorInfo* monitors = NULL;
+	XRRScreenResources* resources = NULL;
 	if (XRRQueryExtension(disp, &event_base, &error_base) && XRRQueryVersion(disp, &major, &minor))
 	{
 		monitors = XRRGetMonitors(disp, root, True, num_monitors);
+		resources = XRRGetScreenResourcesCurrent(disp, root);
 		if (*num_monitors < 0)
 		{
 			*num_monitors = 0;
-			fill_error(err, 2, "Failed to query monitor info via xrandr.");
+			fill_error(err, ERROR_MONITORS_UNAVAILABLE, "Failed to query monitor info via xrandr.");
 		}
 	}
 	else
 	{
-		fill_error(err, 2, "Xrandr is unsupported on this X server.");
+		fill_error(err, ERROR_MONITORS_UNAVAILABLE, "Xrandr is unsupported on this X server.");
 	}
 
-	Window* client_list;
-	unsigned long client_list_size;
+	Window* client_list = NULL;
+	unsigned long client_list_size = 0;
+	if (with_windows)
+		client_list = get_client_list(disp, &client_list_size, err);
 
-	size_t num_windows = ((client_list = get_client_list(disp, &client_list_size, err)) == NULL)
-							 ? 0
-							 : client_list_size / sizeof(Window);
+	size_t num_windows = client_list == NULL ? 0 : client_list_size / sizeof(Window);
 
+	size_t total = num_windows + *num_monitors + 1;
 	size_t i = 0;
-	Capturable* c = malloc(sizeof(Capturable));
-	capturables[i] = c;
-	c->disp = disp;
-	c->screen = ScreenOfDisplay(disp, screen);
-	strncpy(c->name, "Desktop", sizeof(c->name) - 1);
-	c->type = WINDOW;
-	c->c.winfo.win = root;
-	c->c.winfo.is_regular_window = 0;
-	++i;
+	if (size > 0)
+	{
+		capturables[i] = create_desktop_capturable(disp, "Desktop");
+		++i;
+	}
 
 	for (; i < (size_t)*num_monitors + 1 && i < (size_t)size; ++i)
 	{
@@ -226,17 +412,61 @@ int create_capturables(Display* disp, Capturable** capturables, int* num_monitor
 		XRRMonitorInfo* m = &monitors[i - 1];
 		c->disp = disp;
 		c->screen = ScreenOfDisplay(disp, screen);
-		char* name = XGetAtomName(disp, m->name);
-		snprintf(c->name, sizeof(c->name) - 1, "Monitor: %s", name);
-		XFree(name);
 		c->type = RECT;
+		c->c.rinfo.output[0] = '\0';
+		c->c.rinfo.refresh_mhz = 0;
+		c->c.rinfo.scale_x = 1;
+		c->c.rinfo.scale_y = 1;
+		if (m->noutput == 1 && resources)
+		{
+			XRROutputInfo* output_info = XRRGetOutputInfo(disp, resources, m->outputs[0]);
+			if (output_info)
+			{
+				snprintf(c->c.rinfo.output, sizeof(c->c.rinfo.output), "%s", output_info->name);
+				XRRFreeOutputInfo(output_info);
+			}
+			c->c.rinfo.refresh_mhz = get_refresh_mhz(disp, resources, m->outputs[0]);
+			get_output_scale(disp, resources, m, &c->c.rinfo.scale_x, &c->c.rinfo.scale_y);
+		}
+		if (c->c.rinfo.output[0] == '\0')
+		{
+			char* name = XGetAtomName(disp, m->name);
+			snprintf(c->c.rinfo.output, sizeof(c->c.rinfo.output), "%s", name);
+			XFree(name);
+		}
+		// like HDMI-A-2 (2560×1440 @ +1920+0), the name changes along with the mode
+		snprintf(
+			c->name,
+			sizeof(c->name) - 1,
+			"%s (%d\xc3\x97%d @ %+d%+d)",
+			c->c.rinfo.output,
+			m->width,
+			m->height,
+			m->x,
+			m->y);
 		c->c.rinfo.x = m->x;
 		c->c.rinfo.y = m->y;
 		c->c.rinfo.width = m->width;
 		c->c.rinfo.height = m->height;
+		c->c.rinfo.width_mm = 0;
+		c->c.rinfo.height_mm = 0;
+		if (m->mwidth > 0 && m->mheight > 0)
+		{
+			c->c.rinfo.width_mm = m->mwidth;
+			c->c.rinfo.height_mm = m->mheight;
+		}
+		else if (m->noutput > 0)
+			get_edid_size(disp, m->outputs[0], &c->c.rinfo.width_mm, &c->c.rinfo.height_mm);
+		// the EDID and some drivers give the size of the unrotated screen
+		if ((m->width > m->height) != (c->c.rinfo.width_mm > c->c.rinfo.height_mm))
+		{
+			unsigned int width_mm = c->c.rinfo.width_mm;
+			c->c.rinfo.width_mm = c->c.rinfo.height_mm;
+			c->c.rinfo.height_mm = width_mm;
+		}
 	}
 
-	for (; i < num_windows + *num_monitors + 1 && i < (size_t)size; ++i)
+	for (; i < total && i < (size_t)size; ++i)
 	{
 		size_t j = i - *num_monitors - 1;
 		char* title_utf8 = get_window_title(disp, client_list[j], NULL);
@@ -258,7 +488,9 @@ int create_capturables(Display* disp, Capturable** capturables, int* num_monitor
 	}
 	free(client_list);
 	XRRFreeMonitors(monitors);
-	return i;
+	if (resources)
+		XRRFreeScreenResources(resources);
+	return total;
 }
 
 void* clone_capturable(Capturable* c)
@@ -283,11 +515,15 @@ void get_window_geometry(
 	Window junkroot;
 	int junkx, junky;
 	unsigned int bw, depth;
+	// most likely the window has been closed if either fails
 	if (!XGetGeometry(disp, win, &junkroot, &junkx, &junky, width, height, &bw, &depth))
 	{
-		ERROR(err, 1, "Failed to get window geometry!");
+		ERROR(err, ERROR_WINDOW_GONE, "Failed to get window geometry!");
+	}
+	if (!XTranslateCoordinates(disp, win, junkroot, 0, 0, x, y, &junkroot))
+	{
+		ERROR(err, ERROR_WINDOW_GONE, "Failed to get window position!");
 	}
-	XTranslateCoordinates(disp, win, junkroot, 0, 0, x, y, &junkroot);
 }
 
 void get_geometry(
@@ -308,7 +544,14 @@ void get_geometry(
 }
 
 void get_geometry_relative(
-	Capturable* cap, float* x, float* y, float* width, float* height, Error* err)
+	Capturable* cap,
+	float* x,
+	float* y,
+	float* width,
+	float* height,
+	float* scale_x,
+	float* scale_y,
+	Error* err)
 {
 	int x_tmp, y_tmp;
 	unsigned int width_tmp, height_tmp;
@@ -318,6 +561,8 @@ void get_geometry_relative(
 	*y = y_tmp / (float)cap->screen->height;
 	*width = width_tmp / (float)cap->screen->width;
 	*height = height_tmp / (float)cap->screen->height;
+	*scale_x = cap->type == RECT ? cap->c.rinfo.scale_x : 1;
+	*scale_y = cap->type == RECT ? cap->c.rinfo.scale_y : 1;
 }
 
 void client_msg(
@@ -363,13 +608,15 @@ void activate_window(Display* disp, WindowInfo* winfo, Error* err)
 
 	active_window = (Window*)get_property(
 		disp, DefaultRootWindow(disp), XA_WINDOW, "_NET_ACTIVE_WINDOW", &size, err);
-	if (*active_window == winfo->win)
+	if (active_window && *active_window == winfo->win)
 	{
 		// nothing to do window is active already
 		free(active_window);
 		return;
 	}
 	free(active_window);
+	// not knowing the active window is no reason not to activate this one
+	err->code = 0;
 
 	unsigned long* desktop;
 	/* desktop ID */
@@ -396,47 +643,337 @@ void capturable_before_input(Capturable* cap, Error* err)
 	switch (cap->type)
 	{
 	case WINDOW:
+		// the window may have been closed, which only shows up as error some time later
+		x11_trap_errors(cap->disp);
 		activate_window(cap->disp, &cap->c.winfo, err);
+		x11_untrap_errors(cap->disp, err);
 		break;
 	case RECT:
 		break;
 	}
 }
 
+// Innermost window that shows the point x, y given in coordinates of win, which are translated into
+// its coordinates. A real event would have been delivered to it.
+static Window window_at(Display* disp, Window win, int* x, int* y)
+{
+	Window child;
+	int child_x, child_y;
+	while (XTranslateCoordinates(disp, win, win, *x, *y, &child_x, &child_y, &child) &&
+		   child != None)
+	{
+		Window junk;
+		if (!XTranslateCoordinates(disp, win, child, *x, *y, &child_x, &child_y, &junk))
+			break;
+		win = child;
+		*x = child_x;
+		*y = child_y;
+	}
+	return win;
+}
+
+// Send a synthetic pointer event to the window of the capturable without raising or focusing it,
+// at x, y relative to the size of the window. button is 0 for motion, otherwise it is pressed or
+// released. state is the mask of modifiers and buttons that are down, like for real events.
+void send_window_pointer_event(
+	Capturable* cap,
+	float x,
+	float y,
+	unsigned int button,
+	int pressed,
+	unsigned int state,
+	Error* err)
+{
+	if (cap->type != WINDOW || !cap->c.winfo.is_regular_window)
+		ERROR(err, 1, "Synthetic input can only be sent to windows.");
+	int win_x, win_y;
+	unsigned int width, height;
+	x11_trap_errors(cap->disp);
+	get_window_geometry(cap->disp, cap->c.winfo.win, &win_x, &win_y, &width, &height, err);
+	if (err->code)
+	{
+		x11_untrap_errors(cap->disp, err);
+		return;
+	}
+	int px = x * width;
+	int py = y * height;
+	XEvent ev;
+	memset(&ev, 0, sizeof(ev));
+	// the fields of motion and button events are laid out alike up to state
+	ev.xbutton.display = cap->disp;
+	ev.xbutton.root = DefaultRootWindow(cap->disp);
+	ev.xbutton.x_root = win_x + px;
+	ev.xbutton.y_root = win_y + py;
+	ev.xbutton.window = window_at(cap->disp, cap->c.winfo.win, &px, &py);
+	ev.xbutton.x = px;
+	ev.xbutton.y = py;
+	ev.xbutton.time = CurrentTime;
+	ev.xbutton.state = state;
+	long mask;
+	if (button == 0)
+	{
+		ev.type = MotionNotify;
+		ev.xmotion.is_hint = NotifyNormal;
+		ev.xmotion.same_screen = True;
+		mask = PointerMotionMask | ButtonMotionMask;
+	}
+	else
+	{
+		ev.type = pressed ? ButtonPress : ButtonRelease;
+		ev.xbutton.button = button;
+		ev.xbutton.same_screen = True;
+		mask = pressed ? ButtonPressMask : ButtonReleaseMask;
+	}
+	XSendEvent(cap->disp, ev.xbutton.window, True, mask, &ev);
+	x11_untrap_errors(cap->disp, err);
+}
+
+// Send a synthetic key event with the X keycode to the window of the capturable without raising or
+// focusing it. state is the mask of modifiers and buttons that are down.
+void send_window_key_event(
+	Capturable* cap, unsigned int keycode, int pressed, unsigned int state, Error* err)
+{
+	if (cap->type != WINDOW || !cap->c.winfo.is_regular_window)
+		ERROR(err, 1, "Synthetic input can only be sent to windows.");
+	XEvent ev;
+	memset(&ev, 0, sizeof(ev));
+	ev.type = pressed ? KeyPress : KeyRelease;
+	ev.xkey.display = cap->disp;
+	ev.xkey.window = cap->c.winfo.win;
+	ev.xkey.root = DefaultRootWindow(cap->disp);
+	ev.xkey.time = CurrentTime;
+	ev.xkey.same_screen = True;
+	ev.xkey.keycode = keycode;
+	ev.xkey.state = state;
+	x11_trap_errors(cap->disp);
+	XSendEvent(cap->disp, cap->c.winfo.win, True, pressed ? KeyPressMask : KeyReleaseMask, &ev);
+	x11_untrap_errors(cap->disp, err);
+}
+
+int capturable_is_window(Capturable* c)
+{
+	return c->type == WINDOW && c->c.winfo.is_regular_window;
+}
+
 const char* get_capturable_name(Capturable* c) { return c->name; }
 
-void map_input_device_to_entire_screen(Display* disp, const char* device_name, int pen, Error* err)
+Window get_capturable_window(Capturable* c)
+{
+	switch (c->type)
+	{
+	case WINDOW:
+		return c->c.winfo.win;
+	case RECT:
+		return 0;
+	}
+	return 0;
+}
+
+// Size of the capturable in pixels and millimeters, returns 0 if the physical size is unknown,
+// which it is for windows and the desktop, only monitors have one.
+int get_capturable_physical_size(
+	Capturable* c,
+	unsigned int* width,
+	unsigned int* height,
+	unsigned int* width_mm,
+	unsigned int* height_mm)
+{
+	if (c->type != RECT || c->c.rinfo.width_mm == 0 || c->c.rinfo.height_mm == 0)
+		return 0;
+	*width = c->c.rinfo.width;
+	*height = c->c.rinfo.height;
+	*width_mm = c->c.rinfo.width_mm;
+	*height_mm = c->c.rinfo.height_mm;
+	return 1;
+}
+
+// Output, position and size of the current mode of a monitor, returns 0 for windows. output stays
+// valid as long as c does.
+int get_capturable_monitor(
+	Capturable* c,
+	const char** output,
+	int* x,
+	int* y,
+	unsigned int* width,
+	unsigned int* height,
+	unsigned int* refresh_mhz)
+{
+	if (c->type != RECT)
+		return 0;
+	*output = c->c.rinfo.output;
+	*x = c->c.rinfo.x;
+	*y = c->c.rinfo.y;
+	*width = c->c.rinfo.width;
+	*height = c->c.rinfo.height;
+	*refresh_mhz = c->c.rinfo.refresh_mhz;
+	return 1;
+}
+
+// Copy the class from WM_CLASS of the window into class, returns 0 if there is none.
+int get_capturable_class(Capturable* c, char* class, size_t size)
+{
+	if (c->type != WINDOW || !c->c.winfo.is_regular_window || size == 0)
+		return 0;
+	XClassHint hint;
+	if (!XGetClassHint(c->disp, c->c.winfo.win, &hint))
+		return 0;
+	int found = hint.res_class != NULL;
+	if (found)
+		snprintf(class, size, "%s", hint.res_class);
+	XFree(hint.res_name);
+	XFree(hint.res_class);
+	return found;
+}
+
+// Process id from _NET_WM_PID of the window or -1 if it is unknown.
+long get_capturable_pid(Capturable* c)
+{
+	if (c->type != WINDOW || !c->c.winfo.is_regular_window)
+		return -1;
+	Error err;
+	unsigned long size;
+	unsigned long* pid = (unsigned long*)get_property(
+		c->disp, c->c.winfo.win, XA_CARDINAL, "_NET_WM_PID", &size, &err);
+	if (pid == NULL)
+		return -1;
+	long ret = size >= sizeof(unsigned long) ? (long)*pid : -1;
+	free(pid);
+	return ret;
+}
+
+// Returns 1 for override-redirect windows and windows that are docks, panels, menus, tooltips and
+// the like according to _NET_WM_WINDOW_TYPE, which are hardly worth capturing.
+int capturable_is_utility_window(Capturable* c)
+{
+	if (c->type != WINDOW || !c->c.winfo.is_regular_window)
+		return 0;
+
+	XWindowAttributes attr;
+	if (XGetWindowAttributes(c->disp, c->c.winfo.win, &attr) && attr.override_redirect)
+		return 1;
+
+	Error err;
+	unsigned long size;
+	Atom* types =
+		(Atom*)get_property(c->disp, c->c.winfo.win, XA_ATOM, "_NET_WM_WINDOW_TYPE", &size, &err);
+	if (types == NULL)
+		return 0;
+
+	static char* utility_types[] = {
+		"_NET_WM_WINDOW_TYPE_DESKTOP",
+		"_NET_WM_WINDOW_TYPE_DOCK",
+		"_NET_WM_WINDOW_TYPE_TOOLBAR",
+		"_NET_WM_WINDOW_TYPE_MENU",
+		"_NET_WM_WINDOW_TYPE_SPLASH",
+		"_NET_WM_WINDOW_TYPE_DROPDOWN_MENU",
+		"_NET_WM_WINDOW_TYPE_POPUP_MENU",
+		"_NET_WM_WINDOW_TYPE_TOOLTIP",
+		"_NET_WM_WINDOW_TYPE_NOTIFICATION",
+		"_NET_WM_WINDOW_TYPE_COMBO",
+		"_NET_WM_WINDOW_TYPE_DND"};
+	const int num_utility_types = sizeof(utility_types) / sizeof(utility_types[0]);
+	Atom utility_atoms[sizeof(utility_types) / sizeof(utility_types[0])];
+	XInternAtoms(c->disp, utility_types, num_utility_types, False, utility_atoms);
+
+	int is_utility = 0;
+	for (size_t i = 0; i < size / sizeof(Atom) && !is_utility; ++i)
+		for (int j = 0; j < num_utility_types && !is_utility; ++j)
+			is_utility = types[i] == utility_atoms[j];
+	free(types);
+	return is_utility;
+}
+
+static int has_pressure_valuator(Display* disp, XIDeviceInfo* device)
+{
+	Atom pressure = XInternAtom(disp, "Abs Pressure", True);
+	if (pressure == None)
+		return 0;
+	for (int i = 0; i < device->num_classes; ++i)
+	{
+		XIAnyClassInfo* class = device->classes[i];
+		if (class->type == XIValuatorClass && ((XIValuatorClassInfo*)class)->label == pressure)
+			return 1;
+	}
+	return 0;
+}
+
+static int has_touch_class(XIDeviceInfo* device)
 {
+	for (int i = 0; i < device->num_classes; ++i)
+		if (device->classes[i]->type == XITouchClass)
+			return 1;
+	return 0;
+}
 
-	// for some reason a device simualting a stylus does NOT create a single device in
-	// XListInputDevices but actually two: One with the original name and the other one with
-	// "Pen (0)" appended to it. The problem is that the original device does NOT permit setting
-	// "Coordinate Transformation Matrix". This can only be done for the device with "Pen (0)"
-	// appended. So this here is a dirty workaround assuming the configurable stylus/pen device is
-	// always called original name + "Pen" + whatever.
-	char pen_name[256];
-	if (pen)
-		snprintf(pen_name, sizeof(pen_name), "%s Pen", device_name);
-	XID device_id;
+// Fill devices with the pointing devices known to the X server, keyboards and master devices are
+// skipped. Returns the number of devices found.
+int list_input_devices(Display* disp, InputDevice* devices, int size)
+{
 	int num_devices = 0;
-	XDeviceInfo* devices = XListInputDevices(disp, &num_devices);
+	XIDeviceInfo* info = XIQueryDevice(disp, XIAllDevices, &num_devices);
+	if (!info)
+		return 0;
 
-	int found = 0;
-	for (int i = 0; i < num_devices; ++i)
+	int n = 0;
+	for (int i = 0; i < num_devices && n < size; ++i)
 	{
-		if ((!pen && strcmp(device_name, devices[i].name) == 0) ||
-			(pen && strncmp(pen_name, devices[i].name, strlen(pen_name)) == 0))
-		{
-			device_id = devices[i].id;
-			found = 1;
-			break;
-		}
+		if (info[i].use != XISlavePointer && info[i].use != XIFloatingSlave)
+			continue;
+		devices[n].id = info[i].deviceid;
+		strncpy(devices[n].name, info[i].name, sizeof(devices[n].name) - 1);
+		devices[n].name[sizeof(devices[n].name) - 1] = '\0';
+		// XI2 has no notion of a pen, but only pens report pressure
+		if (has_touch_class(&info[i]))
+			devices[n].type = INPUT_DEVICE_TOUCH;
+		else if (has_pressure_valuator(disp, &info[i]))
+			devices[n].type = INPUT_DEVICE_PEN;
+		else
+			devices[n].type = INPUT_DEVICE_POINTER;
+		++n;
 	}
-	XFreeDeviceList(devices);
+	XIFreeDeviceInfo(info);
+	return n;
+}
 
-	if (!found)
-		ERROR(err, 2, "Device with name: %s not found!", device_name);
+static Bool is_hierarchy_event(Display* disp, XEvent* event, XPointer xi_opcode)
+{
+	(void)disp;
+	return event->type == GenericEvent && event->xcookie.extension == *(int*)xi_opcode &&
+		   event->xcookie.evtype == XI_HierarchyChanged;
+}
+
+// Subscribe to the events input_devices_changed is looking for.
+void watch_input_devices(Display* disp)
+{
+	XIEventMask mask;
+	unsigned char bits[XIMaskLen(XI_HierarchyChanged)] = {0};
+	XISetMask(bits, XI_HierarchyChanged);
+	mask.deviceid = XIAllDevices;
+	mask.mask_len = sizeof(bits);
+	mask.mask = bits;
+	XISelectEvents(disp, DefaultRootWindow(disp), &mask, 1);
+}
 
+// Returns 1 if input devices have been added or removed since the last call, requires
+// watch_input_devices to be called first.
+int input_devices_changed(Display* disp)
+{
+	int xi_opcode, event, error;
+	if (!XQueryExtension(disp, "XInputExtension", &xi_opcode, &event, &error))
+		return 1;
+
+	int changed = 0;
+	XEvent ev;
+	while (XCheckIfEvent(disp, &ev, is_hierarchy_event, (XPointer)&xi_opcode))
+		changed = 1;
+	return changed;
+}
+
+// Set the Coordinate Transformation Matrix of the device, matrix holds its 9 elements row by row
+// and maps the input area of the device, scaled to 0..1, onto the root window, scaled to 0..1 as
+// well.
+void set_input_device_matrix(Display* disp, int device_id, const float* matrix, Error* err)
+{
 	Atom prop_float, prop_matrix;
 
 	union
@@ -451,6 +988,12 @@ void map_input_device_to_entire_screen(Display* disp, const char* device_name, i
 
 	int rc;
 
+	int xi_opcode, event, error;
+	if (!XQueryExtension(disp, "XInputExtension", &xi_opcode, &event, &error))
+	{
+		ERROR(err, ERROR_XINPUT_MISSING, "The X server does not support XInput.");
+	}
+
 	prop_float = XInternAtom(disp, "FLOAT", False);
 	prop_matrix = XInternAtom(disp, "Coordinate Transformation Matrix", False);
 
@@ -486,18 +1029,71 @@ void map_input_device_to_entire_screen(Display* disp, const char* device_name, i
 		ERROR(err, 1, "Failed to retrieve current property values.");
 	}
 
-	data.f[0] = 1.0;
-	data.f[1] = 0.0;
-	data.f[2] = 0.0;
-	data.f[3] = 0.0;
-	data.f[4] = 1.0;
-	data.f[5] = 0.0;
-	data.f[6] = 0.0;
-	data.f[7] = 0.0;
-	data.f[8] = 1.0;
+	for (int i = 0; i < 9; ++i)
+		data.f[i] = matrix[i];
 
 	XIChangeProperty(
 		disp, device_id, prop_matrix, prop_float, format_return, PropModeReplace, data.c, nitems);
 
 	XFree(data.c);
 }
+
+// Get the Coordinate Transformation Matrix of the device, row by row like set_input_device_matrix
+// takes it. Others may set it as well, compositors do for touchscreens and tablets they map onto
+// an output.
+void get_input_device_matrix(Display* disp, int device_id, float* matrix, Error* err)
+{
+	Atom prop_float = XInternAtom(disp, "FLOAT", True);
+	Atom prop_matrix = XInternAtom(disp, "Coordinate Transformation Matrix", True);
+	if (prop_float == None || prop_matrix == None)
+	{
+		ERROR(err, 1, "Coordinate transformation matrix not found. This server is too old.");
+	}
+
+	unsigned char* data;
+	int format_return;
+	Atom type_return;
+	unsigned long nitems;
+	unsigned long bytes_after;
+	int rc = XIGetProperty(
+		disp,
+		device_id,
+		prop_matrix,
+		0,
+		9,
+		False,
+		prop_float,
+		&type_return,
+		&format_return,
+		&nitems,
+		&bytes_after,
+		&data);
+	if (rc != Success)
+	{
+		ERROR(err, 1, "Failed to retrieve current property values.");
+	}
+	if (prop_float != type_return || format_return != 32 || nitems != 9 || bytes_after != 0)
+	{
+		XFree(data);
+		ERROR(err, 1, "Failed to retrieve current property values.");
+	}
+	for (int i = 0; i < 9; ++i)
+		matrix[i] = ((float*)data)[i];
+	XFree(data);
+}
+
+// Returns a timestamp that changes whenever the screen configuration changes, that is if the
+// resolution or rotation is changed or monitors are added or removed.
+unsigned long get_screen_config_timestamp(Display* disp)
+{
+	XRRScreenResources* resources = XRRGetScreenResourcesCurrent(disp, DefaultRootWindow(disp));
+	if (!resources)
+		return 0;
+	// timestamp is updated if the configuration is set, configTimestamp if it changes because of
+	// hotplugging
+	Time timestamp = resources->timestamp > resources->configTimestamp
+						 ? resources->timestamp
+						 : resources->configTimestamp;
+	XRRFreeScreenResources(resources);
+	return timestamp;
+}
@@ -25,6 +25,18 @@ typedef struct RectInfo
 	int y;
 	unsigned int width;
 	unsigned int height;
+	// physical size in millimeters, 0 if unknown
+	unsigned int width_mm;
+	unsigned int height_mm;
+	// RandR output showing the monitor, like HDMI-A-2, or the name of the monitor if it spans
+	// several outputs
+	char output[64];
+	// refresh rate of the current mode in millihertz, 0 if unknown
+	unsigned int refresh_mhz;
+	// size of the crtc showing the monitor divided by the size above, which differ if the crtc
+	// is scaled for fractional scaling and the size is the one of the mode, 1 if unknown
+	float scale_x;
+	float scale_y;
 } RectInfo;
 
 typedef enum CaptureType
@@ -33,6 +45,20 @@ typedef enum CaptureType
 	RECT
 } CaptureType;
 
+typedef enum InputDeviceType
+{
+	INPUT_DEVICE_POINTER,
+	INPUT_DEVICE_PEN,
+	INPUT_DEVICE_TOUCH
+} InputDeviceType;
+
+typedef struct InputDevice
+{
+	int id;
+	char name[128];
+	InputDeviceType type;
+} InputDevice;
+
 typedef struct Capturable
 {
 	CaptureType type;
@@ -46,11 +72,32 @@ typedef struct Capturable
 	} c;
 } Capturable;
 
+// Keep the process running if the connection to the X server behind disp is lost, for example
+// because the X server has been restarted. All requests on disp fail from then on, which
+// x11_connection_lost tells, and it is only good for being closed. Requires libX11 1.7 or newer.
+void x11_watch_connection(Display* disp);
+int x11_connection_lost(Display* disp);
+
+// Turn X11 errors of the requests made on disp by this thread until x11_untrap_errors into err,
+// instead of only logging them. Errors are reported asynchronously, so this waits for the X server
+// to process the requests.
+void x11_trap_errors(Display* disp);
+void x11_untrap_errors(Display* disp, Error* err);
+
 char* get_property(
 	Display* disp, Window win, Atom xa_prop_type, char* prop_name, unsigned long* size, Error* err);
 
 void get_geometry(
 	Capturable* cap, int* x, int* y, unsigned int* width, unsigned int* height, Error* err);
 
+// Like get_geometry but relative to the size of the screen, scale_x and scale_y are the scale of
+// the output showing a monitor and 1 for windows and the desktop.
 void get_geometry_relative(
-	Capturable* cap, float* x, float* y, float* width, float* height, Error* err);
+	Capturable* cap,
+	float* x,
+	float* y,
+	float* width,
+	float* height,
+	float* scale_x,
+	float* scale_y,
+	Error* err);
@@ -0,0 +1,477 @@
+use std::error::Error;
+
+use bytes::Bytes;
+use http_body_util::{Full, LengthLimitError};
+use hyper::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use hyper::{Method, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing::{debug, warn};
+
+use crate::protocol::{ClientConfiguration, ClientError, ErrorKind, MessageInbound};
+use crate::websocket::{capturable_infos, WeylusClientConfig};
+
+/// Requests with a larger body are rejected before they are read completely.
+pub const MAX_BODY_LEN: usize = 64 * 1024;
+
+type ApiResult = Result<Response<Full<Bytes>>, (StatusCode, ClientError)>;
+
+/// True if the request may use the API: there is no access code or it is sent as
+/// `Authorization: Bearer <code>` or in the query like the web client does.
+pub fn authorized(headers: &HeaderMap, query: Option<&str>, access_code: Option<&str>) -> bool {
+    let Some(access_code) = access_code else {
+        return true;
+    };
+    let bearer = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if bearer.is_some_and(|code| code.trim() == access_code) {
+        return true;
+    }
+    query.is_some_and(|query| {
+        url::form_urlencoded::parse(query.as_bytes())
+            .any(|(key, code)| key == "access_code" && code == access_code)
+    })
+}
+
+pub fn unauthorized() -> Response<Full<Bytes>> {
+    error_response(
+        StatusCode::UNAUTHORIZED,
+        ClientError::new(ErrorKind::Unauthorized, "Wrong or missing access code!"),
+    )
+}
+
+/// Answer to a request whose body could not be read completely.
+pub fn body_unreadable(err: &(dyn Error + Send + Sync)) -> Response<Full<Bytes>> {
+    if err.is::<LengthLimitError>() {
+        return error_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            ClientError::new(
+                ErrorKind::InvalidMessage,
+                format!("The body of the request is larger than {MAX_BODY_LEN} bytes!"),
+            ),
+        );
+    }
+    error_response(
+        StatusCode::BAD_REQUEST,
+        ClientError::new(
+            ErrorKind::InvalidMessage,
+            format!("Failed to read the body of the request: {err}"),
+        ),
+    )
+}
+
+/// Answer a request to path, which starts with /api, in JSON. Requests and responses are the
+/// types of the websocket protocol, so scripts can do what the web client does:
+///
+/// - `GET /api/capturables`: what clients can capture, a list of CapturableInfo, configurations
+///   sent via the API may use its ids for every client
+/// - `GET /api/clients`: the connected clients, a list of ConnectionInfo
+/// - `GET /api/clients/{id}/config`: the ClientConfiguration the client sent last
+/// - `PUT /api/clients/{id}/config`: configure the client with a whole ClientConfiguration
+/// - `PATCH /api/clients/{id}/config`: change only the fields in the JSON object sent
+/// - `PUT|PATCH /api/config`: the same for every configured client, answers with their ids
+/// - `GET /api/clients/{id}/stats`: the VideoStats measured last
+/// - `POST /api/clients/{id}/messages`: a MessageInbound, handled as if the client had sent it
+/// - `GET|PUT /api/input`: whether input from clients is enabled on the host, a JSON bool
+/// - `POST /api/input/toggle`: switch it, answers with whether it is enabled now
+///
+/// Errors are answered with a ClientError. Whatever the server sends in reply to a configuration
+/// or message goes to the client itself, its web page does not show the changes though.
+pub async fn handle(
+    method: &Method,
+    path: &str,
+    body: Bytes,
+    config: &WeylusClientConfig,
+) -> Response<Full<Bytes>> {
+    route(method, path, body, config)
+        .await
+        .unwrap_or_else(|(status, err)| {
+            debug!("API request {method} {path} failed: {}", err.message);
+            error_response(status, err)
+        })
+}
+
+async fn route(method: &Method, path: &str, body: Bytes, config: &WeylusClientConfig) -> ApiResult {
+    let segments: Vec<&str> = path
+        .trim_start_matches("/api")
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    match (method, &segments[..]) {
+        (&Method::GET, ["capturables"]) => {
+            let config = config.clone();
+            match tokio::task::spawn_blocking(move || capturable_infos(&config)).await {
+                Ok(infos) => Ok(json(&infos)),
+                Err(err) => Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    ClientError::new(
+                        ErrorKind::CaptureInitFailed,
+                        format!("Failed to list capturables: {err}"),
+                    ),
+                )),
+            }
+        }
+        (&Method::GET, ["clients"]) => Ok(json(&config.connections.list())),
+        (&Method::GET, ["clients", id, "config"]) => {
+            let id = client_id(id)?;
+            match config.connections.config(id) {
+                Some(client_config) => Ok(json(&client_config)),
+                None => Err(not_found(format!(
+                    "Client {id} has not been configured yet!"
+                ))),
+            }
+        }
+        (&Method::PUT, ["clients", id, "config"]) => {
+            let id = client_id(id)?;
+            let client_config: ClientConfiguration = parse(&body)?;
+            configure(config, id, client_config.clone()).await?;
+            Ok(json(&client_config))
+        }
+        (&Method::PATCH, ["clients", id, "config"]) => {
+            let id = client_id(id)?;
+            let patch = parse(&body)?;
+            let current = config
+                .connections
+                .config(id)
+                .ok_or_else(|| not_found(format!("Client {id} has not been configured yet!")))?;
+            let client_config = merge(&current, &patch)?;
+            configure(config, id, client_config.clone()).await?;
+            Ok(json(&client_config))
+        }
+        (&Method::PUT, ["config"]) => {
+            let client_config: ClientConfiguration = parse(&body)?;
+            let mut configured = Vec::new();
+            for id in configured_clients(config) {
+                if configure_if_connected(config, id, client_config.clone()).await? {
+                    configured.push(id);
+                }
+            }
+            Ok(json(&configured))
+        }
+        (&Method::PATCH, ["config"]) => {
+            let patch = parse(&body)?;
+            let mut configured = Vec::new();
+            for id in configured_clients(config) {
+                let Some(current) = config.connections.config(id) else {
+                    continue;
+                };
+                let client_config = merge(&current, &patch)?;
+                if configure_if_connected(config, id, client_config).await? {
+                    configured.push(id);
+                }
+            }
+            Ok(json(&configured))
+        }
+        (&Method::GET, ["clients", id, "stats"]) => {
+            let id = client_id(id)?;
+            let info = config
+                .connections
+                .list()
+                .into_iter()
+                .find(|info| info.id == id)
+                .ok_or_else(|| not_found(format!("There is no client {id}!")))?;
+            match info.stats {
+                Some(stats) => Ok(json(&stats)),
+                None => Err(not_found(format!("No stats for client {id} yet!"))),
+            }
+        }
+        (&Method::POST, ["clients", id, "messages"]) => {
+            let id = client_id(id)?;
+            let msg: MessageInbound = parse(&body)?;
+            match msg {
+                // answered by the websocket itself, the answers would go to the client
+                MessageInbound::Ping { .. }
+                | MessageInbound::Resume { .. }
+                | MessageInbound::RtcOffer { .. }
+                | MessageInbound::RtcCandidate(_) => Err((
+                    StatusCode::BAD_REQUEST,
+                    ClientError::new(
+                        ErrorKind::InvalidMessage,
+                        "Only the client itself can send this message!",
+                    ),
+                )),
+                MessageInbound::Config(client_config) => {
+                    configure(config, id, client_config).await?;
+                    Ok(no_content())
+                }
+                msg => {
+                    send(config, id, msg).await?;
+                    Ok(no_content())
+                }
+            }
+        }
+        (&Method::GET, ["input"]) => Ok(json(&config.input_switch.is_enabled())),
+        (&Method::PUT, ["input"]) => {
+            config.input_switch.set_enabled(parse(&body)?);
+            Ok(json(&config.input_switch.is_enabled()))
+        }
+        (&Method::POST, ["input", "toggle"]) => {
+            config.input_switch.toggle();
+            Ok(json(&config.input_switch.is_enabled()))
+        }
+        _ => Err(not_found(format!("There is no API for {method} {path}!"))),
+    }
+}
+
+fn json(value: &impl Serialize) -> Response<Full<Bytes>> {
+    match serde_json::to_vec(value) {
+        Ok(body) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+            .body(body.into())
+            .unwrap(),
+        Err(err) => {
+            warn!("Failed to serialize API response: {err}");
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Full::default())
+                .unwrap()
+        }
+    }
+}
+
+fn no_content() -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Full::default())
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, err: ClientError) -> Response<Full<Bytes>> {
+    let mut response = json(&err);
+    *response.status_mut() = status;
+    response
+}
+
+fn not_found(message: String) -> (StatusCode, ClientError) {
+    (
+        StatusCode::NOT_FOUND,
+        ClientError::new(ErrorKind::NotFound, message),
+    )
+}
+
+fn client_id(id: &str) -> Result<u64, (StatusCode, ClientError)> {
+    id.parse()
+        .map_err(|_| not_found(format!("There is no client {id}!")))
+}
+
+fn parse<T: DeserializeOwned>(body: &[u8]) -> Result<T, (StatusCode, ClientError)> {
+    serde_json::from_slice(body).map_err(|err| {
+        (
+            StatusCode::BAD_REQUEST,
+            ClientError::new(ErrorKind::InvalidMessage, format!("Invalid JSON: {err}")),
+        )
+    })
+}
+
+// the fields of patch replace those of config, unknown ones are an error as they would be
+// ignored otherwise
+fn merge(
+    config: &ClientConfiguration,
+    patch: &serde_json::Value,
+) -> Result<ClientConfiguration, (StatusCode, ClientError)> {
+    let serde_json::Value::Object(patch) = patch else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            ClientError::new(
+                ErrorKind::InvalidMessage,
+                "Expected a JSON object with the fields to change!",
+            ),
+        ));
+    };
+    let mut merged = match serde_json::to_value(config) {
+        Ok(serde_json::Value::Object(merged)) => merged,
+        _ => unreachable!("ClientConfiguration is serialized as an object"),
+    };
+    for (field, value) in patch {
+        if !merged.contains_key(field) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                ClientError::config_invalid(field, format!("There is no setting {field}!")),
+            ));
+        }
+        merged.insert(field.clone(), value.clone());
+    }
+    serde_json::from_value(merged.into()).map_err(|err| {
+        (
+            StatusCode::BAD_REQUEST,
+            ClientError::new(ErrorKind::InvalidMessage, format!("Invalid setting: {err}")),
+        )
+    })
+}
+
+fn configured_clients(config: &WeylusClientConfig) -> Vec<u64> {
+    config
+        .connections
+        .list()
+        .into_iter()
+        .filter(|info| info.config.is_some())
+        .map(|info| info.id)
+        .collect()
+}
+
+async fn configure(
+    config: &WeylusClientConfig,
+    id: u64,
+    client_config: ClientConfiguration,
+) -> Result<(), (StatusCode, ClientError)> {
+    client_config
+        .validate()
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    send(config, id, MessageInbound::ApiConfig(client_config)).await
+}
+
+// clients that disconnect while configuring all of them are skipped
+async fn configure_if_connected(
+    config: &WeylusClientConfig,
+    id: u64,
+    client_config: ClientConfiguration,
+) -> Result<bool, (StatusCode, ClientError)> {
+    match configure(config, id, client_config).await {
+        Ok(()) => Ok(true),
+        Err((StatusCode::NOT_FOUND, _)) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+async fn send(
+    config: &WeylusClientConfig,
+    id: u64,
+    msg: MessageInbound,
+) -> Result<(), (StatusCode, ClientError)> {
+    if config.connections.send(id, msg).await {
+        Ok(())
+    } else {
+        Err(not_found(format!("There is no client {id}!")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol;
+    use crate::websocket::tests::client_config;
+
+    // the requests are those of the curl examples in the Readme, CODE being the access code:
+    // curl -H "Authorization: Bearer $CODE" http://localhost:1701/api/clients
+    // curl -X PATCH -H "Authorization: Bearer $CODE" -d '{"frame_rate": 60}' \
+    //     http://localhost:1701/api/clients/0/config
+    // curl -X POST -H "Authorization: Bearer $CODE" -d '"RequestKeyFrame"' \
+    //     http://localhost:1701/api/clients/0/messages
+    // curl -X PUT -H "Authorization: Bearer $CODE" -d false http://localhost:1701/api/input
+    #[test]
+    fn api_configures_clients_and_switches_input() {
+        use http_body_util::BodyExt;
+
+        let mut headers = HeaderMap::new();
+        assert!(authorized(&headers, None, None));
+        assert!(!authorized(&headers, None, Some("1234")));
+        assert!(authorized(&headers, Some("access_code=1234"), Some("1234")));
+        headers.insert(AUTHORIZATION, "Bearer 1234".parse().unwrap());
+        assert!(authorized(&headers, None, Some("1234")));
+        assert!(!authorized(&headers, None, Some("4321")));
+
+        let config = client_config();
+        let connection = config
+            .connections
+            .open("192.168.0.2:50000".parse().unwrap());
+        let id = connection.id();
+        let mut control = connection.control();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let request = |method: Method, path: &str, body: &str| {
+            let response =
+                runtime.block_on(handle(&method, path, body.to_string().into(), &config));
+            let status = response.status();
+            let body = runtime
+                .block_on(response.into_body().collect())
+                .unwrap()
+                .to_bytes();
+            (
+                status,
+                serde_json::from_slice::<serde_json::Value>(&body).ok(),
+            )
+        };
+
+        let (status, capturables) = request(Method::GET, "/api/capturables", "");
+        assert_eq!(status, StatusCode::OK);
+        assert!(capturables.unwrap().is_array());
+        let (status, clients) = request(Method::GET, "/api/clients", "");
+        assert_eq!(status, StatusCode::OK);
+        let clients = clients.unwrap();
+        assert_eq!(clients[0]["id"], id);
+        assert!(clients[0]["config"].is_null());
+
+        let config_path = format!("/api/clients/{id}/config");
+        let (status, err) = request(Method::PATCH, &config_path, r#"{"frame_rate": 60}"#);
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(err.unwrap()["kind"], "NotFound");
+
+        let base: protocol::ClientConfiguration = serde_json::from_str(
+            r#"{"uinput_support": true, "capturable_id": "x11:HDMI-1", "capture_cursor": false,
+            "max_width": 1920, "max_height": 1080, "client_name": null, "frame_rate": 30}"#,
+        )
+        .unwrap();
+        connection.configured(&base);
+        let (status, patched) = request(Method::PATCH, &config_path, r#"{"frame_rate": 60}"#);
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(patched.unwrap()["capturable_id"], "x11:HDMI-1");
+        match control.try_recv() {
+            Ok(MessageInbound::ApiConfig(config)) => {
+                assert_eq!(config.frame_rate, 60.0);
+                assert_eq!(config.max_width, 1920);
+            }
+            msg => panic!("expected the patched configuration, got {msg:?}"),
+        }
+
+        // neither is passed on
+        let (status, err) = request(Method::PATCH, &config_path, r#"{"frame_rat": 60}"#);
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(err.unwrap()["field"], "frame_rat");
+        let (status, err) = request(Method::PATCH, &config_path, r#"{"bitrate_kbps": 5}"#);
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(err.unwrap()["kind"], "ConfigInvalid");
+        assert!(control.try_recv().is_err());
+
+        let (status, configured) = request(Method::PATCH, "/api/config", r#"{"audio": true}"#);
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(configured.unwrap(), serde_json::json!([id]));
+        assert!(
+            matches!(control.try_recv(), Ok(MessageInbound::ApiConfig(config)) if config.audio)
+        );
+
+        let messages_path = format!("/api/clients/{id}/messages");
+        let (status, _) = request(Method::POST, &messages_path, r#""RequestKeyFrame""#);
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(matches!(
+            control.try_recv(),
+            Ok(MessageInbound::RequestKeyFrame)
+        ));
+        let ping = r#"{"Ping": {"id": 1, "client_ts": 0}}"#;
+        assert_eq!(
+            request(Method::POST, &messages_path, ping).0,
+            StatusCode::BAD_REQUEST
+        );
+        let (status, err) = request(Method::GET, &format!("/api/clients/{id}/stats"), "");
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(err.unwrap()["kind"], "NotFound");
+
+        let (_, enabled) = request(Method::GET, "/api/input", "");
+        assert_eq!(enabled.unwrap(), true);
+        let (_, enabled) = request(Method::PUT, "/api/input", "false");
+        assert_eq!(enabled.unwrap(), false);
+        assert!(!config.input_switch.is_enabled());
+        let (_, enabled) = request(Method::POST, "/api/input/toggle", "");
+        assert_eq!(enabled.unwrap(), true);
+        assert!(config.input_switch.is_enabled());
+
+        drop(connection);
+        let (_, clients) = request(Method::GET, "/api/clients", "");
+        assert_eq!(clients.unwrap(), serde_json::json!([]));
+        let (status, _) = request(Method::POST, &messages_path, r#""RequestKeyFrame""#);
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
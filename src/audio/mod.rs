@@ -0,0 +1,253 @@
+//! Audio of the host, captured from what it plays back and sent to clients encoded as Opus. Audio
+//! is a track of its own next to the video: capturing or encoding it failing is reported to the
+//! client, the video keeps going either way.
+
+use std::error::Error;
+use std::os::raw::{c_int, c_uchar, c_void};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{spawn, JoinHandle};
+use std::time::Instant;
+
+use tracing::{debug, info, warn};
+
+use crate::cerror::CError;
+use crate::protocol::{AudioSource, ClientError, ErrorKind, MessageOutbound, WeylusSender};
+use crate::video::monotonic_micros;
+use crate::websocket::send_message;
+
+#[cfg(target_os = "linux")]
+mod pulse;
+#[cfg(target_os = "windows")]
+mod wasapi;
+
+// plenty for stereo Opus
+const AUDIO_BITRATE_KBPS: u32 = 96;
+
+extern "C" {
+    fn init_audio_encoder(
+        rust_ctx: *mut c_void,
+        sample_rate_in: c_int,
+        channels_in: c_int,
+        bitrate_kbps: c_int,
+        err: *mut CError,
+    ) -> *mut c_void;
+    fn encode_audio(
+        handle: *mut c_void,
+        samples: *const f32,
+        num_samples: c_int,
+        micros: i64,
+        err: *mut CError,
+    );
+    fn destroy_audio_encoder(handle: *mut c_void);
+}
+
+// this is used as callback in lib/encode_audio.c
+#[no_mangle]
+extern "C" fn write_audio_packet(
+    audio_encoder: *mut c_void,
+    buf: *const c_uchar,
+    buf_size: c_int,
+    micros: i64,
+) {
+    let audio_encoder = unsafe { (audio_encoder as *mut AudioEncoder).as_mut().unwrap() };
+    let data = unsafe { std::slice::from_raw_parts(buf as *const u8, buf_size as usize) };
+    audio_encoder
+        .packets
+        .push((micros.max(0) as u64, data.to_vec()));
+}
+
+/// Encodes interleaved samples as Opus at 48 kHz in stereo, whatever the input is.
+struct AudioEncoder {
+    handle: *mut c_void,
+    channels: usize,
+    // packets and the time their first sample has been captured at, see monotonic_micros
+    packets: Vec<(u64, Vec<u8>)>,
+}
+
+impl AudioEncoder {
+    fn new(sample_rate: u32, channels: usize) -> Result<Box<Self>, CError> {
+        let mut audio_encoder = Box::new(Self {
+            handle: std::ptr::null_mut(),
+            channels,
+            packets: Vec::new(),
+        });
+        let mut err = CError::new();
+        audio_encoder.handle = unsafe {
+            init_audio_encoder(
+                audio_encoder.as_mut() as *mut _ as *mut c_void,
+                sample_rate as c_int,
+                channels as c_int,
+                AUDIO_BITRATE_KBPS as c_int,
+                &mut err,
+            )
+        };
+        if err.is_err() {
+            return Err(err);
+        }
+        Ok(audio_encoder)
+    }
+
+    /// Encode samples whose first one has been captured at captured_at, returns the packets that
+    /// are complete. The encoder holds back a few milliseconds of audio.
+    fn encode(
+        &mut self,
+        samples: &[f32],
+        captured_at: Instant,
+    ) -> Result<std::vec::Drain<(u64, Vec<u8>)>, CError> {
+        let mut err = CError::new();
+        unsafe {
+            encode_audio(
+                self.handle,
+                samples.as_ptr(),
+                (samples.len() / self.channels) as c_int,
+                monotonic_micros(captured_at) as i64,
+                &mut err,
+            )
+        };
+        if err.is_err() {
+            self.packets.clear();
+            return Err(err);
+        }
+        Ok(self.packets.drain(..))
+    }
+}
+
+impl Drop for AudioEncoder {
+    fn drop(&mut self) {
+        if !self.handle.is_null() {
+            unsafe { destroy_audio_encoder(self.handle) }
+        }
+    }
+}
+
+/// Captures what the host plays back.
+trait AudioCapture {
+    fn sample_rate(&self) -> u32;
+    fn channels(&self) -> usize;
+    /// Interleaved samples and the time the first one has been captured at, None if nothing has
+    /// been played back for a while, which is checked for again by calling this again.
+    fn read(&mut self) -> Result<Option<(&[f32], Instant)>, Box<dyn Error>>;
+}
+
+/// Sources audio can be captured from besides what the host plays back on its default output,
+/// which is captured if the client does not choose one.
+pub fn audio_sources() -> Vec<AudioSource> {
+    #[cfg(target_os = "linux")]
+    return pulse::audio_sources();
+    #[cfg(target_os = "windows")]
+    return wasapi::audio_sources();
+    #[cfg(target_os = "macos")]
+    return Vec::new();
+}
+
+fn open_capture(source: Option<&str>) -> Result<Box<dyn AudioCapture>, Box<dyn Error>> {
+    #[cfg(target_os = "linux")]
+    return Ok(Box::new(pulse::PulseCapture::new(source)?));
+    #[cfg(target_os = "windows")]
+    return Ok(Box::new(wasapi::WasapiCapture::new(source)?));
+    #[cfg(target_os = "macos")]
+    {
+        let _ = source;
+        Err("Capturing audio is not supported on macOS.".into())
+    }
+}
+
+/// Streams the audio of the host to a client while it exists.
+pub struct AudioStream {
+    source: Option<String>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl AudioStream {
+    /// Start capturing from source, as listed by audio_sources, or the default one if None.
+    pub fn new<S: WeylusSender + Send + 'static>(source: Option<String>, sender: S) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let source = source.clone();
+            let stop = stop.clone();
+            spawn(move || run_audio_stream(source, stop, sender))
+        };
+        Self {
+            source,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+}
+
+impl Drop for AudioStream {
+    fn drop(&mut self) {
+        // the thread checks this at least every 100 ms as that is how long reading waits at most
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            if let Err(err) = thread.join() {
+                warn!("Failed to join audio thread: {err:?}");
+            }
+        }
+    }
+}
+
+fn audio_failed<S: WeylusSender>(sender: &mut S, message: &str) {
+    send_message(
+        sender,
+        MessageOutbound::Error(ClientError::new(ErrorKind::AudioFailed, message)),
+    );
+}
+
+fn run_audio_stream<S: WeylusSender>(source: Option<String>, stop: Arc<AtomicBool>, mut sender: S) {
+    let mut capture = match open_capture(source.as_deref()) {
+        Ok(capture) => capture,
+        Err(err) => {
+            warn!("Failed to capture audio: {err}");
+            audio_failed(&mut sender, &format!("Failed to capture audio: {err}"));
+            return;
+        }
+    };
+    let mut encoder = match AudioEncoder::new(capture.sample_rate(), capture.channels()) {
+        Ok(encoder) => encoder,
+        Err(err) => {
+            warn!("Failed to create audio encoder: {err}");
+            audio_failed(&mut sender, "Failed to create audio encoder!");
+            return;
+        }
+    };
+    info!(
+        "Streaming audio from {}, {} Hz, {} channels.",
+        source.as_deref().unwrap_or("default output"),
+        capture.sample_rate(),
+        capture.channels()
+    );
+    while !stop.load(Ordering::Relaxed) {
+        let (samples, captured_at) = match capture.read() {
+            Ok(Some(read)) => read,
+            Ok(None) => continue,
+            Err(err) => {
+                warn!("Failed to capture audio: {err}");
+                audio_failed(
+                    &mut sender,
+                    "Capturing audio failed, audio has been stopped.",
+                );
+                return;
+            }
+        };
+        let packets = match encoder.encode(samples, captured_at) {
+            Ok(packets) => packets,
+            Err(err) => {
+                warn!("Failed to encode audio: {err}");
+                continue;
+            }
+        };
+        for (captured_at_us, data) in packets {
+            if let Err(err) = sender.send_audio(captured_at_us, &data) {
+                debug!("Stopping audio, the client is gone: {err}");
+                return;
+            }
+        }
+    }
+}
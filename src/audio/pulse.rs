@@ -0,0 +1,170 @@
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app::AppSink;
+use tracing::warn;
+
+use crate::audio::AudioCapture;
+use crate::protocol::AudioSource;
+
+// what the appsink is asked for, audioconvert and audioresample take care of the rest
+const SAMPLE_RATE: u32 = 48000;
+const CHANNELS: usize = 2;
+
+// how long reading waits for samples
+const READ_TIMEOUT_MS: u64 = 100;
+
+/// Id of a source, the name PulseAudio and PipeWire know it by if there is one.
+fn device_id(device: &gst::Device) -> String {
+    if device.find_property("internal-name").is_some() {
+        return device.property::<String>("internal-name");
+    }
+    device
+        .properties()
+        .and_then(|props| props.get::<String>("node.name").ok())
+        .unwrap_or_else(|| device.display_name().into())
+}
+
+fn is_monitor(device: &gst::Device) -> bool {
+    device
+        .properties()
+        .and_then(|props| props.get::<String>("device.class").ok())
+        .is_some_and(|class| class == "monitor")
+}
+
+fn devices() -> Result<Vec<gst::Device>, Box<dyn Error>> {
+    let monitor = gst::DeviceMonitor::new();
+    monitor.add_filter(Some("Audio/Source"), None);
+    monitor.start()?;
+    let mut devices: Vec<gst::Device> = monitor.devices().into_iter().collect();
+    monitor.stop();
+    // what is played back first, then microphones and the like
+    devices.sort_by_key(|device| !is_monitor(device));
+    Ok(devices)
+}
+
+pub fn audio_sources() -> Vec<AudioSource> {
+    match devices() {
+        Ok(devices) => devices
+            .iter()
+            .map(|device| AudioSource {
+                id: device_id(device),
+                name: device.display_name().into(),
+            })
+            .collect(),
+        Err(err) => {
+            warn!("Failed to list audio sources: {err}");
+            Vec::new()
+        }
+    }
+}
+
+/// Captures audio via GStreamer, which talks to PulseAudio or PipeWire, whichever is running.
+pub struct PulseCapture {
+    pipeline: gst::Pipeline,
+    appsink: AppSink,
+    samples: Vec<f32>,
+}
+
+impl PulseCapture {
+    /// Capture from the source with the given id or the monitor of the default output.
+    pub fn new(source: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let src = match source {
+            Some(id) => devices()?
+                .iter()
+                .find(|device| device_id(device) == id)
+                .ok_or_else(|| format!("No audio source named {id}."))?
+                .create_element(None)?,
+            None => gst::ElementFactory::make("pulsesrc")
+                .property("device", "@DEFAULT_MONITOR@")
+                .build()?,
+        };
+        let convert = gst::ElementFactory::make("audioconvert").build()?;
+        let resample = gst::ElementFactory::make("audioresample").build()?;
+        let appsink = AppSink::builder()
+            .caps(
+                &gst::Caps::builder("audio/x-raw")
+                    .field("format", "F32LE")
+                    .field("layout", "interleaved")
+                    .field("rate", SAMPLE_RATE as i32)
+                    .field("channels", CHANNELS as i32)
+                    .build(),
+            )
+            // about a second of audio, older samples are dropped if reading falls behind
+            .max_buffers(100)
+            .drop(true)
+            .build();
+
+        let pipeline = gst::Pipeline::new();
+        pipeline.add_many([&src, &convert, &resample, appsink.upcast_ref()])?;
+        gst::Element::link_many([&src, &convert, &resample, appsink.upcast_ref()])?;
+        pipeline.set_state(gst::State::Playing)?;
+        Ok(Self {
+            pipeline,
+            appsink,
+            samples: Vec::new(),
+        })
+    }
+
+    fn check_bus(&self) -> Result<(), Box<dyn Error>> {
+        let Some(bus) = self.pipeline.bus() else {
+            return Ok(());
+        };
+        if let Some(msg) = bus.pop_filtered(&[gst::MessageType::Error]) {
+            if let gst::MessageView::Error(err) = msg.view() {
+                return Err(format!("{} ({:?})", err.error(), err.debug()).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AudioCapture for PulseCapture {
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn channels(&self) -> usize {
+        CHANNELS
+    }
+
+    fn read(&mut self) -> Result<Option<(&[f32], Instant)>, Box<dyn Error>> {
+        self.check_bus()?;
+        let Some(sample) = self
+            .appsink
+            .try_pull_sample(gst::ClockTime::from_mseconds(READ_TIMEOUT_MS))
+        else {
+            if self.appsink.is_eos() {
+                return Err("Audio source stopped.".into());
+            }
+            return Ok(None);
+        };
+        let Some(buffer) = sample.buffer() else {
+            return Ok(None);
+        };
+        // buffers are timestamped with the running time their first sample was captured at
+        let age = match (buffer.pts(), self.pipeline.current_running_time()) {
+            (Some(pts), Some(now)) => Duration::from_nanos(now.saturating_sub(pts).nseconds()),
+            _ => Duration::ZERO,
+        };
+        let captured_at = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+        let map = buffer.map_readable()?;
+        self.samples.clear();
+        self.samples.extend(
+            map.as_slice()
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+        );
+        Ok(Some((&self.samples, captured_at)))
+    }
+}
+
+impl Drop for PulseCapture {
+    fn drop(&mut self) {
+        if let Err(err) = self.pipeline.set_state(gst::State::Null) {
+            warn!("Failed to stop GStreamer pipeline: {}.", err);
+        }
+    }
+}
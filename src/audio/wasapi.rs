@@ -0,0 +1,361 @@
+use std::error::Error;
+use std::ffi::OsString;
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::ptr;
+use std::slice;
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+use winapi::shared::guiddef::IsEqualGUID;
+use winapi::shared::ksmedia::{KSDATAFORMAT_SUBTYPE_IEEE_FLOAT, KSDATAFORMAT_SUBTYPE_PCM};
+use winapi::shared::mmreg::{
+    WAVEFORMATEX, WAVEFORMATEXTENSIBLE, WAVE_FORMAT_EXTENSIBLE, WAVE_FORMAT_IEEE_FLOAT,
+    WAVE_FORMAT_PCM,
+};
+use winapi::shared::winerror::{HRESULT, RPC_E_CHANGED_MODE};
+use winapi::shared::wtypes::VT_LPWSTR;
+use winapi::um::audioclient::{IAudioCaptureClient, IAudioClient, AUDCLNT_BUFFERFLAGS_SILENT};
+use winapi::um::audiosessiontypes::{AUDCLNT_SHAREMODE_SHARED, AUDCLNT_STREAMFLAGS_LOOPBACK};
+use winapi::um::combaseapi::{
+    CoCreateInstance, CoInitializeEx, CoTaskMemFree, PropVariantClear, CLSCTX_ALL,
+};
+use winapi::um::coml2api::STGM_READ;
+use winapi::um::functiondiscoverykeys_devpkey::PKEY_Device_FriendlyName;
+use winapi::um::mmdeviceapi::{
+    eConsole, eRender, CLSID_MMDeviceEnumerator, IMMDevice, IMMDeviceCollection,
+    IMMDeviceEnumerator, DEVICE_STATE_ACTIVE,
+};
+use winapi::um::objbase::COINIT_MULTITHREADED;
+use winapi::um::profileapi::{QueryPerformanceCounter, QueryPerformanceFrequency};
+use winapi::um::propidl::PROPVARIANT;
+use winapi::um::propsys::IPropertyStore;
+use winapi::um::winnt::LARGE_INTEGER;
+use winapi::Interface;
+use wio::com::ComPtr;
+
+use crate::audio::AudioCapture;
+use crate::protocol::AudioSource;
+
+// size of the buffer WASAPI captures to in 100 ns units
+const BUFFER_DURATION: i64 = 2_000_000;
+
+// how long reading waits for samples and how often it checks for them in the meantime
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+#[derive(Debug)]
+pub struct WasapiError(String, HRESULT);
+
+impl std::fmt::Display for WasapiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self(s, hr) = self;
+        write!(f, "{s} (HRESULT: {:#x})", *hr as u32)
+    }
+}
+
+impl Error for WasapiError {}
+
+fn check(hr: HRESULT, what: &str) -> Result<(), WasapiError> {
+    if hr < 0 {
+        return Err(WasapiError(format!("Failed to {what}"), hr));
+    }
+    Ok(())
+}
+
+fn init_com() -> Result<(), WasapiError> {
+    let hr = unsafe { CoInitializeEx(ptr::null_mut(), COINIT_MULTITHREADED) };
+    // already initialized differently on this thread, which works just as well
+    if hr == RPC_E_CHANGED_MODE {
+        return Ok(());
+    }
+    check(hr, "initialize COM")
+}
+
+fn enumerator() -> Result<ComPtr<IMMDeviceEnumerator>, WasapiError> {
+    init_com()?;
+    let mut enumerator = ptr::null_mut();
+    unsafe {
+        check(
+            CoCreateInstance(
+                &CLSID_MMDeviceEnumerator,
+                ptr::null_mut(),
+                CLSCTX_ALL,
+                &IMMDeviceEnumerator::uuidof(),
+                &mut enumerator,
+            ),
+            "create device enumerator",
+        )?;
+        Ok(ComPtr::from_raw(enumerator as *mut IMMDeviceEnumerator))
+    }
+}
+
+unsafe fn from_wide(s: *const u16) -> String {
+    let len = (0..).take_while(|&i| *s.add(i) != 0).count();
+    OsString::from_wide(slice::from_raw_parts(s, len))
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn device_id(device: &ComPtr<IMMDevice>) -> Result<String, WasapiError> {
+    let mut id = ptr::null_mut();
+    unsafe {
+        check(device.GetId(&mut id), "get id of audio device")?;
+        let string = from_wide(id);
+        CoTaskMemFree(id as *mut _);
+        Ok(string)
+    }
+}
+
+fn device_name(device: &ComPtr<IMMDevice>) -> Result<String, WasapiError> {
+    unsafe {
+        let mut store = ptr::null_mut();
+        check(
+            device.OpenPropertyStore(STGM_READ, &mut store),
+            "open properties of audio device",
+        )?;
+        let store: ComPtr<IPropertyStore> = ComPtr::from_raw(store);
+        let mut value: PROPVARIANT = std::mem::zeroed();
+        check(
+            store.GetValue(&PKEY_Device_FriendlyName, &mut value),
+            "get name of audio device",
+        )?;
+        let name = if value.vt as u32 == VT_LPWSTR {
+            from_wide(*value.data.pwszVal())
+        } else {
+            String::new()
+        };
+        PropVariantClear(&mut value);
+        Ok(name)
+    }
+}
+
+fn active_outputs() -> Result<Vec<ComPtr<IMMDevice>>, WasapiError> {
+    let enumerator = enumerator()?;
+    unsafe {
+        let mut collection = ptr::null_mut();
+        check(
+            enumerator.EnumAudioEndpoints(eRender, DEVICE_STATE_ACTIVE, &mut collection),
+            "enumerate audio outputs",
+        )?;
+        let collection: ComPtr<IMMDeviceCollection> = ComPtr::from_raw(collection);
+        let mut count = 0;
+        check(collection.GetCount(&mut count), "count audio outputs")?;
+        let mut devices = Vec::new();
+        for i in 0..count {
+            let mut device = ptr::null_mut();
+            check(collection.Item(i, &mut device), "get audio output")?;
+            devices.push(ComPtr::from_raw(device));
+        }
+        Ok(devices)
+    }
+}
+
+/// Outputs whose playback can be captured.
+pub fn audio_sources() -> Vec<AudioSource> {
+    let sources = active_outputs().and_then(|devices| {
+        devices
+            .iter()
+            .map(|device| {
+                Ok(AudioSource {
+                    id: device_id(device)?,
+                    name: device_name(device)?,
+                })
+            })
+            .collect()
+    });
+    match sources {
+        Ok(sources) => sources,
+        Err(err) => {
+            warn!("Failed to list audio sources: {err}");
+            Vec::new()
+        }
+    }
+}
+
+/// Monotonic time in 100 ns units, the unit WASAPI timestamps packets in.
+fn qpc_now() -> u64 {
+    unsafe {
+        let mut counter: LARGE_INTEGER = std::mem::zeroed();
+        let mut frequency: LARGE_INTEGER = std::mem::zeroed();
+        QueryPerformanceCounter(&mut counter);
+        QueryPerformanceFrequency(&mut frequency);
+        (*counter.QuadPart() as u128 * 10_000_000 / (*frequency.QuadPart() as u128).max(1)) as u64
+    }
+}
+
+#[derive(Clone, Copy)]
+enum SampleFormat {
+    F32,
+    I16,
+}
+
+/// Captures what an output plays back via WASAPI loopback.
+pub struct WasapiCapture {
+    client: ComPtr<IAudioClient>,
+    capture: ComPtr<IAudioCaptureClient>,
+    sample_rate: u32,
+    channels: usize,
+    format: SampleFormat,
+    samples: Vec<f32>,
+}
+
+impl WasapiCapture {
+    /// Capture from the output with the given id or the default one.
+    pub fn new(source: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let enumerator = enumerator()?;
+        unsafe {
+            let mut device = ptr::null_mut();
+            match source {
+                Some(id) => {
+                    let id: Vec<u16> = OsString::from(id).encode_wide().chain([0]).collect();
+                    check(
+                        enumerator.GetDevice(id.as_ptr(), &mut device),
+                        "find audio output",
+                    )?;
+                }
+                None => check(
+                    enumerator.GetDefaultAudioEndpoint(eRender, eConsole, &mut device),
+                    "get default audio output",
+                )?,
+            }
+            let device = ComPtr::from_raw(device);
+
+            let mut client = ptr::null_mut();
+            check(
+                device.Activate(
+                    &IAudioClient::uuidof(),
+                    CLSCTX_ALL,
+                    ptr::null_mut(),
+                    &mut client,
+                ),
+                "activate audio client",
+            )?;
+            let client = ComPtr::from_raw(client as *mut IAudioClient);
+
+            let mut mix_format: *mut WAVEFORMATEX = ptr::null_mut();
+            check(client.GetMixFormat(&mut mix_format), "get mix format")?;
+            let format = sample_format(&*mix_format);
+            let sample_rate = (*mix_format).nSamplesPerSec;
+            let channels = (*mix_format).nChannels as usize;
+            let hr = client.Initialize(
+                AUDCLNT_SHAREMODE_SHARED,
+                AUDCLNT_STREAMFLAGS_LOOPBACK,
+                BUFFER_DURATION,
+                0,
+                mix_format,
+                ptr::null(),
+            );
+            CoTaskMemFree(mix_format as *mut _);
+            check(hr, "initialize loopback capture")?;
+            let format = format.ok_or("Mix format of the audio output is not supported.")?;
+
+            let mut capture = ptr::null_mut();
+            check(
+                client.GetService(&IAudioCaptureClient::uuidof(), &mut capture),
+                "get capture client",
+            )?;
+            let capture = ComPtr::from_raw(capture as *mut IAudioCaptureClient);
+            check(client.Start(), "start capturing audio")?;
+            Ok(Self {
+                client,
+                capture,
+                sample_rate,
+                channels,
+                format,
+                samples: Vec::new(),
+            })
+        }
+    }
+}
+
+unsafe fn sample_format(format: &WAVEFORMATEX) -> Option<SampleFormat> {
+    match (format.wFormatTag, format.wBitsPerSample) {
+        (WAVE_FORMAT_IEEE_FLOAT, 32) => Some(SampleFormat::F32),
+        (WAVE_FORMAT_PCM, 16) => Some(SampleFormat::I16),
+        (WAVE_FORMAT_EXTENSIBLE, bits) => {
+            let format = &*(format as *const WAVEFORMATEX as *const WAVEFORMATEXTENSIBLE);
+            if IsEqualGUID(&format.SubFormat, &KSDATAFORMAT_SUBTYPE_IEEE_FLOAT) && bits == 32 {
+                Some(SampleFormat::F32)
+            } else if IsEqualGUID(&format.SubFormat, &KSDATAFORMAT_SUBTYPE_PCM) && bits == 16 {
+                Some(SampleFormat::I16)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+impl AudioCapture for WasapiCapture {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn read(&mut self) -> Result<Option<(&[f32], Instant)>, Box<dyn Error>> {
+        // loopback capture delivers nothing while nothing is played back
+        let start = Instant::now();
+        loop {
+            let mut packet_size = 0;
+            check(
+                unsafe { self.capture.GetNextPacketSize(&mut packet_size) },
+                "get size of audio packet",
+            )?;
+            if packet_size > 0 {
+                break;
+            }
+            if start.elapsed() >= READ_TIMEOUT {
+                return Ok(None);
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        let mut data = ptr::null_mut();
+        let mut frames = 0;
+        let mut flags = 0;
+        let mut qpc_position = 0;
+        unsafe {
+            check(
+                self.capture.GetBuffer(
+                    &mut data,
+                    &mut frames,
+                    &mut flags,
+                    ptr::null_mut(),
+                    &mut qpc_position,
+                ),
+                "get audio packet",
+            )?;
+        }
+        let age = Duration::from_nanos(qpc_now().saturating_sub(qpc_position) * 100);
+        let captured_at = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+        let len = frames as usize * self.channels;
+        self.samples.clear();
+        if flags & AUDCLNT_BUFFERFLAGS_SILENT != 0 {
+            self.samples.resize(len, 0.0);
+        } else {
+            match self.format {
+                SampleFormat::F32 => self
+                    .samples
+                    .extend_from_slice(unsafe { slice::from_raw_parts(data as *const f32, len) }),
+                SampleFormat::I16 => self.samples.extend(
+                    unsafe { slice::from_raw_parts(data as *const i16, len) }
+                        .iter()
+                        .map(|&s| s as f32 / 32768.0),
+                ),
+            }
+        }
+        check(
+            unsafe { self.capture.ReleaseBuffer(frames) },
+            "release audio packet",
+        )?;
+        Ok(Some((&self.samples, captured_at)))
+    }
+}
+
+impl Drop for WasapiCapture {
+    fn drop(&mut self) {
+        unsafe { self.client.Stop() };
+    }
+}
@@ -14,12 +14,13 @@ use core_foundation::{
 use core_graphics::{
     display,
     display::{CGDisplay, CGRect},
+    geometry::{CGPoint, CGSize},
     image::CGImage,
     window,
-    window::CGWindowID,
+    window::{CGWindowID, CGWindowImageOption},
 };
 
-use crate::capturable::{Capturable, Geometry, Recorder};
+use crate::capturable::{Capturable, Geometry, PhysicalSize, Recorder};
 
 #[derive(Debug)]
 pub struct CGError(String);
@@ -33,24 +34,43 @@ impl std::fmt::Display for CGError {
 
 impl Error for CGError {}
 
+/// On Retina displays images are by default captured with the backing scale factor applied,
+/// which means four times the pixels of the logical resolution.
+fn image_option(logical_resolution: bool) -> CGWindowImageOption {
+    if logical_resolution {
+        window::kCGWindowImageNominalResolution
+    } else {
+        window::kCGWindowImageDefault
+    }
+}
+
 #[derive(Clone)]
 pub struct CGDisplayCapturable {
     display: CGDisplay,
+    logical_resolution: bool,
 }
 
 impl CGDisplayCapturable {
-    pub fn new(display: CGDisplay) -> Self {
-        Self { display }
+    pub fn new(display: CGDisplay, logical_resolution: bool) -> Self {
+        Self {
+            display,
+            logical_resolution,
+        }
     }
 }
 
 impl Capturable for CGDisplayCapturable {
     fn name(&self) -> String {
-        format!(
-            "Monitor (CG, {}x{})",
-            self.display.pixels_wide(),
-            self.display.pixels_high()
-        )
+        let (width, height) = if self.logical_resolution {
+            let size = self.display.bounds().size;
+            (size.width as u64, size.height as u64)
+        } else {
+            (self.display.pixels_wide(), self.display.pixels_high())
+        };
+        format!("Monitor (CG, {}x{})", width, height)
+    }
+    fn id(&self) -> String {
+        format!("cg:display:{}", self.display.id)
     }
     fn geometry(&self) -> Result<Geometry, Box<dyn Error>> {
         let bounds = self.display.bounds();
@@ -60,8 +80,27 @@ impl Capturable for CGDisplayCapturable {
             (bounds.origin.y - y0) / h,
             bounds.size.width / w,
             bounds.size.height / h,
+            1.0,
+            1.0,
         ))
     }
+    fn physical_size(&self) -> Option<PhysicalSize> {
+        // zero if the display does not tell, like most projectors
+        let size = self.display.screen_size();
+        if size.width <= 0.0 || size.height <= 0.0 {
+            return None;
+        }
+        let (width, height) = if self.logical_resolution {
+            let size = self.display.bounds().size;
+            (size.width as u32, size.height as u32)
+        } else {
+            (
+                self.display.pixels_wide() as u32,
+                self.display.pixels_high() as u32,
+            )
+        };
+        Some(PhysicalSize::new(size.width, size.height, width, height))
+    }
     fn before_input(&mut self) -> Result<(), Box<dyn Error>> {
         Ok(())
     }
@@ -69,6 +108,7 @@ impl Capturable for CGDisplayCapturable {
         Ok(Box::new(RecorderCGDisplay::new(
             self.display,
             capture_cursor,
+            self.logical_resolution,
         )))
     }
 }
@@ -77,14 +117,16 @@ pub struct RecorderCGDisplay {
     img_data: Option<CFData>,
     display: CGDisplay,
     capture_cursor: bool,
+    logical_resolution: bool,
 }
 
 impl RecorderCGDisplay {
-    pub fn new(display: CGDisplay, capture_cursor: bool) -> Self {
+    pub fn new(display: CGDisplay, capture_cursor: bool, logical_resolution: bool) -> Self {
         Self {
             img_data: None,
             display,
             capture_cursor,
+            logical_resolution,
         }
     }
 }
@@ -109,8 +151,15 @@ fn check_pixelformat(img: &CGImage) -> Result<(), Box<dyn Error>> {
 
 impl Recorder for RecorderCGDisplay {
     fn capture(&mut self) -> Result<crate::video::PixelProvider, Box<dyn Error>> {
-        let img = if self.capture_cursor {
-            CGDisplay::screenshot(self.display.bounds(), 0, 0, 0)
+        // CGDisplayCreateImage can not scale the image, the screenshot is taken at logical
+        // resolution even if the cursor is not to be captured
+        let img = if self.capture_cursor || self.logical_resolution {
+            CGDisplay::screenshot(
+                self.display.bounds(),
+                0,
+                0,
+                image_option(self.logical_resolution),
+            )
         } else {
             self.display.image()
         };
@@ -133,6 +182,97 @@ impl Recorder for RecorderCGDisplay {
             )))
         }
     }
+
+    fn capture_method(&self) -> String {
+        "CoreGraphics display".into()
+    }
+}
+
+/// All displays at once, like the desktop of X11.
+#[derive(Clone)]
+pub struct CGDesktopCapturable {
+    logical_resolution: bool,
+}
+
+impl Capturable for CGDesktopCapturable {
+    fn name(&self) -> String {
+        "Desktop (CG, all monitors)".into()
+    }
+    fn id(&self) -> String {
+        "cg:desktop".into()
+    }
+    fn geometry(&self) -> Result<Geometry, Box<dyn Error>> {
+        Ok(Geometry::Relative(0.0, 0.0, 1.0, 1.0, 1.0, 1.0))
+    }
+    fn before_input(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+    fn recorder(&self, capture_cursor: bool) -> Result<Box<dyn Recorder>, Box<dyn Error>> {
+        Ok(Box::new(RecorderCGDesktop {
+            img_data: None,
+            capture_cursor,
+            logical_resolution: self.logical_resolution,
+        }))
+    }
+}
+
+pub struct RecorderCGDesktop {
+    img_data: Option<CFData>,
+    capture_cursor: bool,
+    logical_resolution: bool,
+}
+
+impl Recorder for RecorderCGDesktop {
+    fn capture(&mut self) -> Result<crate::video::PixelProvider, Box<dyn Error>> {
+        // queried for every frame, displays may be rearranged or plugged in while capturing
+        let (x, y, w, h) = screen_coordsys()?;
+        let bounds = CGRect::new(&CGPoint::new(x, y), &CGSize::new(w, h));
+        let cursor_id = if self.capture_cursor {
+            None
+        } else {
+            get_window_infos()
+                .iter()
+                .find(|w| w.name == "Cursor")
+                .map(|w| w.id)
+        };
+        let img = match cursor_id {
+            // the cursor is on top of everything else
+            Some(cursor_id) => CGDisplay::screenshot(
+                bounds,
+                window::kCGWindowListOptionOnScreenBelowWindow,
+                cursor_id,
+                image_option(self.logical_resolution),
+            ),
+            None => CGDisplay::screenshot(
+                bounds,
+                window::kCGWindowListOptionOnScreenOnly,
+                window::kCGNullWindowID,
+                image_option(self.logical_resolution),
+            ),
+        };
+        if let Some(img) = img {
+            check_pixelformat(&img)?;
+            let w = img.width() as usize;
+            let h = img.height() as usize;
+
+            // extract raw image data
+            self.img_data = Some(img.data());
+            Ok(crate::video::PixelProvider::BGR0S(
+                w,
+                h,
+                img.bytes_per_row(),
+                self.img_data.as_ref().unwrap().bytes(),
+            ))
+        } else {
+            Err(Box::new(CGError(
+                "Failed to capture desktop using CoreGraphics.".into(),
+            )))
+        }
+    }
+
+    fn capture_method(&self) -> String {
+        "CoreGraphics desktop".into()
+    }
 }
 
 #[derive(Clone)]
@@ -143,6 +283,7 @@ pub struct CGWindowCapturable {
     bounds: CGRect,
     geometry_relative: (f64, f64, f64, f64),
     last_geometry_update: Instant,
+    logical_resolution: bool,
 }
 
 impl CGWindowCapturable {
@@ -158,13 +299,7 @@ impl CGWindowCapturable {
                     ))
                 })?
                 .bounds;
-            let (x0, y0, w, h) = screen_coordsys()?;
-            self.geometry_relative = (
-                (self.bounds.origin.x - x0) / w,
-                (self.bounds.origin.y - y0) / h,
-                self.bounds.size.width / w,
-                self.bounds.size.height / h,
-            );
+            self.geometry_relative = relative_geometry(&self.bounds)?;
             self.last_geometry_update = Instant::now();
         }
         Ok(())
@@ -175,9 +310,12 @@ impl Capturable for CGWindowCapturable {
     fn name(&self) -> String {
         self.name.clone()
     }
+    fn id(&self) -> String {
+        format!("cg:window:{}", self.id)
+    }
     fn geometry(&self) -> Result<Geometry, Box<dyn Error>> {
         let (x, y, w, h) = self.geometry_relative;
-        Ok(Geometry::Relative(x, y, w, h))
+        Ok(Geometry::Relative(x, y, w, h, 1.0, 1.0))
     }
     fn before_input(&mut self) -> Result<(), Box<dyn Error>> {
         self.update_geometry()
@@ -209,7 +347,7 @@ impl Recorder for RecorderCGWindow {
             } else {
                 CFArray::from_copyable(&[self.win.id as *const c_void])
             },
-            0,
+            image_option(self.win.logical_resolution),
         );
         if let Some(img) = img {
             check_pixelformat(&img)?;
@@ -230,6 +368,10 @@ impl Recorder for RecorderCGWindow {
             )))
         }
     }
+
+    fn capture_method(&self) -> String {
+        "CoreGraphics window".into()
+    }
 }
 
 #[derive(Debug)]
@@ -298,32 +440,57 @@ pub fn screen_coordsys() -> Result<(f64, f64, f64, f64), Box<dyn Error>> {
     Ok((x0, y0, x1 - x0, y1 - y0))
 }
 
-pub fn get_displays() -> Result<Vec<CGDisplayCapturable>, Box<dyn Error>> {
+/// Bounds of a window relative to the area spanned by all displays, bounds are in global display
+/// coordinates which is also what input is simulated in.
+fn relative_geometry(bounds: &CGRect) -> Result<(f64, f64, f64, f64), Box<dyn Error>> {
+    let (x0, y0, w, h) = screen_coordsys()?;
+    Ok((
+        (bounds.origin.x - x0) / w,
+        (bounds.origin.y - y0) / h,
+        bounds.size.width / w,
+        bounds.size.height / h,
+    ))
+}
+
+/// The desktop spanning all displays if there is more than one, followed by every display.
+pub fn get_displays(logical_resolution: bool) -> Result<Vec<Box<dyn Capturable>>, Box<dyn Error>> {
     let display_ids = CGDisplay::active_displays()
         .map_err(|err| CGError(format!("Failed to obtain displays, CGError code: {}", err)))?;
-    Ok(display_ids
-        .iter()
-        .map(|id| CGDisplayCapturable::new(CGDisplay::new(*id)))
-        .collect())
+    let mut capturables: Vec<Box<dyn Capturable>> = Vec::new();
+    if display_ids.len() > 1 {
+        capturables.push(Box::new(CGDesktopCapturable { logical_resolution }));
+    }
+    for id in display_ids {
+        capturables.push(Box::new(CGDisplayCapturable::new(
+            CGDisplay::new(id),
+            logical_resolution,
+        )));
+    }
+    Ok(capturables)
 }
 
-pub fn get_windows() -> Result<Vec<CGWindowCapturable>, Box<dyn Error>> {
+pub fn get_windows(logical_resolution: bool) -> Result<Vec<CGWindowCapturable>, Box<dyn Error>> {
     let window_infos = get_window_infos();
     let cursor_id = window_infos
         .iter()
         .find(|w| w.name == "Cursor")
         .ok_or_else(|| CGError("No Cursor found!".into()))?
         .id;
-    Ok(window_infos
+    window_infos
         .iter()
         .filter(|w| w.id != cursor_id)
-        .map(|w| CGWindowCapturable {
-            id: w.id,
-            name: w.name.clone(),
-            cursor_id,
-            bounds: w.bounds,
-            geometry_relative: (0.0, 0.0, 1.0, 1.0),
-            last_geometry_update: Instant::now() - Duration::from_secs(2),
+        .map(|w| {
+            // the geometry is known right away so that input is mapped correctly even before
+            // the window has been updated in before_input
+            Ok(CGWindowCapturable {
+                id: w.id,
+                name: w.name.clone(),
+                cursor_id,
+                bounds: w.bounds,
+                geometry_relative: relative_geometry(&w.bounds)?,
+                last_geometry_update: Instant::now(),
+                logical_resolution,
+            })
         })
-        .collect())
+        .collect()
 }
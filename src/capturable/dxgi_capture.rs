@@ -0,0 +1,430 @@
+use std::error::Error;
+use std::mem::zeroed;
+use std::os::raw::c_void;
+use std::ptr;
+
+use tracing::debug;
+use winapi::shared::dxgi::{
+    CreateDXGIFactory1, IDXGIAdapter, IDXGIAdapter1, IDXGIFactory1, IDXGIOutput, IDXGIResource,
+    IID_IDXGIFactory1, DXGI_OUTPUT_DESC,
+};
+use winapi::shared::dxgi1_2::{
+    IDXGIOutput1, IDXGIOutputDuplication, DXGI_OUTDUPL_DESC, DXGI_OUTDUPL_FRAME_INFO,
+    DXGI_OUTDUPL_POINTER_SHAPE_INFO, DXGI_OUTDUPL_POINTER_SHAPE_TYPE_COLOR,
+    DXGI_OUTDUPL_POINTER_SHAPE_TYPE_MASKED_COLOR, DXGI_OUTDUPL_POINTER_SHAPE_TYPE_MONOCHROME,
+};
+use winapi::shared::dxgiformat::DXGI_FORMAT_B8G8R8A8_UNORM;
+use winapi::shared::dxgitype::DXGI_SAMPLE_DESC;
+use winapi::shared::winerror::{
+    DXGI_ERROR_ACCESS_LOST, DXGI_ERROR_MORE_DATA, DXGI_ERROR_NOT_FOUND, DXGI_ERROR_WAIT_TIMEOUT,
+    HRESULT,
+};
+use winapi::um::d3d11::{
+    D3D11CreateDevice, ID3D11Device, ID3D11DeviceContext, ID3D11Resource, ID3D11Texture2D,
+    D3D11_CPU_ACCESS_READ, D3D11_MAPPED_SUBRESOURCE, D3D11_MAP_READ, D3D11_SDK_VERSION,
+    D3D11_TEXTURE2D_DESC, D3D11_USAGE_STAGING,
+};
+use winapi::um::d3dcommon::D3D_DRIVER_TYPE_UNKNOWN;
+use wio::com::ComPtr;
+
+use crate::capturable::Recorder;
+use crate::video::PixelProvider;
+
+// how long to wait for the very first frame, afterwards frames are only taken if available
+const FIRST_FRAME_TIMEOUT_MS: u32 = 500;
+
+#[derive(Debug)]
+pub struct DxgiError(String, HRESULT);
+
+impl std::fmt::Display for DxgiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self(s, hr) = self;
+        write!(f, "{s} (HRESULT: {:#x})", *hr as u32)
+    }
+}
+
+impl Error for DxgiError {}
+
+fn check(hr: HRESULT, what: &str) -> Result<(), DxgiError> {
+    if hr < 0 {
+        return Err(DxgiError(format!("Failed to {what}"), hr));
+    }
+    Ok(())
+}
+
+/// Everything that has to be recreated once access to the duplicated output is lost, for
+/// example because of a mode change or switching to the secure desktop.
+struct Duplication {
+    context: ComPtr<ID3D11DeviceContext>,
+    duplication: ComPtr<IDXGIOutputDuplication>,
+    staging: ComPtr<ID3D11Texture2D>,
+    width: usize,
+    height: usize,
+}
+
+/// Find the output showing the monitor with the given device name, monitors may be connected to
+/// any of the adapters.
+unsafe fn find_output(
+    device_name: &str,
+) -> Result<(ComPtr<IDXGIAdapter1>, ComPtr<IDXGIOutput>), DxgiError> {
+    let mut factory = ptr::null_mut();
+    check(
+        CreateDXGIFactory1(&IID_IDXGIFactory1, &mut factory),
+        "create DXGIFactory1",
+    )?;
+    let factory = ComPtr::from_raw(factory as *mut IDXGIFactory1);
+    for i in 0.. {
+        let mut adapter = ptr::null_mut();
+        let hr = factory.EnumAdapters1(i, &mut adapter);
+        if hr == DXGI_ERROR_NOT_FOUND {
+            break;
+        }
+        check(hr, "get adapter")?;
+        let adapter: ComPtr<IDXGIAdapter1> = ComPtr::from_raw(adapter);
+        for j in 0.. {
+            let mut output = ptr::null_mut();
+            if adapter.EnumOutputs(j, &mut output) < 0 {
+                break;
+            }
+            let output: ComPtr<IDXGIOutput> = ComPtr::from_raw(output);
+            let mut desc: DXGI_OUTPUT_DESC = zeroed();
+            output.GetDesc(&mut desc);
+            let len = desc
+                .DeviceName
+                .iter()
+                .position(|c| *c == 0)
+                .unwrap_or(desc.DeviceName.len());
+            if desc.AttachedToDesktop != 0
+                && String::from_utf16_lossy(&desc.DeviceName[..len]) == device_name
+            {
+                return Ok((adapter, output));
+            }
+        }
+    }
+    Err(DxgiError(
+        format!("Failed to find output of {device_name}"),
+        DXGI_ERROR_NOT_FOUND,
+    ))
+}
+
+impl Duplication {
+    fn new(device_name: &str) -> Result<Self, DxgiError> {
+        unsafe {
+            let (adapter, output) = find_output(device_name)?;
+            let output: ComPtr<IDXGIOutput1> = output
+                .cast()
+                .map_err(|hr| DxgiError("Failed to get IDXGIOutput1".into(), hr))?;
+
+            let mut device = ptr::null_mut();
+            let mut context = ptr::null_mut();
+            check(
+                D3D11CreateDevice(
+                    adapter.as_raw() as *mut IDXGIAdapter,
+                    D3D_DRIVER_TYPE_UNKNOWN,
+                    ptr::null_mut(),
+                    0,
+                    ptr::null(),
+                    0,
+                    D3D11_SDK_VERSION,
+                    &mut device,
+                    ptr::null_mut(),
+                    &mut context,
+                ),
+                "create D3D11 device",
+            )?;
+            let device: ComPtr<ID3D11Device> = ComPtr::from_raw(device);
+            let context = ComPtr::from_raw(context);
+
+            // fails for example in remote desktop sessions
+            let mut duplication = ptr::null_mut();
+            check(
+                output.DuplicateOutput(device.as_raw() as *mut _, &mut duplication),
+                "duplicate output",
+            )?;
+            let duplication: ComPtr<IDXGIOutputDuplication> = ComPtr::from_raw(duplication);
+            let mut desc: DXGI_OUTDUPL_DESC = zeroed();
+            duplication.GetDesc(&mut desc);
+            let width = desc.ModeDesc.Width as usize;
+            let height = desc.ModeDesc.Height as usize;
+
+            // the duplicated frame lives on the GPU and has to be copied to a texture the CPU
+            // can read from
+            let texture_desc = D3D11_TEXTURE2D_DESC {
+                Width: width as u32,
+                Height: height as u32,
+                MipLevels: 1,
+                ArraySize: 1,
+                Format: DXGI_FORMAT_B8G8R8A8_UNORM,
+                SampleDesc: DXGI_SAMPLE_DESC {
+                    Count: 1,
+                    Quality: 0,
+                },
+                Usage: D3D11_USAGE_STAGING,
+                BindFlags: 0,
+                CPUAccessFlags: D3D11_CPU_ACCESS_READ,
+                MiscFlags: 0,
+            };
+            let mut staging = ptr::null_mut();
+            check(
+                device.CreateTexture2D(&texture_desc, ptr::null(), &mut staging),
+                "create staging texture",
+            )?;
+            Ok(Self {
+                context,
+                duplication,
+                staging: ComPtr::from_raw(staging),
+                width,
+                height,
+            })
+        }
+    }
+}
+
+#[derive(Default)]
+struct Pointer {
+    visible: bool,
+    // top left corner of the shape
+    x: i32,
+    y: i32,
+    shape: Vec<u8>,
+    shape_type: u32,
+    width: usize,
+    height: usize,
+    pitch: usize,
+}
+
+impl Pointer {
+    /// Composite the pointer shape reported by the Desktop Duplication API onto frame.
+    fn draw(&self, frame: &mut [u8], width: usize, height: usize) {
+        if !self.visible || self.shape.is_empty() {
+            return;
+        }
+        // monochrome shapes consist of an AND mask followed by an XOR mask
+        let shape_height = if self.shape_type == DXGI_OUTDUPL_POINTER_SHAPE_TYPE_MONOCHROME {
+            self.height / 2
+        } else {
+            self.height
+        };
+        for sy in 0..shape_height {
+            let y = self.y + sy as i32;
+            if y < 0 || y as usize >= height {
+                continue;
+            }
+            for sx in 0..self.width {
+                let x = self.x + sx as i32;
+                if x < 0 || x as usize >= width {
+                    continue;
+                }
+                let dst = &mut frame[(y as usize * width + x as usize) * 4..][..3];
+                match self.shape_type {
+                    DXGI_OUTDUPL_POINTER_SHAPE_TYPE_COLOR => {
+                        let src = &self.shape[sy * self.pitch + sx * 4..][..4];
+                        let alpha = src[3] as u32;
+                        for (d, s) in dst.iter_mut().zip(src) {
+                            *d = ((*s as u32 * alpha + *d as u32 * (255 - alpha)) / 255) as u8;
+                        }
+                    }
+                    DXGI_OUTDUPL_POINTER_SHAPE_TYPE_MASKED_COLOR => {
+                        let src = &self.shape[sy * self.pitch + sx * 4..][..4];
+                        // the alpha channel decides if the color replaces or inverts the screen
+                        let xor = src[3] != 0;
+                        for (d, s) in dst.iter_mut().zip(src) {
+                            *d = if xor { *d ^ s } else { *s };
+                        }
+                    }
+                    DXGI_OUTDUPL_POINTER_SHAPE_TYPE_MONOCHROME => {
+                        let bit = 0x80 >> (sx % 8);
+                        let and = self.shape[sy * self.pitch + sx / 8] & bit != 0;
+                        let xor = self.shape[(sy + shape_height) * self.pitch + sx / 8] & bit != 0;
+                        for c in dst.iter_mut() {
+                            *c = (if and { *c } else { 0 }) ^ (if xor { 0xff } else { 0 });
+                        }
+                    }
+                    _ => return,
+                }
+            }
+        }
+    }
+}
+
+/// Captures a screen via the DXGI Desktop Duplication API, frames are only copied from the GPU
+/// if the screen content actually changed.
+pub struct DxgiRecorder {
+    device_name: String,
+    capture_cursor: bool,
+    duplication: Option<Duplication>,
+    // last frame of the desktop without the pointer
+    desktop: Vec<u8>,
+    // what is passed on to the encoder, the desktop with the pointer on top
+    frame: Vec<u8>,
+    width: usize,
+    height: usize,
+    pointer: Pointer,
+    changed: bool,
+}
+
+impl DxgiRecorder {
+    pub fn new(device_name: &str, capture_cursor: bool) -> Result<Self, Box<dyn Error>> {
+        let duplication = Duplication::new(device_name)?;
+        Ok(Self {
+            device_name: device_name.to_string(),
+            capture_cursor,
+            duplication: Some(duplication),
+            desktop: Vec::new(),
+            frame: Vec::new(),
+            width: 0,
+            height: 0,
+            pointer: Pointer::default(),
+            changed: true,
+        })
+    }
+
+    /// Take the next frame from the duplication if there is one within timeout_ms.
+    fn poll(&mut self, timeout_ms: u32) -> Result<(), Box<dyn Error>> {
+        if self.duplication.is_none() {
+            self.duplication = Some(Duplication::new(&self.device_name)?);
+            debug!("Recreated desktop duplication.");
+        }
+        let dup = self.duplication.as_ref().unwrap();
+        let mut info: DXGI_OUTDUPL_FRAME_INFO = unsafe { zeroed() };
+        let mut resource = ptr::null_mut();
+        let hr = unsafe {
+            dup.duplication
+                .AcquireNextFrame(timeout_ms, &mut info, &mut resource)
+        };
+        match hr {
+            DXGI_ERROR_WAIT_TIMEOUT => return Ok(()),
+            DXGI_ERROR_ACCESS_LOST => {
+                // happens on resolution changes, UAC prompts and alike, the duplication has to
+                // be created again
+                debug!("Lost access to desktop duplication.");
+                self.duplication = None;
+                self.changed = true;
+                return Ok(());
+            }
+            _ => check(hr, "acquire frame")?,
+        }
+        let resource: ComPtr<IDXGIResource> = unsafe { ComPtr::from_raw(resource) };
+        let res = self.process_frame(&info, &resource);
+        unsafe {
+            self.duplication
+                .as_ref()
+                .unwrap()
+                .duplication
+                .ReleaseFrame()
+        };
+        res
+    }
+
+    fn process_frame(
+        &mut self,
+        info: &DXGI_OUTDUPL_FRAME_INFO,
+        resource: &ComPtr<IDXGIResource>,
+    ) -> Result<(), Box<dyn Error>> {
+        let dup = self.duplication.as_ref().unwrap();
+        // only the pointer moved if no frames have been accumulated
+        if info.AccumulatedFrames > 0 {
+            let texture: ComPtr<ID3D11Texture2D> = resource
+                .cast()
+                .map_err(|hr| DxgiError("Failed to get frame texture".into(), hr))?;
+            unsafe {
+                dup.context.CopyResource(
+                    dup.staging.as_raw() as *mut ID3D11Resource,
+                    texture.as_raw() as *mut ID3D11Resource,
+                );
+                let mut mapped: D3D11_MAPPED_SUBRESOURCE = zeroed();
+                check(
+                    dup.context.Map(
+                        dup.staging.as_raw() as *mut ID3D11Resource,
+                        0,
+                        D3D11_MAP_READ,
+                        0,
+                        &mut mapped,
+                    ),
+                    "map staging texture",
+                )?;
+                let row_len = dup.width * 4;
+                self.desktop.resize(row_len * dup.height, 0);
+                for (y, row) in self.desktop.chunks_exact_mut(row_len).enumerate() {
+                    let src = (mapped.pData as *const u8).add(y * mapped.RowPitch as usize);
+                    ptr::copy_nonoverlapping(src, row.as_mut_ptr(), row_len);
+                }
+                dup.context
+                    .Unmap(dup.staging.as_raw() as *mut ID3D11Resource, 0);
+            }
+            self.width = dup.width;
+            self.height = dup.height;
+            self.changed = true;
+        }
+
+        if unsafe { *info.LastMouseUpdateTime.QuadPart() } != 0 {
+            self.pointer.visible = info.PointerPosition.Visible != 0;
+            self.pointer.x = info.PointerPosition.Position.x;
+            self.pointer.y = info.PointerPosition.Position.y;
+            if info.PointerShapeBufferSize > 0 {
+                self.update_pointer_shape(info.PointerShapeBufferSize)?;
+            }
+            if self.capture_cursor {
+                self.changed = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn update_pointer_shape(&mut self, size: u32) -> Result<(), Box<dyn Error>> {
+        let dup = self.duplication.as_ref().unwrap();
+        self.pointer.shape.resize(size as usize, 0);
+        let mut required = 0;
+        let mut shape_info: DXGI_OUTDUPL_POINTER_SHAPE_INFO = unsafe { zeroed() };
+        let hr = unsafe {
+            dup.duplication.GetFramePointerShape(
+                size,
+                self.pointer.shape.as_mut_ptr() as *mut c_void,
+                &mut required,
+                &mut shape_info,
+            )
+        };
+        if hr == DXGI_ERROR_MORE_DATA {
+            self.pointer.shape.clear();
+        }
+        check(hr, "get pointer shape")?;
+        self.pointer.shape_type = shape_info.Type;
+        self.pointer.width = shape_info.Width as usize;
+        self.pointer.height = shape_info.Height as usize;
+        self.pointer.pitch = shape_info.Pitch as usize;
+        Ok(())
+    }
+}
+
+impl Recorder for DxgiRecorder {
+    fn capture(&mut self) -> Result<PixelProvider, Box<dyn Error>> {
+        let timeout = if self.desktop.is_empty() {
+            FIRST_FRAME_TIMEOUT_MS
+        } else {
+            0
+        };
+        self.poll(timeout)?;
+        if self.desktop.is_empty() {
+            return Err("No frame has been duplicated yet!".into());
+        }
+        self.changed = false;
+        self.frame.clear();
+        self.frame.extend_from_slice(&self.desktop);
+        if self.capture_cursor {
+            self.pointer.draw(&mut self.frame, self.width, self.height);
+        }
+        Ok(PixelProvider::BGR0(self.width, self.height, &self.frame))
+    }
+
+    fn has_changed(&mut self) -> bool {
+        // errors are reported by the capture that follows
+        if self.poll(0).is_err() {
+            return true;
+        }
+        self.changed
+    }
+
+    fn capture_method(&self) -> String {
+        "DXGI Desktop Duplication".into()
+    }
+}
@@ -0,0 +1,161 @@
+use std::error::Error;
+use std::mem::{size_of, zeroed};
+use std::os::raw::c_void;
+use std::ptr;
+
+use winapi::shared::windef::{HBITMAP, HDC, HGDIOBJ, RECT};
+use winapi::um::wingdi::*;
+use winapi::um::winuser::*;
+
+use crate::capturable::Recorder;
+use crate::video::PixelProvider;
+
+/// Copy the contents of bitmap to buf as BGR0 with rows ordered top to bottom. The bitmap must
+/// not be selected into a device context.
+pub fn read_bitmap(
+    dc: HDC,
+    bitmap: HBITMAP,
+    width: usize,
+    height: usize,
+    buf: &mut Vec<u8>,
+) -> Result<(), Box<dyn Error>> {
+    buf.resize(width * height * 4, 0);
+    let lines = unsafe {
+        let mut info: BITMAPINFO = zeroed();
+        info.bmiHeader.biSize = size_of::<BITMAPINFOHEADER>() as u32;
+        info.bmiHeader.biWidth = width as i32;
+        // negative height for rows ordered top to bottom
+        info.bmiHeader.biHeight = -(height as i32);
+        info.bmiHeader.biPlanes = 1;
+        info.bmiHeader.biBitCount = 32;
+        info.bmiHeader.biCompression = BI_RGB;
+        GetDIBits(
+            dc,
+            bitmap,
+            0,
+            height as u32,
+            buf.as_mut_ptr() as *mut c_void,
+            &mut info,
+            DIB_RGB_COLORS,
+        )
+    };
+    if lines != height as i32 {
+        return Err("Failed to read bitmap!".into());
+    }
+    Ok(())
+}
+
+/// Captures a part of the desktop by copying it via GDI. This is slow but works everywhere,
+/// including remote desktop sessions where the desktop can not be duplicated.
+pub struct GdiRecorder {
+    screen: RECT,
+    capture_cursor: bool,
+    dc: HDC,
+    bitmap: HBITMAP,
+    buf: Vec<u8>,
+}
+
+impl GdiRecorder {
+    pub fn new(screen: RECT, capture_cursor: bool) -> Result<Self, Box<dyn Error>> {
+        let width = screen.right - screen.left;
+        let height = screen.bottom - screen.top;
+        unsafe {
+            let screen_dc = GetDC(ptr::null_mut());
+            let dc = CreateCompatibleDC(screen_dc);
+            let bitmap = CreateCompatibleBitmap(screen_dc, width, height);
+            ReleaseDC(ptr::null_mut(), screen_dc);
+            if dc.is_null() || bitmap.is_null() {
+                if !bitmap.is_null() {
+                    DeleteObject(bitmap as HGDIOBJ);
+                }
+                if !dc.is_null() {
+                    DeleteDC(dc);
+                }
+                return Err("Failed to create bitmap for capturing the screen!".into());
+            }
+            Ok(Self {
+                screen,
+                capture_cursor,
+                dc,
+                bitmap,
+                buf: Vec::new(),
+            })
+        }
+    }
+
+    /// The cursor is not part of what BitBlt copies, so it is drawn separately.
+    unsafe fn draw_cursor(&self) {
+        let mut cursor: CURSORINFO = zeroed();
+        cursor.cbSize = size_of::<CURSORINFO>() as u32;
+        if GetCursorInfo(&mut cursor) == 0 || cursor.flags & CURSOR_SHOWING == 0 {
+            return;
+        }
+        let mut icon: ICONINFO = zeroed();
+        if GetIconInfo(cursor.hCursor, &mut icon) == 0 {
+            return;
+        }
+        DrawIconEx(
+            self.dc,
+            cursor.ptScreenPos.x - self.screen.left - icon.xHotspot as i32,
+            cursor.ptScreenPos.y - self.screen.top - icon.yHotspot as i32,
+            cursor.hCursor,
+            0,
+            0,
+            0,
+            ptr::null_mut(),
+            DI_NORMAL,
+        );
+        // GetIconInfo creates copies of the bitmaps
+        if !icon.hbmMask.is_null() {
+            DeleteObject(icon.hbmMask as HGDIOBJ);
+        }
+        if !icon.hbmColor.is_null() {
+            DeleteObject(icon.hbmColor as HGDIOBJ);
+        }
+    }
+}
+
+impl Recorder for GdiRecorder {
+    fn capture(&mut self) -> Result<PixelProvider, Box<dyn Error>> {
+        let width = (self.screen.right - self.screen.left) as usize;
+        let height = (self.screen.bottom - self.screen.top) as usize;
+        unsafe {
+            let screen_dc = GetDC(ptr::null_mut());
+            let old_bitmap = SelectObject(self.dc, self.bitmap as HGDIOBJ);
+            let copied = BitBlt(
+                self.dc,
+                0,
+                0,
+                width as i32,
+                height as i32,
+                screen_dc,
+                self.screen.left,
+                self.screen.top,
+                SRCCOPY | CAPTUREBLT,
+            );
+            if copied != 0 && self.capture_cursor {
+                self.draw_cursor();
+            }
+            SelectObject(self.dc, old_bitmap);
+            ReleaseDC(ptr::null_mut(), screen_dc);
+            if copied == 0 {
+                return Err("Failed to copy screen!".into());
+            }
+        }
+        read_bitmap(self.dc, self.bitmap, width, height, &mut self.buf)?;
+        Ok(PixelProvider::BGR0(width, height, &self.buf))
+    }
+
+    fn capture_method(&self) -> String {
+        "GDI".into()
+    }
+}
+
+impl Drop for GdiRecorder {
+    fn drop(&mut self) {
+        unsafe {
+            DeleteObject(self.bitmap as HGDIOBJ);
+            DeleteDC(self.dc);
+        }
+    }
+}
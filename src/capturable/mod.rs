@@ -1,3 +1,4 @@
+use serde::{Deserialize, Serialize};
 use std::boxed::Box;
 use std::error::Error;
 use tracing::warn;
@@ -11,13 +12,51 @@ pub mod remote_desktop_dbus;
 pub mod testsrc;
 
 #[cfg(target_os = "windows")]
-pub mod captrs_capture;
+pub mod dxgi_capture;
+#[cfg(target_os = "windows")]
+pub mod gdi_capture;
 #[cfg(target_os = "windows")]
 pub mod win_ctx;
+#[cfg(target_os = "windows")]
+pub mod win_monitor;
+#[cfg(target_os = "windows")]
+pub mod win_window;
 #[cfg(target_os = "linux")]
 pub mod x11;
 pub trait Recorder {
     fn capture(&mut self) -> Result<crate::video::PixelProvider, Box<dyn Error>>;
+
+    /// Check if the content may have changed since the last call, if not capturing and encoding a
+    /// new frame can be skipped. Recorders that can not detect changes always return true.
+    fn has_changed(&mut self) -> bool {
+        true
+    }
+
+    /// Human readable description of how frames are captured.
+    fn capture_method(&self) -> String;
+
+    /// Where the cursor is on the area captured last and what it looks like, None if the
+    /// recorder can not tell.
+    fn cursor(&mut self) -> Option<Cursor> {
+        None
+    }
+}
+
+/// The cursor as queried by Recorder::cursor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cursor {
+    /// Position of the hotspot relative to the captured area in pixels, it may lie outside of it.
+    pub x: i64,
+    pub y: i64,
+    /// Changes whenever the shape does, for every frame of animated cursors too.
+    pub shape_id: u64,
+    pub width: usize,
+    pub height: usize,
+    /// Position of the hotspot within the image.
+    pub hotspot: (u32, u32),
+    /// One premultiplied ARGB pixel per element, row by row, all transparent if the cursor has
+    /// been hidden.
+    pub pixels: Vec<u32>,
 }
 
 pub trait BoxCloneCapturable {
@@ -34,25 +73,111 @@ where
 }
 /// Relative: x, y, width, height of the Capturable as floats relative to the absolute size of the
 /// screen. For example x=0.5, y=0.0, width=0.5, height=1.0 means the right half of the screen.
+/// Being relative, this does not depend on any scaling of the display, except for X servers that
+/// report scaled monitors with the size of their mode. The last two floats are the horizontal and
+/// vertical factor to stretch the Capturable by around its origin to get the area it covers on the
+/// screen then, 1.0 otherwise.
 /// VirtualScreen: offset_x, offset_y, width, height for a capturable using a virtual screen. (Windows)
+/// These are physical pixels, which requires the process to be DPI aware.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum Geometry {
-    Relative(f64, f64, f64, f64),
+    Relative(f64, f64, f64, f64, f64, f64),
     VirtualScreen(i32, i32, u32, u32, i32, i32),
 }
 
+/// Output showing a monitor with the position and size of its current mode in pixels relative to
+/// the whole desktop.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MonitorInfo {
+    // like eDP-1 or HDMI-A-2
+    pub output: String,
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    // None if unknown
+    pub refresh_rate: Option<f64>,
+}
+
+/// Size of a Capturable on the screen showing it, in millimeters and pixels, and the resulting
+/// dots per inch.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct PhysicalSize {
+    pub width_mm: f64,
+    pub height_mm: f64,
+    pub width: u32,
+    pub height: u32,
+    pub dpi: f64,
+}
+
+impl PhysicalSize {
+    pub fn new(width_mm: f64, height_mm: f64, width: u32, height: u32) -> Self {
+        Self {
+            width_mm,
+            height_mm,
+            width,
+            height,
+            dpi: width as f64 / width_mm * 25.4,
+        }
+    }
+}
+
 pub trait Capturable: Send + BoxCloneCapturable {
     /// Name of the Capturable, for example the window title, if it is a window.
     fn name(&self) -> String;
 
+    /// Identifier of the Capturable that stays the same as long as the Capturable exists, even if
+    /// for example the window title changes.
+    fn id(&self) -> String;
+
+    /// Class of the window, like "firefox", if the Capturable is a window that has one.
+    fn class(&self) -> Option<String> {
+        None
+    }
+
+    /// Process the window belongs to, if the Capturable is a window and that is known.
+    fn pid(&self) -> Option<u32> {
+        None
+    }
+
+    /// True for docks, panels, menus and other windows that are hardly worth capturing.
+    fn is_utility_window(&self) -> bool {
+        false
+    }
+
     /// Return Geometry of the Capturable.
     fn geometry(&self) -> Result<Geometry, Box<dyn Error>>;
 
+    /// Physical size of the Capturable, if the screen showing it tells.
+    fn physical_size(&self) -> Option<PhysicalSize> {
+        None
+    }
+
+    /// The output and mode of the monitor, if the Capturable is one.
+    fn monitor(&self) -> Option<MonitorInfo> {
+        None
+    }
+
     /// Callback that is called right before input is simulated.
     /// Useful to focus the window on input.
     fn before_input(&mut self) -> Result<(), Box<dyn Error>>;
 
     /// Return a Recorder that can record the current capturable.
     fn recorder(&self, capture_cursor: bool) -> Result<Box<dyn Recorder>, Box<dyn Error>>;
+
+    /// The RemoteDesktop portal session the Capturable has been obtained from, if input can be
+    /// sent through it.
+    #[cfg(target_os = "linux")]
+    fn remote_desktop(&self) -> Option<pipewire::RemoteDesktopStream> {
+        None
+    }
+
+    /// The X11 window the Capturable is, if it is one, input can be sent to it directly without
+    /// raising it.
+    #[cfg(target_os = "linux")]
+    fn x11_window(&self) -> Option<x11::X11Capturable> {
+        None
+    }
 }
 
 impl Clone for Box<dyn Capturable> {
@@ -61,32 +186,122 @@ impl Clone for Box<dyn Capturable> {
     }
 }
 
+/// Screen capture backend to use on Linux.
+#[cfg(target_os = "linux")]
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CaptureBackend {
+    /// PipeWire on Wayland sessions, X11 otherwise.
+    #[default]
+    Auto,
+    X11,
+    #[value(name = "pipewire")]
+    PipeWire,
+}
+
+/// How windows are captured via X11, the order has to match enum WindowCapture in
+/// lib/linux/xcapture.c.
+#[cfg(target_os = "linux")]
+#[derive(
+    clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default,
+)]
+pub enum X11WindowCapture {
+    /// Crop the active window from the screen so its menus are visible, other windows are read
+    /// from their redirected pixmap if they are off screen and from the screen otherwise.
+    #[default]
+    Auto,
+    /// Always read the window from its redirected pixmap so windows covering it or it being on
+    /// another workspace do not matter, falls back to cropping if Xcomposite is unavailable.
+    Composite,
+    /// Always crop the window from the screen, for applications that show up black otherwise.
+    Crop,
+}
+
+/// Backends capturables are listed from.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Copy)]
+pub struct CaptureBackends {
+    pub x11: bool,
+    pub pipewire: bool,
+    pub x11_window_capture: X11WindowCapture,
+}
+
+#[cfg(target_os = "linux")]
+impl CaptureBackends {
+    /// Resolve the configured backend by looking at XDG_SESSION_TYPE, `wayland_support` enables
+    /// PipeWire on X11 sessions as well.
+    pub fn select(
+        backend: CaptureBackend,
+        wayland_support: bool,
+        x11_window_capture: X11WindowCapture,
+    ) -> Self {
+        let (x11, pipewire) = match backend {
+            CaptureBackend::Auto => {
+                let wayland = std::env::var("XDG_SESSION_TYPE").is_ok_and(|t| t == "wayland");
+                (!wayland, wayland || wayland_support)
+            }
+            CaptureBackend::X11 => (true, wayland_support),
+            CaptureBackend::PipeWire => (false, true),
+        };
+        Self {
+            x11,
+            pipewire,
+            x11_window_capture,
+        }
+    }
+}
+
 pub fn get_capturables(
-    #[cfg(target_os = "linux")] wayland_support: bool,
+    #[cfg(target_os = "linux")] backends: CaptureBackends,
     #[cfg(target_os = "linux")] capture_cursor: bool,
+    #[cfg(target_os = "macos")] logical_resolution: bool,
 ) -> Vec<Box<dyn Capturable>> {
     let mut capturables: Vec<Box<dyn Capturable>> = vec![];
     #[cfg(target_os = "linux")]
-    {
-        if wayland_support {
-            use crate::capturable::pipewire::get_capturables as get_capturables_pw;
-            match get_capturables_pw(capture_cursor) {
-                Ok(captrs) => {
-                    for c in captrs {
-                        capturables.push(Box::new(c));
-                    }
-                }
-                Err(err) => warn!(
-                    "Failed to get list of capturables via dbus/pipewire: {}",
-                    err
-                ),
+    if backends.pipewire {
+        capturables.extend(get_portal_capturables(capture_cursor));
+    }
+    capturables.extend(get_native_capturables(
+        #[cfg(target_os = "linux")]
+        backends,
+        #[cfg(target_os = "macos")]
+        logical_resolution,
+    ));
+    capturables
+}
+
+/// Capturables that are requested via the xdg-desktop-portal, this usually requires the user to
+/// grant permission in a dialog, so this should not be called without the user asking for it.
+#[cfg(target_os = "linux")]
+pub fn get_portal_capturables(capture_cursor: bool) -> Vec<Box<dyn Capturable>> {
+    let mut capturables: Vec<Box<dyn Capturable>> = vec![];
+    use crate::capturable::pipewire::get_capturables as get_capturables_pw;
+    match get_capturables_pw(capture_cursor) {
+        Ok(captrs) => {
+            for c in captrs {
+                capturables.push(Box::new(c));
             }
         }
+        Err(err) => warn!(
+            "Failed to get list of capturables via dbus/pipewire: {}",
+            err
+        ),
+    }
+    capturables
+}
 
+/// Capturables that can be listed without any user interaction, like screens and windows. This is
+/// cheap enough to be polled to keep track of windows being opened or closed.
+pub fn get_native_capturables(
+    #[cfg(target_os = "linux")] backends: CaptureBackends,
+    #[cfg(target_os = "macos")] logical_resolution: bool,
+) -> Vec<Box<dyn Capturable>> {
+    let mut capturables: Vec<Box<dyn Capturable>> = vec![];
+    #[cfg(target_os = "linux")]
+    if backends.x11 {
         use crate::capturable::x11::X11Context;
         let x11ctx = X11Context::new();
         if let Some(mut x11ctx) = x11ctx {
-            match x11ctx.capturables() {
+            match x11ctx.capturables(backends.x11_window_capture) {
                 Ok(captrs) => {
                     for c in captrs {
                         capturables.push(Box::new(c));
@@ -101,16 +316,12 @@ pub fn get_capturables(
     {
         use crate::capturable::core_graphics::get_displays as get_displays_cg;
         use crate::capturable::core_graphics::get_windows as get_windows_cg;
-        match get_displays_cg() {
-            Ok(captrs) => {
-                for c in captrs {
-                    capturables.push(Box::new(c));
-                }
-            }
+        match get_displays_cg(logical_resolution) {
+            Ok(captrs) => capturables.extend(captrs),
             Err(err) => warn!("Failed to get list of displays via CoreGraphics: {}", err),
         }
 
-        match get_windows_cg() {
+        match get_windows_cg(logical_resolution) {
             Ok(mut captrs) => {
                 captrs.sort_by(|a, b| a.name().to_lowercase().cmp(&b.name().to_lowercase()));
                 for c in captrs {
@@ -123,17 +334,18 @@ pub fn get_capturables(
 
     #[cfg(target_os = "windows")]
     {
-        use crate::capturable::captrs_capture::CaptrsCapturable;
-        use crate::capturable::win_ctx::WinCtx;
-        let winctx = WinCtx::new();
-        for (i, o) in winctx.get_outputs().iter().enumerate() {
-            let captr = CaptrsCapturable::new(
-                i as u8,
-                String::from_utf16_lossy(o.DeviceName.as_ref()),
-                o.DesktopCoordinates,
-                winctx.get_union_rect().clone(),
-            );
-            capturables.push(Box::new(captr));
+        use crate::capturable::win_ctx::get_monitors;
+        use crate::capturable::win_monitor::{DesktopCapturable, MonitorCapturable};
+        let monitors = get_monitors();
+        // with a single monitor the desktop is just that monitor
+        if monitors.len() > 1 {
+            capturables.push(Box::new(DesktopCapturable));
+        }
+        for monitor in monitors {
+            capturables.push(Box::new(MonitorCapturable::new(monitor)));
+        }
+        for window in crate::capturable::win_window::get_windows() {
+            capturables.push(Box::new(window));
         }
     }
 
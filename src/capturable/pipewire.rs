@@ -28,6 +28,32 @@ use crate::capturable::remote_desktop_dbus::{
 struct PwStreamInfo {
     path: u64,
     source_type: u64,
+    // logical size of the monitor or window, not necessarily the size of the video
+    size: Option<(i64, i64)>,
+}
+
+/// Stream of a RemoteDesktop portal session, input sent through it is positioned within the
+/// stream by the compositor.
+#[derive(Clone)]
+pub struct RemoteDesktopStream {
+    dbus_conn: Arc<SyncConnection>,
+    session: dbus::Path<'static>,
+    // device types the user allowed, see select_devices for the bits
+    pub devices: u32,
+    pub node_id: u32,
+    // positions are sent in logical pixels of the stream
+    pub width: f64,
+    pub height: f64,
+}
+
+impl RemoteDesktopStream {
+    /// Call one of the Notify methods of the RemoteDesktop portal for this session.
+    pub fn notify<F>(&self, f: F) -> Result<(), dbus::Error>
+    where
+        F: FnOnce(&Proxy<&SyncConnection>, dbus::Path<'static>) -> Result<(), dbus::Error>,
+    {
+        f(&get_portal(&self.dbus_conn), self.session.clone())
+    }
 }
 
 #[derive(Debug)]
@@ -61,15 +87,36 @@ pub struct PipeWireCapturable {
     fd: OwnedFd,
     path: u64,
     source_type: u64,
+    remote_desktop: Option<RemoteDesktopStream>,
 }
 
 impl PipeWireCapturable {
-    fn new(conn: Arc<SyncConnection>, fd: OwnedFd, stream: PwStreamInfo) -> Self {
+    fn new(
+        conn: Arc<SyncConnection>,
+        fd: OwnedFd,
+        stream: PwStreamInfo,
+        remote_desktop: Option<&(dbus::Path<'static>, u32)>,
+    ) -> Self {
+        // without its size positions can not be mapped onto the stream
+        let remote_desktop =
+            remote_desktop
+                .zip(stream.size)
+                .map(
+                    |((session, devices), (width, height))| RemoteDesktopStream {
+                        dbus_conn: conn.clone(),
+                        session: session.clone(),
+                        devices: *devices,
+                        node_id: stream.path as u32,
+                        width: width as f64,
+                        height: height as f64,
+                    },
+                );
         Self {
             dbus_conn: conn,
             fd,
             path: stream.path,
             source_type: stream.source_type,
+            remote_desktop,
         }
     }
 }
@@ -97,8 +144,12 @@ impl Capturable for PipeWireCapturable {
         format!("Pipewire {}, path: {}", type_str, self.path)
     }
 
+    fn id(&self) -> String {
+        format!("pipewire:{}", self.path)
+    }
+
     fn geometry(&self) -> Result<Geometry, Box<dyn Error>> {
-        Ok(Geometry::Relative(0.0, 0.0, 1.0, 1.0))
+        Ok(Geometry::Relative(0.0, 0.0, 1.0, 1.0, 1.0, 1.0))
     }
 
     fn before_input(&mut self) -> Result<(), Box<dyn Error>> {
@@ -108,6 +159,10 @@ impl Capturable for PipeWireCapturable {
     fn recorder(&self, _capture_cursor: bool) -> Result<Box<dyn Recorder>, Box<dyn Error>> {
         Ok(Box::new(PipeWireRecorder::new(self.clone())?))
     }
+
+    fn remote_desktop(&self) -> Option<RemoteDesktopStream> {
+        self.remote_desktop.clone()
+    }
 }
 
 pub struct PipeWireRecorder {
@@ -247,6 +302,10 @@ impl Recorder for PipeWireRecorder {
             _ => unreachable!(),
         }
     }
+
+    fn capture_method(&self) -> String {
+        "PipeWire".into()
+    }
 }
 
 impl Drop for PipeWireRecorder {
@@ -345,6 +404,10 @@ fn streams_from_response(response: &OrgFreedesktopPortalRequestResponse) -> Vec<
                         source_type: attributes
                             .get("source_type")
                             .map_or(Some(0), |v| v.as_u64())?,
+                        size: attributes.get("size").and_then(|size| {
+                            let mut size = size.as_iter()?;
+                            Some((size.next()?.as_i64()?, size.next()?.as_i64()?))
+                        }),
                     })
                 })
                 .collect::<Vec<PwStreamInfo>>(),
@@ -360,6 +423,8 @@ struct CallBackContext {
     session: dbus::Path<'static>,
     streams: Vec<PwStreamInfo>,
     fd: Option<OwnedFd>,
+    // device types granted by the user for a RemoteDesktop session
+    devices: u32,
     restore_token: Option<String>,
     is_plasma: bool,
     failure: bool,
@@ -523,6 +588,9 @@ fn on_start_response(
     context
         .fd
         .replace(portal.open_pipe_wire_remote(session.clone(), HashMap::new())?);
+    if let Some(Some(devices)) = r.results.get("devices").map(|d| d.as_u64()) {
+        context.devices = devices as u32;
+    }
     if let Some(Some(t)) = r.results.get("restore_token").map(|t| t.as_str()) {
         context.restore_token = Some(t.to_string());
     }
@@ -535,9 +603,19 @@ fn on_start_response(
     Ok(())
 }
 
+/// Returns the session and the device types that have been granted as well if input can be sent
+/// through the session.
 fn request_remote_desktop(
     capture_cursor: bool,
-) -> Result<(SyncConnection, OwnedFd, Vec<PwStreamInfo>), Box<dyn Error>> {
+) -> Result<
+    (
+        SyncConnection,
+        OwnedFd,
+        Vec<PwStreamInfo>,
+        Option<(dbus::Path<'static>, u32)>,
+    ),
+    Box<dyn Error>,
+> {
     let conn = SyncConnection::new_session()?;
     let portal = get_portal(&conn);
 
@@ -548,6 +626,7 @@ fn request_remote_desktop(
         session: Default::default(),
         streams: Default::default(),
         fd: None,
+        devices: 0,
         restore_token: None,
         is_plasma,
         failure: false,
@@ -587,7 +666,15 @@ fn request_remote_desktop(
     }
     let context = context.lock().unwrap();
     if context.fd.is_some() && !context.streams.is_empty() {
-        Ok((conn, context.fd.clone().unwrap(), context.streams.clone()))
+        // Plasma only offers a ScreenCast session
+        let remote_desktop = (!context.is_plasma && context.devices != 0)
+            .then(|| (context.session.clone(), context.devices));
+        Ok((
+            conn,
+            context.fd.clone().unwrap(),
+            context.streams.clone(),
+            remote_desktop,
+        ))
     } else {
         Err(Box::new(DBusError(
             "Failed to obtain screen capture.".into(),
@@ -596,10 +683,10 @@ fn request_remote_desktop(
 }
 
 pub fn get_capturables(capture_cursor: bool) -> Result<Vec<PipeWireCapturable>, Box<dyn Error>> {
-    let (conn, fd, streams) = request_remote_desktop(capture_cursor)?;
+    let (conn, fd, streams, remote_desktop) = request_remote_desktop(capture_cursor)?;
     let conn = Arc::new(conn);
     Ok(streams
         .into_iter()
-        .map(|s| PipeWireCapturable::new(conn.clone(), fd.clone(), s))
+        .map(|s| PipeWireCapturable::new(conn.clone(), fd.clone(), s, remote_desktop.as_ref()))
         .collect())
 }
@@ -43,8 +43,11 @@ impl Capturable for TestCapturable {
     fn name(&self) -> String {
         format!("Test Source {}x{}", self.width, self.height)
     }
+    fn id(&self) -> String {
+        format!("test:{}x{}", self.width, self.height)
+    }
     fn geometry(&self) -> Result<Geometry, Box<dyn Error>> {
-        Ok(Geometry::Relative(0.0, 0.0, 1.0, 1.0))
+        Ok(Geometry::Relative(0.0, 0.0, 1.0, 1.0, 1.0, 1.0))
     }
     fn before_input(&mut self) -> Result<(), Box<dyn Error>> {
         Ok(())
@@ -80,4 +83,8 @@ impl Recorder for TestRecorder {
             self.buf.as_slice(),
         ))
     }
+
+    fn capture_method(&self) -> String {
+        "test source".into()
+    }
 }
@@ -1,91 +1,92 @@
-use std::mem::zeroed;
-use std::{mem, ptr};
-use winapi::shared::dxgi::{
-    CreateDXGIFactory1, IDXGIAdapter1, IDXGIFactory1, IDXGIOutput, IID_IDXGIFactory1,
-    DXGI_OUTPUT_DESC,
-};
+use std::mem::{size_of, zeroed};
+use std::ptr;
 
-use winapi::shared::windef::*;
-use winapi::shared::winerror::*;
+use winapi::shared::minwindef::{BOOL, LPARAM, TRUE};
+use winapi::shared::windef::{HDC, HMONITOR, LPRECT, RECT};
 use winapi::um::winuser::*;
-use wio::com::ComPtr;
 
-// from https://github.com/bryal/dxgcap-rs/blob/009b746d1c19c4c10921dd469eaee483db6aa002/src/lib.r
-fn hr_failed(hr: HRESULT) -> bool {
-    hr < 0
+/// A monitor that is part of the desktop, identified by the name of its display device like
+/// \\.\DISPLAY1, which is what DXGI outputs are matched by as well.
+#[derive(Clone)]
+pub struct Monitor {
+    pub device_name: String,
+    // in physical pixels, relative to the primary monitor
+    pub rect: RECT,
+    pub primary: bool,
 }
 
-fn create_dxgi_factory_1() -> ComPtr<IDXGIFactory1> {
-    unsafe {
-        let mut factory = ptr::null_mut();
-        let hr = CreateDXGIFactory1(&IID_IDXGIFactory1, &mut factory);
-        if hr_failed(hr) {
-            panic!("Failed to create DXGIFactory1, {:x}", hr)
-        } else {
-            ComPtr::from_raw(factory as *mut IDXGIFactory1)
-        }
+unsafe extern "system" fn add_monitor(
+    monitor: HMONITOR,
+    _dc: HDC,
+    _rect: LPRECT,
+    monitors: LPARAM,
+) -> BOOL {
+    let monitors = &mut *(monitors as *mut Vec<Monitor>);
+    let mut info: MONITORINFOEXW = zeroed();
+    info.cbSize = size_of::<MONITORINFOEXW>() as u32;
+    if GetMonitorInfoW(
+        monitor,
+        &mut info as *mut MONITORINFOEXW as *mut MONITORINFO,
+    ) != 0
+    {
+        let len = info
+            .szDevice
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(info.szDevice.len());
+        monitors.push(Monitor {
+            device_name: String::from_utf16_lossy(&info.szDevice[..len]),
+            rect: info.rcMonitor,
+            primary: info.dwFlags & MONITORINFOF_PRIMARY != 0,
+        });
     }
+    // keep enumerating
+    TRUE
 }
 
-fn get_adapter_outputs(adapter: &IDXGIAdapter1) -> Vec<ComPtr<IDXGIOutput>> {
-    let mut outputs = Vec::new();
-    for i in 0.. {
-        unsafe {
-            let mut output = ptr::null_mut();
-            if hr_failed(adapter.EnumOutputs(i, &mut output)) {
-                break;
-            } else {
-                let mut out_desc = zeroed();
-                (*output).GetDesc(&mut out_desc);
-                if out_desc.AttachedToDesktop != 0 {
-                    outputs.push(ComPtr::from_raw(output))
-                } else {
-                    break;
-                }
-            }
-        }
+/// All monitors of the desktop, the primary one first. Monitors that are plugged in or removed
+/// show up the next time this is called.
+pub fn get_monitors() -> Vec<Monitor> {
+    let mut monitors: Vec<Monitor> = Vec::new();
+    unsafe {
+        EnumDisplayMonitors(
+            ptr::null_mut(),
+            ptr::null(),
+            Some(add_monitor),
+            &mut monitors as *mut Vec<Monitor> as LPARAM,
+        );
     }
-    outputs
+    monitors.sort_by_key(|m| (!m.primary, m.device_name.clone()));
+    monitors
 }
 
-#[derive(Clone)]
-pub struct WinCtx {
-    outputs: Vec<DXGI_OUTPUT_DESC>,
-    union_rect: RECT,
+/// Changes along with the arrangement and the resolutions of the monitors as long as the monitors
+/// do not merely trade places, and unlike the monitors themselves it is cheap to get.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DisplayLayout {
+    monitors: i32,
+    virtual_screen: (i32, i32, i32, i32),
 }
 
-impl WinCtx {
-    pub fn new() -> WinCtx {
-        let mut desktops: Vec<DXGI_OUTPUT_DESC> = Vec::new();
-        let mut union: RECT;
-        unsafe {
-            union = mem::zeroed();
-            let factory = create_dxgi_factory_1();
-            let mut adapter = ptr::null_mut();
-            if factory.EnumAdapters1(0, &mut adapter) != DXGI_ERROR_NOT_FOUND {
-                let adp = ComPtr::from_raw(adapter);
-                let outputs = get_adapter_outputs(&adp);
-                for o in outputs {
-                    let mut desc: DXGI_OUTPUT_DESC = mem::zeroed();
-                    o.GetDesc(ptr::addr_of_mut!(desc));
-                    desktops.push(desc);
-                    UnionRect(
-                        ptr::addr_of_mut!(union),
-                        ptr::addr_of!(union),
-                        ptr::addr_of!(desc.DesktopCoordinates),
-                    );
-                }
-            }
-        }
-        WinCtx {
-            outputs: desktops,
-            union_rect: union,
-        }
-    }
-    pub fn get_outputs(&self) -> &Vec<DXGI_OUTPUT_DESC> {
-        &self.outputs
+pub fn display_layout() -> DisplayLayout {
+    let screen = virtual_screen();
+    DisplayLayout {
+        monitors: unsafe { GetSystemMetrics(SM_CMONITORS) },
+        virtual_screen: (screen.left, screen.top, screen.right, screen.bottom),
     }
-    pub fn get_union_rect(&self) -> &RECT {
-        &self.union_rect
+}
+
+/// Rectangle spanned by all monitors, which is what coordinates of simulated input are relative
+/// to.
+pub fn virtual_screen() -> RECT {
+    unsafe {
+        let left = GetSystemMetrics(SM_XVIRTUALSCREEN);
+        let top = GetSystemMetrics(SM_YVIRTUALSCREEN);
+        RECT {
+            left,
+            top,
+            right: left + GetSystemMetrics(SM_CXVIRTUALSCREEN),
+            bottom: top + GetSystemMetrics(SM_CYVIRTUALSCREEN),
+        }
     }
 }
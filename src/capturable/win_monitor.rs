@@ -0,0 +1,122 @@
+use crate::capturable::dxgi_capture::DxgiRecorder;
+use crate::capturable::gdi_capture::GdiRecorder;
+use crate::capturable::win_ctx::{
+    display_layout, get_monitors, virtual_screen, DisplayLayout, Monitor,
+};
+use crate::capturable::{Capturable, Recorder};
+use std::boxed::Box;
+use std::cell::Cell;
+use std::error::Error;
+use tracing::warn;
+use winapi::shared::windef::RECT;
+
+use super::Geometry;
+
+fn virtual_screen_geometry(screen: RECT) -> Geometry {
+    let virtual_screen = virtual_screen();
+    Geometry::VirtualScreen(
+        screen.left - virtual_screen.left,
+        screen.top - virtual_screen.top,
+        (screen.right - screen.left) as u32,
+        (screen.bottom - screen.top) as u32,
+        screen.left,
+        screen.top,
+    )
+}
+
+/// A single monitor, captured via DXGI if possible.
+#[derive(Clone)]
+pub struct MonitorCapturable {
+    monitor: Monitor,
+    // where the monitor has been found last and the layout of the displays back then
+    rect: Cell<(DisplayLayout, RECT)>,
+}
+
+impl MonitorCapturable {
+    pub fn new(monitor: Monitor) -> MonitorCapturable {
+        let rect = Cell::new((display_layout(), monitor.rect));
+        MonitorCapturable { monitor, rect }
+    }
+
+    /// Where the monitor is now, it may have been rearranged or changed its resolution since it
+    /// has been listed. The monitors are only enumerated again once the layout has changed, the
+    /// geometry is asked for with every pointer event.
+    fn rect(&self) -> RECT {
+        let layout = display_layout();
+        let (known_layout, rect) = self.rect.get();
+        if known_layout == layout {
+            return rect;
+        }
+        let rect = get_monitors()
+            .into_iter()
+            .find(|m| m.device_name == self.monitor.device_name)
+            .map_or(self.monitor.rect, |m| m.rect);
+        self.rect.set((layout, rect));
+        rect
+    }
+}
+
+impl Capturable for MonitorCapturable {
+    fn name(&self) -> String {
+        let rect = self.monitor.rect;
+        format!(
+            "Monitor {} ({}x{}{})",
+            self.monitor.device_name.trim_start_matches("\\\\.\\"),
+            rect.right - rect.left,
+            rect.bottom - rect.top,
+            if self.monitor.primary {
+                ", primary"
+            } else {
+                ""
+            }
+        )
+    }
+    fn id(&self) -> String {
+        format!("win:monitor:{}", self.monitor.device_name)
+    }
+    fn before_input(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+    fn recorder(&self, capture_cursor: bool) -> Result<Box<dyn Recorder>, Box<dyn Error>> {
+        match DxgiRecorder::new(&self.monitor.device_name, capture_cursor) {
+            Ok(recorder) => Ok(Box::new(recorder)),
+            Err(err) => {
+                warn!("Failed to duplicate desktop via DXGI, falling back to GDI: {err}");
+                Ok(Box::new(GdiRecorder::new(self.rect(), capture_cursor)?))
+            }
+        }
+    }
+    fn geometry(&self) -> Result<Geometry, Box<dyn Error>> {
+        Ok(virtual_screen_geometry(self.rect()))
+    }
+}
+
+/// All monitors at once, captured via GDI as DXGI duplicates outputs one by one.
+#[derive(Clone)]
+pub struct DesktopCapturable;
+
+impl Capturable for DesktopCapturable {
+    fn name(&self) -> String {
+        let screen = virtual_screen();
+        format!(
+            "Desktop (all monitors, {}x{})",
+            screen.right - screen.left,
+            screen.bottom - screen.top
+        )
+    }
+    fn id(&self) -> String {
+        "win:desktop".into()
+    }
+    fn before_input(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+    fn recorder(&self, capture_cursor: bool) -> Result<Box<dyn Recorder>, Box<dyn Error>> {
+        Ok(Box::new(GdiRecorder::new(
+            virtual_screen(),
+            capture_cursor,
+        )?))
+    }
+    fn geometry(&self) -> Result<Geometry, Box<dyn Error>> {
+        Ok(virtual_screen_geometry(virtual_screen()))
+    }
+}
@@ -0,0 +1,253 @@
+use std::error::Error;
+use std::mem::{size_of, zeroed};
+use std::os::raw::c_void;
+use std::ptr;
+
+use winapi::shared::minwindef::{BOOL, DWORD, LPARAM, TRUE};
+use winapi::shared::windef::{HBITMAP, HDC, HGDIOBJ, HWND, POINT, RECT};
+use winapi::um::dwmapi::DwmGetWindowAttribute;
+use winapi::um::wingdi::*;
+use winapi::um::winuser::*;
+
+use crate::capturable::gdi_capture::read_bitmap;
+use crate::capturable::{Capturable, Geometry, Recorder};
+use crate::cerror::{CError, CErrorCode};
+use crate::video::PixelProvider;
+
+// not defined by winapi
+const DWMWA_CLOAKED: DWORD = 14;
+const PW_RENDERFULLCONTENT: u32 = 0x2;
+
+/// A single top-level window, captured via PrintWindow so it does not matter if other windows
+/// are covering it.
+#[derive(Clone)]
+pub struct WinWindowCapturable {
+    // HWND is not Send, but the handle is just an identifier that is valid in any thread
+    hwnd: usize,
+    name: String,
+}
+
+impl WinWindowCapturable {
+    fn hwnd(&self) -> HWND {
+        self.hwnd as HWND
+    }
+}
+
+fn window_title(hwnd: HWND) -> String {
+    let mut title = [0u16; 256];
+    let len = unsafe { GetWindowTextW(hwnd, title.as_mut_ptr(), title.len() as i32) };
+    String::from_utf16_lossy(&title[..len.max(0) as usize])
+}
+
+/// Only windows that show up in the taskbar are of interest, this skips tool windows, popups
+/// and windows of UWP apps that are hidden by the DWM.
+fn is_capturable_window(hwnd: HWND) -> bool {
+    unsafe {
+        if IsWindowVisible(hwnd) == 0 || GetWindowTextLengthW(hwnd) == 0 {
+            return false;
+        }
+        if !GetWindow(hwnd, GW_OWNER).is_null() {
+            return false;
+        }
+        if GetWindowLongW(hwnd, GWL_EXSTYLE) as u32 & WS_EX_TOOLWINDOW != 0 {
+            return false;
+        }
+        let mut cloaked: DWORD = 0;
+        let hr = DwmGetWindowAttribute(
+            hwnd,
+            DWMWA_CLOAKED,
+            &mut cloaked as *mut DWORD as *mut c_void,
+            size_of::<DWORD>() as u32,
+        );
+        !(hr >= 0 && cloaked != 0)
+    }
+}
+
+unsafe extern "system" fn enum_window(hwnd: HWND, windows: LPARAM) -> BOOL {
+    let windows = &mut *(windows as *mut Vec<WinWindowCapturable>);
+    if is_capturable_window(hwnd) {
+        windows.push(WinWindowCapturable {
+            hwnd: hwnd as usize,
+            name: window_title(hwnd),
+        });
+    }
+    TRUE
+}
+
+pub fn get_windows() -> Vec<WinWindowCapturable> {
+    let mut windows: Vec<WinWindowCapturable> = Vec::new();
+    unsafe { EnumWindows(Some(enum_window), &mut windows as *mut _ as LPARAM) };
+    windows.sort_by(|a, b| a.name.to_lowercase().cmp(&b.name.to_lowercase()));
+    windows
+}
+
+fn window_lost() -> Box<dyn Error> {
+    Box::new(CError::with_message(
+        CErrorCode::WindowGone,
+        "Window no longer exists!",
+    ))
+}
+
+/// Position and size of the client area of the window in screen coordinates, that is excluding
+/// title bar and borders.
+fn client_rect(hwnd: HWND) -> Result<RECT, Box<dyn Error>> {
+    unsafe {
+        if IsWindow(hwnd) == 0 {
+            return Err(window_lost());
+        }
+        let mut rect: RECT = zeroed();
+        let mut origin = POINT { x: 0, y: 0 };
+        if GetClientRect(hwnd, &mut rect) == 0 || ClientToScreen(hwnd, &mut origin) == 0 {
+            return Err("Failed to get size of window!".into());
+        }
+        Ok(RECT {
+            left: origin.x,
+            top: origin.y,
+            right: origin.x + rect.right,
+            bottom: origin.y + rect.bottom,
+        })
+    }
+}
+
+impl Capturable for WinWindowCapturable {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn id(&self) -> String {
+        format!("win:{:x}", self.hwnd)
+    }
+
+    fn geometry(&self) -> Result<Geometry, Box<dyn Error>> {
+        // queried every time as the window may have been moved to a different monitor
+        let rect = client_rect(self.hwnd())?;
+        let (virtual_left, virtual_top) = unsafe {
+            (
+                GetSystemMetrics(SM_XVIRTUALSCREEN),
+                GetSystemMetrics(SM_YVIRTUALSCREEN),
+            )
+        };
+        Ok(Geometry::VirtualScreen(
+            rect.left - virtual_left,
+            rect.top - virtual_top,
+            (rect.right - rect.left) as u32,
+            (rect.bottom - rect.top) as u32,
+            rect.left,
+            rect.top,
+        ))
+    }
+
+    fn before_input(&mut self) -> Result<(), Box<dyn Error>> {
+        let hwnd = self.hwnd();
+        unsafe {
+            if IsWindow(hwnd) == 0 {
+                return Err(window_lost());
+            }
+            if IsIconic(hwnd) != 0 {
+                ShowWindow(hwnd, SW_RESTORE);
+            }
+            if GetForegroundWindow() != hwnd {
+                SetForegroundWindow(hwnd);
+            }
+        }
+        Ok(())
+    }
+
+    fn recorder(&self, _capture_cursor: bool) -> Result<Box<dyn Recorder>, Box<dyn Error>> {
+        Ok(Box::new(WinWindowRecorder::new(self.hwnd())?))
+    }
+}
+
+pub struct WinWindowRecorder {
+    hwnd: HWND,
+    dc: HDC,
+    bitmap: HBITMAP,
+    width: usize,
+    height: usize,
+    buf: Vec<u8>,
+}
+
+impl WinWindowRecorder {
+    fn new(hwnd: HWND) -> Result<Self, Box<dyn Error>> {
+        let dc = unsafe { CreateCompatibleDC(ptr::null_mut()) };
+        if dc.is_null() {
+            return Err("Failed to create device context!".into());
+        }
+        Ok(Self {
+            hwnd,
+            dc,
+            bitmap: ptr::null_mut(),
+            width: 0,
+            height: 0,
+            buf: Vec::new(),
+        })
+    }
+
+    fn resize(&mut self, width: usize, height: usize) -> Result<(), Box<dyn Error>> {
+        unsafe {
+            if !self.bitmap.is_null() {
+                DeleteObject(self.bitmap as HGDIOBJ);
+            }
+            let window_dc = GetDC(self.hwnd);
+            self.bitmap = CreateCompatibleBitmap(window_dc, width as i32, height as i32);
+            ReleaseDC(self.hwnd, window_dc);
+        }
+        if self.bitmap.is_null() {
+            return Err("Failed to create bitmap!".into());
+        }
+        self.width = width;
+        self.height = height;
+        Ok(())
+    }
+
+    fn last_frame(&self) -> Option<PixelProvider> {
+        (!self.buf.is_empty()).then(|| PixelProvider::BGR0(self.width, self.height, &self.buf))
+    }
+}
+
+impl Recorder for WinWindowRecorder {
+    fn capture(&mut self) -> Result<PixelProvider, Box<dyn Error>> {
+        let rect = client_rect(self.hwnd)?;
+        let width = (rect.right - rect.left).max(0) as usize;
+        let height = (rect.bottom - rect.top).max(0) as usize;
+        // minimized windows have no content that could be rendered, keep showing what was
+        // visible before
+        if unsafe { IsIconic(self.hwnd) } != 0 || width == 0 || height == 0 {
+            return self
+                .last_frame()
+                .ok_or_else(|| "Window is minimized!".into());
+        }
+        if (width, height) != (self.width, self.height) {
+            self.resize(width, height)?;
+        }
+
+        unsafe {
+            let old_bitmap = SelectObject(self.dc, self.bitmap as HGDIOBJ);
+            // PW_RENDERFULLCONTENT makes the DWM render the window, this works for hardware
+            // accelerated windows and windows covered by others
+            let printed = PrintWindow(self.hwnd, self.dc, PW_CLIENTONLY | PW_RENDERFULLCONTENT);
+            // the bitmap must not be selected into a device context for GetDIBits
+            SelectObject(self.dc, old_bitmap);
+            if printed == 0 {
+                return Err("Failed to capture window!".into());
+            }
+        }
+        read_bitmap(self.dc, self.bitmap, width, height, &mut self.buf)?;
+        Ok(PixelProvider::BGR0(width, height, &self.buf))
+    }
+
+    fn capture_method(&self) -> String {
+        "PrintWindow".into()
+    }
+}
+
+impl Drop for WinWindowRecorder {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.bitmap.is_null() {
+                DeleteObject(self.bitmap as HGDIOBJ);
+            }
+            DeleteDC(self.dc);
+        }
+    }
+}
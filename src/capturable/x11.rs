@@ -1,13 +1,16 @@
-use crate::capturable::{Capturable, Geometry, Recorder};
-use crate::cerror::CError;
+use crate::capturable::{
+    Capturable, Cursor, Geometry, MonitorInfo, PhysicalSize, Recorder, X11WindowCapture,
+};
+use crate::cerror::{CError, CErrorCode};
 use crate::video::PixelProvider;
-use std::ffi::{CStr, CString};
-use std::os::raw::{c_char, c_float, c_int, c_uint, c_void};
-use std::slice::from_raw_parts;
-use std::sync::Arc;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_float, c_int, c_long, c_uint, c_ulong, c_void};
+use std::slice::{from_raw_parts, from_raw_parts_mut};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Once};
 use std::{error::Error, fmt};
 
-use tracing::debug;
+use tracing::{debug, warn};
 
 extern "C" {
     fn XOpenDisplay(name: *const c_char) -> *mut c_void;
@@ -17,55 +20,132 @@ extern "C" {
     fn XUnlockDisplay(disp: *mut c_void);
 
     fn x11_set_error_handler();
+    fn x11_watch_connection(disp: *mut c_void);
+    fn x11_connection_lost(disp: *mut c_void) -> c_int;
 
     fn create_capturables(
         disp: *mut c_void,
         handles: *mut *mut c_void,
         num_monitors: *mut c_int,
         size: c_int,
+        with_windows: c_int,
         err: *mut CError,
     ) -> c_int;
 
+    fn x11_has_ewmh_wm(disp: *mut c_void) -> c_int;
+    fn create_desktop_capturable(disp: *mut c_void, name: *const c_char) -> *mut c_void;
     fn clone_capturable(handle: *const c_void) -> *mut c_void;
     fn destroy_capturable(handle: *mut c_void);
     fn get_capturable_name(handle: *const c_void) -> *const c_char;
+    fn get_capturable_window(handle: *const c_void) -> c_ulong;
+    fn get_capturable_class(handle: *const c_void, class: *mut c_char, size: usize) -> c_int;
+    fn get_capturable_pid(handle: *const c_void) -> c_long;
+    fn capturable_is_utility_window(handle: *const c_void) -> c_int;
     fn capturable_before_input(handle: *mut c_void, err: *mut CError);
+    fn capturable_is_window(handle: *const c_void) -> c_int;
+    fn send_window_pointer_event(
+        handle: *const c_void,
+        x: c_float,
+        y: c_float,
+        button: c_uint,
+        pressed: c_int,
+        state: c_uint,
+        err: *mut CError,
+    );
+    fn send_window_key_event(
+        handle: *const c_void,
+        keycode: c_uint,
+        pressed: c_int,
+        state: c_uint,
+        err: *mut CError,
+    );
+    fn get_capturable_monitor(
+        handle: *const c_void,
+        output: *mut *const c_char,
+        x: *mut c_int,
+        y: *mut c_int,
+        width: *mut c_uint,
+        height: *mut c_uint,
+        refresh_mhz: *mut c_uint,
+    ) -> c_int;
+    fn get_capturable_physical_size(
+        handle: *const c_void,
+        width: *mut c_uint,
+        height: *mut c_uint,
+        width_mm: *mut c_uint,
+        height_mm: *mut c_uint,
+    ) -> c_int;
     fn get_geometry_relative(
         handle: *const c_void,
         x: *mut c_float,
         y: *mut c_float,
         width: *mut c_float,
         height: *mut c_float,
+        scale_x: *mut c_float,
+        scale_y: *mut c_float,
         err: *mut CError,
     );
 
-    fn map_input_device_to_entire_screen(
+    fn list_input_devices(disp: *mut c_void, devices: *mut CInputDevice, size: c_int) -> c_int;
+    fn watch_input_devices(disp: *mut c_void);
+    fn input_devices_changed(disp: *mut c_void) -> c_int;
+    fn set_input_device_matrix(
         disp: *mut c_void,
-        device_name: *const c_char,
-        libinput: c_int,
+        device_id: c_int,
+        matrix: *const c_float,
         err: *mut CError,
     );
-    fn start_capture(handle: *const c_void, ctx: *mut c_void, err: *mut CError) -> *mut c_void;
-    fn capture_screen(
-        handle: *mut c_void,
-        img: *mut CImage,
-        capture_cursor: c_int,
+    fn get_screen_config_timestamp(disp: *mut c_void) -> c_ulong;
+    fn get_input_device_matrix(
+        disp: *mut c_void,
+        device_id: c_int,
+        matrix: *mut c_float,
         err: *mut CError,
     );
+
+    fn start_capture(
+        handle: *const c_void,
+        ctx: *mut c_void,
+        window_capture: c_int,
+        err: *mut CError,
+    ) -> *mut c_void;
+    fn capture_screen(handle: *mut c_void, img: *mut CImage, err: *mut CError);
+    fn get_cursor_image(
+        handle: *mut c_void,
+        cursor_changes: *mut c_ulong,
+        cursor: *mut CCursorImage,
+    ) -> c_int;
     fn stop_capture(handle: *mut c_void, err: *mut CError);
+    fn capture_uses_shm(handle: *mut c_void) -> c_int;
+    fn capture_has_changed(
+        handle: *mut c_void,
+        capture_cursor: c_int,
+        cursor_changes: *mut c_ulong,
+        err: *mut CError,
+    ) -> c_int;
+    fn watch_cursor(disp: *mut c_void, watch: c_int);
 }
 
+static X11_INIT: Once = Once::new();
+// capturables are listed again on every refresh, the fallback is only worth a warning once
+static DESKTOP_FALLBACK_WARNING: Once = Once::new();
+static NO_WINDOWS_WARNING: Once = Once::new();
+
+/// Make Xlib safe to use from multiple threads. This has to happen before any other call to
+/// Xlib, so it is best called right at the start of the program. Opening a display calls this as
+/// well, calling it more than once is harmless.
 pub fn x11_init() {
-    unsafe {
+    X11_INIT.call_once(|| unsafe {
         XInitThreads();
         x11_set_error_handler();
-    }
+    });
 }
 
 pub struct X11Capturable {
     handle: *mut c_void,
     // keep a reference to the display so it is not closed while a capturable still exists
     disp: Arc<XDisplay>,
+    window_capture: X11WindowCapture,
 }
 
 impl Clone for X11Capturable {
@@ -74,6 +154,7 @@ impl Clone for X11Capturable {
         Self {
             handle,
             disp: self.disp.clone(),
+            window_capture: self.window_capture,
         }
     }
 }
@@ -84,6 +165,62 @@ impl X11Capturable {
     pub unsafe fn handle(&mut self) -> *mut c_void {
         self.handle
     }
+
+    /// How the window is captured, by default as configured with --x11-window-capture.
+    pub fn window_capture(&self) -> X11WindowCapture {
+        self.window_capture
+    }
+
+    pub fn with_window_capture(mut self, window_capture: X11WindowCapture) -> Self {
+        self.window_capture = window_capture;
+        self
+    }
+
+    /// Send a synthetic pointer event to the window without raising or focusing it. x and y are
+    /// relative to the window, button is an X11 button, 0 for moving, that is pressed or released
+    /// and state the X11 mask of the modifiers and buttons that are down.
+    pub fn send_pointer_event(
+        &self,
+        x: f64,
+        y: f64,
+        button: u32,
+        pressed: bool,
+        state: u32,
+    ) -> Result<(), CError> {
+        let mut err = CError::new();
+        {
+            let _lock = self.disp.lock_connected()?;
+            unsafe {
+                send_window_pointer_event(
+                    self.handle,
+                    x as c_float,
+                    y as c_float,
+                    button,
+                    pressed.into(),
+                    state,
+                    &mut err,
+                )
+            };
+        }
+        if err.is_err() {
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Send a synthetic key event with the X11 keycode to the window without raising or focusing
+    /// it.
+    pub fn send_key_event(&self, keycode: u32, pressed: bool, state: u32) -> Result<(), CError> {
+        let mut err = CError::new();
+        {
+            let _lock = self.disp.lock_connected()?;
+            unsafe { send_window_key_event(self.handle, keycode, pressed.into(), state, &mut err) };
+        }
+        if err.is_err() {
+            return Err(err);
+        }
+        Ok(())
+    }
 }
 
 impl Capturable for X11Capturable {
@@ -95,24 +232,72 @@ impl Capturable for X11Capturable {
         }
     }
 
+    fn id(&self) -> String {
+        // Windows are identified by their XID, monitors do not have one and use their output, the
+        // name changes along with the mode.
+        match unsafe { get_capturable_window(self.handle) } {
+            0 => match self.monitor() {
+                Some(monitor) => format!("x11:{}", monitor.output),
+                None => format!("x11:{}", self.name()),
+            },
+            win => format!("x11:{:#x}", win),
+        }
+    }
+
+    fn class(&self) -> Option<String> {
+        let mut class: [c_char; 256] = [0; 256];
+        let found = {
+            let _lock = self.disp.lock_connected().ok()?;
+            unsafe { get_capturable_class(self.handle, class.as_mut_ptr(), class.len()) }
+        };
+        if found == 0 {
+            return None;
+        }
+        Some(
+            unsafe { CStr::from_ptr(class.as_ptr()) }
+                .to_string_lossy()
+                .into(),
+        )
+    }
+
+    fn pid(&self) -> Option<u32> {
+        let pid = {
+            let _lock = self.disp.lock_connected().ok()?;
+            unsafe { get_capturable_pid(self.handle) }
+        };
+        u32::try_from(pid).ok()
+    }
+
+    fn is_utility_window(&self) -> bool {
+        let Ok(_lock) = self.disp.lock_connected() else {
+            return false;
+        };
+        unsafe { capturable_is_utility_window(self.handle) != 0 }
+    }
+
     fn geometry(&self) -> Result<Geometry, Box<dyn Error>> {
         let mut x: c_float = 0.0;
         let mut y: c_float = 0.0;
         let mut width: c_float = 0.0;
         let mut height: c_float = 0.0;
+        let mut scale_x: c_float = 1.0;
+        let mut scale_y: c_float = 1.0;
         let mut err = CError::new();
-        self.disp.lock();
-        unsafe {
-            get_geometry_relative(
-                self.handle,
-                &mut x,
-                &mut y,
-                &mut width,
-                &mut height,
-                &mut err,
-            );
+        {
+            let _lock = self.disp.lock_connected()?;
+            unsafe {
+                get_geometry_relative(
+                    self.handle,
+                    &mut x,
+                    &mut y,
+                    &mut width,
+                    &mut height,
+                    &mut scale_x,
+                    &mut scale_y,
+                    &mut err,
+                );
+            }
         }
-        self.disp.unlock();
         if err.is_err() {
             return Err(Box::new(err));
         }
@@ -121,14 +306,64 @@ impl Capturable for X11Capturable {
             y.into(),
             width.into(),
             height.into(),
+            scale_x.into(),
+            scale_y.into(),
         ))
     }
 
+    fn x11_window(&self) -> Option<X11Capturable> {
+        (unsafe { capturable_is_window(self.handle) } != 0).then(|| self.clone())
+    }
+
+    fn monitor(&self) -> Option<MonitorInfo> {
+        let mut output: *const c_char = std::ptr::null();
+        let (mut x, mut y, mut width, mut height, mut refresh_mhz) = (0, 0, 0, 0, 0);
+        // like the physical size this is known since the monitors have been listed
+        let found = unsafe {
+            get_capturable_monitor(
+                self.handle,
+                &mut output,
+                &mut x,
+                &mut y,
+                &mut width,
+                &mut height,
+                &mut refresh_mhz,
+            )
+        };
+        if found == 0 {
+            return None;
+        }
+        Some(MonitorInfo {
+            output: unsafe { CStr::from_ptr(output) }.to_string_lossy().into(),
+            x,
+            y,
+            width,
+            height,
+            refresh_rate: (refresh_mhz > 0).then(|| f64::from(refresh_mhz) / 1000.0),
+        })
+    }
+
+    fn physical_size(&self) -> Option<PhysicalSize> {
+        let (mut width, mut height, mut width_mm, mut height_mm) = (0, 0, 0, 0);
+        // monitors are sized when they are listed, no request is made
+        let found = unsafe {
+            get_capturable_physical_size(
+                self.handle,
+                &mut width,
+                &mut height,
+                &mut width_mm,
+                &mut height_mm,
+            )
+        };
+        (found != 0).then(|| PhysicalSize::new(width_mm.into(), height_mm.into(), width, height))
+    }
+
     fn before_input(&mut self) -> Result<(), Box<dyn Error>> {
         let mut err = CError::new();
-        self.disp.lock();
-        unsafe { capturable_before_input(self.handle, &mut err) };
-        self.disp.unlock();
+        {
+            let _lock = self.disp.lock_connected()?;
+            unsafe { capturable_before_input(self.handle, &mut err) };
+        }
         if err.is_err() {
             Err(Box::new(err))
         } else {
@@ -158,38 +393,127 @@ impl Drop for X11Capturable {
     }
 }
 
+/// Connection to the X server shared by all capturables and recorders created from it, which may
+/// be used from different threads. Xlib calls have to be made while holding the lock.
 struct XDisplay {
     handle: *mut c_void,
+    // recorders that need the events about changes of the cursor, they are selected while any is
+    // alive
+    cursor_watchers: AtomicUsize,
+    // the events are taken from the queue of the display, which all recorders share, so they are
+    // counted here and every recorder compares the count with the one it has seen last
+    cursor_changes: AtomicU64,
 }
 
 impl XDisplay {
     pub fn new() -> Option<Self> {
+        x11_init();
         let handle = unsafe { XOpenDisplay(std::ptr::null()) };
         if handle.is_null() {
             return None;
         }
-        Some(Self { handle })
+        unsafe { x11_watch_connection(handle) };
+        Some(Self {
+            handle,
+            cursor_watchers: AtomicUsize::new(0),
+            cursor_changes: AtomicU64::new(0),
+        })
     }
 
-    pub fn lock(&self) {
-        unsafe { XLockDisplay(self.handle) }
+    /// Lock the display until the returned guard is dropped.
+    pub fn lock(&self) -> XDisplayLock<'_> {
+        unsafe { XLockDisplay(self.handle) };
+        XDisplayLock { disp: self }
     }
 
-    pub fn unlock(&self) {
-        unsafe { XUnlockDisplay(self.handle) }
+    /// Like lock but fails if the connection to the X server has been lost, capturables and
+    /// recorders of a lost display are gone for good.
+    pub fn lock_connected(&self) -> Result<XDisplayLock<'_>, CError> {
+        let lock = self.lock();
+        if unsafe { x11_connection_lost(self.handle) } != 0 {
+            return Err(CError::with_message(
+                CErrorCode::WindowGone,
+                "Lost connection to the X server.",
+            ));
+        }
+        Ok(lock)
+    }
+
+    pub fn is_lost(&self) -> bool {
+        let _lock = self.lock();
+        unsafe { x11_connection_lost(self.handle) != 0 }
     }
 }
 
 impl Drop for XDisplay {
     fn drop(&mut self) {
-        self.lock();
+        // this is the last reference, so nobody else can be using the display, locking is not
+        // possible anyway as the lock is freed by XCloseDisplay
         unsafe { XCloseDisplay(self.handle) };
-        self.unlock();
     }
 }
 
+struct XDisplayLock<'a> {
+    disp: &'a XDisplay,
+}
+
+impl XDisplayLock<'_> {
+    /// Call f with the count of cursor changes on the display, which f may increase.
+    fn with_cursor_changes<T>(&self, f: impl FnOnce(*mut c_ulong) -> T) -> T {
+        let mut changes = self.disp.cursor_changes.load(Ordering::Relaxed) as c_ulong;
+        let result = f(&mut changes);
+        self.disp
+            .cursor_changes
+            .store(changes as u64, Ordering::Relaxed);
+        result
+    }
+
+    fn watch_cursor(&self) {
+        if self.disp.cursor_watchers.fetch_add(1, Ordering::Relaxed) == 0 {
+            unsafe { watch_cursor(self.disp.handle, 1) };
+        }
+    }
+
+    fn unwatch_cursor(&self) {
+        if self.disp.cursor_watchers.fetch_sub(1, Ordering::Relaxed) == 1 {
+            unsafe { watch_cursor(self.disp.handle, 0) };
+        }
+    }
+}
+
+impl Drop for XDisplayLock<'_> {
+    fn drop(&mut self) {
+        unsafe { XUnlockDisplay(self.disp.handle) }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CInputDevice {
+    id: c_int,
+    name: [c_char; 128],
+    device_type: c_int,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum X11InputDeviceType {
+    Pointer,
+    Pen,
+    Touch,
+}
+
+/// Pointing device known to the X server.
+#[derive(Debug, Clone)]
+pub struct X11InputDevice {
+    pub id: i32,
+    pub name: String,
+    pub device_type: X11InputDeviceType,
+}
+
 pub struct X11Context {
     disp: Arc<XDisplay>,
+    // invalidated once devices are added or removed
+    input_devices: Option<Vec<X11InputDevice>>,
 }
 
 impl X11Context {
@@ -197,88 +521,554 @@ impl X11Context {
         let disp = XDisplay::new()?;
         Some(Self {
             disp: Arc::new(disp),
+            input_devices: None,
         })
     }
 
-    pub fn capturables(&mut self) -> Result<Vec<X11Capturable>, CError> {
-        let mut err = CError::new();
-        let mut handles = [std::ptr::null_mut::<c_void>(); 128];
-        let mut num_monitors: c_int = 0;
-        self.disp.lock();
-        let size = unsafe {
-            create_capturables(
-                self.disp.handle,
-                handles.as_mut_ptr(),
-                &mut num_monitors,
-                handles.len() as c_int,
-                &mut err,
+    /// Open a new connection if the one to the X server has been lost, for example because it
+    /// has been restarted. The old one is closed once all capturables created from it are gone.
+    fn reconnect_if_lost(&mut self) -> Result<(), CError> {
+        if !self.disp.is_lost() {
+            return Ok(());
+        }
+        warn!("Lost connection to the X server, reconnecting.");
+        let disp = XDisplay::new().ok_or_else(|| {
+            CError::with_message(
+                CErrorCode::GenericError,
+                "Failed to reconnect to the X server.",
             )
+        })?;
+        self.disp = Arc::new(disp);
+        self.input_devices = None;
+        Ok(())
+    }
+
+    pub fn capturables(
+        &mut self,
+        window_capture: X11WindowCapture,
+    ) -> Result<Vec<X11Capturable>, CError> {
+        self.reconnect_if_lost()?;
+        // Without a window manager that follows EWMH windows are missing, stale or not even top
+        // level windows, which fail to capture. The desktop and monitors are there regardless.
+        let has_ewmh_wm = {
+            let _lock = self.disp.lock();
+            unsafe { x11_has_ewmh_wm(self.disp.handle) != 0 }
         };
-        self.disp.unlock();
-        if err.is_err() {
-            if err.code() == 2 {
-                debug!("{}", err);
-            } else {
-                return Err(err);
-            }
+        if !has_ewmh_wm {
+            let reason = "no EWMH compliant window manager is running";
+            NO_WINDOWS_WARNING.call_once(|| {
+                warn!(
+                    "Windows can not be captured via X11, only the desktop and monitors: {reason}"
+                )
+            });
+            debug!("Listing no windows via X11: {reason}");
         }
-        let mut capturables: Vec<X11Capturable> = handles[0..size as usize]
+        // enough for most setups, if there are more capturables they are queried again
+        let mut handles = vec![std::ptr::null_mut::<c_void>(); 128];
+        let mut num_monitors: c_int = 0;
+        let size = loop {
+            let mut err = CError::new();
+            let total = {
+                let _lock = self.disp.lock();
+                unsafe {
+                    create_capturables(
+                        self.disp.handle,
+                        handles.as_mut_ptr(),
+                        &mut num_monitors,
+                        handles.len() as c_int,
+                        has_ewmh_wm as c_int,
+                        &mut err,
+                    )
+                }
+            } as usize;
+            // without a window manager or monitors there is still the desktop
+            let partial = matches!(
+                err.code(),
+                CErrorCode::NoWindowManager | CErrorCode::MonitorsUnavailable
+            );
+            if total <= handles.len() && (!err.is_err() || partial) {
+                if err.is_err() {
+                    debug!("{}", err);
+                }
+                break total;
+            }
+            for handle in &handles[..total.min(handles.len())] {
+                unsafe { destroy_capturable(*handle) };
+            }
+            if err.is_err() && !partial {
+                return Ok(vec![self.desktop_fallback(&err.to_string())]);
+            }
+            // windows may have been opened in the meantime, so the next try can fall short as well
+            debug!(
+                "There are {total} capturables, more than {}.",
+                handles.len()
+            );
+            handles.resize(total, std::ptr::null_mut());
+        };
+        let mut capturables: Vec<X11Capturable> = handles[0..size]
             .iter()
             .map(|handle| X11Capturable {
                 handle: *handle,
                 disp: self.disp.clone(),
+                window_capture,
             })
             .collect();
         // The first capturable is always the whole desktop, after that there is num_monitors
         // monitors and finally windows.
-        let win = &mut capturables[(num_monitors as usize + 1)..(size as usize)];
+        let win = &mut capturables[(num_monitors as usize + 1)..size];
         win.sort_by(|a, b| a.name().to_lowercase().cmp(&b.name().to_lowercase()));
         Ok(capturables)
     }
 
-    pub fn map_input_device_to_entire_screen(&mut self, device_name: &str, pen: bool) -> CError {
-        let mut err = CError::new();
-        let device_name_c_str = CString::new(device_name).unwrap();
-        self.disp.lock();
-        unsafe {
-            map_input_device_to_entire_screen(
+    /// The whole desktop on its own, labeled so users know why nothing else is offered.
+    fn desktop_fallback(&self, reason: &str) -> X11Capturable {
+        DESKTOP_FALLBACK_WARNING
+            .call_once(|| warn!("Only the whole desktop can be captured via X11: {reason}"));
+        debug!("Falling back to capturing the whole desktop via X11: {reason}");
+        let name = c"Desktop (window capture unsupported)";
+        let handle = {
+            let _lock = self.disp.lock();
+            unsafe { create_desktop_capturable(self.disp.handle, name.as_ptr()) }
+        };
+        X11Capturable {
+            handle,
+            disp: self.disp.clone(),
+            // there is no window to capture anyway
+            window_capture: X11WindowCapture::default(),
+        }
+    }
+
+    /// Pointing devices known to the X server, keyboards are not included.
+    pub fn input_devices(&mut self) -> &[X11InputDevice] {
+        if let Err(err) = self.reconnect_if_lost() {
+            debug!("{}", err);
+            return &[];
+        }
+        let lock = self.disp.lock();
+        let changed = match self.input_devices {
+            Some(_) => unsafe { input_devices_changed(self.disp.handle) != 0 },
+            None => {
+                unsafe { watch_input_devices(self.disp.handle) };
+                true
+            }
+        };
+        if changed {
+            let devices = self.query_input_devices();
+            for device in &devices {
+                debug!(
+                    "Found input device {} with id {} ({:?}).",
+                    device.name, device.id, device.device_type
+                );
+            }
+            self.input_devices = Some(devices);
+        }
+        drop(lock);
+        self.input_devices.as_deref().unwrap_or_default()
+    }
+
+    fn query_input_devices(&self) -> Vec<X11InputDevice> {
+        let mut devices = [CInputDevice {
+            id: 0,
+            name: [0; 128],
+            device_type: 0,
+        }; 256];
+        let n = unsafe {
+            list_input_devices(
                 self.disp.handle,
-                device_name_c_str.as_ptr(),
-                pen.into(),
-                &mut err,
+                devices.as_mut_ptr(),
+                devices.len() as c_int,
             )
         };
-        self.disp.unlock();
+        devices[..n as usize]
+            .iter()
+            .map(|device| X11InputDevice {
+                id: device.id,
+                name: unsafe { CStr::from_ptr(device.name.as_ptr()) }
+                    .to_string_lossy()
+                    .into_owned(),
+                device_type: match device.device_type {
+                    1 => X11InputDeviceType::Pen,
+                    2 => X11InputDeviceType::Touch,
+                    _ => X11InputDeviceType::Pointer,
+                },
+            })
+            .collect()
+    }
+
+    fn find_input_device(&mut self, device_name: &str, pen: bool) -> Option<i32> {
+        // For some reason a device simulating a stylus does NOT create a single device but
+        // actually two: One with the original name and the other one with " Pen (0)" appended
+        // to it. Only the latter permits setting the "Coordinate Transformation Matrix".
+        let pen_name = format!("{device_name} Pen");
+        let matches = |device: &X11InputDevice| {
+            if pen {
+                device.name.starts_with(&pen_name)
+            } else {
+                device.name == device_name
+            }
+        };
+        if let Some(device) = self.input_devices().iter().find(|d| matches(d)) {
+            return Some(device.id);
+        }
+        // the device may have been created just now and the event announcing it is not there
+        // yet
+        self.input_devices = None;
+        self.input_devices()
+            .iter()
+            .find(|d| matches(d))
+            .map(|device| device.id)
+    }
+
+    pub fn map_input_device_to_entire_screen(&mut self, device_name: &str, pen: bool) -> CError {
+        self.map_input_device_to_rect(device_name, pen, (0.0, 0.0, 1.0, 1.0))
+    }
+
+    /// Map the input device to the rectangle x, y, width, height given relative to the size of
+    /// the screen, like the geometry of a window.
+    pub fn map_input_device_to_rect(
+        &mut self,
+        device_name: &str,
+        pen: bool,
+        rect: (f64, f64, f64, f64),
+    ) -> CError {
+        match self.find_input_device(device_name, pen) {
+            Some(device_id) => self.map_input_device_id_to_rect(device_id, rect),
+            None => {
+                let err = CError::with_message(
+                    CErrorCode::DeviceNotFound,
+                    &format!("Device {device_name} not found!"),
+                );
+                debug!("Failed to map input device: {}", &err);
+                err
+            }
+        }
+    }
+
+    /// Like map_input_device_to_rect but identifying the device by its id, names are not
+    /// necessarily unique.
+    pub fn map_input_device_id_to_rect(
+        &mut self,
+        device_id: i32,
+        rect: (f64, f64, f64, f64),
+    ) -> CError {
+        let mut err = CError::new();
+        let matrix = DeviceMapping::new(rect)
+            .matrix()
+            .map(|value| value as c_float);
+        {
+            let _lock = self.disp.lock();
+            unsafe {
+                set_input_device_matrix(
+                    self.disp.handle,
+                    device_id as c_int,
+                    matrix.as_ptr(),
+                    &mut err,
+                )
+            };
+        }
         if err.is_err() {
-            debug!("Failed to map input device to screen: {}", &err);
+            debug!("Failed to map input device: {}", &err);
         }
         err
     }
+
+    /// Timestamp that changes whenever the resolution, rotation or set of monitors changes.
+    pub fn screen_config_timestamp(&mut self) -> u64 {
+        // a new connection most likely means a new screen configuration, which the timestamp of
+        // the new connection tells
+        if let Err(err) = self.reconnect_if_lost() {
+            debug!("{}", err);
+            return 0;
+        }
+        let _lock = self.disp.lock();
+        unsafe { get_screen_config_timestamp(self.disp.handle) as u64 }
+    }
+
+    /// How the input device is mapped onto the screen right now, which is not necessarily how
+    /// it has been mapped by map_input_device_to_rect. Compositors map touchscreens and tablets
+    /// onto an output as well and rotate them along with it, as if the device was built into the
+    /// panel, which leaves input turned by 90° on an output rotated with xrandr --rotate left.
+    /// None if the device can not be found or its matrix is no mapping onto a rectangle.
+    pub fn input_device_mapping(&mut self, device_name: &str, pen: bool) -> Option<DeviceMapping> {
+        let device_id = self.find_input_device(device_name, pen)?;
+        let mut matrix: [c_float; 9] = [0.0; 9];
+        let mut err = CError::new();
+        {
+            let _lock = self.disp.lock();
+            unsafe {
+                get_input_device_matrix(
+                    self.disp.handle,
+                    device_id as c_int,
+                    matrix.as_mut_ptr(),
+                    &mut err,
+                )
+            };
+        }
+        if err.is_err() {
+            debug!("Failed to get the mapping of input device: {}", &err);
+            return None;
+        }
+        DeviceMapping::from_matrix(&matrix.map(f64::from))
+    }
+}
+
+/// Rotation and reflection, the bits of RR_Rotate_* and RR_Reflect_* like RandR uses them for
+/// outputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputTransform(pub u16);
+
+impl OutputTransform {
+    pub const ROTATE_0: u16 = 1;
+    pub const ROTATE_90: u16 = 2;
+    pub const ROTATE_180: u16 = 4;
+    pub const ROTATE_270: u16 = 8;
+    pub const REFLECT_X: u16 = 16;
+    pub const REFLECT_Y: u16 = 32;
+
+    // x = a * u + b * v + c, y = d * u + e * v + f, returned as [a, b, c, d, e, f], takes the
+    // coordinates u, v of the device to the point x, y of the output showing them, all of them
+    // within 0..1
+    fn affine(self) -> [f64; 6] {
+        // rotations are counterclockwise, like xrandr --rotate left for ROTATE_90
+        let [mut a, mut b, mut c, mut d, mut e, mut f] = match self.0 & 0xf {
+            Self::ROTATE_90 => [0.0, -1.0, 1.0, 1.0, 0.0, 0.0],
+            Self::ROTATE_180 => [-1.0, 0.0, 1.0, 0.0, -1.0, 1.0],
+            Self::ROTATE_270 => [0.0, 1.0, 0.0, -1.0, 0.0, 1.0],
+            _ => [1.0, 0.0, 0.0, 0.0, 1.0, 0.0],
+        };
+        // the output is reflected after it has been rotated
+        if self.0 & Self::REFLECT_X != 0 {
+            (a, b, c) = (-a, -b, 1.0 - c);
+        }
+        if self.0 & Self::REFLECT_Y != 0 {
+            (d, e, f) = (-d, -e, 1.0 - f);
+        }
+        [a, b, c, d, e, f]
+    }
+
+    // all distinct transforms, reflecting vertically is the same as rotating by 180° and
+    // reflecting horizontally
+    const ALL: [u16; 8] = [
+        Self::ROTATE_0,
+        Self::ROTATE_90,
+        Self::ROTATE_180,
+        Self::ROTATE_270,
+        Self::ROTATE_0 | Self::REFLECT_X,
+        Self::ROTATE_90 | Self::REFLECT_X,
+        Self::ROTATE_180 | Self::REFLECT_X,
+        Self::ROTATE_270 | Self::REFLECT_X,
+    ];
+
+    /// Coordinates u, v of the device for it to hit the point x, y of the output, the inverse of
+    /// affine.
+    pub fn to_device(self, x: f64, y: f64) -> (f64, f64) {
+        let [a, b, c, d, e, f] = self.affine();
+        // the linear part only swaps and flips axes, so the inverse is its transpose
+        let (x, y) = (x - c, y - f);
+        (a * x + d * y, b * x + e * y)
+    }
+}
+
+/// How the Coordinate Transformation Matrix of an input device maps it onto the screen.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeviceMapping {
+    /// x, y, width, height relative to the size of the screen
+    pub rect: (f64, f64, f64, f64),
+    /// applied on top, by compositors that rotate devices along with the output they are on
+    pub transform: OutputTransform,
+}
+
+impl DeviceMapping {
+    pub fn new(rect: (f64, f64, f64, f64)) -> Self {
+        Self {
+            rect,
+            transform: OutputTransform::default(),
+        }
+    }
+
+    /// Coordinate Transformation Matrix for this mapping, row by row.
+    pub fn matrix(&self) -> [f64; 9] {
+        let (x, y, width, height) = self.rect;
+        let [a, b, c, d, e, f] = self.transform.affine();
+        [
+            width * a,
+            width * b,
+            x + width * c,
+            height * d,
+            height * e,
+            y + height * f,
+            0.0,
+            0.0,
+            1.0,
+        ]
+    }
+
+    /// The mapping a Coordinate Transformation Matrix stands for, None if it shears, scales by a
+    /// negative factor or does anything else a mapping onto a rectangle does not.
+    pub fn from_matrix(matrix: &[f64; 9]) -> Option<Self> {
+        const EPSILON: f64 = 1e-4;
+        if matrix[6].abs() > EPSILON
+            || matrix[7].abs() > EPSILON
+            || (matrix[8] - 1.0).abs() > EPSILON
+        {
+            return None;
+        }
+        // only one of the two factors of a row is not zero
+        let width = matrix[0].abs() + matrix[1].abs();
+        let height = matrix[3].abs() + matrix[4].abs();
+        if width < EPSILON || height < EPSILON {
+            return None;
+        }
+        OutputTransform::ALL.into_iter().find_map(|transform| {
+            let transform = OutputTransform(transform);
+            let [a, b, c, d, e, f] = transform.affine();
+            let matches = [
+                (0, width * a),
+                (1, width * b),
+                (3, height * d),
+                (4, height * e),
+            ]
+            .into_iter()
+            .all(|(i, value)| (matrix[i] - value).abs() < EPSILON);
+            matches.then(|| Self {
+                rect: (matrix[2] - width * c, matrix[5] - height * f, width, height),
+                transform,
+            })
+        })
+    }
+
+    /// Coordinates, within 0..1, to send to the device for it to hit the point x, y given
+    /// relative to the size of the screen. Points outside of the rectangle can not be reached,
+    /// they end up on its edge.
+    pub fn to_device(&self, x: f64, y: f64) -> (f64, f64) {
+        let (rect_x, rect_y, width, height) = self.rect;
+        let (u, v) = self
+            .transform
+            .to_device((x - rect_x) / width, (y - rect_y) / height);
+        (u.clamp(0.0, 1.0), v.clamp(0.0, 1.0))
+    }
+}
+
+impl Default for OutputTransform {
+    fn default() -> Self {
+        Self(Self::ROTATE_0)
+    }
 }
 
 #[repr(C)]
 struct CImage {
-    data: *const u8,
+    data: *mut u8,
     width: c_uint,
     height: c_uint,
+    // XImages pad their rows as the X server likes
+    stride: c_uint,
 }
 
 impl CImage {
     pub fn new() -> Self {
         Self {
-            data: std::ptr::null(),
+            data: std::ptr::null_mut(),
             width: 0,
             height: 0,
+            stride: 0,
         }
     }
 
     pub fn size(&self) -> usize {
-        (self.width * self.height * 4) as usize
+        self.stride as usize * self.height as usize
     }
 
     pub fn data(&self) -> &[u8] {
         unsafe { from_raw_parts(self.data, self.size()) }
     }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        unsafe { from_raw_parts_mut(self.data, self.size()) }
+    }
+}
+
+#[repr(C)]
+struct CCursorImage {
+    x: c_int,
+    y: c_int,
+    width: c_uint,
+    height: c_uint,
+    xhot: c_uint,
+    yhot: c_uint,
+    serial: c_ulong,
+    pixels: *const u32,
+}
+
+impl CCursorImage {
+    pub fn new() -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width: 0,
+            height: 0,
+            xhot: 0,
+            yhot: 0,
+            serial: 0,
+            pixels: std::ptr::null(),
+        }
+    }
+
+    pub fn image(&self) -> CursorImage<'_> {
+        CursorImage {
+            x: self.x as i64,
+            y: self.y as i64,
+            width: self.width as usize,
+            height: self.height as usize,
+            pixels: unsafe { from_raw_parts(self.pixels, (self.width * self.height) as usize) },
+        }
+    }
+}
+
+/// Cursor image with one premultiplied ARGB pixel per element, row by row.
+pub struct CursorImage<'a> {
+    /// Position of the top left corner relative to the frame with the hotspot subtracted already,
+    /// may lie outside of the frame.
+    pub x: i64,
+    pub y: i64,
+    pub width: usize,
+    pub height: usize,
+    pub pixels: &'a [u32],
+}
+
+/// Draw the cursor over a BGR0 frame of the given size with rows stride bytes apart, the parts
+/// outside of the frame are cut off.
+pub fn blend_cursor(
+    frame: &mut [u8],
+    width: usize,
+    height: usize,
+    stride: usize,
+    cursor: &CursorImage,
+) {
+    for cy in 0..cursor.height {
+        let y = cursor.y + cy as i64;
+        if y < 0 || y >= height as i64 {
+            continue;
+        }
+        for cx in 0..cursor.width {
+            let x = cursor.x + cx as i64;
+            if x < 0 || x >= width as i64 {
+                continue;
+            }
+            let c = cursor.pixels[cy * cursor.width + cx];
+            let a = c >> 24;
+            if a == 0 {
+                continue;
+            }
+            let i = y as usize * stride + x as usize * 4;
+            // the color channels are premultiplied already, so only the background needs to be
+            // weighted, premultiplied values may still exceed the alpha in broken themes
+            for (k, shift) in [0, 8, 16].into_iter().enumerate() {
+                let s = (c >> shift) & 0xff;
+                let d = frame[i + k] as u32;
+                frame[i + k] = (s + (d * (255 - a) + 127) / 255).min(255) as u8;
+            }
+        }
+    }
 }
 
 pub struct RecorderX11 {
@@ -293,9 +1083,22 @@ pub struct RecorderX11 {
 impl RecorderX11 {
     pub fn new(mut capturable: X11Capturable, capture_cursor: bool) -> Result<Self, CError> {
         let mut err = CError::new();
-        capturable.disp.lock();
-        let handle = unsafe { start_capture(capturable.handle(), std::ptr::null_mut(), &mut err) };
-        capturable.disp.unlock();
+        let disp = capturable.disp.clone();
+        let handle = {
+            let lock = disp.lock_connected()?;
+            let handle = unsafe {
+                start_capture(
+                    capturable.handle(),
+                    std::ptr::null_mut(),
+                    capturable.window_capture as c_int,
+                    &mut err,
+                )
+            };
+            if !err.is_err() {
+                lock.watch_cursor();
+            }
+            handle
+        };
         if err.is_err() {
             Err(err)
         } else {
@@ -312,36 +1115,275 @@ impl RecorderX11 {
 impl Drop for RecorderX11 {
     fn drop(&mut self) {
         let mut err = CError::new();
-        self.capturable.disp.lock();
+        let lock = self.capturable.disp.lock();
+        lock.unwatch_cursor();
         unsafe {
             stop_capture(self.handle, &mut err);
         }
-        self.capturable.disp.unlock();
     }
 }
 
 impl Recorder for RecorderX11 {
     fn capture(&mut self) -> Result<PixelProvider, Box<dyn Error>> {
         let mut err = CError::new();
-        self.capturable.disp.lock();
-        unsafe {
-            capture_screen(
-                self.handle,
-                &mut self.img,
-                self.capture_cursor.into(),
-                &mut err,
-            );
+        {
+            let _lock = self.capturable.disp.lock_connected()?;
+            unsafe {
+                capture_screen(self.handle, &mut self.img, &mut err);
+            }
         }
-        self.capturable.disp.unlock();
         if err.is_err() {
-            self.img.data = std::ptr::null();
+            self.img.data = std::ptr::null_mut();
             Err(err.into())
         } else {
-            Ok(PixelProvider::BGR0(
+            if self.capture_cursor {
+                let mut cursor = CCursorImage::new();
+                let found = {
+                    let lock = self.capturable.disp.lock();
+                    lock.with_cursor_changes(|changes| unsafe {
+                        get_cursor_image(self.handle, changes, &mut cursor)
+                    })
+                };
+                if found != 0 && !cursor.pixels.is_null() {
+                    let (width, height) = (self.img.width as usize, self.img.height as usize);
+                    let stride = self.img.stride as usize;
+                    blend_cursor(self.img.data_mut(), width, height, stride, &cursor.image());
+                }
+            }
+            Ok(PixelProvider::BGR0S(
                 self.img.width as usize,
                 self.img.height as usize,
+                self.img.stride as usize,
                 self.img.data(),
             ))
         }
     }
+
+    fn has_changed(&mut self) -> bool {
+        let mut err = CError::new();
+        let changed = {
+            // the next capture reports the lost connection
+            let Ok(lock) = self.capturable.disp.lock_connected() else {
+                return true;
+            };
+            lock.with_cursor_changes(|changes| unsafe {
+                capture_has_changed(self.handle, self.capture_cursor.into(), changes, &mut err)
+            })
+        };
+        if err.is_err() {
+            debug!("Failed to check for damage: {}", err);
+            return true;
+        }
+        changed != 0
+    }
+
+    fn capture_method(&self) -> String {
+        if unsafe { capture_uses_shm(self.handle) } != 0 {
+            "X11 MIT-SHM".into()
+        } else {
+            "X11 XGetImage".into()
+        }
+    }
+
+    fn cursor(&mut self) -> Option<Cursor> {
+        let mut cursor = CCursorImage::new();
+        let found = {
+            let Ok(lock) = self.capturable.disp.lock_connected() else {
+                return None;
+            };
+            lock.with_cursor_changes(|changes| unsafe {
+                get_cursor_image(self.handle, changes, &mut cursor)
+            })
+        };
+        if found == 0 || cursor.pixels.is_null() {
+            return None;
+        }
+        let image = cursor.image();
+        Some(Cursor {
+            x: image.x + cursor.xhot as i64,
+            y: image.y + cursor.yhot as i64,
+            shape_id: cursor.serial as u64,
+            width: image.width,
+            height: image.height,
+            hotspot: (cursor.xhot, cursor.yhot),
+            pixels: image.pixels.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video::OwnedFrame;
+    use crate::yuv;
+
+    #[test]
+    fn blend_cursor_over_frame() {
+        const WIDTH: usize = 4;
+        const HEIGHT: usize = 3;
+        fn pixel(frame: &[u8], x: usize, y: usize) -> &[u8] {
+            &frame[(y * WIDTH + x) * 4..][..4]
+        }
+
+        let background = [200, 100, 0, 0];
+        let mut frame = background.repeat(WIDTH * HEIGHT);
+        // the last column lies outside of the frame, the transparent pixel carries garbage colors
+        // that must not show up
+        let cursor = CursorImage {
+            x: 2,
+            y: 1,
+            width: 3,
+            height: 2,
+            pixels: &[
+                0xffffffff, 0x80800000, 0xffffffff, 0x00ffffff, 0x40000000, 0xffffffff,
+            ],
+        };
+        blend_cursor(&mut frame, WIDTH, HEIGHT, WIDTH * 4, &cursor);
+        assert_eq!(pixel(&frame, 2, 1), [255, 255, 255, 0]);
+        assert_eq!(pixel(&frame, 3, 1), [100, 50, 128, 0]);
+        assert_eq!(pixel(&frame, 2, 2), background);
+        assert_eq!(pixel(&frame, 3, 2), [150, 75, 0, 0]);
+        for (x, y) in [(0, 0), (1, 1), (3, 0), (1, 2)] {
+            assert_eq!(pixel(&frame, x, y), background);
+        }
+
+        // the hotspot may move the cursor past the top left corner
+        let mut frame = background.repeat(WIDTH * HEIGHT);
+        let cursor = CursorImage {
+            x: -1,
+            y: -1,
+            width: 2,
+            height: 2,
+            pixels: &[0xffffffff, 0xffffffff, 0xffffffff, 0xff000000],
+        };
+        blend_cursor(&mut frame, WIDTH, HEIGHT, WIDTH * 4, &cursor);
+        assert_eq!(pixel(&frame, 0, 0), [0, 0, 0, 0]);
+        assert_eq!(pixel(&frame, 1, 0), background);
+        assert_eq!(pixel(&frame, 0, 1), background);
+    }
+
+    #[test]
+    fn padded_rows_are_skipped() {
+        const WIDTH: usize = 5;
+        const HEIGHT: usize = 3;
+        const STRIDE: usize = WIDTH * 4 + 12;
+        const PADDING: u8 = 0xee;
+        // each pixel holds its own coordinates
+        let mut padded = vec![PADDING; STRIDE * HEIGHT];
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                padded[y * STRIDE + x * 4..][..4].copy_from_slice(&[x as u8, y as u8, 7, 0]);
+            }
+        }
+
+        let mut frame = OwnedFrame::default();
+        frame.copy_from(&PixelProvider::BGR0S(WIDTH, HEIGHT, STRIDE, &padded));
+        let Some(PixelProvider::BGR0(WIDTH, HEIGHT, packed)) = frame.pixels() else {
+            panic!("padded BGR0 has to be copied to packed BGR0");
+        };
+        assert_eq!(packed.len(), WIDTH * HEIGHT * 4);
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                assert_eq!(packed[(y * WIDTH + x) * 4..][..4], [x as u8, y as u8, 7, 0]);
+            }
+        }
+
+        let mut buf = Vec::new();
+        let cropped =
+            PixelProvider::BGR0S(WIDTH, HEIGHT, STRIDE, &padded).crop((1, 1, 3, 2), &mut buf);
+        frame.copy_from(&cropped);
+        let Some(PixelProvider::BGR0(3, 2, packed)) = frame.pixels() else {
+            panic!("cropped BGR0 has the size of the crop");
+        };
+        assert_eq!(packed[..4], [1, 1, 7, 0]);
+        assert_eq!(packed[(3 + 2) * 4..][..4], [3, 2, 7, 0]);
+
+        // converting to YUV yields the same as converting the packed copy
+        let convert = |src: &[u8], stride: usize| {
+            let (chroma_width, chroma_height) = yuv::chroma_size(WIDTH, HEIGHT);
+            let mut y = vec![0; WIDTH * HEIGHT];
+            let mut u = vec![0; chroma_width * chroma_height];
+            let mut v = vec![0; chroma_width * chroma_height];
+            yuv::bgr0_to_i420((WIDTH, HEIGHT), src, stride, &mut y, &mut u, &mut v);
+            (y, u, v)
+        };
+        let packed: Vec<u8> = padded
+            .chunks(STRIDE)
+            .flat_map(|row| &row[..WIDTH * 4])
+            .copied()
+            .collect();
+        assert_eq!(convert(&padded, STRIDE), convert(&packed, WIDTH * 4));
+
+        // the cursor ends up in the last column of the last row, not in the padding
+        let cursor = CursorImage {
+            x: WIDTH as i64 - 1,
+            y: HEIGHT as i64 - 1,
+            width: 2,
+            height: 1,
+            pixels: &[0xffffffff, 0xffffffff],
+        };
+        blend_cursor(&mut padded, WIDTH, HEIGHT, STRIDE, &cursor);
+        let last = (HEIGHT - 1) * STRIDE + (WIDTH - 1) * 4;
+        assert_eq!(padded[last..][..4], [255, 255, 255, 0]);
+        assert!(padded[last + 4..].iter().all(|b| *b == PADDING));
+        assert_eq!(
+            padded[last - 4..][..4],
+            [WIDTH as u8 - 2, HEIGHT as u8 - 1, 7, 0]
+        );
+    }
+
+    #[test]
+    fn device_mappings_follow_the_matrix() {
+        fn close(a: (f64, f64), b: (f64, f64)) -> bool {
+            (a.0 - b.0).abs() < 1e-9 && (a.1 - b.1).abs() < 1e-9
+        }
+
+        // what Weylus sets, the device is not rotated
+        let mapping = DeviceMapping::new((0.25, 0.5, 0.5, 0.25));
+        let matrix = [0.5, 0.0, 0.25, 0.0, 0.25, 0.5, 0.0, 0.0, 1.0];
+        assert_eq!(mapping.matrix(), matrix);
+        assert_eq!(DeviceMapping::from_matrix(&matrix), Some(mapping));
+        assert!(close(mapping.to_device(0.5, 0.625), (0.5, 0.5)));
+        assert!(close(mapping.to_device(0.0, 1.0), (0.0, 1.0)));
+
+        // matrices compositors set for a device they rotate along with the output, like xinput
+        // documents them, and the coordinates that hit the point 0.2, 0.7 of the screen then
+        for (matrix, expected) in [
+            ([1.0, 0.0, 0.0, 0.0, 1.0, 0.0], (0.2, 0.7)),
+            // xrandr --rotate left
+            ([0.0, -1.0, 1.0, 1.0, 0.0, 0.0], (0.7, 0.8)),
+            // xrandr --rotate inverted
+            ([-1.0, 0.0, 1.0, 0.0, -1.0, 1.0], (0.8, 0.3)),
+            // xrandr --rotate right
+            ([0.0, 1.0, 0.0, -1.0, 0.0, 1.0], (0.3, 0.2)),
+            // xrandr --reflect x
+            ([-1.0, 0.0, 1.0, 0.0, 1.0, 0.0], (0.8, 0.7)),
+            // xrandr --reflect y
+            ([1.0, 0.0, 0.0, 0.0, -1.0, 1.0], (0.2, 0.3)),
+        ] {
+            let [a, b, c, d, e, f] = matrix;
+            let mapping = DeviceMapping::from_matrix(&[a, b, c, d, e, f, 0.0, 0.0, 1.0]).unwrap();
+            assert!(close(mapping.to_device(0.2, 0.7), expected), "{matrix:?}");
+        }
+        // the right half of the screen rotated left
+        let matrix = [0.0, -0.5, 1.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0];
+        let mapping = DeviceMapping::from_matrix(&matrix).unwrap();
+        assert_eq!(mapping.rect, (0.5, 0.0, 0.5, 1.0));
+        assert_eq!(
+            mapping.transform,
+            OutputTransform(OutputTransform::ROTATE_90)
+        );
+        assert!(close(mapping.to_device(0.6, 0.7), (0.7, 0.8)));
+
+        // sheared or degenerate
+        assert_eq!(
+            DeviceMapping::from_matrix(&[1.0, 0.5, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0]),
+            None
+        );
+        assert_eq!(DeviceMapping::from_matrix(&[0.0; 9]), None);
+        assert_eq!(
+            OutputTransform::default(),
+            OutputTransform(OutputTransform::ROTATE_0)
+        );
+    }
 }
@@ -0,0 +1,1510 @@
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use image::codecs::png::PngEncoder;
+use image::{ExtendedColorType, ImageEncoder, ImageResult};
+use tracing::{debug, info, warn};
+
+#[cfg(target_os = "linux")]
+use crate::capturable::X11WindowCapture;
+use crate::capturable::{Capturable, Cursor, Recorder};
+use crate::cerror::{CError, CErrorCode};
+use crate::mjpeg::JpegEncoder;
+use crate::protocol::{
+    BitrateRange, ClientError, CropRect, ErrorKind, FrameInfo, MessageOutbound, VideoCodec,
+    VideoFormat, VideoStats, WeylusSender, MAX_CURSOR_SHAPES,
+};
+use crate::tiles::TileEncoder;
+use crate::video::{
+    monotonic_micros, BitrateController, EncoderOptions, OwnedFrame, RecordingConfig, VideoEncoder,
+};
+use crate::websocket::send_message;
+
+const EFFECTIVE_INIFINITY: Duration = Duration::from_secs(3600 * 24 * 365 * 200);
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+// time the size of the capturable has to be stable before the encoder is recreated
+const RESIZE_DEBOUNCE: Duration = Duration::from_millis(300);
+// No new frames are encoded for a client while more video than this is waiting to be sent to it,
+// queueing more would only add latency.
+const MAX_UNSENT_VIDEO_BYTES: usize = 1 << 20;
+// the cursor is queried at most this often for clients that draw it themselves
+const CURSOR_INTERVAL: Duration = Duration::from_millis(33);
+
+pub struct VideoConfig {
+    pub capturable: Box<dyn Capturable>,
+    pub capture_cursor: bool,
+    pub max_width: usize,
+    pub max_height: usize,
+    pub frame_rate: f64,
+    pub send_stats: bool,
+    pub send_frame_info: bool,
+    pub report_cursor: bool,
+    pub video_format: VideoFormat,
+    pub preferred_codecs: Vec<VideoCodec>,
+    pub jpeg_quality: u8,
+    pub bitrate_kbps: Option<u32>,
+    pub adaptive_bitrate: Option<BitrateRange>,
+    pub crf: Option<u8>,
+    pub intra_refresh: bool,
+    pub crop: Option<CropRect>,
+}
+
+/// Identifies a running capture, clients capturing the same capturable with the same settings
+/// share it.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+struct CaptureKey {
+    capturable_id: String,
+    capture_cursor: bool,
+    #[cfg(target_os = "linux")]
+    x11_window_capture: Option<X11WindowCapture>,
+}
+
+enum CaptureCommand<S> {
+    Subscribe(u64, VideoConfig, S),
+    Unsubscribe(u64),
+    Pause(u64),
+    Resume(u64),
+    RequestKeyFrame(u64),
+    RestartVideo(u64),
+    StartRecording(u64),
+    StopRecording(u64),
+}
+
+struct RegistryState<S> {
+    captures: HashMap<CaptureKey, Sender<CaptureCommand<S>>>,
+    next_client_id: u64,
+    // threads of the captures, including ones that are stopping and no longer in captures
+    threads: Vec<JoinHandle<()>>,
+}
+
+/// Keeps track of the running captures. Every capturable is captured by a single thread no matter
+/// how many clients are watching it, a second thread encodes the frames separately for every
+/// client.
+pub struct CaptureRegistry<S> {
+    state: Arc<Mutex<RegistryState<S>>>,
+    encoder_options: EncoderOptions,
+    full_frame_interval: Duration,
+    recording: RecordingConfig,
+}
+
+impl<S> Clone for CaptureRegistry<S> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            encoder_options: self.encoder_options,
+            full_frame_interval: self.full_frame_interval,
+            recording: self.recording.clone(),
+        }
+    }
+}
+
+impl<S: WeylusSender + Clone + Send + 'static> CaptureRegistry<S> {
+    pub fn new(
+        encoder_options: EncoderOptions,
+        full_frame_interval: Duration,
+        recording: RecordingConfig,
+    ) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(RegistryState {
+                captures: HashMap::new(),
+                next_client_id: 0,
+                threads: Vec::new(),
+            })),
+            encoder_options,
+            full_frame_interval,
+            recording,
+        }
+    }
+
+    pub fn client(&self) -> VideoClient<S> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_client_id;
+        state.next_client_id += 1;
+        VideoClient {
+            id,
+            registry: self.clone(),
+            capture: None,
+        }
+    }
+
+    /// Number of capturables that are being captured.
+    #[cfg(test)]
+    pub(crate) fn capture_count(&self) -> usize {
+        self.state.lock().unwrap().captures.len()
+    }
+
+    /// Wait for all captures to stop, which they do once their last client is gone. Used when
+    /// shutting down, so the recorders are cleaned up before the process exits.
+    pub fn join(&self) {
+        let threads = std::mem::take(&mut self.state.lock().unwrap().threads);
+        for thread in threads {
+            if thread.join().is_err() {
+                warn!("Capture thread panicked.");
+            }
+        }
+    }
+}
+
+/// Handle of a single client to the captures, the client is subscribed to at most one capture at
+/// a time and unsubscribed once this is dropped.
+pub struct VideoClient<S> {
+    id: u64,
+    registry: CaptureRegistry<S>,
+    capture: Option<(CaptureKey, Sender<CaptureCommand<S>>)>,
+}
+
+impl<S: WeylusSender + Clone + Send + 'static> VideoClient<S> {
+    pub fn start(&mut self, config: VideoConfig, sender: S) {
+        let key = CaptureKey {
+            capturable_id: config.capturable.id(),
+            capture_cursor: config.capture_cursor,
+            #[cfg(target_os = "linux")]
+            x11_window_capture: config
+                .capturable
+                .x11_window()
+                .map(|window| window.window_capture()),
+        };
+        let mut state = self.registry.state.lock().unwrap();
+        if let Some((current_key, capture)) = self.capture.take() {
+            if current_key != key {
+                capture.send(CaptureCommand::Unsubscribe(self.id)).ok();
+            }
+        }
+        let capture = match state.captures.get(&key) {
+            Some(capture) => capture.clone(),
+            None => {
+                let (capture, receiver) = mpsc::channel();
+                state.captures.insert(key.clone(), capture.clone());
+                let registry = self.registry.clone();
+                let key = key.clone();
+                let thread = spawn(move || run_capture(key, receiver, registry));
+                state.threads.retain(|thread| !thread.is_finished());
+                state.threads.push(thread);
+                capture
+            }
+        };
+        // the capture thread only exits while holding the lock on the registry and after removing
+        // itself from it, so this can not fail
+        capture
+            .send(CaptureCommand::Subscribe(self.id, config, sender))
+            .ok();
+        self.capture = Some((key, capture));
+    }
+
+    /// Unsubscribe from the capture, it stops once no other client uses it.
+    pub fn stop(&mut self) {
+        if let Some((_, capture)) = self.capture.take() {
+            capture.send(CaptureCommand::Unsubscribe(self.id)).ok();
+        }
+    }
+
+    pub fn pause(&mut self) {
+        if let Some((_, capture)) = &self.capture {
+            capture.send(CaptureCommand::Pause(self.id)).ok();
+        }
+    }
+
+    pub fn resume(&mut self) {
+        if let Some((_, capture)) = &self.capture {
+            capture.send(CaptureCommand::Resume(self.id)).ok();
+        }
+    }
+
+    pub fn request_keyframe(&mut self) {
+        if let Some((_, capture)) = &self.capture {
+            capture.send(CaptureCommand::RequestKeyFrame(self.id)).ok();
+        }
+    }
+
+    /// Start the video over with a new encoder, for a client that lost track of the stream, like
+    /// after reconnecting.
+    pub fn restart_video(&mut self) {
+        if let Some((_, capture)) = &self.capture {
+            capture.send(CaptureCommand::RestartVideo(self.id)).ok();
+        }
+    }
+
+    /// Record the video sent to this client to a file, see RecordingConfig.
+    pub fn start_recording(&mut self) {
+        if let Some((_, capture)) = &self.capture {
+            capture.send(CaptureCommand::StartRecording(self.id)).ok();
+        }
+    }
+
+    pub fn stop_recording(&mut self) {
+        if let Some((_, capture)) = &self.capture {
+            capture.send(CaptureCommand::StopRecording(self.id)).ok();
+        }
+    }
+}
+
+impl<S> Drop for VideoClient<S> {
+    fn drop(&mut self) {
+        if let Some((_, capture)) = self.capture.take() {
+            capture.send(CaptureCommand::Unsubscribe(self.id)).ok();
+        }
+    }
+}
+
+/// Measurements accumulated until they are sent to the client.
+#[derive(Default)]
+struct Stats {
+    frames: u32,
+    capture_time: Duration,
+    encode_time: Duration,
+    bytes: usize,
+    dropped_frames: u32,
+    lost_frames: u32,
+    congestion_skipped_frames: u32,
+    target_bitrate_kbps: Option<u32>,
+    coalesced_pointer_events: u32,
+    encoder: Option<String>,
+}
+
+impl Stats {
+    fn to_message(&self, elapsed: Duration) -> VideoStats {
+        let frames = self.frames.max(1);
+        VideoStats {
+            frames: self.frames,
+            fps: self.frames as f64 / elapsed.as_secs_f64(),
+            capture_ms: (self.capture_time / frames).as_secs_f64() * 1000.0,
+            encode_ms: (self.encode_time / frames).as_secs_f64() * 1000.0,
+            frame_bytes: self.bytes / frames as usize,
+            dropped_frames: self.dropped_frames,
+            lost_frames: self.lost_frames,
+            congestion_skipped_frames: self.congestion_skipped_frames,
+            target_bitrate_kbps: self.target_bitrate_kbps,
+            coalesced_pointer_events: self.coalesced_pointer_events,
+            encoder: self.encoder.clone(),
+        }
+    }
+}
+
+/// Files the video of a subscriber is recorded to, a new one is started whenever the encoder has
+/// to be recreated as a file can only hold video of a single size.
+struct Recording {
+    base: PathBuf,
+    extension: &'static str,
+    parts: u32,
+}
+
+impl Recording {
+    fn next_path(&mut self) -> PathBuf {
+        self.parts += 1;
+        let mut name = self.base.file_name().unwrap_or_default().to_os_string();
+        if self.parts > 1 {
+            name.push(format!("-{}", self.parts));
+        }
+        name.push(".");
+        name.push(self.extension);
+        self.base.with_file_name(name)
+    }
+}
+
+struct Subscriber<S> {
+    sender: S,
+    max_width: usize,
+    max_height: usize,
+    bitrate_kbps: Option<u32>,
+    // set if the bitrate follows the throughput of the connection, bitrate_kbps is not used then
+    bitrate: Option<BitrateController>,
+    crf: Option<u8>,
+    intra_refresh: bool,
+    crop: Option<CropRect>,
+    frame_duration: Duration,
+    next_frame: Instant,
+    paused: bool,
+    video_encoder: Option<Box<VideoEncoder>>,
+    // preferred codecs of the client that did not fail to be encoded so far
+    codecs: Vec<VideoCodec>,
+    // set instead of video_encoder if the client requested VideoFormat::Mjpeg or none of its
+    // codecs can be encoded
+    jpeg_encoder: Option<JpegEncoder>,
+    jpeg_quality: u8,
+    // set instead of video_encoder if the client requested VideoFormat::Tiles
+    tile_encoder: Option<TileEncoder>,
+    // size of the JPEG images or tiled frames the client has last been told about with
+    // VideoConfig
+    image_size: Option<(usize, usize)>,
+    last_frame: Instant,
+    // the content changed since the last frame sent to this subscriber
+    dirty: bool,
+    send_stats: bool,
+    send_frame_info: bool,
+    report_cursor: bool,
+    // the CursorPosition sent last as x, y, visible and shape id
+    cursor_sent: Option<(f64, f64, bool, u64)>,
+    // ids of the cursor shapes the client keeps, in the order they have been sent
+    cursor_shapes: VecDeque<u64>,
+    stats: Stats,
+    last_stats: Instant,
+    // bytes written by the encoder for the current frame and how long sending them blocked
+    frame_bytes: Rc<Cell<usize>>,
+    send_time: Rc<Cell<Duration>>,
+    // new size the encoder has to be recreated for and since when it is unchanged
+    pending_size: Option<((usize, usize, usize, usize), Instant)>,
+    recording: Option<Recording>,
+    // the client is only told once that the encoder could not be created, not for every frame
+    encoder_error_sent: bool,
+}
+
+impl<S: WeylusSender + Clone + 'static> Subscriber<S> {
+    fn new(config: &VideoConfig, sender: S) -> Self {
+        // The Duration type can not handle infinity, if the frame rate is set to 0 we just
+        // set the duration between two frames to a very long one, which is effectively
+        // infinity.
+        let d = 1.0 / config.frame_rate;
+        let frame_duration = if d.is_finite() {
+            Duration::from_secs_f64(d)
+        } else {
+            EFFECTIVE_INIFINITY
+        };
+        Self {
+            sender,
+            max_width: config.max_width,
+            max_height: config.max_height,
+            bitrate_kbps: config.bitrate_kbps,
+            bitrate: config.adaptive_bitrate.map(|range| {
+                BitrateController::new(range.min_kbps, range.max_kbps, Instant::now())
+            }),
+            crf: config.crf,
+            intra_refresh: config.intra_refresh,
+            crop: config.crop,
+            frame_duration: frame_duration.min(EFFECTIVE_INIFINITY),
+            next_frame: Instant::now(),
+            paused: false,
+            video_encoder: None,
+            codecs: config.preferred_codecs.clone(),
+            jpeg_encoder: match config.video_format {
+                VideoFormat::Mp4 | VideoFormat::Tiles => None,
+                VideoFormat::Mjpeg => Some(JpegEncoder::new(config.jpeg_quality)),
+            },
+            jpeg_quality: config.jpeg_quality,
+            tile_encoder: (config.video_format == VideoFormat::Tiles).then(TileEncoder::new),
+            image_size: None,
+            last_frame: Instant::now(),
+            dirty: true,
+            send_stats: config.send_stats,
+            send_frame_info: config.send_frame_info,
+            report_cursor: config.report_cursor,
+            cursor_sent: None,
+            cursor_shapes: VecDeque::new(),
+            stats: Stats::default(),
+            last_stats: Instant::now(),
+            frame_bytes: Rc::new(Cell::new(0)),
+            send_time: Rc::new(Cell::new(Duration::ZERO)),
+            pending_size: None,
+            recording: None,
+            encoder_error_sent: false,
+        }
+    }
+
+    fn is_due(&self, now: Instant) -> bool {
+        !self.paused && self.next_frame <= now
+    }
+
+    fn schedule_next_frame(&mut self, now: Instant) {
+        let elapsed = now - self.next_frame;
+        let frames_passed = (elapsed.as_secs_f64() / self.frame_duration.as_secs_f64()) as u32;
+        if frames_passed > 0 {
+            debug!("Dropped {frames_passed} frame(s)!");
+            self.stats.dropped_frames += frames_passed;
+        }
+        self.next_frame += (frames_passed + 1) * self.frame_duration;
+    }
+
+    /// While a window is being resized its size changes with almost every frame, so the encoder
+    /// is only recreated once the new size has been stable for a moment.
+    fn resize_settled(&mut self, size: (usize, usize, usize, usize)) -> bool {
+        match self.pending_size {
+            Some((pending, since)) if pending == size => since.elapsed() >= RESIZE_DEBOUNCE,
+            _ => {
+                self.pending_size = Some((size, Instant::now()));
+                false
+            }
+        }
+    }
+
+    /// Let the bitrate controller know how much video is waiting to be sent and apply the bitrate
+    /// it picks. Encoders that can not change the bitrate on the fly are only recreated for large
+    /// drops, small steps wait until the encoder is recreated anyway.
+    fn adapt_bitrate(&mut self, now: Instant) {
+        let Some(bitrate) = self.bitrate.as_mut() else {
+            return;
+        };
+        let Some(video_encoder) = self.video_encoder.as_mut() else {
+            return;
+        };
+        let Some(change) = bitrate.update(now, self.sender.pending_video_bytes()) else {
+            return;
+        };
+        if video_encoder.set_bitrate(change.kbps) {
+            if change.keyframe {
+                video_encoder.request_keyframe();
+            }
+        } else if change.keyframe {
+            debug!(
+                "Recreating the video encoder for a bitrate of {} kbps.",
+                change.kbps
+            );
+            self.video_encoder = None;
+        }
+    }
+
+    fn start_recording(&mut self, id: u64, config: &RecordingConfig) {
+        if self.jpeg_encoder.is_some() || self.tile_encoder.is_some() {
+            send_message(
+                &mut self.sender,
+                MessageOutbound::Error(ClientError::new(
+                    ErrorKind::RecordingFailed,
+                    if self.tile_encoder.is_some() {
+                        "Recording is not supported in lossless mode!"
+                    } else {
+                        "Recording is not supported in MJPEG mode!"
+                    },
+                )),
+            );
+            return;
+        }
+        if self.recording.is_some() {
+            return;
+        }
+        if let Err(err) = fs::create_dir_all(&config.dir) {
+            warn!(
+                "Failed to create directory {} for recordings: {err}",
+                config.dir.display()
+            );
+            send_message(
+                &mut self.sender,
+                MessageOutbound::Error(ClientError::new(
+                    ErrorKind::RecordingFailed,
+                    "Failed to create directory for recordings!",
+                )),
+            );
+            return;
+        }
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.recording = Some(Recording {
+            base: config.dir.join(format!("weylus-{secs}-{id}")),
+            extension: config.format.extension(),
+            parts: 0,
+        });
+        // otherwise recording starts once the encoder is created
+        if self.video_encoder.is_some() {
+            self.record_to_new_file();
+        }
+        // the recording starts with a keyframe, make sure it is sent soon
+        self.dirty = true;
+    }
+
+    fn record_to_new_file(&mut self) {
+        let (Some(recording), Some(video_encoder)) =
+            (self.recording.as_mut(), self.video_encoder.as_mut())
+        else {
+            return;
+        };
+        let path = recording.next_path();
+        match video_encoder.start_recording(&path) {
+            Ok(()) => info!("Recording video to {}.", path.display()),
+            Err(err) => self.recording_failed(err),
+        }
+    }
+
+    fn recording_failed(&mut self, err: CError) {
+        warn!("Recording failed: {err}");
+        self.recording = None;
+        send_message(
+            &mut self.sender,
+            MessageOutbound::Error(ClientError::new(
+                ErrorKind::RecordingFailed,
+                "Failed to write recording, it has been stopped!",
+            )),
+        );
+    }
+
+    fn stop_recording(&mut self) {
+        if self.recording.take().is_none() {
+            return;
+        }
+        if let Some(video_encoder) = self.video_encoder.as_mut() {
+            if let Err(err) = video_encoder.stop_recording() {
+                self.recording_failed(err);
+                return;
+            }
+        }
+        info!("Stopped recording video.");
+    }
+
+    fn send_frame(
+        &mut self,
+        pixel_data: crate::video::PixelProvider,
+        encoder_options: EncoderOptions,
+        sequence: u64,
+        captured_at: Instant,
+        capture_time: Duration,
+    ) {
+        let (width_in, height_in) = pixel_data.size();
+        let scale = (self.max_width as f64 / width_in as f64)
+            .min(self.max_height as f64 / height_in as f64);
+        // limit video to 4K
+        let scale_max = (3840.0 / width_in as f64).min(2160.0 / height_in as f64);
+        let scale = scale.min(scale_max);
+        let mut width_out = width_in;
+        let mut height_out = height_in;
+        if scale < 1.0 {
+            width_out = (width_out as f64 * scale) as usize;
+            height_out = (height_out as f64 * scale) as usize;
+        }
+        if self.jpeg_encoder.is_some() || self.tile_encoder.is_some() {
+            let size = (width_out.max(1), height_out.max(1));
+            let format = if self.tile_encoder.is_some() {
+                VideoFormat::Tiles
+            } else {
+                VideoFormat::Mjpeg
+            };
+            if self.image_size != Some(size) {
+                self.image_size = Some(size);
+                send_message(
+                    &mut self.sender,
+                    MessageOutbound::VideoConfig {
+                        format,
+                        codec: None,
+                        mime_type: None,
+                        encoder: None,
+                        width: size.0,
+                        height: size.1,
+                    },
+                );
+            }
+            // every image stands on its own and a new size sends all tiles, so there is no need
+            // to wait for resizing to end
+            let encode_start = Instant::now();
+            let encoded = match (self.tile_encoder.as_mut(), self.jpeg_encoder.as_mut()) {
+                (Some(tile_encoder), _) => tile_encoder.encode(pixel_data, size.0, size.1),
+                (None, Some(jpeg_encoder)) => jpeg_encoder.encode(pixel_data, size.0, size.1),
+                (None, None) => return,
+            };
+            match encoded {
+                // none of the tiles changed, the client keeps showing what it has
+                Ok([]) => self.stats.encode_time += encode_start.elapsed(),
+                Ok(data) => {
+                    self.stats.encode_time += encode_start.elapsed();
+                    self.stats.bytes += data.len();
+                    if let Err(err) = self.sender.send_video(data) {
+                        warn!("Failed to send video frame: {err}!");
+                    }
+                    self.send_frame_info(sequence, captured_at, None);
+                }
+                Err(err) => {
+                    warn!("Failed to encode {format:?} frame: {err}");
+                    return;
+                }
+            }
+            self.frame_done(capture_time);
+            return;
+        }
+        let size_changed = self
+            .video_encoder
+            .as_ref()
+            .is_some_and(|e| !e.check_size(width_in, height_in, width_out, height_out));
+        if !size_changed {
+            self.pending_size = None;
+        } else if !self.resize_settled((width_in, height_in, width_out, height_out)) {
+            // keep showing the last frame and retry with the next one
+            self.dirty = true;
+            return;
+        }
+        // video encoder is not setup or setup for encoding the wrong size: restart it
+        if self.video_encoder.is_none() || size_changed {
+            self.pending_size = None;
+            send_message(&mut self.sender, MessageOutbound::NewVideo);
+            let fall_back_to_mjpeg = !self.codecs.is_empty();
+            let codecs: Vec<Option<VideoCodec>> = if self.codecs.is_empty() {
+                vec![None]
+            } else {
+                self.codecs.iter().copied().map(Some).collect()
+            };
+            let mut res = Err(CError::new());
+            for codec in codecs {
+                let mut sender = self.sender.clone();
+                let frame_bytes = self.frame_bytes.clone();
+                let send_time = self.send_time.clone();
+                res = VideoEncoder::new(
+                    width_in,
+                    height_in,
+                    width_out,
+                    height_out,
+                    move |data| {
+                        frame_bytes.set(frame_bytes.get() + data.len());
+                        let send_start = Instant::now();
+                        if let Err(err) = sender.send_video(data) {
+                            warn!("Failed to send video frame: {err}!");
+                        }
+                        send_time.set(send_time.get() + send_start.elapsed());
+                    },
+                    EncoderOptions {
+                        codec,
+                        bitrate_kbps: self
+                            .bitrate
+                            .as_ref()
+                            .map_or(self.bitrate_kbps, |bitrate| Some(bitrate.target_kbps())),
+                        crf: self.crf,
+                        intra_refresh: self.intra_refresh,
+                        ..encoder_options
+                    },
+                )
+                .map(|encoder| (encoder, codec));
+                match (&res, codec) {
+                    (Ok(_), _) => break,
+                    // do not try it again once the encoder has to be recreated
+                    (Err(err), Some(codec)) => {
+                        info!("Failed to encode {codec:?}, trying the next codec: {err}");
+                        self.codecs.retain(|c| *c != codec);
+                    }
+                    (Err(_), None) => (),
+                }
+            }
+            if res.is_err() && fall_back_to_mjpeg {
+                warn!("None of the codecs the client can play back can be encoded, using MJPEG.");
+                self.video_encoder = None;
+                self.jpeg_encoder = Some(JpegEncoder::new(self.jpeg_quality));
+                if self.recording.is_some() {
+                    self.recording_failed(CError::with_message(
+                        CErrorCode::GenericError,
+                        "Recording is not supported in MJPEG mode!",
+                    ));
+                }
+                // sending the frame tells the client about MJPEG
+                self.image_size = None;
+                self.send_frame(
+                    pixel_data,
+                    encoder_options,
+                    sequence,
+                    captured_at,
+                    capture_time,
+                );
+                return;
+            }
+            match res {
+                Ok((r, codec)) => {
+                    let (width, height) = r.frame_size();
+                    send_message(
+                        &mut self.sender,
+                        MessageOutbound::VideoConfig {
+                            format: VideoFormat::Mp4,
+                            codec,
+                            mime_type: Some(r.mime_type().to_string()),
+                            encoder: Some(r.codec_name().to_string()),
+                            width,
+                            height,
+                        },
+                    );
+                    self.video_encoder = Some(r);
+                    self.encoder_error_sent = false;
+                }
+                Err(e) => {
+                    warn!("{}", e);
+                    if !self.encoder_error_sent {
+                        self.encoder_error_sent = true;
+                        send_message(
+                            &mut self.sender,
+                            MessageOutbound::Error(ClientError::new(
+                                ErrorKind::EncoderInitFailed {
+                                    reason: e.to_string(),
+                                },
+                                "Failed to initialize video encoder!",
+                            )),
+                        );
+                    }
+                    return;
+                }
+            };
+            self.record_to_new_file();
+        }
+        // don't count the header written when the encoder is created
+        self.frame_bytes.set(0);
+        self.send_time.set(Duration::ZERO);
+        let encode_start = Instant::now();
+        let video_encoder = self.video_encoder.as_mut().unwrap();
+        let video_timestamp = video_encoder.encode(pixel_data, captured_at);
+        if let Some(err) = video_encoder.take_recording_error() {
+            self.recording_failed(err);
+        }
+        if video_timestamp.is_some() {
+            self.send_frame_info(sequence, captured_at, video_timestamp);
+        }
+        self.stats.encode_time += encode_start.elapsed();
+        let frame_bytes = self.frame_bytes.take();
+        self.stats.bytes += frame_bytes;
+        if let Some(bitrate) = self.bitrate.as_mut() {
+            bitrate.sent(frame_bytes, self.send_time.take());
+        }
+        self.frame_done(capture_time);
+    }
+
+    fn send_frame_info(
+        &mut self,
+        sequence: u64,
+        captured_at: Instant,
+        video_timestamp_us: Option<u64>,
+    ) {
+        if self.send_frame_info {
+            send_message(
+                &mut self.sender,
+                MessageOutbound::FrameInfo(FrameInfo {
+                    sequence,
+                    captured_at_us: monotonic_micros(captured_at),
+                    video_timestamp_us,
+                }),
+            );
+        }
+    }
+
+    /// Tell the client where the cursor is on its video if that changed, along with the shape if
+    /// the client does not have it. frame_size is the size of the area the cursor is relative to.
+    fn send_cursor(&mut self, cursor: &Cursor, shape: &EncodedCursor, frame_size: (usize, usize)) {
+        if !self.report_cursor || self.paused {
+            return;
+        }
+        let (width, height) = frame_size;
+        let mut x = cursor.x as f64 / width as f64;
+        let mut y = cursor.y as f64 / height as f64;
+        if let Some(crop) = self.crop {
+            x = (x - crop.x) / crop.width;
+            y = (y - crop.y) / crop.height;
+        }
+        let visible = shape.visible && (0.0..1.0).contains(&x) && (0.0..1.0).contains(&y);
+        // where a hidden cursor moves is of no interest
+        let position = (x, y, visible, cursor.shape_id);
+        match self.cursor_sent {
+            Some(sent) if sent == position => return,
+            Some((_, _, false, _)) if !visible => return,
+            _ => self.cursor_sent = Some(position),
+        }
+        if visible && !self.cursor_shapes.contains(&cursor.shape_id) {
+            if self.cursor_shapes.len() >= MAX_CURSOR_SHAPES {
+                self.cursor_shapes.pop_front();
+            }
+            self.cursor_shapes.push_back(cursor.shape_id);
+            send_message(
+                &mut self.sender,
+                MessageOutbound::CursorShape {
+                    id: cursor.shape_id,
+                    png_base64: shape.png_base64.clone(),
+                    hotspot: cursor.hotspot,
+                },
+            );
+        }
+        send_message(
+            &mut self.sender,
+            MessageOutbound::CursorPosition {
+                x,
+                y,
+                visible,
+                shape_id: cursor.shape_id,
+            },
+        );
+    }
+
+    fn frame_done(&mut self, capture_time: Duration) {
+        self.stats.capture_time += capture_time;
+        self.stats.frames += 1;
+
+        let elapsed = self.last_stats.elapsed();
+        if elapsed >= STATS_INTERVAL {
+            // taken even if not sent so enabling stats does not report what happened long ago
+            self.stats.coalesced_pointer_events = self.sender.take_coalesced_pointer_events();
+            self.stats.target_bitrate_kbps = self.bitrate.as_ref().map(|b| b.target_kbps());
+            self.stats.encoder = self
+                .video_encoder
+                .as_ref()
+                .map(|encoder| encoder.codec_name().to_string());
+            let stats = self.stats.to_message(elapsed);
+            self.sender.stats_measured(&stats);
+            if self.send_stats {
+                send_message(&mut self.sender, MessageOutbound::Stats(stats));
+            }
+            self.stats = Stats::default();
+            self.last_stats = Instant::now();
+        }
+    }
+}
+
+/// Shape of the cursor as sent to clients.
+struct EncodedCursor {
+    id: u64,
+    png_base64: String,
+    // false if all of it is transparent, which is how applications hide the cursor
+    visible: bool,
+}
+
+impl EncodedCursor {
+    fn new(cursor: &Cursor) -> Self {
+        let visible = cursor.pixels.iter().any(|c| c >> 24 != 0);
+        let png = if visible {
+            cursor_png(cursor).unwrap_or_else(|err| {
+                warn!("Failed to encode cursor shape: {err}");
+                Vec::new()
+            })
+        } else {
+            Vec::new()
+        };
+        Self {
+            id: cursor.shape_id,
+            visible: visible && !png.is_empty(),
+            png_base64: BASE64_STANDARD.encode(png),
+        }
+    }
+}
+
+/// Encode the image of the cursor as PNG, whose colors are not premultiplied.
+pub fn cursor_png(cursor: &Cursor) -> ImageResult<Vec<u8>> {
+    let mut rgba = Vec::with_capacity(cursor.pixels.len() * 4);
+    for &c in &cursor.pixels {
+        let a = c >> 24;
+        let channel = |shift: u32| match a {
+            0 => 0,
+            // broken themes premultiply values larger than the alpha
+            a => ((((c >> shift) & 0xff) * 255 + a / 2) / a).min(255) as u8,
+        };
+        rgba.extend_from_slice(&[channel(16), channel(8), channel(0), a as u8]);
+    }
+    let mut png = Vec::new();
+    PngEncoder::new(&mut png).write_image(
+        &rgba,
+        cursor.width as u32,
+        cursor.height as u32,
+        ExtendedColorType::Rgba8,
+    )?;
+    Ok(png)
+}
+
+/// A frame on its way from the capture thread to the thread encoding it.
+struct Frame {
+    pixels: OwnedFrame,
+    // numbers the frames of a capture in the order they have been captured, see FrameInfo
+    sequence: u64,
+    captured_at: Instant,
+    capture_time: Duration,
+    // the content may have changed since the previous frame
+    changed: bool,
+}
+
+#[derive(Default)]
+struct Mailbox {
+    // most recent frame that has not been taken yet
+    latest: Option<Frame>,
+    // buffers of frames that are not needed anymore, there are at most three frames: one being
+    // captured, one waiting in latest and one being encoded
+    free: Vec<OwnedFrame>,
+    // frames are captured this often, not at all if None
+    interval: Option<Duration>,
+    // query the cursor along with the frames, the latest result that has not been taken yet
+    report_cursor: bool,
+    cursor: Option<Cursor>,
+    lost: bool,
+    stop: bool,
+}
+
+/// Owns the recorder and captures frames on a thread of its own, so a slow capture does not delay
+/// encoding and the other way round. Only the most recent frame is kept, frames that are not
+/// taken in time are dropped instead of being encoded late.
+struct CaptureThread {
+    mailbox: Arc<(Mutex<Mailbox>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl CaptureThread {
+    /// Create the recorder and start capturing, returns the capture method.
+    fn start(
+        capturable: Box<dyn Capturable>,
+        capture_cursor: bool,
+    ) -> Result<(Self, String), String> {
+        let mailbox = Arc::new((Mutex::new(Mailbox::default()), Condvar::new()));
+        let (init_sender, init_receiver) = mpsc::channel();
+        let handle = {
+            let mailbox = mailbox.clone();
+            // recorders are not Send, so they are created by the thread using them
+            spawn(move || {
+                let mut recorder = match capturable.recorder(capture_cursor) {
+                    Ok(recorder) => recorder,
+                    Err(err) => {
+                        init_sender.send(Err(err.to_string())).ok();
+                        return;
+                    }
+                };
+                init_sender.send(Ok(recorder.capture_method())).ok();
+                capture_frames(recorder.as_mut(), &mailbox);
+                // the recorder may depend on the capturable, so it has to go first
+                drop(recorder);
+                drop(capturable);
+            })
+        };
+        match init_receiver.recv() {
+            Ok(Ok(capture_method)) => Ok((
+                Self {
+                    mailbox,
+                    handle: Some(handle),
+                },
+                capture_method,
+            )),
+            Ok(Err(err)) => {
+                handle.join().ok();
+                Err(err)
+            }
+            Err(_) => {
+                handle.join().ok();
+                Err("Capture thread panicked!".into())
+            }
+        }
+    }
+
+    fn set_interval(&self, interval: Option<Duration>) {
+        let (mailbox, wakeup) = &*self.mailbox;
+        let mut mailbox = mailbox.lock().unwrap();
+        if mailbox.interval != interval {
+            mailbox.interval = interval;
+            wakeup.notify_one();
+        }
+    }
+
+    fn set_report_cursor(&self, report_cursor: bool) {
+        self.mailbox.0.lock().unwrap().report_cursor = report_cursor;
+    }
+
+    fn take_frame(&self) -> Option<Frame> {
+        self.mailbox.0.lock().unwrap().latest.take()
+    }
+
+    fn take_cursor(&self) -> Option<Cursor> {
+        self.mailbox.0.lock().unwrap().cursor.take()
+    }
+
+    /// Hand back a frame that has been encoded so its buffer can be reused.
+    fn recycle(&self, frame: Frame) {
+        self.mailbox.0.lock().unwrap().free.push(frame.pixels);
+    }
+
+    fn is_lost(&self) -> bool {
+        self.mailbox.0.lock().unwrap().lost
+    }
+}
+
+impl Drop for CaptureThread {
+    fn drop(&mut self) {
+        let (mailbox, wakeup) = &*self.mailbox;
+        mailbox.lock().unwrap().stop = true;
+        wakeup.notify_one();
+        // the recorder must be gone before the capture is considered stopped
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
+
+fn capture_frames(recorder: &mut dyn Recorder, mailbox: &(Mutex<Mailbox>, Condvar)) {
+    let (mailbox, wakeup) = mailbox;
+    let mut next_capture = Instant::now();
+    // the first frame is captured no matter what has_changed says
+    let mut changed = true;
+    let mut sequence = 0;
+    let mut next_cursor = Instant::now();
+    loop {
+        let mut state = mailbox.lock().unwrap();
+        let interval = loop {
+            if state.stop {
+                return;
+            }
+            match state.interval {
+                Some(interval) if next_capture <= Instant::now() => break interval,
+                Some(_) => {
+                    let timeout = next_capture.saturating_duration_since(Instant::now());
+                    state = wakeup.wait_timeout(state, timeout).unwrap().0;
+                }
+                None => state = wakeup.wait(state).unwrap(),
+            }
+        };
+        let mut pixels = state.free.pop().unwrap_or_default();
+        let report_cursor = state.report_cursor;
+        drop(state);
+
+        let capture_start = Instant::now();
+        next_capture = capture_start + interval;
+        // moving the cursor may not change the frame, so it is queried either way
+        if report_cursor && next_cursor <= capture_start {
+            next_cursor = capture_start + CURSOR_INTERVAL;
+            if let Some(cursor) = recorder.cursor() {
+                mailbox.lock().unwrap().cursor = Some(cursor);
+            }
+        }
+        // always query for changes to not have stale changes reported next time
+        changed |= recorder.has_changed();
+        if !changed {
+            mailbox.lock().unwrap().free.push(pixels);
+            continue;
+        }
+        match recorder.capture() {
+            Ok(pixel_data) => pixels.copy_from(&pixel_data),
+            Err(err) => {
+                let mut state = mailbox.lock().unwrap();
+                match err.downcast_ref::<CError>().map(CError::code) {
+                    Some(CErrorCode::WindowGone) => {
+                        state.lost = true;
+                        return;
+                    }
+                    Some(CErrorCode::CaptureFailedAgain) => {
+                        debug!("Error capturing screen: {}", err)
+                    }
+                    _ => warn!("Error capturing screen: {}", err),
+                }
+                state.free.push(pixels);
+                continue;
+            }
+        }
+        let mut frame = Frame {
+            pixels,
+            sequence,
+            captured_at: capture_start,
+            capture_time: capture_start.elapsed(),
+            changed,
+        };
+        changed = false;
+        sequence += 1;
+        let mut state = mailbox.lock().unwrap();
+        if let Some(stale) = state.latest.take() {
+            // the changes of the dropped frame are part of this one
+            frame.changed |= stale.changed;
+            state.free.push(stale.pixels);
+        }
+        state.latest = Some(frame);
+    }
+}
+
+fn run_capture<S: WeylusSender + Clone + Send + 'static>(
+    key: CaptureKey,
+    receiver: mpsc::Receiver<CaptureCommand<S>>,
+    registry: CaptureRegistry<S>,
+) {
+    let mut capture: Option<CaptureThread> = None;
+    // the frame encoded last, it is encoded again for subscribers that need a frame while the
+    // content did not change
+    let mut current_frame: Option<Frame> = None;
+    let mut capture_method = String::new();
+    let mut subscribers: HashMap<u64, Subscriber<S>> = HashMap::new();
+    // holds cropped images that are not cropped in place
+    let mut crop_buf = Vec::new();
+    // the cursor queried last and its shape, for subscribers that draw it themselves
+    let mut cursor: Option<Cursor> = None;
+    let mut encoded_cursor: Option<EncodedCursor> = None;
+
+    let mut handle_command =
+        |command: CaptureCommand<S>,
+         capture: &mut Option<CaptureThread>,
+         subscribers: &mut HashMap<u64, Subscriber<S>>| match command {
+            CaptureCommand::Subscribe(id, config, mut sender) => {
+                if capture.is_none() {
+                    match CaptureThread::start(config.capturable.box_clone(), config.capture_cursor)
+                    {
+                        Ok((c, method)) => {
+                            capture_method = method;
+                            info!(
+                                "Capturing {} via {capture_method}.",
+                                config.capturable.name()
+                            );
+                            *capture = Some(c);
+                        }
+                        Err(err) => {
+                            warn!("Failed to init screen cast: {}!", err);
+                            send_message(
+                                &mut sender,
+                                MessageOutbound::Error(ClientError::new(
+                                    ErrorKind::CaptureInitFailed,
+                                    "Failed to init screen cast!",
+                                )),
+                            );
+                            subscribers.remove(&id);
+                            return;
+                        }
+                    }
+                }
+                send_message(&mut sender, MessageOutbound::ConfigOk);
+                send_message(
+                    &mut sender,
+                    MessageOutbound::CaptureMethod(capture_method.clone()),
+                );
+                // Every subscriber gets an encoder of its own, so a client joining a running
+                // capture starts with a keyframe anyway.
+                subscribers.insert(id, Subscriber::new(&config, sender));
+            }
+            CaptureCommand::Unsubscribe(id) => {
+                subscribers.remove(&id);
+            }
+            CaptureCommand::Pause(id) => {
+                if let Some(s) = subscribers.get_mut(&id) {
+                    s.paused = true;
+                }
+            }
+            CaptureCommand::Resume(id) => {
+                if let Some(s) = subscribers.get_mut(&id) {
+                    s.paused = false;
+                    s.dirty = true;
+                    s.next_frame = Instant::now();
+                }
+            }
+            CaptureCommand::RequestKeyFrame(id) => {
+                if let Some(s) = subscribers.get_mut(&id) {
+                    if let Some(video_encoder) = s.video_encoder.as_mut() {
+                        video_encoder.request_keyframe();
+                    }
+                    if let Some(tile_encoder) = s.tile_encoder.as_mut() {
+                        tile_encoder.request_full_refresh();
+                    }
+                    // send a frame even if nothing changed
+                    s.dirty = true;
+                }
+            }
+            CaptureCommand::RestartVideo(id) => {
+                if let Some(s) = subscribers.get_mut(&id) {
+                    // the next frame sends NewVideo and creates the encoder again
+                    s.video_encoder = None;
+                    s.pending_size = None;
+                    s.image_size = None;
+                    if let Some(tile_encoder) = s.tile_encoder.as_mut() {
+                        tile_encoder.request_full_refresh();
+                    }
+                    s.dirty = true;
+                }
+            }
+            CaptureCommand::StartRecording(id) => {
+                if let Some(s) = subscribers.get_mut(&id) {
+                    s.start_recording(id, &registry.recording);
+                }
+            }
+            CaptureCommand::StopRecording(id) => {
+                if let Some(s) = subscribers.get_mut(&id) {
+                    s.stop_recording();
+                }
+            }
+        };
+
+    loop {
+        if subscribers.is_empty() {
+            // Nobody is watching anymore, stop capturing unless a new subscriber is already
+            // waiting. The lock ensures no new subscriber is added to the channel after checking.
+            let mut state = registry.state.lock().unwrap();
+            match receiver.try_recv() {
+                Ok(command) => {
+                    drop(state);
+                    handle_command(command, &mut capture, &mut subscribers);
+                    continue;
+                }
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => {
+                    state.captures.remove(&key);
+                    drop(state);
+                    // joins the capture thread
+                    drop(capture);
+                    debug!("Stopped capturing {}.", key.capturable_id);
+                    return;
+                }
+            }
+        }
+
+        if let Some(capture) = &capture {
+            // capture as often as the most demanding subscriber needs frames
+            capture.set_interval(
+                subscribers
+                    .values()
+                    .filter(|s| !s.paused)
+                    .map(|s| s.frame_duration)
+                    .min(),
+            );
+            capture.set_report_cursor(subscribers.values().any(|s| s.report_cursor && !s.paused));
+        }
+
+        let now = Instant::now();
+        let timeout = subscribers
+            .values()
+            .filter(|s| !s.paused)
+            .map(|s| s.next_frame.saturating_duration_since(now))
+            .min()
+            .unwrap_or(EFFECTIVE_INIFINITY);
+
+        match receiver.recv_timeout(timeout) {
+            Ok(command) => handle_command(command, &mut capture, &mut subscribers),
+            Err(RecvTimeoutError::Timeout) => {
+                let capture_thread = match capture.as_ref() {
+                    Some(capture) => capture,
+                    None => continue,
+                };
+                if capture_thread.is_lost() {
+                    info!("{} vanished, stopping capture.", key.capturable_id);
+                    // once unregistered no new subscribers can show up, but some may
+                    // already be waiting
+                    registry.state.lock().unwrap().captures.remove(&key);
+                    drop(capture);
+                    let waiting = receiver.try_iter().filter_map(|command| match command {
+                        CaptureCommand::Subscribe(_, _, sender) => Some(sender),
+                        _ => None,
+                    });
+                    let senders: Vec<S> = subscribers
+                        .into_values()
+                        .map(|s| s.sender)
+                        .chain(waiting)
+                        .collect();
+                    for mut sender in senders {
+                        send_message(&mut sender, MessageOutbound::CapturableLost);
+                    }
+                    return;
+                }
+                if let Some(frame) = capture_thread.take_frame() {
+                    if frame.changed {
+                        subscribers.values_mut().for_each(|s| s.dirty = true);
+                    }
+                    // frames captured in between have been replaced before they could be encoded
+                    let lost = current_frame
+                        .as_ref()
+                        .map_or(0, |last| frame.sequence.saturating_sub(last.sequence + 1));
+                    if lost > 0 {
+                        debug!("Lost {lost} captured frame(s)!");
+                        subscribers
+                            .values_mut()
+                            .filter(|s| !s.paused)
+                            .for_each(|s| s.stats.lost_frames += lost as u32);
+                    }
+                    if let Some(encoded) = current_frame.replace(frame) {
+                        capture_thread.recycle(encoded);
+                    }
+                }
+                if let Some(queried) = capture_thread.take_cursor() {
+                    if encoded_cursor.as_ref().map(|shape| shape.id) != Some(queried.shape_id) {
+                        encoded_cursor = Some(EncodedCursor::new(&queried));
+                    }
+                    cursor = Some(queried);
+                }
+                let frame_size = current_frame
+                    .as_ref()
+                    .and_then(|frame| frame.pixels.pixels())
+                    .map(|pixels| pixels.size());
+                if let (Some(cursor), Some(shape), Some(frame_size)) =
+                    (&cursor, &encoded_cursor, frame_size)
+                {
+                    for s in subscribers.values_mut() {
+                        s.send_cursor(cursor, shape, frame_size);
+                    }
+                }
+                let now = Instant::now();
+                let due: Vec<u64> = subscribers
+                    .iter_mut()
+                    .filter(|(_, s)| s.is_due(now))
+                    .filter_map(|(id, s)| {
+                        s.schedule_next_frame(now);
+                        s.adapt_bitrate(now);
+                        if !(s.dirty || s.last_frame.elapsed() >= registry.full_frame_interval) {
+                            return None;
+                        }
+                        // the content remains dirty, so the frame is sent once the connection
+                        // caught up
+                        if s.sender.pending_video_bytes() > MAX_UNSENT_VIDEO_BYTES {
+                            s.stats.congestion_skipped_frames += 1;
+                            return None;
+                        }
+                        Some(*id)
+                    })
+                    .collect();
+                if due.is_empty() {
+                    continue;
+                }
+                // nothing has been captured yet, the subscribers remain dirty
+                let frame = match &current_frame {
+                    Some(frame) => frame,
+                    None => continue,
+                };
+                let pixel_data = match frame.pixels.pixels() {
+                    Some(pixel_data) => pixel_data,
+                    None => continue,
+                };
+                for id in due {
+                    let s = subscribers.get_mut(&id).unwrap();
+                    s.dirty = false;
+                    s.last_frame = now;
+                    // The region is recomputed for every frame as the size of the capturable may
+                    // change, a changed size of the region recreates the encoder like any other
+                    // resize.
+                    let pixel_data = match s.crop {
+                        Some(crop) => {
+                            let (width, height) = pixel_data.size();
+                            pixel_data.crop(crop.to_pixels(width, height), &mut crop_buf)
+                        }
+                        None => pixel_data,
+                    };
+                    s.send_frame(
+                        pixel_data,
+                        registry.encoder_options,
+                        frame.sequence,
+                        frame.captured_at,
+                        frame.capture_time,
+                    );
+                }
+            }
+            // all senders are owned by the registry entry and the clients, as long as this thread
+            // is registered this can not happen
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capturable::testsrc::TestCapturable;
+    use std::cell::RefCell;
+
+    // keeps what is sent as short descriptions of the cursor messages
+    #[derive(Clone, Default)]
+    struct RecordingSender(Rc<RefCell<Vec<String>>>);
+
+    impl RecordingSender {
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut *self.0.borrow_mut())
+        }
+    }
+
+    impl WeylusSender for RecordingSender {
+        type Error = std::convert::Infallible;
+
+        fn send_message(&mut self, message: MessageOutbound) -> Result<(), Self::Error> {
+            let message = match message {
+                MessageOutbound::CursorShape { id, .. } => format!("shape {id}"),
+                MessageOutbound::CursorPosition {
+                    x,
+                    y,
+                    visible,
+                    shape_id,
+                } => format!("{x} {y} {visible} {shape_id}"),
+                message => format!("{message:?}"),
+            };
+            self.0.borrow_mut().push(message);
+            Ok(())
+        }
+
+        fn send_video(&mut self, _: &[u8]) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn send_audio(&mut self, _: u64, _: &[u8]) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    fn cursor(x: i64, y: i64, shape_id: u64, visible: bool) -> (Cursor, EncodedCursor) {
+        let cursor = Cursor {
+            x,
+            y,
+            shape_id,
+            width: 1,
+            height: 1,
+            hotspot: (0, 0),
+            pixels: vec![if visible { 0xff00_0000 } else { 0 }],
+        };
+        let shape = EncodedCursor::new(&cursor);
+        (cursor, shape)
+    }
+
+    #[test]
+    fn cursor_positions_are_relative_to_the_video() {
+        let sender = RecordingSender::default();
+        let config = VideoConfig {
+            capturable: Box::new(TestCapturable {
+                width: 1000,
+                height: 500,
+            }),
+            capture_cursor: false,
+            max_width: 1000,
+            max_height: 500,
+            frame_rate: 30.0,
+            send_stats: false,
+            send_frame_info: false,
+            report_cursor: true,
+            video_format: VideoFormat::Mjpeg,
+            preferred_codecs: Vec::new(),
+            jpeg_quality: 80,
+            bitrate_kbps: None,
+            adaptive_bitrate: None,
+            crf: None,
+            intra_refresh: false,
+            crop: None,
+        };
+        let mut subscriber = Subscriber::new(&config, sender.clone());
+        let mut send = |x, y, shape_id, visible| {
+            let (cursor, shape) = cursor(x, y, shape_id, visible);
+            subscriber.send_cursor(&cursor, &shape, (1000, 500));
+            sender.take()
+        };
+
+        // the shape comes first, an unchanged position is not sent again
+        assert_eq!(send(250, 125, 1, true), ["shape 1", "0.25 0.25 true 1"]);
+        assert!(send(250, 125, 1, true).is_empty());
+        assert_eq!(send(500, 0, 1, true), ["0.5 0 true 1"]);
+
+        // outside of the video the cursor is hidden, where a hidden cursor moves is not sent
+        assert_eq!(send(1000, 250, 1, true), ["1 0.5 false 1"]);
+        assert!(send(-10, 250, 1, true).is_empty());
+        assert!(send(100, 100, 2, false).is_empty());
+        assert_eq!(send(100, 100, 1, true), ["0.1 0.2 true 1"]);
+        assert_eq!(send(100, 100, 2, false), ["0.1 0.2 false 2"]);
+        assert!(send(200, 100, 2, false).is_empty());
+
+        // the client keeps MAX_CURSOR_SHAPES, the oldest one is sent again once it is needed
+        for id in 3..MAX_CURSOR_SHAPES as u64 + 3 {
+            assert_eq!(send(100, 100, id, true)[0], format!("shape {id}"));
+        }
+        assert_eq!(send(100, 100, 4, true), ["0.1 0.2 true 4"]);
+        assert_eq!(send(100, 100, 1, true), ["shape 1", "0.1 0.2 true 1"]);
+        assert_eq!(send(100, 100, 3, true), ["shape 3", "0.1 0.2 true 3"]);
+
+        // with a crop region positions are relative to it
+        subscriber.crop = Some(CropRect {
+            x: 0.5,
+            y: 0.5,
+            width: 0.25,
+            height: 0.5,
+        });
+        let mut send = |x, y| {
+            let (cursor, shape) = cursor(x, y, 1, true);
+            subscriber.send_cursor(&cursor, &shape, (1000, 500));
+            sender.take()
+        };
+        assert_eq!(send(625, 375), ["0.5 0.5 true 1"]);
+        assert_eq!(send(500, 250), ["0 0 true 1"]);
+        assert_eq!(send(250, 375), ["-1 0.5 false 1"]);
+    }
+
+    #[test]
+    fn cursor_shapes_are_sent_straight_alpha() {
+        let cursor = Cursor {
+            x: 10,
+            y: 20,
+            shape_id: 1,
+            width: 2,
+            height: 1,
+            hotspot: (0, 0),
+            // half transparent red and nothing, premultiplied like XFixes reports it
+            pixels: vec![0x8080_0000, 0],
+        };
+        let png = cursor_png(&cursor).unwrap();
+        let image = image::load_from_memory(&png).unwrap().into_rgba8();
+        assert_eq!(image.dimensions(), (2, 1));
+        assert_eq!(image.get_pixel(0, 0).0, [255, 0, 0, 128]);
+        assert_eq!(image.get_pixel(1, 0).0, [0, 0, 0, 0]);
+    }
+}
@@ -1,3 +1,4 @@
+use std::borrow::Cow;
 use std::error::Error;
 use std::ffi::CStr;
 use std::fmt;
@@ -10,10 +11,63 @@ pub struct CError {
     error_str: [c_char; 1024],
 }
 
+/// Kinds of errors the native code reports, these mirror enum ErrorCode in lib/error.h.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CErrorCode {
     NoError,
     GenericError,
     UInputNotAccessible,
+    // the window that is captured no longer exists or the connection to its X server is lost
+    WindowGone,
+    // there is no client list to take windows from, only the desktop and monitors are offered
+    NoWindowManager,
+    // the monitors could not be queried, everything else is there
+    MonitorsUnavailable,
+    // MIT-SHM is missing or setting up the shared memory failed
+    ShmUnavailable,
+    XInputMissing,
+    // the input device to configure has not shown up (yet)
+    DeviceNotFound,
+    // capturing failed just like the last time, which has been reported already
+    CaptureFailedAgain,
+    // a code without a variant of its own, the message tells what went wrong
+    Other(i32),
+}
+
+impl CErrorCode {
+    fn to_raw(self) -> c_int {
+        match self {
+            Self::NoError => 0,
+            Self::GenericError => 1,
+            Self::UInputNotAccessible => 101,
+            Self::WindowGone => 102,
+            Self::NoWindowManager => 103,
+            Self::MonitorsUnavailable => 104,
+            Self::ShmUnavailable => 105,
+            Self::XInputMissing => 106,
+            Self::DeviceNotFound => 107,
+            Self::CaptureFailedAgain => 108,
+            Self::Other(code) => code as c_int,
+        }
+    }
+}
+
+impl From<i32> for CErrorCode {
+    fn from(code: i32) -> Self {
+        match code {
+            0 => Self::NoError,
+            1 => Self::GenericError,
+            101 => Self::UInputNotAccessible,
+            102 => Self::WindowGone,
+            103 => Self::NoWindowManager,
+            104 => Self::MonitorsUnavailable,
+            105 => Self::ShmUnavailable,
+            106 => Self::XInputMissing,
+            107 => Self::DeviceNotFound,
+            108 => Self::CaptureFailedAgain,
+            code => Self::Other(code),
+        }
+    }
 }
 
 impl CError {
@@ -24,35 +78,83 @@ impl CError {
         }
     }
 
+    pub fn with_message(code: CErrorCode, message: &str) -> Self {
+        let mut err = Self::new();
+        err.code = code.to_raw();
+        // leave room for the terminating nul byte
+        for (dst, src) in err.error_str[..1023].iter_mut().zip(message.bytes()) {
+            *dst = src as c_char;
+        }
+        err
+    }
+
     pub fn is_err(&self) -> bool {
         self.code != 0
     }
 
-    pub fn code(&self) -> i32 {
-        self.code as i32
+    pub fn code(&self) -> CErrorCode {
+        CErrorCode::from(self.code as i32)
     }
 
-    pub fn to_enum(&self) -> CErrorCode {
-        match self.code {
-            0 => CErrorCode::NoError,
-            101 => CErrorCode::UInputNotAccessible,
-            _ => CErrorCode::GenericError,
-        }
+    pub fn message(&self) -> Cow<'_, str> {
+        // the native code always terminates the message and so does with_message
+        unsafe { CStr::from_ptr(self.error_str.as_ptr()) }.to_string_lossy()
     }
 }
 
 impl fmt::Display for CError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "CError: code: {} message: {}", self.code, unsafe {
-            CStr::from_ptr(self.error_str.as_ptr()).to_string_lossy()
-        })
+        match self.code() {
+            CErrorCode::GenericError => write!(f, "{}", self.message()),
+            code => write!(f, "{} ({:?})", self.message(), code),
+        }
     }
 }
 
 impl fmt::Debug for CError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Display::fmt(self, f)
+        f.debug_struct("CError")
+            .field("code", &self.code())
+            .field("message", &self.message())
+            .finish()
     }
 }
 
 impl Error for CError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cerror_codes_and_messages() {
+        fn send_sync_error<E: std::error::Error + Send + Sync + 'static>(_: &E) {}
+
+        for code in [
+            CErrorCode::GenericError,
+            CErrorCode::UInputNotAccessible,
+            CErrorCode::WindowGone,
+            CErrorCode::NoWindowManager,
+            CErrorCode::MonitorsUnavailable,
+            CErrorCode::ShmUnavailable,
+            CErrorCode::XInputMissing,
+            CErrorCode::DeviceNotFound,
+            CErrorCode::CaptureFailedAgain,
+            CErrorCode::Other(42),
+        ] {
+            let err = CError::with_message(code, "Something broke.");
+            assert!(err.is_err());
+            assert_eq!(err.code(), code);
+            assert_eq!(err.message(), "Something broke.");
+            send_sync_error(&err);
+        }
+        assert!(!CError::new().is_err());
+        assert_eq!(CError::new().code(), CErrorCode::NoError);
+        let err = CError::with_message(CErrorCode::Other(-3), "Unknown.");
+        assert_eq!(err.to_string(), "Unknown. (Other(-3))");
+        // overlong messages are cut off but stay terminated
+        let err = CError::with_message(CErrorCode::GenericError, &"x".repeat(2000));
+        assert_eq!(err.message().len(), 1023);
+        assert_eq!(err.to_string(), "x".repeat(1023));
+    }
+}
@@ -0,0 +1,104 @@
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{spawn, JoinHandle};
+use std::time::Duration;
+
+use tracing::{debug, warn};
+
+use crate::protocol::{ClientError, ErrorKind, MessageOutbound, WeylusSender};
+use crate::websocket::send_message;
+
+/// Clipboard contents larger than this are neither sent to the client nor accepted from it.
+pub const MAX_CLIPBOARD_SIZE: usize = 1 << 20;
+
+/// Interval in which the clipboard of the host is checked for changes.
+const CLIPBOARD_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Keeps the text clipboard of the host and a client in sync while it exists.
+pub struct ClipboardSync {
+    set_text: Option<Sender<String>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ClipboardSync {
+    pub fn new<S: WeylusSender + Send + 'static>(sender: S) -> Self {
+        let (set_text, receiver) = mpsc::channel();
+        Self {
+            set_text: Some(set_text),
+            thread: Some(spawn(move || run_clipboard_sync(receiver, sender))),
+        }
+    }
+
+    /// Replace the contents of the host clipboard with text from the client.
+    pub fn set_text(&self, text: String) {
+        if text.len() > MAX_CLIPBOARD_SIZE {
+            warn!(
+                "Ignoring clipboard contents of {} bytes from client, the limit is {} bytes.",
+                text.len(),
+                MAX_CLIPBOARD_SIZE
+            );
+            return;
+        }
+        if let Some(set_text) = &self.set_text {
+            set_text.send(text).ok();
+        }
+    }
+}
+
+impl Drop for ClipboardSync {
+    fn drop(&mut self) {
+        // disconnecting the channel stops the thread
+        self.set_text.take();
+        if let Some(thread) = self.thread.take() {
+            if let Err(err) = thread.join() {
+                warn!("Failed to join clipboard thread: {err:?}");
+            }
+        }
+    }
+}
+
+fn run_clipboard_sync<S: WeylusSender>(receiver: mpsc::Receiver<String>, mut sender: S) {
+    // The clipboard is owned by this thread as on X11 the contents set by Weylus are only
+    // available as long as it exists.
+    let mut clipboard = match arboard::Clipboard::new() {
+        Ok(clipboard) => clipboard,
+        Err(err) => {
+            warn!("Failed to access clipboard: {err}");
+            send_message(
+                &mut sender,
+                MessageOutbound::Error(ClientError::new(
+                    ErrorKind::ClipboardFailed,
+                    "Failed to access clipboard!",
+                )),
+            );
+            return;
+        }
+    };
+    // Only changes are sent, this way text that just came from the client is not echoed back.
+    let mut last_text = clipboard.get_text().ok();
+    loop {
+        match receiver.recv_timeout(CLIPBOARD_POLL_INTERVAL) {
+            Ok(text) => {
+                if let Err(err) = clipboard.set_text(text.as_str()) {
+                    warn!("Failed to set clipboard: {err}");
+                }
+                last_text = Some(text);
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                // the clipboard may be empty or contain something other than text
+                let Ok(text) = clipboard.get_text() else {
+                    continue;
+                };
+                if last_text.as_ref() == Some(&text) {
+                    continue;
+                }
+                if text.len() > MAX_CLIPBOARD_SIZE {
+                    debug!("Clipboard contents too large, not sending them to the client.");
+                } else {
+                    send_message(&mut sender, MessageOutbound::ClipboardContent(text.clone()));
+                }
+                last_text = Some(text);
+            }
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
@@ -1,9 +1,15 @@
-use std::net::IpAddr;
-use std::{fs, path::PathBuf};
+use std::fs;
+use std::path::{Path, PathBuf};
 
 use clap::Parser;
 use serde::{Deserialize, Serialize};
-use tracing::{debug, warn};
+use tracing::{debug, error, warn};
+
+#[cfg(target_os = "linux")]
+use crate::capturable::{CaptureBackend, CaptureBackends, X11WindowCapture};
+use crate::encoder_probe::Encoder;
+use crate::network::{BindAddresses, IpNetwork};
+use crate::video::{EncoderPreset, RecordingFormat};
 
 #[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ThemeType {
@@ -35,6 +41,7 @@ impl Default for ThemeType {
 }
 
 impl ThemeType {
+    #[cfg(feature = "gui")]
     pub fn apply(&self) {
         let theme = match self {
             ThemeType::Classic => fltk_theme::ThemeType::Classic,
@@ -73,10 +80,38 @@ impl ThemeType {
 pub struct Config {
     #[arg(long, help = "Access code")]
     pub access_code: Option<String>,
-    #[arg(long, default_value = "0.0.0.0", help = "Bind address")]
-    pub bind_address: IpAddr,
+    #[arg(
+        long,
+        default_value = "0.0.0.0",
+        help = "Comma separated IP addresses or names of network interfaces to listen on, like \
+        \"0.0.0.0, ::\" or \"eth0\"."
+    )]
+    pub bind_address: BindAddresses,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "Comma separated networks clients may connect from, like \"192.168.0.0/16, \
+        fd00::/8\", connections from anywhere else are rejected. Clients may connect from \
+        anywhere if none are given."
+    )]
+    #[serde(default)]
+    pub allowed_networks: Vec<IpNetwork>,
     #[arg(long, default_value = "1701", help = "Web port")]
     pub web_port: u16,
+    #[arg(long, help = "Serve Weylus via HTTPS and secure websockets.")]
+    #[serde(default)]
+    pub tls: bool,
+    #[arg(
+        long,
+        help = "PEM file containing the TLS certificate, if neither certificate nor key are given a \
+        self-signed certificate is generated."
+    )]
+    pub tls_cert: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "PEM file containing the private key of the TLS certificate."
+    )]
+    pub tls_key: Option<PathBuf>,
     #[cfg(target_os = "linux")]
     #[arg(
         long,
@@ -87,6 +122,14 @@ pub struct Config {
     #[arg(long, help = "Try to use Nvidia's NVENC to encode the video via GPU.")]
     #[serde(default)]
     pub try_nvenc: bool,
+    #[cfg(any(target_os = "linux", target_os = "windows"))]
+    #[arg(
+        long,
+        help = "Index of the GPU NVENC encodes on as counted by CUDA, by default the driver picks \
+        one. Set CUDA_DEVICE_ORDER=PCI_BUS_ID to count them in the order nvidia-smi lists them in."
+    )]
+    #[serde(default)]
+    pub nvenc_gpu: Option<u32>,
     #[cfg(target_os = "macos")]
     #[arg(
         long,
@@ -101,18 +144,151 @@ pub struct Config {
     )]
     #[serde(default)]
     pub try_mediafoundation: bool,
+    #[arg(
+        long,
+        value_enum,
+        default_value = "fastest",
+        help = "Time encoders spend on each frame, slower presets improve the quality at the same \
+        bitrate but add latency and load. VideoToolbox and MediaFoundation ignore this."
+    )]
+    #[serde(default)]
+    pub encoder_preset: EncoderPreset,
+    #[arg(
+        long,
+        default_value = "12",
+        value_parser = clap::value_parser!(u32).range(1..=1000),
+        help = "Frames after which encoders insert a keyframe, higher values save bandwidth but \
+        leave clients longer with a broken picture after data got lost."
+    )]
+    #[serde(default = "default_gop_size")]
+    pub gop_size: u32,
     #[arg(long, help = "Start Weylus server immediately on program start.")]
     #[serde(default)]
     pub auto_start: bool,
     #[arg(long, help = "Gui Theme")]
     pub gui_theme: Option<ThemeType>,
-    #[arg(long, help = "Run Weylus without gui and start immediately.")]
+    #[arg(
+        long,
+        visible_alias = "headless",
+        help = "Run Weylus without gui and start immediately."
+    )]
     #[serde(default)]
     pub no_gui: bool,
+    #[arg(long, help = "Do not announce Weylus on the local network via mDNS.")]
+    #[serde(default)]
+    pub no_mdns: bool,
     #[cfg(target_os = "linux")]
     #[arg(long, help = "Wayland/PipeWire Support.")]
     #[serde(default)]
     pub wayland_support: bool,
+    #[cfg(target_os = "linux")]
+    #[arg(
+        long,
+        value_enum,
+        help = "Screen capture backend, by default PipeWire is used on Wayland and X11 otherwise."
+    )]
+    pub capture_backend: Option<CaptureBackend>,
+    #[cfg(target_os = "linux")]
+    #[arg(
+        long,
+        value_enum,
+        help = "How windows are captured via X11: composite reads them from their own pixmap so \
+        windows covering them do not show up, crop takes the screen under them. Auto crops the \
+        active window so menus are visible. Some OpenGL applications show up black with \
+        composite. Clients can choose for themselves, this is the default."
+    )]
+    pub x11_window_capture: Option<X11WindowCapture>,
+    #[cfg(target_os = "macos")]
+    #[arg(
+        long,
+        help = "Capture screens and windows at their logical resolution instead of the full \
+        resolution of Retina displays."
+    )]
+    #[serde(default)]
+    pub logical_resolution: bool,
+    #[arg(
+        long,
+        default_value = "2.0",
+        help = "Interval in seconds after which a frame is sent even if the screen content did \
+        not change."
+    )]
+    #[serde(default = "default_full_frame_interval")]
+    pub full_frame_interval: f64,
+    #[arg(
+        long,
+        default_value = "15.0",
+        help = "Seconds after which clients that stopped responding, for example because their \
+        network connection dropped, are disconnected, 0 disables the timeout."
+    )]
+    #[serde(default = "default_client_timeout")]
+    pub client_timeout: f64,
+    #[arg(
+        long,
+        default_value = "30.0",
+        help = "Seconds clients whose connection dropped have to reconnect and resume where they \
+        left off, 0 disables resuming."
+    )]
+    #[serde(default = "default_session_grace_period")]
+    pub session_grace_period: f64,
+    #[arg(
+        long,
+        help = "Let clients send pointer movements while hovering via WebRTC, which unlike the \
+        websocket does not hold them back while lost packets are resent. Needs UDP between client \
+        and host and Weylus built with the webrtc feature."
+    )]
+    #[serde(default)]
+    pub webrtc: bool,
+    #[arg(
+        long,
+        help = "Directory recordings requested by clients are saved to, defaults to the videos \
+        directory of the user."
+    )]
+    pub recording_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        value_enum,
+        default_value = "mp4",
+        help = "Container format of recordings."
+    )]
+    #[serde(default)]
+    pub recording_format: RecordingFormat,
+    #[arg(
+        long,
+        help = "Capturable selected for clients when they connect, given by its index in the \
+        list of capturables or a part of its name."
+    )]
+    pub default_capturable: Option<String>,
+    // the capturable selected last, selected again if there is no default_capturable, see
+    // LastCapturable
+    #[arg(skip)]
+    #[serde(default)]
+    pub capturable_name: Option<String>,
+    #[cfg(target_os = "linux")]
+    #[arg(
+        long,
+        help = "Leave docks, panels, menus, tooltips and other windows that are hardly worth \
+        capturing out of the list of capturables, X11 only."
+    )]
+    #[serde(default)]
+    pub hide_utility_windows: bool,
+    #[cfg(target_os = "linux")]
+    #[arg(
+        long,
+        default_value = "65535",
+        value_parser = clap::value_parser!(i32).range(1..=65535),
+        help = "Largest pressure the simulated stylus reports, uinput only. Lower it for \
+        applications that do not cope with a large pressure range."
+    )]
+    #[serde(default = "default_pen_pressure_max")]
+    pub pen_pressure_max: i32,
+    #[arg(
+        long,
+        help = "Global hotkey that turns input from all clients off and on again, like \
+        \"Ctrl+Alt+I\". Modifiers are Ctrl, Alt, Shift and Super, keys are letters, digits, F1 to \
+        F24, Escape, space, Insert, Delete, Home, End, Page_Up, Page_Down, Print, Pause and Scroll_Lock. \
+        Only supported on X11 and Windows."
+    )]
+    pub input_toggle_hotkey: Option<String>,
 
     #[arg(long, help = "Print template of index.html served by Weylus.")]
     #[serde(skip)]
@@ -126,6 +302,24 @@ pub struct Config {
     #[arg(long, help = "Print lib.js served by Weylus.")]
     #[serde(skip)]
     pub print_lib_js: bool,
+    #[arg(
+        long,
+        help = "Check if Weylus is allowed to create uinput devices to simulate input, print a \
+        report and exit. The exit code is 0 if all devices could be created."
+    )]
+    #[serde(skip)]
+    pub check: bool,
+    #[arg(
+        long,
+        help = "Test capturing, encoding and simulating input without starting the server, print \
+        a report and exit. The exit code is 0 if everything Weylus can not do without works."
+    )]
+    #[serde(skip)]
+    pub self_test: bool,
+    // run by the GUI in a child process, see encoder_probe::probe_encoders
+    #[arg(long, value_enum, hide = true)]
+    #[serde(skip)]
+    pub probe_encoder: Option<Encoder>,
 
     #[arg(
         long,
@@ -142,31 +336,73 @@ pub struct Config {
     #[arg(long, help = "Use custom lib.js to be served by Weylus.")]
     #[serde(skip)]
     pub custom_lib_js: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Serve the web client from this directory, files missing in it are served from the \
+        ones built into Weylus. index.html is a template like the one printed by \
+        --print-index-html. Browsers are told not to cache the files, so changes show up on \
+        reload. The --custom-* options take precedence."
+    )]
+    pub web_root: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Read the configuration from this TOML file instead of the default location, \
+        command line arguments take precedence."
+    )]
+    #[serde(skip)]
+    pub config_file: Option<PathBuf>,
 
     #[arg(long, help = "Print shell completions for given shell.")]
     #[serde(skip)]
     pub completions: Option<clap_complete::Shell>,
 }
 
+impl Config {
+    /// The backends to list capturables from, resolved for the current session.
+    #[cfg(target_os = "linux")]
+    pub fn capture_backends(&self) -> CaptureBackends {
+        CaptureBackends::select(
+            self.capture_backend.unwrap_or_default(),
+            self.wayland_support,
+            self.x11_window_capture.unwrap_or_default(),
+        )
+    }
+}
+
+fn default_full_frame_interval() -> f64 {
+    2.0
+}
+
+fn default_gop_size() -> u32 {
+    12
+}
+
+fn default_client_timeout() -> f64 {
+    15.0
+}
+
+fn default_session_grace_period() -> f64 {
+    30.0
+}
+
+#[cfg(target_os = "linux")]
+fn default_pen_pressure_max() -> i32 {
+    crate::input::uinput_device::DEFAULT_PRESSURE_MAX
+}
+
 pub fn read_config() -> Option<Config> {
     if let Some(mut config_path) = dirs::config_dir() {
         config_path.push("weylus");
         config_path.push("weylus.toml");
-        match fs::read_to_string(&config_path) {
-            Ok(s) => match toml::from_str(&s) {
-                Ok(c) => Some(c),
-                Err(e) => {
-                    warn!("Failed to read configuration file: {}", e);
-                    None
-                }
-            },
+        match read_config_file(&config_path) {
+            Ok(c) => Some(c),
+            Err(ConfigFileError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+                debug!("Failed to read configuration file: {}", err);
+                None
+            }
             Err(err) => {
-                match err.kind() {
-                    std::io::ErrorKind::NotFound => {
-                        debug!("Failed to read configuration file: {}", err)
-                    }
-                    _ => warn!("Failed to read configuration file: {}", err),
-                }
+                warn!("Failed to read configuration file, using defaults: {}", err);
                 None
             }
         }
@@ -175,6 +411,28 @@ pub fn read_config() -> Option<Config> {
     }
 }
 
+#[derive(Debug)]
+pub enum ConfigFileError {
+    Io(std::io::Error),
+    Toml(toml::de::Error),
+}
+
+impl std::fmt::Display for ConfigFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{err}"),
+            Self::Toml(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigFileError {}
+
+pub fn read_config_file(path: &Path) -> Result<Config, ConfigFileError> {
+    let s = fs::read_to_string(path).map_err(ConfigFileError::Io)?;
+    toml::from_str(&s).map_err(ConfigFileError::Toml)
+}
+
 pub fn write_config(conf: &Config) {
     match dirs::config_dir() {
         Some(mut config_path) => {
@@ -186,10 +444,15 @@ pub fn write_config(conf: &Config) {
                 }
             }
             config_path.push("weylus.toml");
+            // write to a temporary file first so that a crash does not leave a truncated
+            // configuration behind
+            let tmp_path = config_path.with_extension("toml.tmp");
             if let Err(err) = fs::write(
-                config_path,
-                &toml::to_string_pretty(&conf).expect("Failed to encode config to toml."),
-            ) {
+                &tmp_path,
+                toml::to_string_pretty(&conf).expect("Failed to encode config to toml."),
+            )
+            .and_then(|_| fs::rename(&tmp_path, &config_path))
+            {
                 warn!("Failed to write configuration file: {}", err);
             }
         }
@@ -199,14 +462,39 @@ pub fn write_config(conf: &Config) {
     }
 }
 
+/// Saves the capturable selected last to the configuration file, if there is one, without the
+/// settings that were only given on the command line.
+pub fn write_capturable_name(name: String) {
+    if let Some(mut config) = read_config() {
+        config.capturable_name = Some(name);
+        write_config(&config);
+    }
+}
+
 pub fn get_config() -> Config {
     let args = std::env::args();
-    if let Some(mut config) = read_config() {
+    let cli = Config::parse();
+    let config = match &cli.config_file {
+        // the file has been asked for explicitly, carrying on without it would be a surprise
+        Some(path) => match read_config_file(path) {
+            Ok(config) => Some(config),
+            Err(err) => {
+                error!(
+                    "Failed to read configuration file {}: {}",
+                    path.display(),
+                    err
+                );
+                std::process::exit(1);
+            }
+        },
+        None => read_config(),
+    };
+    if let Some(mut config) = config {
         if args.len() > 1 {
             config.update_from(args);
         }
         config
     } else {
-        Config::parse()
+        cli
     }
 }
@@ -0,0 +1,244 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::mpsc;
+
+use crate::protocol::{
+    ClientConfiguration, ConnectionInfo, MessageInbound, VideoFormat, VideoStats,
+};
+use crate::websocket::unix_time_ms;
+
+// messages from the HTTP API that may be waiting for a connection to pass them on
+const CONTROL_QUEUE_LEN: usize = 8;
+
+/// Websocket connections that are open right now, listed in the GUI and sent to clients asking
+/// for them. Clones share the same list.
+#[derive(Clone, Default)]
+pub struct Connections {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: u64,
+    list: Vec<Entry>,
+}
+
+struct Entry {
+    info: ConnectionInfo,
+    // the configuration the client sent last
+    config: Option<ClientConfiguration>,
+    // passes messages on to the handler of the connection as if the client had sent them
+    control: Option<mpsc::Sender<MessageInbound>>,
+}
+
+impl Connections {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a connection, it stays in the list until the returned handle is dropped.
+    pub fn open(&self, address: SocketAddr) -> Connection {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let id = inner.next_id;
+        let now = unix_time_ms();
+        inner.list.push(Entry {
+            info: ConnectionInfo {
+                id,
+                address: Some(address),
+                connected_at_ms: now,
+                last_activity_ms: now,
+                config: None,
+                frames_sent: 0,
+                stats: None,
+            },
+            config: None,
+            control: None,
+        });
+        Connection {
+            id,
+            connections: self.clone(),
+        }
+    }
+
+    /// All connections in the order they have been opened.
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let inner = self.inner.lock().unwrap();
+        inner.list.iter().map(|entry| entry.info.clone()).collect()
+    }
+
+    /// Like list but without what tells who the clients are, their addresses and the names they
+    /// gave themselves, for clients that are not on the host.
+    pub fn list_anonymized(&self) -> Vec<ConnectionInfo> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .list
+            .iter()
+            .map(|entry| ConnectionInfo {
+                address: None,
+                config: entry.config.as_ref().map(|config| {
+                    summary(&ClientConfiguration {
+                        client_name: None,
+                        ..config.clone()
+                    })
+                }),
+                ..entry.info.clone()
+            })
+            .collect()
+    }
+
+    /// The configuration the client of the connection sent last, None if there is no such
+    /// connection or it has not been configured yet.
+    pub fn config(&self, id: u64) -> Option<ClientConfiguration> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .list
+            .iter()
+            .find(|entry| entry.info.id == id)
+            .and_then(|entry| entry.config.clone())
+    }
+
+    /// Pass msg on to the handler of the connection as if its client had sent it, returns false
+    /// if there is no such connection or it does not take messages this way.
+    pub async fn send(&self, id: u64, msg: MessageInbound) -> bool {
+        let control = {
+            let inner = self.inner.lock().unwrap();
+            inner
+                .list
+                .iter()
+                .find(|entry| entry.info.id == id)
+                .and_then(|entry| entry.control.clone())
+        };
+        match control {
+            Some(control) => control.send(msg).await.is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// The entry of a single connection in Connections.
+pub struct Connection {
+    id: u64,
+    connections: Connections,
+}
+
+impl Connection {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The client sent something.
+    pub fn seen(&self) {
+        self.update(|entry| entry.info.last_activity_ms = unix_time_ms());
+    }
+
+    pub fn configured(&self, config: &ClientConfiguration) {
+        let summary = summary(config);
+        self.update(|entry| {
+            entry.info.config = Some(summary);
+            entry.config = Some(config.clone());
+        });
+    }
+
+    pub fn frame_sent(&self) {
+        self.update(|entry| entry.info.frames_sent += 1);
+    }
+
+    pub fn stats_measured(&self, stats: &VideoStats) {
+        self.update(|entry| entry.info.stats = Some(stats.clone()));
+    }
+
+    /// Messages sent to this connection with Connections::send, calling this again replaces the
+    /// receiver.
+    pub fn control(&self) -> mpsc::Receiver<MessageInbound> {
+        let (sender, receiver) = mpsc::channel(CONTROL_QUEUE_LEN);
+        self.update(|entry| entry.control = Some(sender));
+        receiver
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Entry)) {
+        let mut inner = self.connections.inner.lock().unwrap();
+        if let Some(entry) = inner.list.iter_mut().find(|entry| entry.info.id == self.id) {
+            f(entry);
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut inner = self.connections.inner.lock().unwrap();
+        inner.list.retain(|entry| entry.info.id != self.id);
+    }
+}
+
+/// One line describing what the client asked for, like
+/// "Tablet: x11:0, 1280x800, 30 fps, MP4, input".
+fn summary(config: &ClientConfiguration) -> String {
+    let mut summary = String::new();
+    if let Some(name) = &config.client_name {
+        summary.push_str(&format!("{name}: "));
+    }
+    summary.push_str(&format!(
+        "{}, {}x{}, {} fps, {}, {}",
+        config.capturable_id,
+        config.max_width,
+        config.max_height,
+        config.frame_rate,
+        match config.video_format {
+            VideoFormat::Mp4 => "MP4",
+            VideoFormat::Mjpeg => "MJPEG",
+            VideoFormat::Tiles => "lossless tiles",
+        },
+        if !config.input_enabled {
+            "view-only"
+        } else if config.uinput_support {
+            "uinput"
+        } else {
+            "input"
+        }
+    ));
+    summary
+}
+
+/// Format a time from ConnectionInfo as how long ago it has been, like "3m 12s".
+pub fn elapsed_since(time_ms: f64) -> String {
+    let secs = ((unix_time_ms() - time_ms) / 1000.0).max(0.0) as u64;
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anonymized_list_leaves_out_addresses_and_names() {
+        let connections = Connections::new();
+        let connection = connections.open("192.168.1.20:51234".parse().unwrap());
+        let config: ClientConfiguration = serde_json::from_str(
+            r#"{"uinput_support": true, "capturable_id": "x11:HDMI-1", "capture_cursor": false,
+            "max_width": 1920, "max_height": 1080, "client_name": "Tablet", "frame_rate": 30}"#,
+        )
+        .unwrap();
+        connection.configured(&config);
+
+        let list = connections.list();
+        assert_eq!(list[0].address, Some("192.168.1.20:51234".parse().unwrap()));
+        assert!(list[0]
+            .config
+            .as_ref()
+            .unwrap()
+            .starts_with("Tablet: x11:HDMI-1"));
+
+        let anonymized = connections.list_anonymized();
+        assert_eq!(anonymized[0].id, list[0].id);
+        assert_eq!(anonymized[0].address, None);
+        let summary = anonymized[0].config.as_ref().unwrap();
+        assert!(summary.starts_with("x11:HDMI-1"), "{summary}");
+        assert!(!summary.contains("Tablet"));
+    }
+}
@@ -0,0 +1,191 @@
+use std::process::{Child, Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use tracing::{debug, warn};
+
+use crate::protocol::VideoCodec;
+use crate::video::{EncoderOptions, EncoderPreset, PixelProvider, VideoEncoder};
+
+// drivers that hang are given up on after this
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// small but large enough for the minimum size of hardware encoders
+const PROBE_SIZE: usize = 256;
+
+/// Encoders Weylus can use if FFmpeg has been built with them and the drivers work.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoder {
+    X264,
+    Vp9,
+    Vaapi,
+    Nvenc,
+    VideoToolbox,
+    MediaFoundation,
+}
+
+impl Encoder {
+    /// The encoders that exist on this platform at all.
+    pub fn platform_encoders() -> Vec<Self> {
+        let mut encoders = vec![Self::X264, Self::Vp9];
+        if cfg!(target_os = "linux") {
+            encoders.push(Self::Vaapi);
+        }
+        if cfg!(any(target_os = "linux", target_os = "windows")) {
+            encoders.push(Self::Nvenc);
+        }
+        if cfg!(target_os = "macos") {
+            encoders.push(Self::VideoToolbox);
+        }
+        if cfg!(target_os = "windows") {
+            encoders.push(Self::MediaFoundation);
+        }
+        encoders
+    }
+
+    /// Name of the encoder in FFmpeg, hardware encoders are probed with H.264.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::X264 => "libx264",
+            Self::Vp9 => "libvpx-vp9",
+            Self::Vaapi => "h264_vaapi",
+            Self::Nvenc => "h264_nvenc",
+            Self::VideoToolbox => "h264_videotoolbox",
+            Self::MediaFoundation => "h264_mf",
+        }
+    }
+
+    fn options(&self) -> EncoderOptions {
+        let mut options = EncoderOptions {
+            try_vaapi: false,
+            try_nvenc: false,
+            nvenc_gpu: None,
+            try_videotoolbox: false,
+            try_mediafoundation: false,
+            codec: None,
+            bitrate_kbps: None,
+            crf: None,
+            preset: EncoderPreset::default(),
+            gop_size: 12,
+            intra_refresh: false,
+        };
+        match self {
+            Self::X264 => (),
+            Self::Vp9 => options.codec = Some(VideoCodec::Vp9),
+            Self::Vaapi => options.try_vaapi = true,
+            Self::Nvenc => options.try_nvenc = true,
+            Self::VideoToolbox => options.try_videotoolbox = true,
+            Self::MediaFoundation => options.try_mediafoundation = true,
+        }
+        options
+    }
+}
+
+/// Open the encoder, encode a black frame and close it again, true if that worked. Some drivers
+/// only fail once there is something to encode and broken ones may crash the process, so this is
+/// meant to be run in a process of its own, see probe_encoders.
+pub fn probe_in_process(encoder: Encoder) -> bool {
+    let mut video_encoder = match VideoEncoder::new(
+        PROBE_SIZE,
+        PROBE_SIZE,
+        PROBE_SIZE,
+        PROBE_SIZE,
+        |_| (),
+        encoder.options(),
+    ) {
+        Ok(video_encoder) => video_encoder,
+        Err(err) => {
+            debug!("Failed to open {}: {err}", encoder.name());
+            return false;
+        }
+    };
+    // the software encoder is used if the hardware encoder does not work
+    if video_encoder.codec_name() != encoder.name() {
+        return false;
+    }
+    let black = vec![0; PROBE_SIZE * PROBE_SIZE * 4];
+    let encoded = video_encoder
+        .encode(
+            PixelProvider::BGR0(PROBE_SIZE, PROBE_SIZE, &black),
+            Instant::now(),
+        )
+        .is_some();
+    if !encoded {
+        debug!("Failed to encode a frame with {}.", encoder.name());
+    }
+    encoded
+}
+
+/// Check which of the encoders work, each one in a child process running the current executable
+/// with --probe-encoder. An encoder whose probe crashes, fails or hangs counts as unavailable. The
+/// probes run in parallel, this blocks until all of them are done.
+pub fn probe_encoders(encoders: &[Encoder]) -> Vec<(Encoder, bool)> {
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(err) => {
+            warn!("Failed to find the Weylus executable to probe encoders with: {err}");
+            return encoders.iter().map(|encoder| (*encoder, false)).collect();
+        }
+    };
+    let children: Vec<(Encoder, Option<Child>)> = encoders
+        .iter()
+        .map(|encoder| {
+            let child = Command::new(&exe)
+                .arg("--probe-encoder")
+                .arg(encoder.to_possible_value().unwrap().get_name())
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn();
+            match child {
+                Ok(child) => (*encoder, Some(child)),
+                Err(err) => {
+                    warn!("Failed to probe {}: {err}", encoder.name());
+                    (*encoder, None)
+                }
+            }
+        })
+        .collect();
+    let deadline = Instant::now() + PROBE_TIMEOUT;
+    children
+        .into_iter()
+        .map(|(encoder, child)| {
+            let available = child.is_some_and(|child| wait_for_probe(child, deadline, encoder));
+            debug!(
+                "Encoder {} is {}available.",
+                encoder.name(),
+                if available { "" } else { "not " }
+            );
+            (encoder, available)
+        })
+        .collect()
+}
+
+fn wait_for_probe(mut child: Child, deadline: Instant, encoder: Encoder) -> bool {
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                if status.code().is_none() {
+                    warn!(
+                        "Probing {} crashed, it is most likely not supported by the drivers: \
+                        {status}",
+                        encoder.name()
+                    );
+                }
+                return status.success();
+            }
+            Ok(None) if Instant::now() < deadline => sleep(Duration::from_millis(20)),
+            Ok(None) => {
+                warn!("Probing {} timed out.", encoder.name());
+                let _ = child.kill();
+                let _ = child.wait();
+                return false;
+            }
+            Err(err) => {
+                warn!("Failed to wait for probing {}: {err}", encoder.name());
+                return false;
+            }
+        }
+    }
+}
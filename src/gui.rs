@@ -1,7 +1,6 @@
 use std::cmp::min;
 use std::io::Cursor;
 use std::iter::Iterator;
-use std::net::{IpAddr, SocketAddr};
 
 use fltk::image::PngImage;
 use fltk::menu::Choice;
@@ -19,11 +18,15 @@ use fltk::{
     window::Window,
 };
 
-#[cfg(not(target_os = "windows"))]
-use pnet_datalink as datalink;
-
-use crate::config::{write_config, Config, ThemeType};
-use crate::web::Web2UiMessage::UInputInaccessible;
+use weylus::config::{write_config, Config, ThemeType};
+use weylus::connections::elapsed_since;
+use weylus::encoder_probe::{probe_encoders, Encoder};
+use weylus::network::{interface_addresses, url_host, BindAddress, BindAddresses};
+use weylus::protocol::ConnectionInfo;
+use weylus::video::EncoderPreset;
+use weylus::web::Web2UiMessage::{
+    CapturableSelected, HostInputEnabled, UInputInaccessible, ViewOnlyClients,
+};
 
 pub fn run(config: &Config, log_receiver: mpsc::Receiver<String>) {
     let width = 200;
@@ -33,7 +36,7 @@ pub fn run(config: &Config, log_receiver: mpsc::Receiver<String>) {
     let app = App::default().with_scheme(fltk::app::AppScheme::Gtk);
     config.gui_theme.map(|th| th.apply());
     let mut wind = Window::default()
-        .with_size(660, 600)
+        .with_size(660, 690 + 8 * height)
         .center_screen()
         .with_label(&format!("Weylus - {}", env!("CARGO_PKG_VERSION")));
     wind.set_xclass("weylus");
@@ -44,9 +47,9 @@ pub fn run(config: &Config, log_receiver: mpsc::Receiver<String>) {
         .with_label("Access code");
     input_access_code.set_tooltip(
         "Restrict who can control your computer with an access code. Note that this does NOT do \
-        any kind of encryption and it is advised to only run Weylus inside trusted networks! Do \
-        NOT reuse any of your passwords! If left blank, no code is required to access Weylus \
-        remotely.",
+        any kind of encryption unless TLS is enabled and it is advised to only run Weylus inside \
+        trusted networks! Do NOT reuse any of your passwords! If left blank, no code is required \
+        to access Weylus remotely.",
     );
     if let Some(code) = config.access_code.as_ref() {
         input_access_code.set_value(code);
@@ -56,6 +59,10 @@ pub fn run(config: &Config, log_receiver: mpsc::Receiver<String>) {
         .with_size(width, height)
         .below_of(&input_access_code, padding)
         .with_label("Bind Address");
+    input_bind_addr.set_tooltip(
+        "IP addresses or names of network interfaces to listen on, separated by commas. Use \
+        \"0.0.0.0, ::\" to listen on all IPv4 and IPv6 addresses.",
+    );
     input_bind_addr.set_value(&config.bind_address.to_string());
 
     let mut input_port = IntInput::default()
@@ -64,9 +71,21 @@ pub fn run(config: &Config, log_receiver: mpsc::Receiver<String>) {
         .with_label("Port");
     input_port.set_value(&config.web_port.to_string());
 
+    let mut input_hotkey = Input::default()
+        .with_size(width, height)
+        .below_of(&input_port, padding)
+        .with_label("Input Hotkey");
+    input_hotkey.set_tooltip(
+        "Key combination like Ctrl+Alt+I that turns input from all clients off and on again, no \
+        matter which application has the focus. Only supported on X11 and Windows.",
+    );
+    if let Some(hotkey) = config.input_toggle_hotkey.as_ref() {
+        input_hotkey.set_value(hotkey);
+    }
+
     let mut check_auto_start = CheckButton::default()
         .with_size(70, height)
-        .below_of(&input_port, padding + 5)
+        .below_of(&input_hotkey, padding + 5)
         .with_label("Auto Start");
     check_auto_start.set_tooltip("Start Weylus server immediately on program start.");
     check_auto_start.set_checked(config.auto_start);
@@ -138,11 +157,76 @@ pub fn run(config: &Config, log_receiver: mpsc::Receiver<String>) {
         check_nvenc.hide();
     }
 
+    let mut label_encoders = Frame::default()
+        .with_size(2 * width, height)
+        .below_of(&check_native_hw_accel, padding)
+        .with_label("Probing encoders...");
+    label_encoders.set_tooltip(
+        "Encoders that work with this build of FFmpeg and the installed drivers, hardware \
+        encoders that do not work are greyed out.",
+    );
+
+    let mut choice_preset = Choice::default()
+        .with_size(width, height)
+        .below_of(&label_encoders, padding)
+        .with_label("Encoder Preset");
+    choice_preset.set_tooltip(
+        "Time encoders spend on each frame, slower presets improve the quality at the same \
+        bitrate but add latency and load. VideoToolbox and MediaFoundation ignore this.",
+    );
+    for preset in EncoderPreset::presets() {
+        choice_preset.add_choice(&format!("{preset:?}"));
+    }
+    choice_preset.set_value(
+        EncoderPreset::presets()
+            .iter()
+            .position(|preset| *preset == config.encoder_preset)
+            .unwrap_or(0) as i32,
+    );
+
+    let mut input_gop_size = IntInput::default()
+        .with_size(width, height)
+        .below_of(&choice_preset, padding)
+        .with_label("GOP Size");
+    input_gop_size.set_tooltip(
+        "Frames after which encoders insert a keyframe, higher values save bandwidth but leave \
+        clients longer with a broken picture after data got lost.",
+    );
+    input_gop_size.set_value(&config.gop_size.to_string());
+
+    let mut check_tls = CheckButton::default()
+        .with_size(70, height)
+        .below_of(&input_gop_size, padding)
+        .with_label("TLS");
+    check_tls.set_tooltip(
+        "Serve Weylus via HTTPS. Unless a certificate is configured, a self-signed certificate is \
+        generated, which browsers will warn about on the first visit.",
+    );
+    check_tls.set_checked(config.tls);
+
+    let mut check_input = CheckButton::default()
+        .with_size(110, height)
+        .right_of(&check_tls, 2 * padding)
+        .with_label("Allow Input");
+    check_input.set_tooltip(
+        "Uncheck to ignore input from all clients, for example if a touch got stuck. Pressing the \
+        input hotkey does the same.",
+    );
+    check_input.set_checked(true);
+
     let mut but_toggle = Button::default()
         .with_size(width, height)
-        .below_of(&check_native_hw_accel, 2 * padding)
+        .below_of(&check_tls, padding)
         .with_label("Start");
 
+    let mut label_view_only = Frame::default()
+        .with_size(250, height)
+        .right_of(&but_toggle, padding);
+    label_view_only.set_tooltip(
+        "Clients in view-only mode only mirror the screen and can not control the computer.",
+    );
+    label_view_only.hide();
+
     let mut output_server_addr = Output::default()
         .with_size(500, height)
         .below_of(&but_toggle, 3 * padding)
@@ -157,6 +241,12 @@ pub fn run(config: &Config, log_receiver: mpsc::Receiver<String>) {
     output.set_buffer(output_buf);
     let output_buf = output.buffer().unwrap();
 
+    let mut output_connections = TextDisplay::default()
+        .with_size(600, 4 * height)
+        .below_of(&output, padding);
+    output_connections.set_buffer(TextBuffer::default());
+    output_connections.set_tooltip("Clients connected to Weylus.");
+
     let mut choice_theme = Choice::default()
         .with_size(width, height)
         .right_of(&input_access_code, padding);
@@ -172,6 +262,12 @@ pub fn run(config: &Config, log_receiver: mpsc::Receiver<String>) {
 
     qr_frame.hide();
 
+    let mut choice_addr = Choice::default()
+        .with_size(235, height)
+        .below_of(&qr_frame, padding);
+    choice_addr.set_tooltip("Address of this computer encoded in the QR code.");
+    choice_addr.hide();
+
     wind.make_resizable(true);
     wind.end();
     wind.show();
@@ -185,7 +281,54 @@ pub fn run(config: &Config, log_receiver: mpsc::Receiver<String>) {
         }
     });
 
-    let mut weylus = crate::weylus::Weylus::new();
+    {
+        let mut label_encoders = label_encoders.clone();
+        let mut check_native_hw_accel = check_native_hw_accel.clone();
+        let mut check_nvenc = check_nvenc.clone();
+        std::thread::spawn(move || {
+            let encoders = probe_encoders(&Encoder::platform_encoders());
+            awake_callback(move || {
+                let available: Vec<&str> = encoders
+                    .iter()
+                    .filter(|(_, available)| *available)
+                    .map(|(encoder, _)| encoder.name())
+                    .collect();
+                if available.is_empty() {
+                    label_encoders.set_label("No working encoder found!");
+                } else {
+                    label_encoders.set_label(&format!("Encoders: {}", available.join(", ")));
+                }
+                for (encoder, _) in encoders.iter().filter(|(_, available)| !available) {
+                    let check = match encoder {
+                        Encoder::Vaapi | Encoder::VideoToolbox | Encoder::MediaFoundation => {
+                            &mut check_native_hw_accel
+                        }
+                        Encoder::Nvenc => &mut check_nvenc,
+                        Encoder::X264 | Encoder::Vp9 => continue,
+                    };
+                    check.deactivate();
+                    check.set_tooltip(&format!(
+                        "{} does not work with this build of FFmpeg or the installed drivers.",
+                        encoder.name()
+                    ));
+                }
+            });
+        });
+    }
+
+    let mut weylus = weylus::Weylus::new();
+    {
+        let input_switch = weylus.input_switch().clone();
+        check_input.set_callback(move |c| input_switch.set_enabled(c.is_checked()));
+    }
+    {
+        let connections = weylus.connections().clone();
+        let mut buf = output_connections.buffer().unwrap();
+        fltk::app::add_timeout3(0.0, move |handle| {
+            buf.set_text(&describe_connections(&connections.list()));
+            fltk::app::repeat_timeout3(1.0, handle);
+        });
+    }
     let mut is_server_running = false;
     let auto_start = config.auto_start;
     let config = Arc::new(Mutex::new(config.clone()));
@@ -204,6 +347,7 @@ pub fn run(config: &Config, log_receiver: mpsc::Receiver<String>) {
 
     let mut toggle_server = move |but: &mut Button| {
         if let Err(err) = || -> Result<(), Box<dyn std::error::Error>> {
+            let shared_config = config.clone();
             let mut config = config.lock().unwrap();
             if !is_server_running {
                 {
@@ -212,34 +356,50 @@ pub fn run(config: &Config, log_receiver: mpsc::Receiver<String>) {
                         "" => None,
                         code => Some(code),
                     };
-                    let bind_addr: IpAddr = input_bind_addr.value().parse()?;
+                    let bind_addr: BindAddresses = input_bind_addr.value().parse()?;
                     let web_port: u16 = input_port.value().parse()?;
 
                     config.access_code = access_code.map(|s| s.to_string());
                     config.web_port = web_port;
                     config.bind_address = bind_addr;
                     config.auto_start = check_auto_start.is_checked();
+                    config.tls = check_tls.is_checked();
+                    let hotkey = input_hotkey.value();
+                    config.input_toggle_hotkey =
+                        (!hotkey.trim().is_empty()).then(|| hotkey.trim().to_string());
                     config.gui_theme = Some(ThemeType::from_index(choice_theme.value()));
+                    config.encoder_preset =
+                        EncoderPreset::presets()[choice_preset.value().max(0) as usize];
+                    let gop_size: u32 = input_gop_size.value().parse()?;
+                    if !(1..=1000).contains(&gop_size) {
+                        return Err("The GOP size must be between 1 and 1000.".into());
+                    }
+                    config.gop_size = gop_size;
+                    // encoders that failed probing are not tried, their drivers may crash
+                    let hw_accel =
+                        check_native_hw_accel.is_checked() && check_native_hw_accel.active();
                     #[cfg(target_os = "linux")]
                     {
-                        config.try_vaapi = check_native_hw_accel.is_checked();
+                        config.try_vaapi = hw_accel;
                         config.wayland_support = check_wayland.is_checked();
                     }
                     #[cfg(any(target_os = "linux", target_os = "windows"))]
                     {
-                        config.try_nvenc = check_nvenc.is_checked();
+                        config.try_nvenc = check_nvenc.is_checked() && check_nvenc.active();
                     }
                     #[cfg(target_os = "macos")]
                     {
-                        config.try_videotoolbox = check_native_hw_accel.is_checked();
+                        config.try_videotoolbox = hw_accel;
                     }
                     #[cfg(target_os = "windows")]
                     {
-                        config.try_mediafoundation = check_native_hw_accel.is_checked();
+                        config.try_mediafoundation = hw_accel;
                     }
                 }
-                if !weylus.start(&config, |message| match message {
-                    UInputInaccessible => awake_callback(move || {
+                let label_view_only = label_view_only.clone();
+                let check_input = check_input.clone();
+                if !weylus.start(&config, move |message| match message {
+                    UInputInaccessible(diagnosis) => awake_callback(move || {
                         let w = 500;
                         let h = 300;
                         let mut pop_up = Window::default()
@@ -253,12 +413,36 @@ pub fn run(config: &Config, log_receiver: mpsc::Receiver<String>) {
                         pop_up_text.set_buffer(buf);
                         pop_up_text.wrap_mode(fltk::text::WrapMode::AtBounds, 5);
                         let mut buf = pop_up_text.buffer().unwrap();
-                        buf.set_text(std::include_str!("strings/uinput_error.txt"));
+                        buf.set_text(&format!(
+                            "{diagnosis}\n\n{}",
+                            std::include_str!("strings/uinput_error.txt")
+                        ));
 
                         pop_up.end();
                         pop_up.make_modal(true);
                         pop_up.show();
                     }),
+                    ViewOnlyClients(count) => {
+                        let mut label_view_only = label_view_only.clone();
+                        awake_callback(move || {
+                            if count == 0 {
+                                label_view_only.hide();
+                            } else {
+                                label_view_only
+                                    .set_label(&format!("{count} view-only client(s) connected"));
+                                label_view_only.show();
+                            }
+                        })
+                    }
+                    HostInputEnabled(enabled) => {
+                        let mut check_input = check_input.clone();
+                        awake_callback(move || check_input.set_checked(enabled))
+                    }
+                    CapturableSelected(name) => {
+                        let mut config = shared_config.lock().unwrap();
+                        config.capturable_name = Some(name);
+                        write_config(&config);
+                    }
                 }) {
                     return Ok(());
                 }
@@ -266,98 +450,61 @@ pub fn run(config: &Config, log_receiver: mpsc::Receiver<String>) {
 
                 write_config(&config);
 
-                let mut web_sock = SocketAddr::new(config.bind_address, config.web_port);
-
-                #[cfg(not(target_os = "windows"))]
-                {
-                    if web_sock.ip().is_unspecified() {
-                        // try to guess an ip
-                        let mut ips = Vec::<IpAddr>::new();
-                        for iface in datalink::interfaces()
-                            .iter()
-                            .filter(|iface| iface.is_up() && !iface.is_loopback())
-                        {
-                            for ipnetw in &iface.ips {
-                                if (ipnetw.is_ipv4() && web_sock.ip().is_ipv4())
-                                    || (ipnetw.is_ipv6() && web_sock.ip().is_ipv6())
-                                {
-                                    // filtering ipv6 unicast requires nightly or more fiddling,
-                                    // lets wait for nightlies to stabilize...
-                                    ips.push(ipnetw.ip())
-                                }
-                            }
-                        }
-                        if !ips.is_empty() {
-                            web_sock.set_ip(ips[0]);
-                        }
-                        if ips.len() > 1 {
-                            info!("Found more than one IP address for browsers to connect to,");
-                            info!("other urls are:");
-                            for ip in &ips[1..] {
-                                info!("http://{}", SocketAddr::new(*ip, config.web_port));
-                            }
-                        }
+                let scheme = if config.tls { "https" } else { "http" };
+                let port = config.web_port;
+                let hosts = connection_hosts(&config.bind_address, weylus.mdns_hostname());
+                if hosts.len() > 1 {
+                    info!("Found more than one address for browsers to connect to:");
+                    for (_, host) in &hosts {
+                        info!("{scheme}://{host}:{port}");
                     }
                 }
-
-                #[cfg(not(target_os = "windows"))]
-                {
-                    use image::Luma;
-                    use qrcode::QrCode;
-                    let addr_string = format!("http://{}", web_sock);
-                    output_server_addr.set_value(&addr_string);
-                    let mut url_string = addr_string;
-                    if let Some(access_code) = &config.access_code {
-                        url_string.push_str("?access_code=");
-                        url_string.push_str(
-                            &percent_encoding::utf8_percent_encode(
-                                access_code,
-                                percent_encoding::NON_ALPHANUMERIC,
-                            )
-                            .to_string(),
-                        );
+                if hosts.is_empty() {
+                    output_server_addr.set_value(&format!("{scheme}://<your ip address>"));
+                } else {
+                    // keep the address picked before if it is still around
+                    let previous = choice_addr.choice();
+                    choice_addr.clear();
+                    for (label, _) in &hosts {
+                        choice_addr.add_choice(&label.replace('/', "\\/"));
                     }
-
-                    let cb = move |qr_frame: &mut Frame, _, _, w, h| {
-                        let code = QrCode::new(&url_string).unwrap();
-                        let img_buf = code.render::<Luma<u8>>().build();
-                        let image = image::DynamicImage::ImageLuma8(img_buf);
-                        let dims = min(w, h) as u32;
-                        let image =
-                            image.resize_exact(dims, dims, image::imageops::FilterType::Nearest);
-                        let mut buf = vec![];
-                        let mut cursor = Cursor::new(&mut buf);
-                        image
-                            .write_to(&mut cursor, image::ImageFormat::Png)
-                            .unwrap();
-                        let png = PngImage::from_data(&buf).unwrap();
-                        qr_frame.set_image(Some(png));
+                    let selected = previous
+                        .and_then(|previous| hosts.iter().position(|(label, _)| *label == previous))
+                        .unwrap_or(0);
+                    choice_addr.set_value(selected as i32);
+
+                    let mut output_server_addr = output_server_addr.clone();
+                    let mut qr_frame = qr_frame.clone();
+                    let access_code = config.access_code.clone();
+                    let mdns_hostname = weylus.mdns_hostname().map(str::to_string);
+                    let mut show_address = move |index: usize| {
+                        let host = &hosts[index].1;
+                        let url = format!("{scheme}://{host}:{port}");
+                        match &mdns_hostname {
+                            Some(hostname) if hostname != host => output_server_addr
+                                .set_value(&format!("{url}  or  {scheme}://{hostname}:{port}")),
+                            _ => output_server_addr.set_value(&url),
+                        }
+                        show_qr_code(&mut qr_frame, with_access_code(url, access_code.as_deref()));
                     };
-
-                    let x = qr_frame.x();
-                    let y = qr_frame.y();
-                    let w = qr_frame.width();
-                    let h = qr_frame.height();
-                    cb(&mut qr_frame, x, y, w, h);
-                    qr_frame.resize_callback(cb);
-                    qr_frame.show();
-                }
-                #[cfg(target_os = "windows")]
-                {
-                    if web_sock.ip().is_unspecified() {
-                        output_server_addr.set_value("http://<your ip address>");
-                    } else {
-                        output_server_addr.set_value(&format!("http://{}", web_sock.to_string()));
-                    }
+                    show_address(selected);
+                    choice_addr.set_callback(move |c| {
+                        if c.value() >= 0 {
+                            show_address(c.value() as usize);
+                        }
+                    });
+                    choice_addr.show();
                 }
                 output_server_addr.show();
                 but.set_label("Stop");
             } else {
                 weylus.stop();
                 but.set_label("Start");
+                label_view_only.hide();
                 output_server_addr.hide();
                 qr_frame.resize_callback(|_, _, _, _, _| {});
                 qr_frame.hide();
+                choice_addr.hide();
                 is_server_running = false;
             }
             Ok(())
@@ -378,3 +525,109 @@ pub fn run(config: &Config, log_receiver: mpsc::Receiver<String>) {
     // this is required to drop the callback and do a graceful shutdown of the web server
     but_toggle.set_callback(|_| ());
 }
+
+/// Close all windows, which makes run return and shut down the server like closing the main
+/// window does. May be called from any thread.
+pub fn quit() {
+    awake_callback(|| {
+        while let Some(mut window) = fltk::app::first_window() {
+            window.hide();
+        }
+    });
+}
+
+fn describe_connections(connections: &[ConnectionInfo]) -> String {
+    if connections.is_empty() {
+        return "No clients connected.".into();
+    }
+    let mut text = String::new();
+    for c in connections {
+        text.push_str(&format!(
+            "#{} {}, connected {} ago, {}, {} frames sent, last active {} ago\n",
+            c.id,
+            c.address
+                .map_or_else(|| "unknown address".into(), |a| a.to_string()),
+            elapsed_since(c.connected_at_ms),
+            c.config.as_deref().unwrap_or("not configured yet"),
+            c.frames_sent,
+            elapsed_since(c.last_activity_ms)
+        ));
+    }
+    text
+}
+
+/// Labels and hosts in URLs browsers may be able to reach the server at.
+fn connection_hosts(
+    bind_addresses: &BindAddresses,
+    mdns_hostname: Option<&str>,
+) -> Vec<(String, String)> {
+    let interfaces = interface_addresses();
+    let mut hosts: Vec<(String, String)> = Vec::new();
+    let mut push = |label: String, host: String| {
+        if !hosts.iter().any(|(_, h)| *h == host) {
+            hosts.push((label, host));
+        }
+    };
+    for bind_address in &bind_addresses.0 {
+        match bind_address {
+            BindAddress::Ip(ip) if ip.is_unspecified() => {
+                for addr in interfaces
+                    .iter()
+                    .filter(|addr| !addr.loopback && addr.ip.is_ipv4() == ip.is_ipv4())
+                {
+                    push(format!("{}: {addr}", addr.interface), addr.url_host());
+                }
+            }
+            BindAddress::Ip(ip) => push(ip.to_string(), url_host(*ip)),
+            BindAddress::Interface(name) => {
+                for addr in interfaces.iter().filter(|addr| addr.interface == *name) {
+                    push(format!("{name}: {addr}"), addr.url_host());
+                }
+            }
+        }
+    }
+    if let Some(hostname) = mdns_hostname {
+        push(format!("mDNS: {hostname}"), hostname.to_string());
+    }
+    hosts
+}
+
+fn with_access_code(mut url: String, access_code: Option<&str>) -> String {
+    if let Some(access_code) = access_code {
+        url.push_str("?access_code=");
+        url.push_str(
+            &percent_encoding::utf8_percent_encode(access_code, percent_encoding::NON_ALPHANUMERIC)
+                .to_string(),
+        );
+    }
+    url
+}
+
+fn show_qr_code(qr_frame: &mut Frame, url: String) {
+    use image::Luma;
+    use qrcode::QrCode;
+
+    let cb = move |qr_frame: &mut Frame, _, _, w, h| {
+        let code = QrCode::new(&url).unwrap();
+        let img_buf = code.render::<Luma<u8>>().build();
+        let image = image::DynamicImage::ImageLuma8(img_buf);
+        let dims = min(w, h) as u32;
+        let image = image.resize_exact(dims, dims, image::imageops::FilterType::Nearest);
+        let mut buf = vec![];
+        let mut cursor = Cursor::new(&mut buf);
+        image
+            .write_to(&mut cursor, image::ImageFormat::Png)
+            .unwrap();
+        let png = PngImage::from_data(&buf).unwrap();
+        qr_frame.set_image(Some(png));
+        qr_frame.redraw();
+    };
+
+    let x = qr_frame.x();
+    let y = qr_frame.y();
+    let w = qr_frame.width();
+    let h = qr_frame.height();
+    cb(qr_frame, x, y, w, h);
+    qr_frame.resize_callback(cb);
+    qr_frame.show();
+}
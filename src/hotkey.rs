@@ -0,0 +1,381 @@
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::thread::JoinHandle;
+
+use tracing::warn;
+
+// X keysym names and Windows virtual key codes of the keys besides letters, digits and F1 to F24
+const NAMED_KEYS: &[(&str, u32)] = &[
+    ("Escape", 0x1b),
+    ("space", 0x20),
+    ("Page_Up", 0x21),
+    ("Page_Down", 0x22),
+    ("End", 0x23),
+    ("Home", 0x24),
+    ("Print", 0x2c),
+    ("Insert", 0x2d),
+    ("Delete", 0x2e),
+    ("Pause", 0x13),
+    ("Scroll_Lock", 0x91),
+];
+
+/// Key combination like "Ctrl+Alt+I", modifiers are Ctrl, Alt, Shift and Super.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyCombination {
+    ctrl: bool,
+    alt: bool,
+    shift: bool,
+    super_key: bool,
+    // X keysym name
+    key: String,
+}
+
+impl KeyCombination {
+    #[cfg(target_os = "windows")]
+    fn virtual_key(&self) -> u32 {
+        let mut chars = self.key.chars();
+        match (chars.next(), chars.next()) {
+            // virtual key codes of letters and digits are their ASCII codes
+            (Some(c), None) => c as u32,
+            _ => match self
+                .key
+                .strip_prefix('F')
+                .and_then(|n| n.parse::<u32>().ok())
+            {
+                Some(n) => 0x70 + n - 1,
+                None => NAMED_KEYS
+                    .iter()
+                    .find(|(name, _)| *name == self.key)
+                    .map_or(0, |(_, vk)| *vk),
+            },
+        }
+    }
+}
+
+impl FromStr for KeyCombination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut combination = Self {
+            ctrl: false,
+            alt: false,
+            shift: false,
+            super_key: false,
+            key: String::new(),
+        };
+        for part in s.split('+').map(str::trim) {
+            if !combination.key.is_empty() {
+                return Err(format!(
+                    "{s}: the key has to come last, after the modifiers."
+                ));
+            }
+            match part.to_lowercase().as_str() {
+                "ctrl" | "control" => combination.ctrl = true,
+                "alt" => combination.alt = true,
+                "shift" => combination.shift = true,
+                "super" | "win" | "meta" => combination.super_key = true,
+                key => {
+                    let mut chars = key.chars();
+                    combination.key = match (chars.next(), chars.next()) {
+                        (Some(c), None) if c.is_ascii_alphanumeric() => {
+                            c.to_ascii_uppercase().to_string()
+                        }
+                        _ => match key.strip_prefix('f').and_then(|n| n.parse::<u32>().ok()) {
+                            Some(n @ 1..=24) => format!("F{n}"),
+                            _ => NAMED_KEYS
+                                .iter()
+                                .find(|(name, _)| name.to_lowercase() == key)
+                                .map(|(name, _)| name.to_string())
+                                .ok_or_else(|| format!("{s}: unknown key {part}."))?,
+                        },
+                    };
+                }
+            }
+        }
+        if combination.key.is_empty() {
+            return Err(format!("{s}: a key besides the modifiers is required."));
+        }
+        Ok(combination)
+    }
+}
+
+impl fmt::Display for KeyCombination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (pressed, name) in [
+            (self.ctrl, "Ctrl"),
+            (self.alt, "Alt"),
+            (self.shift, "Shift"),
+            (self.super_key, "Super"),
+        ] {
+            if pressed {
+                write!(f, "{name}+")?;
+            }
+        }
+        write!(f, "{}", self.key)
+    }
+}
+
+/// A key combination registered with the system that calls a function whenever it is pressed, no
+/// matter which application has the focus. The key combination is unregistered once this is
+/// dropped.
+pub struct Hotkey {
+    thread: Option<JoinHandle<()>>,
+    #[cfg(target_os = "linux")]
+    stop: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    #[cfg(target_os = "windows")]
+    thread_id: u32,
+}
+
+impl Hotkey {
+    pub fn register(
+        keys: &KeyCombination,
+        on_press: impl FnMut() + Send + 'static,
+    ) -> Result<Self, Box<dyn Error>> {
+        #[cfg(any(target_os = "linux", target_os = "windows"))]
+        {
+            // the hotkey has to be registered by the thread receiving its events
+            let (sender, receiver) = std::sync::mpsc::channel();
+            let thread = platform::spawn(keys.clone(), sender, on_press);
+            match receiver.recv() {
+                Ok(Ok(state)) => Ok(Self {
+                    thread: Some(thread),
+                    #[cfg(target_os = "linux")]
+                    stop: state,
+                    #[cfg(target_os = "windows")]
+                    thread_id: state,
+                }),
+                Ok(Err(err)) => {
+                    let _ = thread.join();
+                    Err(format!("Failed to register hotkey {keys}: {err}").into())
+                }
+                Err(_) => {
+                    let _ = thread.join();
+                    Err(format!("Failed to register hotkey {keys}.").into())
+                }
+            }
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+        {
+            drop(on_press);
+            Err(format!(
+                "Failed to register hotkey {keys}: global hotkeys are not supported on this \
+                platform."
+            )
+            .into())
+        }
+    }
+}
+
+impl Drop for Hotkey {
+    fn drop(&mut self) {
+        #[cfg(target_os = "linux")]
+        self.stop.store(true, std::sync::atomic::Ordering::Relaxed);
+        #[cfg(target_os = "windows")]
+        unsafe {
+            winapi::um::winuser::PostThreadMessageW(
+                self.thread_id,
+                winapi::um::winuser::WM_QUIT,
+                0,
+                0,
+            );
+        }
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("Hotkey thread panicked.");
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::ffi::CString;
+    use std::os::raw::{c_char, c_int, c_uint, c_void};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::thread::JoinHandle;
+
+    use tracing::warn;
+
+    use super::KeyCombination;
+    use crate::cerror::CError;
+
+    extern "C" {
+        fn hotkey_grab(key: *const c_char, modifiers: c_uint, err: *mut CError) -> *mut c_void;
+        fn hotkey_wait(hotkey: *mut c_void, timeout_ms: c_int) -> c_int;
+        fn hotkey_ungrab(hotkey: *mut c_void);
+    }
+
+    // ShiftMask, ControlMask, Mod1Mask and Mod4Mask
+    const SHIFT: c_uint = 1 << 0;
+    const CONTROL: c_uint = 1 << 2;
+    const ALT: c_uint = 1 << 3;
+    const SUPER: c_uint = 1 << 6;
+
+    // how long to wait for the hotkey before checking if it should be unregistered
+    const POLL_TIMEOUT_MS: c_int = 100;
+
+    pub fn spawn(
+        keys: KeyCombination,
+        sender: mpsc::Sender<Result<Arc<AtomicBool>, String>>,
+        mut on_press: impl FnMut() + Send + 'static,
+    ) -> JoinHandle<()> {
+        std::thread::spawn(move || {
+            let modifiers = [
+                (keys.shift, SHIFT),
+                (keys.ctrl, CONTROL),
+                (keys.alt, ALT),
+                (keys.super_key, SUPER),
+            ]
+            .iter()
+            .filter(|(pressed, _)| *pressed)
+            .fold(0, |mask, (_, modifier)| mask | modifier);
+            let key = CString::new(keys.key.as_str()).unwrap();
+            let mut err = CError::new();
+            let hotkey = unsafe { hotkey_grab(key.as_ptr(), modifiers, &mut err) };
+            if err.is_err() {
+                let _ = sender.send(Err(err.to_string()));
+                return;
+            }
+            let stop = Arc::new(AtomicBool::new(false));
+            let _ = sender.send(Ok(stop.clone()));
+            while !stop.load(Ordering::Relaxed) {
+                match unsafe { hotkey_wait(hotkey, POLL_TIMEOUT_MS) } {
+                    0 => (),
+                    -1 => {
+                        warn!("Lost connection to the X server, hotkey {keys} stopped working.");
+                        break;
+                    }
+                    _ => on_press(),
+                }
+            }
+            unsafe { hotkey_ungrab(hotkey) };
+        })
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod platform {
+    use std::sync::mpsc;
+    use std::thread::JoinHandle;
+
+    use winapi::um::processthreadsapi::GetCurrentThreadId;
+    use winapi::um::winuser::{
+        GetMessageW, PeekMessageW, RegisterHotKey, UnregisterHotKey, MOD_ALT, MOD_CONTROL,
+        MOD_NOREPEAT, MOD_SHIFT, MOD_WIN, MSG, PM_NOREMOVE, WM_HOTKEY, WM_USER,
+    };
+
+    use super::KeyCombination;
+
+    const HOTKEY_ID: i32 = 1;
+
+    pub fn spawn(
+        keys: KeyCombination,
+        sender: mpsc::Sender<Result<u32, String>>,
+        mut on_press: impl FnMut() + Send + 'static,
+    ) -> JoinHandle<()> {
+        std::thread::spawn(move || unsafe {
+            let modifiers = [
+                (keys.alt, MOD_ALT),
+                (keys.ctrl, MOD_CONTROL),
+                (keys.shift, MOD_SHIFT),
+                (keys.super_key, MOD_WIN),
+            ]
+            .iter()
+            .filter(|(pressed, _)| *pressed)
+            .fold(MOD_NOREPEAT, |mask, (_, modifier)| mask | modifier);
+            let mut msg: MSG = std::mem::zeroed();
+            // make sure the thread has a message queue before anyone posts WM_QUIT to it
+            PeekMessageW(
+                &mut msg,
+                std::ptr::null_mut(),
+                WM_USER,
+                WM_USER,
+                PM_NOREMOVE,
+            );
+            if RegisterHotKey(
+                std::ptr::null_mut(),
+                HOTKEY_ID,
+                modifiers as u32,
+                keys.virtual_key(),
+            ) == 0
+            {
+                let _ = sender.send(Err(std::io::Error::last_os_error().to_string()));
+                return;
+            }
+            let _ = sender.send(Ok(GetCurrentThreadId()));
+            // returns 0 for WM_QUIT
+            while GetMessageW(&mut msg, std::ptr::null_mut(), 0, 0) > 0 {
+                if msg.message == WM_HOTKEY {
+                    on_press();
+                }
+            }
+            UnregisterHotKey(std::ptr::null_mut(), HOTKEY_ID);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_combinations_round_trip() {
+        for keys in [
+            "Ctrl+Alt+I",
+            "Shift+F12",
+            "Super+space",
+            "Ctrl+Shift+Page_Down",
+            "Alt+Super+5",
+            "Ctrl+Alt+Shift+Super+F24",
+            "Scroll_Lock",
+        ] {
+            let combination: KeyCombination = keys.parse().unwrap();
+            assert_eq!(combination.to_string(), keys);
+            assert_eq!(combination.to_string().parse(), Ok(combination));
+        }
+        // written as users may write them
+        for (keys, normalized) in [
+            (" ctrl + alt + i ", "Ctrl+Alt+I"),
+            ("control+win+f1", "Ctrl+Super+F1"),
+            ("Meta+ESCAPE", "Super+Escape"),
+            ("alt+ctrl+x", "Ctrl+Alt+X"),
+            ("Shift+page_up", "Shift+Page_Up"),
+        ] {
+            assert_eq!(
+                keys.parse::<KeyCombination>().unwrap().to_string(),
+                normalized
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_key_combinations_are_rejected() {
+        for keys in [
+            "",
+            "Ctrl",
+            "Ctrl+Alt",
+            "Ctrl+I+Alt",
+            "I+J",
+            "Ctrl+",
+            "Ctrl++",
+            "Ctrl+F0",
+            "Ctrl+F25",
+            "Ctrl+Foo",
+            "Ctrl+-",
+            "Ctrl+ä",
+            "Hyper+I",
+        ] {
+            assert!(keys.parse::<KeyCombination>().is_err(), "{keys}");
+        }
+        assert_eq!(
+            "Ctrl+I+Alt".parse::<KeyCombination>(),
+            Err("Ctrl+I+Alt: the key has to come last, after the modifiers.".to_string())
+        );
+        assert_eq!(
+            "Ctrl+Alt".parse::<KeyCombination>(),
+            Err("Ctrl+Alt: a key besides the modifiers is required.".to_string())
+        );
+    }
+}
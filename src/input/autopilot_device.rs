@@ -3,28 +3,47 @@ use autopilot::mouse;
 use autopilot::mouse::ScrollDirection;
 use autopilot::screen::size as screen_size;
 
+use std::error::Error;
+
 use tracing::warn;
 
 use crate::input::device::{InputDevice, InputDeviceType};
-use crate::protocol::{Button, KeyboardEvent, KeyboardEventType, PointerEvent, WheelEvent};
+use crate::protocol::{
+    Button, KeyboardEvent, KeyboardEventType, KeyboardLocation, PointerEvent, PointerMode,
+    WheelEvent, WHEEL_NOTCH,
+};
 
 use crate::capturable::{Capturable, Geometry};
 
 pub struct AutoPilotDevice {
     capturable: Box<dyn Capturable>,
+    // scroll distance that has not been sent yet because it is less than a full wheel click
+    wheel_acc_y: f64,
+    pressed_buttons: Button,
+    // code and key of the keys that are down
+    pressed_keys: Vec<(String, String)>,
 }
 
 impl AutoPilotDevice {
     pub fn new(capturable: Box<dyn Capturable>) -> Self {
-        Self { capturable }
+        Self {
+            capturable,
+            wheel_acc_y: 0.0,
+            pressed_buttons: Button::NONE,
+            pressed_keys: Vec::new(),
+        }
     }
 }
 
 impl InputDevice for AutoPilotDevice {
     fn send_wheel_event(&mut self, event: &WheelEvent) {
-        match event.dy {
-            1..=i32::MAX => mouse::scroll(ScrollDirection::Up, 1),
-            i32::MIN..=-1 => mouse::scroll(ScrollDirection::Down, 1),
+        let (_, dy) = event.hi_res_delta();
+        self.wheel_acc_y += dy;
+        let clicks = (self.wheel_acc_y / WHEEL_NOTCH).trunc();
+        self.wheel_acc_y -= clicks * WHEEL_NOTCH;
+        match clicks as i32 {
+            c @ 1..=i32::MAX => mouse::scroll(ScrollDirection::Up, c as u32),
+            c @ i32::MIN..=-1 => mouse::scroll(ScrollDirection::Down, c.unsigned_abs()),
             0 => {}
         }
     }
@@ -37,8 +56,8 @@ impl InputDevice for AutoPilotDevice {
             warn!("Failed to activate window, sending no input ({})", err);
             return;
         }
-        let (x_rel, y_rel, width_rel, height_rel) = match self.capturable.geometry().unwrap() {
-            Geometry::Relative(x, y, width, height) => (x, y, width, height),
+        let (x_rel, y_rel, width_rel, height_rel) = match self.capturable.geometry() {
+            Ok(Geometry::Relative(x, y, width, height, _, _)) => (x, y, width, height),
             _ => {
                 warn!("Failed to get window geometry, sending no input");
                 return;
@@ -60,28 +79,22 @@ impl InputDevice for AutoPilotDevice {
         )) {
             warn!("Could not move mouse: {}", err);
         }
-        match event.button {
-            Button::PRIMARY => {
-                mouse::toggle(mouse::Button::Left, event.buttons.contains(event.button))
-            }
-            Button::AUXILARY => {
-                mouse::toggle(mouse::Button::Middle, event.buttons.contains(event.button))
+        for button in [Button::PRIMARY, Button::AUXILARY, Button::SECONDARY] {
+            let down = event.buttons.contains(button);
+            if down != self.pressed_buttons.contains(button) {
+                self.send_mouse_button(button, down);
             }
-            Button::SECONDARY => {
-                mouse::toggle(mouse::Button::Right, event.buttons.contains(event.button))
-            }
-            _ => (),
         }
     }
 
-    fn send_keyboard_event(&mut self, event: &KeyboardEvent) {
+    fn send_keyboard_event(&mut self, event: &KeyboardEvent) -> Result<(), Box<dyn Error>> {
         use autopilot::key::{Character, Code, KeyCode};
 
         let state = match event.event_type {
             KeyboardEventType::UP => false,
             KeyboardEventType::DOWN => true,
             // autopilot doesn't handle this, so just do nothing
-            KeyboardEventType::REPEAT => return,
+            KeyboardEventType::REPEAT => return Ok(()),
         };
 
         fn map_key(code: &str) -> Option<KeyCode> {
@@ -149,11 +162,78 @@ impl InputDevice for AutoPilotDevice {
         match key {
             Some(key) => autopilot::key::toggle(&Code(key), state, &flags, 0),
             None => {
+                if event.key.is_empty() {
+                    return Err(format!("Unknown key with code: {}", event.code).into());
+                }
                 for c in event.key.chars() {
                     autopilot::key::toggle(&Character(c), state, &flags, 0);
                 }
             }
         }
+        if !state {
+            self.pressed_keys.retain(|(code, _)| *code != event.code);
+        } else if !self
+            .pressed_keys
+            .iter()
+            .any(|(code, _)| *code == event.code)
+        {
+            self.pressed_keys
+                .push((event.code.clone(), event.key.clone()));
+        }
+        Ok(())
+    }
+
+    fn send_relative_motion(&mut self, dx: i32, dy: i32) {
+        let location = mouse::location();
+        if let Err(err) = mouse::move_to(autopilot::geometry::Point::new(
+            location.x + dx as f64,
+            location.y + dy as f64,
+        )) {
+            warn!("Could not move mouse: {}", err);
+        }
+    }
+
+    fn send_mouse_button(&mut self, button: Button, pressed: bool) {
+        match button {
+            Button::PRIMARY => mouse::toggle(mouse::Button::Left, pressed),
+            Button::AUXILARY => mouse::toggle(mouse::Button::Middle, pressed),
+            Button::SECONDARY => mouse::toggle(mouse::Button::Right, pressed),
+            _ => return,
+        }
+        self.pressed_buttons.set(button, pressed);
+    }
+
+    fn set_pointer_mode(&mut self, _mode: PointerMode) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn release_all(&mut self) {
+        for (button, mouse_button) in [
+            (Button::PRIMARY, mouse::Button::Left),
+            (Button::AUXILARY, mouse::Button::Middle),
+            (Button::SECONDARY, mouse::Button::Right),
+        ] {
+            if self.pressed_buttons.contains(button) {
+                mouse::toggle(mouse_button, false);
+            }
+        }
+        self.pressed_buttons = Button::NONE;
+        for (code, key) in std::mem::take(&mut self.pressed_keys) {
+            // release the modifiers too, they are pressed along with the keys
+            let event = KeyboardEvent {
+                event_type: KeyboardEventType::UP,
+                code,
+                key,
+                location: KeyboardLocation::STANDARD,
+                alt: true,
+                ctrl: true,
+                shift: true,
+                meta: true,
+            };
+            if let Err(err) = self.send_keyboard_event(&event) {
+                warn!("Failed to release key {}: {err}", event.code);
+            }
+        }
     }
 
     fn set_capturable(&mut self, capturable: Box<dyn Capturable>) {
@@ -1,3 +1,5 @@
+use std::error::Error;
+
 use winapi::shared::minwindef::DWORD;
 use winapi::shared::windef::{HWND, POINT};
 use winapi::um::winuser::*;
@@ -7,7 +9,7 @@ use tracing::warn;
 use crate::input::autopilot_device::AutoPilotDevice;
 use crate::input::device::{InputDevice, InputDeviceType};
 use crate::protocol::{
-    Button, KeyboardEvent, PointerEvent, PointerEventType, PointerType, WheelEvent,
+    Button, KeyboardEvent, PointerEvent, PointerEventType, PointerMode, PointerType, WheelEvent,
 };
 
 use crate::capturable::{Capturable, Geometry};
@@ -18,6 +20,10 @@ pub struct WindowsInput {
     pointer_device_handle: *mut HSYNTHETICPOINTERDEVICE__,
     touch_device_handle: *mut HSYNTHETICPOINTERDEVICE__,
     multitouch_map: std::collections::HashMap<i64, POINTER_TYPE_INFO>,
+    // last input of the pen while it touches the screen
+    pen_contact: Option<POINTER_TYPE_INFO>,
+    // flags for mouse_event that release the mouse buttons that are down
+    mouse_release_flags: DWORD,
 }
 
 impl WindowsInput {
@@ -30,14 +36,26 @@ impl WindowsInput {
                 pointer_device_handle: CreateSyntheticPointerDevice(PT_PEN, 1, 1),
                 touch_device_handle: CreateSyntheticPointerDevice(PT_TOUCH, 5, 1),
                 multitouch_map: std::collections::HashMap::new(),
+                pen_contact: None,
+                mouse_release_flags: 0,
             }
         }
     }
 }
 
+// SAFETY: what keeps WindowsInput from being Send are raw pointers: the handles of the synthetic
+// pointer devices and the HANDLE and HWND fields of the POINTER_TYPE_INFOs kept for the next
+// injection. Synthetic pointer devices belong to the process, not to the thread that created them,
+// InjectSyntheticPointerInput may be called with them from any thread. The POINTER_TYPE_INFOs are
+// plain values that are only copied into injections, none of the pointers is dereferenced by
+// Weylus. WindowsInput owns all of them and only uses them through &mut self, so moving it to
+// another thread never leaves them shared between threads.
+unsafe impl Send for WindowsInput {}
+
 impl InputDevice for WindowsInput {
     fn send_wheel_event(&mut self, event: &WheelEvent) {
-        unsafe { mouse_event(MOUSEEVENTF_WHEEL, 0, 0, event.dy as DWORD, 0) };
+        let (_, dy) = event.hi_res_delta();
+        unsafe { mouse_event(MOUSEEVENTF_WHEEL, 0, 0, dy.round() as i32 as DWORD, 0) };
     }
 
     fn send_pointer_event(&mut self, event: &PointerEvent) {
@@ -45,13 +63,16 @@ impl InputDevice for WindowsInput {
             warn!("Failed to activate window, sending no input ({})", err);
             return;
         }
-        let (offset_x, offset_y, width, height, left, top) =
-            match self.capturable.geometry().unwrap() {
-                Geometry::VirtualScreen(offset_x, offset_y, width, height, left, top) => {
-                    (offset_x, offset_y, width, height, left, top)
-                }
-                _ => unreachable!(),
-            };
+        let (offset_x, offset_y, width, height, left, top) = match self.capturable.geometry() {
+            Ok(Geometry::VirtualScreen(offset_x, offset_y, width, height, left, top)) => {
+                (offset_x, offset_y, width, height, left, top)
+            }
+            Ok(_) => unreachable!(),
+            Err(err) => {
+                warn!("Failed to get window geometry, sending no input ({})", err);
+                return;
+            }
+        };
         let (x, y) = (
             (event.x * width as f64) as i32 + offset_x,
             (event.y * height as f64) as i32 + offset_y,
@@ -116,6 +137,8 @@ impl InputDevice for WindowsInput {
                         tiltY: event.tilt_y,
                     };
                     InjectSyntheticPointerInput(self.pointer_device_handle, &pointer_type_info, 1);
+                    self.pen_contact =
+                        (pointer_flags & POINTER_FLAG_INCONTACT != 0).then_some(pointer_type_info);
                 }
             }
             PointerType::Touch => {
@@ -168,36 +191,34 @@ impl InputDevice for WindowsInput {
                     (event.y * height as f64) as i32 + top,
                 );
 
-                match event.event_type {
-                    PointerEventType::DOWN => match event.buttons {
-                        Button::PRIMARY => {
-                            dw_flags |= MOUSEEVENTF_LEFTDOWN;
-                        }
-                        Button::SECONDARY => {
-                            dw_flags |= MOUSEEVENTF_RIGHTDOWN;
-                        }
-                        Button::AUXILARY => {
-                            dw_flags |= MOUSEEVENTF_MIDDLEDOWN;
-                        }
-                        _ => {}
-                    },
-                    PointerEventType::MOVE => {
-                        unsafe { SetCursorPos(screen_x, screen_y) };
-                    }
-                    PointerEventType::UP => match event.button {
-                        Button::PRIMARY => {
-                            dw_flags |= MOUSEEVENTF_LEFTUP;
-                        }
-                        Button::SECONDARY => {
-                            dw_flags |= MOUSEEVENTF_RIGHTUP;
-                        }
-                        Button::AUXILARY => {
-                            dw_flags |= MOUSEEVENTF_MIDDLEUP;
-                        }
-                        _ => {}
-                    },
-                    PointerEventType::CANCEL => {
-                        dw_flags |= MOUSEEVENTF_LEFTUP;
+                if let PointerEventType::MOVE = event.event_type {
+                    unsafe { SetCursorPos(screen_x, screen_y) };
+                }
+                let buttons = match event.event_type {
+                    PointerEventType::CANCEL => Button::NONE,
+                    _ => event.buttons,
+                };
+                for (button, down, up) in [
+                    (Button::PRIMARY, MOUSEEVENTF_LEFTDOWN, MOUSEEVENTF_LEFTUP),
+                    (
+                        Button::SECONDARY,
+                        MOUSEEVENTF_RIGHTDOWN,
+                        MOUSEEVENTF_RIGHTUP,
+                    ),
+                    (
+                        Button::AUXILARY,
+                        MOUSEEVENTF_MIDDLEDOWN,
+                        MOUSEEVENTF_MIDDLEUP,
+                    ),
+                ] {
+                    // buttons that are down are the ones that have to be released eventually
+                    let was_down = self.mouse_release_flags & up != 0;
+                    if buttons.contains(button) && !was_down {
+                        dw_flags |= down;
+                        self.mouse_release_flags |= up;
+                    } else if !buttons.contains(button) && was_down {
+                        dw_flags |= up;
+                        self.mouse_release_flags &= !up;
                     }
                 }
                 unsafe { mouse_event(dw_flags, 0 as u32, 0 as u32, 0, 0) };
@@ -206,8 +227,66 @@ impl InputDevice for WindowsInput {
         }
     }
 
-    fn send_keyboard_event(&mut self, event: &KeyboardEvent) {
-        self.autopilot_device.send_keyboard_event(event);
+    fn send_keyboard_event(&mut self, event: &KeyboardEvent) -> Result<(), Box<dyn Error>> {
+        self.autopilot_device.send_keyboard_event(event)
+    }
+
+    fn send_relative_motion(&mut self, dx: i32, dy: i32) {
+        // relative motion is subject to the pointer acceleration of Windows
+        unsafe { mouse_event(MOUSEEVENTF_MOVE, dx as DWORD, dy as DWORD, 0, 0) };
+    }
+
+    fn send_mouse_button(&mut self, button: Button, pressed: bool) {
+        let (down, up) = match button {
+            Button::PRIMARY => (MOUSEEVENTF_LEFTDOWN, MOUSEEVENTF_LEFTUP),
+            Button::SECONDARY => (MOUSEEVENTF_RIGHTDOWN, MOUSEEVENTF_RIGHTUP),
+            Button::AUXILARY => (MOUSEEVENTF_MIDDLEDOWN, MOUSEEVENTF_MIDDLEUP),
+            _ => return,
+        };
+        if pressed {
+            self.mouse_release_flags |= up;
+            unsafe { mouse_event(down, 0, 0, 0, 0) };
+        } else {
+            self.mouse_release_flags &= !up;
+            unsafe { mouse_event(up, 0, 0, 0, 0) };
+        }
+    }
+
+    fn set_pointer_mode(&mut self, _mode: PointerMode) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn release_all(&mut self) {
+        unsafe {
+            if let Some(mut pen) = self.pen_contact.take() {
+                let pointer_info = &mut pen.u.penInfo_mut().pointerInfo;
+                pointer_info.pointerFlags = POINTER_FLAG_UP;
+                pointer_info.ButtonChangeType = POINTER_CHANGE_FIRSTBUTTON_UP;
+                InjectSyntheticPointerInput(self.pointer_device_handle, &pen, 1);
+            }
+            if !self.multitouch_map.is_empty() {
+                let touches: Vec<POINTER_TYPE_INFO> = self
+                    .multitouch_map
+                    .drain()
+                    .map(|(_, mut touch)| {
+                        let pointer_info = &mut touch.u.touchInfo_mut().pointerInfo;
+                        pointer_info.pointerFlags = POINTER_FLAG_UP;
+                        pointer_info.ButtonChangeType = POINTER_CHANGE_NONE;
+                        touch
+                    })
+                    .collect();
+                InjectSyntheticPointerInput(
+                    self.touch_device_handle,
+                    touches.as_ptr(),
+                    touches.len() as u32,
+                );
+            }
+            if self.mouse_release_flags != 0 {
+                mouse_event(self.mouse_release_flags, 0, 0, 0, 0);
+                self.mouse_release_flags = 0;
+            }
+        }
+        self.autopilot_device.release_all();
     }
 
     fn set_capturable(&mut self, capturable: Box<dyn Capturable>) {
@@ -0,0 +1,181 @@
+use tracing::debug;
+
+use crate::protocol::{Button, ButtonAction, ButtonMapping, PointerType};
+
+/// Turns the buttons of mice and the stylus into the actions they are mapped to. Buttons are
+/// released the way they have been pressed, even if the mapping has changed since.
+#[derive(Default)]
+pub struct ButtonMapper {
+    mapping: Vec<ButtonMapping>,
+    // buttons that are down and what pressing them did
+    held: Vec<(PointerType, Button, ButtonAction)>,
+}
+
+impl ButtonMapper {
+    /// Returns the actions of held buttons whose mapping changed, they have to be undone as the
+    /// buttons are not going to undo them once released.
+    pub fn set_mapping(&mut self, mapping: Vec<ButtonMapping>) -> Vec<ButtonAction> {
+        if mapping == self.mapping {
+            return Vec::new();
+        }
+        debug!("Button mapping: {mapping:?}");
+        self.mapping = mapping;
+        let mut released = Vec::new();
+        let held = std::mem::take(&mut self.held);
+        for (pointer_type, button, action) in held {
+            if action == self.action(pointer_type, button) {
+                self.held.push((pointer_type, button, action));
+            } else {
+                released.push(action);
+                // still down, but does nothing until pressed again
+                self.held.push((pointer_type, button, ButtonAction::None));
+            }
+        }
+        released
+    }
+
+    /// What the button does if pressed now.
+    pub fn action(&self, pointer_type: PointerType, button: Button) -> ButtonAction {
+        let pointer_type = canonical(pointer_type);
+        self.mapping
+            .iter()
+            .find(|m| canonical(m.pointer_type) == pointer_type && m.button == button)
+            .map_or_else(
+                || default_action(pointer_type, button),
+                |m| m.action.clone(),
+            )
+    }
+
+    /// Takes the buttons of a pointer that are down now, returns the actions to undo for
+    /// released buttons followed by the ones to do for pressed buttons, actions that do nothing
+    /// are left out.
+    pub fn update(
+        &mut self,
+        pointer_type: PointerType,
+        buttons: Button,
+    ) -> Vec<(ButtonAction, bool)> {
+        let pointer_type = canonical(pointer_type);
+        let mut changes = Vec::new();
+        self.held.retain(|(held_type, button, action)| {
+            if *held_type != pointer_type || buttons.contains(*button) {
+                return true;
+            }
+            changes.push((action.clone(), false));
+            false
+        });
+        for &button in pointer_type.mappable_buttons() {
+            if !buttons.contains(button) || self.is_held(pointer_type, button) {
+                continue;
+            }
+            let action = self.action(pointer_type, button);
+            changes.push((action.clone(), true));
+            self.held.push((pointer_type, button, action));
+        }
+        changes.retain(|(action, _)| *action != ButtonAction::None);
+        changes
+    }
+
+    /// If a button of the pointer that is down has been mapped to the action.
+    pub fn holds(&self, pointer_type: PointerType, action: &ButtonAction) -> bool {
+        let pointer_type = canonical(pointer_type);
+        self.held
+            .iter()
+            .any(|(held_type, _, held)| *held_type == pointer_type && held == action)
+    }
+
+    /// Forget about all buttons that are down, returns their actions to undo them.
+    pub fn release_all(&mut self) -> Vec<ButtonAction> {
+        self.held
+            .drain(..)
+            .map(|(_, _, action)| action)
+            .filter(|action| *action != ButtonAction::None)
+            .collect()
+    }
+
+    fn is_held(&self, pointer_type: PointerType, button: Button) -> bool {
+        self.held
+            .iter()
+            .any(|(held_type, held, _)| *held_type == pointer_type && *held == button)
+    }
+}
+
+// pointers of unknown type are treated like mice
+fn canonical(pointer_type: PointerType) -> PointerType {
+    match pointer_type {
+        PointerType::Unknown => PointerType::Mouse,
+        pointer_type => pointer_type,
+    }
+}
+
+// mouse buttons click, the buttons on the barrel of the stylus do nothing unless mapped
+fn default_action(pointer_type: PointerType, button: Button) -> ButtonAction {
+    match (pointer_type, button) {
+        (PointerType::Mouse, Button::PRIMARY) => ButtonAction::LeftClick,
+        (PointerType::Mouse, Button::SECONDARY) => ButtonAction::RightClick,
+        (PointerType::Mouse, Button::AUXILARY) => ButtonAction::MiddleClick,
+        _ => ButtonAction::None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buttons_do_what_they_are_mapped_to() {
+        use ButtonAction::*;
+
+        let mut mapper = ButtonMapper::default();
+        assert_eq!(
+            mapper.update(PointerType::Mouse, Button::PRIMARY),
+            [(LeftClick, true)]
+        );
+        assert_eq!(
+            mapper.update(PointerType::Mouse, Button::NONE),
+            [(LeftClick, false)]
+        );
+        // the barrel button does nothing by default ...
+        assert!(mapper
+            .update(PointerType::Pen, Button::SECONDARY)
+            .is_empty());
+        assert!(mapper.update(PointerType::Pen, Button::NONE).is_empty());
+
+        // ... unless mapped
+        let barrel = ButtonMapping {
+            pointer_type: PointerType::Pen,
+            button: Button::SECONDARY,
+            action: MiddleClick,
+        };
+        assert!(mapper.set_mapping(vec![barrel.clone()]).is_empty());
+        assert_eq!(
+            mapper.update(PointerType::Pen, Button::PRIMARY | Button::SECONDARY),
+            [(MiddleClick, true)]
+        );
+        assert!(mapper.holds(PointerType::Pen, &MiddleClick));
+        // mice are unaffected
+        assert_eq!(
+            mapper.update(PointerType::Mouse, Button::SECONDARY),
+            [(RightClick, true)]
+        );
+
+        // changing the mapping while the button is held releases what it pressed, the button
+        // does nothing once it is actually released
+        let eraser = ButtonMapping {
+            action: Eraser,
+            ..barrel
+        };
+        assert_eq!(mapper.set_mapping(vec![eraser]), [MiddleClick]);
+        assert!(!mapper.holds(PointerType::Pen, &Eraser));
+        assert!(mapper.update(PointerType::Pen, Button::PRIMARY).is_empty());
+        assert_eq!(
+            mapper.update(PointerType::Pen, Button::SECONDARY),
+            [(Eraser, true)]
+        );
+        assert!(mapper.holds(PointerType::Pen, &Eraser));
+
+        let mut released = mapper.release_all();
+        released.sort_by_key(|action| format!("{action:?}"));
+        assert_eq!(released, [Eraser, RightClick]);
+        assert!(mapper.update(PointerType::Pen, Button::NONE).is_empty());
+    }
+}
@@ -1,17 +1,185 @@
+use std::error::Error;
+
 use crate::capturable::Capturable;
-use crate::protocol::{KeyboardEvent, PointerEvent, WheelEvent};
+use crate::protocol::{
+    Button, ButtonMapping, KeyboardEvent, PointerEvent, PointerMode, WheelEvent,
+};
 
 #[derive(PartialEq, Eq)]
 pub enum InputDeviceType {
     AutoPilotDevice,
+    PortalDevice,
     UInputDevice,
     WindowsInput,
+    X11WindowInput,
 }
 
-pub trait InputDevice {
+// Send as movements may be injected by the timer of PacedInput
+pub trait InputDevice: Send {
     fn send_wheel_event(&mut self, event: &WheelEvent);
     fn send_pointer_event(&mut self, event: &PointerEvent);
-    fn send_keyboard_event(&mut self, event: &KeyboardEvent);
+    fn send_keyboard_event(&mut self, event: &KeyboardEvent) -> Result<(), Box<dyn Error>>;
+    /// Move the cursor by the given number of pixels, used in PointerMode::Relative.
+    fn send_relative_motion(&mut self, dx: i32, dy: i32);
+    fn send_mouse_button(&mut self, button: Button, pressed: bool);
+    /// Called whenever the client is configured, nothing is pressed when the mode changes.
+    fn set_pointer_mode(&mut self, mode: PointerMode) -> Result<(), Box<dyn Error>>;
+    /// Called whenever the client is configured, see ButtonMapper. Devices that do not support
+    /// mapping buttons ignore it.
+    fn set_button_mapping(&mut self, _mapping: &[ButtonMapping]) {}
+    /// Lift all touches, the stylus, mouse buttons and keys that are still down, so nothing stays
+    /// pressed once no more events arrive.
+    fn release_all(&mut self);
     fn set_capturable(&mut self, capturable: Box<dyn Capturable>);
     fn device_type(&self) -> InputDeviceType;
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::protocol;
+    use std::sync::{Arc, Mutex};
+
+    const BUTTON_NAMES: [(protocol::Button, &str); 3] = [
+        (protocol::Button::PRIMARY, "primary"),
+        (protocol::Button::SECONDARY, "secondary"),
+        (protocol::Button::AUXILARY, "middle"),
+    ];
+
+    // logs which of the buttons, pens, touches and keys it has seen pressed are released and when
+    // it is destroyed
+    pub(crate) struct FakeInputDevice {
+        buttons: protocol::Button,
+        // pens and touches between DOWN and UP or CANCEL
+        contacts: Vec<(protocol::PointerType, i64)>,
+        keys: Vec<String>,
+        log: Arc<Mutex<Vec<String>>>,
+        // records the pointer events that reach it, if set
+        pointer_events: Option<Arc<Mutex<Vec<protocol::PointerEvent>>>>,
+        // records relative motion, mouse buttons, scrolling and keys, if set
+        inputs: Option<Arc<Mutex<Vec<String>>>>,
+    }
+
+    impl FakeInputDevice {
+        pub(crate) fn new(log: Arc<Mutex<Vec<String>>>) -> Self {
+            Self {
+                buttons: protocol::Button::NONE,
+                contacts: Vec::new(),
+                keys: Vec::new(),
+                log,
+                pointer_events: None,
+                inputs: None,
+            }
+        }
+
+        pub(crate) fn with_pointer_events(
+            mut self,
+            pointer_events: Arc<Mutex<Vec<protocol::PointerEvent>>>,
+        ) -> Self {
+            self.pointer_events = Some(pointer_events);
+            self
+        }
+
+        pub(crate) fn with_inputs(mut self, inputs: Arc<Mutex<Vec<String>>>) -> Self {
+            self.inputs = Some(inputs);
+            self
+        }
+
+        fn record(&self, input: String) {
+            if let Some(inputs) = &self.inputs {
+                inputs.lock().unwrap().push(input);
+            }
+        }
+    }
+
+    impl InputDevice for FakeInputDevice {
+        fn send_wheel_event(&mut self, event: &protocol::WheelEvent) {
+            self.record(format!(
+                "wheel {} {} {:?}",
+                event.dx, event.dy, event.delta_mode
+            ));
+        }
+
+        fn send_pointer_event(&mut self, event: &protocol::PointerEvent) {
+            use protocol::PointerEventType::*;
+
+            if let Some(pointer_events) = &self.pointer_events {
+                pointer_events.lock().unwrap().push(event.clone());
+            }
+            if event.pointer_type == protocol::PointerType::Mouse {
+                self.buttons = event.buttons;
+                return;
+            }
+            let contact = (event.pointer_type, event.pointer_id);
+            match event.event_type {
+                DOWN if !self.contacts.contains(&contact) => self.contacts.push(contact),
+                UP | CANCEL => self.contacts.retain(|c| *c != contact),
+                DOWN | MOVE => (),
+            }
+        }
+
+        fn send_keyboard_event(
+            &mut self,
+            event: &protocol::KeyboardEvent,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            self.record(format!("key {:?} {}", event.event_type, event.code));
+            match event.event_type {
+                protocol::KeyboardEventType::DOWN => self.keys.push(event.code.clone()),
+                protocol::KeyboardEventType::UP => self.keys.retain(|code| *code != event.code),
+                protocol::KeyboardEventType::REPEAT => (),
+            }
+            Ok(())
+        }
+
+        fn send_relative_motion(&mut self, dx: i32, dy: i32) {
+            self.record(format!("motion {dx} {dy}"));
+        }
+
+        fn send_mouse_button(&mut self, button: protocol::Button, pressed: bool) {
+            let name = BUTTON_NAMES
+                .iter()
+                .find(|(b, _)| *b == button)
+                .map_or("other", |(_, name)| *name);
+            self.record(format!(
+                "{} {name}",
+                if pressed { "press" } else { "release" }
+            ));
+            self.buttons.set(button, pressed);
+        }
+
+        fn set_pointer_mode(
+            &mut self,
+            _: protocol::PointerMode,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+
+        fn release_all(&mut self) {
+            let mut log = self.log.lock().unwrap();
+            for (button, name) in BUTTON_NAMES {
+                if self.buttons.contains(button) {
+                    log.push(format!("release {name}"));
+                }
+            }
+            self.buttons = protocol::Button::NONE;
+            log.extend(
+                self.contacts
+                    .drain(..)
+                    .map(|(pointer_type, id)| format!("lift {pointer_type:?} {id}")),
+            );
+            log.extend(self.keys.drain(..).map(|key| format!("release {key}")));
+        }
+
+        fn set_capturable(&mut self, _: Box<dyn Capturable>) {}
+
+        fn device_type(&self) -> InputDeviceType {
+            InputDeviceType::AutoPilotDevice
+        }
+    }
+
+    impl Drop for FakeInputDevice {
+        fn drop(&mut self) {
+            self.log.lock().unwrap().push("destroyed".into());
+        }
+    }
+}
@@ -0,0 +1,426 @@
+use tracing::{debug, warn};
+
+use crate::input::device::InputDevice;
+use crate::protocol::{
+    Button, KeyboardEvent, KeyboardEventType, KeyboardLocation, PointerButton, PointerEvent,
+    PointerEventType, PointerType, WheelDeltaMode, WheelEvent,
+};
+
+// how far the center of both fingers has to move, relative to the size of the capturable,
+// before the gesture is considered a pan
+const PAN_THRESHOLD: f64 = 0.02;
+// how much the distance between both fingers has to change before the gesture is considered
+// a pinch
+const PINCH_THRESHOLD: f64 = 0.1;
+// moving the fingers across the whole capturable scrolls this many pixels
+const PAN_PIXELS: f64 = 1000.0;
+// upper limit per event so that a fast swipe does not turn into a huge burst of scrolling
+const MAX_PAN_PIXELS: f64 = 200.0;
+// wheel notches sent per doubling of the distance between both fingers
+const ZOOM_NOTCHES_PER_DOUBLING: f64 = 4.0;
+const MAX_ZOOM_NOTCHES: f64 = 2.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum GestureKind {
+    Pan,
+    Pinch,
+}
+
+struct Gesture {
+    // undecided until the fingers moved far enough
+    kind: Option<GestureKind>,
+    start_center: (f64, f64),
+    start_distance: f64,
+    last_center: (f64, f64),
+    last_distance: f64,
+    // zooming is only done in whole notches, fractions are kept for the next event
+    zoom_notches: f64,
+}
+
+/// Turns two finger touch gestures into scroll wheel events: panning scrolls and pinching sends
+/// Ctrl + scroll wheel, which is how most applications zoom. Touches that are not part of a
+/// gesture are passed on untouched.
+#[derive(Default)]
+pub struct TouchGestures {
+    enabled: bool,
+    // touches move the cursor relatively instead of reaching the device as touches, so there is
+    // nothing to cancel and wheel events go to wherever the cursor is
+    relative: bool,
+    // last event of every finger currently touching the screen
+    touches: Vec<PointerEvent>,
+    gesture: Option<Gesture>,
+}
+
+impl TouchGestures {
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if enabled != self.enabled {
+            debug!(
+                "Touch gestures {}.",
+                if enabled { "enabled" } else { "disabled" }
+            );
+            self.enabled = enabled;
+            self.reset();
+        }
+    }
+
+    pub fn set_relative(&mut self, relative: bool) {
+        if relative != self.relative {
+            self.relative = relative;
+            self.reset();
+        }
+    }
+
+    /// Forget about all touches, used once the device has released them.
+    pub fn reset(&mut self) {
+        self.touches.clear();
+        self.gesture = None;
+    }
+
+    /// Returns true if the event is part of a gesture and must not be sent to the device as is.
+    pub fn process(&mut self, event: &PointerEvent, device: &mut dyn InputDevice) -> bool {
+        if !self.enabled || !matches!(event.pointer_type, PointerType::Touch) {
+            return false;
+        }
+        let index = self
+            .touches
+            .iter()
+            .position(|t| t.pointer_id == event.pointer_id);
+        match event.event_type {
+            PointerEventType::DOWN => {
+                match index {
+                    Some(i) => self.touches[i] = event.clone(),
+                    None => self.touches.push(event.clone()),
+                }
+                if self.gesture.is_some() {
+                    return true;
+                }
+                if self.touches.len() < 2 {
+                    return false;
+                }
+                self.start_gesture(device);
+                true
+            }
+            PointerEventType::MOVE => {
+                if let Some(i) = index {
+                    self.touches[i] = event.clone();
+                }
+                if self.gesture.is_none() {
+                    return false;
+                }
+                self.update_gesture(event, device);
+                true
+            }
+            PointerEventType::UP | PointerEventType::CANCEL => {
+                if let Some(i) = index {
+                    self.touches.remove(i);
+                }
+                if self.gesture.is_none() {
+                    return false;
+                }
+                // the gesture only ends once all fingers left the screen, otherwise the
+                // remaining finger would suddenly start dragging
+                if self.touches.is_empty() {
+                    self.gesture = None;
+                }
+                true
+            }
+        }
+    }
+
+    fn center_and_distance(&self) -> ((f64, f64), f64) {
+        let (a, b) = (&self.touches[0], &self.touches[1]);
+        let center = ((a.x + b.x) / 2.0, (a.y + b.y) / 2.0);
+        let distance = (a.x - b.x).hypot(a.y - b.y);
+        (center, distance)
+    }
+
+    fn start_gesture(&mut self, device: &mut dyn InputDevice) {
+        // the first finger has already been sent to the device and has to be released, unless it
+        // moved the cursor
+        if !self.relative {
+            for touch in &self.touches[..self.touches.len() - 1] {
+                let mut cancel = touch.clone();
+                cancel.event_type = PointerEventType::CANCEL;
+                device.send_pointer_event(&cancel);
+            }
+        }
+        let (center, distance) = self.center_and_distance();
+        self.gesture = Some(Gesture {
+            kind: None,
+            start_center: center,
+            start_distance: distance,
+            last_center: center,
+            last_distance: distance,
+            zoom_notches: 0.0,
+        });
+    }
+
+    fn update_gesture(&mut self, event: &PointerEvent, device: &mut dyn InputDevice) {
+        if self.touches.len() < 2 {
+            return;
+        }
+        let (center, distance) = self.center_and_distance();
+        let gesture = self.gesture.as_mut().unwrap();
+        if gesture.kind.is_none() {
+            let moved =
+                (center.0 - gesture.start_center.0).hypot(center.1 - gesture.start_center.1);
+            let kind = if gesture.start_distance > 0.0
+                && (distance / gesture.start_distance - 1.0).abs() > PINCH_THRESHOLD
+            {
+                GestureKind::Pinch
+            } else if moved > PAN_THRESHOLD {
+                GestureKind::Pan
+            } else {
+                return;
+            };
+            debug!("Detected touch gesture: {kind:?}");
+            gesture.kind = Some(kind);
+            gesture.last_center = center;
+            gesture.last_distance = distance;
+            if self.relative {
+                return;
+            }
+            // wheel events go to whatever is under the mouse pointer
+            device.send_pointer_event(&PointerEvent {
+                event_type: PointerEventType::MOVE,
+                pointer_id: 0,
+                timestamp: event.timestamp,
+                is_primary: true,
+                pointer_type: PointerType::Mouse,
+                button: PointerButton::None,
+                buttons: Button::NONE,
+                x: center.0,
+                y: center.1,
+                movement_x: 0,
+                movement_y: 0,
+                pressure: 0.0,
+                tilt_x: 0,
+                tilt_y: 0,
+                twist: 0,
+                width: 0.0,
+                height: 0.0,
+                is_eraser: false,
+            });
+            return;
+        }
+        match gesture.kind {
+            Some(GestureKind::Pan) => {
+                // the content follows the fingers, so moving them up scrolls down
+                let pixels =
+                    |delta: f64| (-delta * PAN_PIXELS).clamp(-MAX_PAN_PIXELS, MAX_PAN_PIXELS);
+                let dx = pixels(center.0 - gesture.last_center.0);
+                let dy = pixels(center.1 - gesture.last_center.1);
+                gesture.last_center = center;
+                if dx != 0.0 || dy != 0.0 {
+                    device.send_wheel_event(&WheelEvent {
+                        dx,
+                        dy,
+                        delta_mode: WheelDeltaMode::Pixel,
+                        timestamp: event.timestamp,
+                    });
+                }
+            }
+            Some(GestureKind::Pinch) => {
+                if gesture.last_distance <= 0.0 || distance <= 0.0 {
+                    return;
+                }
+                gesture.zoom_notches +=
+                    (distance / gesture.last_distance).log2() * ZOOM_NOTCHES_PER_DOUBLING;
+                gesture.last_distance = distance;
+                let notches = gesture.zoom_notches.trunc();
+                if notches == 0.0 {
+                    return;
+                }
+                gesture.zoom_notches -= notches;
+                // spreading the fingers zooms in, which is scrolling up
+                let wheel = WheelEvent {
+                    dx: 0.0,
+                    // browsers report 3 lines per notch
+                    dy: -notches.clamp(-MAX_ZOOM_NOTCHES, MAX_ZOOM_NOTCHES) * 3.0,
+                    delta_mode: WheelDeltaMode::Line,
+                    timestamp: event.timestamp,
+                };
+                send_ctrl(device, true);
+                device.send_wheel_event(&wheel);
+                send_ctrl(device, false);
+            }
+            None => (),
+        }
+    }
+}
+
+fn send_ctrl(device: &mut dyn InputDevice, down: bool) {
+    let event = KeyboardEvent {
+        event_type: if down {
+            KeyboardEventType::DOWN
+        } else {
+            KeyboardEventType::UP
+        },
+        code: "ControlLeft".into(),
+        key: "Control".into(),
+        location: KeyboardLocation::LEFT,
+        alt: false,
+        ctrl: down,
+        shift: false,
+        meta: false,
+    };
+    if let Err(err) = device.send_keyboard_event(&event) {
+        warn!("Failed to send Ctrl for zooming: {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::device::tests::FakeInputDevice;
+    use crate::protocol::tests::pointer_event;
+    use std::sync::{Arc, Mutex};
+
+    use PointerEventType::*;
+
+    fn touch(event_type: PointerEventType, pointer_id: i64, (x, y): (f64, f64)) -> PointerEvent {
+        let mut event = pointer_event(PointerType::Touch, event_type, pointer_id, 0);
+        (event.x, event.y) = (x, y);
+        event
+    }
+
+    struct Run {
+        // whether each event has been taken as part of a gesture
+        consumed: Vec<bool>,
+        // pointer events the gestures sent to the device themselves
+        sent: Vec<String>,
+        inputs: Vec<String>,
+    }
+
+    fn run(gestures: &mut TouchGestures, events: &[PointerEvent]) -> Run {
+        let pointer_events = Arc::new(Mutex::new(Vec::new()));
+        let inputs = Arc::new(Mutex::new(Vec::new()));
+        let mut device = FakeInputDevice::new(Arc::new(Mutex::new(Vec::new())))
+            .with_pointer_events(pointer_events.clone())
+            .with_inputs(inputs.clone());
+        let consumed = events
+            .iter()
+            .map(|event| gestures.process(event, &mut device))
+            .collect();
+        drop(device);
+        let sent = pointer_events
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| {
+                format!(
+                    "{:?} {:?} {} {} {}",
+                    e.pointer_type, e.event_type, e.pointer_id, e.x, e.y
+                )
+            })
+            .collect();
+        let inputs = Arc::try_unwrap(inputs).unwrap().into_inner().unwrap();
+        Run {
+            consumed,
+            sent,
+            inputs,
+        }
+    }
+
+    fn enabled() -> TouchGestures {
+        let mut gestures = TouchGestures::default();
+        gestures.set_enabled(true);
+        gestures
+    }
+
+    #[test]
+    fn two_fingers_scroll() {
+        let run = run(
+            &mut enabled(),
+            &[
+                touch(DOWN, 1, (0.25, 0.5)),
+                touch(DOWN, 2, (0.75, 0.5)),
+                // far enough for the gesture to be a pan, it scrolls from then on
+                touch(MOVE, 1, (0.28125, 0.53125)),
+                touch(MOVE, 2, (0.78125, 0.53125)),
+                touch(MOVE, 2, (0.78125, 0.40625)),
+                touch(UP, 1, (0.28125, 0.53125)),
+                touch(UP, 2, (0.78125, 0.40625)),
+            ],
+        );
+        assert_eq!(run.consumed, [false, true, true, true, true, true, true]);
+        // the first finger has reached the device already, wheel events go to where the mouse is
+        assert_eq!(
+            run.sent,
+            ["Touch CANCEL 1 0.25 0.5", "Mouse MOVE 0 0.515625 0.515625"]
+        );
+        // the content follows the fingers
+        assert_eq!(
+            run.inputs,
+            ["wheel -15.625 -15.625 Pixel", "wheel -0 62.5 Pixel"]
+        );
+    }
+
+    #[test]
+    fn pinching_zooms_with_ctrl_and_the_wheel() {
+        let run = run(
+            &mut enabled(),
+            &[
+                touch(DOWN, 1, (0.25, 0.5)),
+                touch(DOWN, 2, (0.75, 0.5)),
+                // a distance of 0.75, too far apart for a pan
+                touch(MOVE, 2, (1.0, 0.5)),
+                // zooms in by 1.66 notches, the fraction is kept for the next move
+                touch(MOVE, 1, (0.0, 0.5)),
+                // the fingers come back together, zooming out by 3 notches of which at most 2 are
+                // sent at once
+                touch(MOVE, 2, (0.5, 0.5)),
+                touch(UP, 2, (0.5, 0.5)),
+                touch(UP, 1, (0.0, 0.5)),
+            ],
+        );
+        assert_eq!(run.consumed, [false, true, true, true, true, true, true]);
+        assert_eq!(
+            run.sent,
+            ["Touch CANCEL 1 0.25 0.5", "Mouse MOVE 0 0.625 0.5"]
+        );
+        assert_eq!(
+            run.inputs,
+            [
+                "key DOWN ControlLeft",
+                "wheel 0 -3 Line",
+                "key UP ControlLeft",
+                "key DOWN ControlLeft",
+                "wheel 0 6 Line",
+                "key UP ControlLeft",
+            ]
+        );
+    }
+
+    #[test]
+    fn other_touches_pass_through() {
+        let mut gestures = enabled();
+        let pen = pointer_event(PointerType::Pen, DOWN, 3, 0);
+        let single = run(
+            &mut gestures,
+            &[
+                touch(DOWN, 1, (0.25, 0.5)),
+                pen.clone(),
+                touch(MOVE, 1, (0.5, 0.5)),
+                touch(UP, 1, (0.5, 0.5)),
+            ],
+        );
+        assert_eq!(single.consumed, [false; 4]);
+        assert!(single.sent.is_empty() && single.inputs.is_empty());
+
+        // nothing is taken while disabled
+        gestures.set_enabled(false);
+        let disabled = run(
+            &mut gestures,
+            &[
+                touch(DOWN, 1, (0.25, 0.5)),
+                touch(DOWN, 2, (0.75, 0.5)),
+                touch(MOVE, 2, (1.0, 0.5)),
+                touch(UP, 2, (1.0, 0.5)),
+                touch(UP, 1, (0.25, 0.5)),
+            ],
+        );
+        assert_eq!(disabled.consumed, [false; 5]);
+        assert!(disabled.sent.is_empty() && disabled.inputs.is_empty());
+    }
+}
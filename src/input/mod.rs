@@ -1,10 +1,26 @@
 pub mod autopilot_device;
+pub mod buttons;
 pub mod device;
+pub mod gesture;
+pub mod ordering;
+pub mod pacing;
+pub mod palm;
+pub mod pressure;
+pub mod queue;
+pub mod relative;
+pub mod smoothing;
+pub mod switch;
 
 #[cfg(target_os = "windows")]
 pub mod autopilot_device_win;
 #[cfg(target_os = "linux")]
+pub mod portal_device;
+#[cfg(target_os = "linux")]
 pub mod uinput_device;
 #[cfg(target_os = "linux")]
 #[allow(dead_code)]
 pub mod uinput_keys;
+#[cfg(target_os = "linux")]
+pub mod uinput_pool;
+#[cfg(target_os = "linux")]
+pub mod x11_window_device;
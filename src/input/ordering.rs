@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, trace};
+
+use crate::protocol::{PointerEvent, PointerEventType, PointerOrdering};
+
+#[derive(Default)]
+struct Pointer {
+    // the last event that has been passed on
+    last: Option<PointerEvent>,
+    // between DOWN and UP or CANCEL
+    down: bool,
+    // timestamp of the latest UP or CANCEL, even if it has been dropped
+    lifted_at: Option<u64>,
+}
+
+fn same(a: &PointerEvent, b: &PointerEvent) -> bool {
+    a.pointer_id == b.pointer_id
+        && a.timestamp == b.timestamp
+        && a.event_type == b.event_type
+        && a.x == b.x
+        && a.y == b.y
+}
+
+/// Brings pointer events back into the order the browser generated them in, messages delayed by
+/// bursts of packets or a reconnect otherwise zigzag strokes. Events that arrive too late to be
+/// put in order are dropped, unless they change whether the pointer is down: a DOWN is only
+/// dropped if the pointer has been lifted after it and an UP or CANCEL only if the pointer is not
+/// down anyway, so nothing stays pressed. Duplicates are dropped as well.
+#[derive(Default)]
+pub struct PointerOrder {
+    // events are passed on as they are if None
+    ordering: Option<PointerOrdering>,
+    pointers: HashMap<i64, Pointer>,
+    // held back for PointerOrdering::Reorder with the time they arrived at, sorted by timestamp
+    pending: Vec<(PointerEvent, Instant)>,
+}
+
+impl PointerOrder {
+    /// Returns the events that have been held back, they have to be passed on before any other.
+    pub fn set_ordering(&mut self, ordering: Option<PointerOrdering>) -> Vec<PointerEvent> {
+        if ordering == self.ordering {
+            return Vec::new();
+        }
+        debug!("Ordering pointer events: {ordering:?}");
+        self.ordering = ordering;
+        let pending: Vec<PointerEvent> = self.pending.drain(..).map(|(event, _)| event).collect();
+        for event in &pending {
+            self.passed_on(event);
+        }
+        if ordering.is_none() {
+            self.pointers.clear();
+        }
+        pending
+    }
+
+    /// Forget about all pointers and drop what is held back, used once the device has released
+    /// everything.
+    pub fn reset(&mut self) {
+        self.pointers.clear();
+        self.pending.clear();
+    }
+
+    /// When the first of the events held back is due, flush_due has to be called then if no other
+    /// event arrives until then. None if nothing is held back.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let window = self.window()?;
+        self.pending
+            .iter()
+            .map(|(_, arrived)| *arrived + window)
+            .min()
+    }
+
+    /// Events that have been held back for window_ms by now, in the order to pass them on, so a
+    /// DOWN followed by silence is not held back forever.
+    pub fn flush_due(&mut self, now: Instant) -> Vec<PointerEvent> {
+        match self.window() {
+            Some(window) => self.take_due(window, now),
+            None => Vec::new(),
+        }
+    }
+
+    fn window(&self) -> Option<Duration> {
+        match self.ordering? {
+            PointerOrdering::DropLate => None,
+            PointerOrdering::Reorder { window_ms } => Some(Duration::from_millis(window_ms.into())),
+        }
+    }
+
+    /// Events to pass on now in the order to pass them on, the event itself may come later or
+    /// not at all. Events that are held back are passed on once an event window_ms newer than
+    /// them arrives, the stroke they belong to ends or they have been held back for window_ms,
+    /// see next_deadline.
+    pub fn process(&mut self, event: PointerEvent, now: Instant) -> Vec<PointerEvent> {
+        let Some(ordering) = self.ordering else {
+            return vec![event];
+        };
+        let pointer = self.pointers.entry(event.pointer_id).or_default();
+        if pointer.last.as_ref().is_some_and(|last| same(last, &event))
+            || self
+                .pending
+                .iter()
+                .any(|(pending, _)| same(pending, &event))
+        {
+            trace!("Dropping duplicate pointer event: {event:?}");
+            return Vec::new();
+        }
+        let late = pointer.is_late(&event);
+        if matches!(
+            event.event_type,
+            PointerEventType::UP | PointerEventType::CANCEL
+        ) {
+            pointer.lifted_at = pointer.lifted_at.max(Some(event.timestamp));
+        }
+        if late {
+            trace!("Dropping pointer event that arrived too late: {event:?}");
+            return Vec::new();
+        }
+        let window = match ordering {
+            PointerOrdering::DropLate => {
+                self.passed_on(&event);
+                return vec![event];
+            }
+            PointerOrdering::Reorder { window_ms } => Duration::from_millis(window_ms.into()),
+        };
+        let index = self
+            .pending
+            .partition_point(|(pending, _)| pending.timestamp <= event.timestamp);
+        self.pending.insert(index, (event, now));
+        self.take_due(window, now)
+    }
+
+    fn take_due(&mut self, window: Duration, now: Instant) -> Vec<PointerEvent> {
+        // the events of each pointer up to the newest one that is due are passed on
+        let newest = self.pending.iter().map(|(event, _)| event.timestamp).max();
+        let mut due: HashMap<i64, u64> = HashMap::new();
+        for (event, arrived) in &self.pending {
+            let ends_stroke = matches!(
+                event.event_type,
+                PointerEventType::UP | PointerEventType::CANCEL
+            );
+            if ends_stroke
+                || newest.is_some_and(|newest| {
+                    newest.saturating_sub(event.timestamp) >= window.as_micros() as u64
+                })
+                || now.duration_since(*arrived) >= window
+            {
+                let timestamp = due.entry(event.pointer_id).or_default();
+                *timestamp = (*timestamp).max(event.timestamp);
+            }
+        }
+        let (ready, pending): (Vec<_>, Vec<_>) = self.pending.drain(..).partition(|(event, _)| {
+            due.get(&event.pointer_id)
+                .is_some_and(|timestamp| event.timestamp <= *timestamp)
+        });
+        self.pending = pending;
+        let ready: Vec<PointerEvent> = ready.into_iter().map(|(event, _)| event).collect();
+        for event in &ready {
+            self.passed_on(event);
+        }
+        ready
+    }
+
+    fn passed_on(&mut self, event: &PointerEvent) {
+        let pointer = self.pointers.entry(event.pointer_id).or_default();
+        match event.event_type {
+            PointerEventType::DOWN => pointer.down = true,
+            PointerEventType::UP | PointerEventType::CANCEL => pointer.down = false,
+            PointerEventType::MOVE => (),
+        }
+        pointer.last = Some(event.clone());
+    }
+}
+
+impl Pointer {
+    fn is_late(&self, event: &PointerEvent) -> bool {
+        if self
+            .last
+            .as_ref()
+            .is_none_or(|last| event.timestamp >= last.timestamp)
+        {
+            return false;
+        }
+        match event.event_type {
+            PointerEventType::MOVE => true,
+            PointerEventType::DOWN => self
+                .lifted_at
+                .is_some_and(|lifted_at| lifted_at > event.timestamp),
+            PointerEventType::UP | PointerEventType::CANCEL => !self.down,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol;
+    use crate::protocol::tests::pointer_event;
+
+    // a stroke of the pen with an event every 5 ms, DOWN at 0 ms and UP at 5 * (moves + 1) ms
+    fn stroke(pointer_id: i64, moves: u64) -> Vec<protocol::PointerEvent> {
+        use protocol::PointerEventType::*;
+        use protocol::PointerType::*;
+        std::iter::once(pointer_event(Pen, DOWN, pointer_id, 0))
+            .chain((1..=moves).map(|i| pointer_event(Pen, MOVE, pointer_id, 5 * i)))
+            .chain(std::iter::once(pointer_event(
+                Pen,
+                UP,
+                pointer_id,
+                5 * (moves + 1),
+            )))
+            .collect()
+    }
+
+    // feeds the events in the given order, all arriving at once, returns the timestamps in ms of
+    // those that are passed on in the order they are passed on
+    fn pointer_order(
+        order: &mut PointerOrder,
+        events: &[protocol::PointerEvent],
+        arrival: &[usize],
+    ) -> Vec<u64> {
+        let now = Instant::now();
+        arrival
+            .iter()
+            .flat_map(|i| order.process(events[*i].clone(), now))
+            .map(|event| event.timestamp / 1000)
+            .collect()
+    }
+
+    #[test]
+    fn pointer_order_sorts_shuffled_events() {
+        let events = stroke(1, 20);
+        // every event is delayed by up to 15 ms, the UP arrives last
+        let mut arrival: Vec<usize> = (0..events.len()).collect();
+        let mut seed = 7u32;
+        arrival.sort_by_cached_key(|i| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let delay = if *i == events.len() - 1 {
+                15
+            } else {
+                (seed >> 16) % 15
+            };
+            events[*i].timestamp / 1000 + delay as u64
+        });
+        assert_ne!(arrival, (0..events.len()).collect::<Vec<_>>());
+        let sorted: Vec<u64> = events.iter().map(|e| e.timestamp / 1000).collect();
+
+        // passed on as they are by default
+        let mut order = PointerOrder::default();
+        let unordered = pointer_order(&mut order, &events, &arrival);
+        assert_eq!(unordered.len(), events.len());
+        assert_ne!(unordered, sorted);
+
+        // a window of 20 ms is enough to sort everything
+        let mut order = PointerOrder::default();
+        assert!(order
+            .set_ordering(Some(protocol::PointerOrdering::Reorder { window_ms: 20 }))
+            .is_empty());
+        assert_eq!(pointer_order(&mut order, &events, &arrival), sorted);
+
+        // with a window of 5 ms events may come too late and are dropped, the rest is still in
+        // order and the pen goes down and up
+        order.reset();
+        order.set_ordering(Some(protocol::PointerOrdering::Reorder { window_ms: 5 }));
+        let passed = pointer_order(&mut order, &events, &arrival);
+        assert!(passed.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(passed.first(), Some(&0));
+        assert_eq!(passed.last(), sorted.last());
+
+        // dropping late events does not hold anything back
+        order.reset();
+        order.set_ordering(Some(protocol::PointerOrdering::DropLate));
+        let passed = pointer_order(&mut order, &events, &arrival);
+        assert!(passed.len() < events.len());
+        assert!(passed.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(passed.first(), Some(&0));
+        assert_eq!(passed.last(), sorted.last());
+
+        // pointers are ordered on their own, the UP of one does not flush the other
+        let mut order = PointerOrder::default();
+        order.set_ordering(Some(protocol::PointerOrdering::Reorder { window_ms: 100 }));
+        let mut events = stroke(1, 2);
+        events.extend(stroke(2, 2));
+        let passed: Vec<(i64, u64)> = [5, 0, 4, 1, 2, 3, 6, 7]
+            .into_iter()
+            .flat_map(|i| order.process(events[i].clone(), Instant::now()))
+            .map(|event| (event.pointer_id, event.timestamp / 1000))
+            .collect();
+        assert_eq!(
+            passed,
+            [
+                (1, 0),
+                (1, 5),
+                (1, 10),
+                (1, 15),
+                (2, 0),
+                (2, 5),
+                (2, 10),
+                (2, 15)
+            ]
+        );
+    }
+
+    #[test]
+    fn pointer_order_drops_duplicates_and_stale_events() {
+        use protocol::PointerEventType::*;
+        use protocol::PointerType::*;
+        let mut order = PointerOrder::default();
+        order.set_ordering(Some(protocol::PointerOrdering::DropLate));
+        let events = [
+            pointer_event(Pen, DOWN, 1, 0),
+            pointer_event(Pen, MOVE, 1, 10),
+            // sent twice after a reconnect
+            pointer_event(Pen, MOVE, 1, 10),
+            // moves do not go back in time
+            pointer_event(Pen, MOVE, 1, 5),
+            // coarse timers give several events the same timestamp
+            protocol::PointerEvent {
+                x: 0.6,
+                ..pointer_event(Pen, MOVE, 1, 10)
+            },
+            pointer_event(Pen, MOVE, 1, 20),
+            // the pen is lifted even if the UP is late
+            pointer_event(Pen, UP, 1, 15),
+            // a DOWN from before the pen has been lifted would leave it down
+            pointer_event(Pen, DOWN, 1, 12),
+            // neither does an UP while the pen is up
+            pointer_event(Pen, UP, 1, 13),
+            pointer_event(Pen, DOWN, 1, 30),
+        ];
+        let passed = pointer_order(&mut order, &events, &(0..events.len()).collect::<Vec<_>>());
+        assert_eq!(passed, [0, 10, 10, 20, 15, 30]);
+
+        // events held back are passed on once the ordering changes, a late DOWN of a stroke
+        // whose moves arrived first still puts the pen down
+        order.set_ordering(Some(protocol::PointerOrdering::Reorder { window_ms: 100 }));
+        let events = [
+            pointer_event(Pen, MOVE, 2, 10),
+            pointer_event(Pen, MOVE, 2, 15),
+            pointer_event(Pen, DOWN, 2, 5),
+        ];
+        assert!(pointer_order(&mut order, &events, &[0, 1]).is_empty());
+        assert_eq!(order.set_ordering(None).len(), 2);
+        order.set_ordering(Some(protocol::PointerOrdering::DropLate));
+        assert_eq!(pointer_order(&mut order, &events, &[0, 1, 2]), [10, 15, 5]);
+
+        // events held back for longer than the window are passed on with the next event
+        let mut order = PointerOrder::default();
+        order.set_ordering(Some(protocol::PointerOrdering::Reorder { window_ms: 20 }));
+        let start = Instant::now();
+        assert!(order
+            .process(pointer_event(Pen, MOVE, 1, 0), start)
+            .is_empty());
+        let passed = order.process(
+            pointer_event(Pen, MOVE, 1, 1),
+            start + std::time::Duration::from_millis(30),
+        );
+        assert_eq!(passed.iter().map(|e| e.timestamp).collect::<Vec<_>>(), [0]);
+    }
+
+    #[test]
+    fn pointer_order_passes_on_a_down_followed_by_silence() {
+        use protocol::PointerEventType::*;
+        use protocol::PointerType::*;
+        let window = Duration::from_millis(20);
+        let mut order = PointerOrder::default();
+        assert_eq!(order.next_deadline(), None);
+        order.set_ordering(Some(protocol::PointerOrdering::Reorder { window_ms: 20 }));
+        let start = Instant::now();
+        assert!(order
+            .process(pointer_event(Pen, DOWN, 1, 0), start)
+            .is_empty());
+        assert_eq!(order.next_deadline(), Some(start + window));
+        // nothing else arrives, the DOWN is due once it has been held back for the window
+        assert!(order.flush_due(start + window / 2).is_empty());
+        let passed = order.flush_due(start + window);
+        assert_eq!(passed.len(), 1);
+        assert_eq!(passed[0].event_type, DOWN);
+        assert_eq!(order.next_deadline(), None);
+        assert!(order.flush_due(start + window * 2).is_empty());
+
+        // the earliest arrival decides
+        let later = start + window * 3;
+        assert!(order
+            .process(pointer_event(Pen, MOVE, 1, 10), later)
+            .is_empty());
+        assert!(order
+            .process(pointer_event(Pen, MOVE, 1, 5), later + window / 4)
+            .is_empty());
+        assert_eq!(order.next_deadline(), Some(later + window));
+        let passed = order.flush_due(later + window);
+        assert_eq!(
+            passed
+                .iter()
+                .map(|e| e.timestamp / 1000)
+                .collect::<Vec<_>>(),
+            [5, 10]
+        );
+
+        // nothing is held back without reordering
+        order.set_ordering(Some(protocol::PointerOrdering::DropLate));
+        order.process(pointer_event(Pen, UP, 1, 15), later + window);
+        assert_eq!(order.next_deadline(), None);
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+use tracing::{debug, warn};
+
+use crate::capturable::Capturable;
+use crate::input::device::{InputDevice, InputDeviceType};
+use crate::protocol::{
+    Button, ButtonMapping, InputPacing, KeyboardEvent, PointerEvent, PointerEventType, PointerMode,
+    PointerType, WheelEvent,
+};
+
+struct State {
+    device: Box<dyn InputDevice>,
+    // movements are injected as they arrive if None
+    pacing: Option<InputPacing>,
+    // the latest movement of each pointer that is held back with the time it is due, in the
+    // order the pointers moved in
+    pending: Vec<(PointerEvent, Instant)>,
+    // when each pointer has been injected last and the buttons it had
+    injected: HashMap<i64, (Instant, Button)>,
+    stopped: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    // signaled once a movement is held back or the timer has to stop
+    timer: Condvar,
+}
+
+/// Injects the movements of each pointer at most InputPacing::rate_hz times per second instead of
+/// as often as the client sends them, tablets with a high refresh rate send 240 per second, which
+/// costs CPU on the host and makes some applications lag. Movements that arrive in between are
+/// coalesced into the latest one, which keeps its pressure and tilt, and a timer injects it once
+/// it is due. Everything else is injected right away after what is held back, so pressing and
+/// lifting a pointer is neither delayed nor reordered. The stylus is not paced unless
+/// InputPacing::pace_pen is set, drawing applications want all of its samples.
+pub struct PacedInput {
+    shared: Arc<Shared>,
+    timer: Option<JoinHandle<()>>,
+}
+
+impl PacedInput {
+    pub fn new(device: Box<dyn InputDevice>) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State::new(device)),
+                timer: Condvar::new(),
+            }),
+            timer: None,
+        }
+    }
+
+    /// Called whenever the client is configured, what is held back is injected if pacing is
+    /// turned off.
+    pub fn set_pacing(&mut self, pacing: Option<InputPacing>) {
+        let mut state = self.state();
+        if state.pacing == pacing {
+            return;
+        }
+        debug!("Pacing pointer movements: {pacing:?}");
+        state.pacing = pacing;
+        if pacing.is_none() {
+            state.flush(Instant::now());
+            state.injected.clear();
+            return;
+        }
+        drop(state);
+        if self.timer.is_none() {
+            let shared = self.shared.clone();
+            self.timer = Some(spawn(move || run_timer(shared)));
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.shared.state.lock().unwrap()
+    }
+
+    // the state after injecting what is held back, for events that have to come after it
+    fn flushed(&self) -> MutexGuard<'_, State> {
+        let mut state = self.state();
+        state.flush(Instant::now());
+        state
+    }
+}
+
+impl State {
+    fn new(device: Box<dyn InputDevice>) -> Self {
+        Self {
+            device,
+            pacing: None,
+            pending: Vec::new(),
+            injected: HashMap::new(),
+            stopped: false,
+        }
+    }
+
+    fn flush(&mut self, now: Instant) {
+        for (event, _) in std::mem::take(&mut self.pending) {
+            self.inject(&event, now);
+        }
+    }
+
+    fn inject(&mut self, event: &PointerEvent, now: Instant) {
+        match event.event_type {
+            PointerEventType::MOVE | PointerEventType::DOWN => {
+                self.injected.insert(event.pointer_id, (now, event.buttons));
+            }
+            PointerEventType::UP | PointerEventType::CANCEL => {
+                self.injected.remove(&event.pointer_id);
+            }
+        }
+        self.device.send_pointer_event(event);
+    }
+
+    // inject the movements that are due and return when the next one is
+    fn inject_due(&mut self, now: Instant) -> Option<Instant> {
+        let (due, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|(_, due)| *due <= now);
+        self.pending = pending;
+        for (event, _) in due {
+            self.inject(&event, now);
+        }
+        self.pending.iter().map(|(_, due)| *due).min()
+    }
+
+    fn send_pointer_event(&mut self, event: &PointerEvent, now: Instant, timer: &Condvar) {
+        let interval = match self.pacing {
+            Some(pacing)
+                if event.event_type == PointerEventType::MOVE
+                    && (pacing.pace_pen || event.pointer_type != PointerType::Pen) =>
+            {
+                Duration::from_secs_f64(1.0 / f64::from(pacing.rate_hz))
+            }
+            _ => {
+                self.flush(now);
+                self.inject(event, now);
+                return;
+            }
+        };
+        let pending = self
+            .pending
+            .iter()
+            .position(|(pending, _)| pending.pointer_id == event.pointer_id);
+        if let Some(index) = pending {
+            if self.pending[index].0.buttons == event.buttons {
+                let (replaced, due) = self.pending.remove(index);
+                let mut event = event.clone();
+                event.movement_x += replaced.movement_x;
+                event.movement_y += replaced.movement_y;
+                self.pending.push((event, due));
+                return;
+            }
+        }
+        let due = match self.injected.get(&event.pointer_id) {
+            // a button pressed or released while moving is not held back
+            Some((injected_at, buttons)) if pending.is_none() && *buttons == event.buttons => {
+                *injected_at + interval
+            }
+            _ => now,
+        };
+        if due <= now {
+            self.flush(now);
+            self.inject(event, now);
+        } else {
+            self.pending.push((event.clone(), due));
+            timer.notify_one();
+        }
+    }
+}
+
+fn run_timer(shared: Arc<Shared>) {
+    let mut state = shared.state.lock().unwrap();
+    while !state.stopped {
+        let now = Instant::now();
+        state = match state.inject_due(now) {
+            Some(due) => {
+                shared
+                    .timer
+                    .wait_timeout(state, due.saturating_duration_since(now))
+                    .unwrap()
+                    .0
+            }
+            None => shared.timer.wait(state).unwrap(),
+        };
+    }
+}
+
+impl InputDevice for PacedInput {
+    fn send_wheel_event(&mut self, event: &WheelEvent) {
+        self.flushed().device.send_wheel_event(event)
+    }
+
+    fn send_pointer_event(&mut self, event: &PointerEvent) {
+        self.state()
+            .send_pointer_event(event, Instant::now(), &self.shared.timer)
+    }
+
+    fn send_keyboard_event(&mut self, event: &KeyboardEvent) -> Result<(), Box<dyn Error>> {
+        self.flushed().device.send_keyboard_event(event)
+    }
+
+    fn send_relative_motion(&mut self, dx: i32, dy: i32) {
+        self.flushed().device.send_relative_motion(dx, dy)
+    }
+
+    fn send_mouse_button(&mut self, button: Button, pressed: bool) {
+        self.flushed().device.send_mouse_button(button, pressed)
+    }
+
+    fn set_pointer_mode(&mut self, mode: PointerMode) -> Result<(), Box<dyn Error>> {
+        self.flushed().device.set_pointer_mode(mode)
+    }
+
+    fn set_button_mapping(&mut self, mapping: &[ButtonMapping]) {
+        self.flushed().device.set_button_mapping(mapping)
+    }
+
+    fn release_all(&mut self) {
+        // movements that are held back would only move what is released
+        let mut state = self.state();
+        state.pending.clear();
+        state.injected.clear();
+        state.device.release_all();
+    }
+
+    fn set_capturable(&mut self, capturable: Box<dyn Capturable>) {
+        self.flushed().device.set_capturable(capturable)
+    }
+
+    fn device_type(&self) -> InputDeviceType {
+        self.state().device.device_type()
+    }
+}
+
+impl Drop for PacedInput {
+    fn drop(&mut self) {
+        self.state().stopped = true;
+        self.shared.timer.notify_one();
+        if let Some(timer) = self.timer.take() {
+            if let Err(err) = timer.join() {
+                warn!("Failed to join input pacing thread: {err:?}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::device::tests::FakeInputDevice;
+    use crate::protocol;
+    use crate::protocol::tests::pointer_event;
+
+    // a touch that moves far more often than the pacing allows is lifted right away, with its last
+    // movement injected before, the pen is not paced and what is held back is injected once due
+    #[test]
+    fn pacing_coalesces_moves_and_keeps_the_latest_sample() {
+        use protocol::PointerEventType::*;
+        use protocol::PointerType::{Mouse, Pen, Touch};
+
+        let pointer_events = Arc::new(Mutex::new(Vec::new()));
+        let device = FakeInputDevice::new(Arc::new(Mutex::new(Vec::new())))
+            .with_pointer_events(pointer_events.clone());
+        let mut state = State::new(Box::new(device));
+        state.pacing = Some(protocol::InputPacing {
+            rate_hz: 10,
+            pace_pen: false,
+        });
+        let timer = Condvar::new();
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let take = || std::mem::take(&mut *pointer_events.lock().unwrap());
+
+        state.send_pointer_event(&pointer_event(Touch, DOWN, 1, 0), at(0), &timer);
+        for t in 1..=100 {
+            let mut event = pointer_event(Touch, MOVE, 1, t);
+            event.movement_x = 1;
+            event.pressure = t as f64 / 100.0;
+            state.send_pointer_event(&event, at(t), &timer);
+        }
+        state.send_pointer_event(&pointer_event(Touch, UP, 1, 101), at(101), &timer);
+        let events = take();
+        assert_eq!(
+            events
+                .iter()
+                .map(|e| e.event_type.clone())
+                .collect::<Vec<_>>(),
+            [DOWN, MOVE, UP]
+        );
+        assert_eq!(events[1].timestamp, 100_000);
+        assert_eq!(events[1].pressure, 1.0);
+        assert_eq!(events[1].movement_x, 100);
+
+        state.send_pointer_event(&pointer_event(Pen, DOWN, 2, 200), at(200), &timer);
+        for t in 201..=220 {
+            state.send_pointer_event(&pointer_event(Pen, MOVE, 2, t), at(t), &timer);
+        }
+        assert_eq!(take().len(), 21);
+
+        // the first movement of the mouse is injected right away, the next one is due 100 ms later
+        state.send_pointer_event(&pointer_event(Mouse, MOVE, 3, 300), at(300), &timer);
+        state.send_pointer_event(&pointer_event(Mouse, MOVE, 3, 301), at(301), &timer);
+        assert_eq!(take().len(), 1);
+        assert_eq!(state.inject_due(at(350)), Some(at(400)));
+        assert!(take().is_empty());
+        assert_eq!(state.inject_due(at(400)), None);
+        assert_eq!(take()[0].timestamp, 301_000);
+
+        // moving again right after waits for the interval since the injection that was due
+        state.send_pointer_event(&pointer_event(Mouse, MOVE, 3, 410), at(410), &timer);
+        assert!(take().is_empty());
+        assert_eq!(state.inject_due(at(410)), Some(at(500)));
+    }
+}
@@ -0,0 +1,198 @@
+use std::time::Duration;
+
+use tracing::debug;
+
+use crate::protocol::{PointerEvent, PointerEventType, PointerType};
+
+/// Used if the client does not ask for a timeout of its own.
+pub const DEFAULT_PALM_REJECTION_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Drops touches while a stylus is in use as the hand holding it tends to rest on the screen.
+/// The stylus counts as in use while it touches the screen and until the timeout passed since its
+/// last event, which covers hovering too. Touches already sent to the device when the stylus shows
+/// up are cancelled, the cancellations have to take the same way to the device as other events.
+#[derive(Default)]
+pub struct PalmRejection {
+    // disabled if None
+    timeout: Option<Duration>,
+    // pens touching the screen
+    pens: Vec<i64>,
+    // timestamp of the last stylus event
+    last_pen_event: Option<u64>,
+    // last event of every touch passed on to the device that has not been lifted yet
+    touches: Vec<PointerEvent>,
+    // touches that are dropped until they are lifted, even if the stylus is gone by then
+    rejected: Vec<i64>,
+}
+
+impl PalmRejection {
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        if timeout != self.timeout {
+            debug!("Palm rejection timeout: {timeout:?}");
+            self.timeout = timeout;
+            self.reset();
+        }
+    }
+
+    /// Forget about all touches and pens, used once the device has released them.
+    pub fn reset(&mut self) {
+        self.pens.clear();
+        self.last_pen_event = None;
+        self.touches.clear();
+        self.rejected.clear();
+    }
+
+    /// Returns true if the event is a touch that must not be sent to the device. Touches that have
+    /// to be cancelled are handed to cancel.
+    pub fn process(&mut self, event: &PointerEvent, mut cancel: impl FnMut(&PointerEvent)) -> bool {
+        let Some(timeout) = self.timeout else {
+            return false;
+        };
+        match event.pointer_type {
+            PointerType::Pen => {
+                self.last_pen_event = Some(event.timestamp);
+                match event.event_type {
+                    PointerEventType::DOWN => {
+                        if !self.pens.contains(&event.pointer_id) {
+                            self.pens.push(event.pointer_id);
+                        }
+                    }
+                    PointerEventType::UP | PointerEventType::CANCEL => {
+                        self.pens.retain(|id| *id != event.pointer_id)
+                    }
+                    PointerEventType::MOVE => (),
+                }
+                self.cancel_touches(&mut cancel);
+                false
+            }
+            PointerType::Touch => {
+                let lifted = matches!(
+                    event.event_type,
+                    PointerEventType::UP | PointerEventType::CANCEL
+                );
+                if self.rejected.contains(&event.pointer_id) {
+                    if lifted {
+                        self.rejected.retain(|id| *id != event.pointer_id);
+                    }
+                    return true;
+                }
+                // touches from before the stylus showed up have been cancelled by its first event
+                if self.pen_in_use(event.timestamp, timeout) {
+                    if !lifted {
+                        self.rejected.push(event.pointer_id);
+                    }
+                    return true;
+                }
+                let index = self
+                    .touches
+                    .iter()
+                    .position(|t| t.pointer_id == event.pointer_id);
+                match (index, lifted) {
+                    (Some(i), true) => {
+                        self.touches.remove(i);
+                    }
+                    (Some(i), false) => self.touches[i] = event.clone(),
+                    (None, false) => self.touches.push(event.clone()),
+                    (None, true) => (),
+                }
+                false
+            }
+            _ => false,
+        }
+    }
+
+    fn pen_in_use(&self, timestamp: u64, timeout: Duration) -> bool {
+        !self.pens.is_empty()
+            || self.last_pen_event.is_some_and(|last| {
+                u128::from(timestamp.saturating_sub(last)) < timeout.as_micros()
+            })
+    }
+
+    fn cancel_touches(&mut self, cancel: &mut impl FnMut(&PointerEvent)) {
+        for mut touch in self.touches.drain(..) {
+            debug!(
+                "Cancelling touch {} in favour of the stylus.",
+                touch.pointer_id
+            );
+            touch.event_type = PointerEventType::CANCEL;
+            cancel(&touch);
+            self.rejected.push(touch.pointer_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol;
+    use crate::protocol::tests::pointer_event;
+
+    // feeds the events in order, returns which ones reached the device and the cancelled touches
+    fn palm_rejection(events: &[protocol::PointerEvent]) -> (Vec<bool>, Vec<i64>) {
+        let mut palm = PalmRejection::default();
+        palm.set_timeout(Some(std::time::Duration::from_millis(500)));
+        let mut cancelled = Vec::new();
+        let passed = events
+            .iter()
+            .map(|event| {
+                !palm.process(event, |cancel| {
+                    assert!(matches!(
+                        cancel.event_type,
+                        protocol::PointerEventType::CANCEL
+                    ));
+                    cancelled.push(cancel.pointer_id)
+                })
+            })
+            .collect();
+        (passed, cancelled)
+    }
+
+    #[test]
+    fn palm_rejection_pen_down_during_touch() {
+        use protocol::PointerEventType::*;
+        use protocol::PointerType::*;
+        let (passed, cancelled) = palm_rejection(&[
+            pointer_event(Touch, DOWN, 1, 0),
+            pointer_event(Touch, MOVE, 1, 10),
+            pointer_event(Pen, DOWN, 2, 20),
+            // the touch has been cancelled and stays rejected until it is lifted
+            pointer_event(Touch, MOVE, 1, 30),
+            pointer_event(Pen, UP, 2, 40),
+            pointer_event(Touch, MOVE, 1, 2000),
+            pointer_event(Touch, UP, 1, 2010),
+            // long enough after the stylus has been lifted
+            pointer_event(Touch, DOWN, 3, 2020),
+            pointer_event(Touch, UP, 3, 2030),
+        ]);
+        assert_eq!(
+            passed,
+            [true, true, true, false, true, false, false, true, true]
+        );
+        assert_eq!(cancelled, [1]);
+    }
+
+    #[test]
+    fn palm_rejection_touch_down_during_pen() {
+        use protocol::PointerEventType::*;
+        use protocol::PointerType::*;
+        let (passed, cancelled) = palm_rejection(&[
+            pointer_event(Pen, DOWN, 1, 0),
+            pointer_event(Touch, DOWN, 2, 10),
+            pointer_event(Pen, MOVE, 1, 20),
+            pointer_event(Pen, UP, 1, 30),
+            // the stylus still counts as in use while hovering or shortly after being lifted
+            pointer_event(Touch, DOWN, 3, 100),
+            pointer_event(Pen, MOVE, 1, 200),
+            pointer_event(Touch, MOVE, 2, 1000),
+            pointer_event(Touch, UP, 2, 1010),
+            pointer_event(Touch, UP, 3, 1020),
+            pointer_event(Touch, DOWN, 4, 1030),
+            pointer_event(Touch, UP, 4, 1040),
+        ]);
+        assert_eq!(
+            passed,
+            [true, false, true, true, false, true, false, false, false, true, true]
+        );
+        assert!(cancelled.is_empty());
+    }
+}
@@ -0,0 +1,568 @@
+use std::error::Error;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread::{spawn, JoinHandle};
+
+use dbus::arg::PropMap;
+use dbus::blocking::{Proxy, SyncConnection};
+use tracing::{debug, warn};
+
+use crate::capturable::pipewire::RemoteDesktopStream;
+use crate::capturable::remote_desktop_dbus::OrgFreedesktopPortalRemoteDesktop;
+use crate::capturable::Capturable;
+use crate::input::device::{InputDevice, InputDeviceType};
+use crate::input::uinput_keys::{map_key, KEY_UNKNOWN};
+use crate::protocol::{
+    Button, ButtonMapping, KeyboardEvent, KeyboardEventType, PointerEvent, PointerEventType,
+    PointerMode, PointerType, WheelEvent, WHEEL_NOTCH,
+};
+
+// device types of the RemoteDesktop portal
+const DEVICE_KEYBOARD: u32 = 1;
+const DEVICE_POINTER: u32 = 2;
+const DEVICE_TOUCHSCREEN: u32 = 4;
+
+// see linux/input-event-codes.h
+const BTN_LEFT: i32 = 0x110;
+const BTN_RIGHT: i32 = 0x111;
+const BTN_MIDDLE: i32 = 0x112;
+
+// axes of NotifyPointerAxisDiscrete
+const AXIS_VERTICAL: u32 = 0;
+const AXIS_HORIZONTAL: u32 = 1;
+
+// calls that may wait for the thread calling the portal before input is dropped
+const CALL_QUEUE_LEN: usize = 256;
+
+/// A call of one of the Notify methods of the RemoteDesktop portal.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Notify {
+    PointerMotion {
+        dx: f64,
+        dy: f64,
+    },
+    PointerMotionAbsolute {
+        stream: u32,
+        x: f64,
+        y: f64,
+    },
+    PointerButton {
+        code: i32,
+        pressed: bool,
+    },
+    PointerAxisDiscrete {
+        axis: u32,
+        steps: i32,
+    },
+    KeyboardKeycode {
+        code: i32,
+        pressed: bool,
+    },
+    TouchDown {
+        stream: u32,
+        slot: u32,
+        x: f64,
+        y: f64,
+    },
+    TouchMotion {
+        stream: u32,
+        slot: u32,
+        x: f64,
+        y: f64,
+    },
+    TouchUp {
+        slot: u32,
+    },
+}
+
+impl Notify {
+    fn what(&self) -> &'static str {
+        match self {
+            Self::PointerMotion { .. } | Self::PointerMotionAbsolute { .. } => "pointer motion",
+            Self::PointerButton { .. } => "mouse button",
+            Self::PointerAxisDiscrete { .. } => "scrolling",
+            Self::KeyboardKeycode { .. } => "key",
+            Self::TouchDown { .. } | Self::TouchMotion { .. } | Self::TouchUp { .. } => "touch",
+        }
+    }
+
+    fn call(
+        self,
+        portal: &Proxy<&SyncConnection>,
+        session: dbus::Path<'static>,
+    ) -> Result<(), dbus::Error> {
+        let options = PropMap::new();
+        match self {
+            Self::PointerMotion { dx, dy } => {
+                portal.notify_pointer_motion(session, options, dx, dy)
+            }
+            Self::PointerMotionAbsolute { stream, x, y } => {
+                portal.notify_pointer_motion_absolute(session, options, stream, x, y)
+            }
+            Self::PointerButton { code, pressed } => {
+                portal.notify_pointer_button(session, options, code, pressed as u32)
+            }
+            Self::PointerAxisDiscrete { axis, steps } => {
+                portal.notify_pointer_axis_discrete(session, options, axis, steps)
+            }
+            Self::KeyboardKeycode { code, pressed } => {
+                portal.notify_keyboard_keycode(session, options, code, pressed as u32)
+            }
+            Self::TouchDown { stream, slot, x, y } => {
+                portal.notify_touch_down(session, options, stream, slot, x, y)
+            }
+            Self::TouchMotion { stream, slot, x, y } => {
+                portal.notify_touch_motion(session, options, stream, slot, x, y)
+            }
+            Self::TouchUp { slot } => portal.notify_touch_up(session, options, slot),
+        }
+    }
+}
+
+// every call blocks until the portal answers, so they are made on a thread of their own, one after
+// another until the device is gone
+fn spawn_caller(stream: RemoteDesktopStream) -> (SyncSender<Notify>, JoinHandle<()>) {
+    let (calls, receiver) = sync_channel::<Notify>(CALL_QUEUE_LEN);
+    let caller = spawn(move || {
+        for call in receiver {
+            if let Err(err) = stream.notify(|portal, session| call.call(portal, session)) {
+                warn!(
+                    "Failed to send {} via the RemoteDesktop portal: {err}",
+                    call.what()
+                );
+            }
+        }
+    });
+    (calls, caller)
+}
+
+/// Where input goes within the portal session.
+#[derive(Debug, Clone, Copy)]
+struct StreamInfo {
+    // device types the user allowed, see RemoteDesktopStream
+    devices: u32,
+    node_id: u32,
+    // positions are sent in logical pixels of the stream
+    width: f64,
+    height: f64,
+}
+
+impl From<&RemoteDesktopStream> for StreamInfo {
+    fn from(stream: &RemoteDesktopStream) -> Self {
+        Self {
+            devices: stream.devices,
+            node_id: stream.node_id,
+            width: stream.width,
+            height: stream.height,
+        }
+    }
+}
+
+/// Input via the RemoteDesktop portal session the PipeWire capturable belongs to. Positions are
+/// sent relative to the stream in its logical pixels, so the compositor maps them onto the right
+/// output, wherever it is in the layout and whatever its scale. The portal has no notion of a
+/// stylus, pens go to the uinput stylus if there is one and otherwise move the pointer and press
+/// the primary button, without pressure or tilt.
+pub struct PortalInputDevice {
+    capturable: Box<dyn Capturable>,
+    stream: StreamInfo,
+    calls: SyncSender<Notify>,
+    // makes the calls, None if nobody does
+    caller: Option<JoinHandle<()>>,
+    stylus: Option<Box<dyn InputDevice>>,
+    // scroll distance that has not been sent yet because it is less than a full wheel click
+    wheel_acc: (f64, f64),
+    pressed_buttons: Button,
+    pressed_keys: Vec<i32>,
+    // pointer ids of the touches that are down, the index is the slot
+    touches: Vec<Option<i64>>,
+}
+
+impl PortalInputDevice {
+    /// Pens are sent to stylus if given, like the uinput device of the client.
+    pub fn new(
+        capturable: Box<dyn Capturable>,
+        stream: RemoteDesktopStream,
+        stylus: Option<Box<dyn InputDevice>>,
+    ) -> Self {
+        debug!(
+            "Sending input via the RemoteDesktop portal, devices: {}, stylus: {}.",
+            stream.devices,
+            if stylus.is_some() { "uinput" } else { "none" }
+        );
+        let info = StreamInfo::from(&stream);
+        let (calls, caller) = spawn_caller(stream);
+        let mut device = Self::with_calls(capturable, info, stylus, calls);
+        device.caller = Some(caller);
+        device
+    }
+
+    fn with_calls(
+        capturable: Box<dyn Capturable>,
+        stream: StreamInfo,
+        stylus: Option<Box<dyn InputDevice>>,
+        calls: SyncSender<Notify>,
+    ) -> Self {
+        Self {
+            capturable,
+            stream,
+            calls,
+            caller: None,
+            stylus,
+            wheel_acc: (0.0, 0.0),
+            pressed_buttons: Button::NONE,
+            pressed_keys: Vec::new(),
+            touches: Vec::new(),
+        }
+    }
+
+    fn notify(&self, call: Notify) {
+        if let Err(err) = self.calls.send(call) {
+            warn!(
+                "Failed to send {} via the RemoteDesktop portal: {err}",
+                call.what()
+            );
+        }
+    }
+
+    // position in logical pixels of the stream
+    fn stream_position(&self, event: &PointerEvent) -> (f64, f64) {
+        (event.x * self.stream.width, event.y * self.stream.height)
+    }
+
+    fn send_touch_event(&mut self, event: &PointerEvent) {
+        let stream = self.stream.node_id;
+        let (x, y) = self.stream_position(event);
+        let slot = self
+            .touches
+            .iter()
+            .position(|id| *id == Some(event.pointer_id));
+        match (&event.event_type, slot) {
+            (PointerEventType::DOWN, None) => {
+                let slot = match self.touches.iter().position(Option::is_none) {
+                    Some(slot) => slot,
+                    None => {
+                        self.touches.push(None);
+                        self.touches.len() - 1
+                    }
+                };
+                self.touches[slot] = Some(event.pointer_id);
+                self.notify(Notify::TouchDown {
+                    stream,
+                    slot: slot as u32,
+                    x,
+                    y,
+                });
+            }
+            (PointerEventType::MOVE, Some(slot)) => self.notify(Notify::TouchMotion {
+                stream,
+                slot: slot as u32,
+                x,
+                y,
+            }),
+            (PointerEventType::UP | PointerEventType::CANCEL, Some(slot)) => {
+                self.touches[slot] = None;
+                self.notify(Notify::TouchUp { slot: slot as u32 });
+            }
+            _ => (),
+        }
+    }
+}
+
+fn button_code(button: Button) -> Option<i32> {
+    match button {
+        Button::PRIMARY => Some(BTN_LEFT),
+        Button::AUXILARY => Some(BTN_MIDDLE),
+        Button::SECONDARY => Some(BTN_RIGHT),
+        _ => None,
+    }
+}
+
+impl InputDevice for PortalInputDevice {
+    fn send_wheel_event(&mut self, event: &WheelEvent) {
+        if self.stream.devices & DEVICE_POINTER == 0 {
+            return;
+        }
+        let (dx, dy) = event.hi_res_delta();
+        self.wheel_acc.0 += dx;
+        self.wheel_acc.1 += dy;
+        let clicks_x = (self.wheel_acc.0 / WHEEL_NOTCH).trunc();
+        let clicks_y = (self.wheel_acc.1 / WHEEL_NOTCH).trunc();
+        self.wheel_acc.0 -= clicks_x * WHEEL_NOTCH;
+        self.wheel_acc.1 -= clicks_y * WHEEL_NOTCH;
+        // positive steps scroll down, as opposed to the wheel of a mouse
+        for (axis, steps) in [
+            (AXIS_VERTICAL, -clicks_y as i32),
+            (AXIS_HORIZONTAL, clicks_x as i32),
+        ] {
+            if steps != 0 {
+                self.notify(Notify::PointerAxisDiscrete { axis, steps });
+            }
+        }
+    }
+
+    fn send_pointer_event(&mut self, event: &PointerEvent) {
+        if event.pointer_type == PointerType::Pen {
+            if let Some(stylus) = &mut self.stylus {
+                stylus.send_pointer_event(event);
+                return;
+            }
+        }
+        if let Err(err) = self.capturable.before_input() {
+            warn!("Failed to activate window, sending no input ({})", err);
+            return;
+        }
+        if event.pointer_type == PointerType::Touch && self.stream.devices & DEVICE_TOUCHSCREEN != 0
+        {
+            self.send_touch_event(event);
+            return;
+        }
+        if !event.is_primary || self.stream.devices & DEVICE_POINTER == 0 {
+            return;
+        }
+        let (x, y) = self.stream_position(event);
+        self.notify(Notify::PointerMotionAbsolute {
+            stream: self.stream.node_id,
+            x,
+            y,
+        });
+        for button in [Button::PRIMARY, Button::AUXILARY, Button::SECONDARY] {
+            let down = event.buttons.contains(button);
+            if down != self.pressed_buttons.contains(button) {
+                self.send_mouse_button(button, down);
+            }
+        }
+    }
+
+    fn send_keyboard_event(&mut self, event: &KeyboardEvent) -> Result<(), Box<dyn Error>> {
+        if self.stream.devices & DEVICE_KEYBOARD == 0 {
+            return Ok(());
+        }
+        if let Err(err) = self.capturable.before_input() {
+            warn!("Failed to activate window, sending no input ({})", err);
+            return Ok(());
+        }
+        let key_code = map_key(&event.code, &event.location);
+        if key_code == KEY_UNKNOWN {
+            return Err(format!("Unknown key with code: {}", event.code).into());
+        }
+        let pressed = match event.event_type {
+            KeyboardEventType::UP => false,
+            KeyboardEventType::DOWN => true,
+            // the compositor repeats keys that are held down on its own
+            KeyboardEventType::REPEAT => return Ok(()),
+        };
+        self.notify(Notify::KeyboardKeycode {
+            code: key_code,
+            pressed,
+        });
+        if !pressed {
+            self.pressed_keys.retain(|key| *key != key_code);
+        } else if !self.pressed_keys.contains(&key_code) {
+            self.pressed_keys.push(key_code);
+        }
+        Ok(())
+    }
+
+    fn send_relative_motion(&mut self, dx: i32, dy: i32) {
+        if self.stream.devices & DEVICE_POINTER == 0 {
+            return;
+        }
+        self.notify(Notify::PointerMotion {
+            dx: dx as f64,
+            dy: dy as f64,
+        });
+    }
+
+    fn send_mouse_button(&mut self, button: Button, pressed: bool) {
+        let Some(code) = button_code(button) else {
+            return;
+        };
+        if self.stream.devices & DEVICE_POINTER == 0 {
+            return;
+        }
+        self.notify(Notify::PointerButton { code, pressed });
+        self.pressed_buttons.set(button, pressed);
+    }
+
+    fn set_pointer_mode(&mut self, _mode: PointerMode) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn set_button_mapping(&mut self, mapping: &[ButtonMapping]) {
+        if let Some(stylus) = &mut self.stylus {
+            stylus.set_button_mapping(mapping);
+        }
+    }
+
+    fn release_all(&mut self) {
+        for button in [Button::PRIMARY, Button::AUXILARY, Button::SECONDARY] {
+            if self.pressed_buttons.contains(button) {
+                self.send_mouse_button(button, false);
+            }
+        }
+        for (slot, id) in std::mem::take(&mut self.touches).into_iter().enumerate() {
+            if id.is_some() {
+                self.notify(Notify::TouchUp { slot: slot as u32 });
+            }
+        }
+        for code in std::mem::take(&mut self.pressed_keys) {
+            self.notify(Notify::KeyboardKeycode {
+                code,
+                pressed: false,
+            });
+        }
+        if let Some(stylus) = &mut self.stylus {
+            stylus.release_all();
+        }
+    }
+
+    fn set_capturable(&mut self, capturable: Box<dyn Capturable>) {
+        if let Some(stream) = capturable.remote_desktop() {
+            self.stream = StreamInfo::from(&stream);
+            let (calls, caller) = spawn_caller(stream);
+            // what has been sent via the old session is sent before anything else
+            drop(std::mem::replace(&mut self.calls, calls));
+            if let Some(caller) = std::mem::replace(&mut self.caller, Some(caller)) {
+                caller.join().ok();
+            }
+        }
+        if let Some(stylus) = &mut self.stylus {
+            stylus.set_capturable(capturable.clone());
+        }
+        self.capturable = capturable;
+    }
+
+    fn device_type(&self) -> InputDeviceType {
+        InputDeviceType::PortalDevice
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::device::tests::FakeInputDevice;
+    use crate::protocol::tests::pointer_event;
+    use std::sync::mpsc::Receiver;
+    use std::sync::{Arc, Mutex};
+
+    const NODE_ID: u32 = 42;
+
+    // a stream of 1280x800 logical pixels, the calls end up in the returned receiver
+    fn portal_device(
+        stylus: Option<Box<dyn InputDevice>>,
+    ) -> (PortalInputDevice, Receiver<Notify>) {
+        let (calls, receiver) = sync_channel(CALL_QUEUE_LEN);
+        let device = PortalInputDevice::with_calls(
+            Box::new(crate::capturable::testsrc::TestCapturable {
+                width: 2560,
+                height: 1600,
+            }),
+            StreamInfo {
+                devices: DEVICE_KEYBOARD | DEVICE_POINTER | DEVICE_TOUCHSCREEN,
+                node_id: NODE_ID,
+                width: 1280.0,
+                height: 800.0,
+            },
+            stylus,
+            calls,
+        );
+        (device, receiver)
+    }
+
+    fn event_at(
+        pointer_type: PointerType,
+        event_type: PointerEventType,
+        id: i64,
+        (x, y): (f64, f64),
+    ) -> PointerEvent {
+        let mut event = pointer_event(pointer_type, event_type, id, 0);
+        event.x = x;
+        event.y = y;
+        if event.event_type == PointerEventType::DOWN {
+            event.buttons = Button::PRIMARY;
+        }
+        event
+    }
+
+    // positions are in logical pixels of the stream, not in those of the capturable
+    #[test]
+    fn positions_are_logical_pixels_of_the_stream() {
+        use PointerEventType::*;
+        use PointerType::*;
+
+        let (mut device, calls) = portal_device(None);
+        device.send_pointer_event(&event_at(Mouse, MOVE, 1, (0.5, 0.25)));
+        device.send_pointer_event(&event_at(Mouse, MOVE, 1, (1.0, 1.0)));
+        device.send_pointer_event(&event_at(Touch, DOWN, 2, (0.0, 0.75)));
+        device.send_pointer_event(&event_at(Touch, DOWN, 3, (0.125, 0.5)));
+        device.send_pointer_event(&event_at(Touch, MOVE, 3, (0.25, 0.5)));
+        assert_eq!(
+            calls.try_iter().collect::<Vec<_>>(),
+            [
+                Notify::PointerMotionAbsolute {
+                    stream: NODE_ID,
+                    x: 640.0,
+                    y: 200.0
+                },
+                Notify::PointerMotionAbsolute {
+                    stream: NODE_ID,
+                    x: 1280.0,
+                    y: 800.0
+                },
+                Notify::TouchDown {
+                    stream: NODE_ID,
+                    slot: 0,
+                    x: 0.0,
+                    y: 600.0
+                },
+                Notify::TouchDown {
+                    stream: NODE_ID,
+                    slot: 1,
+                    x: 160.0,
+                    y: 400.0
+                },
+                Notify::TouchMotion {
+                    stream: NODE_ID,
+                    slot: 1,
+                    x: 320.0,
+                    y: 400.0
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn pens_go_to_the_uinput_stylus_if_there_is_one() {
+        use PointerEventType::*;
+        use PointerType::Pen;
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let pointer_events = Arc::new(Mutex::new(Vec::new()));
+        let stylus = FakeInputDevice::new(log.clone()).with_pointer_events(pointer_events.clone());
+        let (mut device, calls) = portal_device(Some(Box::new(stylus)));
+        device.send_pointer_event(&event_at(Pen, DOWN, 1, (0.5, 0.5)));
+        device.send_pointer_event(&event_at(Pen, MOVE, 1, (0.75, 0.5)));
+        assert_eq!(calls.try_iter().count(), 0);
+        assert_eq!(pointer_events.lock().unwrap().len(), 2);
+        device.release_all();
+        assert_eq!(*log.lock().unwrap(), ["lift Pen 1"]);
+
+        // without uinput the pen is a mouse
+        let (mut device, calls) = portal_device(None);
+        device.send_pointer_event(&event_at(Pen, DOWN, 1, (0.5, 0.5)));
+        assert_eq!(
+            calls.try_iter().collect::<Vec<_>>(),
+            [
+                Notify::PointerMotionAbsolute {
+                    stream: NODE_ID,
+                    x: 640.0,
+                    y: 400.0
+                },
+                Notify::PointerButton {
+                    code: BTN_LEFT,
+                    pressed: true
+                },
+            ]
+        );
+    }
+}
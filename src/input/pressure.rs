@@ -0,0 +1,95 @@
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+
+use crate::protocol::{PointerEvent, PointerType, PressureCurve};
+
+// minimum time between two log messages about the observed pressure range
+const PRESSURE_LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Applies the pressure curve requested by the client to stylus events.
+#[derive(Default)]
+pub struct PressureMapping {
+    curve: Option<PressureCurve>,
+    // smallest and largest pressure reported by the stylus, useful for calibrating the curve
+    observed: Option<(f64, f64)>,
+    observed_changed: bool,
+    last_log: Option<Instant>,
+}
+
+impl PressureMapping {
+    pub fn set_curve(&mut self, curve: Option<PressureCurve>) {
+        let curve = match curve {
+            Some(PressureCurve::Gamma(gamma)) if !(gamma.is_finite() && gamma > 0.0) => {
+                debug!("Ignoring invalid pressure curve gamma: {gamma}");
+                None
+            }
+            Some(PressureCurve::Points(mut points)) => {
+                points.retain(|(x, y)| x.is_finite() && y.is_finite());
+                for (x, y) in points.iter_mut() {
+                    *x = x.clamp(0.0, 1.0);
+                    *y = y.clamp(0.0, 1.0);
+                }
+                points.sort_by(|a, b| a.0.total_cmp(&b.0));
+                Some(PressureCurve::Points(points))
+            }
+            curve => curve,
+        };
+        if curve != self.curve {
+            debug!("Using pressure curve: {curve:?}");
+            self.curve = curve;
+        }
+    }
+
+    /// Map the pressure of stylus events, other events and a hovering stylus, that is pressure 0,
+    /// are left untouched.
+    pub fn apply(&mut self, event: &mut PointerEvent) {
+        if !matches!(event.pointer_type, PointerType::Pen) || event.pressure <= 0.0 {
+            return;
+        }
+        let pressure = event.pressure.min(1.0);
+        self.observe(pressure);
+        let Some(curve) = &self.curve else {
+            return;
+        };
+        let mapped = match curve {
+            PressureCurve::Gamma(gamma) => pressure.powf(*gamma),
+            PressureCurve::Points(points) => interpolate(points, pressure),
+        };
+        // a stylus touching the screen must not turn into a hovering one
+        event.pressure = mapped.clamp(f64::MIN_POSITIVE, 1.0);
+    }
+
+    fn observe(&mut self, pressure: f64) {
+        let (min, max) = self.observed.unwrap_or((pressure, pressure));
+        if self.observed.is_none() || pressure < min || pressure > max {
+            self.observed = Some((min.min(pressure), max.max(pressure)));
+            self.observed_changed = true;
+        }
+        if self.observed_changed
+            && !self
+                .last_log
+                .is_some_and(|last_log| last_log.elapsed() < PRESSURE_LOG_INTERVAL)
+        {
+            let (min, max) = self.observed.unwrap();
+            debug!("Observed stylus pressure ranges from {min:.3} to {max:.3}.");
+            self.observed_changed = false;
+            self.last_log = Some(Instant::now());
+        }
+    }
+}
+
+// points have to be sorted by their input pressure
+fn interpolate(points: &[(f64, f64)], pressure: f64) -> f64 {
+    let mut prev = (0.0, 0.0);
+    for &(x, y) in points.iter().chain(std::iter::once(&(1.0, 1.0))) {
+        if pressure <= x {
+            if x <= prev.0 {
+                return y;
+            }
+            return prev.1 + (pressure - prev.0) / (x - prev.0) * (y - prev.1);
+        }
+        prev = (x, y);
+    }
+    prev.1
+}
@@ -0,0 +1,234 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc::error::{SendError, TrySendError};
+use tokio::sync::Notify;
+
+use crate::protocol::{MessageInbound, PointerEvent, PointerEventType};
+
+struct State {
+    queue: VecDeque<MessageInbound>,
+    sender_alive: bool,
+    receiver_alive: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    capacity: usize,
+    // signaled once there is something to receive or the sender is gone
+    received: Condvar,
+    // signaled once there is space or the receiver is gone
+    space: Notify,
+    coalesced: Arc<AtomicU32>,
+}
+
+/// Queue between the websocket and the handler injecting the input of its client, see [`channel`].
+pub struct InboundSender {
+    shared: Arc<Shared>,
+}
+
+pub struct InboundReceiver {
+    shared: Arc<Shared>,
+}
+
+/// A queue holding at most capacity messages. Once it is full the handler is behind and a
+/// movement of a pointer replaces the movement of the same pointer that is still queued instead
+/// of waiting, each one counted in coalesced, so a burst of movements does not delay the input
+/// after it. The replaced movement is added to the newer one, so relative movements add up. All
+/// other messages, including when a pointer is put down or lifted, wait for space and are never
+/// dropped.
+pub fn channel(capacity: usize, coalesced: Arc<AtomicU32>) -> (InboundSender, InboundReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queue: VecDeque::with_capacity(capacity),
+            sender_alive: true,
+            receiver_alive: true,
+        }),
+        capacity: capacity.max(1),
+        received: Condvar::new(),
+        space: Notify::new(),
+        coalesced,
+    });
+    (
+        InboundSender {
+            shared: shared.clone(),
+        },
+        InboundReceiver { shared },
+    )
+}
+
+// a movement that may be replaced by event, it may not if buttons changed in between
+fn coalescable(queued: &MessageInbound, event: &PointerEvent) -> bool {
+    matches!(queued, MessageInbound::PointerEvent(queued)
+        if queued.event_type == PointerEventType::MOVE && queued.buttons == event.buttons)
+}
+
+impl InboundSender {
+    /// Queue msg, waiting for space if it can not be coalesced. Fails if the receiver is gone.
+    pub async fn send(&self, msg: MessageInbound) -> Result<(), SendError<MessageInbound>> {
+        let mut msg = msg;
+        loop {
+            // registered before trying so space made in between is not missed
+            let space = self.shared.space.notified();
+            tokio::pin!(space);
+            space.as_mut().enable();
+            match self.try_send(msg) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(full)) => msg = full,
+                Err(TrySendError::Closed(closed)) => return Err(SendError(closed)),
+            }
+            space.await;
+        }
+    }
+
+    fn try_send(&self, mut msg: MessageInbound) -> Result<(), TrySendError<MessageInbound>> {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.receiver_alive {
+            return Err(TrySendError::Closed(msg));
+        }
+        if state.queue.len() >= self.shared.capacity {
+            let MessageInbound::PointerEvent(event) = &mut msg else {
+                return Err(TrySendError::Full(msg));
+            };
+            if event.event_type != PointerEventType::MOVE {
+                return Err(TrySendError::Full(msg));
+            }
+            let latest = state.queue.iter().rposition(|queued| {
+                matches!(queued, MessageInbound::PointerEvent(queued)
+                    if queued.pointer_id == event.pointer_id)
+            });
+            let Some(index) = latest.filter(|index| coalescable(&state.queue[*index], event))
+            else {
+                return Err(TrySendError::Full(msg));
+            };
+            if let Some(MessageInbound::PointerEvent(replaced)) = state.queue.remove(index) {
+                event.movement_x += replaced.movement_x;
+                event.movement_y += replaced.movement_y;
+            }
+            self.shared.coalesced.fetch_add(1, Ordering::Relaxed);
+        }
+        state.queue.push_back(msg);
+        self.shared.received.notify_one();
+        Ok(())
+    }
+}
+
+impl Drop for InboundSender {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().sender_alive = false;
+        self.shared.received.notify_all();
+    }
+}
+
+impl InboundReceiver {
+    /// The next message, blocking until there is one. None once the sender is gone and everything
+    /// it sent has been received.
+    pub fn recv_blocking(&self) -> Option<MessageInbound> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(msg) = state.queue.pop_front() {
+                drop(state);
+                self.shared.space.notify_one();
+                return Some(msg);
+            }
+            if !state.sender_alive {
+                return None;
+            }
+            state = self.shared.received.wait(state).unwrap();
+        }
+    }
+
+    /// Like recv_blocking but waiting for at most timeout.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<MessageInbound, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(msg) = state.queue.pop_front() {
+                drop(state);
+                self.shared.space.notify_one();
+                return Ok(msg);
+            }
+            if !state.sender_alive {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            state = self.shared.received.wait_timeout(state, timeout).unwrap().0;
+        }
+    }
+}
+
+impl Drop for InboundReceiver {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().receiver_alive = false;
+        self.shared.space.notify_waiters();
+        self.shared.space.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol;
+    use crate::protocol::tests::pointer_event;
+
+    // a burst of movements arrives while the handler is busy, the pen must be lifted afterwards
+    // anyway and the position it is lifted at must not lag behind
+    #[test]
+    fn inbound_queue_coalesces_moves_but_keeps_up() {
+        use protocol::MessageInbound::PointerEvent;
+        use protocol::PointerEventType::*;
+        use protocol::PointerType::Pen;
+
+        const MOVES: u64 = 10_000;
+        let coalesced = Arc::new(AtomicU32::new(0));
+        let (sender, receiver) = channel(32, coalesced.clone());
+        let (moves_sent, wait_for_moves) = std::sync::mpsc::channel();
+        let send = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                sender
+                    .send(PointerEvent(pointer_event(Pen, DOWN, 1, 0)))
+                    .await
+                    .unwrap();
+                for t in 1..=MOVES {
+                    let mut event = pointer_event(Pen, MOVE, 1, t);
+                    event.movement_x = 1;
+                    sender.send(PointerEvent(event)).await.unwrap();
+                }
+                moves_sent.send(()).unwrap();
+                // the queue is full, this has to wait for the handler
+                sender
+                    .send(PointerEvent(pointer_event(Pen, UP, 1, MOVES + 1)))
+                    .await
+                    .unwrap();
+            });
+        });
+        wait_for_moves.recv().unwrap();
+        let events: Vec<protocol::PointerEvent> = std::iter::from_fn(|| receiver.recv_blocking())
+            .map(|msg| match msg {
+                PointerEvent(event) => event,
+                msg => panic!("unexpected message: {msg:?}"),
+            })
+            .collect();
+        send.join().unwrap();
+
+        assert_eq!(events.len(), 33);
+        assert_eq!(events[0].event_type, DOWN);
+        assert_eq!(events[32].event_type, UP);
+        assert_eq!(events[31].timestamp, MOVES * 1000);
+        assert!(events[1..32].iter().all(|event| event.event_type == MOVE));
+        assert_eq!(
+            events.iter().map(|event| event.movement_x).sum::<i64>(),
+            MOVES as i64
+        );
+        assert_eq!(coalesced.load(Ordering::Relaxed), MOVES as u32 - 31);
+    }
+}
@@ -0,0 +1,311 @@
+use tracing::debug;
+
+use crate::input::device::InputDevice;
+use crate::protocol::{Button, PointerEvent, PointerEventType, PointerMode, PointerType};
+
+// a touch is a tap if all fingers are lifted within this many microseconds of the first one
+// touching the screen ...
+const TAP_TIMEOUT_US: u64 = 200_000;
+// ... and they moved less than this many pixels in total
+const TAP_MAX_MOVEMENT: f64 = 10.0;
+
+struct Tap {
+    start: u64,
+    // most fingers on the screen at once, tapping with two fingers right clicks and with three
+    // middle clicks
+    fingers: usize,
+    movement: f64,
+}
+
+/// Moves the cursor by how far pointers moved instead of to where they point, which makes the
+/// client act like a large touchpad. A single finger on the screen moves the cursor and tapping
+/// clicks, touches are left to TouchGestures as well for scrolling with two fingers, mice and the
+/// stylus move it and press buttons like a mouse. The stylus stays absolute unless configured
+/// otherwise. Acceleration is left to the host, which applies its own to relative motion.
+pub struct RelativePointer {
+    mode: PointerMode,
+    sensitivity: f64,
+    stylus: bool,
+    // fractions of pixels that have not been sent yet
+    remainder: (f64, f64),
+    // fingers touching the screen
+    touches: Vec<i64>,
+    tap: Option<Tap>,
+    // buttons pressed by mice and the stylus
+    buttons: Button,
+}
+
+impl Default for RelativePointer {
+    fn default() -> Self {
+        Self {
+            mode: PointerMode::Absolute,
+            sensitivity: 1.0,
+            stylus: false,
+            remainder: (0.0, 0.0),
+            touches: Vec::new(),
+            tap: None,
+            buttons: Button::NONE,
+        }
+    }
+}
+
+impl RelativePointer {
+    /// Returns true if pointers are handled differently from now on. Whatever is pressed has to
+    /// be released in that case, as it has been pressed the other way.
+    pub fn configure(&mut self, mode: PointerMode, sensitivity: f64, stylus: bool) -> bool {
+        self.sensitivity = sensitivity;
+        if mode == self.mode && stylus == self.stylus {
+            return false;
+        }
+        debug!("Pointer mode: {mode:?}, relative stylus: {stylus}");
+        self.mode = mode;
+        self.stylus = stylus;
+        self.reset();
+        true
+    }
+
+    /// Forget about all touches and buttons, used once the device has released them.
+    pub fn reset(&mut self) {
+        self.remainder = (0.0, 0.0);
+        self.touches.clear();
+        self.tap = None;
+        self.buttons = Button::NONE;
+    }
+
+    /// Returns true if the event has been turned into relative motion and must not be sent to
+    /// the device as is.
+    pub fn process(&mut self, event: &PointerEvent, device: &mut dyn InputDevice) -> bool {
+        if self.mode != PointerMode::Relative {
+            return false;
+        }
+        match event.pointer_type {
+            PointerType::Touch => self.process_touch(event, device),
+            PointerType::Pen if !self.stylus => return false,
+            _ => {
+                self.move_by(event, device);
+                let buttons = match event.event_type {
+                    PointerEventType::CANCEL => Button::NONE,
+                    _ => event.buttons,
+                };
+                for button in [Button::PRIMARY, Button::SECONDARY, Button::AUXILARY] {
+                    let down = buttons.contains(button);
+                    if down != self.buttons.contains(button) {
+                        device.send_mouse_button(button, down);
+                    }
+                }
+                self.buttons = buttons;
+            }
+        }
+        true
+    }
+
+    fn process_touch(&mut self, event: &PointerEvent, device: &mut dyn InputDevice) {
+        match event.event_type {
+            PointerEventType::DOWN => {
+                if !self.touches.contains(&event.pointer_id) {
+                    self.touches.push(event.pointer_id);
+                }
+                let tap = self.tap.get_or_insert(Tap {
+                    start: event.timestamp,
+                    fingers: 0,
+                    movement: 0.0,
+                });
+                tap.fingers = tap.fingers.max(self.touches.len());
+            }
+            PointerEventType::MOVE => {
+                // touches that went down while rejected by palm rejection show up without DOWN,
+                // they may move the cursor but never tap
+                if !self.touches.contains(&event.pointer_id) {
+                    self.touches.push(event.pointer_id);
+                    self.tap = None;
+                }
+                if let Some(tap) = &mut self.tap {
+                    tap.movement += (event.movement_x as f64).hypot(event.movement_y as f64);
+                }
+                if self.touches == [event.pointer_id] {
+                    self.move_by(event, device);
+                }
+            }
+            PointerEventType::UP | PointerEventType::CANCEL => {
+                self.touches.retain(|id| *id != event.pointer_id);
+                let is_tap = matches!(event.event_type, PointerEventType::UP)
+                    && self.tap.as_ref().is_some_and(|tap| {
+                        event.timestamp.saturating_sub(tap.start) < TAP_TIMEOUT_US
+                            && tap.movement < TAP_MAX_MOVEMENT
+                    });
+                if !is_tap {
+                    self.tap = None;
+                }
+                if !self.touches.is_empty() {
+                    return;
+                }
+                if let Some(tap) = self.tap.take() {
+                    let button = match tap.fingers {
+                        1 => Button::PRIMARY,
+                        2 => Button::SECONDARY,
+                        _ => Button::AUXILARY,
+                    };
+                    debug!("Tap with {} finger(s), clicking {button:?}.", tap.fingers);
+                    device.send_mouse_button(button, true);
+                    device.send_mouse_button(button, false);
+                }
+            }
+        }
+    }
+
+    fn move_by(&mut self, event: &PointerEvent, device: &mut dyn InputDevice) {
+        let dx = self.remainder.0 + event.movement_x as f64 * self.sensitivity;
+        let dy = self.remainder.1 + event.movement_y as f64 * self.sensitivity;
+        let (dx_int, dy_int) = (dx.trunc(), dy.trunc());
+        self.remainder = (dx - dx_int, dy - dy_int);
+        if dx_int != 0.0 || dy_int != 0.0 {
+            device.send_relative_motion(dx_int as i32, dy_int as i32);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::device::tests::FakeInputDevice;
+    use crate::input::gesture::TouchGestures;
+    use crate::protocol::tests::pointer_event;
+    use std::sync::{Arc, Mutex};
+
+    use PointerEventType::*;
+
+    fn touch(
+        event_type: PointerEventType,
+        pointer_id: i64,
+        timestamp_ms: u64,
+        (x, y): (f64, f64),
+        (movement_x, movement_y): (i64, i64),
+    ) -> PointerEvent {
+        let mut event = pointer_event(PointerType::Touch, event_type, pointer_id, timestamp_ms);
+        (event.x, event.y) = (x, y);
+        (event.movement_x, event.movement_y) = (movement_x, movement_y);
+        event
+    }
+
+    // touchpad mode with two finger scrolling as the client handler runs it, returns what reached
+    // the device
+    fn touchpad(sensitivity: f64, events: &[PointerEvent]) -> Vec<String> {
+        let inputs = Arc::new(Mutex::new(Vec::new()));
+        let mut device =
+            FakeInputDevice::new(Arc::new(Mutex::new(Vec::new()))).with_inputs(inputs.clone());
+        let mut relative = RelativePointer::default();
+        assert!(relative.configure(PointerMode::Relative, sensitivity, false));
+        let mut gestures = TouchGestures::default();
+        gestures.set_enabled(true);
+        gestures.set_relative(true);
+        for event in events {
+            let gesture = gestures.process(event, &mut device);
+            assert!(relative.process(event, &mut device) || gesture);
+        }
+        drop(device);
+        Arc::try_unwrap(inputs).unwrap().into_inner().unwrap()
+    }
+
+    #[test]
+    fn one_finger_moves_the_cursor() {
+        let at = (0.5, 0.5);
+        // fractions of pixels add up
+        let inputs = touchpad(
+            0.5,
+            &[
+                touch(DOWN, 1, 0, at, (0, 0)),
+                touch(MOVE, 1, 10, at, (3, -3)),
+                touch(MOVE, 1, 20, at, (3, -3)),
+                touch(MOVE, 1, 30, at, (1, 0)),
+                // a second finger stops the cursor
+                touch(DOWN, 2, 40, at, (0, 0)),
+                touch(MOVE, 1, 50, at, (100, 0)),
+                touch(UP, 2, 60, at, (0, 0)),
+                touch(MOVE, 1, 70, at, (-4, 8)),
+                // too late for a tap
+                touch(UP, 1, 300, at, (0, 0)),
+            ],
+        );
+        assert_eq!(inputs, ["motion 1 -1", "motion 2 -2", "motion -1 4"]);
+    }
+
+    #[test]
+    fn tapping_clicks() {
+        let at = (0.5, 0.5);
+        let tap = touchpad(
+            1.0,
+            &[
+                touch(DOWN, 1, 0, at, (0, 0)),
+                touch(MOVE, 1, 50, at, (2, 1)),
+                touch(UP, 1, 100, at, (0, 0)),
+            ],
+        );
+        assert_eq!(tap, ["motion 2 1", "press primary", "release primary"]);
+
+        let two_finger_tap = touchpad(
+            1.0,
+            &[
+                touch(DOWN, 1, 0, at, (0, 0)),
+                touch(DOWN, 2, 20, (0.75, 0.5), (0, 0)),
+                touch(UP, 1, 100, at, (0, 0)),
+                touch(UP, 2, 120, (0.75, 0.5), (0, 0)),
+            ],
+        );
+        assert_eq!(two_finger_tap, ["press secondary", "release secondary"]);
+
+        let three_finger_tap = touchpad(
+            1.0,
+            &[
+                touch(DOWN, 1, 0, at, (0, 0)),
+                touch(DOWN, 2, 10, at, (0, 0)),
+                touch(DOWN, 3, 20, at, (0, 0)),
+                touch(UP, 3, 100, at, (0, 0)),
+                touch(UP, 2, 110, at, (0, 0)),
+                touch(UP, 1, 120, at, (0, 0)),
+            ],
+        );
+        assert_eq!(three_finger_tap, ["press middle", "release middle"]);
+
+        // held for too long, moved too far or cancelled
+        let slow = touchpad(
+            1.0,
+            &[touch(DOWN, 1, 0, at, (0, 0)), touch(UP, 1, 250, at, (0, 0))],
+        );
+        assert!(slow.is_empty());
+        let moved = touchpad(
+            1.0,
+            &[
+                touch(DOWN, 1, 0, at, (0, 0)),
+                touch(MOVE, 1, 50, at, (8, 8)),
+                touch(UP, 1, 100, at, (0, 0)),
+            ],
+        );
+        assert_eq!(moved, ["motion 8 8"]);
+        let cancelled = touchpad(
+            1.0,
+            &[
+                touch(DOWN, 1, 0, at, (0, 0)),
+                touch(CANCEL, 1, 50, at, (0, 0)),
+            ],
+        );
+        assert!(cancelled.is_empty());
+    }
+
+    #[test]
+    fn two_fingers_scroll_instead_of_moving_the_cursor() {
+        let inputs = touchpad(
+            1.0,
+            &[
+                touch(DOWN, 1, 0, (0.25, 0.5), (0, 0)),
+                touch(DOWN, 2, 10, (0.75, 0.5), (0, 0)),
+                // far enough for the gesture to be a pan, it scrolls from then on
+                touch(MOVE, 1, 20, (0.28125, 0.53125), (10, 10)),
+                touch(MOVE, 2, 30, (0.78125, 0.53125), (10, 10)),
+                touch(UP, 1, 40, (0.28125, 0.53125), (0, 0)),
+                touch(UP, 2, 50, (0.78125, 0.53125), (0, 0)),
+            ],
+        );
+        // the content follows the fingers, there is neither motion nor a tap
+        assert_eq!(inputs, ["wheel -15.625 -15.625 Pixel"]);
+    }
+}
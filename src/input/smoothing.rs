@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::time::Duration;
+
+use tracing::debug;
+
+use crate::protocol::{PenSmoothing, PointerEvent, PointerEventType, PointerType};
+
+/// Longest time constant of the filters, the smoothed position of the stylus never lags further
+/// behind than that. Events are not delayed, only their coordinates are pulled towards earlier
+/// ones.
+pub const MAX_LAG: Duration = Duration::from_millis(100);
+
+// cutoff in Hz of the low pass the speed of the pen goes through for the 1€ filter, the value
+// suggested by its authors
+const SPEED_CUTOFF: f64 = 1.0;
+
+// assumed time between events that come with the same timestamp
+const DEFAULT_INTERVAL: f64 = 1.0 / 120.0;
+
+fn time_constant(cutoff: f64) -> f64 {
+    1.0 / (2.0 * PI * cutoff)
+}
+
+// weight of a new sample dt seconds after the previous one for a low pass with time constant tau
+fn weight(dt: f64, tau: f64) -> f64 {
+    1.0 / (1.0 + tau / dt)
+}
+
+/// Where a pointer is according to the filter.
+struct Stroke {
+    timestamp: u64,
+    x: f64,
+    y: f64,
+    // smoothed speed, only used by the 1€ filter
+    speed_x: f64,
+    speed_y: f64,
+}
+
+impl Stroke {
+    fn new(event: &PointerEvent) -> Self {
+        Self {
+            timestamp: event.timestamp,
+            x: event.x,
+            y: event.y,
+            speed_x: 0.0,
+            speed_y: 0.0,
+        }
+    }
+
+    fn smooth(&mut self, smoothing: PenSmoothing, event: &mut PointerEvent) {
+        // timestamps are in microseconds
+        let dt = match event.timestamp.checked_sub(self.timestamp) {
+            Some(dt) if dt > 0 => dt as f64 / 1e6,
+            _ => DEFAULT_INTERVAL,
+        };
+        self.timestamp = event.timestamp;
+        let tau = match smoothing {
+            PenSmoothing::OneEuro { min_cutoff, beta } => {
+                let w = weight(dt, time_constant(SPEED_CUTOFF));
+                self.speed_x += w * ((event.x - self.x) / dt - self.speed_x);
+                self.speed_y += w * ((event.y - self.y) / dt - self.speed_y);
+                time_constant(min_cutoff + beta * self.speed_x.hypot(self.speed_y))
+            }
+            PenSmoothing::Ema { time_constant_ms } => time_constant_ms / 1000.0,
+        };
+        let w = weight(dt, tau);
+        self.x += w * (event.x - self.x);
+        self.y += w * (event.y - self.y);
+        event.x = self.x;
+        event.y = self.y;
+    }
+}
+
+/// Smooths the coordinates of the stylus to get rid of jitter. Each pointer is filtered on its
+/// own and the filter starts over whenever the pen touches down, so a stroke is not dragged
+/// towards where the previous one ended.
+#[derive(Default)]
+pub struct PenSmoother {
+    smoothing: Option<PenSmoothing>,
+    strokes: HashMap<i64, Stroke>,
+}
+
+impl PenSmoother {
+    pub fn set_smoothing(&mut self, smoothing: Option<PenSmoothing>) {
+        let max_lag = MAX_LAG.as_secs_f64();
+        let smoothing = match smoothing {
+            Some(PenSmoothing::OneEuro { min_cutoff, beta })
+                if min_cutoff.is_finite() && beta.is_finite() =>
+            {
+                Some(PenSmoothing::OneEuro {
+                    min_cutoff: min_cutoff.max(1.0 / (2.0 * PI * max_lag)),
+                    beta: beta.max(0.0),
+                })
+            }
+            Some(PenSmoothing::Ema { time_constant_ms }) if time_constant_ms.is_finite() => {
+                Some(PenSmoothing::Ema {
+                    time_constant_ms: time_constant_ms.clamp(0.0, max_lag * 1000.0),
+                })
+            }
+            Some(smoothing) => {
+                debug!("Ignoring invalid pen smoothing: {smoothing:?}");
+                None
+            }
+            None => None,
+        };
+        if smoothing != self.smoothing {
+            debug!("Using pen smoothing: {smoothing:?}");
+            self.smoothing = smoothing;
+            self.strokes.clear();
+        }
+    }
+
+    /// Smooth x and y of stylus events, other events are left untouched.
+    pub fn apply(&mut self, event: &mut PointerEvent) {
+        let Some(smoothing) = self.smoothing else {
+            return;
+        };
+        if !matches!(event.pointer_type, PointerType::Pen) {
+            return;
+        }
+        match self.strokes.get_mut(&event.pointer_id) {
+            Some(stroke) if !matches!(event.event_type, PointerEventType::DOWN) => {
+                stroke.smooth(smoothing, event)
+            }
+            _ => {
+                self.strokes.insert(event.pointer_id, Stroke::new(event));
+            }
+        }
+        if matches!(
+            event.event_type,
+            PointerEventType::UP | PointerEventType::CANCEL
+        ) {
+            self.strokes.remove(&event.pointer_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol;
+    use crate::protocol::tests::pointer_event;
+
+    #[test]
+    fn pen_smoothing_restarts_with_each_stroke() {
+        use protocol::PointerEventType::*;
+        use protocol::PointerType::*;
+        let mut smoother = PenSmoother::default();
+        let mut smooth = |pointer_type, event_type, pointer_id, timestamp_ms, x| {
+            let mut event = pointer_event(pointer_type, event_type, pointer_id, timestamp_ms);
+            event.x = x;
+            smoother.apply(&mut event);
+            event.x
+        };
+        // off by default
+        assert_eq!(smooth(Pen, DOWN, 1, 0, 0.0), 0.0);
+        assert_eq!(smooth(Pen, MOVE, 1, 10, 1.0), 1.0);
+
+        // the time constant is clamped to 100 ms
+        smoother.set_smoothing(Some(protocol::PenSmoothing::Ema {
+            time_constant_ms: 1000.0,
+        }));
+        let mut smooth = |pointer_type, event_type, pointer_id, timestamp_ms, x| {
+            let mut event = pointer_event(pointer_type, event_type, pointer_id, timestamp_ms);
+            event.x = x;
+            smoother.apply(&mut event);
+            event.x
+        };
+        assert_eq!(smooth(Pen, DOWN, 1, 0, 0.0), 0.0);
+        assert!((smooth(Pen, MOVE, 1, 10, 1.0) - 1.0 / 11.0).abs() < 1e-9);
+        // other pointers have their own state and touches are left alone
+        assert_eq!(smooth(Pen, DOWN, 2, 10, 0.7), 0.7);
+        assert_eq!(smooth(Touch, MOVE, 3, 10, 0.3), 0.3);
+        assert!(smooth(Pen, MOVE, 1, 1010, 1.0) > 0.9);
+        assert!(smooth(Pen, UP, 1, 1020, 1.0) < 1.0);
+        // the next stroke is not dragged towards where the last one ended
+        assert_eq!(smooth(Pen, DOWN, 1, 1030, 0.2), 0.2);
+
+        // jitter is smoothed out, even with a cutoff below the smallest one allowed
+        smoother.set_smoothing(Some(protocol::PenSmoothing::OneEuro {
+            min_cutoff: 0.1,
+            beta: 1.0,
+        }));
+        let mut event = pointer_event(Pen, DOWN, 1, 0);
+        smoother.apply(&mut event);
+        for i in 1..100 {
+            let mut event = pointer_event(Pen, MOVE, 1, i * 8);
+            event.x = if i % 2 == 0 { 0.51 } else { 0.49 };
+            smoother.apply(&mut event);
+            assert!((event.x - 0.5).abs() < 0.01);
+            if i > 50 {
+                assert!((event.x - 0.5).abs() < 0.002);
+            }
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use std::sync::Arc;
+
+use tokio::sync::watch;
+use tracing::info;
+
+/// Turns input from all clients on and off at once, for example if a client got stuck in a touch
+/// or keeps moving the pointer around. Clones share the same state.
+#[derive(Clone)]
+pub struct InputSwitch {
+    enabled: Arc<watch::Sender<bool>>,
+}
+
+impl InputSwitch {
+    pub fn new() -> Self {
+        Self {
+            enabled: Arc::new(watch::Sender::new(true)),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        *self.enabled.borrow()
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        let changed = self.enabled.send_if_modified(|current| {
+            let changed = *current != enabled;
+            *current = enabled;
+            changed
+        });
+        if changed {
+            log_state(enabled);
+        }
+    }
+
+    pub fn toggle(&self) {
+        let mut enabled = true;
+        self.enabled.send_modify(|current| {
+            *current = !*current;
+            enabled = *current;
+        });
+        log_state(enabled);
+    }
+
+    /// The receiver only sees changes made after subscribing.
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.enabled.subscribe()
+    }
+}
+
+impl Default for InputSwitch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn log_state(enabled: bool) {
+    info!(
+        "Input from clients {}.",
+        if enabled { "enabled" } else { "disabled" }
+    );
+}
@@ -1,13 +1,16 @@
-use std::cmp::Ordering;
+use std::error::Error;
 use std::ffi::CString;
 use std::os::raw::{c_char, c_int};
+use std::time::{Duration, Instant};
 
-use crate::capturable::x11::X11Context;
+use crate::capturable::x11::{DeviceMapping, X11Context};
 use crate::capturable::{Capturable, Geometry};
+use crate::input::buttons::ButtonMapper;
 use crate::input::device::{InputDevice, InputDeviceType};
+use crate::input::uinput_pool::UInputPool;
 use crate::protocol::{
-    Button, KeyboardEvent, KeyboardEventType, KeyboardLocation, PointerEvent, PointerEventType,
-    PointerType, WheelEvent,
+    Button, ButtonAction, ButtonMapping, KeyboardEvent, KeyboardEventType, KeyboardLocation,
+    PointerEvent, PointerEventType, PointerMode, PointerType, WheelEvent, WHEEL_NOTCH,
 };
 
 use crate::cerror::CError;
@@ -16,26 +19,427 @@ use tracing::{debug, warn};
 
 extern "C" {
     fn init_uinput_keyboard(name: *const c_char, err: *mut CError) -> c_int;
-    fn init_uinput_stylus(name: *const c_char, err: *mut CError) -> c_int;
+    fn init_uinput_stylus(name: *const c_char, pressure_max: c_int, err: *mut CError) -> c_int;
     fn init_uinput_mouse(name: *const c_char, err: *mut CError) -> c_int;
     fn init_uinput_touch(name: *const c_char, err: *mut CError) -> c_int;
+    fn init_uinput_relative_mouse(name: *const c_char, err: *mut CError) -> c_int;
     fn destroy_uinput_device(fd: c_int);
     fn send_uinput_event(device: c_int, typ: c_int, code: c_int, value: c_int, err: *mut CError);
 }
 
+type InitDevice<'a> = &'a dyn Fn(*const c_char, *mut CError) -> c_int;
+
+pub const UINPUT_PATH: &str = "/dev/uinput";
+
+/// Largest pressure the stylus reports by default, the same resolution its position has.
+pub const DEFAULT_PRESSURE_MAX: i32 = 65535;
+
+/// Value of ABS_PRESSURE for a pressure between 0 and 1, rounded to the nearest step. Pressures
+/// above 0 never end up as 0, some applications draw segments of zero width for a pen that
+/// touches the screen without pressure.
+pub fn pressure_value(pressure: f64, pressure_max: i32) -> i32 {
+    if pressure.is_nan() || pressure <= 0.0 {
+        return 0;
+    }
+    ((pressure.min(1.0) * pressure_max as f64).round() as i32).max(1)
+}
+
+/// Position and size of the area the absolute axes have to cover for a Capturable, relative to the
+/// screen. Capturables on a scaled output are stretched around their origin by its scale.
+fn input_area(geometry: &Geometry) -> Option<(f64, f64, f64, f64)> {
+    match *geometry {
+        Geometry::Relative(x, y, width, height, scale_x, scale_y) => {
+            Some((x, y, width * scale_x, height * scale_y))
+        }
+        Geometry::VirtualScreen(..) => None,
+    }
+}
+
+/// Value of an absolute axis for a position between 0 and 1 within an area at offset of size,
+/// both relative to the screen.
+fn abs_value(position: f64, offset: f64, size: f64) -> i32 {
+    ((position * size + offset) * ABS_MAX) as i32
+}
+
+/// Why /dev/uinput can not be opened, the C side only reports that opening it failed.
+pub fn uinput_errno() -> Option<i32> {
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(UINPUT_PATH)
+        .err()
+        .and_then(|err| err.raw_os_error())
+}
+
+/// Rule for udev that lets members of the uinput group create devices.
+pub const UDEV_RULE: &str =
+    r#"KERNEL=="uinput", MODE="0660", GROUP="uinput", OPTIONS+="static_node=uinput""#;
+
+const ENOENT: i32 = 2;
+
+/// Why uinput devices can not be created and what to do about it. Groups are looked up in
+/// /etc/group, which misses groups of users that come from LDAP and the like, but is good enough
+/// to tell people what to fix.
+#[derive(Debug, Clone)]
+pub struct UInputDiagnosis {
+    pub errno: Option<i32>,
+    // group owning /dev/uinput, None if it does not exist
+    pub device_group: Option<String>,
+    // groups Weylus runs with
+    pub groups: Vec<String>,
+    // groups the user is a member of according to /etc/group, these only apply to processes
+    // started after logging in again
+    member_of: Vec<String>,
+}
+
+impl UInputDiagnosis {
+    pub fn new() -> Self {
+        use std::os::unix::fs::MetadataExt;
+        let group_file = std::fs::read_to_string("/etc/group").unwrap_or_default();
+        // name, gid and members of each group
+        let group_entries: Vec<(&str, u32, Vec<&str>)> = group_file
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split(':');
+                let name = fields.next()?;
+                let gid = fields.nth(1)?.parse().ok()?;
+                let members = fields.next().unwrap_or("").split(',').collect();
+                Some((name, gid, members))
+            })
+            .collect();
+        let group_name = |gid: u32| {
+            group_entries
+                .iter()
+                .find(|(_, id, _)| *id == gid)
+                .map_or_else(|| gid.to_string(), |(name, _, _)| name.to_string())
+        };
+
+        let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+        let status_field = |field: &str| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix(field))
+                .unwrap_or("")
+                .split_whitespace()
+                .filter_map(|id| id.parse::<u32>().ok())
+                .collect::<Vec<_>>()
+        };
+        // real, effective, saved and filesystem gid, only the effective one matters
+        let mut gids: Vec<u32> = status_field("Gid:").get(1).copied().into_iter().collect();
+        for gid in status_field("Groups:") {
+            if !gids.contains(&gid) {
+                gids.push(gid);
+            }
+        }
+
+        let user = status_field("Uid:").get(1).and_then(|uid| {
+            std::fs::read_to_string("/etc/passwd")
+                .unwrap_or_default()
+                .lines()
+                .find(|line| line.split(':').nth(2) == Some(&uid.to_string()))
+                .and_then(|line| line.split(':').next())
+                .map(String::from)
+        });
+        let member_of = user.map_or_else(Vec::new, |user| {
+            group_entries
+                .iter()
+                .filter(|(_, _, members)| members.contains(&user.as_str()))
+                .map(|(name, _, _)| name.to_string())
+                .collect()
+        });
+
+        Self {
+            errno: uinput_errno(),
+            device_group: std::fs::metadata(UINPUT_PATH)
+                .ok()
+                .map(|metadata| group_name(metadata.gid())),
+            groups: gids.into_iter().map(group_name).collect(),
+            member_of,
+        }
+    }
+
+    /// What to do to make uinput work.
+    pub fn hint(&self) -> String {
+        match (self.errno, &self.device_group) {
+            (Some(ENOENT), _) | (_, None) => format!(
+                "{UINPUT_PATH} does not exist, the uinput kernel module does not seem to be \
+                loaded. Load it by running `sudo modprobe uinput`."
+            ),
+            (None, _) => format!("{UINPUT_PATH} is writable by Weylus."),
+            (_, Some(group)) if group == "root" => format!(
+                "{UINPUT_PATH} is only accessible by root. Create the group uinput, add your \
+                user to it and install the udev rule {UDEV_RULE} to make {UINPUT_PATH} writable \
+                by the group, then log out and in again."
+            ),
+            (_, Some(group)) if self.groups.contains(group) => format!(
+                "Weylus runs as a member of the group {group} owning {UINPUT_PATH}, but the \
+                group is not allowed to write to it. Install the udev rule {UDEV_RULE} to make \
+                it writable by the group."
+            ),
+            (_, Some(group)) if self.member_of.contains(group) => format!(
+                "Your user has been added to the group {group} owning {UINPUT_PATH} but Weylus \
+                has been started before that. Log out and in again, then restart Weylus."
+            ),
+            (_, Some(group)) => format!(
+                "{UINPUT_PATH} belongs to the group {group}, which Weylus is not running as. \
+                Add your user to it by running `sudo usermod -aG {group} $USER`, then log out \
+                and in again."
+            ),
+        }
+    }
+}
+
+impl Default for UInputDiagnosis {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Display for UInputDiagnosis {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.errno {
+            Some(errno) => write!(
+                f,
+                "Opening {UINPUT_PATH} failed: {}",
+                std::io::Error::from_raw_os_error(errno)
+            )?,
+            None => write!(f, "{UINPUT_PATH} can be opened")?,
+        }
+        if let Some(group) = &self.device_group {
+            write!(f, ", it belongs to the group {group}")?;
+        }
+        write!(
+            f,
+            ". Weylus runs with the groups: {}.\n{}",
+            self.groups.join(", "),
+            self.hint()
+        )
+    }
+}
+
+fn create_device(init: InitDevice, name: &str) -> Result<c_int, CError> {
+    let name_c_str = CString::new(name.as_bytes()).unwrap();
+    let mut err = CError::new();
+    let fd = init(name_c_str.as_ptr(), &mut err);
+    if !err.is_err() {
+        return Ok(fd);
+    }
+    // opened, but setting the device up failed
+    if fd >= 0 {
+        unsafe { destroy_uinput_device(fd) };
+    }
+    Err(err)
+}
+
+/// Creates and destroys a device of each kind to find out if input can be simulated.
+pub fn check_devices() -> Vec<(&'static str, Result<(), CError>)> {
+    let devices: [(&'static str, InitDevice); 5] = [
+        ("stylus", &|name, err| unsafe {
+            init_uinput_stylus(name, DEFAULT_PRESSURE_MAX, err)
+        }),
+        ("mouse", &|name, err| unsafe {
+            init_uinput_mouse(name, err)
+        }),
+        ("touch", &|name, err| unsafe {
+            init_uinput_touch(name, err)
+        }),
+        ("keyboard", &|name, err| unsafe {
+            init_uinput_keyboard(name, err)
+        }),
+        ("relative mouse", &|name, err| unsafe {
+            init_uinput_relative_mouse(name, err)
+        }),
+    ];
+    devices
+        .into_iter()
+        .map(|(kind, init)| {
+            let result = create_device(init, &format!("Weylus Check {kind}"))
+                .map(|fd| unsafe { destroy_uinput_device(fd) });
+            (kind, result)
+        })
+        .collect()
+}
+
 struct MultiTouch {
     id: i64,
 }
 
+/// Scroll distance along one axis that has not been sent yet.
+#[derive(Default)]
+struct WheelAccumulator {
+    hi_res: f64,
+    clicks: i32,
+}
+
+impl WheelAccumulator {
+    /// Add a delta in units of REL_WHEEL_HI_RES and return the integer part that can be sent.
+    fn accumulate_hi_res(&mut self, delta: f64) -> i32 {
+        self.hi_res += delta;
+        let d = self.hi_res.trunc();
+        self.hi_res -= d;
+        d as i32
+    }
+
+    /// Add a delta in units of REL_WHEEL_HI_RES and return the number of full wheel clicks.
+    fn accumulate_clicks(&mut self, delta: i32) -> i32 {
+        // reset if the scroll direction changed
+        if self.clicks.signum() * delta.signum() < 0 {
+            self.clicks = 0;
+        }
+        self.clicks += delta;
+        let clicks = self.clicks / WHEEL_NOTCH as i32;
+        self.clicks %= WHEEL_NOTCH as i32;
+        clicks
+    }
+}
+
+/// Tool of the stylus that is currently in proximity.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PenTool {
+    Pen,
+    Rubber,
+}
+
+impl PenTool {
+    fn key_code(self) -> c_int {
+        match self {
+            PenTool::Pen => EC_KEY_TOOL_PEN,
+            PenTool::Rubber => EC_KEY_TOOL_RUBBER,
+        }
+    }
+}
+
+/// The uinput devices created for a client, without any state of the input sent through them.
+/// Destroys the devices once dropped.
+pub struct UInputHandles {
+    keyboard_fd: c_int,
+    stylus_fd: c_int,
+    mouse_fd: c_int,
+    touch_fd: c_int,
+    // kinds of devices that could not be created, their fds are -1
+    missing_devices: Vec<&'static str>,
+    // what the devices have been created with
+    id: Option<String>,
+    pressure_max: i32,
+}
+
+impl UInputHandles {
+    pub fn new(id: &Option<String>, pressure_max: i32) -> Result<Self, CError> {
+        let suffix = suffix(id);
+        // devices that can not be created are left out, the others keep working
+        let mut missing_devices = Vec::new();
+        let mut first_err = None;
+        let mut create =
+            |kind: &'static str, init: InitDevice, name: &str| match create_device(init, name) {
+                Ok(fd) => fd,
+                Err(err) => {
+                    warn!("Failed to create uinput {kind} device: {err}");
+                    missing_devices.push(kind);
+                    first_err.get_or_insert(err);
+                    -1
+                }
+            };
+
+        let fds = [
+            create(
+                "stylus",
+                &|name, err| unsafe { init_uinput_stylus(name, pressure_max, err) },
+                &format!("Weylus Stylus{}", suffix),
+            ),
+            create(
+                "mouse",
+                &|name, err| unsafe { init_uinput_mouse(name, err) },
+                &format!("Weylus Mouse{}", suffix),
+            ),
+            create(
+                "touch",
+                &|name, err| unsafe { init_uinput_touch(name, err) },
+                &format!("Weylus Touch{}", suffix),
+            ),
+            create(
+                "keyboard",
+                &|name, err| unsafe { init_uinput_keyboard(name, err) },
+                &format!("Weylus Keyboard{}", suffix),
+            ),
+        ];
+
+        // nothing at all works, most likely because /dev/uinput can not be opened
+        if let Some(err) = first_err.filter(|_| missing_devices.len() == fds.len()) {
+            return Err(err);
+        }
+        let [stylus_fd, mouse_fd, touch_fd, keyboard_fd] = fds;
+
+        Ok(Self {
+            keyboard_fd,
+            stylus_fd,
+            mouse_fd,
+            touch_fd,
+            missing_devices,
+            id: id.clone(),
+            pressure_max,
+        })
+    }
+
+    /// The devices have the capabilities devices created for the given id and pressure range
+    /// would have.
+    pub fn matches(&self, id: &Option<String>, pressure_max: i32) -> bool {
+        self.id == *id && self.pressure_max == pressure_max
+    }
+}
+
+impl Drop for UInputHandles {
+    fn drop(&mut self) {
+        for fd in [
+            self.keyboard_fd,
+            self.stylus_fd,
+            self.mouse_fd,
+            self.touch_fd,
+        ]
+        .into_iter()
+        .filter(|fd| *fd >= 0)
+        {
+            unsafe { destroy_uinput_device(fd) };
+        }
+    }
+}
+
+fn suffix(id: &Option<String>) -> String {
+    match id {
+        Some(id) => format!(" - {}", id),
+        None => String::new(),
+    }
+}
+
 pub struct UInputDevice {
     keyboard_fd: c_int,
     stylus_fd: c_int,
     mouse_fd: c_int,
     touch_fd: c_int,
-    touches: [Option<MultiTouch>; 5],
-    tool_pen_active: bool,
+    // only exists in PointerMode::Relative
+    relative_mouse_fd: Option<c_int>,
+    touches: [Option<MultiTouch>; MAX_TOUCHES],
+    next_tracking_id: c_int,
+    wheel_x: WheelAccumulator,
+    wheel_y: WheelAccumulator,
+    pen_tool: Option<PenTool>,
     pen_touching: bool,
+    pressure_max: i32,
+    // buttons of mice and the stylus that are down
+    buttons: ButtonMapper,
+    // keys that are down, to release them if input is turned off
+    pressed_keys: Vec<c_int>,
+    // geometry the stylus is mapped to via its coordinate transformation matrix
+    stylus_mapping: Option<(f64, f64, f64, f64)>,
+    // how the stylus and the touch device are actually mapped, compositors may replace the
+    // mapping of Weylus with one that rotates them along with their output, the entire screen
+    // if None
+    stylus_device_mapping: Option<DeviceMapping>,
+    touch_device_mapping: Option<DeviceMapping>,
+    // kinds of devices that could not be created, their fds are -1
+    missing_devices: Vec<&'static str>,
     capturable: Box<dyn Capturable>,
+    // what the devices have been created for and where they go once this is dropped
+    id: Option<String>,
+    pool: Option<UInputPool>,
     x: f64,
     y: f64,
     width: f64,
@@ -43,96 +447,102 @@ pub struct UInputDevice {
     name_mouse_device: String,
     name_stylus_device: String,
     name_touch_device: String,
+    name_relative_mouse_device: String,
     num_mouse_mapping_tries: usize,
     num_stylus_mapping_tries: usize,
     num_touch_mapping_tries: usize,
     x11ctx: Option<X11Context>,
+    screen_config_timestamp: Option<u64>,
+    last_screen_config_check: Instant,
+    // events are recorded instead of sent if set
+    #[cfg(test)]
+    sent: Option<tests::Sent>,
 }
 
 impl UInputDevice {
-    pub fn new(capturable: Box<dyn Capturable>, id: &Option<String>) -> Result<Self, CError> {
-        let mut suffix = String::new();
-        if let Some(id) = id {
-            suffix = format!(" - {}", id);
-        }
-        let mut err = CError::new();
-        let name_stylus = format!("Weylus Stylus{}", suffix);
-        let name_stylus_c_str = CString::new(name_stylus.as_bytes()).unwrap();
-        let stylus_fd = unsafe { init_uinput_stylus(name_stylus_c_str.as_ptr(), &mut err) };
-        if err.is_err() {
-            return Err(err);
-        }
-
-        let name_mouse = format!("Weylus Mouse{}", suffix);
-        let name_mouse_c_str = CString::new(name_mouse.as_bytes()).unwrap();
-        let mouse_fd = unsafe { init_uinput_mouse(name_mouse_c_str.as_ptr(), &mut err) };
-        if err.is_err() {
-            unsafe { destroy_uinput_device(stylus_fd) };
-            return Err(err);
-        }
-
-        let name_touch = format!("Weylus Touch{}", suffix);
-        let name_touch_c_str = CString::new(name_touch.as_bytes()).unwrap();
-        let touch_fd = unsafe { init_uinput_touch(name_touch_c_str.as_ptr(), &mut err) };
-        if err.is_err() {
-            unsafe {
-                destroy_uinput_device(stylus_fd);
-                destroy_uinput_device(mouse_fd);
-            }
-            return Err(err);
-        }
-
-        let name_keyboard = format!("Weylus Keyboard{}", suffix);
-        let name_keyboard_c_str = CString::new(name_keyboard.as_bytes()).unwrap();
-        let keyboard_fd = unsafe { init_uinput_keyboard(name_keyboard_c_str.as_ptr(), &mut err) };
-        if err.is_err() {
-            unsafe {
-                destroy_uinput_device(stylus_fd);
-                destroy_uinput_device(mouse_fd);
-                destroy_uinput_device(touch_fd);
-            }
-            return Err(err);
-        }
-
-        Ok(Self {
-            keyboard_fd,
-            stylus_fd,
-            mouse_fd,
-            touch_fd,
+    /// Send input through devices that already exist, nothing is pressed on them. If pool is
+    /// given, the devices are returned to it once this is dropped instead of being destroyed.
+    pub fn from_handles(
+        mut handles: UInputHandles,
+        capturable: Box<dyn Capturable>,
+        pool: Option<UInputPool>,
+    ) -> Self {
+        let suffix = suffix(&handles.id);
+        Self {
+            // the devices are moved out, dropping handles leaves them alone
+            keyboard_fd: std::mem::replace(&mut handles.keyboard_fd, -1),
+            stylus_fd: std::mem::replace(&mut handles.stylus_fd, -1),
+            mouse_fd: std::mem::replace(&mut handles.mouse_fd, -1),
+            touch_fd: std::mem::replace(&mut handles.touch_fd, -1),
+            relative_mouse_fd: None,
             touches: Default::default(),
-            tool_pen_active: false,
+            next_tracking_id: 0,
+            wheel_x: WheelAccumulator::default(),
+            wheel_y: WheelAccumulator::default(),
+            pen_tool: None,
             pen_touching: false,
+            pressure_max: handles.pressure_max,
+            buttons: ButtonMapper::default(),
+            pressed_keys: Vec::new(),
+            stylus_mapping: None,
+            stylus_device_mapping: None,
+            touch_device_mapping: None,
+            missing_devices: std::mem::take(&mut handles.missing_devices),
             capturable,
+            id: handles.id.take(),
+            pool,
             x: 0.0,
             y: 0.0,
             width: 1.0,
             height: 1.0,
-            name_mouse_device: name_mouse,
-            name_touch_device: name_touch,
-            name_stylus_device: name_stylus,
+            name_mouse_device: format!("Weylus Mouse{}", suffix),
+            name_touch_device: format!("Weylus Touch{}", suffix),
+            name_stylus_device: format!("Weylus Stylus{}", suffix),
+            name_relative_mouse_device: format!("Weylus Relative Mouse{}", suffix),
             num_mouse_mapping_tries: 0,
             num_stylus_mapping_tries: 0,
             num_touch_mapping_tries: 0,
             x11ctx: X11Context::new(),
-        })
+            screen_config_timestamp: None,
+            last_screen_config_check: Instant::now(),
+            #[cfg(test)]
+            sent: None,
+        }
+    }
+
+    /// Kinds of devices like "touch" that could not be created, input they would simulate is
+    /// dropped.
+    pub fn missing_devices(&self) -> &[&'static str] {
+        &self.missing_devices
     }
 
     fn transform_x(&self, x: f64) -> i32 {
-        let x = (x * self.width + self.x) * ABS_MAX;
-        x as i32
+        abs_value(x, self.x, self.width)
     }
 
     fn transform_y(&self, y: f64) -> i32 {
-        let y = (y * self.height + self.y) * ABS_MAX;
-        y as i32
+        abs_value(y, self.y, self.height)
+    }
+
+    // like transform_x and transform_y but for a device mapped as by mapping
+    fn transform_position(&self, mapping: Option<DeviceMapping>, x: f64, y: f64) -> (i32, i32) {
+        let (x, y) = (x * self.width + self.x, y * self.height + self.y);
+        let (x, y) = mapping.map_or((x, y), |mapping| mapping.to_device(x, y));
+        ((x * ABS_MAX) as i32, (y * ABS_MAX) as i32)
     }
 
+    // the touch device keeps the full range, only the stylus has a configurable one
     fn transform_pressure(&self, p: f64) -> i32 {
-        (p * ABS_MAX) as i32
+        pressure_value(p, self.pressure_max)
+    }
+
+    // the size axes share the resolution of the position axes
+    fn transform_touch_width(&self, w: f64) -> i32 {
+        (w * self.width * ABS_MAX) as i32
     }
 
-    fn transform_touch_size(&self, s: f64) -> i32 {
-        (s * ABS_MAX) as i32
+    fn transform_touch_height(&self, h: f64) -> i32 {
+        (h * self.height * ABS_MAX) as i32
     }
 
     fn find_slot(&self, id: i64) -> Option<usize> {
@@ -151,7 +561,199 @@ impl UInputDevice {
             })
     }
 
+    /// Do or undo what a button has been mapped to. Events for the device of report_fd are
+    /// left for the caller to send along with the rest of its report, other devices get reports
+    /// of their own.
+    fn send_button_action(
+        &mut self,
+        action: &ButtonAction,
+        pressed: bool,
+        report_fd: Option<c_int>,
+    ) {
+        use crate::input::uinput_keys::*;
+
+        let (fd, code) = match action {
+            // the stylus switches to the eraser while the button is held, see send_pointer_event
+            ButtonAction::None | ButtonAction::Eraser => return,
+            ButtonAction::LeftClick => (self.mouse_fd, EC_KEY_MOUSE_LEFT),
+            ButtonAction::MiddleClick => (self.mouse_fd, EC_KEY_MOUSE_MIDDLE),
+            ButtonAction::RightClick => (self.mouse_fd, EC_KEY_MOUSE_RIGHT),
+            ButtonAction::Stylus => (self.stylus_fd, EC_KEY_STYLUS),
+            ButtonAction::Stylus2 => (self.stylus_fd, EC_KEY_STYLUS2),
+            ButtonAction::Keys(codes) => {
+                let mut keys = Vec::with_capacity(codes.len());
+                for code in codes {
+                    match map_key(code, &KeyboardLocation::STANDARD) {
+                        KEY_UNKNOWN if pressed => warn!("Unknown key in button mapping: {code}"),
+                        KEY_UNKNOWN => (),
+                        key => keys.push(key),
+                    }
+                }
+                // released the other way round, so modifiers are still down as the key goes up
+                if !pressed {
+                    keys.reverse();
+                }
+                for key in keys {
+                    self.send(self.keyboard_fd, ET_KEY, key, pressed as c_int);
+                    self.send(self.keyboard_fd, ET_SYNC, EC_SYNC_REPORT, 0);
+                }
+                return;
+            }
+        };
+        self.send(fd, ET_KEY, code, pressed as c_int);
+        if report_fd != Some(fd) {
+            self.send(fd, ET_SYNC, EC_SYNC_REPORT, 0);
+        }
+    }
+
+    /// Switch the tool of the stylus. The previous tool leaves proximity in a report of its own,
+    /// applications like Krita and Xournal++ only pick up the new tool that way.
+    fn set_pen_tool(&mut self, tool: Option<PenTool>) {
+        if self.pen_tool == tool {
+            return;
+        }
+        if let Some(old_tool) = self.pen_tool {
+            if self.pen_touching {
+                self.send(self.stylus_fd, ET_KEY, EC_KEY_TOUCH, 0);
+                self.send(self.stylus_fd, ET_ABSOLUTE, EC_ABSOLUTE_PRESSURE, 0);
+            }
+            self.send(self.stylus_fd, ET_KEY, old_tool.key_code(), 0);
+            self.send(self.stylus_fd, ET_SYNC, EC_SYNC_REPORT, 0);
+        }
+        if let Some(tool) = tool {
+            self.send(self.stylus_fd, ET_KEY, tool.key_code(), 1);
+            if self.pen_touching {
+                self.send(self.stylus_fd, ET_KEY, EC_KEY_TOUCH, 1);
+            }
+        }
+        self.pen_tool = tool;
+    }
+
+    /// Update BTN_TOUCH and BTN_TOOL_FINGER, ..., BTN_TOOL_QUINTTAP after the number of active
+    /// touches changed.
+    fn send_touch_count(&self, old_count: usize, new_count: usize) {
+        fn tool(count: usize) -> Option<c_int> {
+            match count {
+                0 => None,
+                1 => Some(EC_KEY_TOOL_FINGER),
+                2 => Some(EC_KEY_TOOL_DOUBLETAP),
+                3 => Some(EC_KEY_TOOL_TRIPLETAP),
+                4 => Some(EC_KEY_TOOL_QUADTAP),
+                _ => Some(EC_KEY_TOOL_QUINTTAP),
+            }
+        }
+        let (old_tool, new_tool) = (tool(old_count), tool(new_count));
+        if old_tool == new_tool {
+            return;
+        }
+        if let Some(old_tool) = old_tool {
+            self.send(self.touch_fd, ET_KEY, old_tool, 0);
+        }
+        if let Some(new_tool) = new_tool {
+            self.send(self.touch_fd, ET_KEY, new_tool, 1);
+        }
+        if (old_count == 0) != (new_count == 0) {
+            self.send(
+                self.touch_fd,
+                ET_KEY,
+                EC_KEY_TOUCH,
+                (new_count > 0) as c_int,
+            );
+        }
+    }
+
+    // The coordinate transformation matrix X computes for a device is not updated if the
+    // resolution, rotation or set of monitors changes, so map the devices created by Weylus again
+    // in that case. Devices that have not been mapped yet are left alone, they will be mapped on
+    // their first input events anyway. Compositors may map the stylus and the touch device on
+    // their own at any time, so how they are mapped is checked every time.
+    fn check_screen_config(&mut self) {
+        if self.last_screen_config_check.elapsed() < SCREEN_CONFIG_CHECK_INTERVAL {
+            return;
+        }
+        self.last_screen_config_check = Instant::now();
+        let Some(x11ctx) = &mut self.x11ctx else {
+            return;
+        };
+        let timestamp = x11ctx.screen_config_timestamp();
+        let changed = self
+            .screen_config_timestamp
+            .is_some_and(|last_timestamp| last_timestamp != timestamp);
+        self.screen_config_timestamp = Some(timestamp);
+        if changed && self.num_touch_mapping_tries > 0 {
+            debug!(
+                "Screen configuration changed, mapping {} to the entire screen again.",
+                self.name_touch_device
+            );
+            x11ctx.map_input_device_to_entire_screen(&self.name_touch_device, false);
+        }
+        if changed && self.num_mouse_mapping_tries > 0 {
+            debug!(
+                "Screen configuration changed, mapping {} to the entire screen again.",
+                self.name_mouse_device
+            );
+            x11ctx.map_input_device_to_entire_screen(&self.name_mouse_device, false);
+        }
+        if let Some(geometry) = self.stylus_mapping.filter(|_| changed) {
+            debug!(
+                "Screen configuration changed, mapping {} again.",
+                self.name_stylus_device
+            );
+            if x11ctx
+                .map_input_device_to_rect(&self.name_stylus_device, true, geometry)
+                .is_err()
+            {
+                self.stylus_mapping = None;
+            }
+        }
+        if self.num_touch_mapping_tries > 0 {
+            self.touch_device_mapping = x11ctx.input_device_mapping(&self.name_touch_device, false);
+        }
+        self.stylus_device_mapping = self.stylus_mapping.map(|geometry| {
+            x11ctx
+                .input_device_mapping(&self.name_stylus_device, true)
+                .unwrap_or(DeviceMapping::new(geometry))
+        });
+    }
+
+    fn send_pen_position(&self, event: &PointerEvent, pressure: c_int) {
+        // the coordinate transformation matrix does not apply to the tilt, it is left as it is
+        let (x, y) = self.transform_position(self.stylus_device_mapping, event.x, event.y);
+        let (tilt_x, tilt_y) = (event.tilt_x, event.tilt_y);
+        self.send(self.stylus_fd, ET_ABSOLUTE, EC_ABSOLUTE_X, x);
+        self.send(self.stylus_fd, ET_ABSOLUTE, EC_ABSOLUTE_Y, y);
+        self.send(self.stylus_fd, ET_ABSOLUTE, EC_ABSOLUTE_PRESSURE, pressure);
+        // stay within the ranges advertised for the device
+        self.send(
+            self.stylus_fd,
+            ET_ABSOLUTE,
+            EC_ABSOLUTE_TILT_X,
+            tilt_x.clamp(-90, 90),
+        );
+        self.send(
+            self.stylus_fd,
+            ET_ABSOLUTE,
+            EC_ABSOLUTE_TILT_Y,
+            tilt_y.clamp(-90, 90),
+        );
+        self.send(
+            self.stylus_fd,
+            ET_ABSOLUTE,
+            EC_ABSOLUTE_Z,
+            event.twist.rem_euclid(360),
+        );
+    }
+
     fn send(&self, fd: c_int, typ: c_int, code: c_int, value: c_int) {
+        // the device could not be created
+        if fd < 0 {
+            return;
+        }
+        #[cfg(test)]
+        if let Some(sent) = &self.sent {
+            sent.lock().unwrap().push((fd, typ, code, value));
+            return;
+        }
         let mut err = CError::new();
         unsafe {
             send_uinput_event(fd, typ, code, value, &mut err);
@@ -164,12 +766,32 @@ impl UInputDevice {
 
 impl Drop for UInputDevice {
     fn drop(&mut self) {
-        unsafe {
-            destroy_uinput_device(self.keyboard_fd);
-            destroy_uinput_device(self.stylus_fd);
-            destroy_uinput_device(self.mouse_fd);
-            destroy_uinput_device(self.touch_fd);
-        };
+        if let Some(pool) = self.pool.take() {
+            // whoever uses the devices next must not find anything pressed
+            self.release_all();
+            pool.put(UInputHandles {
+                keyboard_fd: std::mem::replace(&mut self.keyboard_fd, -1),
+                stylus_fd: std::mem::replace(&mut self.stylus_fd, -1),
+                mouse_fd: std::mem::replace(&mut self.mouse_fd, -1),
+                touch_fd: std::mem::replace(&mut self.touch_fd, -1),
+                missing_devices: std::mem::take(&mut self.missing_devices),
+                id: self.id.take(),
+                pressure_max: self.pressure_max,
+            });
+        }
+        // the relative mouse is not kept, it only exists in PointerMode::Relative
+        for fd in [
+            self.keyboard_fd,
+            self.stylus_fd,
+            self.mouse_fd,
+            self.touch_fd,
+        ]
+        .into_iter()
+        .chain(self.relative_mouse_fd)
+        .filter(|fd| *fd >= 0)
+        {
+            unsafe { destroy_uinput_device(fd) };
+        }
     }
 }
 
@@ -189,13 +811,15 @@ const EC_KEY_MOUSE_MIDDLE: c_int = 0x112;
 const EC_KEY_TOOL_PEN: c_int = 0x140;
 const EC_KEY_TOOL_RUBBER: c_int = 0x141;
 const EC_KEY_TOUCH: c_int = 0x14a;
+const EC_KEY_STYLUS: c_int = 0x14b;
+const EC_KEY_STYLUS2: c_int = 0x14c;
 const EC_KEY_TOOL_FINGER: c_int = 0x145;
 const EC_KEY_TOOL_DOUBLETAP: c_int = 0x14d;
 const EC_KEY_TOOL_TRIPLETAP: c_int = 0x14e;
 const EC_KEY_TOOL_QUADTAP: c_int = 0x14f; /* Four fingers on trackpad */
 const EC_KEY_TOOL_QUINTTAP: c_int = 0x148; /* Five fingers on trackpad */
-//const EC_RELATIVE_X: c_int = 0x00;
-//const EC_RELATIVE_Y: c_int = 0x01;
+const EC_RELATIVE_X: c_int = 0x00;
+const EC_RELATIVE_Y: c_int = 0x01;
 
 const EC_REL_HWHEEL: c_int = 0x06;
 const EC_REL_WHEEL: c_int = 0x08;
@@ -204,9 +828,11 @@ const EC_REL_HWHEEL_HI_RES: c_int = 0x0c;
 
 const EC_ABSOLUTE_X: c_int = 0x00;
 const EC_ABSOLUTE_Y: c_int = 0x01;
+const EC_ABSOLUTE_Z: c_int = 0x02;
 const EC_ABSOLUTE_PRESSURE: c_int = 0x18;
 const EC_ABSOLUTE_TILT_X: c_int = 0x1a;
 const EC_ABSOLUTE_TILT_Y: c_int = 0x1b;
+const EC_ABSOLUTE_TOOL_WIDTH: c_int = 0x1c;
 const EC_ABS_MT_SLOT: c_int = 0x2f; /* MT slot being modified */
 const EC_ABS_MT_TOUCH_MAJOR: c_int = 0x30; /* Major axis of touching ellipse */
 const EC_ABS_MT_TOUCH_MINOR: c_int = 0x31; /* Minor axis (omit if circular) */
@@ -239,6 +865,14 @@ const ABS_MAX: f64 = 65535.0;
 // has been choosen. If anyone knows a better solution: PLEASE FIX THIS!
 const MAX_SCREEN_MAPPING_TRIES: usize = 100;
 
+// how often to check if the screen configuration changed and the input devices need to be mapped
+// again
+const SCREEN_CONFIG_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// number of multitouch slots, this has to match lib/linux/uinput.c
+const MAX_TOUCHES: usize = 10;
+const MAX_TRACKING_ID: c_int = 0xffff;
+
 impl InputDevice for UInputDevice {
     fn send_wheel_event(&mut self, event: &WheelEvent) {
         if let Err(err) = self.capturable.before_input() {
@@ -246,28 +880,26 @@ impl InputDevice for UInputDevice {
             return;
         }
 
-        fn direction(d: i32) -> i32 {
-            match d.cmp(&0) {
-                Ordering::Equal => 0,
-                Ordering::Less => -1,
-                Ordering::Greater => 1,
-            }
-        }
+        // Touchpads and smooth scrolling produce many small fractional deltas, accumulate them
+        // so that slow scrolling still eventually results in a wheel click.
+        let (dx, dy) = event.hi_res_delta();
+        let dx_hi_res = self.wheel_x.accumulate_hi_res(dx);
+        let dy_hi_res = self.wheel_y.accumulate_hi_res(dy);
+        let dx_clicks = self.wheel_x.accumulate_clicks(dx_hi_res);
+        let dy_clicks = self.wheel_y.accumulate_clicks(dy_hi_res);
 
-        self.send(
-            self.mouse_fd,
-            ET_RELATIVE,
-            EC_REL_WHEEL,
-            direction(event.dy),
-        );
-        self.send(
-            self.mouse_fd,
-            ET_RELATIVE,
-            EC_REL_HWHEEL,
-            direction(event.dx),
-        );
-        self.send(self.mouse_fd, ET_RELATIVE, EC_REL_WHEEL_HI_RES, event.dy);
-        self.send(self.mouse_fd, ET_RELATIVE, EC_REL_HWHEEL_HI_RES, event.dx);
+        if dy_clicks != 0 {
+            self.send(self.mouse_fd, ET_RELATIVE, EC_REL_WHEEL, dy_clicks);
+        }
+        if dx_clicks != 0 {
+            self.send(self.mouse_fd, ET_RELATIVE, EC_REL_HWHEEL, dx_clicks);
+        }
+        if dy_hi_res != 0 {
+            self.send(self.mouse_fd, ET_RELATIVE, EC_REL_WHEEL_HI_RES, dy_hi_res);
+        }
+        if dx_hi_res != 0 {
+            self.send(self.mouse_fd, ET_RELATIVE, EC_REL_HWHEEL_HI_RES, dx_hi_res);
+        }
 
         self.send(
             self.mouse_fd,
@@ -283,9 +915,11 @@ impl InputDevice for UInputDevice {
             warn!("Failed to activate window, sending no input ({})", err);
             return;
         }
-        let (x, y, width, height) = match self.capturable.geometry().unwrap() {
-            Geometry::Relative(x, y, width, height) => (x, y, width, height),
-            _ => {
+        // queried for every event, so input follows the window as it moves
+        let area = self.capturable.geometry().ok();
+        let (x, y, width, height) = match area.as_ref().and_then(input_area) {
+            Some(area) => area,
+            None => {
                 warn!("Failed to get window geometry, sending no input");
                 return;
             }
@@ -294,115 +928,90 @@ impl InputDevice for UInputDevice {
         self.y = y;
         self.width = width;
         self.height = height;
+        self.check_screen_config();
         match event.pointer_type {
             PointerType::Touch => {
                 if self.num_touch_mapping_tries < MAX_SCREEN_MAPPING_TRIES {
                     if let Some(x11ctx) = &mut self.x11ctx {
                         x11ctx.map_input_device_to_entire_screen(&self.name_touch_device, false);
+                        self.touch_device_mapping =
+                            x11ctx.input_device_mapping(&self.name_touch_device, false);
                     }
                     self.num_touch_mapping_tries += 1;
                 }
                 match event.event_type {
                     PointerEventType::DOWN | PointerEventType::MOVE => {
-                        let slot: usize;
-                        // check if this event is already assigned to one of our 10 multitouch slots
-                        if let Some(s) = self.find_slot(event.pointer_id) {
-                            slot = s;
-                        } else {
-                            // this event is not assigned to a slot, lets try to do so now
-                            // find the first unused slot
-                            if let Some(s) =
-                                self.touches
-                                    .iter()
-                                    .enumerate()
-                                    .find_map(|(slot, mt)| match mt {
-                                        None => Some(slot),
-                                        Some(_) => None,
-                                    })
-                            {
-                                slot = s;
-                                self.touches[slot] = Some(MultiTouch {
-                                    id: event.pointer_id,
-                                })
-                            } else {
-                                // out of slots, do nothing
-                                return;
+                        // check if this event is already assigned to one of the multitouch slots
+                        let (slot, new_touch) = match self.find_slot(event.pointer_id) {
+                            Some(slot) => (slot, false),
+                            None => {
+                                // this event is not assigned to a slot, lets try to do so now
+                                match self.touches.iter().position(Option::is_none) {
+                                    Some(slot) => (slot, true),
+                                    None => {
+                                        debug!("Out of multitouch slots, dropping touch.");
+                                        return;
+                                    }
+                                }
                             }
                         };
                         self.send(self.touch_fd, ET_ABSOLUTE, EC_ABS_MT_SLOT, slot as i32);
-                        self.send(
-                            self.touch_fd,
-                            ET_ABSOLUTE,
-                            EC_ABS_MT_TRACKING_ID,
-                            slot as i32,
-                        );
-
-                        if let PointerEventType::DOWN = event.event_type {
-                            self.send(self.touch_fd, ET_KEY, EC_KEY_TOUCH, 1);
-                            match slot {
-                                1 => self.send(self.touch_fd, ET_KEY, EC_KEY_TOOL_FINGER, 0),
-                                2 => self.send(self.touch_fd, ET_KEY, EC_KEY_TOOL_DOUBLETAP, 0),
-                                3 => self.send(self.touch_fd, ET_KEY, EC_KEY_TOOL_TRIPLETAP, 0),
-                                4 => self.send(self.touch_fd, ET_KEY, EC_KEY_TOOL_QUADTAP, 0),
-                                _ => self.send(self.touch_fd, ET_KEY, EC_KEY_TOOL_QUINTTAP, 0),
-                            }
-                            match slot {
-                                1 => self.send(self.touch_fd, ET_KEY, EC_KEY_TOOL_DOUBLETAP, 1),
-                                2 => self.send(self.touch_fd, ET_KEY, EC_KEY_TOOL_TRIPLETAP, 1),
-                                3 => self.send(self.touch_fd, ET_KEY, EC_KEY_TOOL_QUADTAP, 1),
-                                4 => self.send(self.touch_fd, ET_KEY, EC_KEY_TOOL_QUINTTAP, 1),
-                                _ => self.send(self.touch_fd, ET_KEY, EC_KEY_TOOL_FINGER, 1),
-                            }
+                        if new_touch {
+                            let count = self.touches.iter().flatten().count();
+                            let tracking_id = self.next_tracking_id;
+                            self.next_tracking_id = (tracking_id + 1) % (MAX_TRACKING_ID + 1);
+                            self.touches[slot] = Some(MultiTouch {
+                                id: event.pointer_id,
+                            });
+                            self.send(
+                                self.touch_fd,
+                                ET_ABSOLUTE,
+                                EC_ABS_MT_TRACKING_ID,
+                                tracking_id,
+                            );
+                            self.send_touch_count(count, count + 1);
                         }
                         self.send(
                             self.touch_fd,
                             ET_ABSOLUTE,
                             EC_ABS_MT_PRESSURE,
-                            self.transform_pressure(event.pressure),
-                        );
-                        let major: i32;
-                        let minor: i32;
-                        let orientation = if event.height >= event.width {
-                            major = self.transform_touch_size(event.height);
-                            minor = self.transform_touch_size(event.width);
-                            0
-                        } else {
-                            major = self.transform_touch_size(event.width);
-                            minor = self.transform_touch_size(event.height);
-                            1
-                        };
-                        self.send(self.touch_fd, ET_ABSOLUTE, EC_ABS_MT_TOUCH_MAJOR, major);
-                        self.send(self.touch_fd, ET_ABSOLUTE, EC_ABS_MT_TOUCH_MINOR, minor);
-                        self.send(
-                            self.touch_fd,
-                            ET_ABSOLUTE,
-                            EC_ABS_MT_ORIENTATION,
-                            orientation,
-                        );
-                        self.send(
-                            self.touch_fd,
-                            ET_ABSOLUTE,
-                            EC_ABS_MT_POSITION_X,
-                            self.transform_x(event.x),
-                        );
-                        self.send(
-                            self.touch_fd,
-                            ET_ABSOLUTE,
-                            EC_ABS_MT_POSITION_Y,
-                            self.transform_y(event.y),
-                        );
-                        self.send(
-                            self.touch_fd,
-                            ET_ABSOLUTE,
-                            EC_ABSOLUTE_X,
-                            self.transform_x(event.x),
-                        );
-                        self.send(
-                            self.touch_fd,
-                            ET_ABSOLUTE,
-                            EC_ABSOLUTE_Y,
-                            self.transform_y(event.y),
+                            pressure_value(event.pressure, ABS_MAX as i32),
                         );
+                        // a contact of unknown size is not a point, leave the size alone
+                        if event.width > 0.0 || event.height > 0.0 {
+                            let width = self.transform_touch_width(event.width);
+                            let height = self.transform_touch_height(event.height);
+                            let (major, minor, orientation) = if height >= width {
+                                (height, width, 0)
+                            } else {
+                                (width, height, 1)
+                            };
+                            self.send(self.touch_fd, ET_ABSOLUTE, EC_ABS_MT_TOUCH_MAJOR, major);
+                            self.send(self.touch_fd, ET_ABSOLUTE, EC_ABS_MT_TOUCH_MINOR, minor);
+                            self.send(
+                                self.touch_fd,
+                                ET_ABSOLUTE,
+                                EC_ABS_MT_ORIENTATION,
+                                orientation,
+                            );
+                            if event.is_primary {
+                                self.send(
+                                    self.touch_fd,
+                                    ET_ABSOLUTE,
+                                    EC_ABSOLUTE_TOOL_WIDTH,
+                                    major,
+                                );
+                            }
+                        }
+                        let (x, y) =
+                            self.transform_position(self.touch_device_mapping, event.x, event.y);
+                        self.send(self.touch_fd, ET_ABSOLUTE, EC_ABS_MT_POSITION_X, x);
+                        self.send(self.touch_fd, ET_ABSOLUTE, EC_ABS_MT_POSITION_Y, y);
+                        // single touch emulation follows the primary touch
+                        if event.is_primary {
+                            self.send(self.touch_fd, ET_ABSOLUTE, EC_ABSOLUTE_X, x);
+                            self.send(self.touch_fd, ET_ABSOLUTE, EC_ABSOLUTE_Y, y);
+                        }
                         self.send(
                             self.touch_fd,
                             ET_MSC,
@@ -412,19 +1021,13 @@ impl InputDevice for UInputDevice {
                         self.send(self.touch_fd, ET_SYNC, EC_SYNC_REPORT, 0);
                     }
                     PointerEventType::CANCEL | PointerEventType::UP => {
-                        // remove from slot
+                        // Release the slot, a cancelled touch has to be released as well,
+                        // otherwise applications consider the touch to be still going on.
                         if let Some(slot) = self.find_slot(event.pointer_id) {
+                            let count = self.touches.iter().flatten().count();
                             self.send(self.touch_fd, ET_ABSOLUTE, EC_ABS_MT_SLOT, slot as i32);
                             self.send(self.touch_fd, ET_ABSOLUTE, EC_ABS_MT_TRACKING_ID, -1);
-                            self.send(self.touch_fd, ET_KEY, EC_KEY_TOUCH, 0);
-                            self.send(self.touch_fd, ET_KEY, EC_KEY_TOOL_FINGER, 0);
-                            match slot {
-                                1 => self.send(self.touch_fd, ET_KEY, EC_KEY_TOOL_DOUBLETAP, 0),
-                                2 => self.send(self.touch_fd, ET_KEY, EC_KEY_TOOL_TRIPLETAP, 0),
-                                3 => self.send(self.touch_fd, ET_KEY, EC_KEY_TOOL_QUADTAP, 0),
-                                4 => self.send(self.touch_fd, ET_KEY, EC_KEY_TOOL_QUINTTAP, 0),
-                                _ => (),
-                            }
+                            self.send_touch_count(count, count - 1);
                             self.send(
                                 self.touch_fd,
                                 ET_MSC,
@@ -438,70 +1041,84 @@ impl InputDevice for UInputDevice {
                 };
             }
             PointerType::Pen => {
-                if self.num_stylus_mapping_tries < MAX_SCREEN_MAPPING_TRIES {
+                // Map the stylus to the captured window and map it again whenever the window is
+                // moved or resized, this way strokes do not end up outside of the window.
+                let geometry = (self.x, self.y, self.width, self.height);
+                if self.num_stylus_mapping_tries < MAX_SCREEN_MAPPING_TRIES
+                    || matches!(self.stylus_mapping, Some(mapping) if mapping != geometry)
+                {
                     if let Some(x11ctx) = &mut self.x11ctx {
-                        x11ctx.map_input_device_to_entire_screen(&self.name_stylus_device, true);
+                        if x11ctx
+                            .map_input_device_to_rect(&self.name_stylus_device, true, geometry)
+                            .is_err()
+                        {
+                            self.stylus_mapping = None;
+                            self.stylus_device_mapping = None;
+                        } else {
+                            self.stylus_mapping = Some(geometry);
+                            self.stylus_device_mapping = Some(
+                                x11ctx
+                                    .input_device_mapping(&self.name_stylus_device, true)
+                                    .unwrap_or(DeviceMapping::new(geometry)),
+                            );
+                        }
                     }
-                    self.num_touch_mapping_tries += 1;
+                    self.num_stylus_mapping_tries += 1;
                 }
+                // the stylus leaves proximity once it is lifted, so do its buttons
+                let buttons = match event.event_type {
+                    PointerEventType::DOWN | PointerEventType::MOVE => event.buttons,
+                    PointerEventType::UP | PointerEventType::CANCEL => Button::NONE,
+                };
+                let button_changes = self.buttons.update(PointerType::Pen, buttons);
                 match event.event_type {
                     PointerEventType::DOWN | PointerEventType::MOVE => {
-                        if let PointerEventType::DOWN = event.event_type {
-                            self.pen_touching = true;
-                            self.send(self.stylus_fd, ET_KEY, EC_KEY_TOUCH, 1);
-                        }
-                        if !self.tool_pen_active && !event.buttons.contains(Button::ERASER) {
-                            self.send(self.stylus_fd, ET_KEY, EC_KEY_TOOL_PEN, 1);
-                            self.send(self.stylus_fd, ET_KEY, EC_KEY_TOOL_RUBBER, 0);
-                            self.tool_pen_active = true;
+                        let eraser = event.is_eraser
+                            || self.buttons.holds(PointerType::Pen, &ButtonAction::Eraser);
+                        self.set_pen_tool(Some(if eraser {
+                            PenTool::Rubber
+                        } else {
+                            PenTool::Pen
+                        }));
+                        for (action, pressed) in button_changes {
+                            self.send_button_action(&action, pressed, Some(self.stylus_fd));
                         }
-                        if let Button::ERASER = event.button {
-                            self.send(self.stylus_fd, ET_KEY, EC_KEY_TOOL_PEN, 0);
-                            self.send(self.stylus_fd, ET_KEY, EC_KEY_TOOL_RUBBER, 1);
-                            self.tool_pen_active = false;
+                        // without pressure or pressed tip the pen is only hovering
+                        let touching = event.pressure > 0.0
+                            && event.buttons.intersects(Button::PRIMARY | Button::ERASER);
+                        if touching && !self.pen_touching {
+                            // Move the hovering pen to where the contact begins in a report of
+                            // its own, otherwise the stroke starts at the previous position.
+                            self.send_pen_position(event, 0);
+                            self.send(
+                                self.stylus_fd,
+                                ET_MSC,
+                                EC_MSC_TIMESTAMP,
+                                (event.timestamp % (i32::MAX as u64 + 1)) as i32,
+                            );
+                            self.send(self.stylus_fd, ET_SYNC, EC_SYNC_REPORT, 0);
+                            self.send(self.stylus_fd, ET_KEY, EC_KEY_TOUCH, 1);
+                        } else if !touching && self.pen_touching {
+                            self.send(self.stylus_fd, ET_KEY, EC_KEY_TOUCH, 0);
                         }
-                        self.send(
-                            self.stylus_fd,
-                            ET_ABSOLUTE,
-                            EC_ABSOLUTE_X,
-                            self.transform_x(event.x),
-                        );
-                        self.send(
-                            self.stylus_fd,
-                            ET_ABSOLUTE,
-                            EC_ABSOLUTE_Y,
-                            self.transform_y(event.y),
-                        );
-                        self.send(
-                            self.stylus_fd,
-                            ET_ABSOLUTE,
-                            EC_ABSOLUTE_PRESSURE,
-                            if self.pen_touching {
-                                self.transform_pressure(event.pressure)
-                            } else {
-                                0
-                            },
-                        );
-                        self.send(
-                            self.stylus_fd,
-                            ET_ABSOLUTE,
-                            EC_ABSOLUTE_TILT_X,
-                            event.tilt_x,
-                        );
-                        self.send(
-                            self.stylus_fd,
-                            ET_ABSOLUTE,
-                            EC_ABSOLUTE_TILT_Y,
-                            event.tilt_y,
-                        );
+                        self.pen_touching = touching;
+                        let pressure = if touching {
+                            self.transform_pressure(event.pressure)
+                        } else {
+                            0
+                        };
+                        self.send_pen_position(event, pressure);
                     }
                     PointerEventType::UP | PointerEventType::CANCEL => {
+                        for (action, pressed) in button_changes {
+                            self.send_button_action(&action, pressed, Some(self.stylus_fd));
+                        }
                         self.send(self.stylus_fd, ET_KEY, EC_KEY_TOUCH, 0);
-                        self.send(self.stylus_fd, ET_KEY, EC_KEY_TOOL_PEN, 0);
-                        self.send(self.stylus_fd, ET_KEY, EC_KEY_TOOL_RUBBER, 0);
                         self.send(self.stylus_fd, ET_ABSOLUTE, EC_ABSOLUTE_PRESSURE, 0);
-                        self.tool_pen_active = false;
                         self.pen_touching = false;
+                        if let Some(tool) = self.pen_tool.take() {
+                            self.send(self.stylus_fd, ET_KEY, tool.key_code(), 0);
+                        }
                     }
                 }
                 self.send(
@@ -517,24 +1134,17 @@ impl InputDevice for UInputDevice {
                     if let Some(x11ctx) = &mut self.x11ctx {
                         x11ctx.map_input_device_to_entire_screen(&self.name_mouse_device, false);
                     }
-                    self.num_touch_mapping_tries += 1;
+                    self.num_mouse_mapping_tries += 1;
+                }
+                let buttons = match event.event_type {
+                    PointerEventType::CANCEL => Button::NONE,
+                    _ => event.buttons,
+                };
+                for (action, pressed) in self.buttons.update(event.pointer_type, buttons) {
+                    self.send_button_action(&action, pressed, Some(self.mouse_fd));
                 }
                 match event.event_type {
                     PointerEventType::DOWN | PointerEventType::MOVE => {
-                        if let PointerEventType::DOWN = event.event_type {
-                            match event.button {
-                                Button::PRIMARY => {
-                                    self.send(self.mouse_fd, ET_KEY, EC_KEY_MOUSE_LEFT, 1)
-                                }
-                                Button::SECONDARY => {
-                                    self.send(self.mouse_fd, ET_KEY, EC_KEY_MOUSE_RIGHT, 1)
-                                }
-                                Button::AUXILARY => {
-                                    self.send(self.mouse_fd, ET_KEY, EC_KEY_MOUSE_MIDDLE, 1)
-                                }
-                                _ => (),
-                            }
-                        }
                         self.send(
                             self.mouse_fd,
                             ET_ABSOLUTE,
@@ -548,16 +1158,7 @@ impl InputDevice for UInputDevice {
                             self.transform_y(event.y),
                         );
                     }
-                    PointerEventType::UP | PointerEventType::CANCEL => match event.button {
-                        Button::PRIMARY => self.send(self.mouse_fd, ET_KEY, EC_KEY_MOUSE_LEFT, 0),
-                        Button::SECONDARY => {
-                            self.send(self.mouse_fd, ET_KEY, EC_KEY_MOUSE_RIGHT, 0)
-                        }
-                        Button::AUXILARY => {
-                            self.send(self.mouse_fd, ET_KEY, EC_KEY_MOUSE_MIDDLE, 0)
-                        }
-                        _ => (),
-                    },
+                    PointerEventType::UP | PointerEventType::CANCEL => (),
                 }
                 self.send(
                     self.mouse_fd,
@@ -570,196 +1171,12 @@ impl InputDevice for UInputDevice {
         }
     }
 
-    fn send_keyboard_event(&mut self, event: &KeyboardEvent) {
+    fn send_keyboard_event(&mut self, event: &KeyboardEvent) -> Result<(), Box<dyn Error>> {
         use crate::input::uinput_keys::*;
         if let Err(err) = self.capturable.before_input() {
             warn!("Failed to activate window, sending no input ({})", err);
-            return;
-        }
-        fn map_key(code: &str, location: &KeyboardLocation) -> c_int {
-            match (code, location) {
-                ("Escape", _) => KEY_ESC,
-                ("Digit0", KeyboardLocation::NUMPAD) => KEY_KP0,
-                ("Digit1", KeyboardLocation::NUMPAD) => KEY_KP1,
-                ("Digit2", KeyboardLocation::NUMPAD) => KEY_KP2,
-                ("Digit3", KeyboardLocation::NUMPAD) => KEY_KP3,
-                ("Digit4", KeyboardLocation::NUMPAD) => KEY_KP4,
-                ("Digit5", KeyboardLocation::NUMPAD) => KEY_KP5,
-                ("Digit6", KeyboardLocation::NUMPAD) => KEY_KP6,
-                ("Digit7", KeyboardLocation::NUMPAD) => KEY_KP7,
-                ("Digit8", KeyboardLocation::NUMPAD) => KEY_KP8,
-                ("Digit9", KeyboardLocation::NUMPAD) => KEY_KP9,
-                ("Minus", KeyboardLocation::NUMPAD) => KEY_KPMINUS,
-                ("Equal", KeyboardLocation::NUMPAD) => KEY_KPEQUAL,
-                ("Enter", KeyboardLocation::NUMPAD) => KEY_KPENTER,
-                ("Digit0", _) => KEY_0,
-                ("Digit1", _) => KEY_1,
-                ("Digit2", _) => KEY_2,
-                ("Digit3", _) => KEY_3,
-                ("Digit4", _) => KEY_4,
-                ("Digit5", _) => KEY_5,
-                ("Digit6", _) => KEY_6,
-                ("Digit7", _) => KEY_7,
-                ("Digit8", _) => KEY_8,
-                ("Digit9", _) => KEY_9,
-                ("Minus", _) => KEY_MINUS,
-                ("Equal", _) => KEY_EQUAL,
-                ("Enter", _) => KEY_ENTER,
-                ("Backspace", _) => KEY_BACKSPACE,
-                ("Tab", _) => KEY_TAB,
-                ("KeyA", _) => KEY_A,
-                ("KeyB", _) => KEY_B,
-                ("KeyC", _) => KEY_C,
-                ("KeyD", _) => KEY_D,
-                ("KeyE", _) => KEY_E,
-                ("KeyF", _) => KEY_F,
-                ("KeyG", _) => KEY_G,
-                ("KeyH", _) => KEY_H,
-                ("KeyI", _) => KEY_I,
-                ("KeyJ", _) => KEY_J,
-                ("KeyK", _) => KEY_K,
-                ("KeyL", _) => KEY_L,
-                ("KeyM", _) => KEY_M,
-                ("KeyN", _) => KEY_N,
-                ("KeyO", _) => KEY_O,
-                ("KeyP", _) => KEY_P,
-                ("KeyQ", _) => KEY_Q,
-                ("KeyR", _) => KEY_R,
-                ("KeyS", _) => KEY_S,
-                ("KeyT", _) => KEY_T,
-                ("KeyU", _) => KEY_U,
-                ("KeyV", _) => KEY_V,
-                ("KeyW", _) => KEY_W,
-                ("KeyX", _) => KEY_X,
-                ("KeyY", _) => KEY_Y,
-                ("KeyZ", _) => KEY_Z,
-                ("BracketLeft", _) => KEY_LEFTBRACE,
-                ("BracketRight", _) => KEY_RIGHTBRACE,
-                ("Semicolon", _) => KEY_SEMICOLON,
-                ("Quote", _) => KEY_APOSTROPHE,
-                ("Backquote", _) => KEY_GRAVE,
-                ("Backslash", _) => KEY_BACKSLASH,
-                ("Comma", _) => KEY_COMMA,
-                ("Period", _) => KEY_DOT,
-                ("Slash", _) => KEY_SLASH,
-                ("Space", _) => KEY_SPACE,
-                ("CapsLock", _) => KEY_CAPSLOCK,
-                ("NumpadMultiply", _) => KEY_KPASTERISK,
-                ("F1", _) => KEY_F1,
-                ("F2", _) => KEY_F2,
-                ("F3", _) => KEY_F3,
-                ("F4", _) => KEY_F4,
-                ("F5", _) => KEY_F5,
-                ("F6", _) => KEY_F6,
-                ("F7", _) => KEY_F7,
-                ("F8", _) => KEY_F8,
-                ("F9", _) => KEY_F9,
-                ("F10", _) => KEY_F10,
-                ("F11", _) => KEY_F11,
-                ("F12", _) => KEY_F12,
-                ("F13", _) => KEY_F13,
-                ("F14", _) => KEY_F14,
-                ("F15", _) => KEY_F15,
-                ("F16", _) => KEY_F16,
-                ("F17", _) => KEY_F17,
-                ("F18", _) => KEY_F18,
-                ("F19", _) => KEY_F19,
-                ("F20", _) => KEY_F20,
-                ("F21", _) => KEY_F21,
-                ("F22", _) => KEY_F22,
-                ("F23", _) => KEY_F23,
-                ("F24", _) => KEY_F24,
-                ("NumLock", _) => KEY_NUMLOCK,
-                ("ScrollLock", _) => KEY_SCROLLLOCK,
-                ("Numpad0", _) => KEY_KP0,
-                ("Numpad1", _) => KEY_KP1,
-                ("Numpad2", _) => KEY_KP2,
-                ("Numpad3", _) => KEY_KP3,
-                ("Numpad4", _) => KEY_KP4,
-                ("Numpad5", _) => KEY_KP5,
-                ("Numpad6", _) => KEY_KP6,
-                ("Numpad7", _) => KEY_KP7,
-                ("Numpad8", _) => KEY_KP8,
-                ("Numpad9", _) => KEY_KP9,
-                ("NumpadSubtract", _) => KEY_KPMINUS,
-                ("NumpadAdd", _) => KEY_KPPLUS,
-                // ("NumpadDecimal", _) => ?,
-                ("IntlBackslash", _) => KEY_102ND,
-                ("IntlRo", _) => KEY_RO,
-                ("NumpadEnter", _) => KEY_KPENTER,
-                ("NumpadDivide", _) => KEY_KPSLASH,
-                ("NumpadEqual", _) => KEY_KPEQUAL,
-                ("NumpadComma", _) => KEY_KPCOMMA,
-                ("NumpadParenLeft", _) => KEY_KPLEFTPAREN,
-                ("NumpadParenRight", _) => KEY_KPRIGHTPAREN,
-                // ("NumpadChangeSign", _) => ?,
-                // ("Convert", _) => ?,
-                ("KanaMode", _) => KEY_KATAKANA,
-                // ("NonConvert", _) => ?,
-                ("PrintScreen", _) => KEY_SYSRQ,
-                ("Home", _) => KEY_HOME,
-                ("ArrowUp", _) => KEY_UP,
-                ("PageUp", _) => KEY_PAGEUP,
-                ("ArrowLeft", _) => KEY_LEFT,
-                ("ArrowRight", _) => KEY_RIGHT,
-                ("End", _) => KEY_END,
-                ("ArrowDown", _) => KEY_DOWN,
-                ("PageDown", _) => KEY_PAGEDOWN,
-                ("Insert", _) => KEY_INSERT,
-                ("Delete", _) => KEY_DELETE,
-                ("VolumeMute", _) | ("AudioVolumeMute", _) => KEY_MUTE,
-                ("VolumeDown", _) | ("AudioVolumeDown", _) => KEY_VOLUMEDOWN,
-                ("VolumeUp", _) | ("AudioVolumeUp", _) => KEY_VOLUMEUP,
-                ("Pause", _) => KEY_PAUSE,
-
-                ("Lang1", _) => KEY_HANGUEL,
-                ("Lang2", _) => KEY_HANJA,
-                ("IntlYen", _) => KEY_YEN,
-                ("OSLeft", _) => KEY_LEFTMETA,
-                ("OSRight", _) => KEY_RIGHTMETA,
-                ("ContextMenu", _) => KEY_MENU,
-                // ("BrowserStop", _) => ?,
-                ("Cancel", _) => KEY_CANCEL,
-                ("Again", _) => KEY_AGAIN,
-                ("Props", _) => KEY_PROPS,
-                ("Undo", _) => KEY_UNDO,
-                // ("Select", _) => ?,
-                ("Copy", _) => KEY_COPY,
-                ("Open", _) => KEY_OPEN,
-                ("Paste", _) => KEY_PASTE,
-                ("Find", _) => KEY_FIND,
-                ("Cut", _) => KEY_CUT,
-                ("Help", _) => KEY_HELP,
-                // ("LaunchApp2", _) => ?,
-                // ("LaunchApp1", _) => ,
-                ("LaunchMail", _) => KEY_MAIL,
-                // ("BrowserFavorites", _) => ?,
-                // ("BrowserBack", _) => ?,
-                // ("BrowserForward", _) => ?,
-                ("Eject", _) => KEY_EJECTCD,
-                ("MediaTrackNext", _) => KEY_NEXTSONG,
-                ("MediaPlayPause", _) => KEY_PLAYPAUSE,
-                ("MediaTrackPrevious", _) => KEY_PREVIOUSSONG,
-                ("MediaStop", _) => KEY_STOPCD,
-                ("MediaSelect", _) | ("LaunchMediaPlayer", _) => KEY_MEDIA,
-                // ("BrowserHome", _) => ?,
-                // ("BrowserRefresh", _) => ?,
-                // ("BrowserSearch", _) => ?,
-                ("Power", _) => KEY_POWER,
-                ("Sleep", _) => KEY_SLEEP,
-                ("WakeUp", _) => KEY_WAKEUP,
-                ("ControlLeft", _) => KEY_LEFTCTRL,
-                ("ControlRight", _) => KEY_RIGHTCTRL,
-                ("AltLeft", _) => KEY_LEFTALT,
-                ("AltRight", _) => KEY_RIGHTALT,
-                ("MetaLeft", _) => KEY_LEFTMETA,
-                ("MetaRight", _) => KEY_RIGHTMETA,
-                ("ShiftLeft", _) => KEY_LEFTSHIFT,
-                ("ShiftRight", _) => KEY_RIGHTSHIFT,
-                _ => KEY_UNKNOWN,
-            }
+            return Ok(());
         }
-
         let key_code: c_int = map_key(&event.code, &event.location);
         let state: c_int = match event.event_type {
             KeyboardEventType::UP => 0,
@@ -768,51 +1185,52 @@ impl InputDevice for UInputDevice {
         };
 
         if key_code == KEY_UNKNOWN {
+            if event.key.is_empty() {
+                return Err(format!("Unknown key with code: {}", event.code).into());
+            }
             if let KeyboardEventType::DOWN = event.event_type {
-                if !event.key.is_empty() {
-                    // If the key is unknow try inserting the unicode character directly
-                    // to do so use CTRL + SHIFT + U + UTF16 HEX of the unicode point.
-                    let unicode_keys = event
-                        .key
-                        .encode_utf16()
-                        .map(|b| format!("{:X}", b))
-                        .collect::<Vec<String>>()
-                        .concat();
-
-                    debug!(
-                        "Got unknown key: {} code: {}, trying to insert unicode using ctrl + \
-                        shift + u + {}!",
-                        event.code, event.key, unicode_keys
-                    );
-
-                    self.send(self.keyboard_fd, ET_KEY, KEY_LEFTCTRL, 1);
-                    self.send(self.keyboard_fd, ET_KEY, KEY_LEFTSHIFT, 1);
-                    self.send(self.keyboard_fd, ET_KEY, KEY_U, 1);
-                    self.send(self.keyboard_fd, ET_SYNC, EC_SYNC_REPORT, 0);
-                    for c in unicode_keys.chars() {
-                        let key_code = if c.is_alphabetic() {
-                            map_key(&format!("Key{}", c), &KeyboardLocation::STANDARD)
-                        } else {
-                            map_key(&format!("Digit{}", c), &KeyboardLocation::STANDARD)
-                        };
+                // If the key is unknow try inserting the unicode character directly
+                // to do so use CTRL + SHIFT + U + UTF16 HEX of the unicode point.
+                let unicode_keys = event
+                    .key
+                    .encode_utf16()
+                    .map(|b| format!("{:X}", b))
+                    .collect::<Vec<String>>()
+                    .concat();
 
-                        self.send(self.keyboard_fd, ET_KEY, key_code, 1);
-                        self.send(self.keyboard_fd, ET_SYNC, EC_SYNC_REPORT, 0);
-                        self.send(self.keyboard_fd, ET_KEY, key_code, 0);
-                        self.send(self.keyboard_fd, ET_SYNC, EC_SYNC_REPORT, 0);
-                    }
-                    self.send(self.keyboard_fd, ET_KEY, KEY_LEFTCTRL, 0);
-                    self.send(self.keyboard_fd, ET_KEY, KEY_LEFTSHIFT, 0);
-                    self.send(self.keyboard_fd, ET_KEY, KEY_U, 0);
+                debug!(
+                    "Got unknown key: {} code: {}, trying to insert unicode using ctrl + \
+                    shift + u + {}!",
+                    event.code, event.key, unicode_keys
+                );
+
+                self.send(self.keyboard_fd, ET_KEY, KEY_LEFTCTRL, 1);
+                self.send(self.keyboard_fd, ET_KEY, KEY_LEFTSHIFT, 1);
+                self.send(self.keyboard_fd, ET_KEY, KEY_U, 1);
+                self.send(self.keyboard_fd, ET_SYNC, EC_SYNC_REPORT, 0);
+                for c in unicode_keys.chars() {
+                    let key_code = if c.is_alphabetic() {
+                        map_key(&format!("Key{}", c), &KeyboardLocation::STANDARD)
+                    } else {
+                        map_key(&format!("Digit{}", c), &KeyboardLocation::STANDARD)
+                    };
+
+                    self.send(self.keyboard_fd, ET_KEY, key_code, 1);
+                    self.send(self.keyboard_fd, ET_SYNC, EC_SYNC_REPORT, 0);
+                    self.send(self.keyboard_fd, ET_KEY, key_code, 0);
                     self.send(self.keyboard_fd, ET_SYNC, EC_SYNC_REPORT, 0);
                 }
+                self.send(self.keyboard_fd, ET_KEY, KEY_LEFTCTRL, 0);
+                self.send(self.keyboard_fd, ET_KEY, KEY_LEFTSHIFT, 0);
+                self.send(self.keyboard_fd, ET_KEY, KEY_U, 0);
+                self.send(self.keyboard_fd, ET_SYNC, EC_SYNC_REPORT, 0);
             } else {
                 debug!(
                     "Got unknow key: code: {} key: {}, ignoring event.",
                     event.code, event.key
                 );
             }
-            return;
+            return Ok(());
         }
 
         if event.ctrl {
@@ -834,6 +1252,116 @@ impl InputDevice for UInputDevice {
 
         self.send(self.keyboard_fd, ET_KEY, key_code, state);
         self.send(self.keyboard_fd, ET_SYNC, EC_SYNC_REPORT, 0);
+        match event.event_type {
+            KeyboardEventType::DOWN if !self.pressed_keys.contains(&key_code) => {
+                self.pressed_keys.push(key_code)
+            }
+            KeyboardEventType::UP => self.pressed_keys.retain(|k| *k != key_code),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn send_relative_motion(&mut self, dx: i32, dy: i32) {
+        let Some(fd) = self.relative_mouse_fd else {
+            debug!("No relative mouse, dropping motion.");
+            return;
+        };
+        if let Err(err) = self.capturable.before_input() {
+            warn!("Failed to activate window, sending no input ({})", err);
+            return;
+        }
+        self.send(fd, ET_RELATIVE, EC_RELATIVE_X, dx);
+        self.send(fd, ET_RELATIVE, EC_RELATIVE_Y, dy);
+        self.send(fd, ET_SYNC, EC_SYNC_REPORT, 0);
+    }
+
+    fn send_mouse_button(&mut self, button: Button, pressed: bool) {
+        let code = match button {
+            Button::PRIMARY => EC_KEY_MOUSE_LEFT,
+            Button::SECONDARY => EC_KEY_MOUSE_RIGHT,
+            Button::AUXILARY => EC_KEY_MOUSE_MIDDLE,
+            _ => return,
+        };
+        if let Err(err) = self.capturable.before_input() {
+            warn!("Failed to activate window, sending no input ({})", err);
+            return;
+        }
+        let fd = self.relative_mouse_fd.unwrap_or(self.mouse_fd);
+        self.send(fd, ET_KEY, code, pressed as c_int);
+        self.send(fd, ET_SYNC, EC_SYNC_REPORT, 0);
+    }
+
+    fn set_pointer_mode(&mut self, mode: PointerMode) -> Result<(), Box<dyn Error>> {
+        match (mode, self.relative_mouse_fd) {
+            (PointerMode::Relative, None) => {
+                let name = CString::new(self.name_relative_mouse_device.as_bytes()).unwrap();
+                let mut err = CError::new();
+                let fd = unsafe { init_uinput_relative_mouse(name.as_ptr(), &mut err) };
+                if err.is_err() {
+                    return Err(err.into());
+                }
+                debug!("Created {}.", self.name_relative_mouse_device);
+                self.relative_mouse_fd = Some(fd);
+            }
+            (PointerMode::Absolute, Some(fd)) => {
+                unsafe { destroy_uinput_device(fd) };
+                self.relative_mouse_fd = None;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn set_button_mapping(&mut self, mapping: &[ButtonMapping]) {
+        for action in self.buttons.set_mapping(mapping.to_vec()) {
+            self.send_button_action(&action, false, None);
+        }
+    }
+
+    fn release_all(&mut self) {
+        use crate::input::uinput_keys::*;
+
+        let touches = std::mem::take(&mut self.touches);
+        let count = touches.iter().flatten().count();
+        if count > 0 {
+            for (slot, touch) in touches.iter().enumerate() {
+                if touch.is_some() {
+                    self.send(self.touch_fd, ET_ABSOLUTE, EC_ABS_MT_SLOT, slot as i32);
+                    self.send(self.touch_fd, ET_ABSOLUTE, EC_ABS_MT_TRACKING_ID, -1);
+                }
+            }
+            self.send_touch_count(count, 0);
+            self.send(self.touch_fd, ET_SYNC, EC_SYNC_REPORT, 0);
+        }
+
+        // mapped buttons may press keys or buttons of the stylus, which the rest does not cover
+        for action in self.buttons.release_all() {
+            self.send_button_action(&action, false, None);
+        }
+
+        // lifts the stylus as well if it is touching the screen
+        self.set_pen_tool(None);
+        self.pen_touching = false;
+
+        // the kernel drops events that do not change the state of a key, so all buttons can be
+        // released no matter which ones are down
+        for fd in std::iter::once(self.mouse_fd).chain(self.relative_mouse_fd) {
+            for button in [EC_KEY_MOUSE_LEFT, EC_KEY_MOUSE_RIGHT, EC_KEY_MOUSE_MIDDLE] {
+                self.send(fd, ET_KEY, button, 0);
+            }
+            self.send(fd, ET_SYNC, EC_SYNC_REPORT, 0);
+        }
+
+        // modifiers are pressed along with other keys and not tracked on their own
+        let modifiers = [KEY_LEFTCTRL, KEY_LEFTALT, KEY_LEFTMETA, KEY_LEFTSHIFT];
+        for key in std::mem::take(&mut self.pressed_keys)
+            .into_iter()
+            .chain(modifiers)
+        {
+            self.send(self.keyboard_fd, ET_KEY, key, 0);
+        }
+        self.send(self.keyboard_fd, ET_SYNC, EC_SYNC_REPORT, 0);
     }
 
     fn set_capturable(&mut self, capturable: Box<dyn Capturable>) {
@@ -844,3 +1372,214 @@ impl InputDevice for UInputDevice {
         InputDeviceType::UInputDevice
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::os::fd::IntoRawFd;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn pen_pressure_quantization() {
+        assert_eq!(pressure_value(0.0, 8191), 0);
+        assert_eq!(pressure_value(-0.5, 8191), 0);
+        assert_eq!(pressure_value(f64::NAN, 8191), 0);
+        assert_eq!(pressure_value(1.0, 8191), 8191);
+        assert_eq!(pressure_value(1.5, 8191), 8191);
+        // rounded instead of truncated
+        assert_eq!(pressure_value(0.5, 8191), 4096);
+        assert_eq!(pressure_value(0.9999, 100), 100);
+        assert_eq!(pressure_value(0.0049, 100), 1);
+        // the lightest touch does not become a pen touching without pressure
+        assert_eq!(pressure_value(1e-9, 65535), 1);
+        assert_eq!(pressure_value(f64::MIN_POSITIVE, 1), 1);
+        // every step of the range is hit by the pressure it stands for
+        for max in [1, 255, 8191, 65535] {
+            for value in (1..=max).step_by(max as usize / 200 + 1) {
+                assert_eq!(pressure_value(value as f64 / max as f64, max), value);
+            }
+        }
+    }
+
+    // pixel of a screen of the given size a position within a capturable ends up on
+    fn hit_pixel(
+        geometry: Geometry,
+        (x, y): (f64, f64),
+        (screen_width, screen_height): (f64, f64),
+    ) -> (i64, i64) {
+        let (area_x, area_y, width, height) = input_area(&geometry).unwrap();
+        let abs_x = abs_value(x, area_x, width) as f64;
+        let abs_y = abs_value(y, area_y, height) as f64;
+        (
+            (abs_x / ABS_MAX * screen_width).round() as i64,
+            (abs_y / ABS_MAX * screen_height).round() as i64,
+        )
+    }
+
+    #[test]
+    fn scaled_outputs_map_to_the_pixels_they_cover() {
+        let screen = (3200.0, 1800.0);
+        // a 1280x720 mode at +640+360 scaled by 1.25 covers 1600x900 pixels of the screen
+        let monitor = Geometry::Relative(0.2, 0.2, 0.4, 0.4, 1.25, 1.25);
+        assert_eq!(hit_pixel(monitor.clone(), (0.0, 0.0), screen), (640, 360));
+        assert_eq!(hit_pixel(monitor.clone(), (0.5, 0.5), screen), (1440, 810));
+        assert_eq!(hit_pixel(monitor, (1.0, 1.0), screen), (2240, 1260));
+        // a 800x450 mode at +1600+900 scaled by 2
+        let monitor = Geometry::Relative(0.5, 0.5, 0.25, 0.25, 2.0, 2.0);
+        assert_eq!(hit_pixel(monitor.clone(), (0.0, 0.0), screen), (1600, 900));
+        assert_eq!(
+            hit_pixel(monitor.clone(), (0.25, 0.75), screen),
+            (2000, 1575)
+        );
+        assert_eq!(hit_pixel(monitor, (1.0, 1.0), screen), (3200, 1800));
+        // an unscaled window
+        let window = Geometry::Relative(0.25, 0.5, 0.5, 0.25, 1.0, 1.0);
+        assert_eq!(hit_pixel(window, (0.5, 0.5), screen), (1600, 1125));
+        assert!(input_area(&Geometry::VirtualScreen(0, 0, 1920, 1080, 0, 0)).is_none());
+    }
+
+    pub(crate) type Sent = Arc<Mutex<Vec<(c_int, c_int, c_int, c_int)>>>;
+
+    /// Devices that only pretend to exist, they are /dev/null and destroying them just closes
+    /// them.
+    pub(crate) fn null_handles(id: &Option<String>, pressure_max: i32) -> UInputHandles {
+        let null = || {
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open("/dev/null")
+                .unwrap()
+                .into_raw_fd()
+        };
+        UInputHandles {
+            keyboard_fd: null(),
+            stylus_fd: null(),
+            mouse_fd: null(),
+            touch_fd: null(),
+            missing_devices: Vec::new(),
+            id: id.clone(),
+            pressure_max,
+        }
+    }
+
+    pub(crate) fn test_capturable() -> Box<dyn Capturable> {
+        Box::new(crate::capturable::testsrc::TestCapturable {
+            width: 100,
+            height: 100,
+        })
+    }
+
+    // what is sent through the device is recorded, even after it has been dropped
+    fn recording_device(handles: UInputHandles, pool: Option<UInputPool>) -> (UInputDevice, Sent) {
+        let mut device = UInputDevice::from_handles(handles, test_capturable(), pool);
+        let sent = Sent::default();
+        device.sent = Some(sent.clone());
+        (device, sent)
+    }
+
+    // what has been sent since the last call
+    fn take_sent(sent: &Sent) -> Vec<(c_int, c_int, c_int, c_int)> {
+        std::mem::take(&mut *sent.lock().unwrap())
+    }
+
+    fn on(sent: &[(c_int, c_int, c_int, c_int)], fd: c_int) -> Vec<(c_int, c_int, c_int)> {
+        sent.iter()
+            .filter(|event| event.0 == fd)
+            .map(|&(_, typ, code, value)| (typ, code, value))
+            .collect()
+    }
+
+    // the client is gone with two fingers and the stylus on the screen
+    #[test]
+    fn release_all_lifts_touches_and_the_stylus() {
+        let (mut device, sent) = recording_device(null_handles(&None, DEFAULT_PRESSURE_MAX), None);
+        let (stylus, mouse, touch, keyboard) = (
+            device.stylus_fd,
+            device.mouse_fd,
+            device.touch_fd,
+            device.keyboard_fd,
+        );
+        device.touches[0] = Some(MultiTouch { id: 1 });
+        device.touches[3] = Some(MultiTouch { id: 4 });
+        device.pen_tool = Some(PenTool::Pen);
+        device.pen_touching = true;
+
+        device.release_all();
+        let released = take_sent(&sent);
+        assert_eq!(
+            on(&released, touch),
+            [
+                (ET_ABSOLUTE, EC_ABS_MT_SLOT, 0),
+                (ET_ABSOLUTE, EC_ABS_MT_TRACKING_ID, -1),
+                (ET_ABSOLUTE, EC_ABS_MT_SLOT, 3),
+                (ET_ABSOLUTE, EC_ABS_MT_TRACKING_ID, -1),
+                (ET_KEY, EC_KEY_TOOL_DOUBLETAP, 0),
+                (ET_KEY, EC_KEY_TOUCH, 0),
+                (ET_SYNC, EC_SYNC_REPORT, 0),
+            ]
+        );
+        assert_eq!(
+            on(&released, stylus),
+            [
+                (ET_KEY, EC_KEY_TOUCH, 0),
+                (ET_ABSOLUTE, EC_ABSOLUTE_PRESSURE, 0),
+                (ET_KEY, EC_KEY_TOOL_PEN, 0),
+                (ET_SYNC, EC_SYNC_REPORT, 0),
+            ]
+        );
+        assert_eq!(
+            on(&released, mouse),
+            [
+                (ET_KEY, EC_KEY_MOUSE_LEFT, 0),
+                (ET_KEY, EC_KEY_MOUSE_RIGHT, 0),
+                (ET_KEY, EC_KEY_MOUSE_MIDDLE, 0),
+                (ET_SYNC, EC_SYNC_REPORT, 0),
+            ]
+        );
+        assert_eq!(
+            on(&released, keyboard).last(),
+            Some(&(ET_SYNC, EC_SYNC_REPORT, 0))
+        );
+
+        // nothing is down anymore
+        device.release_all();
+        let released = take_sent(&sent);
+        assert!(on(&released, touch).is_empty());
+        assert!(on(&released, stylus).is_empty());
+    }
+
+    // the next client must not find the mouse button and the key of this one still down
+    #[test]
+    fn devices_return_to_the_pool_with_nothing_pressed() {
+        let pool = UInputPool::new();
+        let (mut device, sent) = recording_device(
+            null_handles(&None, DEFAULT_PRESSURE_MAX),
+            Some(pool.clone()),
+        );
+        let (mouse, keyboard) = (device.mouse_fd, device.keyboard_fd);
+        device.send_mouse_button(Button::PRIMARY, true);
+        device
+            .send_keyboard_event(&KeyboardEvent {
+                event_type: KeyboardEventType::DOWN,
+                code: "KeyA".into(),
+                key: "a".into(),
+                location: KeyboardLocation::STANDARD,
+                alt: false,
+                ctrl: false,
+                shift: false,
+                meta: false,
+            })
+            .unwrap();
+        take_sent(&sent);
+
+        drop(device);
+        let released = take_sent(&sent);
+        assert!(on(&released, mouse).contains(&(ET_KEY, EC_KEY_MOUSE_LEFT, 0)));
+        assert!(on(&released, keyboard).contains(&(ET_KEY, crate::input::uinput_keys::KEY_A, 0)));
+
+        // the same devices are handed out again
+        let reused = pool
+            .device(test_capturable(), &None, DEFAULT_PRESSURE_MAX)
+            .unwrap();
+        assert_eq!((reused.mouse_fd, reused.keyboard_fd), (mouse, keyboard));
+    }
+}
@@ -1,5 +1,7 @@
 use std::os::raw::c_int;
 
+use crate::protocol::KeyboardLocation;
+
 pub const KEY_ESC: c_int = 1;
 pub const KEY_1: c_int = 2;
 pub const KEY_2: c_int = 3;
@@ -260,3 +262,189 @@ pub const KEY_WIMAX: c_int = KEY_WWAN;
 pub const KEY_RFKILL: c_int = 247; /* Key that controls all radios */
 
 pub const KEY_MICMUTE: c_int = 248; /* Mute / unmute the microphone */
+
+/// Map a DOM `KeyboardEvent.code` to the corresponding Linux KEY_* constant, returns KEY_UNKNOWN
+/// if there is no such key.
+pub fn map_key(code: &str, location: &KeyboardLocation) -> c_int {
+    match (code, location) {
+        ("Escape", _) => KEY_ESC,
+        ("Digit0", KeyboardLocation::NUMPAD) => KEY_KP0,
+        ("Digit1", KeyboardLocation::NUMPAD) => KEY_KP1,
+        ("Digit2", KeyboardLocation::NUMPAD) => KEY_KP2,
+        ("Digit3", KeyboardLocation::NUMPAD) => KEY_KP3,
+        ("Digit4", KeyboardLocation::NUMPAD) => KEY_KP4,
+        ("Digit5", KeyboardLocation::NUMPAD) => KEY_KP5,
+        ("Digit6", KeyboardLocation::NUMPAD) => KEY_KP6,
+        ("Digit7", KeyboardLocation::NUMPAD) => KEY_KP7,
+        ("Digit8", KeyboardLocation::NUMPAD) => KEY_KP8,
+        ("Digit9", KeyboardLocation::NUMPAD) => KEY_KP9,
+        ("Minus", KeyboardLocation::NUMPAD) => KEY_KPMINUS,
+        ("Equal", KeyboardLocation::NUMPAD) => KEY_KPEQUAL,
+        ("Enter", KeyboardLocation::NUMPAD) => KEY_KPENTER,
+        ("Digit0", _) => KEY_0,
+        ("Digit1", _) => KEY_1,
+        ("Digit2", _) => KEY_2,
+        ("Digit3", _) => KEY_3,
+        ("Digit4", _) => KEY_4,
+        ("Digit5", _) => KEY_5,
+        ("Digit6", _) => KEY_6,
+        ("Digit7", _) => KEY_7,
+        ("Digit8", _) => KEY_8,
+        ("Digit9", _) => KEY_9,
+        ("Minus", _) => KEY_MINUS,
+        ("Equal", _) => KEY_EQUAL,
+        ("Enter", _) => KEY_ENTER,
+        ("Backspace", _) => KEY_BACKSPACE,
+        ("Tab", _) => KEY_TAB,
+        ("KeyA", _) => KEY_A,
+        ("KeyB", _) => KEY_B,
+        ("KeyC", _) => KEY_C,
+        ("KeyD", _) => KEY_D,
+        ("KeyE", _) => KEY_E,
+        ("KeyF", _) => KEY_F,
+        ("KeyG", _) => KEY_G,
+        ("KeyH", _) => KEY_H,
+        ("KeyI", _) => KEY_I,
+        ("KeyJ", _) => KEY_J,
+        ("KeyK", _) => KEY_K,
+        ("KeyL", _) => KEY_L,
+        ("KeyM", _) => KEY_M,
+        ("KeyN", _) => KEY_N,
+        ("KeyO", _) => KEY_O,
+        ("KeyP", _) => KEY_P,
+        ("KeyQ", _) => KEY_Q,
+        ("KeyR", _) => KEY_R,
+        ("KeyS", _) => KEY_S,
+        ("KeyT", _) => KEY_T,
+        ("KeyU", _) => KEY_U,
+        ("KeyV", _) => KEY_V,
+        ("KeyW", _) => KEY_W,
+        ("KeyX", _) => KEY_X,
+        ("KeyY", _) => KEY_Y,
+        ("KeyZ", _) => KEY_Z,
+        ("BracketLeft", _) => KEY_LEFTBRACE,
+        ("BracketRight", _) => KEY_RIGHTBRACE,
+        ("Semicolon", _) => KEY_SEMICOLON,
+        ("Quote", _) => KEY_APOSTROPHE,
+        ("Backquote", _) => KEY_GRAVE,
+        ("Backslash", _) => KEY_BACKSLASH,
+        ("Comma", _) => KEY_COMMA,
+        ("Period", _) => KEY_DOT,
+        ("Slash", _) => KEY_SLASH,
+        ("Space", _) => KEY_SPACE,
+        ("CapsLock", _) => KEY_CAPSLOCK,
+        ("NumpadMultiply", _) => KEY_KPASTERISK,
+        ("F1", _) => KEY_F1,
+        ("F2", _) => KEY_F2,
+        ("F3", _) => KEY_F3,
+        ("F4", _) => KEY_F4,
+        ("F5", _) => KEY_F5,
+        ("F6", _) => KEY_F6,
+        ("F7", _) => KEY_F7,
+        ("F8", _) => KEY_F8,
+        ("F9", _) => KEY_F9,
+        ("F10", _) => KEY_F10,
+        ("F11", _) => KEY_F11,
+        ("F12", _) => KEY_F12,
+        ("F13", _) => KEY_F13,
+        ("F14", _) => KEY_F14,
+        ("F15", _) => KEY_F15,
+        ("F16", _) => KEY_F16,
+        ("F17", _) => KEY_F17,
+        ("F18", _) => KEY_F18,
+        ("F19", _) => KEY_F19,
+        ("F20", _) => KEY_F20,
+        ("F21", _) => KEY_F21,
+        ("F22", _) => KEY_F22,
+        ("F23", _) => KEY_F23,
+        ("F24", _) => KEY_F24,
+        ("NumLock", _) => KEY_NUMLOCK,
+        ("ScrollLock", _) => KEY_SCROLLLOCK,
+        ("Numpad0", _) => KEY_KP0,
+        ("Numpad1", _) => KEY_KP1,
+        ("Numpad2", _) => KEY_KP2,
+        ("Numpad3", _) => KEY_KP3,
+        ("Numpad4", _) => KEY_KP4,
+        ("Numpad5", _) => KEY_KP5,
+        ("Numpad6", _) => KEY_KP6,
+        ("Numpad7", _) => KEY_KP7,
+        ("Numpad8", _) => KEY_KP8,
+        ("Numpad9", _) => KEY_KP9,
+        ("NumpadSubtract", _) => KEY_KPMINUS,
+        ("NumpadAdd", _) => KEY_KPPLUS,
+        // ("NumpadDecimal", _) => ?,
+        ("IntlBackslash", _) => KEY_102ND,
+        ("IntlRo", _) => KEY_RO,
+        ("NumpadEnter", _) => KEY_KPENTER,
+        ("NumpadDivide", _) => KEY_KPSLASH,
+        ("NumpadEqual", _) => KEY_KPEQUAL,
+        ("NumpadComma", _) => KEY_KPCOMMA,
+        ("NumpadParenLeft", _) => KEY_KPLEFTPAREN,
+        ("NumpadParenRight", _) => KEY_KPRIGHTPAREN,
+        // ("NumpadChangeSign", _) => ?,
+        // ("Convert", _) => ?,
+        ("KanaMode", _) => KEY_KATAKANA,
+        // ("NonConvert", _) => ?,
+        ("PrintScreen", _) => KEY_SYSRQ,
+        ("Home", _) => KEY_HOME,
+        ("ArrowUp", _) => KEY_UP,
+        ("PageUp", _) => KEY_PAGEUP,
+        ("ArrowLeft", _) => KEY_LEFT,
+        ("ArrowRight", _) => KEY_RIGHT,
+        ("End", _) => KEY_END,
+        ("ArrowDown", _) => KEY_DOWN,
+        ("PageDown", _) => KEY_PAGEDOWN,
+        ("Insert", _) => KEY_INSERT,
+        ("Delete", _) => KEY_DELETE,
+        ("VolumeMute", _) | ("AudioVolumeMute", _) => KEY_MUTE,
+        ("VolumeDown", _) | ("AudioVolumeDown", _) => KEY_VOLUMEDOWN,
+        ("VolumeUp", _) | ("AudioVolumeUp", _) => KEY_VOLUMEUP,
+        ("Pause", _) => KEY_PAUSE,
+
+        ("Lang1", _) => KEY_HANGUEL,
+        ("Lang2", _) => KEY_HANJA,
+        ("IntlYen", _) => KEY_YEN,
+        ("OSLeft", _) => KEY_LEFTMETA,
+        ("OSRight", _) => KEY_RIGHTMETA,
+        ("ContextMenu", _) => KEY_MENU,
+        // ("BrowserStop", _) => ?,
+        ("Cancel", _) => KEY_CANCEL,
+        ("Again", _) => KEY_AGAIN,
+        ("Props", _) => KEY_PROPS,
+        ("Undo", _) => KEY_UNDO,
+        // ("Select", _) => ?,
+        ("Copy", _) => KEY_COPY,
+        ("Open", _) => KEY_OPEN,
+        ("Paste", _) => KEY_PASTE,
+        ("Find", _) => KEY_FIND,
+        ("Cut", _) => KEY_CUT,
+        ("Help", _) => KEY_HELP,
+        // ("LaunchApp2", _) => ?,
+        // ("LaunchApp1", _) => ,
+        ("LaunchMail", _) => KEY_MAIL,
+        // ("BrowserFavorites", _) => ?,
+        // ("BrowserBack", _) => ?,
+        // ("BrowserForward", _) => ?,
+        ("Eject", _) => KEY_EJECTCD,
+        ("MediaTrackNext", _) => KEY_NEXTSONG,
+        ("MediaPlayPause", _) => KEY_PLAYPAUSE,
+        ("MediaTrackPrevious", _) => KEY_PREVIOUSSONG,
+        ("MediaStop", _) => KEY_STOPCD,
+        ("MediaSelect", _) | ("LaunchMediaPlayer", _) => KEY_MEDIA,
+        // ("BrowserHome", _) => ?,
+        // ("BrowserRefresh", _) => ?,
+        // ("BrowserSearch", _) => ?,
+        ("Power", _) => KEY_POWER,
+        ("Sleep", _) => KEY_SLEEP,
+        ("WakeUp", _) => KEY_WAKEUP,
+        ("ControlLeft", _) => KEY_LEFTCTRL,
+        ("ControlRight", _) => KEY_RIGHTCTRL,
+        ("AltLeft", _) => KEY_LEFTALT,
+        ("AltRight", _) => KEY_RIGHTALT,
+        ("MetaLeft", _) => KEY_LEFTMETA,
+        ("MetaRight", _) => KEY_RIGHTMETA,
+        ("ShiftLeft", _) => KEY_LEFTSHIFT,
+        ("ShiftRight", _) => KEY_RIGHTSHIFT,
+        _ => KEY_UNKNOWN,
+    }
+}
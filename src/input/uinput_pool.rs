@@ -0,0 +1,173 @@
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+
+use crate::capturable::Capturable;
+use crate::cerror::CError;
+use crate::input::uinput_device::{UInputDevice, UInputHandles};
+
+// devices nobody used for this long are destroyed
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const REAP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// uinput devices no client is using right now. Every new device is enumerated again by libinput
+/// and X, which moves the cursor and has GNOME show notifications, so devices are kept around for
+/// clients that reconnect, change their configuration or switch back to uinput. Devices are
+/// reused if they have been created for the same client name and pressure range. Clones share the
+/// same devices, which are destroyed once they have been idle for IDLE_TIMEOUT, on clear or once
+/// the last clone is gone.
+#[derive(Clone, Default)]
+pub struct UInputPool {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    idle: Vec<(UInputHandles, Instant)>,
+    // a thread destroys devices that have been idle for too long
+    reaping: bool,
+}
+
+impl Inner {
+    // destroy devices that have been idle for IDLE_TIMEOUT at the given time
+    fn reap(&mut self, now: Instant) {
+        let count = self.idle.len();
+        self.idle
+            .retain(|(_, since)| now.saturating_duration_since(*since) < IDLE_TIMEOUT);
+        if self.idle.len() < count {
+            debug!(
+                "Destroyed the idle uinput devices of {} clients.",
+                count - self.idle.len()
+            );
+        }
+    }
+}
+
+impl UInputPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Devices for the client with the given id, idle ones are reused if they match. The devices
+    /// return to the pool once the returned device is dropped.
+    pub fn device(
+        &self,
+        capturable: Box<dyn Capturable>,
+        id: &Option<String>,
+        pressure_max: i32,
+    ) -> Result<UInputDevice, CError> {
+        let handles = match self.take(id, pressure_max) {
+            Some(handles) => {
+                debug!("Reusing idle uinput devices.");
+                handles
+            }
+            None => UInputHandles::new(id, pressure_max)?,
+        };
+        Ok(UInputDevice::from_handles(
+            handles,
+            capturable,
+            Some(self.clone()),
+        ))
+    }
+
+    // idle devices that match, they are no longer idle then
+    fn take(&self, id: &Option<String>, pressure_max: i32) -> Option<UInputHandles> {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .idle
+            .iter()
+            .position(|(handles, _)| handles.matches(id, pressure_max))
+            .map(|i| inner.idle.swap_remove(i).0)
+    }
+
+    /// Keep devices around for reuse, nothing may be pressed on them.
+    pub fn put(&self, handles: UInputHandles) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.idle.push((handles, Instant::now()));
+        if !inner.reaping {
+            inner.reaping = true;
+            let inner = Arc::downgrade(&self.inner);
+            spawn(move || reap(inner));
+        }
+    }
+
+    /// Destroy all idle devices.
+    pub fn clear(&self) {
+        self.inner.lock().unwrap().idle.clear();
+    }
+}
+
+fn reap(inner: Weak<Mutex<Inner>>) {
+    loop {
+        sleep(REAP_INTERVAL);
+        // the pool is gone and has destroyed the devices already
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        let mut inner = inner.lock().unwrap();
+        inner.reap(Instant::now());
+        if inner.idle.is_empty() {
+            inner.reaping = false;
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::uinput_device::tests::null_handles;
+
+    const TABLET: Option<&str> = Some("tablet");
+
+    fn id(id: Option<&str>) -> Option<String> {
+        id.map(String::from)
+    }
+
+    fn idle(pool: &UInputPool) -> usize {
+        pool.inner.lock().unwrap().idle.len()
+    }
+
+    #[test]
+    fn idle_devices_are_reused_if_they_match() {
+        let pool = UInputPool::new();
+        pool.put(null_handles(&id(TABLET), 8191));
+        pool.put(null_handles(&None, 8191));
+        pool.put(null_handles(&id(TABLET), 1023));
+
+        let handles = pool.take(&id(TABLET), 8191).unwrap();
+        assert!(handles.matches(&id(TABLET), 8191));
+        assert_eq!(idle(&pool), 2);
+        // each device is handed out once
+        assert!(pool.take(&id(TABLET), 8191).is_none());
+        // neither other names nor other pressure ranges match
+        assert!(pool.take(&id(Some("phone")), 1023).is_none());
+        assert!(pool.take(&None, 1023).is_none());
+        assert_eq!(idle(&pool), 2);
+
+        assert!(pool.take(&None, 8191).is_some());
+        assert!(pool.take(&id(TABLET), 1023).is_some());
+        assert_eq!(idle(&pool), 0);
+    }
+
+    #[test]
+    fn devices_idle_for_too_long_are_destroyed() {
+        let pool = UInputPool::new();
+        pool.put(null_handles(&id(TABLET), 8191));
+        pool.put(null_handles(&None, 8191));
+        let mut inner = pool.inner.lock().unwrap();
+        let start = Instant::now();
+        inner.idle[0].1 = start;
+        inner.idle[1].1 = start + IDLE_TIMEOUT / 2;
+
+        inner.reap(start + IDLE_TIMEOUT / 2);
+        assert_eq!(inner.idle.len(), 2);
+        inner.reap(start + IDLE_TIMEOUT);
+        assert_eq!(inner.idle.len(), 1);
+        assert!(inner.idle[0].0.matches(&None, 8191));
+        inner.reap(start + IDLE_TIMEOUT * 2);
+        assert!(inner.idle.is_empty());
+    }
+}
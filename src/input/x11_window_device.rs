@@ -0,0 +1,329 @@
+use std::error::Error;
+
+use autopilot::geometry::Size;
+use tracing::warn;
+
+use crate::capturable::x11::X11Capturable;
+use crate::capturable::{Capturable, Geometry};
+use crate::input::device::{InputDevice, InputDeviceType};
+use crate::input::uinput_keys::{map_key, KEY_UNKNOWN};
+use crate::protocol::{
+    Button, KeyboardEvent, KeyboardEventType, PointerEvent, PointerMode, WheelEvent, WHEEL_NOTCH,
+};
+
+// X11 keycodes are those of evdev shifted by 8
+const X11_KEYCODE_OFFSET: u32 = 8;
+
+// masks of the state of X11 events
+const SHIFT_MASK: u32 = 1 << 0;
+const CONTROL_MASK: u32 = 1 << 2;
+const MOD1_MASK: u32 = 1 << 3;
+const MOD4_MASK: u32 = 1 << 6;
+const BUTTON1_MASK: u32 = 1 << 8;
+
+// the buttons of PointerEvent and the X11 buttons they are, only 1 to 5 are part of the state
+const BUTTONS: [(Button, u32); 5] = [
+    (Button::PRIMARY, 1),
+    (Button::AUXILARY, 2),
+    (Button::SECONDARY, 3),
+    (Button::FOURTH, 8),
+    (Button::FIFTH, 9),
+];
+
+// X11 buttons that scroll up, down, left and right
+const WHEEL_BUTTONS: [u32; 4] = [4, 5, 6, 7];
+
+/// Sends input straight to an X11 window as synthetic events instead of simulating devices, so
+/// the window is neither raised nor focused and the user can go on working with other windows on
+/// the host. Together with capturing the window via Xcomposite it can be controlled while covered
+/// or on another workspace. Applications are free to ignore synthetic events though, some do, like
+/// xterm unless allowSendEvents is set, and there is neither pressure nor multitouch: every
+/// pointer acts like a mouse.
+pub struct X11WindowInput {
+    window: Option<X11Capturable>,
+    // last position of the pointer relative to the window
+    x: f64,
+    y: f64,
+    pressed_buttons: Button,
+    // X11 modifier mask of the last keyboard event
+    modifiers: u32,
+    // X11 keycodes of the keys that are down
+    pressed_keys: Vec<u32>,
+    // scroll distance that has not been sent yet because it is less than a full wheel click
+    wheel_acc: (f64, f64),
+}
+
+impl X11WindowInput {
+    pub fn new(window: X11Capturable) -> Self {
+        Self {
+            window: Some(window),
+            x: 0.5,
+            y: 0.5,
+            pressed_buttons: Button::NONE,
+            modifiers: 0,
+            pressed_keys: Vec::new(),
+            wheel_acc: (0.0, 0.0),
+        }
+    }
+
+    fn state(&self) -> u32 {
+        BUTTONS
+            .iter()
+            .filter(|(button, x11_button)| {
+                *x11_button <= 5 && self.pressed_buttons.contains(*button)
+            })
+            .fold(self.modifiers, |mask, (_, x11_button)| {
+                mask | BUTTON1_MASK << (x11_button - 1)
+            })
+    }
+
+    fn send_pointer(&self, button: u32, pressed: bool) {
+        let Some(window) = &self.window else {
+            return;
+        };
+        if let Err(err) = window.send_pointer_event(self.x, self.y, button, pressed, self.state()) {
+            warn!("Failed to send pointer event to window: {err}");
+        }
+    }
+
+    fn move_to(&mut self, x: f64, y: f64) {
+        self.x = x.clamp(0.0, 1.0);
+        self.y = y.clamp(0.0, 1.0);
+        self.send_pointer(0, false);
+    }
+
+    // the X11 buttons to click for the scroll distance that added up to full wheel clicks
+    fn wheel_clicks(&mut self, event: &WheelEvent) -> Vec<u32> {
+        let (dx, dy) = event.hi_res_delta();
+        self.wheel_acc.0 += dx;
+        self.wheel_acc.1 += dy;
+        let clicks_x = (self.wheel_acc.0 / WHEEL_NOTCH).trunc();
+        let clicks_y = (self.wheel_acc.1 / WHEEL_NOTCH).trunc();
+        self.wheel_acc.0 -= clicks_x * WHEEL_NOTCH;
+        self.wheel_acc.1 -= clicks_y * WHEEL_NOTCH;
+        let [up, down, left, right] = WHEEL_BUTTONS;
+        [(clicks_y, up, down), (clicks_x, right, left)]
+            .into_iter()
+            .flat_map(|(clicks, positive, negative)| {
+                let button = if clicks > 0.0 { positive } else { negative };
+                std::iter::repeat(button).take(clicks.abs() as usize)
+            })
+            .collect()
+    }
+}
+
+impl InputDevice for X11WindowInput {
+    fn send_wheel_event(&mut self, event: &WheelEvent) {
+        for button in self.wheel_clicks(event) {
+            self.send_pointer(button, true);
+            self.send_pointer(button, false);
+        }
+    }
+
+    fn send_pointer_event(&mut self, event: &PointerEvent) {
+        if !event.is_primary {
+            return;
+        }
+        self.move_to(event.x, event.y);
+        for (button, _) in BUTTONS {
+            let down = event.buttons.contains(button);
+            if down != self.pressed_buttons.contains(button) {
+                self.send_mouse_button(button, down);
+            }
+        }
+    }
+
+    fn send_keyboard_event(&mut self, event: &KeyboardEvent) -> Result<(), Box<dyn Error>> {
+        let pressed = match event.event_type {
+            KeyboardEventType::UP => false,
+            KeyboardEventType::DOWN | KeyboardEventType::REPEAT => true,
+        };
+        let key = map_key(&event.code, &event.location);
+        if key == KEY_UNKNOWN {
+            return Err(format!("Unknown key with code: {}", event.code).into());
+        }
+        let keycode = key as u32 + X11_KEYCODE_OFFSET;
+        self.modifiers = [
+            (event.shift, SHIFT_MASK),
+            (event.ctrl, CONTROL_MASK),
+            (event.alt, MOD1_MASK),
+            (event.meta, MOD4_MASK),
+        ]
+        .iter()
+        .filter(|(down, _)| *down)
+        .fold(0, |mask, (_, modifier)| mask | modifier);
+        if pressed {
+            if !self.pressed_keys.contains(&keycode) {
+                self.pressed_keys.push(keycode);
+            }
+        } else {
+            self.pressed_keys.retain(|k| *k != keycode);
+        }
+        if let Some(window) = &self.window {
+            window.send_key_event(keycode, pressed, self.state())?;
+        }
+        Ok(())
+    }
+
+    fn send_relative_motion(&mut self, dx: i32, dy: i32) {
+        let Some(window) = &self.window else {
+            return;
+        };
+        // the movement is in pixels of the screen, relative to the window it depends on its size
+        let (width, height) = match window.geometry() {
+            Ok(Geometry::Relative(_, _, width, height, _, _)) => (width, height),
+            _ => {
+                warn!("Failed to get window geometry, sending no input");
+                return;
+            }
+        };
+        let Size {
+            width: screen_width,
+            height: screen_height,
+        } = autopilot::screen::size();
+        self.move_to(
+            self.x + dx as f64 / (width * screen_width),
+            self.y + dy as f64 / (height * screen_height),
+        );
+    }
+
+    fn send_mouse_button(&mut self, button: Button, pressed: bool) {
+        let Some((_, x11_button)) = BUTTONS.into_iter().find(|(b, _)| *b == button) else {
+            return;
+        };
+        // the state of the event is the one before it
+        self.send_pointer(x11_button, pressed);
+        self.pressed_buttons.set(button, pressed);
+    }
+
+    fn set_pointer_mode(&mut self, _mode: PointerMode) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn release_all(&mut self) {
+        for (button, _) in BUTTONS {
+            if self.pressed_buttons.contains(button) {
+                self.send_mouse_button(button, false);
+            }
+        }
+        if let Some(window) = &self.window {
+            for keycode in std::mem::take(&mut self.pressed_keys) {
+                if let Err(err) = window.send_key_event(keycode, false, self.modifiers) {
+                    warn!("Failed to release key {keycode}: {err}");
+                }
+            }
+        }
+        self.modifiers = 0;
+    }
+
+    fn set_capturable(&mut self, capturable: Box<dyn Capturable>) {
+        self.release_all();
+        self.window = capturable.x11_window();
+        if self.window.is_none() {
+            warn!("Input can only be sent directly to X11 windows, sending no input.");
+        }
+    }
+
+    fn device_type(&self) -> InputDeviceType {
+        InputDeviceType::X11WindowInput
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{KeyboardLocation, WheelDeltaMode};
+
+    // without a window nothing is sent, only the state is kept
+    fn device() -> X11WindowInput {
+        X11WindowInput {
+            window: None,
+            x: 0.5,
+            y: 0.5,
+            pressed_buttons: Button::NONE,
+            modifiers: 0,
+            pressed_keys: Vec::new(),
+            wheel_acc: (0.0, 0.0),
+        }
+    }
+
+    fn wheel(dx: f64, dy: f64) -> WheelEvent {
+        WheelEvent {
+            dx,
+            dy,
+            delta_mode: WheelDeltaMode::Line,
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn buttons_and_modifiers_make_up_the_state() {
+        let mut device = device();
+        assert_eq!(device.state(), 0);
+        device.send_mouse_button(Button::PRIMARY, true);
+        assert_eq!(device.state(), BUTTON1_MASK);
+        device.send_mouse_button(Button::SECONDARY, true);
+        // button 3
+        assert_eq!(device.state(), BUTTON1_MASK | (BUTTON1_MASK << 2));
+        device.send_mouse_button(Button::PRIMARY, false);
+        assert_eq!(device.state(), (BUTTON1_MASK << 2));
+        // buttons 8 and 9 are not part of the state
+        device.send_mouse_button(Button::FOURTH, true);
+        device.send_mouse_button(Button::FIFTH, true);
+        assert_eq!(device.state(), (BUTTON1_MASK << 2));
+
+        device
+            .send_keyboard_event(&KeyboardEvent {
+                event_type: KeyboardEventType::DOWN,
+                code: "KeyA".into(),
+                key: "A".into(),
+                location: KeyboardLocation::STANDARD,
+                alt: false,
+                ctrl: true,
+                shift: true,
+                meta: false,
+            })
+            .unwrap();
+        assert_eq!(
+            device.state(),
+            SHIFT_MASK | CONTROL_MASK | (BUTTON1_MASK << 2)
+        );
+        assert_eq!(device.pressed_keys.len(), 1);
+
+        device.release_all();
+        assert_eq!(device.state(), 0);
+        assert_eq!(device.pressed_buttons, Button::NONE);
+    }
+
+    #[test]
+    fn pointer_events_press_and_release_the_buttons_that_changed() {
+        let mut device = device();
+        let mut event = crate::protocol::tests::pointer_event(
+            crate::protocol::PointerType::Mouse,
+            crate::protocol::PointerEventType::DOWN,
+            1,
+            0,
+        );
+        event.buttons = Button::PRIMARY.union(Button::AUXILARY);
+        device.send_pointer_event(&event);
+        assert_eq!(device.state(), BUTTON1_MASK | (BUTTON1_MASK << 1));
+        event.buttons = Button::AUXILARY;
+        device.send_pointer_event(&event);
+        assert_eq!(device.state(), (BUTTON1_MASK << 1));
+    }
+
+    #[test]
+    fn scrolling_adds_up_to_wheel_clicks() {
+        let [up, down, left, right] = WHEEL_BUTTONS;
+        let mut device = device();
+        // a notch is 3 lines
+        assert!(device.wheel_clicks(&wheel(0.0, 2.0)).is_empty());
+        assert_eq!(device.wheel_clicks(&wheel(0.0, 2.0)), [up]);
+        assert_eq!(device.wheel_clicks(&wheel(0.0, 5.0)), [up, up]);
+        // what is left over is kept, scrolling back takes it away first
+        assert!(device.wheel_clicks(&wheel(0.0, -2.0)).is_empty());
+        assert_eq!(device.wheel_clicks(&wheel(0.0, -3.0)), [down]);
+        assert_eq!(device.wheel_clicks(&wheel(-7.0, 5.0)), [up, left, left]);
+        assert_eq!(device.wheel_clicks(&wheel(4.0, 0.0)), [right]);
+    }
+}
@@ -0,0 +1,379 @@
+//! Weylus streams the screen of a computer to a web browser and turns input from that browser,
+//! like touch and stylus events, into input on the computer. Besides the `weylus` binary,
+//! which adds a GUI and a command line interface on top, the pipeline is available as a library:
+//!
+//! * [`capturable`] lists what can be captured, like screens and windows, see
+//!   [`capturable::get_capturables`]. A [`capturable::Capturable`] creates a
+//!   [`capturable::Recorder`] that returns frames as [`video::PixelProvider`].
+//! * [`video::VideoEncoder`] encodes those frames to a fragmented MP4 stream, the encoded data is
+//!   handed to a callback.
+//! * [`capture::CaptureRegistry`] does both in a background thread and shares a capture between
+//!   clients, the video is passed to the [`protocol::WeylusSender`] of each client.
+//! * [`input::device::InputDevice`] simulates [`protocol::PointerEvent`]s and other input on
+//!   the computer.
+//! * [`protocol`] also holds the messages exchanged with the browser via websocket.
+//!
+//! On Linux [`capturable::x11::x11_init`] has to be called before any other threads are started.
+//! The GUI is only part of the binary, no module of the library uses FLTK. Features apply to the
+//! whole package though, so FLTK is built along with the library unless the default `gui` feature
+//! is turned off with `default-features = false`.
+
+#![cfg_attr(feature = "bench", feature(test))]
+#[cfg(feature = "bench")]
+extern crate test;
+
+#[macro_use]
+extern crate bitflags;
+
+pub mod api;
+mod audio;
+pub mod capturable;
+pub mod capture;
+pub mod cerror;
+mod clipboard;
+pub mod config;
+pub mod connections;
+pub mod encoder_probe;
+mod hotkey;
+pub mod input;
+pub mod log;
+mod mdns;
+mod mjpeg;
+pub mod network;
+pub mod protocol;
+mod rtc;
+mod scale;
+pub mod self_test;
+mod tiles;
+mod tls;
+pub mod video;
+pub mod web;
+pub mod websocket;
+pub mod weylus;
+mod yuv;
+
+pub use crate::weylus::Weylus;
+
+#[cfg(feature = "bench")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capturable::{Capturable, Recorder};
+    use std::time::Instant;
+    use test::Bencher;
+
+    #[cfg(target_os = "linux")]
+    #[bench]
+    fn bench_capture_x11(b: &mut Bencher) {
+        let mut x11ctx = capturable::x11::X11Context::new().unwrap();
+        let root = x11ctx
+            .capturables(capturable::X11WindowCapture::Auto)
+            .unwrap()
+            .remove(0);
+        let mut r = root.recorder(false).unwrap();
+        b.iter(|| {
+            r.capture().unwrap();
+        });
+    }
+
+    // Xlib must be usable from any thread without the GUI being around
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn capture_x11_from_thread() {
+        std::thread::spawn(|| {
+            let mut x11ctx = capturable::x11::X11Context::new().unwrap();
+            let mut root = x11ctx
+                .capturables(capturable::X11WindowCapture::Auto)
+                .unwrap()
+                .remove(0);
+            root.geometry().unwrap();
+            root.before_input().unwrap();
+            let mut r = root.recorder(false).unwrap();
+            r.capture().unwrap();
+            r.has_changed();
+        })
+        .join()
+        .unwrap();
+    }
+
+    // closing a window while it is captured must not take the process with it
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore = "needs a window manager"]
+    fn capture_x11_destroyed_window() {
+        use std::os::raw::{c_char, c_int, c_uint, c_ulong, c_void};
+        extern "C" {
+            fn XOpenDisplay(name: *const c_char) -> *mut c_void;
+            fn XCloseDisplay(disp: *mut c_void) -> c_int;
+            fn XDefaultRootWindow(disp: *mut c_void) -> c_ulong;
+            fn XCreateSimpleWindow(
+                disp: *mut c_void,
+                parent: c_ulong,
+                x: c_int,
+                y: c_int,
+                width: c_uint,
+                height: c_uint,
+                border_width: c_uint,
+                border: c_ulong,
+                background: c_ulong,
+            ) -> c_ulong;
+            fn XMapWindow(disp: *mut c_void, win: c_ulong) -> c_int;
+            fn XDestroyWindow(disp: *mut c_void, win: c_ulong) -> c_int;
+            fn XSync(disp: *mut c_void, discard: c_int) -> c_int;
+        }
+
+        let mut x11ctx = capturable::x11::X11Context::new().unwrap();
+        // the window belongs to a connection of its own like the windows of any other application
+        let disp = unsafe { XOpenDisplay(std::ptr::null()) };
+        assert!(!disp.is_null());
+        let win = unsafe {
+            let win = XCreateSimpleWindow(disp, XDefaultRootWindow(disp), 0, 0, 320, 240, 0, 0, 0);
+            XMapWindow(disp, win);
+            XSync(disp, 0);
+            win
+        };
+        // windows are only listed once the window manager picked them up
+        let id = format!("x11:{win:#x}");
+        let mut window = None;
+        for _ in 0..50 {
+            window = x11ctx
+                .capturables(capturable::X11WindowCapture::Auto)
+                .unwrap()
+                .into_iter()
+                .find(|c| c.id() == id);
+            if window.is_some() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        let mut window = window.expect("The window has not been listed by the window manager!");
+        let mut r = window.recorder(false).unwrap();
+        for i in 0..100 {
+            if i == 50 {
+                unsafe {
+                    XDestroyWindow(disp, win);
+                    XSync(disp, 0);
+                }
+            }
+            let _ = r.capture();
+            let _ = window.geometry();
+            let _ = window.before_input();
+        }
+        assert!(r.capture().is_err());
+        assert!(window.geometry().is_err());
+        assert!(window.before_input().is_err());
+        unsafe { XCloseDisplay(disp) };
+    }
+
+    #[cfg(target_os = "linux")]
+    #[bench]
+    fn bench_video_x11(b: &mut Bencher) {
+        let mut x11ctx = capturable::x11::X11Context::new().unwrap();
+        let root = x11ctx
+            .capturables(capturable::X11WindowCapture::Auto)
+            .unwrap()
+            .remove(0);
+        let mut r = root.recorder(false).unwrap();
+        let (width, height) = r.capture().unwrap().size();
+
+        let opts = video::EncoderOptions {
+            try_vaapi: true,
+            try_nvenc: true,
+            nvenc_gpu: None,
+            try_videotoolbox: false,
+            try_mediafoundation: false,
+            codec: None,
+            bitrate_kbps: None,
+            crf: None,
+            preset: video::EncoderPreset::default(),
+            gop_size: 12,
+            intra_refresh: false,
+        };
+        let mut encoder =
+            video::VideoEncoder::new(width, height, width, height, |_| {}, opts).unwrap();
+        b.iter(|| encoder.encode(r.capture().unwrap(), Instant::now()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[bench]
+    fn bench_capture_wayland(b: &mut Bencher) {
+        gstreamer::init().unwrap();
+        let root = capturable::pipewire::get_capturables(false)
+            .unwrap()
+            .remove(0);
+        let mut r = root.recorder(false).unwrap();
+        let _ = r.capture();
+        b.iter(|| {
+            r.capture().unwrap();
+        });
+    }
+
+    #[cfg(target_os = "linux")]
+    #[bench]
+    fn bench_video_wayland(b: &mut Bencher) {
+        gstreamer::init().unwrap();
+        let root = capturable::pipewire::get_capturables(false)
+            .unwrap()
+            .remove(0);
+        let mut r = root.recorder(false).unwrap();
+        let (width, height) = r.capture().unwrap().size();
+
+        let opts = video::EncoderOptions {
+            try_vaapi: true,
+            try_nvenc: true,
+            nvenc_gpu: None,
+            try_videotoolbox: false,
+            try_mediafoundation: false,
+            codec: None,
+            bitrate_kbps: None,
+            crf: None,
+            preset: video::EncoderPreset::default(),
+            gop_size: 12,
+            intra_refresh: false,
+        };
+        let mut encoder =
+            video::VideoEncoder::new(width, height, width, height, |_| {}, opts).unwrap();
+        b.iter(|| encoder.encode(r.capture().unwrap(), Instant::now()));
+    }
+
+    #[bench]
+    fn bench_scale_4k_to_720p(b: &mut Bencher) {
+        let bgr0: Vec<u8> = (0..3840 * 2160 * 4).map(|i| (i % 251) as u8).collect();
+        let mut scaler = scale::Scaler::default();
+        b.iter(|| {
+            scaler.scale(video::PixelProvider::BGR0(3840, 2160, &bgr0), (1280, 720));
+        });
+    }
+
+    #[bench]
+    fn bench_tiles_unchanged_4k(b: &mut Bencher) {
+        let bgr0: Vec<u8> = (0..3840 * 2160 * 4).map(|i| (i % 251) as u8).collect();
+        let mut encoder = tiles::TileEncoder::new();
+        let mut encode = || {
+            encoder
+                .encode(video::PixelProvider::BGR0(3840, 2160, &bgr0), 3840, 2160)
+                .unwrap()
+                .len()
+        };
+        encode();
+        // only scaling and diffing, nothing is encoded
+        b.iter(|| assert_eq!(encode(), 0));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[bench]
+    fn bench_video_vaapi(b: &mut Bencher) {
+        const WIDTH: usize = 1920;
+        const HEIGHT: usize = 1080;
+        const N: usize = 60;
+        let mut bufs = vec![vec![0u8; SIZE]; N];
+        for i in 0..N {
+            for j in 0..SIZE {
+                bufs[i][j] = ((i * SIZE + j) % 256) as u8;
+            }
+        }
+
+        let opts = video::EncoderOptions {
+            try_vaapi: true,
+            try_nvenc: false,
+            nvenc_gpu: None,
+            try_videotoolbox: false,
+            try_mediafoundation: false,
+            codec: None,
+            bitrate_kbps: None,
+            crf: None,
+            preset: video::EncoderPreset::default(),
+            gop_size: 12,
+            intra_refresh: false,
+        };
+        let mut encoder =
+            video::VideoEncoder::new(WIDTH, HEIGHT, WIDTH, HEIGHT, |_| {}, opts).unwrap();
+        const SIZE: usize = WIDTH * HEIGHT * 4;
+        let mut i = 0;
+        b.iter(|| {
+            encoder.encode(
+                video::PixelProvider::BGR0(WIDTH, HEIGHT, &bufs[i % N]),
+                Instant::now(),
+            );
+            i += 1;
+        });
+    }
+
+    #[cfg(target_os = "linux")]
+    #[bench]
+    fn bench_video_x264(b: &mut Bencher) {
+        const WIDTH: usize = 1920;
+        const HEIGHT: usize = 1080;
+        const N: usize = 60;
+        let mut bufs = vec![vec![0u8; SIZE]; N];
+        for i in 0..N {
+            for j in 0..SIZE {
+                bufs[i][j] = ((i * SIZE + j) % 256) as u8;
+            }
+        }
+
+        let opts = video::EncoderOptions {
+            try_vaapi: false,
+            try_nvenc: false,
+            nvenc_gpu: None,
+            try_videotoolbox: false,
+            try_mediafoundation: false,
+            codec: None,
+            bitrate_kbps: None,
+            crf: None,
+            preset: video::EncoderPreset::default(),
+            gop_size: 12,
+            intra_refresh: false,
+        };
+        let mut encoder =
+            video::VideoEncoder::new(WIDTH, HEIGHT, WIDTH, HEIGHT, |_| {}, opts).unwrap();
+        const SIZE: usize = WIDTH * HEIGHT * 4;
+        let mut i = 0;
+        b.iter(|| {
+            encoder.encode(
+                video::PixelProvider::BGR0(WIDTH, HEIGHT, &bufs[i % N]),
+                Instant::now(),
+            );
+            i += 1;
+        });
+    }
+
+    #[cfg(target_os = "linux")]
+    #[bench]
+    fn bench_video_nvenc(b: &mut Bencher) {
+        const WIDTH: usize = 1920;
+        const HEIGHT: usize = 1080;
+        const N: usize = 60;
+        let mut bufs = vec![vec![0u8; SIZE]; N];
+        for i in 0..N {
+            for j in 0..SIZE {
+                bufs[i][j] = ((i * SIZE + j) % 256) as u8;
+            }
+        }
+
+        let opts = video::EncoderOptions {
+            try_vaapi: false,
+            try_nvenc: true,
+            nvenc_gpu: None,
+            try_videotoolbox: false,
+            try_mediafoundation: false,
+            codec: None,
+            bitrate_kbps: None,
+            crf: None,
+            preset: video::EncoderPreset::default(),
+            gop_size: 12,
+            intra_refresh: false,
+        };
+        let mut encoder =
+            video::VideoEncoder::new(WIDTH, HEIGHT, WIDTH, HEIGHT, |_| {}, opts).unwrap();
+        const SIZE: usize = WIDTH * HEIGHT * 4;
+        let mut i = 0;
+        b.iter(|| {
+            encoder.encode(
+                video::PixelProvider::BGR0(WIDTH, HEIGHT, &bufs[i % N]),
+                Instant::now(),
+            );
+            i += 1;
+        });
+    }
+}
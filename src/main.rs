@@ -1,10 +1,3 @@
-#![cfg_attr(feature = "bench", feature(test))]
-#[cfg(feature = "bench")]
-extern crate test;
-
-#[macro_use]
-extern crate bitflags;
-
 use clap::CommandFactory;
 use clap_complete::generate;
 #[cfg(unix)]
@@ -14,25 +7,29 @@ use tracing::{error, info, warn};
 
 use std::sync::mpsc;
 
-use config::{get_config, Config};
+use weylus::config::{get_config, write_capturable_name, Config};
+use weylus::{encoder_probe, log, web};
 
-mod capturable;
-mod cerror;
-mod config;
+#[cfg(feature = "gui")]
 mod gui;
-mod input;
-mod log;
-mod protocol;
-mod video;
-mod web;
-mod websocket;
-mod weylus;
 
 fn main() {
+    // log messages are only shown if there is a GUI
+    #[cfg_attr(not(feature = "gui"), allow(unused_variables))]
     let (sender, receiver) = mpsc::sync_channel::<String>(100);
 
     log::setup_logging(sender);
 
+    // Screens are captured in physical pixels, but unless the process is DPI aware Windows
+    // virtualizes the coordinates used for input if the display is scaled, and input ends up in
+    // the wrong place.
+    #[cfg(target_os = "windows")]
+    unsafe {
+        winapi::um::winuser::SetProcessDpiAwarenessContext(
+            winapi::shared::windef::DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2,
+        );
+    }
+
     let conf = get_config();
 
     if let Some(shell) = conf.completions {
@@ -61,11 +58,21 @@ fn main() {
         print!("{}", web::LIB_JS);
         return;
     }
+    if let Some(encoder) = conf.probe_encoder {
+        std::process::exit(if encoder_probe::probe_in_process(encoder) {
+            0
+        } else {
+            1
+        });
+    }
+    if conf.check {
+        std::process::exit(if check_input() { 0 } else { 1 });
+    }
 
     #[cfg(target_os = "linux")]
     {
         // make sure XInitThreads is called before any threading is done
-        crate::capturable::x11::x11_init();
+        weylus::capturable::x11::x11_init();
 
         if let Err(err) = gstreamer::init() {
             error!(
@@ -76,34 +83,30 @@ fn main() {
         }
     }
 
-    if conf.no_gui {
-        let mut weylus = crate::weylus::Weylus::new();
+    if conf.self_test {
+        std::process::exit(if weylus::self_test::run(&conf) { 0 } else { 1 });
+    }
+
+    if conf.no_gui || cfg!(not(feature = "gui")) {
+        let mut weylus = weylus::Weylus::new();
         weylus.start(&conf, |msg| match msg {
-            web::Web2UiMessage::UInputInaccessible => {
-                warn!(std::include_str!("strings/uinput_error.txt"))
+            web::Web2UiMessage::UInputInaccessible(diagnosis) => {
+                warn!(
+                    "{diagnosis}\n\n{}",
+                    std::include_str!("strings/uinput_error.txt")
+                )
+            }
+            web::Web2UiMessage::ViewOnlyClients(count) => {
+                info!("{count} client(s) connected in view-only mode.")
             }
+            // logged when it is switched already
+            web::Web2UiMessage::HostInputEnabled(_) => (),
+            web::Web2UiMessage::CapturableSelected(name) => write_capturable_name(name),
         });
         #[cfg(unix)]
         {
-            let mut signals = Signals::new(TERM_SIGNALS).unwrap();
-            for sig in signals.forever() {
-                info!(
-                    "Shutting down after receiving signal {signame} ({sig})...",
-                    signame = signal_name(sig).unwrap_or("UNKNOWN SIGNAL")
-                );
-                std::thread::spawn(move || {
-                    for sig in signals.forever() {
-                        warn!(
-                            "Received second signal {signame} ({sig}) while shutting down \
-                            gracefully, proceeding with forceful shutdown...",
-                            signame = signal_name(sig).unwrap_or("UNKNOWN SIGNAL")
-                        );
-                        std::process::exit(1);
-                    }
-                });
-                weylus.stop();
-                break;
-            }
+            wait_for_signal(Signals::new(TERM_SIGNALS).unwrap());
+            weylus.stop();
         }
         #[cfg(not(unix))]
         {
@@ -112,166 +115,72 @@ fn main() {
             }
         }
     } else {
-        gui::run(&conf, receiver);
-    }
-}
-
-#[cfg(feature = "bench")]
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use capturable::{Capturable, Recorder};
-    use test::Bencher;
-
-    #[cfg(target_os = "linux")]
-    #[bench]
-    fn bench_capture_x11(b: &mut Bencher) {
-        let mut x11ctx = capturable::x11::X11Context::new().unwrap();
-        let root = x11ctx.capturables().unwrap().remove(0);
-        let mut r = root.recorder(false).unwrap();
-        b.iter(|| {
-            r.capture().unwrap();
-        });
-    }
-
-    #[cfg(target_os = "linux")]
-    #[bench]
-    fn bench_video_x11(b: &mut Bencher) {
-        let mut x11ctx = capturable::x11::X11Context::new().unwrap();
-        let root = x11ctx.capturables().unwrap().remove(0);
-        let mut r = root.recorder(false).unwrap();
-        let (width, height) = r.capture().unwrap().size();
-
-        let opts = video::EncoderOptions {
-            try_vaapi: true,
-            try_nvenc: true,
-            try_videotoolbox: false,
-            try_mediafoundation: false,
-        };
-        let mut encoder =
-            video::VideoEncoder::new(width, height, width, height, |_| {}, opts).unwrap();
-        b.iter(|| encoder.encode(r.capture().unwrap()));
-    }
-
-    #[cfg(target_os = "linux")]
-    #[bench]
-    fn bench_capture_wayland(b: &mut Bencher) {
-        gstreamer::init().unwrap();
-        let root = capturable::pipewire::get_capturables(false)
-            .unwrap()
-            .remove(0);
-        let mut r = root.recorder(false).unwrap();
-        let _ = r.capture();
-        b.iter(|| {
-            r.capture().unwrap();
-        });
-    }
-
-    #[cfg(target_os = "linux")]
-    #[bench]
-    fn bench_video_wayland(b: &mut Bencher) {
-        gstreamer::init().unwrap();
-        let root = capturable::pipewire::get_capturables(false)
-            .unwrap()
-            .remove(0);
-        let mut r = root.recorder(false).unwrap();
-        let (width, height) = r.capture().unwrap().size();
-
-        let opts = video::EncoderOptions {
-            try_vaapi: true,
-            try_nvenc: true,
-            try_videotoolbox: false,
-            try_mediafoundation: false,
-        };
-        let mut encoder =
-            video::VideoEncoder::new(width, height, width, height, |_| {}, opts).unwrap();
-        b.iter(|| encoder.encode(r.capture().unwrap()));
-    }
-
-    #[cfg(target_os = "linux")]
-    #[bench]
-    fn bench_video_vaapi(b: &mut Bencher) {
-        const WIDTH: usize = 1920;
-        const HEIGHT: usize = 1080;
-        const N: usize = 60;
-        let mut bufs = vec![vec![0u8; SIZE]; N];
-        for i in 0..N {
-            for j in 0..SIZE {
-                bufs[i][j] = ((i * SIZE + j) % 256) as u8;
+        #[cfg(feature = "gui")]
+        {
+            // closing the window shuts the server down, so a signal does the same
+            #[cfg(unix)]
+            {
+                let signals = Signals::new(TERM_SIGNALS).unwrap();
+                std::thread::spawn(move || {
+                    wait_for_signal(signals);
+                    gui::quit();
+                });
             }
+            gui::run(&conf, receiver);
         }
-
-        let opts = video::EncoderOptions {
-            try_vaapi: true,
-            try_nvenc: false,
-            try_videotoolbox: false,
-            try_mediafoundation: false,
-        };
-        let mut encoder =
-            video::VideoEncoder::new(WIDTH, HEIGHT, WIDTH, HEIGHT, |_| {}, opts).unwrap();
-        const SIZE: usize = WIDTH * HEIGHT * 4;
-        let mut i = 0;
-        b.iter(|| {
-            encoder.encode(video::PixelProvider::BGR0(WIDTH, HEIGHT, &bufs[i % N]));
-            i += 1;
-        });
     }
+}
 
-    #[cfg(target_os = "linux")]
-    #[bench]
-    fn bench_video_x264(b: &mut Bencher) {
-        const WIDTH: usize = 1920;
-        const HEIGHT: usize = 1080;
-        const N: usize = 60;
-        let mut bufs = vec![vec![0u8; SIZE]; N];
-        for i in 0..N {
-            for j in 0..SIZE {
-                bufs[i][j] = ((i * SIZE + j) % 256) as u8;
+/// Prints if each kind of input device can be created, returns true if all of them can.
+#[cfg(target_os = "linux")]
+fn check_input() -> bool {
+    use weylus::input::uinput_device::{check_devices, UInputDiagnosis, UDEV_RULE};
+
+    let mut ok = true;
+    for (kind, result) in check_devices() {
+        match result {
+            Ok(()) => println!("[PASS] uinput {kind} device"),
+            Err(err) => {
+                println!("[FAIL] uinput {kind} device: {err}");
+                ok = false;
             }
         }
-
-        let opts = video::EncoderOptions {
-            try_vaapi: false,
-            try_nvenc: false,
-            try_videotoolbox: false,
-            try_mediafoundation: false,
-        };
-        let mut encoder =
-            video::VideoEncoder::new(WIDTH, HEIGHT, WIDTH, HEIGHT, |_| {}, opts).unwrap();
-        const SIZE: usize = WIDTH * HEIGHT * 4;
-        let mut i = 0;
-        b.iter(|| {
-            encoder.encode(video::PixelProvider::BGR0(WIDTH, HEIGHT, &bufs[i % N]));
-            i += 1;
-        });
     }
+    if !ok {
+        println!(
+            "\n{}\nThe udev rule for uinput is: {UDEV_RULE}",
+            UInputDiagnosis::new()
+        );
+    }
+    ok
+}
 
-    #[cfg(target_os = "linux")]
-    #[bench]
-    fn bench_video_nvenc(b: &mut Bencher) {
-        const WIDTH: usize = 1920;
-        const HEIGHT: usize = 1080;
-        const N: usize = 60;
-        let mut bufs = vec![vec![0u8; SIZE]; N];
-        for i in 0..N {
-            for j in 0..SIZE {
-                bufs[i][j] = ((i * SIZE + j) % 256) as u8;
-            }
-        }
+#[cfg(not(target_os = "linux"))]
+fn check_input() -> bool {
+    println!(
+        "Nothing to check: uinput is only used on Linux, input is simulated via the system's API."
+    );
+    true
+}
 
-        let opts = video::EncoderOptions {
-            try_vaapi: false,
-            try_nvenc: true,
-            try_videotoolbox: false,
-            try_mediafoundation: false,
-        };
-        let mut encoder =
-            video::VideoEncoder::new(WIDTH, HEIGHT, WIDTH, HEIGHT, |_| {}, opts).unwrap();
-        const SIZE: usize = WIDTH * HEIGHT * 4;
-        let mut i = 0;
-        b.iter(|| {
-            encoder.encode(video::PixelProvider::BGR0(WIDTH, HEIGHT, &bufs[i % N]));
-            i += 1;
-        });
+/// Blocks until the first signal arrives, any further signal ends the process right away in case
+/// shutting down gracefully gets stuck.
+#[cfg(unix)]
+fn wait_for_signal(mut signals: Signals) {
+    if let Some(sig) = signals.forever().next() {
+        info!(
+            "Shutting down after receiving signal {signame} ({sig})...",
+            signame = signal_name(sig).unwrap_or("UNKNOWN SIGNAL")
+        );
     }
+    std::thread::spawn(move || {
+        for sig in signals.forever() {
+            warn!(
+                "Received second signal {signame} ({sig}) while shutting down gracefully, \
+                proceeding with forceful shutdown...",
+                signame = signal_name(sig).unwrap_or("UNKNOWN SIGNAL")
+            );
+            std::process::exit(1);
+        }
+    });
 }
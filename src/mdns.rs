@@ -0,0 +1,96 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use mdns_sd::{ServiceDaemon, ServiceInfo, UnregisterStatus};
+use tracing::{debug, warn};
+
+const SERVICE_TYPE: &str = "_weylus._tcp.local.";
+// how long to wait for the goodbye packets to go out on shutdown
+const WITHDRAW_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Announces the web server on the local network via mDNS so clients can find it by name instead of
+/// by IP address. The announcement is withdrawn once this is dropped.
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+    hostname: String,
+}
+
+impl Advertisement {
+    /// Only the given addresses are advertised, all addresses of this computer if there are none.
+    pub fn new(
+        ips: &[IpAddr],
+        port: u16,
+        access_code_required: bool,
+        tls: bool,
+    ) -> Result<Self, mdns_sd::Error> {
+        let host = gethostname::gethostname();
+        let host = host.to_string_lossy();
+        // mDNS names live in .local, strip any domain the system added
+        let host = host.split('.').next().unwrap_or_default();
+        let host = if host.is_empty() { "weylus" } else { host };
+        let hostname = format!("{host}.local");
+
+        let flag = |b: bool| if b { "1" } else { "0" };
+        let properties = [
+            ("version", env!("CARGO_PKG_VERSION")),
+            ("access_code", flag(access_code_required)),
+            ("tls", flag(tls)),
+        ];
+        let instance = format!("Weylus on {host}");
+        let host_fqdn = format!("{hostname}.");
+        let info = if ips.is_empty() {
+            ServiceInfo::new(
+                SERVICE_TYPE,
+                &instance,
+                &host_fqdn,
+                "",
+                port,
+                &properties[..],
+            )?
+            .enable_addr_auto()
+        } else {
+            ServiceInfo::new(
+                SERVICE_TYPE,
+                &instance,
+                &host_fqdn,
+                ips,
+                port,
+                &properties[..],
+            )?
+        };
+        let fullname = info.get_fullname().to_string();
+
+        let daemon = ServiceDaemon::new()?;
+        daemon.register(info)?;
+        debug!("Registered {fullname} via mDNS.");
+        Ok(Self {
+            daemon,
+            fullname,
+            hostname,
+        })
+    }
+
+    /// Name clients can resolve to reach this computer, like "laptop.local".
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        match self.daemon.unregister(&self.fullname) {
+            Ok(status) => match status.recv_timeout(WITHDRAW_TIMEOUT) {
+                Ok(UnregisterStatus::OK) => debug!("Withdrew {} via mDNS.", self.fullname),
+                Ok(UnregisterStatus::NotFound) => {
+                    warn!("{} was not registered via mDNS.", self.fullname)
+                }
+                Err(err) => warn!("Failed to withdraw mDNS announcement: {err}"),
+            },
+            Err(err) => warn!("Failed to withdraw mDNS announcement: {err}"),
+        }
+        if let Err(err) = self.daemon.shutdown() {
+            warn!("Failed to stop mDNS daemon: {err}");
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use image::codecs::jpeg;
+use image::{ExtendedColorType, ImageResult};
+
+use crate::video::PixelProvider;
+use crate::yuv;
+
+pub const DEFAULT_JPEG_QUALITY: u8 = 80;
+
+/// Encodes frames as separate JPEG images for clients that can not play back the MP4 stream. The
+/// buffers are kept between frames so that encoding does not allocate once the size is stable.
+pub struct JpegEncoder {
+    quality: u8,
+    rgb: Vec<u8>,
+    jpeg: Vec<u8>,
+}
+
+impl JpegEncoder {
+    pub fn new(quality: u8) -> Self {
+        Self {
+            quality: quality.clamp(1, 100),
+            rgb: Vec::new(),
+            jpeg: Vec::new(),
+        }
+    }
+
+    /// Scale the frame to width_out x height_out and encode it, the returned image is valid until
+    /// the next frame is encoded.
+    pub fn encode(
+        &mut self,
+        pixel_provider: PixelProvider,
+        width_out: usize,
+        height_out: usize,
+    ) -> ImageResult<&[u8]> {
+        scale_to_rgb(&mut self.rgb, pixel_provider, width_out, height_out);
+        self.jpeg.clear();
+        jpeg::JpegEncoder::new_with_quality(&mut self.jpeg, self.quality).encode(
+            &self.rgb,
+            width_out as u32,
+            height_out as u32,
+            ExtendedColorType::Rgb8,
+        )?;
+        Ok(&self.jpeg)
+    }
+}
+
+/// Scale the frame to width_out x height_out and store it as RGB in rgb, whatever rgb held is
+/// overwritten but its capacity is reused.
+pub(crate) fn scale_to_rgb(
+    rgb: &mut Vec<u8>,
+    pixel_provider: PixelProvider,
+    width_out: usize,
+    height_out: usize,
+) {
+    let (width_in, height_in) = pixel_provider.size();
+    let size = ((width_in, height_in), (width_out, height_out));
+    match pixel_provider {
+        PixelProvider::RGB(w, _, data) => scale(rgb, size, |x, y| {
+            let p = &data[y * w * 3 + x * 3..];
+            [p[0], p[1], p[2]]
+        }),
+        PixelProvider::RGB0(w, _, data) => scale(rgb, size, |x, y| {
+            let p = &data[y * w * 4 + x * 4..];
+            [p[0], p[1], p[2]]
+        }),
+        PixelProvider::BGR0(w, _, data) => scale(rgb, size, |x, y| {
+            let p = &data[y * w * 4 + x * 4..];
+            [p[2], p[1], p[0]]
+        }),
+        PixelProvider::BGR0S(_, _, stride, data) => scale(rgb, size, |x, y| {
+            let p = &data[y * stride + x * 4..];
+            [p[2], p[1], p[0]]
+        }),
+        PixelProvider::NV12(_, _, [luma, uv], strides) => scale(rgb, size, |x, y| {
+            let c = y / 2 * strides[1] + x / 2 * 2;
+            yuv::to_rgb(luma[y * strides[0] + x], uv[c], uv[c + 1])
+        }),
+        PixelProvider::I420(_, _, [luma, u, v], strides) => scale(rgb, size, |x, y| {
+            yuv::to_rgb(
+                luma[y * strides[0] + x],
+                u[y / 2 * strides[1] + x / 2],
+                v[y / 2 * strides[2] + x / 2],
+            )
+        }),
+    }
+}
+
+/// Scale the image to width_out x height_out and store it as RGB in rgb, pixel returns the color
+/// at x, y of the original image.
+fn scale(
+    rgb: &mut Vec<u8>,
+    ((width_in, height_in), (width_out, height_out)): ((usize, usize), (usize, usize)),
+    pixel: impl Fn(usize, usize) -> [u8; 3],
+) {
+    // nearest neighbour scaling is good enough here, the JPEG compression loses more detail and
+    // lossless tiles are only sharp if they are not scaled at all
+    rgb.clear();
+    rgb.reserve(width_out * height_out * 3);
+    for y in 0..height_out {
+        let y = y * height_in / height_out;
+        for x in 0..width_out {
+            rgb.extend_from_slice(&pixel(x * width_in / width_out, y));
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[cfg(not(target_os = "windows"))]
+use pnet_datalink as datalink;
+
+/// Something the web server listens on, either an IP address or all addresses of a network
+/// interface given by its name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindAddress {
+    Ip(IpAddr),
+    Interface(String),
+}
+
+impl BindAddress {
+    pub fn socket_addrs(&self, port: u16) -> Result<Vec<SocketAddr>, String> {
+        match self {
+            Self::Ip(ip) => Ok(vec![SocketAddr::new(*ip, port)]),
+            Self::Interface(name) => {
+                let addrs: Vec<SocketAddr> = interface_addresses()
+                    .iter()
+                    .filter(|addr| addr.interface == *name)
+                    .map(|addr| addr.socket_addr(port))
+                    .collect();
+                if addrs.is_empty() {
+                    if cfg!(target_os = "windows") {
+                        Err(
+                            "selecting network interfaces by name is not supported on Windows."
+                                .into(),
+                        )
+                    } else {
+                        Err(format!("no network interface named {name} is up."))
+                    }
+                } else {
+                    Ok(addrs)
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for BindAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(ip) = s.parse() {
+            return Ok(Self::Ip(ip));
+        }
+        // anything that looks like an address but failed to parse is most likely a typo
+        if s.is_empty()
+            || s.contains([':', '/', ' '])
+            || s.chars().all(|c| c.is_ascii_digit() || c == '.')
+        {
+            Err(format!(
+                "{s} is neither an IP address nor a network interface."
+            ))
+        } else {
+            Ok(Self::Interface(s.to_string()))
+        }
+    }
+}
+
+impl fmt::Display for BindAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(ip) => write!(f, "{ip}"),
+            Self::Interface(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+/// Comma separated list of [`BindAddress`]es, like "0.0.0.0, ::" or "eth0".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BindAddresses(pub Vec<BindAddress>);
+
+impl FromStr for BindAddresses {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let addrs = s
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(BindAddress::from_str)
+            .collect::<Result<Vec<_>, _>>()?;
+        if addrs.is_empty() {
+            return Err("At least one address to bind to is required.".into());
+        }
+        Ok(Self(addrs))
+    }
+}
+
+impl fmt::Display for BindAddresses {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, addr) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{addr}")?;
+        }
+        Ok(())
+    }
+}
+
+// stored as the same string that is accepted on the command line, this also keeps configuration
+// files from before multiple addresses were supported working
+impl Serialize for BindAddresses {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for BindAddresses {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// A network in CIDR notation like "192.168.0.0/16" or "fd00::/8", a single address if the
+/// prefix length is left out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    /// IPv4 addresses mapped to IPv6 ones, which is how sockets listening on IPv6 see IPv4
+    /// clients, are part of the network of the IPv4 address.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .trim()
+            .parse()
+            .map_err(|_| format!("{s} is not a network like 192.168.0.0/16."))?;
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .trim()
+                .parse()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .ok_or_else(|| {
+                    format!("{s}: the prefix length has to be between 0 and {max_prefix_len}.")
+                })?,
+            None => max_prefix_len,
+        };
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl Serialize for IpNetwork {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for IpNetwork {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// An address assigned to a network interface of this computer.
+#[derive(Debug, Clone)]
+pub struct InterfaceAddress {
+    pub interface: String,
+    pub index: u32,
+    pub ip: IpAddr,
+    pub loopback: bool,
+}
+
+impl InterfaceAddress {
+    pub fn socket_addr(&self, port: u16) -> SocketAddr {
+        match self.ip {
+            // link-local addresses are ambiguous without the interface they belong to
+            IpAddr::V6(ip) if is_link_local(&ip) => {
+                SocketAddr::V6(SocketAddrV6::new(ip, port, 0, self.index))
+            }
+            ip => SocketAddr::new(ip, port),
+        }
+    }
+
+    /// Host part of a URL pointing at this address, see RFC 6874 for the zone index of
+    /// link-local IPv6 addresses.
+    pub fn url_host(&self) -> String {
+        match self.ip {
+            IpAddr::V6(ip) if is_link_local(&ip) => format!("[{ip}%25{}]", self.interface),
+            ip => url_host(ip),
+        }
+    }
+}
+
+impl fmt::Display for InterfaceAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.ip {
+            IpAddr::V6(ip) if is_link_local(&ip) => write!(f, "{ip}%{}", self.interface),
+            ip => write!(f, "{ip}"),
+        }
+    }
+}
+
+/// Host part of a URL pointing at ip.
+pub fn url_host(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{ip}]"),
+    }
+}
+
+fn is_link_local(ip: &Ipv6Addr) -> bool {
+    // Ipv6Addr::is_unicast_link_local is only stable since Rust 1.84
+    ip.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// Addresses of all network interfaces that are up, always empty on Windows.
+pub fn interface_addresses() -> Vec<InterfaceAddress> {
+    #[cfg(not(target_os = "windows"))]
+    {
+        datalink::interfaces()
+            .iter()
+            .filter(|iface| iface.is_up())
+            .flat_map(|iface| {
+                iface.ips.iter().map(|ipnetw| InterfaceAddress {
+                    interface: iface.name.clone(),
+                    index: iface.index,
+                    ip: ipnetw.ip(),
+                    loopback: iface.is_loopback(),
+                })
+            })
+            .collect()
+    }
+    #[cfg(target_os = "windows")]
+    {
+        Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowed_networks_contain_addresses() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let lan: IpNetwork = "192.168.0.0/16".parse().unwrap();
+        assert!(lan.contains(ip("192.168.178.20")));
+        assert!(!lan.contains(ip("192.169.0.1")));
+        // IPv4 clients of sockets listening on IPv6
+        assert!(lan.contains(ip("::ffff:192.168.1.2")));
+        assert!(!lan.contains(ip("fd00::1")));
+
+        let host: IpNetwork = "10.0.0.1".parse().unwrap();
+        assert_eq!(host.to_string(), "10.0.0.1/32");
+        assert!(host.contains(ip("10.0.0.1")));
+        assert!(!host.contains(ip("10.0.0.2")));
+
+        let anywhere: IpNetwork = "0.0.0.0/0".parse().unwrap();
+        assert!(anywhere.contains(ip("203.0.113.7")));
+
+        let ula: IpNetwork = "fd00::/8".parse().unwrap();
+        assert!(ula.contains(ip("fd12:3456::1")));
+        assert!(!ula.contains(ip("fe80::1")));
+
+        assert!("192.168.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("fd00::/129".parse::<IpNetwork>().is_err());
+        assert!("localhost".parse::<IpNetwork>().is_err());
+    }
+}
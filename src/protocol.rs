@@ -1,15 +1,533 @@
-use serde::{Deserialize, Deserializer, Serialize};
+use std::net::SocketAddr;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
 
-#[derive(Serialize, Deserialize, Debug)]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[cfg(target_os = "linux")]
+use crate::capturable::X11WindowCapture;
+use crate::capturable::{Geometry, MonitorInfo, PhysicalSize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ClientConfiguration {
     #[cfg(target_os = "linux")]
     pub uinput_support: bool,
-    pub capturable_id: usize,
+    pub capturable_id: String,
     pub capture_cursor: bool,
     pub max_width: usize,
     pub max_height: usize,
     pub client_name: Option<String>,
     pub frame_rate: f64,
+    #[serde(default)]
+    pub send_stats: bool,
+    #[serde(default)]
+    pub clipboard_sync: bool,
+    // without video the capturable only tells where input goes, neither a capture nor an encoder
+    // is created
+    #[serde(default = "default_video")]
+    pub video: bool,
+    #[serde(default)]
+    pub video_format: VideoFormat,
+    // codecs the browser can play back in the order it prefers them, the first one that can be
+    // encoded is used, falling back to MJPEG if none can be; empty leaves the choice to the server
+    #[serde(default)]
+    pub preferred_codecs: Vec<VideoCodec>,
+    // 1 to 100, only used for VideoFormat::Mjpeg
+    pub jpeg_quality: Option<u8>,
+    pub pressure_curve: Option<PressureCurve>,
+    // smooths the path of the stylus to get rid of jitter, off if not set
+    pub pen_smoothing: Option<PenSmoothing>,
+    // the encoder picks a suitable bitrate and quality if these are not set
+    pub bitrate_kbps: Option<u32>,
+    pub crf: Option<u8>,
+    // parts of the picture are refreshed in every frame instead of sending large keyframes
+    // periodically, keyframes requested with RequestKeyFrame are still sent and restart the cycle
+    #[serde(default)]
+    pub intra_refresh: bool,
+    // the bitrate follows the throughput of the connection within these bounds instead of
+    // staying at bitrate_kbps
+    pub adaptive_bitrate: Option<BitrateRange>,
+    // upper limit for frame_rate
+    pub max_fps: Option<f64>,
+    // if disabled the client only watches and can not control the computer
+    #[serde(default = "default_input_enabled")]
+    pub input_enabled: bool,
+    // only this part of the capturable is streamed
+    pub crop: Option<CropRect>,
+    #[serde(default)]
+    pub scale_mode: ScaleMode,
+    // two finger pan and pinch are turned into scrolling and zooming instead of being passed on
+    // as multitouch
+    #[serde(default)]
+    pub touch_gestures: bool,
+    // drop touches while the stylus is in use and for palm_rejection_timeout_ms after its last
+    // event
+    #[serde(default)]
+    pub palm_rejection: bool,
+    pub palm_rejection_timeout_ms: Option<u32>,
+    // pointer events that arrive out of order are passed on as they are if not set
+    pub pointer_ordering: Option<PointerOrdering>,
+    // movements of pointers are injected as often as they arrive if not set
+    pub input_pacing: Option<InputPacing>,
+    #[serde(default)]
+    pub pointer_mode: PointerMode,
+    // scales the movement in PointerMode::Relative, 1 if not set
+    pub pointer_sensitivity: Option<f64>,
+    // the stylus stays absolute in PointerMode::Relative unless this is set
+    #[serde(default)]
+    pub relative_stylus: bool,
+    // if the capturable is an X11 window, input is sent to it as synthetic events instead of
+    // raising and focusing it first, so the host can be used for other things meanwhile; some
+    // applications ignore synthetic events and the stylus and touches act like a mouse
+    #[cfg(target_os = "linux")]
+    #[serde(default)]
+    pub background_input: bool,
+    // how the capturable is captured if it is an X11 window, as configured on the host if not set
+    #[cfg(target_os = "linux")]
+    pub x11_window_capture: Option<X11WindowCapture>,
+    // what the buttons of mice and the stylus do, buttons that are not listed do what they do by
+    // default, only supported by uinput
+    #[serde(default)]
+    pub button_mapping: Vec<ButtonMapping>,
+    // send a FrameInfo for every frame of video
+    #[serde(default)]
+    pub send_frame_info: bool,
+    // send CursorPosition and CursorShape, so the client can draw the cursor of the host itself
+    // without it being part of the video
+    #[serde(default)]
+    pub report_cursor: bool,
+    // stream the audio of the host as well, from audio_source, an id from AudioSources, or what
+    // is played back on the default output if not set
+    #[serde(default)]
+    pub audio: bool,
+    pub audio_source: Option<String>,
+}
+
+fn default_input_enabled() -> bool {
+    true
+}
+
+fn default_video() -> bool {
+    true
+}
+
+impl ClientConfiguration {
+    /// Check the video settings are within sensible ranges.
+    pub fn validate(&self) -> Result<(), ClientError> {
+        if let Some(bitrate_kbps) = self.bitrate_kbps {
+            if !(100..=200_000).contains(&bitrate_kbps) {
+                return Err(ClientError::config_invalid(
+                    "bitrate_kbps",
+                    format!("Bitrate has to be between 100 and 200000 kbps, got {bitrate_kbps}!"),
+                ));
+            }
+        }
+        if let Some(range) = self.adaptive_bitrate {
+            if !(100..=200_000).contains(&range.min_kbps)
+                || !(100..=200_000).contains(&range.max_kbps)
+                || range.min_kbps > range.max_kbps
+            {
+                return Err(ClientError::config_invalid(
+                    "adaptive_bitrate",
+                    format!(
+                        "Bitrate range has to be within 100 and 200000 kbps with min_kbps at most \
+                        max_kbps, got {range:?}!"
+                    ),
+                ));
+            }
+        }
+        if let Some(crf) = self.crf {
+            if crf > 51 {
+                return Err(ClientError::config_invalid(
+                    "crf",
+                    format!("CRF has to be between 0 and 51, got {crf}!"),
+                ));
+            }
+        }
+        if let Some(max_fps) = self.max_fps {
+            if !(max_fps > 0.0 && max_fps <= 240.0) {
+                return Err(ClientError::config_invalid(
+                    "max_fps",
+                    format!("Max fps has to be between 0 and 240, got {max_fps}!"),
+                ));
+            }
+        }
+        if let Some(jpeg_quality) = self.jpeg_quality {
+            if !(1..=100).contains(&jpeg_quality) {
+                return Err(ClientError::config_invalid(
+                    "jpeg_quality",
+                    format!("JPEG quality has to be between 1 and 100, got {jpeg_quality}!"),
+                ));
+            }
+        }
+        if let Some(timeout) = self.palm_rejection_timeout_ms {
+            if timeout > 10_000 {
+                return Err(ClientError::config_invalid(
+                    "palm_rejection_timeout_ms",
+                    format!("Palm rejection timeout has to be at most 10000 ms, got {timeout}!"),
+                ));
+            }
+        }
+        if let Some(PointerOrdering::Reorder { window_ms }) = self.pointer_ordering {
+            if window_ms > MAX_REORDER_WINDOW_MS {
+                return Err(ClientError::config_invalid(
+                    "pointer_ordering",
+                    format!(
+                        "Reorder window has to be at most {MAX_REORDER_WINDOW_MS} ms, got \
+                        {window_ms}!"
+                    ),
+                ));
+            }
+        }
+        if let Some(pacing) = self.input_pacing {
+            if !(MIN_PACING_RATE_HZ..=MAX_PACING_RATE_HZ).contains(&pacing.rate_hz) {
+                return Err(ClientError::config_invalid(
+                    "input_pacing",
+                    format!(
+                        "Pacing rate has to be between {MIN_PACING_RATE_HZ} and \
+                        {MAX_PACING_RATE_HZ} Hz, got {}!",
+                        pacing.rate_hz
+                    ),
+                ));
+            }
+        }
+        if let Some(sensitivity) = self.pointer_sensitivity {
+            if !(0.1..=10.0).contains(&sensitivity) {
+                return Err(ClientError::config_invalid(
+                    "pointer_sensitivity",
+                    format!("Pointer sensitivity has to be between 0.1 and 10, got {sensitivity}!"),
+                ));
+            }
+        }
+        if let Some(crop) = &self.crop {
+            crop.validate()
+                .map_err(|message| ClientError::config_invalid("crop", message))?;
+        }
+        if let ScaleMode::Physical {
+            width_mm,
+            height_mm,
+        } = self.scale_mode
+        {
+            if !(width_mm > 0.0 && width_mm <= 10_000.0 && height_mm > 0.0 && height_mm <= 10_000.0)
+            {
+                return Err(ClientError::config_invalid(
+                    "scale_mode",
+                    format!(
+                        "Physical size has to be between 0 and 10000 mm, got {width_mm} x \
+                        {height_mm} mm!"
+                    ),
+                ));
+            }
+        }
+        for (i, mapping) in self.button_mapping.iter().enumerate() {
+            mapping
+                .validate()
+                .map_err(|message| ClientError::config_invalid("button_mapping", message))?;
+            if self.button_mapping[..i].iter().any(|other| {
+                other.pointer_type == mapping.pointer_type && other.button == mapping.button
+            }) {
+                return Err(ClientError::config_invalid(
+                    "button_mapping",
+                    format!(
+                        "Button {:?} of {:?} pointers is mapped more than once!",
+                        mapping.button, mapping.pointer_type
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How the capturable is brought to the view of the client.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum ScaleMode {
+    // the capturable or its crop region is scaled to fit the view
+    #[default]
+    Fit,
+    // things are as large on the client as on the host, for example to draw with the stylus as
+    // on a graphics tablet: the view of the client is width_mm x height_mm large and shows a
+    // region of the capturable that large, centered on the crop region if there is one, which
+    // requires the capturable to have a physical_size
+    Physical {
+        width_mm: f64,
+        height_mm: f64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitrateRange {
+    pub min_kbps: u32,
+    pub max_kbps: u32,
+}
+
+/// Rectangle within the capturable, given relative to its size so it stays valid if the
+/// capturable is resized.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct CropRect {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl CropRect {
+    fn validate(&self) -> Result<(), String> {
+        let inside = self.x >= 0.0
+            && self.y >= 0.0
+            && self.width > 0.0
+            && self.height > 0.0
+            && self.x + self.width <= 1.0
+            && self.y + self.height <= 1.0;
+        if !inside {
+            return Err(format!(
+                "Crop region {self:?} is not inside the capturable!"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Transform coordinates relative to the crop region to coordinates relative to the whole
+    /// capturable.
+    pub fn to_capturable(self, x: f64, y: f64) -> (f64, f64) {
+        (self.x + x * self.width, self.y + y * self.height)
+    }
+
+    /// The region of a capturable of the given physical size that is as large as width_mm x
+    /// height_mm, centered on around or the whole capturable and shrunk to fit into it.
+    pub fn physical(
+        width_mm: f64,
+        height_mm: f64,
+        size: PhysicalSize,
+        around: Option<CropRect>,
+    ) -> Self {
+        let around = around.unwrap_or(CropRect {
+            x: 0.0,
+            y: 0.0,
+            width: 1.0,
+            height: 1.0,
+        });
+        let width = (width_mm / size.width_mm).min(1.0);
+        let height = (height_mm / size.height_mm).min(1.0);
+        let x = (around.x + (around.width - width) / 2.0).clamp(0.0, 1.0 - width);
+        let y = (around.y + (around.height - height) / 2.0).clamp(0.0, 1.0 - height);
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// The region in pixels for an image of the given size, at least one pixel wide and high.
+    pub fn to_pixels(self, width: usize, height: usize) -> (usize, usize, usize, usize) {
+        let x = ((self.x * width as f64) as usize).min(width.saturating_sub(1));
+        let y = ((self.y * height as f64) as usize).min(height.saturating_sub(1));
+        let w =
+            ((self.width * width as f64).round() as usize).clamp(1, width.saturating_sub(x).max(1));
+        let h = ((self.height * height as f64).round() as usize)
+            .clamp(1, height.saturating_sub(y).max(1));
+        (x, y, w, h)
+    }
+}
+
+/// The part of a view, like the video element of the client, that shows the content of the video
+/// if it is scaled to fit the view keeping its aspect ratio, what is left are black bars. The
+/// client does the same in content_rect in ts/lib.ts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ContentArea {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl ContentArea {
+    pub fn letterbox(
+        content_width: usize,
+        content_height: usize,
+        view_width: f64,
+        view_height: f64,
+    ) -> Self {
+        let scale = (view_width / content_width as f64).min(view_height / content_height as f64);
+        if !scale.is_finite() || scale <= 0.0 {
+            // nothing to fit, all of the view counts as content
+            return Self {
+                x: 0.0,
+                y: 0.0,
+                width: view_width,
+                height: view_height,
+            };
+        }
+        // the side that fills the view is taken as is, so its edges are exact
+        let (width, height) = if view_width / content_width as f64 <= scale {
+            (view_width, content_height as f64 * scale)
+        } else {
+            (content_width as f64 * scale, view_height)
+        };
+        Self {
+            x: (view_width - width) / 2.0,
+            y: (view_height - height) / 2.0,
+            width,
+            height,
+        }
+    }
+
+    /// Position relative to the content for a position in the view, positions on the black bars
+    /// end up on the edge of the content.
+    pub fn to_content(&self, x: f64, y: f64) -> (f64, f64) {
+        (
+            ((x - self.x) / self.width).clamp(0.0, 1.0),
+            ((y - self.y) / self.height).clamp(0.0, 1.0),
+        )
+    }
+}
+
+/// Maps the pressure reported by the stylus to the pressure passed on to applications, both range
+/// from 0 to 1.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum PressureCurve {
+    /// pressure^gamma
+    Gamma(f64),
+    /// Piecewise linear curve through the given (input, output) points, (0, 0) and (1, 1) are
+    /// implied.
+    Points(Vec<(f64, f64)>),
+}
+
+/// Filter for the coordinates of the stylus. Both filters make the pen lag behind, at most by
+/// [`crate::input::smoothing::MAX_LAG`] as parameters that would smooth more are clamped.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum PenSmoothing {
+    /// 1€ filter: the cutoff frequency in Hz rises from min_cutoff by beta times the speed of the
+    /// pen in video widths and heights per second. Slow movements are smoothed a lot, fast ones
+    /// hardly lag behind.
+    OneEuro { min_cutoff: f64, beta: f64 },
+    /// Exponential moving average with the given time constant.
+    Ema { time_constant_ms: f64 },
+}
+
+/// What to do with pointer events that arrive in another order than the browser generated them
+/// in, see [`crate::input::ordering::PointerOrder`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PointerOrdering {
+    /// Drop duplicates and events older than the last one of their pointer, adds no latency.
+    DropLate,
+    /// Hold events back for up to window_ms to pass them on sorted by their timestamps, which
+    /// delays input by about as much. Events that arrive even later are dropped like for
+    /// DropLate.
+    Reorder { window_ms: u32 },
+}
+
+/// Longest time pointer events can be held back for [`PointerOrdering::Reorder`].
+pub const MAX_REORDER_WINDOW_MS: u32 = 200;
+
+/// How often movements of pointers are injected, see [`crate::input::pacing::PacedInput`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputPacing {
+    // per pointer
+    #[serde(default = "default_pacing_rate")]
+    pub rate_hz: u32,
+    // the stylus is not paced unless this is set, drawing applications want all of its samples
+    #[serde(default)]
+    pub pace_pen: bool,
+}
+
+fn default_pacing_rate() -> u32 {
+    DEFAULT_PACING_RATE_HZ
+}
+
+/// Movements per second [`InputPacing`] injects by default, the polling rate of most mice.
+pub const DEFAULT_PACING_RATE_HZ: u32 = 125;
+const MIN_PACING_RATE_HZ: u32 = 10;
+const MAX_PACING_RATE_HZ: u32 = 1000;
+
+// longest key combination a button can press
+const MAX_MAPPED_KEYS: usize = 4;
+
+/// Makes a button of a mouse or the stylus do something else than it does by default.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ButtonMapping {
+    pub pointer_type: PointerType,
+    // a single one of the buttons of PointerEvent.buttons
+    #[serde(deserialize_with = "buttons_from", serialize_with = "buttons_to")]
+    pub button: Button,
+    pub action: ButtonAction,
+}
+
+impl ButtonMapping {
+    fn validate(&self) -> Result<(), String> {
+        if !self.pointer_type.mappable_buttons().contains(&self.button) {
+            return Err(format!(
+                "Button {:?} of {:?} pointers can not be mapped!",
+                self.button, self.pointer_type
+            ));
+        }
+        match &self.action {
+            ButtonAction::Keys(keys)
+                if keys.is_empty()
+                    || keys.len() > MAX_MAPPED_KEYS
+                    || keys.iter().any(String::is_empty) =>
+            {
+                Err(format!(
+                    "Key combinations consist of 1 to {MAX_MAPPED_KEYS} key codes, got {keys:?}!"
+                ))
+            }
+            ButtonAction::Eraser if self.pointer_type != PointerType::Pen => {
+                Err("Only the stylus can be turned into the eraser!".into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What pressing a mapped button does, the action is undone once the button is released.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ButtonAction {
+    None,
+    LeftClick,
+    MiddleClick,
+    RightClick,
+    /// BTN_STYLUS, the first button on the barrel of a stylus.
+    Stylus,
+    /// BTN_STYLUS2
+    Stylus2,
+    /// The stylus acts as the eraser while the button is held.
+    Eraser,
+    /// Press the keys with the given KeyboardEvent.code in order, like ["ControlLeft", "KeyZ"].
+    Keys(Vec<String>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PointerMode {
+    /// Pointers control the position on the capturable they point at.
+    #[default]
+    Absolute,
+    /// Pointers move the cursor by how far they moved, like a touchpad, tapping clicks.
+    Relative,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VideoFormat {
+    /// Fragmented MP4 stream for playback via MediaSource.
+    #[default]
+    Mp4,
+    /// Every frame is sent as a JPEG image, for browsers that can not play back the MP4 stream.
+    Mjpeg,
+    /// Lossless, only the tiles that changed since the previous frame are sent as PNG images, see
+    /// tiles::TileEncoder for the format. All of them are sent on RequestKeyFrame.
+    Tiles,
+}
+
+/// Codecs the MP4 stream can be encoded with.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoCodec {
+    /// H.264 constrained baseline profile, the one browsers are most likely to play back.
+    H264Baseline,
+    H264Main,
+    /// Encoded in software, which is slower than the hardware encoders for H.264.
+    Vp9,
+    /// Only encoded in hardware.
+    Hevc,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -21,18 +539,325 @@ pub enum MessageInbound {
     Config(ClientConfiguration),
     PauseVideo,
     ResumeVideo,
+    // the client failed to decode the video and needs a keyframe to recover
+    RequestKeyFrame,
+    // switch between view-only mode and controlling the computer without a full reconfiguration
+    SetInputEnabled(bool),
+    // record the video of this client to a file on the host until stopped or reconfigured
+    StartRecording,
+    StopRecording,
+    // text only for now, other kinds of data get variants of their own
+    ClipboardSet(String),
+    // answered with a Pong right away to measure the round trip time, client_ts is echoed back
+    Ping {
+        id: u32,
+        client_ts: f64,
+    },
+    // input from all clients has been switched on or off on the host, never sent by clients
+    #[serde(skip_deserializing)]
+    HostInputEnabled(bool),
+    // a configuration from the HTTP API, its capturable_id may be the id GET /api/capturables
+    // lists instead of the one the session knows the capturable by, never sent by clients
+    #[serde(skip_deserializing)]
+    ApiConfig(ClientConfiguration),
+    // sent on a new connection instead of configuring everything again, session_id is the one
+    // received in Session
+    Resume {
+        session_id: String,
+    },
+    // the connection of the client dropped and it may resume the session, never sent by clients
+    #[serde(skip_deserializing)]
+    SessionSuspended,
+    // the client is back on a new connection, never sent by clients
+    #[serde(skip_deserializing)]
+    SessionResumed,
+    // answered with a ConnectionList of all clients connected to the server
+    GetConnectionList,
+    // answered with AudioSources
+    GetAudioSources,
+    // sets up a WebRTC connection to send input over, only if ServerCapabilities allow it,
+    // answered with RtcAnswer
+    RtcOffer {
+        sdp: String,
+    },
+    RtcCandidate(RtcCandidate),
+}
+
+/// ICE candidate of a WebRTC connection, the fields are those of RTCIceCandidateInit in the
+/// browser.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RtcCandidate {
+    pub candidate: String,
+    pub sdp_mid: Option<String>,
+    pub sdp_m_line_index: Option<u16>,
+    #[serde(default)]
+    pub username_fragment: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CapturableInfo {
+    pub id: String,
+    pub name: String,
+    pub geometry: Option<Geometry>,
+    // None if the screen does not tell, which is always the case for windows
+    pub physical_size: Option<PhysicalSize>,
+    // None for windows and if the platform does not tell which output shows the monitor
+    pub monitor: Option<MonitorInfo>,
+    // WM_CLASS and _NET_WM_PID on X11, None for screens and if the window does not set them
+    pub class: Option<String>,
+    pub pid: Option<u32>,
+    // selected when the client has not chosen a capturable yet
+    pub default: bool,
+}
+
+/// A websocket connection to the server.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConnectionInfo {
+    // unique as long as the server runs, log lines of the connection carry it as well
+    pub id: u64,
+    // None in lists sent to clients that are not on the host
+    pub address: Option<SocketAddr>,
+    // milliseconds since the UNIX epoch
+    pub connected_at_ms: f64,
+    pub last_activity_ms: f64,
+    // what the client is configured to show, None until it sent its configuration
+    pub config: Option<String>,
+    pub frames_sent: u64,
+    // measured over the last few seconds, None until the client received video for a while
+    pub stats: Option<VideoStats>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AudioSource {
+    pub id: String,
+    pub name: String,
+}
+
+/// Timings of the video pipeline averaged over the frames sent since the last report, along with
+/// how often input had to be coalesced in that time.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VideoStats {
+    pub frames: u32,
+    // frames actually sent per second, at most the frame rate asked for
+    pub fps: f64,
+    pub capture_ms: f64,
+    pub encode_ms: f64,
+    pub frame_bytes: usize,
+    pub dropped_frames: u32,
+    // frames captured but replaced by a newer one before they could be encoded, they are the gaps
+    // in the sequence numbers of FrameInfo
+    pub lost_frames: u32,
+    // frames not encoded because the connection to the client could not keep up
+    pub congestion_skipped_frames: u32,
+    // what the bitrate has been adapted to, None unless adaptive_bitrate is configured
+    pub target_bitrate_kbps: Option<u32>,
+    // pointer movements merged into newer ones as injecting input fell behind
+    pub coalesced_pointer_events: u32,
+    // the FFmpeg encoder of the MP4 stream, None for the other formats
+    pub encoder: Option<String>,
+}
+
+/// Sent right after a frame of video if the client asked for it, to find out which frames have
+/// been dropped and when the frames have been captured.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FrameInfo {
+    // Frames are numbered in the order they have been captured. Frames sent again because the
+    // content did not change keep their number, numbers are skipped for lost frames and for
+    // frames this client is not sent as it receives fewer frames than others watching the same
+    // capturable.
+    pub sequence: u64,
+    // see video::monotonic_micros
+    pub captured_at_us: u64,
+    // the time the frame is shown at in the MP4 stream in microseconds, equal to captured_at_us
+    // unless the frame has been sent before, None unless the video is VideoFormat::Mp4
+    pub video_timestamp_us: Option<u64>,
+}
+
+/// What went wrong, clients decide how to react based on this.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind")]
+pub enum ErrorKind {
+    // the capturable asked for does not exist (anymore)
+    CapturableLost,
+    CaptureInitFailed,
+    EncoderInitFailed {
+        reason: String,
+    },
+    // uinput devices can not be created, errno is the reason opening path failed, if known, hint
+    // tells the user how to fix it
+    UInputPermissionDenied {
+        path: String,
+        errno: Option<i32>,
+        // groups Weylus runs with and the one owning path
+        groups: Vec<String>,
+        device_group: Option<String>,
+        hint: String,
+        udev_rule: String,
+    },
+    InputDeviceFailed,
+    ConfigInvalid {
+        field: String,
+    },
+    InputFailed,
+    RecordingFailed,
+    ClipboardFailed,
+    // capturing or encoding audio failed, it has been stopped while the video goes on
+    AudioFailed,
+    InvalidMessage,
+    // the session to resume does not exist (anymore), the client has to start over
+    SessionExpired,
+    // asked for keyframes or a recording while configured without video
+    VideoDisabled,
+    // the server does not offer WebRTC or setting it up failed, input goes via the websocket
+    RtcUnavailable,
+    // errors of the HTTP API: the access code is missing or wrong, the connection or what has
+    // been asked for does not exist (anymore)
+    Unauthorized,
+    NotFound,
+}
+
+/// Error reported to the client, the message is meant to be shown to the user.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientError {
+    #[serde(flatten)]
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl ClientError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    pub fn config_invalid(field: &str, message: impl Into<String>) -> Self {
+        Self::new(
+            ErrorKind::ConfigInvalid {
+                field: field.into(),
+            },
+            message,
+        )
+    }
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Messages sent to the client as JSON. Video is sent as binary messages, each starts with the
+/// time it has been sent at as little endian f64 of milliseconds since the UNIX epoch, followed by
+/// the MP4 fragment or JPEG. Audio is sent the same way, each Opus packet right after an
+/// AudioPacket message.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum MessageOutbound {
-    CapturableList(Vec<String>),
+    CapturableList(Vec<CapturableInfo>),
+    // updates of the CapturableList sent last, ids are not reused, once a capturable has been
+    // removed its id stays unknown; CapturableUpdated replaces the entry with the same id
+    CapturableAdded(CapturableInfo),
+    CapturableRemoved {
+        id: String,
+    },
+    CapturableUpdated(CapturableInfo),
+    ConnectionList(Vec<ConnectionInfo>),
     NewVideo,
+    // sent once the encoder of a new video has been created, which may be after its first data,
+    // and for VideoFormat::Mjpeg and VideoFormat::Tiles whenever the size of the images changes;
+    // mime_type is what to play back the MP4 stream with and encoder the name of the FFmpeg
+    // encoder producing it, like h264_nvenc, both are None for the others
+    VideoConfig {
+        format: VideoFormat,
+        codec: Option<VideoCodec>,
+        mime_type: Option<String>,
+        encoder: Option<String>,
+        // size of the frames in pixels, all of which is content, a client that letterboxes the
+        // video tells the black bars from the content by it, see ContentArea
+        width: usize,
+        height: usize,
+    },
+    // the capturable has vanished, like a window that has been closed, the client has to choose
+    // another one
+    CapturableLost,
+    // how the scale_mode of a configuration with ScaleMode::Physical has been applied, sent right
+    // before ConfigOk: the video covers width_mm x height_mm of the view, less than requested if
+    // the capturable is physically smaller; ScaleMode::Fit with the reason in fallback if the
+    // physical size of the capturable is unknown
+    ScaleApplied {
+        scale_mode: ScaleMode,
+        fallback: Option<String>,
+    },
     ConfigOk,
-    ConfigError(String),
-    Error(String),
+    // the configuration has not been applied
+    ConfigError(ClientError),
+    CaptureMethod(String),
+    Stats(VideoStats),
+    FrameInfo(FrameInfo),
+    // where the hotspot of the cursor of the host is, x and y relative to the width and height of
+    // the video, sent whenever it moved or changed its shape, at most about 30 times per second;
+    // visible is false if the cursor is outside of the video or has been hidden
+    CursorPosition {
+        x: f64,
+        y: f64,
+        visible: bool,
+        shape_id: u64,
+    },
+    // sent before the first visible CursorPosition with a shape the client does not have, the
+    // client has to keep at least the last MAX_CURSOR_SHAPES shapes it received; png_base64 is the
+    // image as base64 encoded PNG and hotspot the pixel of it that points
+    CursorShape {
+        id: u64,
+        png_base64: String,
+        hotspot: (u32, u32),
+    },
+    ClipboardContent(String),
+    AudioSources(Vec<AudioSource>),
+    // the next binary message is an Opus packet, not video, captured_at_us is the time its first
+    // sample has been captured at, see video::monotonic_micros, and matches the timestamps of the
+    // MP4 stream
+    AudioPacket {
+        captured_at_us: u64,
+    },
+    Error(ClientError),
+    // server_ts in milliseconds since the UNIX epoch
+    Pong {
+        id: u32,
+        client_ts: f64,
+        server_ts: f64,
+    },
+    // input from all clients has been switched on or off on the host, events sent while it is
+    // off are dropped
+    HostInputEnabled(bool),
+    // sent once the client is configured, the session can be resumed with this id if the
+    // connection drops
+    Session {
+        session_id: String,
+    },
+    // answer to Resume, followed by NewVideo
+    SessionResumed,
+    // sent right before the connection is closed because Weylus is quitting, the client should
+    // not try to reconnect
+    ServerShutdown,
+    // sent first on each connection, the optional parts of the protocol the server supports
+    ServerCapabilities {
+        // input may be sent over WebRTC, see RtcOffer
+        webrtc: bool,
+    },
+    // answer to RtcOffer, ICE candidates of the server follow as RtcCandidate
+    RtcAnswer {
+        sdp: String,
+    },
+    RtcCandidate(RtcCandidate),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Shapes of the cursor the server assumes a client to keep, see [`MessageOutbound::CursorShape`].
+/// Older ones are sent again when they are needed.
+pub const MAX_CURSOR_SHAPES: usize = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PointerType {
     #[serde(rename = "")]
     Unknown,
@@ -44,7 +869,30 @@ pub enum PointerType {
     Touch,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl PointerType {
+    /// Buttons that can be given a ButtonMapping. The tip and the eraser end of the stylus are
+    /// what it touches the screen with, as are touches.
+    pub fn mappable_buttons(self) -> &'static [Button] {
+        match self {
+            PointerType::Mouse | PointerType::Unknown => &[
+                Button::PRIMARY,
+                Button::SECONDARY,
+                Button::AUXILARY,
+                Button::FOURTH,
+                Button::FIFTH,
+            ],
+            PointerType::Pen => &[
+                Button::SECONDARY,
+                Button::AUXILARY,
+                Button::FOURTH,
+                Button::FIFTH,
+            ],
+            PointerType::Touch => &[],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum PointerEventType {
     #[serde(rename = "pointerdown")]
     DOWN,
@@ -100,7 +948,7 @@ bitflags! {
     }
 }
 
-fn button_from<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Button, D::Error> {
+fn buttons_from<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Button, D::Error> {
     let bits: u8 = Deserialize::deserialize(deserializer)?;
     Button::from_bits(bits).map_or(
         Err(serde::de::Error::custom("Failed to parse button code.")),
@@ -108,6 +956,77 @@ fn button_from<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Button, D::
     )
 }
 
+fn buttons_to<S: Serializer>(buttons: &Button, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u8(buttons.bits())
+}
+
+/// Button whose state changed with a pointer event, numbered like the button property of pointer
+/// events in browsers. Which buttons are down is given by the buttons bitmask instead.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "i8", into = "i8")]
+pub enum PointerButton {
+    /// -1, no button changed, which is the case for most moves
+    None,
+    /// 0, left mouse button, touch contact or tip of the pen
+    Primary,
+    /// 1, middle mouse button
+    Auxiliary,
+    /// 2, right mouse button or barrel button of the pen
+    Secondary,
+    /// 3, usually back
+    Fourth,
+    /// 4, usually forward
+    Fifth,
+    /// 5, eraser end of the pen
+    Eraser,
+}
+
+impl PointerButton {
+    /// The flag of this button in the buttons bitmask.
+    pub fn flag(self) -> Button {
+        match self {
+            PointerButton::None => Button::NONE,
+            PointerButton::Primary => Button::PRIMARY,
+            PointerButton::Auxiliary => Button::AUXILARY,
+            PointerButton::Secondary => Button::SECONDARY,
+            PointerButton::Fourth => Button::FOURTH,
+            PointerButton::Fifth => Button::FIFTH,
+            PointerButton::Eraser => Button::ERASER,
+        }
+    }
+}
+
+impl TryFrom<i8> for PointerButton {
+    type Error = String;
+
+    fn try_from(button: i8) -> Result<Self, Self::Error> {
+        Ok(match button {
+            -1 => PointerButton::None,
+            0 => PointerButton::Primary,
+            1 => PointerButton::Auxiliary,
+            2 => PointerButton::Secondary,
+            3 => PointerButton::Fourth,
+            4 => PointerButton::Fifth,
+            5 => PointerButton::Eraser,
+            _ => return Err(format!("Unknown pointer button {button}.")),
+        })
+    }
+}
+
+impl From<PointerButton> for i8 {
+    fn from(button: PointerButton) -> Self {
+        match button {
+            PointerButton::None => -1,
+            PointerButton::Primary => 0,
+            PointerButton::Auxiliary => 1,
+            PointerButton::Secondary => 2,
+            PointerButton::Fourth => 3,
+            PointerButton::Fifth => 4,
+            PointerButton::Eraser => 5,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct KeyboardEvent {
     pub event_type: KeyboardEventType,
@@ -121,17 +1040,21 @@ pub struct KeyboardEvent {
     pub meta: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PointerEvent {
     pub event_type: PointerEventType,
     pub pointer_id: i64,
     pub timestamp: u64,
     pub is_primary: bool,
     pub pointer_type: PointerType,
-    #[serde(deserialize_with = "button_from")]
-    pub button: Button,
-    #[serde(deserialize_with = "button_from")]
+    pub button: PointerButton,
+    // buttons that are down, input devices press and release buttons by how this changes as
+    // button is not reliable for that: pressing a second button while another one is down only
+    // shows up as pointermove, and pointerup may not name the released button
+    #[serde(deserialize_with = "buttons_from", serialize_with = "buttons_to")]
     pub buttons: Button,
+    // relative to the content of the video, that is the capturable or its crop region, 0 and 1
+    // are its edges however the client lays out the video, see ContentArea
     pub x: f64,
     pub y: f64,
     pub movement_x: i64,
@@ -140,23 +1063,289 @@ pub struct PointerEvent {
     pub tilt_x: i32,
     pub tilt_y: i32,
     pub twist: i32,
+    // size of the contact relative to the video like x and y, 0 if unknown
     pub width: f64,
     pub height: f64,
+    // the eraser end of the stylus is used
+    #[serde(default)]
+    pub is_eraser: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum WheelDeltaMode {
+    Pixel,
+    Line,
+    Page,
+}
+
+fn delta_mode_from<'de, D: Deserializer<'de>>(deserializer: D) -> Result<WheelDeltaMode, D::Error> {
+    let mode: u8 = Deserialize::deserialize(deserializer)?;
+    match mode {
+        0 => Ok(WheelDeltaMode::Pixel),
+        1 => Ok(WheelDeltaMode::Line),
+        2 => Ok(WheelDeltaMode::Page),
+        _ => Err(serde::de::Error::custom(
+            "Failed to parse wheel delta mode.",
+        )),
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct WheelEvent {
-    pub dx: i32,
-    pub dy: i32,
+    pub dx: f64,
+    pub dy: f64,
+    #[serde(deserialize_with = "delta_mode_from")]
+    pub delta_mode: WheelDeltaMode,
     pub timestamp: u64,
 }
 
+/// Scroll distance corresponding to a single notch of a classic scroll wheel, this matches
+/// REL_WHEEL_HI_RES on Linux and WHEEL_DELTA on Windows.
+pub const WHEEL_NOTCH: f64 = 120.0;
+
+impl WheelEvent {
+    /// Return dx, dy in units of 1/120 of a scroll wheel notch.
+    ///
+    /// There is not always a way to accurately convert the DOM's scroll distance into notches,
+    /// the following is a guesstimate: most browsers report 100 pixels or 3 lines per notch.
+    pub fn hi_res_delta(&self) -> (f64, f64) {
+        let scale = match self.delta_mode {
+            WheelDeltaMode::Pixel => WHEEL_NOTCH / 100.0,
+            WheelDeltaMode::Line => WHEEL_NOTCH / 3.0,
+            WheelDeltaMode::Page => WHEEL_NOTCH * 10.0,
+        };
+        (scale * self.dx, scale * self.dy)
+    }
+}
+
 pub trait WeylusSender {
     type Error: std::error::Error;
     fn send_message(&mut self, message: MessageOutbound) -> Result<(), Self::Error>;
     fn send_video(&mut self, bytes: &[u8]) -> Result<(), Self::Error>;
+    /// Send an Opus packet, packets are dropped instead of waiting if the connection can not keep
+    /// up.
+    fn send_audio(&mut self, captured_at_us: u64, bytes: &[u8]) -> Result<(), Self::Error>;
+    /// Amount of video data handed to send_video that has not been sent to the client yet.
+    fn pending_video_bytes(&self) -> usize {
+        0
+    }
+    /// Movements of pointers that have been merged into newer ones since the last call because
+    /// the input of the client could not be injected as fast as it arrived.
+    fn take_coalesced_pointer_events(&self) -> u32 {
+        0
+    }
+    /// Called with the stats of the video sent via this sender every few seconds, also if the
+    /// client has not asked for them.
+    fn stats_measured(&mut self, _stats: &VideoStats) {}
 }
 
 pub trait WeylusReceiver: Iterator<Item = Result<MessageInbound, Self::Error>> {
     type Error: std::error::Error;
+
+    /// Like next but waiting for at most timeout. Receivers that never block, like one replaying
+    /// recorded messages, do not have to implement it.
+    fn next_timeout(&mut self, _timeout: Duration) -> Result<Self::Item, RecvTimeoutError> {
+        self.next().ok_or(RecvTimeoutError::Disconnected)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn pointer_event(
+        pointer_type: PointerType,
+        event_type: PointerEventType,
+        pointer_id: i64,
+        timestamp_ms: u64,
+    ) -> PointerEvent {
+        PointerEvent {
+            event_type,
+            pointer_id,
+            timestamp: timestamp_ms * 1000,
+            is_primary: true,
+            pointer_type,
+            button: PointerButton::None,
+            buttons: Button::NONE,
+            x: 0.5,
+            y: 0.5,
+            movement_x: 0,
+            movement_y: 0,
+            pressure: 0.5,
+            tilt_x: 0,
+            tilt_y: 0,
+            twist: 0,
+            width: 0.0,
+            height: 0.0,
+            is_eraser: false,
+        }
+    }
+
+    // pointer events as ts/lib.ts sends them, recorded in Chrome and Firefox
+    const POINTER_EVENTS: &[(&str, PointerButton, Button)] = &[
+        // Chrome, right click
+        (
+            r#"{"event_type":"pointerdown","pointer_id":1,"timestamp":5392118000,"is_primary":true,
+            "pointer_type":"mouse","button":2,"buttons":2,"x":0.4140625,"y":0.3125,"movement_x":0,
+            "movement_y":0,"pressure":0.5,"tilt_x":0,"tilt_y":0,"twist":0,"width":0.00078125,
+            "height":0.001388888888888889,"is_eraser":false}"#,
+            PointerButton::Secondary,
+            Button::SECONDARY,
+        ),
+        // Chrome, moving with the right button held
+        (
+            r#"{"event_type":"pointermove","pointer_id":1,"timestamp":5392134000,"is_primary":true,
+            "pointer_type":"mouse","button":-1,"buttons":2,"x":0.41484375,"y":0.31388888888888886,
+            "movement_x":1,"movement_y":1,"pressure":0.5,"tilt_x":0,"tilt_y":0,"twist":0,
+            "width":0.00078125,"height":0.001388888888888889,"is_eraser":false}"#,
+            PointerButton::None,
+            Button::SECONDARY,
+        ),
+        // Chrome, pressing the left button while the right one is held only shows up as move
+        (
+            r#"{"event_type":"pointermove","pointer_id":1,"timestamp":5392201000,"is_primary":true,
+            "pointer_type":"mouse","button":0,"buttons":3,"x":0.41484375,"y":0.31388888888888886,
+            "movement_x":0,"movement_y":0,"pressure":0.5,"tilt_x":0,"tilt_y":0,"twist":0,
+            "width":0.00078125,"height":0.001388888888888889,"is_eraser":false}"#,
+            PointerButton::Primary,
+            Button::PRIMARY.union(Button::SECONDARY),
+        ),
+        // Firefox, middle click, released
+        (
+            r#"{"event_type":"pointerup","pointer_id":0,"timestamp":18039000,"is_primary":true,
+            "pointer_type":"mouse","button":1,"buttons":0,"x":0.63671875,"y":0.5263888888888889,
+            "movement_x":0,"movement_y":0,"pressure":0,"tilt_x":0,"tilt_y":0,"twist":0,"width":0,
+            "height":0,"is_eraser":false}"#,
+            PointerButton::Auxiliary,
+            Button::NONE,
+        ),
+        // Firefox, barrel button of the pen while hovering
+        (
+            r#"{"event_type":"pointerdown","pointer_id":2,"timestamp":21575000,"is_primary":true,
+            "pointer_type":"pen","button":2,"buttons":2,"x":0.2890625,"y":0.71875,"movement_x":0,
+            "movement_y":0,"pressure":0,"tilt_x":-12,"tilt_y":31,"twist":0,"width":0,"height":0,
+            "is_eraser":false}"#,
+            PointerButton::Secondary,
+            Button::SECONDARY,
+        ),
+        // Firefox, eraser end of the pen touching the screen
+        (
+            r#"{"event_type":"pointerdown","pointer_id":2,"timestamp":23811000,"is_primary":true,
+            "pointer_type":"pen","button":5,"buttons":32,"x":0.30078125,"y":0.70138888888888884,
+            "movement_x":0,"movement_y":0,"pressure":0.3720703125,"tilt_x":8,"tilt_y":-20,
+            "twist":0,"width":0,"height":0,"is_eraser":true}"#,
+            PointerButton::Eraser,
+            Button::ERASER,
+        ),
+    ];
+
+    #[test]
+    fn pointer_event_buttons_from_browsers() {
+        for (json, button, buttons) in POINTER_EVENTS {
+            let event: PointerEvent = serde_json::from_str(json).unwrap();
+            assert_eq!(event.button, *button, "{json}");
+            assert_eq!(event.buttons, *buttons, "{json}");
+            let sent: PointerEvent =
+                serde_json::from_str(&serde_json::to_string(&event).unwrap()).unwrap();
+            assert_eq!(format!("{sent:?}"), format!("{event:?}"));
+        }
+        // there is no button 6, nor is button a bitmask like buttons
+        let (json, _, _) = POINTER_EVENTS[0];
+        for button in [6, 16] {
+            let json = json.replace(r#""button":2"#, &format!(r#""button":{button}"#));
+            assert!(
+                serde_json::from_str::<PointerEvent>(&json).is_err(),
+                "{json}"
+            );
+        }
+    }
+
+    #[test]
+    fn rtc_candidate_from_browsers() {
+        // RTCIceCandidate.toJSON() in Chrome, Safari leaves out usernameFragment
+        for json in [
+            r#"{"RtcCandidate":{
+            "candidate":"candidate:842163049 1 udp 2122260223 192.168.1.20 52811 typ host",
+            "sdpMid":"0","sdpMLineIndex":0,"usernameFragment":"nJx3"}}"#,
+            r#"{"RtcCandidate":{"candidate":"candidate:1 1 UDP 2122252543 ab.local 60419 typ host",
+            "sdpMid":"0","sdpMLineIndex":0}}"#,
+        ] {
+            match serde_json::from_str(json).unwrap() {
+                MessageInbound::RtcCandidate(candidate) => {
+                    assert!(candidate.candidate.starts_with("candidate:"), "{json}");
+                    assert_eq!(candidate.sdp_mid.as_deref(), Some("0"));
+                    assert_eq!(candidate.sdp_m_line_index, Some(0));
+                    // sent back as is, so the browser takes the candidates of the server
+                    let sent = serde_json::to_value(&candidate).unwrap();
+                    assert_eq!(sent["sdpMLineIndex"], 0, "{json}");
+                }
+                msg => panic!("Not a candidate: {msg:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn content_area_maps_edges_exactly() {
+        // pillarbox, 1920x1080 in a square view leaves bars above and below
+        let area = ContentArea::letterbox(1920, 1080, 1000.0, 1000.0);
+        assert_eq!(area.x, 0.0);
+        assert_eq!(area.width, 1000.0);
+        assert_eq!(area.height, 562.5);
+        assert_eq!(area.y, 218.75);
+        assert_eq!(area.to_content(0.0, 218.75), (0.0, 0.0));
+        assert_eq!(area.to_content(1000.0, 781.25), (1.0, 1.0));
+        assert_eq!(area.to_content(500.0, 500.0), (0.5, 0.5));
+        // the black bars clamp to the edge of the content
+        assert_eq!(area.to_content(250.0, 10.0), (0.25, 0.0));
+        assert_eq!(area.to_content(250.0, 990.0), (0.25, 1.0));
+
+        // a tall capturable, like a rotated monitor, in a wide view leaves bars left and right
+        let area = ContentArea::letterbox(1080, 1920, 1600.0, 800.0);
+        assert_eq!(area.height, 800.0);
+        assert_eq!(area.width, 450.0);
+        assert_eq!(area.x, 575.0);
+        assert_eq!(area.to_content(575.0, 0.0), (0.0, 0.0));
+        assert_eq!(area.to_content(1025.0, 800.0), (1.0, 1.0));
+        assert_eq!(area.to_content(0.0, 400.0), (0.0, 0.5));
+        assert_eq!(area.to_content(1600.0, 400.0), (1.0, 0.5));
+
+        // without a size to fit the view is all content
+        let area = ContentArea::letterbox(0, 0, 800.0, 600.0);
+        assert_eq!(area.to_content(400.0, 150.0), (0.5, 0.25));
+    }
+
+    #[test]
+    fn physical_scale_crops_as_large_as_the_view() {
+        let monitor = PhysicalSize::new(300.0, 200.0, 1200, 800);
+        assert_eq!(monitor.dpi, 101.6);
+
+        // centered on the capturable
+        let crop = CropRect::physical(150.0, 50.0, monitor, None);
+        assert_eq!(
+            crop,
+            CropRect {
+                x: 0.25,
+                y: 0.375,
+                width: 0.5,
+                height: 0.25
+            }
+        );
+        let (x, y) = crop.to_capturable(0.5, 0.5);
+        assert_eq!((x, y), (0.5, 0.5));
+
+        // centered on the crop region, but kept inside the capturable
+        let around = CropRect {
+            x: 0.0,
+            y: 0.5,
+            width: 0.25,
+            height: 0.5,
+        };
+        let crop = CropRect::physical(150.0, 50.0, monitor, Some(around));
+        assert_eq!((crop.x, crop.y), (0.0, 0.625));
+
+        // a view larger than the capturable shows all of it
+        let crop = CropRect::physical(600.0, 100.0, monitor, None);
+        assert_eq!((crop.x, crop.width), (0.0, 1.0));
+        assert_eq!(crop.width * monitor.width_mm, 300.0);
+    }
 }
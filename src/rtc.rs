@@ -0,0 +1,134 @@
+//! Input sent by clients over WebRTC instead of the websocket, only available if Weylus is built
+//! with the webrtc feature.
+
+#[cfg(feature = "webrtc")]
+mod connection;
+
+#[cfg(feature = "webrtc")]
+pub use connection::RtcInput;
+
+use std::collections::HashSet;
+
+use crate::protocol::{Button, PointerEvent, PointerEventType};
+
+/// Pointer event received via WebRTC.
+#[derive(Debug)]
+pub struct RtcPointerEvent {
+    pub event: PointerEvent,
+    /// Received via the unordered channel, the event may have been overtaken by later ones.
+    pub unordered: bool,
+}
+
+/// Keeps track of the pointer events forwarded to the handler to tell which of the movements
+/// received via the unordered WebRTC channel have been overtaken. Pointers are pressed and lifted
+/// via the websocket or the ordered channel, so a movement with buttons pressed that arrives ahead
+/// of its pointer being pressed, or after it has been lifted, is dropped as well.
+#[derive(Default)]
+pub struct RtcOrder {
+    newest_timestamp: u64,
+    // pointers that have been pressed and not been lifted yet
+    down: HashSet<i64>,
+}
+
+impl RtcOrder {
+    /// True for a movement received via the unordered channel that is to be dropped.
+    pub fn is_stale(&self, event: &PointerEvent) -> bool {
+        event.timestamp < self.newest_timestamp
+            || (event.buttons != Button::NONE && !self.down.contains(&event.pointer_id))
+    }
+
+    /// Note a pointer event that is forwarded to the handler, whichever way it has been received.
+    pub fn forwarded(&mut self, event: &PointerEvent) {
+        self.newest_timestamp = self.newest_timestamp.max(event.timestamp);
+        match event.event_type {
+            PointerEventType::DOWN => {
+                self.down.insert(event.pointer_id);
+            }
+            PointerEventType::UP | PointerEventType::CANCEL => {
+                self.down.remove(&event.pointer_id);
+            }
+            PointerEventType::MOVE => (),
+        }
+    }
+}
+
+/// Stands in for the WebRTC connection if Weylus is built without the webrtc feature, offers of
+/// clients are turned down.
+#[cfg(not(feature = "webrtc"))]
+pub struct RtcInput;
+
+#[cfg(not(feature = "webrtc"))]
+impl RtcInput {
+    pub async fn answer(
+        _sdp: String,
+        _sender_signal: tokio::sync::mpsc::UnboundedSender<crate::protocol::MessageOutbound>,
+        _sender_inbound: tokio::sync::mpsc::Sender<RtcPointerEvent>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Err("Weylus has been built without WebRTC support.".into())
+    }
+
+    pub async fn add_candidate(&self, _candidate: crate::protocol::RtcCandidate) {}
+
+    pub async fn close(self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol;
+    use crate::protocol::tests::pointer_event;
+
+    #[test]
+    fn overtaken_pointer_events_are_stale() {
+        let mut order = RtcOrder::default();
+        let hover = pointer_event(
+            protocol::PointerType::Pen,
+            protocol::PointerEventType::MOVE,
+            1,
+            10,
+        );
+        assert!(!order.is_stale(&hover));
+        order.forwarded(&hover);
+        assert!(!order.is_stale(&hover));
+        // a hover movement sent before the pen touched down arrives after the down
+        order.forwarded(&pointer_event(
+            protocol::PointerType::Pen,
+            protocol::PointerEventType::DOWN,
+            1,
+            11,
+        ));
+        assert!(order.is_stale(&hover));
+    }
+
+    #[test]
+    fn pressed_movements_need_their_pointer_down() {
+        use protocol::PointerEventType::*;
+        use protocol::PointerType::Touch;
+
+        let touch = |event_type: protocol::PointerEventType, pointer_id, timestamp_ms| {
+            let pressed = event_type != UP;
+            let mut event = pointer_event(Touch, event_type, pointer_id, timestamp_ms);
+            if pressed {
+                event.buttons = Button::PRIMARY;
+            }
+            event
+        };
+        let mut order = RtcOrder::default();
+        // the first movement of the stroke overtook the touch down
+        assert!(order.is_stale(&touch(MOVE, 1, 5)));
+        order.forwarded(&touch(DOWN, 1, 4));
+        assert!(!order.is_stale(&touch(MOVE, 1, 6)));
+        order.forwarded(&touch(MOVE, 1, 6));
+        // a second finger that is down already does not hold up the first
+        order.forwarded(&touch(DOWN, 2, 7));
+        assert!(!order.is_stale(&touch(MOVE, 1, 8)));
+        order.forwarded(&touch(MOVE, 1, 8));
+        assert!(!order.is_stale(&touch(MOVE, 2, 9)));
+        order.forwarded(&touch(UP, 1, 10));
+        // the last movement of the stroke arrives after the finger has been lifted
+        assert!(order.is_stale(&touch(MOVE, 1, 9)));
+        // only a down presses the pointer again, a pressed movement that follows the up does not
+        assert!(order.is_stale(&touch(MOVE, 1, 11)));
+        assert!(!order.is_stale(&touch(MOVE, 2, 11)));
+    }
+}
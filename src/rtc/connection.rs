@@ -0,0 +1,305 @@
+use std::error::Error;
+use std::sync::Arc;
+
+use tracing::{debug, info, warn};
+use webrtc::api::APIBuilder;
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+
+use super::RtcPointerEvent;
+use crate::protocol::{MessageInbound, MessageOutbound, PointerEventType, RtcCandidate};
+
+/// Label of the unordered data channel the client sends pointer movements over.
+const INPUT_CHANNEL: &str = "input";
+
+/// Label of the ordered and reliable data channel the client presses and lifts pointers over.
+const CONTACT_CHANNEL: &str = "contact";
+
+/// WebRTC connection of a client that is signaled via its websocket. The client sends pointer
+/// movements, which may as well get lost, over an unordered data channel without retransmissions,
+/// so a lost packet does not hold up the events behind it as it does on the websocket. Pressing
+/// and lifting pointers must neither get lost nor be reordered, these events go over an ordered
+/// and reliable channel, which is not held up by the video either. Everything else stays on the
+/// websocket, movements that have been overtaken are dropped by [`super::RtcOrder`].
+pub struct RtcInput {
+    peer_connection: Arc<RTCPeerConnection>,
+}
+
+impl RtcInput {
+    /// Answer the offer of the client. The answer and the ICE candidates of the server are sent
+    /// to sender_signal in that order, the messages the client sends on the input channel to
+    /// sender_inbound.
+    pub async fn answer(
+        sdp: String,
+        sender_signal: tokio::sync::mpsc::UnboundedSender<MessageOutbound>,
+        sender_inbound: tokio::sync::mpsc::Sender<RtcPointerEvent>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let api = APIBuilder::new().build();
+        // clients connect via the local network, there is no need for STUN or TURN servers
+        let peer_connection = api.new_peer_connection(RTCConfiguration::default()).await?;
+        let peer_connection = Arc::new(peer_connection);
+
+        peer_connection.on_ice_candidate(Box::new({
+            let sender_signal = sender_signal.clone();
+            move |candidate: Option<RTCIceCandidate>| {
+                // None once gathering is complete, the client does not need to be told
+                if let Some(candidate) = candidate {
+                    match candidate.to_json() {
+                        Ok(init) => {
+                            let candidate = MessageOutbound::RtcCandidate(RtcCandidate {
+                                candidate: init.candidate,
+                                sdp_mid: init.sdp_mid,
+                                sdp_m_line_index: init.sdp_mline_index,
+                                username_fragment: init.username_fragment,
+                            });
+                            // fails only once the connection to the client is gone
+                            let _ = sender_signal.send(candidate);
+                        }
+                        Err(err) => warn!("Failed to serialize ICE candidate: {err}"),
+                    }
+                }
+                Box::pin(async {})
+            }
+        }));
+
+        peer_connection.on_peer_connection_state_change(Box::new(
+            |state: RTCPeerConnectionState| {
+                match state {
+                    RTCPeerConnectionState::Connected => info!("WebRTC connection established."),
+                    RTCPeerConnectionState::Failed => {
+                        info!("WebRTC connection failed, the client falls back to the websocket.")
+                    }
+                    state => debug!("WebRTC connection state: {state}"),
+                }
+                Box::pin(async {})
+            },
+        ));
+
+        peer_connection.on_data_channel(Box::new(move |channel: Arc<RTCDataChannel>| {
+            let unordered = match channel.label() {
+                INPUT_CHANNEL => Some(true),
+                CONTACT_CHANNEL => Some(false),
+                label => {
+                    warn!("Ignoring unknown data channel: {label}");
+                    None
+                }
+            };
+            if let Some(unordered) = unordered {
+                let sender_inbound = sender_inbound.clone();
+                channel.on_message(Box::new(move |msg: DataChannelMessage| {
+                    let sender_inbound = sender_inbound.clone();
+                    Box::pin(async move {
+                        match serde_json::from_slice(&msg.data) {
+                            Ok(MessageInbound::PointerEvent(event))
+                                if unordered == (event.event_type == PointerEventType::MOVE) =>
+                            {
+                                let event = RtcPointerEvent { event, unordered };
+                                if unordered {
+                                    // the handler is behind if the queue is full, a dropped
+                                    // movement is made up for by the next one
+                                    let _ = sender_inbound.try_send(event);
+                                } else {
+                                    // fails only once the connection to the client is gone
+                                    let _ = sender_inbound.send(event).await;
+                                }
+                            }
+                            Ok(msg) => {
+                                warn!(
+                                    "Ignoring message that has to go via another channel: {msg:?}"
+                                )
+                            }
+                            Err(err) => warn!("Failed to parse message: {err}"),
+                        }
+                    })
+                }));
+            }
+            Box::pin(async {})
+        }));
+
+        peer_connection
+            .set_remote_description(RTCSessionDescription::offer(sdp)?)
+            .await?;
+        let answer = peer_connection.create_answer(None).await?;
+        // candidates trickle in via on_ice_candidate once the answer is set, they must not reach
+        // the client ahead of it
+        let _ = sender_signal.send(MessageOutbound::RtcAnswer {
+            sdp: answer.sdp.clone(),
+        });
+        peer_connection.set_local_description(answer).await?;
+
+        Ok(Self { peer_connection })
+    }
+
+    pub async fn add_candidate(&self, candidate: RtcCandidate) {
+        let candidate = RTCIceCandidateInit {
+            candidate: candidate.candidate,
+            sdp_mid: candidate.sdp_mid,
+            sdp_mline_index: candidate.sdp_m_line_index,
+            username_fragment: candidate.username_fragment,
+        };
+        if let Err(err) = self.peer_connection.add_ice_candidate(candidate).await {
+            warn!("Failed to add ICE candidate of the client: {err}");
+        }
+    }
+
+    pub async fn close(self) {
+        if let Err(err) = self.peer_connection.close().await {
+            warn!("Failed to close WebRTC connection: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Button;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tokio::time::timeout;
+    use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
+
+    // a pen event as ts/lib.ts sends it
+    fn pen_event(event_type: &str, buttons: u8, timestamp_ms: u64, x: f64) -> String {
+        let button = if event_type == "pointermove" { -1 } else { 0 };
+        format!(
+            r#"{{"PointerEvent":{{"event_type":"{event_type}","pointer_id":1,
+            "timestamp":{},"is_primary":true,"pointer_type":"pen","button":{button},
+            "buttons":{buttons},"x":{x},"y":0.5,"movement_x":0,"movement_y":0,"pressure":0.5,
+            "tilt_x":0,"tilt_y":0,"twist":0,"width":0.0,"height":0.0,"is_eraser":false}}}}"#,
+            timestamp_ms * 1000
+        )
+    }
+
+    fn opened(channel: &RTCDataChannel) -> mpsc::UnboundedReceiver<()> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        channel.on_open(Box::new(move || {
+            let _ = sender.send(());
+            Box::pin(async {})
+        }));
+        receiver
+    }
+
+    async fn receive(
+        receiver: &mut mpsc::Receiver<RtcPointerEvent>,
+    ) -> (PointerEventType, Button, f64, bool) {
+        let RtcPointerEvent { event, unordered } =
+            timeout(Duration::from_secs(10), receiver.recv())
+                .await
+                .unwrap()
+                .unwrap();
+        (event.event_type, event.buttons, event.x, unordered)
+    }
+
+    // the client draws a stroke, pressing and lifting the pen arrive in order, the movements
+    // in between via the unordered channel
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pen_strokes_arrive_via_webrtc() {
+        const WAIT: Duration = Duration::from_secs(10);
+
+        // the client side, set up like ts/lib.ts does
+        let api = APIBuilder::new().build();
+        let client = Arc::new(
+            api.new_peer_connection(RTCConfiguration::default())
+                .await
+                .unwrap(),
+        );
+        let input = client
+            .create_data_channel(
+                INPUT_CHANNEL,
+                Some(RTCDataChannelInit {
+                    ordered: Some(false),
+                    max_retransmits: Some(0),
+                    ..Default::default()
+                }),
+            )
+            .await
+            .unwrap();
+        let contact = client
+            .create_data_channel(CONTACT_CHANNEL, None)
+            .await
+            .unwrap();
+        let mut input_opened = opened(&input);
+        let mut contact_opened = opened(&contact);
+        let offer = client.create_offer(None).await.unwrap();
+        // the candidates of the client are part of the offer, the server trickles its own
+        let mut gathered = client.gathering_complete_promise().await;
+        client.set_local_description(offer).await.unwrap();
+        let _ = gathered.recv().await;
+        let offer = client.local_description().await.unwrap().sdp;
+
+        let (sender_signal, mut receiver_signal) = mpsc::unbounded_channel();
+        let (sender_inbound, mut receiver_inbound) = mpsc::channel(32);
+        let server = RtcInput::answer(offer, sender_signal, sender_inbound)
+            .await
+            .unwrap();
+        let signaling = tokio::spawn({
+            let client = client.clone();
+            async move {
+                while let Some(msg) = receiver_signal.recv().await {
+                    match msg {
+                        MessageOutbound::RtcAnswer { sdp } => client
+                            .set_remote_description(RTCSessionDescription::answer(sdp).unwrap())
+                            .await
+                            .unwrap(),
+                        MessageOutbound::RtcCandidate(candidate) => client
+                            .add_ice_candidate(RTCIceCandidateInit {
+                                candidate: candidate.candidate,
+                                sdp_mid: candidate.sdp_mid,
+                                sdp_mline_index: candidate.sdp_m_line_index,
+                                username_fragment: candidate.username_fragment,
+                            })
+                            .await
+                            .unwrap(),
+                        msg => panic!("Not a signaling message: {msg:?}"),
+                    }
+                }
+            }
+        });
+        timeout(WAIT, input_opened.recv()).await.unwrap();
+        timeout(WAIT, contact_opened.recv()).await.unwrap();
+
+        contact
+            .send_text(pen_event("pointerdown", 1, 0, 0.1))
+            .await
+            .unwrap();
+        assert_eq!(
+            receive(&mut receiver_inbound).await,
+            (PointerEventType::DOWN, Button::PRIMARY, 0.1, false)
+        );
+        for (i, x) in [0.2, 0.3, 0.4].into_iter().enumerate() {
+            input
+                .send_text(pen_event("pointermove", 1, i as u64 + 1, x))
+                .await
+                .unwrap();
+            assert_eq!(
+                receive(&mut receiver_inbound).await,
+                (PointerEventType::MOVE, Button::PRIMARY, x, true)
+            );
+        }
+        contact
+            .send_text(pen_event("pointerup", 0, 4, 0.4))
+            .await
+            .unwrap();
+        assert_eq!(
+            receive(&mut receiver_inbound).await,
+            (PointerEventType::UP, Button::NONE, 0.4, false)
+        );
+        // a movement sent over the ordered channel is not taken
+        contact
+            .send_text(pen_event("pointermove", 0, 5, 0.5))
+            .await
+            .unwrap();
+        assert!(timeout(Duration::from_millis(200), receiver_inbound.recv())
+            .await
+            .is_err());
+
+        server.close().await;
+        client.close().await.unwrap();
+        signaling.abort();
+    }
+}
@@ -0,0 +1,295 @@
+//! Scaling of frames to the size they are encoded at, so that neither the encoder nor the
+//! conversion to YUV has to process more pixels than the client gets to see.
+//!
+//! Up to a factor of 2 pixels are interpolated bilinearly with pixel centers mapped onto each
+//! other: output pixel x samples the input at (x + 0.5) * width_in / width_out - 0.5. Beyond that
+//! bilinear interpolation skips input pixels and aliases, so each output pixel is the average of
+//! the input pixels it covers instead, weighted by how much of them it covers. Either way the
+//! scaled frame covers exactly the area of the original one and coordinates relative to the frame
+//! point at the same spot in both, no matter the scale factor.
+//!
+//! Weights are fixed point with 8 bits. Each output row is blended from its input rows first, a
+//! loop over plain bytes the compiler vectorizes, then the pixels are sampled from that row.
+
+use crate::video::PixelProvider;
+use crate::yuv;
+
+/// For every output pixel the input pixels it is blended from, starting at first, and their
+/// weights in 1/256, which add up to 256. The weights of all output pixels are kept in a single
+/// buffer, which is reused once the size changes.
+#[derive(Default)]
+struct Taps {
+    first: Vec<usize>,
+    // the weights of output pixel i are weights[ends[i - 1]..ends[i]], starting at 0
+    ends: Vec<usize>,
+    weights: Vec<u16>,
+}
+
+impl Taps {
+    fn compute(&mut self, len_in: usize, len_out: usize) {
+        self.first.clear();
+        self.ends.clear();
+        self.weights.clear();
+        let scale = len_in as f64 / len_out as f64;
+        for i in 0..len_out {
+            if scale <= 2.0 {
+                let pos = ((i as f64 + 0.5) * scale - 0.5).clamp(0.0, (len_in - 1) as f64);
+                let first = pos as usize;
+                let weight = ((pos - first as f64) * 256.0).round() as u16;
+                self.first.push(first);
+                if first + 1 < len_in {
+                    self.weights.extend([256 - weight, weight]);
+                } else {
+                    self.weights.push(256);
+                }
+            } else {
+                // the output pixel covers start..end of the input, the weights are the differences
+                // of the rounded running coverage so that they add up to exactly 256
+                let start = i as f64 * scale;
+                let end = ((i + 1) as f64 * scale).min(len_in as f64);
+                let first = start as usize;
+                let last = (end.ceil() as usize).clamp(first + 1, len_in);
+                let covered = |x: f64| ((x - start) / (end - start) * 256.0).round() as u16;
+                self.first.push(first);
+                self.weights.extend(
+                    (first..last).map(|j| {
+                        covered((j as f64 + 1.0).min(end)) - covered((j as f64).max(start))
+                    }),
+                );
+            }
+            self.ends.push(self.weights.len());
+        }
+    }
+
+    /// The first input pixel and the weights of every output pixel.
+    fn iter(&self) -> impl Iterator<Item = (usize, &[u16])> + '_ {
+        let starts = std::iter::once(0).chain(self.ends.iter().copied());
+        self.first
+            .iter()
+            .zip(starts.zip(&self.ends))
+            .map(|(&first, (start, &end))| (first, &self.weights[start..end]))
+    }
+}
+
+/// Scales a single plane, the taps are computed again only if the size changes.
+#[derive(Default)]
+struct PlaneScaler {
+    sizes: ((usize, usize), (usize, usize)),
+    x_taps: Taps,
+    y_taps: Taps,
+    // sum of the weighted input rows of an output row
+    acc: Vec<u32>,
+    row: Vec<u8>,
+    out: Vec<u8>,
+}
+
+impl PlaneScaler {
+    /// Scale a plane with rows stride bytes apart and bpp bytes per pixel, the result is packed.
+    fn scale(
+        &mut self,
+        src: &[u8],
+        stride: usize,
+        bpp: usize,
+        size_in: (usize, usize),
+        size_out: (usize, usize),
+    ) -> &[u8] {
+        let ((width_in, height_in), (width_out, height_out)) = (size_in, size_out);
+        self.out.resize(width_out * height_out * bpp, 0);
+        if width_in == 0 || height_in == 0 || width_out == 0 || height_out == 0 {
+            return &self.out;
+        }
+        if self.sizes != (size_in, size_out) {
+            self.x_taps.compute(width_in, width_out);
+            self.y_taps.compute(height_in, height_out);
+            self.sizes = (size_in, size_out);
+        }
+        let row_len = width_in * bpp;
+        self.acc.resize(row_len, 0);
+        self.row.resize(row_len, 0);
+        for ((first_row, y_weights), out_row) in self
+            .y_taps
+            .iter()
+            .zip(self.out.chunks_exact_mut(width_out * bpp))
+        {
+            self.acc.fill(128);
+            for (k, &weight) in y_weights.iter().enumerate() {
+                let src_row = &src[(first_row + k) * stride..][..row_len];
+                for (a, &b) in self.acc.iter_mut().zip(src_row) {
+                    *a += b as u32 * weight as u32;
+                }
+            }
+            for (b, &a) in self.row.iter_mut().zip(&self.acc) {
+                *b = (a >> 8) as u8;
+            }
+            for ((first, x_weights), pixel) in self.x_taps.iter().zip(out_row.chunks_exact_mut(bpp))
+            {
+                let row = &self.row[first * bpp..];
+                for (c, b) in pixel.iter_mut().enumerate() {
+                    let sum: u32 = x_weights
+                        .iter()
+                        .enumerate()
+                        .map(|(k, &weight)| row[k * bpp + c] as u32 * weight as u32)
+                        .sum();
+                    *b = ((sum + 128) >> 8) as u8;
+                }
+            }
+        }
+        &self.out
+    }
+}
+
+/// Scales frames in any of the formats recorders produce, the planes of YUV frames are scaled
+/// separately. The buffers are kept between frames.
+#[derive(Default)]
+pub struct Scaler {
+    planes: [PlaneScaler; 3],
+}
+
+impl Scaler {
+    /// Scale the frame to width x height, the result is valid until the next frame is scaled.
+    pub fn scale(
+        &mut self,
+        pixel_provider: PixelProvider,
+        (width, height): (usize, usize),
+    ) -> PixelProvider<'_> {
+        let size_in = pixel_provider.size();
+        let size_out = (width, height);
+        let chroma_in = yuv::chroma_size(size_in.0, size_in.1);
+        let chroma_out = yuv::chroma_size(width, height);
+        let [p0, p1, p2] = &mut self.planes;
+        match pixel_provider {
+            PixelProvider::RGB(w, _, data) => {
+                PixelProvider::RGB(width, height, p0.scale(data, w * 3, 3, size_in, size_out))
+            }
+            PixelProvider::RGB0(w, _, data) => {
+                PixelProvider::RGB0(width, height, p0.scale(data, w * 4, 4, size_in, size_out))
+            }
+            PixelProvider::BGR0(w, _, data) => {
+                PixelProvider::BGR0(width, height, p0.scale(data, w * 4, 4, size_in, size_out))
+            }
+            PixelProvider::BGR0S(_, _, stride, data) => {
+                PixelProvider::BGR0(width, height, p0.scale(data, stride, 4, size_in, size_out))
+            }
+            PixelProvider::NV12(_, _, [luma, uv], strides) => PixelProvider::NV12(
+                width,
+                height,
+                [
+                    p0.scale(luma, strides[0], 1, size_in, size_out),
+                    p1.scale(uv, strides[1], 2, chroma_in, chroma_out),
+                ],
+                [width, chroma_out.0 * 2],
+            ),
+            PixelProvider::I420(_, _, [luma, u, v], strides) => PixelProvider::I420(
+                width,
+                height,
+                [
+                    p0.scale(luma, strides[0], 1, size_in, size_out),
+                    p1.scale(u, strides[1], 1, chroma_in, chroma_out),
+                    p2.scale(v, strides[2], 1, chroma_in, chroma_out),
+                ],
+                [width, chroma_out.0, chroma_out.0],
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // taps computed for another size before leave nothing behind
+    #[test]
+    fn taps_cover_the_input_with_their_weights() {
+        let mut taps = Taps::default();
+        for (len_in, len_out) in [(1920, 1280), (7, 3), (3840, 1000), (5, 5), (3, 7), (1, 4)] {
+            taps.compute(len_in, len_out);
+            assert_eq!(taps.iter().count(), len_out);
+            for (first, weights) in taps.iter() {
+                assert!(first + weights.len() <= len_in, "{len_in} to {len_out}");
+                assert_eq!(weights.iter().sum::<u16>(), 256, "{len_in} to {len_out}");
+            }
+        }
+    }
+
+    // 7 to 3 pixels is no integer factor and averaged, 5 to 3 is interpolated, the rows are padded
+    #[test]
+    fn scaling_keeps_pixel_centers() {
+        const WIDTH: usize = 7;
+        const HEIGHT: usize = 5;
+        const STRIDE: usize = WIDTH * 4 + 4;
+        // brightness grows linearly from left to right and top to bottom
+        let mut bgr0 = vec![0xff; STRIDE * HEIGHT];
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                bgr0[y * STRIDE + x * 4..][..4].copy_from_slice(&[
+                    (x * 30) as u8,
+                    (y * 40) as u8,
+                    0,
+                    0,
+                ]);
+            }
+        }
+        let mut scaler = Scaler::default();
+        let PixelProvider::BGR0(3, 3, scaled) =
+            scaler.scale(PixelProvider::BGR0S(WIDTH, HEIGHT, STRIDE, &bgr0), (3, 3))
+        else {
+            panic!("BGR0 is scaled to packed BGR0 of the size asked for");
+        };
+        assert_eq!(scaled.len(), 3 * 3 * 4);
+        let center = |x: usize, len_in: usize, len_out: usize| {
+            (x as f64 + 0.5) * len_in as f64 / len_out as f64 - 0.5
+        };
+        for y in 0..3 {
+            for x in 0..3 {
+                let pixel = &scaled[(y * 3 + x) * 4..][..4];
+                let b = center(x, WIDTH, 3) * 30.0;
+                let g = center(y, HEIGHT, 3) * 40.0;
+                assert!((pixel[0] as f64 - b).abs() <= 1.0, "{x}, {y}: {pixel:?}");
+                assert!((pixel[1] as f64 - g).abs() <= 1.0, "{x}, {y}: {pixel:?}");
+            }
+        }
+
+        // the chroma planes are scaled to the chroma size of the scaled frame
+        let luma = vec![16; WIDTH * HEIGHT];
+        let chroma = vec![128; 4 * 3];
+        let PixelProvider::I420(3, 2, [y, u, v], strides) = scaler.scale(
+            PixelProvider::I420(WIDTH, HEIGHT, [&luma, &chroma, &chroma], [WIDTH, 4, 4]),
+            (3, 2),
+        ) else {
+            panic!("I420 stays I420");
+        };
+        assert_eq!((y.len(), u.len(), v.len()), (6, 2, 2));
+        assert_eq!(strides, [3, 2, 2]);
+        assert!(y.iter().all(|l| *l == 16) && u.iter().chain(v).all(|c| *c == 128));
+    }
+
+    // interpolating between two neighbours would pick either the dark or the bright stripes of a
+    // pattern finer than the scaled frame, its average is what should be left
+    #[test]
+    fn large_downscales_average_the_pixels() {
+        const WIDTH: usize = 24;
+        const HEIGHT: usize = 12;
+        // red alternates every column, green every row, blue is bright in one of four columns
+        let mut rgb = vec![0; WIDTH * HEIGHT * 3];
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                rgb[(y * WIDTH + x) * 3..][..3].copy_from_slice(&[
+                    if x % 2 == 0 { 0 } else { 255 },
+                    if y % 2 == 0 { 0 } else { 255 },
+                    if x % 4 == 0 { 255 } else { 0 },
+                ]);
+            }
+        }
+        let mut scaler = Scaler::default();
+        let PixelProvider::RGB(6, 3, scaled) =
+            scaler.scale(PixelProvider::RGB(WIDTH, HEIGHT, &rgb), (6, 3))
+        else {
+            panic!("RGB stays RGB");
+        };
+        for pixel in scaled.chunks_exact(3) {
+            assert!((pixel[0] as f64 - 127.5).abs() <= 1.0, "{pixel:?}");
+            assert!((pixel[1] as f64 - 127.5).abs() <= 1.0, "{pixel:?}");
+            assert!((pixel[2] as f64 - 63.75).abs() <= 1.0, "{pixel:?}");
+        }
+    }
+}
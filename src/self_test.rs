@@ -0,0 +1,215 @@
+use std::time::{Duration, Instant};
+
+use crate::capturable::{get_native_capturables, Capturable};
+use crate::config::Config;
+use crate::encoder_probe::{probe_encoders, Encoder};
+
+// frames captured from the first capturable, enough to tell the first one, which may be slow
+// because buffers are set up, from the rest
+const CAPTURE_FRAMES: usize = 10;
+
+/// Prints the results as the checks are done, so it shows where it hangs if it does.
+struct Report {
+    mandatory_failed: usize,
+    optional_failed: usize,
+    passed: usize,
+}
+
+impl Report {
+    /// Run a check that returns what it found or why it failed. Weylus can do without the optional
+    /// ones, like hardware encoders.
+    fn check<T>(
+        &mut self,
+        name: &str,
+        mandatory: bool,
+        f: impl FnOnce() -> Result<T, String>,
+        describe: impl FnOnce(&T) -> String,
+    ) -> Option<T> {
+        let start = Instant::now();
+        let result = f();
+        let elapsed = format_duration(start.elapsed());
+        match result {
+            Ok(value) => {
+                self.passed += 1;
+                println!("[PASS] {name}: {} ({elapsed})", describe(&value));
+                Some(value)
+            }
+            Err(err) => {
+                if mandatory {
+                    self.mandatory_failed += 1;
+                    println!("[FAIL] {name}: {err} ({elapsed})");
+                } else {
+                    self.optional_failed += 1;
+                    println!("[WARN] {name}: {err} ({elapsed})");
+                }
+                None
+            }
+        }
+    }
+
+    fn skip(&mut self, name: &str, reason: &str) {
+        self.mandatory_failed += 1;
+        println!("[FAIL] {name}: skipped, {reason}");
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    format!("{:.1} ms", duration.as_secs_f64() * 1000.0)
+}
+
+/// List the capturables like the server does and capture frames from the first one, usually the
+/// whole desktop, timing each of them.
+fn check_capture(report: &mut Report, config: &Config) {
+    #[cfg(target_os = "linux")]
+    let backends = config.capture_backends();
+    #[cfg(target_os = "linux")]
+    if backends.x11 {
+        report.check(
+            "X11 display",
+            true,
+            || {
+                crate::capturable::x11::X11Context::new()
+                    .ok_or_else(|| "Failed to open the X display, is DISPLAY set?".to_string())
+            },
+            |_| std::env::var("DISPLAY").unwrap_or_default(),
+        );
+    }
+    #[cfg_attr(not(target_os = "linux"), allow(unused_mut))]
+    let mut capturables = report.check(
+        "List capturables",
+        true,
+        || {
+            #[cfg_attr(not(target_os = "linux"), allow(unused_mut))]
+            let mut capturables = get_native_capturables(
+                #[cfg(target_os = "linux")]
+                backends,
+                #[cfg(target_os = "macos")]
+                config.logical_resolution,
+            );
+            // the portal asks the user, the native ones do not, on Wayland there are none though
+            #[cfg(target_os = "linux")]
+            if capturables.is_empty() && backends.pipewire {
+                println!("Requesting a screen via the desktop portal, please choose one...");
+                capturables = crate::capturable::get_portal_capturables(false);
+            }
+            if capturables.is_empty() {
+                return Err("Found nothing to capture.".into());
+            }
+            Ok(capturables)
+        },
+        |capturables: &Vec<Box<dyn Capturable>>| {
+            format!(
+                "{} found, the first one is {}",
+                capturables.len(),
+                capturables[0].name()
+            )
+        },
+    );
+    #[cfg(not(target_os = "linux"))]
+    let _ = config;
+
+    let name = format!("Capture {CAPTURE_FRAMES} frames");
+    let Some(capturable) = capturables.as_mut().map(|c| c.remove(0)) else {
+        report.skip(&name, "there is nothing to capture");
+        return;
+    };
+    report.check(
+        &name,
+        true,
+        || {
+            let mut recorder = capturable
+                .recorder(false)
+                .map_err(|err| format!("Failed to start capturing {}: {err}", capturable.name()))?;
+            let mut times = Vec::with_capacity(CAPTURE_FRAMES);
+            let mut size = (0, 0);
+            for i in 0..CAPTURE_FRAMES {
+                let start = Instant::now();
+                let frame = recorder
+                    .capture()
+                    .map_err(|err| format!("Failed to capture frame {}: {err}", i + 1))?;
+                size = frame.size();
+                times.push(start.elapsed());
+            }
+            Ok((size, times))
+        },
+        |((width, height), times)| {
+            let total: Duration = times.iter().sum();
+            format!(
+                "{width}x{height}, first {}, average {}, max {}",
+                format_duration(times[0]),
+                format_duration(total / times.len() as u32),
+                format_duration(*times.iter().max().unwrap()),
+            )
+        },
+    );
+}
+
+/// Open every encoder of the platform and encode a frame, each in a process of its own like the
+/// GUI does, as broken drivers may crash it. Only the software encoder for H.264 is required.
+fn check_encoders(report: &mut Report) {
+    for encoder in Encoder::platform_encoders() {
+        report.check(
+            &format!("Encoder {}", encoder.name()),
+            encoder == Encoder::X264,
+            || match probe_encoders(&[encoder])[..] {
+                [(_, true)] => Ok(()),
+                _ => {
+                    Err("Failed to encode a frame, run with --log-level DEBUG for details.".into())
+                }
+            },
+            |_| "encoded a frame".into(),
+        );
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn check_input(report: &mut Report) {
+    use crate::input::uinput_device::{check_devices, UInputDiagnosis, UDEV_RULE};
+
+    let passed = report.check(
+        "uinput devices",
+        true,
+        || {
+            let failed: Vec<String> = check_devices()
+                .into_iter()
+                .filter_map(|(kind, result)| result.err().map(|err| format!("{kind}: {err}")))
+                .collect();
+            if failed.is_empty() {
+                Ok(())
+            } else {
+                Err(format!("Failed to create {}", failed.join(", ")))
+            }
+        },
+        |_| "created and destroyed stylus, mouse, touch and keyboard devices".into(),
+    );
+    if passed.is_none() {
+        println!(
+            "\n{}\nThe udev rule for uinput is: {UDEV_RULE}\n",
+            UInputDiagnosis::new()
+        );
+    }
+}
+
+/// Check the parts of Weylus one after the other without starting the server, printing a line for
+/// each of them, and return true if all that Weylus can not do without works. This is what bug
+/// reports are best accompanied by.
+pub fn run(config: &Config) -> bool {
+    let mut report = Report {
+        mandatory_failed: 0,
+        optional_failed: 0,
+        passed: 0,
+    };
+    println!("Weylus {} self-test", env!("CARGO_PKG_VERSION"));
+    check_capture(&mut report, config);
+    check_encoders(&mut report);
+    #[cfg(target_os = "linux")]
+    check_input(&mut report);
+    #[cfg(not(target_os = "linux"))]
+    println!("[PASS] Input: simulated via the system's API, nothing to check");
+
+    println!(
+        "\n{} passed, {} failed, {} optional failed.",
+        report.passed, report.mandatory_failed, report.optional_failed
+    );
+    report.mandatory_failed == 0
+}
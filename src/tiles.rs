@@ -0,0 +1,185 @@
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::{ExtendedColorType, ImageEncoder, ImageResult};
+
+use crate::mjpeg::scale_to_rgb;
+use crate::video::PixelProvider;
+
+/// Width and height of the tiles, those at the right and bottom edge may be smaller.
+pub const TILE_SIZE: usize = 64;
+
+/// Bytes in front of the PNG image of each tile: x, y, width and height as u16 and the length of
+/// the image as u32, all little-endian.
+pub const TILE_HEADER_LEN: usize = 12;
+
+/// Encodes frames losslessly for clients that want sharp text. Each frame is divided into tiles
+/// of TILE_SIZE x TILE_SIZE pixels and only the tiles that differ from the previous frame are
+/// sent, each one as PNG image, so a mostly static screen costs next to nothing. All buffers are
+/// kept between frames so that encoding does not allocate once the size is stable.
+pub struct TileEncoder {
+    width: usize,
+    height: usize,
+    // RGB of the frame being encoded and the one sent before
+    current: Vec<u8>,
+    previous: Vec<u8>,
+    // pixels of the tile being encoded, without the rest of the rows
+    tile: Vec<u8>,
+    message: Vec<u8>,
+    // send all tiles with the next frame
+    full_refresh: bool,
+}
+
+impl TileEncoder {
+    pub fn new() -> Self {
+        Self {
+            width: 0,
+            height: 0,
+            current: Vec::new(),
+            previous: Vec::new(),
+            tile: Vec::new(),
+            message: Vec::new(),
+            full_refresh: true,
+        }
+    }
+
+    /// Send all tiles with the next frame, like for a client that lost track of what it shows.
+    pub fn request_full_refresh(&mut self) {
+        self.full_refresh = true;
+    }
+
+    /// Scale the frame to width_out x height_out and encode the tiles that changed since the
+    /// previous frame, one after the other, each one a header as described at TILE_HEADER_LEN and
+    /// the PNG image. Nothing has changed if the message is empty, it is valid until the next
+    /// frame is encoded. All tiles are sent with the first frame and once the size changes.
+    pub fn encode(
+        &mut self,
+        pixel_provider: PixelProvider,
+        width_out: usize,
+        height_out: usize,
+    ) -> ImageResult<&[u8]> {
+        if (width_out, height_out) != (self.width, self.height) {
+            self.width = width_out;
+            self.height = height_out;
+            self.full_refresh = true;
+        }
+        scale_to_rgb(&mut self.current, pixel_provider, width_out, height_out);
+        let full_refresh =
+            std::mem::take(&mut self.full_refresh) || self.previous.len() != self.current.len();
+
+        self.message.clear();
+        let stride = width_out * 3;
+        for y in (0..height_out).step_by(TILE_SIZE) {
+            let tile_height = TILE_SIZE.min(height_out - y);
+            for x in (0..width_out).step_by(TILE_SIZE) {
+                let tile_width = TILE_SIZE.min(width_out - x);
+                let row_len = tile_width * 3;
+                let rows = (y..y + tile_height).map(|row| {
+                    let start = row * stride + x * 3;
+                    start..start + row_len
+                });
+                // comparing whole rows of the tile at once compiles to memcmp, which is
+                // vectorized
+                if !full_refresh
+                    && rows
+                        .clone()
+                        .all(|row| self.current[row.clone()] == self.previous[row])
+                {
+                    continue;
+                }
+                self.tile.clear();
+                for row in rows {
+                    self.tile.extend_from_slice(&self.current[row]);
+                }
+                let header = self.message.len();
+                self.message.resize(header + TILE_HEADER_LEN, 0);
+                PngEncoder::new_with_quality(
+                    &mut self.message,
+                    CompressionType::Fast,
+                    FilterType::Adaptive,
+                )
+                .write_image(
+                    &self.tile,
+                    tile_width as u32,
+                    tile_height as u32,
+                    ExtendedColorType::Rgb8,
+                )?;
+                let len = (self.message.len() - header - TILE_HEADER_LEN) as u32;
+                let fields = [x as u16, y as u16, tile_width as u16, tile_height as u16];
+                for (i, field) in fields.iter().enumerate() {
+                    self.message[header + 2 * i..header + 2 * i + 2]
+                        .copy_from_slice(&field.to_le_bytes());
+                }
+                self.message[header + 8..header + TILE_HEADER_LEN]
+                    .copy_from_slice(&len.to_le_bytes());
+            }
+        }
+        std::mem::swap(&mut self.current, &mut self.previous);
+        Ok(&self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (x, y, width, height) and the RGB pixels of each tile in a message of the tile encoder
+    fn decode_tiles(mut message: &[u8]) -> Vec<((u16, u16, u16, u16), Vec<u8>)> {
+        let mut tiles = Vec::new();
+        while !message.is_empty() {
+            let field = |i: usize| u16::from_le_bytes([message[2 * i], message[2 * i + 1]]);
+            let rect = (field(0), field(1), field(2), field(3));
+            let len = u32::from_le_bytes(message[8..12].try_into().unwrap()) as usize;
+            let png = &message[TILE_HEADER_LEN..TILE_HEADER_LEN + len];
+            let image = image::load_from_memory(png).unwrap().into_rgb8();
+            assert_eq!(image.dimensions(), (rect.2 as u32, rect.3 as u32));
+            tiles.push((rect, image.into_raw()));
+            message = &message[TILE_HEADER_LEN + len..];
+        }
+        tiles
+    }
+
+    #[test]
+    fn tiles_only_changed_ones_are_sent() {
+        // neither side is a multiple of the tile size, so the last row and column are smaller
+        const WIDTH: usize = 150;
+        const HEIGHT: usize = 100;
+        let mut bgr0: Vec<u8> = (0..WIDTH * HEIGHT * 4).map(|i| (i % 251) as u8).collect();
+        let mut encoder = TileEncoder::new();
+        let encode = |encoder: &mut TileEncoder, bgr0: &[u8]| {
+            let message = encoder
+                .encode(PixelProvider::BGR0(WIDTH, HEIGHT, bgr0), WIDTH, HEIGHT)
+                .unwrap();
+            decode_tiles(message)
+        };
+
+        let all = encode(&mut encoder, &bgr0);
+        let rects: Vec<_> = all.iter().map(|(rect, _)| *rect).collect();
+        assert_eq!(
+            rects,
+            [
+                (0, 0, 64, 64),
+                (64, 0, 64, 64),
+                (128, 0, 22, 64),
+                (0, 64, 64, 36),
+                (64, 64, 64, 36),
+                (128, 64, 22, 36),
+            ]
+        );
+        // lossless, the pixels are exactly those of the frame
+        let (_, pixels) = &all[5];
+        let (x, y) = (128 + 21, 64 + 35);
+        let p = &bgr0[(y * WIDTH + x) * 4..];
+        assert_eq!(pixels[pixels.len() - 3..], [p[2], p[1], p[0]]);
+
+        assert!(encode(&mut encoder, &bgr0).is_empty());
+
+        // a single pixel in the bottom right tile
+        bgr0[((HEIGHT - 1) * WIDTH + WIDTH - 1) * 4] ^= 0xff;
+        let changed = encode(&mut encoder, &bgr0);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].0, (128, 64, 22, 36));
+        assert!(encode(&mut encoder, &bgr0).is_empty());
+
+        encoder.request_full_refresh();
+        assert_eq!(encode(&mut encoder, &bgr0).len(), 6);
+    }
+}
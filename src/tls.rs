@@ -0,0 +1,63 @@
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tokio_rustls::rustls::ServerConfig;
+use tracing::info;
+
+/// Create the TLS configuration for the webserver from a PEM encoded certificate and key. If
+/// neither is given, a self-signed certificate is created on first use and reused afterwards.
+pub fn server_config(
+    cert: Option<&Path>,
+    key: Option<&Path>,
+) -> Result<Arc<ServerConfig>, Box<dyn Error>> {
+    let (cert_path, key_path) = match (cert, key) {
+        (Some(cert), Some(key)) => (cert.to_path_buf(), key.to_path_buf()),
+        (None, None) => self_signed_certificate()?,
+        _ => return Err("TLS certificate and key have to be specified together!".into()),
+    };
+
+    let certs = rustls_pemfile::certs(&mut fs::read(&cert_path)?.as_slice())
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut fs::read(&key_path)?.as_slice())?
+        .ok_or_else(|| format!("No private key found in {}!", key_path.display()))?;
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+fn self_signed_certificate() -> Result<(PathBuf, PathBuf), Box<dyn Error>> {
+    let mut dir = dirs::config_dir().ok_or("Failed to find configuration directory!")?;
+    dir.push("weylus");
+    let cert_path = dir.join("tls_cert.pem");
+    let key_path = dir.join("tls_key.pem");
+    if cert_path.exists() && key_path.exists() {
+        return Ok((cert_path, key_path));
+    }
+
+    info!("Generating self-signed TLS certificate.");
+    let rcgen::CertifiedKey { cert, key_pair } =
+        rcgen::generate_simple_self_signed(vec!["weylus".to_string(), "localhost".to_string()])?;
+    fs::create_dir_all(&dir)?;
+    // a key left without its certificate is replaced, the new file is never readable by others
+    if key_path.exists() {
+        fs::remove_file(&key_path)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(&key_path)?
+        .write_all(key_pair.serialize_pem().as_bytes())?;
+    fs::write(&cert_path, cert.pem())?;
+    Ok((cert_path, key_path))
+}
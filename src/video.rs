@@ -1,9 +1,16 @@
-use std::os::raw::{c_int, c_uchar, c_void};
-use std::time::Instant;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_uchar, c_void};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 
-use tracing::warn;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
 
 use crate::cerror::CError;
+use crate::protocol::VideoCodec;
+use crate::scale::Scaler;
+use crate::yuv;
 
 extern "C" {
     fn init_video_encoder(
@@ -14,16 +21,48 @@ extern "C" {
         height_out: c_int,
         try_vaapi: c_int,
         try_nvenc: c_int,
+        nvenc_gpu: c_int,
         try_videotoolbox: c_int,
         try_mediafoundation: c_int,
+        codec: c_int,
+        bitrate_kbps: c_int,
+        crf: c_int,
+        preset: c_int,
+        gop_size: c_int,
+        intra_refresh: c_int,
     ) -> *mut c_void;
     fn open_video(handle: *mut c_void, err: *mut CError);
+    fn get_video_codec_name(handle: *mut c_void) -> *const c_char;
+    fn get_video_codec_string(handle: *mut c_void, str: *mut c_char, size: usize);
     fn destroy_video_encoder(handle: *mut c_void);
-    fn encode_video_frame(handle: *mut c_void, micros: c_int, err: *mut CError);
+    fn request_keyframe(handle: *mut c_void);
+    fn set_video_bitrate(handle: *mut c_void, bitrate_kbps: c_int) -> c_int;
+    fn encode_video_frame(handle: *mut c_void, micros: i64, err: *mut CError, rec_err: *mut CError);
+    fn start_recording(handle: *mut c_void, path: *const c_char, err: *mut CError);
+    fn stop_recording(handle: *mut c_void, err: *mut CError);
 
     fn fill_rgb(ctx: *mut c_void, data: *const u8, err: *mut CError);
     fn fill_rgb0(ctx: *mut c_void, data: *const u8, err: *mut CError);
     fn fill_bgr0(ctx: *mut c_void, data: *const u8, stride: c_int, err: *mut CError);
+    fn fill_nv12(
+        ctx: *mut c_void,
+        y: *const u8,
+        y_stride: c_int,
+        uv: *const u8,
+        uv_stride: c_int,
+        err: *mut CError,
+    );
+    fn fill_i420(
+        ctx: *mut c_void,
+        y: *const u8,
+        y_stride: c_int,
+        u: *const u8,
+        u_stride: c_int,
+        v: *const u8,
+        v_stride: c_int,
+        err: *mut CError,
+    );
+    fn get_video_input_format(handle: *mut c_void) -> c_int;
 }
 
 // this is used as callback in lib/encode_video.c via ffmpegs AVIOContext
@@ -36,6 +75,7 @@ fn write_video_packet(video_encoder: *mut c_void, buf: *const c_uchar, buf_size:
     0
 }
 
+#[derive(Clone, Copy)]
 pub enum PixelProvider<'a> {
     // 8 bits per color
     RGB(usize, usize, &'a [u8]),
@@ -43,6 +83,10 @@ pub enum PixelProvider<'a> {
     BGR0(usize, usize, &'a [u8]),
     // width, height, stride
     BGR0S(usize, usize, usize, &'a [u8]),
+    // width, height, y and interleaved uv plane, their strides
+    NV12(usize, usize, [&'a [u8]; 2], [usize; 2]),
+    // width, height, y, u and v plane, their strides
+    I420(usize, usize, [&'a [u8]; 3], [usize; 3]),
 }
 
 impl<'a> PixelProvider<'a> {
@@ -52,16 +96,284 @@ impl<'a> PixelProvider<'a> {
             PixelProvider::RGB0(w, h, _) => (*w, *h),
             PixelProvider::BGR0(w, h, _) => (*w, *h),
             PixelProvider::BGR0S(w, h, _, _) => (*w, *h),
+            PixelProvider::NV12(w, h, _, _) => (*w, *h),
+            PixelProvider::I420(w, h, _, _) => (*w, *h),
+        }
+    }
+
+    /// Restrict the image to the rectangle x, y, width, height given in pixels. BGR0 and YUV
+    /// images are cropped in place by adjusting the stride, the others are copied to buf.
+    pub fn crop<'b>(
+        &self,
+        (x, y, width, height): (usize, usize, usize, usize),
+        buf: &'b mut Vec<u8>,
+    ) -> PixelProvider<'b>
+    where
+        'a: 'b,
+    {
+        match *self {
+            PixelProvider::BGR0(w, _, data) => {
+                PixelProvider::BGR0S(width, height, w * 4, &data[(y * w + x) * 4..])
+            }
+            PixelProvider::BGR0S(_, _, stride, data) => {
+                PixelProvider::BGR0S(width, height, stride, &data[y * stride + x * 4..])
+            }
+            PixelProvider::RGB(w, _, data) => {
+                copy_rows(data, w * 3, (x * 3, y, width * 3, height), buf);
+                PixelProvider::RGB(width, height, buf)
+            }
+            PixelProvider::RGB0(w, _, data) => {
+                copy_rows(data, w * 4, (x * 4, y, width * 4, height), buf);
+                PixelProvider::RGB0(width, height, buf)
+            }
+            // chroma covers 2x2 pixels, so the crop has to start at even coordinates
+            PixelProvider::NV12(_, _, [luma, uv], strides) => {
+                let (x, y) = (x & !1, y & !1);
+                PixelProvider::NV12(
+                    width,
+                    height,
+                    [&luma[y * strides[0] + x..], &uv[y / 2 * strides[1] + x..]],
+                    strides,
+                )
+            }
+            PixelProvider::I420(_, _, [luma, u, v], strides) => {
+                let (x, y) = (x & !1, y & !1);
+                PixelProvider::I420(
+                    width,
+                    height,
+                    [
+                        &luma[y * strides[0] + x..],
+                        &u[y / 2 * strides[1] + x / 2..],
+                        &v[y / 2 * strides[2] + x / 2..],
+                    ],
+                    strides,
+                )
+            }
         }
     }
 }
 
+/// Copy of a frame that does not borrow from the recorder that captured it, so it can be handed to
+/// another thread. The allocation is reused by the next copy.
+#[derive(Default)]
+pub struct OwnedFrame {
+    data: Vec<u8>,
+    layout: Option<FrameLayout>,
+}
+
+#[derive(Clone, Copy)]
+enum FrameLayout {
+    RGB(usize, usize),
+    RGB0(usize, usize),
+    BGR0(usize, usize),
+    // width, height and where the chroma planes start
+    NV12(usize, usize, usize),
+    I420(usize, usize, [usize; 2]),
+}
+
+impl OwnedFrame {
+    /// Replace the frame by a copy of pixel_provider, padding between rows is dropped.
+    pub fn copy_from(&mut self, pixel_provider: &PixelProvider) {
+        let (width, height) = pixel_provider.size();
+        let (chroma_width, chroma_height) = yuv::chroma_size(width, height);
+        let data = &mut self.data;
+        data.clear();
+        self.layout = Some(match *pixel_provider {
+            PixelProvider::RGB(w, h, pixels) => {
+                data.extend_from_slice(&pixels[..w * h * 3]);
+                FrameLayout::RGB(w, h)
+            }
+            PixelProvider::RGB0(w, h, pixels) => {
+                data.extend_from_slice(&pixels[..w * h * 4]);
+                FrameLayout::RGB0(w, h)
+            }
+            PixelProvider::BGR0(w, h, pixels) => {
+                data.extend_from_slice(&pixels[..w * h * 4]);
+                FrameLayout::BGR0(w, h)
+            }
+            PixelProvider::BGR0S(w, h, stride, pixels) => {
+                append_rows(data, pixels, stride, w * 4, h);
+                FrameLayout::BGR0(w, h)
+            }
+            PixelProvider::NV12(w, h, [luma, uv], strides) => {
+                append_rows(data, luma, strides[0], w, h);
+                let uv_start = data.len();
+                append_rows(data, uv, strides[1], chroma_width * 2, chroma_height);
+                FrameLayout::NV12(w, h, uv_start)
+            }
+            PixelProvider::I420(w, h, [luma, u, v], strides) => {
+                append_rows(data, luma, strides[0], w, h);
+                let u_start = data.len();
+                append_rows(data, u, strides[1], chroma_width, chroma_height);
+                let v_start = data.len();
+                append_rows(data, v, strides[2], chroma_width, chroma_height);
+                FrameLayout::I420(w, h, [u_start, v_start])
+            }
+        });
+    }
+
+    pub fn pixels(&self) -> Option<PixelProvider<'_>> {
+        let data = self.data.as_slice();
+        Some(match self.layout? {
+            FrameLayout::RGB(w, h) => PixelProvider::RGB(w, h, data),
+            FrameLayout::RGB0(w, h) => PixelProvider::RGB0(w, h, data),
+            FrameLayout::BGR0(w, h) => PixelProvider::BGR0(w, h, data),
+            FrameLayout::NV12(w, h, uv_start) => {
+                let (chroma_width, _) = yuv::chroma_size(w, h);
+                let (luma, uv) = data.split_at(uv_start);
+                PixelProvider::NV12(w, h, [luma, uv], [w, chroma_width * 2])
+            }
+            FrameLayout::I420(w, h, [u_start, v_start]) => {
+                let (chroma_width, _) = yuv::chroma_size(w, h);
+                PixelProvider::I420(
+                    w,
+                    h,
+                    [&data[..u_start], &data[u_start..v_start], &data[v_start..]],
+                    [w, chroma_width, chroma_width],
+                )
+            }
+        })
+    }
+}
+
+fn append_rows(buf: &mut Vec<u8>, data: &[u8], stride: usize, len: usize, rows: usize) {
+    for row in 0..rows {
+        buf.extend_from_slice(&data[row * stride..row * stride + len]);
+    }
+}
+
+/// YUV format the encoder takes without scaling or converting it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum YuvInput {
+    I420,
+    Nv12,
+}
+
+/// Convert a BGR0 frame to format, the planes are stored in buf. Other frames are returned as is.
+fn bgr0_to_yuv<'b>(
+    pixel_provider: PixelProvider<'b>,
+    format: YuvInput,
+    buf: &'b mut Vec<u8>,
+) -> PixelProvider<'b> {
+    let (width, height, stride, data) = match pixel_provider {
+        PixelProvider::BGR0(w, h, data) => (w, h, w * 4, data),
+        PixelProvider::BGR0S(w, h, stride, data) => (w, h, stride, data),
+        _ => return pixel_provider,
+    };
+    let (chroma_width, chroma_height) = yuv::chroma_size(width, height);
+    let luma_size = width * height;
+    let chroma_size = chroma_width * chroma_height;
+    buf.resize(luma_size + 2 * chroma_size, 0);
+    let (luma, chroma) = buf.split_at_mut(luma_size);
+    match format {
+        YuvInput::I420 => {
+            let (u, v) = chroma.split_at_mut(chroma_size);
+            yuv::bgr0_to_i420((width, height), data, stride, luma, u, v);
+            let buf: &'b [u8] = buf;
+            let (luma, chroma) = buf.split_at(luma_size);
+            let (u, v) = chroma.split_at(chroma_size);
+            PixelProvider::I420(
+                width,
+                height,
+                [luma, u, v],
+                [width, chroma_width, chroma_width],
+            )
+        }
+        YuvInput::Nv12 => {
+            yuv::bgr0_to_nv12((width, height), data, stride, luma, chroma);
+            let buf: &'b [u8] = buf;
+            let (luma, uv) = buf.split_at(luma_size);
+            PixelProvider::NV12(width, height, [luma, uv], [width, chroma_width * 2])
+        }
+    }
+}
+
+fn copy_rows(
+    data: &[u8],
+    stride: usize,
+    (offset, y, len, height): (usize, usize, usize, usize),
+    buf: &mut Vec<u8>,
+) {
+    buf.clear();
+    for row in data.chunks(stride).skip(y).take(height) {
+        buf.extend_from_slice(&row[offset..offset + len]);
+    }
+}
+
+// how often the average time spent encoding a frame is logged
+const ENCODE_STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+// keyframes are expensive, so clients can not force them more often than this
+const MIN_KEYFRAME_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Microseconds on the monotonic clock since Weylus started timestamping frames. Frames of all
+/// captures are timestamped this way, so the timestamps of different clients can be compared.
+pub fn monotonic_micros(instant: Instant) -> u64 {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    instant
+        .saturating_duration_since(*EPOCH.get_or_init(Instant::now))
+        .as_micros() as u64
+}
+
+/// Container the encoded video is recorded to.
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RecordingFormat {
+    #[default]
+    Mp4,
+    Mkv,
+}
+
+impl RecordingFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            RecordingFormat::Mp4 => "mp4",
+            RecordingFormat::Mkv => "mkv",
+        }
+    }
+}
+
+/// Where recordings requested by clients are stored.
+#[derive(Clone, Debug)]
+pub struct RecordingConfig {
+    pub dir: PathBuf,
+    pub format: RecordingFormat,
+}
+
+/// How much time encoders spend on each frame, slower presets improve the quality at the same
+/// bitrate. Each encoder maps this to its own presets, VideoToolbox and MediaFoundation ignore it.
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EncoderPreset {
+    #[default]
+    Fastest,
+    Fast,
+    Balanced,
+    Quality,
+}
+
+impl EncoderPreset {
+    pub fn presets() -> [Self; 4] {
+        [Self::Fastest, Self::Fast, Self::Balanced, Self::Quality]
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct EncoderOptions {
     pub try_vaapi: bool,
     pub try_nvenc: bool,
+    // CUDA device index of the GPU NVENC is opened on, the driver picks one if not set
+    pub nvenc_gpu: Option<u32>,
     pub try_videotoolbox: bool,
     pub try_mediafoundation: bool,
+    // H.264 in the profile the encoder picks if not set
+    pub codec: Option<VideoCodec>,
+    // the defaults of the encoder are used if these are not set
+    pub bitrate_kbps: Option<u32>,
+    pub crf: Option<u8>,
+    pub preset: EncoderPreset,
+    // frames between keyframes the encoder inserts on its own, with intra_refresh the number of
+    // frames it takes to refresh the whole picture
+    pub gop_size: u32,
+    pub intra_refresh: bool,
 }
 
 pub struct VideoEncoder {
@@ -70,8 +382,23 @@ pub struct VideoEncoder {
     height_in: usize,
     width_out: usize,
     height_out: usize,
+    // frames are scaled to this before encoding them, the encoder requires even sizes
+    frame_size: (usize, usize),
+    scaler: Scaler,
     write_data: Box<dyn FnMut(&[u8])>,
-    start_time: Instant,
+    codec_name: String,
+    mime_type: String,
+    frames_encoded: u32,
+    encode_time: Duration,
+    scale_time: Duration,
+    last_stats: Instant,
+    keyframe_requested: bool,
+    last_forced_keyframe: Option<Instant>,
+    last_pts: Option<i64>,
+    recording_error: Option<CError>,
+    // BGR0 frames are converted to this format before passing them to the encoder, if set
+    yuv_input: Option<YuvInput>,
+    yuv_buf: Vec<u8>,
 }
 
 impl VideoEncoder {
@@ -89,20 +416,57 @@ impl VideoEncoder {
             height_in,
             width_out,
             height_out,
+            frame_size: (width_out - width_out % 2, height_out - height_out % 2),
+            scaler: Scaler::default(),
             write_data: Box::new(move |data| write_data(data)),
-            start_time: Instant::now(),
+            codec_name: String::new(),
+            mime_type: String::new(),
+            frames_encoded: 0,
+            encode_time: Duration::ZERO,
+            scale_time: Duration::ZERO,
+            last_stats: Instant::now(),
+            keyframe_requested: false,
+            last_forced_keyframe: None,
+            last_pts: None,
+            recording_error: None,
+            yuv_input: None,
+            yuv_buf: Vec::new(),
         });
+        let (width, height) = video_encoder.frame_size;
         let handle = unsafe {
+            // frames are scaled already, the encoder only converts their format if needed
             init_video_encoder(
                 video_encoder.as_mut() as *mut _ as *mut c_void,
-                width_in as c_int,
-                height_in as c_int,
-                width_out as c_int,
-                height_out as c_int,
+                width as c_int,
+                height as c_int,
+                width as c_int,
+                height as c_int,
                 options.try_vaapi.into(),
                 options.try_nvenc.into(),
+                options
+                    .nvenc_gpu
+                    .map_or(-1, |gpu| gpu.min(c_int::MAX as u32) as c_int),
                 options.try_videotoolbox.into(),
                 options.try_mediafoundation.into(),
+                // the order of the codecs in lib/encode_video.c
+                match options.codec {
+                    None => 0,
+                    Some(VideoCodec::H264Baseline) => 1,
+                    Some(VideoCodec::H264Main) => 2,
+                    Some(VideoCodec::Vp9) => 3,
+                    Some(VideoCodec::Hevc) => 4,
+                },
+                options.bitrate_kbps.map_or(0, |bitrate| bitrate as c_int),
+                options.crf.map_or(-1, c_int::from),
+                // the order of the presets in lib/encode_video.c
+                match options.preset {
+                    EncoderPreset::Fastest => 0,
+                    EncoderPreset::Fast => 1,
+                    EncoderPreset::Balanced => 2,
+                    EncoderPreset::Quality => 3,
+                },
+                options.gop_size.min(c_int::MAX as u32) as c_int,
+                options.intra_refresh.into(),
             )
         };
         video_encoder.handle = handle;
@@ -112,11 +476,47 @@ impl VideoEncoder {
         if err.is_err() {
             return Err(err);
         }
+        video_encoder.codec_name =
+            unsafe { CStr::from_ptr(get_video_codec_name(video_encoder.handle)) }
+                .to_string_lossy()
+                .into_owned();
+        let mut codec_string: [c_char; 64] = [0; 64];
+        unsafe {
+            get_video_codec_string(
+                video_encoder.handle,
+                codec_string.as_mut_ptr(),
+                codec_string.len(),
+            )
+        };
+        video_encoder.mime_type = format!(
+            "video/mp4; codecs=\"{}\"",
+            unsafe { CStr::from_ptr(codec_string.as_ptr()) }.to_string_lossy()
+        );
+        video_encoder.yuv_input = match unsafe { get_video_input_format(video_encoder.handle) } {
+            1 => Some(YuvInput::I420),
+            2 => Some(YuvInput::Nv12),
+            _ => None,
+        };
         Ok(video_encoder)
     }
 
-    pub fn encode(&mut self, pixel_provider: PixelProvider) {
+    /// Encode a frame, the time it has been captured at is used as its timestamp in the stream so
+    /// the video plays at the speed it was captured at. Returns the timestamp in microseconds, see
+    /// monotonic_micros, if the frame has been encoded.
+    pub fn encode(&mut self, pixel_provider: PixelProvider, captured_at: Instant) -> Option<u64> {
+        let start = Instant::now();
         let mut err = CError::new();
+        let pixel_provider = if pixel_provider.size() == self.frame_size {
+            pixel_provider
+        } else {
+            let scaled = self.scaler.scale(pixel_provider, self.frame_size);
+            self.scale_time += start.elapsed();
+            scaled
+        };
+        let pixel_provider = match self.yuv_input {
+            Some(format) => bgr0_to_yuv(pixel_provider, format, &mut self.yuv_buf),
+            None => pixel_provider,
+        };
         match pixel_provider {
             PixelProvider::BGR0(w, _, bgr0) => unsafe {
                 fill_bgr0(self.handle, bgr0.as_ptr(), (w * 4) as c_int, &mut err);
@@ -130,22 +530,130 @@ impl VideoEncoder {
             PixelProvider::RGB0(_, _, rgb) => unsafe {
                 fill_rgb0(self.handle, rgb.as_ptr(), &mut err);
             },
+            PixelProvider::NV12(_, _, [y, uv], [y_stride, uv_stride]) => unsafe {
+                fill_nv12(
+                    self.handle,
+                    y.as_ptr(),
+                    y_stride as c_int,
+                    uv.as_ptr(),
+                    uv_stride as c_int,
+                    &mut err,
+                );
+            },
+            PixelProvider::I420(_, _, [y, u, v], [y_stride, u_stride, v_stride]) => unsafe {
+                fill_i420(
+                    self.handle,
+                    y.as_ptr(),
+                    y_stride as c_int,
+                    u.as_ptr(),
+                    u_stride as c_int,
+                    v.as_ptr(),
+                    v_stride as c_int,
+                    &mut err,
+                );
+            },
         }
         if err.is_err() {
             warn!("Failed to fill video frame: {}", err);
-            return;
+            return None;
+        }
+        if self.keyframe_requested
+            && !self
+                .last_forced_keyframe
+                .is_some_and(|last| last.elapsed() < MIN_KEYFRAME_INTERVAL)
+        {
+            unsafe { request_keyframe(self.handle) };
+            self.keyframe_requested = false;
+            self.last_forced_keyframe = Some(Instant::now());
         }
+        // the encoder requires strictly increasing timestamps, frames encoded again because the
+        // content did not change share the time they have been captured at
+        let pts =
+            (monotonic_micros(captured_at) as i64).max(self.last_pts.map_or(0, |pts| pts + 1));
+        self.last_pts = Some(pts);
+        let mut rec_err = CError::new();
         unsafe {
-            encode_video_frame(
-                self.handle,
-                (Instant::now() - self.start_time).as_millis() as c_int,
-                &mut err,
-            );
+            encode_video_frame(self.handle, pts, &mut err, &mut rec_err);
+        }
+        if rec_err.is_err() {
+            self.recording_error = Some(rec_err);
         }
         if err.is_err() {
             warn!("Failed to encode video frame: {}", err);
-            return;
+            return None;
+        }
+        self.frames_encoded += 1;
+        self.encode_time += start.elapsed();
+        if self.last_stats.elapsed() >= ENCODE_STATS_INTERVAL {
+            debug!(
+                "Encoded {} frames via {}, {:.2?} per frame on average, {:.2?} of which scaling \
+                them to {}x{}.",
+                self.frames_encoded,
+                self.codec_name,
+                self.encode_time / self.frames_encoded,
+                self.scale_time / self.frames_encoded,
+                self.frame_size.0,
+                self.frame_size.1
+            );
+            self.frames_encoded = 0;
+            self.encode_time = Duration::ZERO;
+            self.scale_time = Duration::ZERO;
+            self.last_stats = Instant::now();
+        }
+        Some(pts as u64)
+    }
+
+    /// Name of the FFmpeg encoder in use.
+    pub fn codec_name(&self) -> &str {
+        &self.codec_name
+    }
+
+    /// MIME type including the codec string the stream has to be played back with.
+    pub fn mime_type(&self) -> &str {
+        &self.mime_type
+    }
+
+    /// Make one of the next frames a keyframe, requests are delayed to not exceed one forced
+    /// keyframe per MIN_KEYFRAME_INTERVAL.
+    pub fn request_keyframe(&mut self) {
+        self.keyframe_requested = true;
+    }
+
+    /// Change the bitrate from the next frame on, returns false if the encoder can only use the
+    /// one it has been created with.
+    pub fn set_bitrate(&mut self, bitrate_kbps: u32) -> bool {
+        unsafe { set_video_bitrate(self.handle, bitrate_kbps as c_int) != 0 }
+    }
+
+    /// Additionally write the encoded video to path, the container is chosen based on its
+    /// extension. The recording starts with the next keyframe, which is forced.
+    pub fn start_recording(&mut self, path: &Path) -> Result<(), CError> {
+        let path = CString::new(path.to_string_lossy().into_owned()).unwrap_or_default();
+        let mut err = CError::new();
+        unsafe { start_recording(self.handle, path.as_ptr(), &mut err) };
+        if err.is_err() {
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> Result<(), CError> {
+        let mut err = CError::new();
+        unsafe { stop_recording(self.handle, &mut err) };
+        if err.is_err() {
+            return Err(err);
         }
+        Ok(())
+    }
+
+    /// Writing the recording failed during encoding, the recording has been stopped.
+    pub fn take_recording_error(&mut self) -> Option<CError> {
+        self.recording_error.take()
+    }
+
+    /// Size of the encoded frames, all of which shows the content.
+    pub fn frame_size(&self) -> (usize, usize) {
+        self.frame_size
     }
 
     pub fn check_size(
@@ -169,3 +677,338 @@ impl Drop for VideoEncoder {
         }
     }
 }
+
+// the throughput is measured and the bitrate adjusted this often
+const BITRATE_ADJUST_INTERVAL: Duration = Duration::from_millis(500);
+// The connection is congested if the video waiting to be sent takes longer than this to send at
+// the current bitrate and does not get less, or if handing video to the sink blocked for more than
+// CONGESTED_SEND_TIME of the time.
+const CONGESTED_QUEUE: Duration = Duration::from_millis(200);
+const CONGESTED_SEND_TIME: f64 = 0.1;
+// the bitrate is only raised while less than this is waiting to be sent
+const CLEAR_QUEUE: Duration = Duration::from_millis(50);
+// Once congested the bitrate is lowered to this share of the measured throughput, and further so
+// the video waiting to be sent is sent within QUEUE_DRAIN_TIME, but not below half of it.
+const BITRATE_HEADROOM: f64 = 0.85;
+const QUEUE_DRAIN_TIME: Duration = Duration::from_secs(2);
+// without congestion for this many windows in a row the bitrate is raised by BITRATE_INCREASE
+const BITRATE_PROBE_WINDOWS: u32 = 2;
+const BITRATE_INCREASE: f64 = 1.1;
+// lowering the bitrate by more than this share requests a keyframe
+const LARGE_BITRATE_DROP: f64 = 0.5;
+
+/// New bitrate chosen by a [`BitrateController`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitrateChange {
+    pub kbps: u32,
+    // encoders take a while to get down to a much lower bitrate unless they start over with a
+    // keyframe
+    pub keyframe: bool,
+}
+
+/// Adapts the bitrate of the video to the throughput of the connection to the client. How fast
+/// the sink sends the video handed to it only tells something about the connection once video
+/// queues up, so the bitrate is raised step by step while nothing queues and lowered to below the
+/// measured throughput once it does.
+pub struct BitrateController {
+    min_kbps: u32,
+    max_kbps: u32,
+    target_kbps: u32,
+    window_start: Instant,
+    // video handed to the sink during the current window, how long that blocked and how much
+    // video was waiting to be sent when the window started
+    sent_bytes: usize,
+    send_time: Duration,
+    start_pending_bytes: usize,
+    // windows in a row without congestion
+    clear_windows: u32,
+}
+
+impl BitrateController {
+    /// The bitrate starts halfway between min_kbps and max_kbps.
+    pub fn new(min_kbps: u32, max_kbps: u32, now: Instant) -> Self {
+        let max_kbps = max_kbps.max(min_kbps);
+        Self {
+            min_kbps,
+            max_kbps,
+            target_kbps: min_kbps + (max_kbps - min_kbps) / 2,
+            window_start: now,
+            sent_bytes: 0,
+            send_time: Duration::ZERO,
+            start_pending_bytes: 0,
+            clear_windows: 0,
+        }
+    }
+
+    pub fn target_kbps(&self) -> u32 {
+        self.target_kbps
+    }
+
+    /// The encoder handed bytes to the sink, which blocked for send_time.
+    pub fn sent(&mut self, bytes: usize, send_time: Duration) {
+        self.sent_bytes += bytes;
+        self.send_time += send_time;
+    }
+
+    /// Called for every frame, including the ones skipped because of congestion, with the amount
+    /// of video handed to the sink that has not been sent yet. Returns the new bitrate if it has
+    /// to change.
+    pub fn update(&mut self, now: Instant, pending_bytes: usize) -> Option<BitrateChange> {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed < BITRATE_ADJUST_INTERVAL {
+            return None;
+        }
+        let kbps = |bytes: usize| bytes as f64 * 8.0 / 1000.0 / elapsed.as_secs_f64();
+        let throughput_kbps =
+            kbps((self.start_pending_bytes + self.sent_bytes).saturating_sub(pending_bytes));
+        let sent_kbps = kbps(self.sent_bytes);
+        let queued = Duration::from_secs_f64(
+            pending_bytes as f64 * 8.0 / 1000.0 / self.target_kbps.max(1) as f64,
+        );
+        let congested = (queued > CONGESTED_QUEUE && pending_bytes >= self.start_pending_bytes)
+            || self.send_time.as_secs_f64() > CONGESTED_SEND_TIME * elapsed.as_secs_f64();
+        self.window_start = now;
+        self.sent_bytes = 0;
+        self.send_time = Duration::ZERO;
+        self.start_pending_bytes = pending_bytes;
+
+        let target_kbps = if congested {
+            self.clear_windows = 0;
+            let available_kbps = (self.target_kbps as f64).min(throughput_kbps);
+            let drain_kbps = pending_bytes as f64 * 8.0 / 1000.0 / QUEUE_DRAIN_TIME.as_secs_f64();
+            (available_kbps * BITRATE_HEADROOM - drain_kbps).max(available_kbps / 2.0)
+        } else if queued < CLEAR_QUEUE {
+            self.clear_windows += 1;
+            // there is no point in raising the bitrate if the video does not use it, static
+            // content for example
+            if self.clear_windows < BITRATE_PROBE_WINDOWS
+                || sent_kbps < self.target_kbps as f64 / 2.0
+            {
+                return None;
+            }
+            self.clear_windows = 0;
+            self.target_kbps as f64 * BITRATE_INCREASE
+        } else {
+            // the queue is draining
+            self.clear_windows = 0;
+            return None;
+        };
+        let target_kbps = (target_kbps as u32).clamp(self.min_kbps, self.max_kbps);
+        if target_kbps == self.target_kbps {
+            return None;
+        }
+        let keyframe = (target_kbps as f64) < self.target_kbps as f64 * (1.0 - LARGE_BITRATE_DROP);
+        debug!(
+            "Throughput {throughput_kbps:.0} kbps, {pending_bytes} bytes waiting to be sent, \
+            changing the bitrate from {} to {target_kbps} kbps.",
+            self.target_kbps
+        );
+        self.target_kbps = target_kbps;
+        Some(BitrateChange {
+            kbps: target_kbps,
+            keyframe,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sends video at 30 fps and the bitrate picked by the controller over a link whose throughput
+    // in kbps changes over time like capacity_kbps, frames are skipped while more than 1 MiB is
+    // queued like capture does. Returns the time, the bitrate, for how many seconds video is
+    // queued and if a keyframe has been requested for every frame.
+    fn simulate_link(
+        controller: &mut BitrateController,
+        seconds: u32,
+        capacity_kbps: impl Fn(f64) -> f64,
+    ) -> Vec<(f64, u32, f64, bool)> {
+        const FPS: u32 = 30;
+        let start = Instant::now();
+        let mut queued_bytes = 0usize;
+        let mut trace = Vec::new();
+        for frame in 0..seconds * FPS {
+            let t = frame as f64 / FPS as f64;
+            let capacity_kbps = capacity_kbps(t);
+            let sent_bytes = (capacity_kbps * 1000.0 / 8.0 / FPS as f64) as usize;
+            queued_bytes = queued_bytes.saturating_sub(sent_bytes);
+            let change =
+                controller.update(start + std::time::Duration::from_secs_f64(t), queued_bytes);
+            if queued_bytes <= 1 << 20 {
+                let frame_bytes =
+                    (controller.target_kbps() as f64 * 1000.0 / 8.0 / FPS as f64) as usize;
+                queued_bytes += frame_bytes;
+                controller.sent(frame_bytes, std::time::Duration::ZERO);
+            }
+            trace.push((
+                t,
+                controller.target_kbps(),
+                queued_bytes as f64 * 8.0 / 1000.0 / capacity_kbps,
+                change.is_some_and(|change| change.keyframe),
+            ));
+        }
+        trace
+    }
+
+    #[test]
+    fn adaptive_bitrate_follows_throughput() {
+        let mut controller = BitrateController::new(500, 50_000, Instant::now());
+        let trace = simulate_link(&mut controller, 90, |t| match t {
+            t if t < 30.0 => 20_000.0,
+            t if t < 60.0 => 3_000.0,
+            _ => 20_000.0,
+        });
+        let during =
+            |from: f64, to: f64| trace.iter().filter(move |(t, ..)| (from..to).contains(t));
+        for &(t, kbps, queued, _) in during(10.0, 30.0).chain(during(40.0, 60.0)) {
+            let capacity_kbps = if t < 30.0 { 20_000 } else { 3_000 };
+            assert!(
+                (capacity_kbps / 2..=capacity_kbps * 6 / 5).contains(&kbps),
+                "{kbps} kbps at {t} s"
+            );
+            assert!(queued < 0.4, "{queued} s queued at {t} s");
+        }
+        // the throughput collapsed, the encoder starts over at the much lower bitrate
+        assert!(during(30.0, 31.0).any(|(_, _, _, keyframe)| *keyframe));
+        // once the network recovers the bitrate goes back up
+        assert!(during(85.0, 90.0).all(|(_, kbps, ..)| *kbps > 10_000));
+    }
+
+    #[test]
+    fn adaptive_bitrate_stays_within_bounds() {
+        let mut controller = BitrateController::new(1_000, 5_000, Instant::now());
+        let trace = simulate_link(&mut controller, 20, |_| 100.0);
+        assert!(trace.iter().all(|(_, kbps, ..)| *kbps >= 1_000));
+        assert_eq!(controller.target_kbps(), 1_000);
+
+        let mut controller = BitrateController::new(1_000, 5_000, Instant::now());
+        let trace = simulate_link(&mut controller, 20, |_| 100_000.0);
+        assert!(trace.iter().all(|(_, kbps, ..)| *kbps <= 5_000));
+        assert_eq!(controller.target_kbps(), 5_000);
+    }
+
+    // bytes written per frame, with intra refresh only the keyframe requested for frame 30 is large
+    #[test]
+    fn intra_refresh_keeps_bitrate_flat() {
+        const WIDTH: usize = 640;
+        const HEIGHT: usize = 480;
+        // noise so that refreshing the picture is expensive
+        let mut seed: u32 = 1;
+        let bgr0: Vec<u8> = (0..WIDTH * HEIGHT * 4)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 24) as u8
+            })
+            .collect();
+        let frame_sizes = |intra_refresh: bool| -> Vec<usize> {
+            let bytes = std::rc::Rc::new(std::cell::Cell::new(0));
+            let opts = EncoderOptions {
+                try_vaapi: false,
+                try_nvenc: false,
+                nvenc_gpu: None,
+                try_videotoolbox: false,
+                try_mediafoundation: false,
+                codec: None,
+                bitrate_kbps: None,
+                crf: None,
+                preset: EncoderPreset::default(),
+                gop_size: 12,
+                intra_refresh,
+            };
+            let written = bytes.clone();
+            let mut encoder = VideoEncoder::new(
+                WIDTH,
+                HEIGHT,
+                WIDTH,
+                HEIGHT,
+                move |data| written.set(written.get() + data.len()),
+                opts,
+            )
+            .unwrap();
+            (0..36)
+                .map(|i| {
+                    if i == 30 {
+                        encoder.request_keyframe();
+                    }
+                    bytes.set(0);
+                    encoder.encode(PixelProvider::BGR0(WIDTH, HEIGHT, &bgr0), Instant::now());
+                    bytes.get()
+                })
+                .collect()
+        };
+
+        let keyframes = frame_sizes(false);
+        assert!(keyframes[12] > keyframes[0] / 2, "{keyframes:?}");
+        let refreshed = frame_sizes(true);
+        assert!(
+            refreshed[1..30].iter().all(|size| *size < refreshed[0] / 3),
+            "{refreshed:?}"
+        );
+        assert!(refreshed[30] > refreshed[0] / 2, "{refreshed:?}");
+    }
+
+    // Encodes a moving gradient of an odd size, which has to be cropped to an even one for the
+    // encoder, returns the name of the encoder and the number of bytes of video after the header.
+    #[cfg(any(target_os = "macos", target_os = "windows"))]
+    fn encode_synthetic_frames(configure: impl FnOnce(&mut EncoderOptions)) -> (String, usize) {
+        const WIDTH: usize = 1281;
+        const HEIGHT: usize = 721;
+        let mut options = EncoderOptions {
+            try_vaapi: false,
+            try_nvenc: false,
+            nvenc_gpu: None,
+            try_videotoolbox: false,
+            try_mediafoundation: false,
+            codec: None,
+            bitrate_kbps: None,
+            crf: None,
+            preset: EncoderPreset::default(),
+            gop_size: 12,
+            intra_refresh: false,
+        };
+        configure(&mut options);
+        let bytes = std::rc::Rc::new(std::cell::Cell::new(0));
+        let written = bytes.clone();
+        let mut encoder = VideoEncoder::new(
+            WIDTH,
+            HEIGHT,
+            WIDTH,
+            HEIGHT,
+            move |data| written.set(written.get() + data.len()),
+            options,
+        )
+        .unwrap();
+        assert_eq!(encoder.frame_size(), (WIDTH - 1, HEIGHT - 1));
+        bytes.set(0);
+        for i in 0..10 {
+            let bgr0: Vec<u8> = (0..WIDTH * HEIGHT * 4)
+                .map(|j| ((j / 4 % WIDTH + i * 8) % 256) as u8)
+                .collect();
+            assert!(encoder
+                .encode(PixelProvider::BGR0(WIDTH, HEIGHT, &bgr0), Instant::now())
+                .is_some());
+        }
+        (encoder.codec_name().to_string(), bytes.get())
+    }
+
+    // hardware encoders only exist on some CI machines, run there with --ignored
+    #[cfg(target_os = "macos")]
+    #[test]
+    #[ignore]
+    fn videotoolbox_encodes_frames() {
+        let (encoder, bytes) = encode_synthetic_frames(|options| options.try_videotoolbox = true);
+        assert_eq!(encoder, "h264_videotoolbox");
+        assert!(bytes > 0);
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    #[ignore]
+    fn mediafoundation_encodes_frames() {
+        let (encoder, bytes) =
+            encode_synthetic_frames(|options| options.try_mediafoundation = true);
+        assert_eq!(encoder, "h264_mf");
+        assert!(bytes > 0);
+    }
+}
@@ -2,25 +2,40 @@ use bytes::Bytes;
 use fastwebsockets::upgrade;
 use handlebars::Handlebars;
 use http_body_util::combinators::BoxBody;
-use http_body_util::{BodyExt, Full};
+use http_body_util::{BodyExt, Full, Limited};
 use hyper::body::Incoming;
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_TYPE, ETAG,
+    IF_NONE_MATCH, VARY,
+};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Method, Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
 use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::convert::Infallible;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
 use tokio::sync::{mpsc, oneshot};
-use tracing::{debug, error, info, warn};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn, Instrument};
 
-use crate::websocket::{weylus_websocket_channel, WeylusClientConfig, WeylusClientHandler};
+use crate::api;
+use crate::capture::CaptureRegistry;
+use crate::network::{BindAddresses, IpNetwork};
+use crate::websocket::{
+    weylus_websocket_channel, Sessions, WeylusClientConfig, WeylusClientHandler, WsWeylusSender,
+};
 
 #[derive(Debug)]
 pub enum WebStartUpMessage {
@@ -29,7 +44,14 @@ pub enum WebStartUpMessage {
 }
 
 pub enum Web2UiMessage {
-    UInputInaccessible,
+    // what is wrong with uinput and how to fix it
+    UInputInaccessible(String),
+    // number of clients connected in view-only mode
+    ViewOnlyClients(usize),
+    // input from all clients has been switched on or off
+    HostInputEnabled(bool),
+    // a client selected another capturable, to be saved as Config::capturable_name
+    CapturableSelected(String),
 }
 
 pub const INDEX_HTML: &str = std::include_str!("../www/templates/index.html");
@@ -45,14 +67,6 @@ struct IndexTemplateContext {
     log_level: String,
 }
 
-fn response_from_str(s: &str, content_type: &str) -> Response<Full<Bytes>> {
-    Response::builder()
-        .status(StatusCode::OK)
-        .header("content-type", content_type)
-        .body(s.to_string().into())
-        .unwrap()
-}
-
 fn response_not_found() -> Response<Full<Bytes>> {
     Response::builder()
         .status(StatusCode::NOT_FOUND)
@@ -61,20 +75,299 @@ fn response_not_found() -> Response<Full<Bytes>> {
         .unwrap()
 }
 
-async fn response_from_path_or_default(
-    path: Option<&PathBuf>,
-    default: &str,
+/// Content type of a file of the web client served from the web root.
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("json" | "map") => "application/json",
+        Some("webmanifest") => "application/manifest+json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("wasm") => "application/wasm",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Path of the file a request asks for within the web root, None if the request tries to get out
+/// of it.
+fn web_root_path(web_root: &Path, uri_path: &str) -> Option<PathBuf> {
+    let mut path = web_root.to_path_buf();
+    for segment in uri_path.split('/').filter(|segment| !segment.is_empty()) {
+        let segment = percent_encoding::percent_decode_str(segment)
+            .decode_utf8()
+            .ok()?;
+        // backslashes and drive letters separate paths on Windows
+        if segment == "." || segment == ".." || segment.contains(['/', '\\', ':', '\0']) {
+            return None;
+        }
+        path.push(segment.as_ref());
+    }
+    Some(path)
+}
+
+/// Read a file from the web root, None if it is not there.
+async fn read_web_root_file(web_root: &Path, uri_path: &str) -> Option<(Vec<u8>, PathBuf)> {
+    let Some(path) = web_root_path(web_root, uri_path) else {
+        warn!("Refusing to serve {uri_path}, it is outside of the web root.");
+        return None;
+    };
+    // symlinks may point anywhere
+    let root = tokio::fs::canonicalize(web_root).await.ok()?;
+    let path = tokio::fs::canonicalize(&path).await.ok()?;
+    if !path.starts_with(&root) {
+        warn!("Refusing to serve {uri_path}, it links to outside of the web root.");
+        return None;
+    }
+    if !tokio::fs::metadata(&path).await.ok()?.is_file() {
+        return None;
+    }
+    match tokio::fs::read(&path).await {
+        Ok(data) => Some((data, path)),
+        Err(err) => {
+            warn!("Failed to load file {}: {err}", path.display());
+            None
+        }
+    }
+}
+
+/// A file named like one built into Weylus that has to be served from the web root instead.
+fn web_root_file(web_root: Option<&PathBuf>, name: &str) -> Option<PathBuf> {
+    web_root
+        .map(|web_root| web_root.join(name))
+        .filter(|path| path.is_file())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum ContentEncoding {
+    Brotli,
+    Gzip,
+}
+
+impl ContentEncoding {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+        }
+    }
+
+    /// The encoding the client accepts, preferring Brotli as it compresses better.
+    fn accepted(headers: &HeaderMap) -> Option<Self> {
+        let accept_encoding = headers.get(ACCEPT_ENCODING)?.to_str().ok()?;
+        let accepts = |name: &str| {
+            accept_encoding.split(',').any(|coding| {
+                let mut params = coding.split(';');
+                let coding = params.next().unwrap_or_default().trim();
+                // a quality of 0 means the encoding must not be used
+                let refused = params.any(|param| {
+                    param
+                        .trim()
+                        .strip_prefix("q=")
+                        .and_then(|q| q.parse::<f64>().ok())
+                        == Some(0.0)
+                });
+                coding.eq_ignore_ascii_case(name) && !refused
+            })
+        };
+        [Self::Brotli, Self::Gzip]
+            .into_iter()
+            .find(|encoding| accepts(encoding.name()))
+    }
+
+    fn compress(self, content: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut compressed = Vec::new();
+        match self {
+            Self::Brotli => {
+                let mut writer = brotli::CompressorWriter::new(
+                    &mut compressed,
+                    4096,
+                    BROTLI_QUALITY,
+                    BROTLI_WINDOW_BITS,
+                );
+                writer.write_all(content)?;
+                // finishes the stream
+                writer.into_inner();
+            }
+            Self::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(&mut compressed, flate2::Compression::best());
+                encoder.write_all(content)?;
+                encoder.finish()?;
+            }
+        }
+        Ok(compressed)
+    }
+}
+
+// the files are compressed once and then served from the cache, so it pays to compress them well
+const BROTLI_QUALITY: u32 = 11;
+const BROTLI_WINDOW_BITS: u32 = 22;
+// custom files are compressed again whenever they change, the cache starts over once it holds
+// this many versions
+const MAX_COMPRESSED_FILES: usize = 32;
+// not worth the trouble below this size
+const MIN_COMPRESSED_SIZE: usize = 1024;
+
+/// Compressed files of the web client by hash of their content and encoding.
+#[derive(Default)]
+struct CompressionCache(Mutex<HashMap<(u64, ContentEncoding), Bytes>>);
+
+impl CompressionCache {
+    fn get(&self, hash: u64, encoding: ContentEncoding, content: &[u8]) -> Option<Bytes> {
+        if let Some(compressed) = self.0.lock().unwrap().get(&(hash, encoding)) {
+            return Some(compressed.clone());
+        }
+        let compressed = match encoding.compress(content) {
+            Ok(compressed) => Bytes::from(compressed),
+            Err(err) => {
+                warn!("Failed to compress file with {}: {err}", encoding.name());
+                return None;
+            }
+        };
+        let mut cache = self.0.lock().unwrap();
+        if cache.len() >= MAX_COMPRESSED_FILES {
+            cache.clear();
+        }
+        cache.insert((hash, encoding), compressed.clone());
+        Some(compressed)
+    }
+}
+
+fn is_compressible(content_type: &str) -> bool {
+    content_type.starts_with("text/")
+        || [
+            "application/json",
+            "application/manifest+json",
+            "application/wasm",
+            "image/svg+xml",
+        ]
+        .iter()
+        .any(|compressible| content_type.starts_with(compressible))
+}
+
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    let Some(Ok(if_none_match)) = headers.get(IF_NONE_MATCH).map(HeaderValue::to_str) else {
+        return false;
+    };
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Response with a file of the web client, compressed if the client supports it. The files are
+/// served under the same paths by every version of Weylus and may change any time if they are
+/// custom ones, so browsers are told to check for a new version on every load, which only costs
+/// a 304 Not Modified if there is none.
+fn static_response(
+    context: &Context<'_>,
+    headers: &HeaderMap,
+    content: Bytes,
     content_type: &str,
-) -> Response<Full<Bytes>> {
+) -> Response<BoxBody<Bytes, Infallible>> {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    let hash = hasher.finish();
+    let compressed = ContentEncoding::accepted(headers)
+        .filter(|_| content.len() >= MIN_COMPRESSED_SIZE && is_compressible(content_type))
+        .and_then(|encoding| {
+            context
+                .compression_cache
+                .get(hash, encoding, &content)
+                .map(|compressed| (encoding, compressed))
+        });
+    // every encoding is a representation of its own and needs its own tag
+    let etag = match &compressed {
+        Some((encoding, _)) => format!("\"{hash:016x}-{}\"", encoding.name()),
+        None => format!("\"{hash:016x}\""),
+    };
+    let response = Response::builder()
+        .header(CACHE_CONTROL, "no-cache")
+        .header(VARY, "accept-encoding")
+        .header(ETAG, &etag);
+    if etag_matches(headers, &etag) {
+        return response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Full::new(Bytes::new()).boxed())
+            .unwrap();
+    }
+    let (response, body) = match compressed {
+        Some((encoding, compressed)) => (
+            response.header(CONTENT_ENCODING, encoding.name()),
+            compressed,
+        ),
+        None => (response, content),
+    };
+    response
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, content_type)
+        .body(Full::new(body).boxed())
+        .unwrap()
+}
+
+async fn read_or_default(path: Option<&PathBuf>, default: &'static str) -> Bytes {
     match path {
-        Some(path) => match tokio::fs::read_to_string(path).await {
-            Ok(s) => response_from_str(&s, content_type),
+        Some(path) => match tokio::fs::read(path).await {
+            Ok(content) => content.into(),
             Err(err) => {
                 warn!("Failed to load file: {}", err);
-                response_from_str(default, content_type)
+                Bytes::from_static(default.as_bytes())
             }
         },
-        None => response_from_str(default, content_type),
+        None => Bytes::from_static(default.as_bytes()),
+    }
+}
+
+// how long to wait for the last frames to be written once a client handler is done, writes to a
+// client that is gone may block for much longer
+const WEBSOCKET_CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// connections from outside of the allowed networks are logged at most this often
+const REJECTION_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Keeps a scanner from flooding the log with rejected connections.
+#[derive(Default)]
+struct Rejections {
+    last_logged: Option<Instant>,
+    // rejected since then without being logged
+    suppressed: usize,
+}
+
+impl Rejections {
+    fn log(&mut self, addr: SocketAddr) {
+        let now = Instant::now();
+        if self
+            .last_logged
+            .is_some_and(|last_logged| now - last_logged < REJECTION_LOG_INTERVAL)
+        {
+            self.suppressed += 1;
+            return;
+        }
+        if self.suppressed > 0 {
+            warn!(
+                address = ?addr,
+                "Rejected connection from outside of the allowed networks, {} more since the \
+                last message.",
+                self.suppressed
+            );
+        } else {
+            warn!(address = ?addr, "Rejected connection from outside of the allowed networks.");
+        }
+        self.last_logged = Some(now);
+        self.suppressed = 0;
     }
 }
 
@@ -88,6 +381,27 @@ async fn serve(
     notify_disconnect: Arc<tokio::sync::Notify>,
 ) -> Result<Response<BoxBody<Bytes, Infallible>>, hyper::Error> {
     debug!("Got request: {:?}", req);
+    if req.uri().path() == "/api" || req.uri().path().starts_with("/api/") {
+        if !api::authorized(
+            req.headers(),
+            req.uri().query(),
+            context.web_config.access_code.as_deref(),
+        ) {
+            warn!(address = ?addr, "Rejected unauthenticated API request.");
+            return Ok(api::unauthorized().map(|r| r.boxed()));
+        }
+        let method = req.method().clone();
+        let path = req.uri().path().to_string();
+        let body = match Limited::new(req.into_body(), api::MAX_BODY_LEN)
+            .collect()
+            .await
+        {
+            Ok(body) => body.to_bytes(),
+            Err(err) => return Ok(api::body_unreadable(err.as_ref()).map(|r| r.boxed())),
+        };
+        let response = api::handle(&method, &path, body, &context.weylus_client_config).await;
+        return Ok(response.map(|r| r.boxed()));
+    }
     let mut authed = false;
     if let Some(access_code) = &context.web_config.access_code {
         if req.method() == Method::GET && (req.uri().path() == "/" || req.uri().path() == "/ws") {
@@ -96,11 +410,13 @@ async fn serve(
                 let params = form_urlencoded::parse(query.as_bytes())
                     .into_owned()
                     .collect::<HashMap<String, String>>();
-                if let Some(code) = params.get("access_code") {
-                    if code == access_code {
+                match params.get("access_code") {
+                    Some(code) if code == access_code => {
                         authed = true;
                         debug!(address = ?addr, "Web-Client authenticated.");
                     }
+                    Some(_) => warn!(address = ?addr, "Web-Client sent a wrong access code."),
+                    None => (),
                 }
             }
         }
@@ -113,22 +429,32 @@ async fn serve(
     match req.uri().path() {
         "/" => {
             if !authed {
-                return Ok(response_from_path_or_default(
-                    context.web_config.custom_access_html.as_ref(),
-                    ACCESS_HTML,
+                let custom_access_html =
+                    context.web_config.custom_access_html.clone().or_else(|| {
+                        web_root_file(context.web_config.web_root.as_ref(), "access_code.html")
+                    });
+                let content = read_or_default(custom_access_html.as_ref(), ACCESS_HTML).await;
+                return Ok(static_response(
+                    &context,
+                    req.headers(),
+                    content,
                     "text/html; charset=utf-8",
-                )
-                .await
-                .map(|r| r.boxed()));
+                ));
             }
             let config = IndexTemplateContext {
                 access_code: context.web_config.access_code.clone(),
                 uinput_enabled: cfg!(target_os = "linux"),
-                capture_cursor_enabled: cfg!(not(target_os = "windows")),
+                capture_cursor_enabled: true,
                 log_level: crate::log::get_log_level().to_string(),
             };
 
-            let html = if let Some(path) = context.web_config.custom_index_html.as_ref() {
+            // a custom index.html is a template just like the built in one
+            let custom_index_html = context
+                .web_config
+                .custom_index_html
+                .clone()
+                .or_else(|| web_root_file(context.web_config.web_root.as_ref(), "index.html"));
+            let html = if let Some(path) = custom_index_html.as_ref() {
                 let mut reg = Handlebars::new();
                 if let Err(err) = reg.register_template_file("index", path) {
                     warn!("Failed to register template from path: {}", err);
@@ -141,9 +467,12 @@ async fn serve(
             };
 
             match html {
-                Ok(html) => {
-                    Ok(response_from_str(&html, "text/html; charset=utf-8").map(|r| r.boxed()))
-                }
+                Ok(html) => Ok(static_response(
+                    &context,
+                    req.headers(),
+                    html.into(),
+                    "text/html; charset=utf-8",
+                )),
                 Err(err) => {
                     error!("Failed to render index template: {}", err);
                     Ok(response_not_found().map(|r| r.boxed()))
@@ -152,6 +481,7 @@ async fn serve(
         }
         "/ws" => {
             if !authed {
+                warn!(address = ?addr, "Rejected unauthenticated websocket connection.");
                 return Ok(Response::builder()
                     .status(StatusCode::UNAUTHORIZED)
                     .body("unauthorized".to_string().boxed())
@@ -162,30 +492,67 @@ async fn serve(
             num_clients.fetch_add(1, Ordering::Relaxed);
 
             let config = context.weylus_client_config.clone();
-            tokio::spawn(async move {
+            let capture_registry = context.capture_registry.clone();
+            let view_only_clients = context.view_only_clients.clone();
+            let client_timeout = context.web_config.client_timeout;
+            let webrtc = context.web_config.webrtc;
+            let sessions = context.sessions.clone();
+            let connection = Arc::new(config.connections.open(addr));
+            // everything logged on behalf of this client says which one it is
+            let span = tracing::info_span!("client", id = connection.id(), address = %addr);
+            let serve_client = async move {
                 match fut.await {
                     Ok(ws) => {
-                        let (sender, receiver) =
-                            weylus_websocket_channel(ws, semaphore_websocket_shutdown);
+                        info!("Websocket connection established.");
+                        let (sender, receiver, writer) = weylus_websocket_channel(
+                            ws,
+                            connection,
+                            semaphore_websocket_shutdown,
+                            client_timeout,
+                            config.input_switch.subscribe(),
+                            sessions,
+                            webrtc,
+                        );
+                        let (sender_done, done) = oneshot::channel::<()>();
+                        let span = tracing::Span::current();
                         std::thread::spawn(move || {
+                            let _span = span.enter();
                             let client = WeylusClientHandler::new(
                                 sender,
                                 receiver,
-                                || {
-                                    if let Err(err) =
-                                        sender_ui.blocking_send(Web2UiMessage::UInputInaccessible)
+                                |diagnosis| {
+                                    if let Err(err) = sender_ui
+                                        .blocking_send(Web2UiMessage::UInputInaccessible(diagnosis))
                                     {
                                         warn!(
                                             "Failed to send message 'UInputInaccessible': {err}."
                                         );
                                     }
                                 },
+                                |view_only| {
+                                    let count = if view_only {
+                                        view_only_clients.fetch_add(1, Ordering::Relaxed) + 1
+                                    } else {
+                                        view_only_clients.fetch_sub(1, Ordering::Relaxed) - 1
+                                    };
+                                    if let Err(err) = sender_ui
+                                        .blocking_send(Web2UiMessage::ViewOnlyClients(count))
+                                    {
+                                        warn!("Failed to send message 'ViewOnlyClients': {err}.");
+                                    }
+                                },
                                 config,
-                            );
+                                &capture_registry,
+                            )
+                            .with_client_address(addr);
                             client.run();
-                            num_clients.fetch_sub(1, Ordering::Relaxed);
-                            notify_disconnect.notify_waiters();
+                            let _ = sender_done.send(());
                         });
+                        let _ = done.await;
+                        // give the client a chance to receive the close frame when shutting down
+                        let _ = tokio::time::timeout(WEBSOCKET_CLOSE_TIMEOUT, writer).await;
+                        num_clients.fetch_sub(1, Ordering::Relaxed);
+                        notify_disconnect.notify_waiters();
                     }
                     Err(err) => {
                         eprintln!("Error in websocket connection: {}", err);
@@ -193,42 +560,91 @@ async fn serve(
                         notify_disconnect.notify_waiters();
                     }
                 }
-            });
+            };
+            tokio::spawn(serve_client.instrument(span));
 
             Ok(response.map(|r| r.boxed()))
         }
-        "/style.css" => Ok(response_from_path_or_default(
-            context.web_config.custom_style_css.as_ref(),
-            STYLE_CSS,
-            "text/css; charset=utf-8",
-        )
-        .await
-        .map(|r| r.boxed())),
-        "/lib.js" => Ok(response_from_path_or_default(
-            context.web_config.custom_lib_js.as_ref(),
-            LIB_JS,
-            "text/javascript; charset=utf-8",
-        )
-        .await
-        .map(|r| r.boxed())),
-        _ => Ok(response_not_found().map(|r| r.boxed())),
+        "/style.css" => {
+            let custom_style_css = context
+                .web_config
+                .custom_style_css
+                .clone()
+                .or_else(|| web_root_file(context.web_config.web_root.as_ref(), "style.css"));
+            let content = read_or_default(custom_style_css.as_ref(), STYLE_CSS).await;
+            Ok(static_response(
+                &context,
+                req.headers(),
+                content,
+                "text/css; charset=utf-8",
+            ))
+        }
+        "/lib.js" => {
+            let custom_lib_js = context
+                .web_config
+                .custom_lib_js
+                .clone()
+                .or_else(|| web_root_file(context.web_config.web_root.as_ref(), "lib.js"));
+            let content = read_or_default(custom_lib_js.as_ref(), LIB_JS).await;
+            Ok(static_response(
+                &context,
+                req.headers(),
+                content,
+                "text/javascript; charset=utf-8",
+            ))
+        }
+        uri_path => {
+            let file = match &context.web_config.web_root {
+                // the template is only served rendered, as /
+                Some(_) if uri_path == "/index.html" => None,
+                Some(web_root) => read_web_root_file(web_root, uri_path).await,
+                None => None,
+            };
+            match file {
+                Some((data, path)) => Ok(static_response(
+                    &context,
+                    req.headers(),
+                    data.into(),
+                    content_type(&path),
+                )),
+                None => Ok(response_not_found().map(|r| r.boxed())),
+            }
+        }
     }
 }
 
 #[derive(Clone)]
 pub struct WebServerConfig {
-    pub bind_addr: SocketAddr,
+    pub bind_addresses: BindAddresses,
+    pub port: u16,
+    /// Connections from anywhere else are closed right away, unless this is empty.
+    pub allowed_networks: Vec<IpNetwork>,
     pub access_code: Option<String>,
     pub custom_index_html: Option<PathBuf>,
     pub custom_access_html: Option<PathBuf>,
     pub custom_style_css: Option<PathBuf>,
     pub custom_lib_js: Option<PathBuf>,
+    /// The web client is served from this directory, files missing in it from the ones built
+    /// into Weylus.
+    pub web_root: Option<PathBuf>,
+    pub tls: Option<Arc<ServerConfig>>,
+    /// Websocket connections are closed if the client does not respond for this long.
+    pub client_timeout: Option<Duration>,
+    /// Clients whose connection dropped can resume their session for this long.
+    pub session_grace_period: Option<Duration>,
+    /// Clients may send input via WebRTC data channels.
+    pub webrtc: bool,
 }
 
 struct Context<'a> {
     web_config: WebServerConfig,
     weylus_client_config: WeylusClientConfig,
+    capture_registry: CaptureRegistry<WsWeylusSender>,
+    sessions: Sessions,
     templates: Handlebars<'a>,
+    view_only_clients: Arc<AtomicUsize>,
+    compression_cache: CompressionCache,
+    rejections: Mutex<Rejections>,
 }
 
 pub fn run(
@@ -243,14 +659,71 @@ pub fn run(
         .register_template_string("index", INDEX_HTML)
         .unwrap();
 
+    let capture_registry = CaptureRegistry::new(
+        weylus_client_config.encoder_options,
+        weylus_client_config.full_frame_interval,
+        weylus_client_config.recording.clone(),
+    );
+    let sessions = Sessions::new(web_server_config.session_grace_period);
     let context = Context {
         web_config: web_server_config,
         weylus_client_config,
+        capture_registry,
+        sessions,
         templates,
+        view_only_clients: Arc::new(AtomicUsize::new(0)),
+        compression_cache: CompressionCache::default(),
+        rejections: Mutex::new(Rejections::default()),
     };
     std::thread::spawn(move || run_server(context, sender_ui, sender_startup, notify_shutdown))
 }
 
+/// Listens on every address in bind_addresses, addresses that fail are logged and skipped.
+fn bind(bind_addresses: &BindAddresses, port: u16) -> Vec<TcpListener> {
+    let mut addrs = Vec::new();
+    for bind_address in &bind_addresses.0 {
+        match bind_address.socket_addrs(port) {
+            Ok(a) => addrs.extend(a),
+            Err(err) => error!("Failed to bind to {bind_address}: {err}"),
+        }
+    }
+    // a socket bound to :: accepts IPv4 connections as well on most systems, which makes binding
+    // 0.0.0.0 too fail unless the IPv6 socket is restricted to IPv6
+    let only_v6 = addrs.iter().any(SocketAddr::is_ipv4);
+    let mut listeners = Vec::new();
+    for addr in addrs {
+        match listen(addr, only_v6) {
+            Ok(listener) => {
+                info!("Listening on {addr}.");
+                listeners.push(listener);
+            }
+            Err(err) => error!("Failed to bind to {addr}: {err}."),
+        }
+    }
+    listeners
+}
+
+fn listen(addr: SocketAddr, only_v6: bool) -> std::io::Result<TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    // same as tokio's TcpListener::bind, allows restarting the server right away
+    #[cfg(not(target_os = "windows"))]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Connection to a client, either plain TCP or TLS.
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
 #[tokio::main]
 async fn run_server(
     context: Context<'static>,
@@ -258,19 +731,69 @@ async fn run_server(
     sender_startup: oneshot::Sender<WebStartUpMessage>,
     notify_shutdown: Arc<tokio::sync::Notify>,
 ) {
-    let addr = context.web_config.bind_addr;
-
-    let listener = match TcpListener::bind(addr).await {
-        Ok(listener) => listener,
-        Err(err) => {
-            error!("Failed to bind to socket: {err}.");
-            sender_startup.send(WebStartUpMessage::Error).unwrap();
-            return;
-        }
-    };
+    let listeners = bind(&context.web_config.bind_addresses, context.web_config.port);
+    if listeners.is_empty() {
+        error!("Failed to bind to any address.");
+        sender_startup.send(WebStartUpMessage::Error).unwrap();
+        return;
+    }
 
     sender_startup.send(WebStartUpMessage::Start).unwrap();
 
+    let (sender_conn, mut receiver_conn) = mpsc::channel(16);
+    for listener in listeners {
+        let sender_conn = sender_conn.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok(conn) => {
+                        if sender_conn.send(conn).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => warn!("Connection failed: {err}."),
+                }
+            }
+        });
+    }
+    drop(sender_conn);
+
+    {
+        let mut host_input = context.weylus_client_config.input_switch.subscribe();
+        let sender_ui = sender_ui.clone();
+        tokio::spawn(async move {
+            while host_input.changed().await.is_ok() {
+                let enabled = *host_input.borrow_and_update();
+                if let Err(err) = sender_ui
+                    .send(Web2UiMessage::HostInputEnabled(enabled))
+                    .await
+                {
+                    warn!("Failed to send message 'HostInputEnabled': {err}.");
+                    break;
+                }
+            }
+        });
+    }
+
+    {
+        let mut last_capturable = context.weylus_client_config.last_capturable.subscribe();
+        let sender_ui = sender_ui.clone();
+        tokio::spawn(async move {
+            while last_capturable.changed().await.is_ok() {
+                let Some(name) = last_capturable.borrow_and_update().clone() else {
+                    continue;
+                };
+                if let Err(err) = sender_ui
+                    .send(Web2UiMessage::CapturableSelected(name))
+                    .await
+                {
+                    warn!("Failed to send message 'CapturableSelected': {err}.");
+                    break;
+                }
+            }
+        });
+    }
+
     let context = Arc::new(context);
 
     let broadcast_shutdown = Arc::new(tokio::sync::Notify::new());
@@ -279,16 +802,13 @@ async fn run_server(
     let notify_disconnect = Arc::new(tokio::sync::Notify::new());
     let semaphore_websocket_shutdown = Arc::new(tokio::sync::Semaphore::new(0));
 
+    let tls_acceptor = context.web_config.tls.clone().map(TlsAcceptor::from);
+
     loop {
         let (tcp, remote_address) = tokio::select! {
-            res = listener.accept() => {
-                match res {
-                    Ok(conn) => conn,
-                    Err(err) => {
-                        warn!("Connection failed: {err}.");
-                        continue;
-                    }
-                }
+            conn = receiver_conn.recv() => match conn {
+                Some(conn) => conn,
+                None => break,
             },
             _ = notify_shutdown.notified() => {
                 info!("Webserver is shutting down.");
@@ -297,10 +817,20 @@ async fn run_server(
             }
         };
 
-        debug!(address = ?remote_address, "Client connected.");
+        // closed before the TLS handshake, so nothing is spent on clients that are not allowed
+        let allowed_networks = &context.web_config.allowed_networks;
+        if !allowed_networks.is_empty()
+            && !allowed_networks
+                .iter()
+                .any(|network| network.contains(remote_address.ip()))
+        {
+            context.rejections.lock().unwrap().log(remote_address);
+            continue;
+        }
 
-        let io = TokioIo::new(tcp);
+        debug!(address = ?remote_address, "Client connected.");
 
+        let tls_acceptor = tls_acceptor.clone();
         let sender_ui = sender_ui.clone();
         let broadcast_shutdown = broadcast_shutdown.clone();
         let context = context.clone();
@@ -309,6 +839,18 @@ async fn run_server(
         let notify_disconnect = notify_disconnect.clone();
 
         tokio::task::spawn(async move {
+            let io: Box<dyn Stream> = match tls_acceptor {
+                Some(tls_acceptor) => match tls_acceptor.accept(tcp).await {
+                    Ok(stream) => Box::new(stream),
+                    Err(err) => {
+                        warn!("TLS handshake with {remote_address} failed: {err}.");
+                        return;
+                    }
+                },
+                None => Box::new(tcp),
+            };
+            let io = TokioIo::new(io);
+
             let conn = http1::Builder::new().serve_connection(
                 io,
                 service_fn({
@@ -346,7 +888,14 @@ async fn run_server(
         });
     }
 
-    semaphore_websocket_shutdown.add_permits(num_clients.load(Ordering::Relaxed));
+    // stops the listeners, they notice once they try to pass on the next connection
+    drop(receiver_conn);
+
+    // tells every websocket to say goodbye to its client and close, including ones that have
+    // handed their session to another connection and are not counted as clients anymore
+    semaphore_websocket_shutdown.close();
+    // clients of suspended sessions are not coming back anymore
+    context.sessions.clear();
 
     loop {
         let remaining_clients = num_clients.load(Ordering::Relaxed);
@@ -358,8 +907,136 @@ async fn run_server(
         tokio::select! {
             _ = notify_disconnect.notified() => (),
             _ = tokio::time::sleep(Duration::from_secs(1)) => {
-                semaphore_websocket_shutdown.add_permits(num_clients.load(Ordering::Relaxed));
+                // connections that dropped in the meantime may have suspended their session
+                context.sessions.clear();
             },
         }
     }
+
+    // the clients are gone, so are the subscribers of the captures
+    let capture_registry = context.capture_registry.clone();
+    if let Err(err) = tokio::task::spawn_blocking(move || capture_registry.join()).await {
+        error!("Failed to wait for the captures to stop: {err}");
+    }
+    debug!("All captures stopped.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(name: hyper::header::HeaderName, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn accepted_encodings() {
+        use ContentEncoding::*;
+
+        let accepted = |value| ContentEncoding::accepted(&headers(ACCEPT_ENCODING, value));
+        assert_eq!(ContentEncoding::accepted(&HeaderMap::new()), None);
+        assert_eq!(accepted("gzip, deflate, br, zstd"), Some(Brotli));
+        assert_eq!(accepted("gzip"), Some(Gzip));
+        assert_eq!(accepted("GZip"), Some(Gzip));
+        assert_eq!(accepted("deflate"), None);
+        // a quality of 0 refuses the encoding, any other one is fine as Brotli is preferred
+        assert_eq!(accepted("br;q=0, gzip"), Some(Gzip));
+        assert_eq!(accepted("br ; q=0.000, gzip;q=0.5"), Some(Gzip));
+        assert_eq!(accepted("br;q=0.1, gzip;q=1.0"), Some(Brotli));
+        assert_eq!(accepted("br;q=0, gzip;q=0"), None);
+        // refusing to take the file uncompressed does not refuse the others
+        assert_eq!(accepted("identity;q=0, gzip"), Some(Gzip));
+        assert_eq!(accepted("identity;q=0"), None);
+        assert_eq!(accepted("identity"), None);
+    }
+
+    #[test]
+    fn etags_match_if_none_match() {
+        let etag = "\"0123456789abcdef-br\"";
+        let matches = |value| etag_matches(&headers(IF_NONE_MATCH, value), etag);
+        assert!(!etag_matches(&HeaderMap::new(), etag));
+        assert!(matches("\"0123456789abcdef-br\""));
+        // weak comparison, as If-None-Match asks for
+        assert!(matches("W/\"0123456789abcdef-br\""));
+        assert!(matches("\"other\", \"0123456789abcdef-br\""));
+        assert!(matches("\"other\",W/\"0123456789abcdef-br\" , \"more\""));
+        assert!(matches("*"));
+        // the tag of another encoding of the same file
+        assert!(!matches("\"0123456789abcdef-gzip\""));
+        assert!(!matches("\"0123456789abcdef\""));
+        assert!(!matches("\"other\", W/\"more\""));
+        assert!(!matches(""));
+    }
+
+    #[test]
+    fn web_root_paths_stay_in_the_web_root() {
+        let root = Path::new("/srv/weylus");
+        assert_eq!(web_root_path(root, "/"), Some(root.to_path_buf()));
+        assert_eq!(
+            web_root_path(root, "/css/style.css"),
+            Some(root.join("css").join("style.css"))
+        );
+        assert_eq!(
+            web_root_path(root, "/my%20page.html"),
+            Some(root.join("my page.html"))
+        );
+        // an absolute path is taken relative to the web root
+        assert_eq!(
+            web_root_path(root, "//etc/passwd"),
+            Some(root.join("etc").join("passwd"))
+        );
+        for uri_path in [
+            "/../etc/passwd",
+            "/css/../../etc/passwd",
+            "/./index.html",
+            "/%2e%2e/etc/passwd",
+            "/%2E%2E/%2E%2E/etc/passwd",
+            "/..%2fetc/passwd",
+            "/%2fetc/passwd",
+            "/..%5c..%5cWindows",
+            "/C:/Windows/win.ini",
+            "/C%3a%5cWindows",
+            "/index.html%00.png",
+            "/%ff",
+        ] {
+            assert_eq!(web_root_path(root, uri_path), None, "{uri_path}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn web_root_files_that_link_out_of_it_are_not_served() {
+        let dir = std::env::temp_dir().join(format!("weylus-web-root-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let root = dir.join("www");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("index.html"), "inside").unwrap();
+        std::fs::write(dir.join("secret"), "outside").unwrap();
+        std::os::unix::fs::symlink(dir.join("secret"), root.join("escape")).unwrap();
+        std::os::unix::fs::symlink(&dir, root.join("parent")).unwrap();
+        std::os::unix::fs::symlink(root.join("index.html"), root.join("home.html")).unwrap();
+
+        let file = |uri_path: &'static str| {
+            let root = root.clone();
+            async move {
+                read_web_root_file(&root, uri_path)
+                    .await
+                    .map(|(data, _)| data)
+            }
+        };
+        assert_eq!(file("/index.html").await.as_deref(), Some(&b"inside"[..]));
+        // links within the web root are fine
+        assert_eq!(file("/home.html").await.as_deref(), Some(&b"inside"[..]));
+        assert_eq!(file("/escape").await, None);
+        assert_eq!(file("/parent/secret").await, None);
+        assert_eq!(file("/../secret").await, None);
+        assert_eq!(file("/%2e%2e/secret").await, None);
+        // directories are not served
+        assert_eq!(file("/").await, None);
+        assert_eq!(file("/missing.html").await, None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -1,39 +1,248 @@
 use fastwebsockets::{FragmentCollectorRead, Frame, OpCode, WebSocket, WebSocketError};
 use hyper::upgrade::Upgraded;
 use hyper_util::rt::TokioIo;
+use std::collections::HashMap;
 use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
 use std::sync::mpsc::RecvTimeoutError;
-use std::sync::{mpsc, Arc};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread::{spawn, JoinHandle};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::mpsc::channel;
-use tracing::{debug, error, trace, warn};
+use tokio::sync::mpsc::error::{SendError, TrySendError};
+use tokio::sync::watch;
+use tracing::{debug, error, info, trace, warn, Instrument};
 
-use crate::capturable::{get_capturables, Capturable, Recorder};
+use crate::audio::{audio_sources, AudioStream};
+use crate::capturable::{get_native_capturables, Capturable, Geometry};
+#[cfg(target_os = "linux")]
+use crate::capturable::{get_portal_capturables, CaptureBackends};
 use crate::input::device::{InputDevice, InputDeviceType};
+use crate::input::gesture::TouchGestures;
+use crate::input::ordering::PointerOrder;
+use crate::input::pacing::PacedInput;
+use crate::input::palm::{PalmRejection, DEFAULT_PALM_REJECTION_TIMEOUT};
+use crate::input::pressure::PressureMapping;
+use crate::input::queue::{self, InboundReceiver, InboundSender};
+use crate::input::relative::RelativePointer;
+use crate::input::smoothing::PenSmoother;
+use crate::input::switch::InputSwitch;
+#[cfg(target_os = "linux")]
+use crate::input::uinput_pool::UInputPool;
 use crate::protocol::{
-    ClientConfiguration, KeyboardEvent, MessageInbound, MessageOutbound, PointerEvent,
+    CapturableInfo, ClientConfiguration, ClientError, CropRect, ErrorKind, KeyboardEvent,
+    MessageInbound, MessageOutbound, PointerEvent, PointerMode, ScaleMode, VideoStats,
     WeylusReceiver, WeylusSender, WheelEvent,
 };
 
+use crate::capture::{CaptureRegistry, VideoClient, VideoConfig};
 use crate::cerror::CErrorCode;
-use crate::video::{EncoderOptions, VideoEncoder};
+use crate::clipboard::ClipboardSync;
+use crate::connections::{elapsed_since, Connection, Connections};
+use crate::mjpeg::DEFAULT_JPEG_QUALITY;
+use crate::rtc::{RtcInput, RtcOrder, RtcPointerEvent};
+use crate::video::{EncoderOptions, RecordingConfig};
 
-struct VideoConfig {
-    capturable: Box<dyn Capturable>,
-    capture_cursor: bool,
-    max_width: usize,
-    max_height: usize,
-    frame_rate: f64,
+/// Interval in which the list of capturables is checked for windows that have been opened, closed
+/// or renamed.
+const CAPTURABLE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Capturables offered to the client, capturables from the portal are kept separate as they can
+/// not be polled without bothering the user.
+#[derive(Default)]
+struct Capturables {
+    portal: Vec<Box<dyn Capturable>>,
+    native: Vec<Box<dyn Capturable>>,
+    // the client has asked for the list at least once, before that there is nothing to update
+    requested: bool,
+    // index or part of the name of the capturable selected by default
+    default: Option<String>,
+    // see LastCapturable, selected by default if there is no default and it is still around
+    last: Option<String>,
+    // what the client has been told about the capturables that are still around by their
+    // Capturable::id, including the id the client knows them by, see replace
+    sent: HashMap<String, CapturableInfo>,
+    // capturables added to the list so far, numbers the ids they are given
+    added: u64,
+    // id of the capturable input goes to while the client is configured without video, there is
+    // no capture that would notice it vanishing, so the watcher does
+    input_only: Option<String>,
+}
+
+impl Capturables {
+    fn find(&self, id: &str) -> Option<Box<dyn Capturable>> {
+        self.portal
+            .iter()
+            .chain(self.native.iter())
+            .find(|c| self.client_id(c.as_ref()) == id)
+            .cloned()
+    }
+
+    /// The id this session knows the capturable by for an id from GET /api/capturables, which
+    /// lists the capturables as a new session would: ids that are not Capturable::id of any of the
+    /// capturables, like those sent by the client, are returned as they are.
+    fn resolve_api_id(&self, id: &str) -> String {
+        self.portal
+            .iter()
+            .chain(self.native.iter())
+            .find(|c| c.id() == id)
+            .map_or_else(|| id.to_string(), |c| self.client_id(c.as_ref()))
+    }
+
+    fn client_id(&self, capturable: &dyn Capturable) -> String {
+        let id = capturable.id();
+        self.sent.get(&id).map_or(id, |info| info.id.clone())
+    }
+
+    fn info(&self, capturable: &dyn Capturable) -> CapturableInfo {
+        describe(capturable, self.client_id(capturable))
+    }
+
+    /// Replace the capturables, the portal capturables are kept if `portal` is None, and return
+    /// the updates that bring the list of the client up to date. Ids are never reused within a
+    /// session: every capturable added to the list is given a new id, even one that vanished and
+    /// shows up again, like a window whose id the X server recycled, so an update can not be
+    /// applied to the wrong entry. Capturables that changed in any way, like being renamed, moved
+    /// or resized or a monitor whose mode changed, are sent again as a whole.
+    fn replace(
+        &mut self,
+        portal: Option<Vec<Box<dyn Capturable>>>,
+        native: Vec<Box<dyn Capturable>>,
+    ) -> Vec<MessageOutbound> {
+        let old: Vec<String> = self
+            .portal
+            .iter()
+            .chain(self.native.iter())
+            .map(|c| c.id())
+            .collect();
+        if let Some(portal) = portal {
+            self.portal = portal;
+        }
+        self.native = native;
+        let mut sent = std::mem::take(&mut self.sent);
+        let mut changes = Vec::new();
+        for capturable in self.portal.iter().chain(self.native.iter()) {
+            let id = capturable.id();
+            let info = match sent.remove(&id) {
+                Some(last) => {
+                    let info = describe(capturable.as_ref(), last.id.clone());
+                    if info != last {
+                        changes.push(MessageOutbound::CapturableUpdated(info.clone()));
+                    }
+                    info
+                }
+                None => {
+                    self.added += 1;
+                    let info = describe(capturable.as_ref(), format!("{id}#{}", self.added));
+                    changes.push(MessageOutbound::CapturableAdded(info.clone()));
+                    info
+                }
+            };
+            self.sent.insert(id, info);
+        }
+        // whatever is left has vanished
+        let mut updates: Vec<MessageOutbound> = old
+            .iter()
+            .filter_map(|id| sent.remove(id))
+            .map(|info| MessageOutbound::CapturableRemoved { id: info.id })
+            .collect();
+        updates.extend(changes);
+        updates
+    }
+
+    fn infos(&self) -> Vec<CapturableInfo> {
+        let mut infos: Vec<CapturableInfo> = self
+            .portal
+            .iter()
+            .chain(self.native.iter())
+            .map(|c| self.info(c.as_ref()))
+            .collect();
+        if let Some(default) = &self.default {
+            let index = match default.parse::<usize>() {
+                Ok(index) => Some(index),
+                Err(_) => {
+                    let default = default.to_lowercase();
+                    infos
+                        .iter()
+                        .position(|info| info.name.to_lowercase().contains(&default))
+                }
+            };
+            match index.and_then(|i| infos.get_mut(i)) {
+                Some(info) => info.default = true,
+                None => debug!("No capturable matches the default capturable {default}."),
+            }
+        } else if let Some(last) = &self.last {
+            let index = self
+                .portal
+                .iter()
+                .chain(self.native.iter())
+                .position(|c| remembered_name(c.as_ref()) == *last);
+            match index {
+                Some(i) => infos[i].default = true,
+                None => debug!("The capturable selected last, {last}, is gone."),
+            }
+        }
+        infos
+    }
+}
+
+/// The capturable selected last by any client, kept in the configuration file so that it is
+/// selected again after a restart if it is still around. Clones share the same state.
+#[derive(Clone)]
+pub struct LastCapturable {
+    name: Arc<watch::Sender<Option<String>>>,
 }
 
-enum VideoCommands {
-    Start(VideoConfig),
-    Pause,
-    Resume,
+impl LastCapturable {
+    pub fn new(name: Option<String>) -> Self {
+        Self {
+            name: Arc::new(watch::Sender::new(name)),
+        }
+    }
+
+    pub fn get(&self) -> Option<String> {
+        self.name.borrow().clone()
+    }
+
+    fn set(&self, capturable: &dyn Capturable) {
+        let name = remembered_name(capturable);
+        self.name.send_if_modified(|current| {
+            let changed = current.as_ref() != Some(&name);
+            *current = Some(name);
+            changed
+        });
+    }
+
+    /// The receiver only sees changes made after subscribing.
+    pub fn subscribe(&self) -> watch::Receiver<Option<String>> {
+        self.name.subscribe()
+    }
+}
+
+/// Ids change across restarts, so capturables are remembered by name and monitors by their
+/// output, their names tend to include the mode.
+fn remembered_name(capturable: &dyn Capturable) -> String {
+    capturable
+        .monitor()
+        .map_or_else(|| capturable.name(), |monitor| monitor.output)
+}
+
+/// What the client is told about the capturable, which it knows by `id`.
+fn describe(capturable: &dyn Capturable, id: String) -> CapturableInfo {
+    CapturableInfo {
+        id,
+        name: capturable.name(),
+        geometry: capturable.geometry().ok(),
+        physical_size: capturable.physical_size(),
+        monitor: capturable.monitor(),
+        class: capturable.class(),
+        pid: capturable.pid(),
+        default: false,
+    }
 }
 
-fn send_message<S>(sender: &mut S, message: MessageOutbound)
+pub fn send_message<S>(sender: &mut S, message: MessageOutbound)
 where
     S: WeylusSender,
 {
@@ -42,97 +251,277 @@ where
     }
 }
 
-pub struct WeylusClientHandler<S, R, FnUInput> {
+pub struct WeylusClientHandler<S, R, FnUInput, FnViewOnly> {
     sender: S,
     receiver: Option<R>,
-    video_sender: mpsc::Sender<VideoCommands>,
-    input_device: Option<Box<dyn InputDevice>>,
-    capturables: Vec<Box<dyn Capturable>>,
+    video: VideoClient<S>,
+    // movements are paced as the client configured, see PacedInput
+    input_device: Option<PacedInput>,
+    capturables: Arc<Mutex<Capturables>>,
+    capturable_watcher_stop: mpsc::Sender<()>,
+    capturable_watcher: JoinHandle<()>,
+    clipboard: Option<ClipboardSync>,
+    audio: Option<AudioStream>,
+    pressure_mapping: PressureMapping,
+    pointer_order: PointerOrder,
+    pen_smoother: PenSmoother,
+    touch_gestures: TouchGestures,
+    palm_rejection: PalmRejection,
+    relative_pointer: RelativePointer,
+    // the video only shows this part of the capturable, pointer events are relative to it
+    crop: Option<CropRect>,
     on_uinput_inaccessible: FnUInput,
+    // called with true once the client switches to view-only mode and false once it leaves it
+    on_view_only: FnViewOnly,
+    input_enabled: bool,
+    // the client asked to pause the video, as opposed to the server pausing it while the client
+    // is away
+    video_paused: bool,
+    // false while the client is configured without video
+    video_enabled: bool,
     config: WeylusClientConfig,
     #[cfg(target_os = "linux")]
     capture_cursor: bool,
     client_name: Option<String>,
-    video_thread: JoinHandle<()>,
+    // only clients on the host are told who else is connected
+    client_address: Option<SocketAddr>,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct WeylusClientConfig {
     pub encoder_options: EncoderOptions,
     #[cfg(target_os = "linux")]
-    pub wayland_support: bool,
+    pub capture_backends: CaptureBackends,
+    #[cfg(target_os = "macos")]
+    pub logical_resolution: bool,
+    pub full_frame_interval: Duration,
+    pub recording: RecordingConfig,
+    pub default_capturable: Option<String>,
+    #[cfg(target_os = "linux")]
+    pub hide_utility_windows: bool,
+    #[cfg(target_os = "linux")]
+    pub pen_pressure_max: i32,
+    // shared by all clients
+    pub input_switch: InputSwitch,
+    pub last_capturable: LastCapturable,
+    pub connections: Connections,
+    #[cfg(target_os = "linux")]
+    pub uinput_pool: UInputPool,
 }
 
-impl<S, R, FnUInput> WeylusClientHandler<S, R, FnUInput> {
+impl<S, R, FnUInput, FnViewOnly> WeylusClientHandler<S, R, FnUInput, FnViewOnly> {
     pub fn new(
         sender: S,
         receiver: R,
         on_uinput_inaccessible: FnUInput,
+        on_view_only: FnViewOnly,
         config: WeylusClientConfig,
+        capture_registry: &CaptureRegistry<S>,
     ) -> Self
     where
         R: WeylusReceiver,
         S: WeylusSender + Clone + Send + Sync + 'static,
     {
-        let (video_sender, video_receiver) = mpsc::channel::<VideoCommands>();
-        let video_thread = {
+        let capturables = Arc::new(Mutex::new(Capturables {
+            default: config.default_capturable.clone(),
+            last: config.last_capturable.get(),
+            ..Default::default()
+        }));
+        let (capturable_watcher_stop, stop_receiver) = mpsc::channel::<()>();
+        let capturable_watcher = {
             let sender = sender.clone();
-            // offload creating the videostream to another thread to avoid blocking the thread that
-            // is receiving messages from the websocket
-            spawn(move || handle_video(video_receiver, sender, config.encoder_options))
+            let capturables = capturables.clone();
+            let config = config.clone();
+            spawn(move || watch_capturables(stop_receiver, capturables, sender, config))
         };
 
         Self {
             sender,
             receiver: Some(receiver),
-            video_sender,
+            video: capture_registry.client(),
             input_device: None,
-            capturables: vec![],
+            capturables,
+            capturable_watcher_stop,
+            capturable_watcher,
+            clipboard: None,
+            audio: None,
+            pressure_mapping: PressureMapping::default(),
+            pointer_order: PointerOrder::default(),
+            pen_smoother: PenSmoother::default(),
+            touch_gestures: TouchGestures::default(),
+            palm_rejection: PalmRejection::default(),
+            relative_pointer: RelativePointer::default(),
+            crop: None,
             on_uinput_inaccessible,
+            on_view_only,
+            input_enabled: true,
+            video_paused: false,
+            video_enabled: true,
             config,
             #[cfg(target_os = "linux")]
             capture_cursor: false,
             client_name: None,
-            video_thread,
+            client_address: None,
         }
     }
 
+    /// Address the client connected from, clients on the host get the addresses and names of the
+    /// other clients in the connection list.
+    pub fn with_client_address(mut self, address: SocketAddr) -> Self {
+        self.client_address = Some(address);
+        self
+    }
+
+    /// Send input to the given device until the client configures one of another type.
+    #[cfg(test)]
+    fn with_input_device(mut self, input_device: Box<dyn InputDevice>) -> Self {
+        self.input_device = Some(PacedInput::new(input_device));
+        self
+    }
+
     pub fn run(mut self)
     where
         R: WeylusReceiver,
         S: WeylusSender + Clone + Send + Sync + 'static,
-        FnUInput: Fn(),
+        FnUInput: Fn(String),
+        FnViewOnly: Fn(bool),
     {
-        for message in self.receiver.take().unwrap() {
+        if !self.config.input_switch.is_enabled() {
+            self.send_message(MessageOutbound::HostInputEnabled(false));
+        }
+        let mut receiver = self.receiver.take().unwrap();
+        loop {
+            // pointer events that are held back are passed on once they are due, even if nothing
+            // arrives after them
+            let message = match self.pointer_order.next_deadline() {
+                Some(deadline) => {
+                    match receiver.next_timeout(deadline.saturating_duration_since(Instant::now()))
+                    {
+                        Ok(message) => message,
+                        Err(RecvTimeoutError::Timeout) => {
+                            for event in self.pointer_order.flush_due(Instant::now()) {
+                                self.inject_pointer_event(event);
+                            }
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                None => match receiver.next() {
+                    Some(message) => message,
+                    None => break,
+                },
+            };
             match message {
                 Ok(message) => {
                     trace!("Received message: {message:?}");
                     match message {
-                        MessageInbound::PointerEvent(event) => self.process_pointer_event(&event),
+                        MessageInbound::PointerEvent(event) => self.process_pointer_event(event),
                         MessageInbound::WheelEvent(event) => self.process_wheel_event(&event),
                         MessageInbound::KeyboardEvent(event) => self.process_keyboard_event(&event),
                         MessageInbound::GetCapturableList => self.send_capturable_list(),
                         MessageInbound::Config(config) => self.update_config(config),
+                        MessageInbound::ApiConfig(mut config) => {
+                            config.capturable_id = self
+                                .capturables
+                                .lock()
+                                .unwrap()
+                                .resolve_api_id(&config.capturable_id);
+                            self.update_config(config)
+                        }
                         MessageInbound::PauseVideo => {
-                            self.video_sender.send(VideoCommands::Pause).unwrap()
+                            self.video_paused = true;
+                            self.video.pause()
                         }
                         MessageInbound::ResumeVideo => {
-                            self.video_sender.send(VideoCommands::Resume).unwrap()
+                            self.video_paused = false;
+                            self.video.resume()
+                        }
+                        MessageInbound::RequestKeyFrame | MessageInbound::StartRecording
+                            if !self.video_enabled =>
+                        {
+                            self.send_message(MessageOutbound::Error(ClientError::new(
+                                ErrorKind::VideoDisabled,
+                                "There is no video, it has been disabled in the configuration.",
+                            )))
+                        }
+                        MessageInbound::RequestKeyFrame => self.video.request_keyframe(),
+                        MessageInbound::SetInputEnabled(enabled) => self.set_input_enabled(enabled),
+                        MessageInbound::HostInputEnabled(enabled) => {
+                            self.host_input_enabled(enabled)
+                        }
+                        MessageInbound::StartRecording => self.video.start_recording(),
+                        MessageInbound::StopRecording => self.video.stop_recording(),
+                        MessageInbound::ClipboardSet(text) => match &self.clipboard {
+                            Some(clipboard) => clipboard.set_text(text),
+                            None => warn!("Clipboard sync is disabled, ignoring clipboard."),
+                        },
+                        // the websocket answers these itself, this is for other receivers
+                        MessageInbound::Ping { id, client_ts } => {
+                            self.send_message(MessageOutbound::Pong {
+                                id,
+                                client_ts,
+                                server_ts: unix_time_ms(),
+                            })
+                        }
+                        // only the websocket keeps sessions around to resume them
+                        MessageInbound::Resume { .. } => {
+                            self.send_message(MessageOutbound::Error(ClientError::new(
+                                ErrorKind::SessionExpired,
+                                "Session can not be resumed!",
+                            )))
                         }
+                        MessageInbound::SessionSuspended => self.session_suspended(),
+                        MessageInbound::SessionResumed => self.session_resumed(),
+                        MessageInbound::GetConnectionList => {
+                            let on_host = self
+                                .client_address
+                                .is_some_and(|address| address.ip().to_canonical().is_loopback());
+                            let list = if on_host {
+                                self.config.connections.list()
+                            } else {
+                                self.config.connections.list_anonymized()
+                            };
+                            self.send_message(MessageOutbound::ConnectionList(list))
+                        }
+                        MessageInbound::GetAudioSources => {
+                            self.send_message(MessageOutbound::AudioSources(audio_sources()))
+                        }
+                        // only the websocket sets up WebRTC connections
+                        MessageInbound::RtcOffer { .. } => {
+                            self.send_message(MessageOutbound::Error(ClientError::new(
+                                ErrorKind::RtcUnavailable,
+                                "WebRTC is not supported on this connection!",
+                            )))
+                        }
+                        MessageInbound::RtcCandidate(_) => (),
                     }
                 }
                 Err(err) => {
                     warn!("Failed to read message {err}!");
-                    self.send_message(MessageOutbound::Error(
-                        "Failed to read message!".to_string(),
-                    ));
+                    self.send_message(MessageOutbound::Error(ClientError::new(
+                        ErrorKind::InvalidMessage,
+                        "Failed to read message!",
+                    )));
                 }
             }
         }
 
-        drop(self.video_sender);
-        if let Err(err) = self.video_thread.join() {
-            warn!("Failed to join video thread: {err:?}");
+        if !self.input_enabled {
+            (self.on_view_only)(false);
+        }
+        // the client may be gone in the middle of a stroke or while holding a key, release
+        // everything before the device is destroyed
+        if let Some(mut device) = self.input_device.take() {
+            device.release_all();
+        }
+        // stop receiving video, the capture itself keeps running if other clients use it
+        drop(self.video);
+        drop(self.clipboard);
+        drop(self.audio);
+        drop(self.capturable_watcher_stop);
+        if let Err(err) = self.capturable_watcher.join() {
+            warn!("Failed to join capturable watcher thread: {err:?}");
         }
     }
 
@@ -143,90 +532,407 @@ impl<S, R, FnUInput> WeylusClientHandler<S, R, FnUInput> {
         send_message(&mut self.sender, message)
     }
 
+    fn set_input_enabled(&mut self, enabled: bool)
+    where
+        FnViewOnly: Fn(bool),
+    {
+        if enabled != self.input_enabled {
+            debug!(
+                "Client {} view-only mode.",
+                if enabled { "left" } else { "entered" }
+            );
+            self.input_enabled = enabled;
+            (self.on_view_only)(!enabled);
+            if !enabled {
+                self.release_input();
+            }
+        }
+    }
+
+    // the old device is released first, the new one does not know what it pressed
+    fn switch_input_device(&mut self, device: Option<Box<dyn InputDevice>>) {
+        if let Some(mut old) = self.input_device.take() {
+            old.release_all();
+        }
+        self.input_device = device.map(PacedInput::new);
+    }
+
+    // lets go of whatever is down, for when the events that would release it are dropped
+    fn release_input(&mut self) {
+        if let Some(device) = &mut self.input_device {
+            device.release_all();
+        }
+        self.touch_gestures.reset();
+        self.palm_rejection.reset();
+        self.relative_pointer.reset();
+        self.pointer_order.reset();
+    }
+
+    fn host_input_enabled(&mut self, enabled: bool)
+    where
+        S: WeylusSender,
+    {
+        if !enabled {
+            // events still in flight are dropped, so whatever is down now would stay down
+            self.release_input();
+        }
+        self.send_message(MessageOutbound::HostInputEnabled(enabled));
+    }
+
+    fn session_suspended(&mut self) {
+        debug!("Client is away, pausing its video.");
+        // nobody is there to lift the pen or release the keys
+        self.release_input();
+        self.video.pause();
+    }
+
+    fn session_resumed(&mut self)
+    where
+        S: WeylusSender,
+    {
+        debug!("Client is back, resuming its video.");
+        self.send_message(MessageOutbound::SessionResumed);
+        // the switch may have been flipped while the client was away
+        self.host_input_enabled(self.config.input_switch.is_enabled());
+        if !self.video_paused {
+            self.video.resume();
+        }
+        // the client starts over with a fresh MediaSource, which needs the stream from its start
+        self.video.restart_video();
+    }
+
+    fn accepts_input(&self) -> bool {
+        self.input_enabled && self.config.input_switch.is_enabled()
+    }
+
     fn process_wheel_event(&mut self, event: &WheelEvent) {
+        if !self.accepts_input() {
+            return;
+        }
         match &mut self.input_device {
             Some(i) => i.send_wheel_event(event),
             None => warn!("Input device is not initalized, can not process WheelEvent!"),
         }
     }
 
-    fn process_pointer_event(&mut self, event: &PointerEvent) {
+    fn process_pointer_event(&mut self, mut event: PointerEvent) {
+        if !self.accepts_input() {
+            return;
+        }
+        if !event.x.is_finite() || !event.y.is_finite() {
+            warn!("Dropping pointer event without a position: {event:?}");
+            return;
+        }
+        // positions are relative to the content, a client that letterboxes the video may send
+        // positions on the black bars, which belong to the edge
+        if !(0.0..=1.0).contains(&event.x) || !(0.0..=1.0).contains(&event.y) {
+            trace!("Pointer event is outside of the content: {event:?}");
+            event.x = event.x.clamp(0.0, 1.0);
+            event.y = event.y.clamp(0.0, 1.0);
+        }
+        for event in self.pointer_order.process(event, Instant::now()) {
+            self.inject_pointer_event(event);
+        }
+    }
+
+    fn inject_pointer_event(&mut self, mut event: PointerEvent) {
         if self.input_device.is_some() {
-            self.input_device
-                .as_mut()
-                .unwrap()
-                .send_pointer_event(event)
+            // in coordinates of the video, which is what the client's parameters refer to
+            self.pen_smoother.apply(&mut event);
+            if let Some(crop) = self.crop {
+                (event.x, event.y) = crop.to_capturable(event.x, event.y);
+            }
+            let device = self.input_device.as_mut().unwrap();
+            let touch_gestures = &mut self.touch_gestures;
+            let relative_pointer = &mut self.relative_pointer;
+            // in relative mode touches go to both, one finger moves the cursor and two scroll
+            let rejected = self.palm_rejection.process(&event, |cancel| {
+                let gesture = touch_gestures.process(cancel, &mut *device);
+                if !relative_pointer.process(cancel, &mut *device) && !gesture {
+                    device.send_pointer_event(cancel);
+                }
+            });
+            if rejected {
+                return;
+            }
+            let gesture = self.touch_gestures.process(&event, &mut *device);
+            if self.relative_pointer.process(&event, &mut *device) || gesture {
+                return;
+            }
+            self.pressure_mapping.apply(&mut event);
+            device.send_pointer_event(&event)
         } else {
             warn!("Input device is not initalized, can not process PointerEvent!");
         }
     }
 
-    fn process_keyboard_event(&mut self, event: &KeyboardEvent) {
+    fn process_keyboard_event(&mut self, event: &KeyboardEvent)
+    where
+        S: WeylusSender,
+    {
+        if !self.accepts_input() {
+            return;
+        }
         if self.input_device.is_some() {
-            self.input_device
+            if let Err(err) = self
+                .input_device
                 .as_mut()
                 .unwrap()
                 .send_keyboard_event(event)
+            {
+                warn!("Failed to send keyboard event: {err}");
+                self.send_message(MessageOutbound::Error(ClientError::new(
+                    ErrorKind::InputFailed,
+                    err.to_string(),
+                )));
+            }
         } else {
             warn!("Input device is not initalized, can not process KeyboardEvent!");
         }
     }
 
+    /// The region of the capturable to stream and map input to, a physical scale mode is cropping
+    /// the part that is as large as the view of the client. How the scale mode has been applied
+    /// is sent to the client.
+    fn scaled_crop(
+        &mut self,
+        config: &ClientConfiguration,
+        capturable: &dyn Capturable,
+    ) -> Option<CropRect> {
+        let ScaleMode::Physical {
+            width_mm,
+            height_mm,
+        } = config.scale_mode
+        else {
+            return config.crop;
+        };
+        let (crop, scale_mode, fallback) = match capturable.physical_size() {
+            Some(size) => {
+                let crop = CropRect::physical(width_mm, height_mm, size, config.crop);
+                let scale_mode = ScaleMode::Physical {
+                    width_mm: crop.width * size.width_mm,
+                    height_mm: crop.height * size.height_mm,
+                };
+                (Some(crop), scale_mode, None)
+            }
+            None => {
+                let fallback = format!(
+                    "The physical size of {} is unknown, the video is scaled to fit instead.",
+                    capturable.name()
+                );
+                info!("{fallback}");
+                (config.crop, ScaleMode::Fit, Some(fallback))
+            }
+        };
+        self.send_message(MessageOutbound::ScaleApplied {
+            scale_mode,
+            fallback,
+        });
+        crop
+    }
+
     fn send_capturable_list(&mut self)
     where
         S: WeylusSender,
     {
-        let mut windows = Vec::<String>::new();
-        self.capturables = get_capturables(
-            #[cfg(target_os = "linux")]
-            self.config.wayland_support,
+        let windows = {
+            let mut capturables = self.capturables.lock().unwrap();
             #[cfg(target_os = "linux")]
-            self.capture_cursor,
-        );
-        self.capturables.iter().for_each(|c| {
-            windows.push(c.name());
-        });
+            let portal = Some(if self.config.capture_backends.pipewire {
+                get_portal_capturables(self.capture_cursor)
+            } else {
+                vec![]
+            });
+            #[cfg(not(target_os = "linux"))]
+            let portal = None;
+            // the whole list is sent, the updates only have to be applied to the ids
+            capturables.replace(portal, native_capturables(&self.config));
+            capturables.requested = true;
+            capturables.infos()
+        };
         self.send_message(MessageOutbound::CapturableList(windows));
     }
 
     fn update_config(&mut self, config: ClientConfiguration)
     where
-        S: WeylusSender,
-        FnUInput: Fn(),
+        S: WeylusSender + Clone + Send + 'static,
+        FnUInput: Fn(String),
+        FnViewOnly: Fn(bool),
     {
+        if let Err(err) = config.validate() {
+            warn!("Invalid configuration: {err}");
+            self.send_message(MessageOutbound::ConfigError(err));
+            return;
+        }
+        self.set_input_enabled(config.input_enabled);
         let client_name_changed = if self.client_name != config.client_name {
             self.client_name = config.client_name;
             true
         } else {
             false
         };
-        if config.capturable_id < self.capturables.len() {
-            let capturable = self.capturables[config.capturable_id].clone();
-
+        if config.clipboard_sync != self.clipboard.is_some() {
+            self.clipboard = config
+                .clipboard_sync
+                .then(|| ClipboardSync::new(self.sender.clone()));
+        }
+        let audio_changed = match &self.audio {
+            Some(audio) => !config.audio || audio.source() != config.audio_source.as_deref(),
+            None => config.audio,
+        };
+        if audio_changed {
+            // the old stream stops capturing before the new one starts
+            self.audio = None;
+            self.audio = config
+                .audio
+                .then(|| AudioStream::new(config.audio_source.clone(), self.sender.clone()));
+        }
+        // the curve is applied before events reach the input device, so there is no need to
+        // recreate the device
+        self.pressure_mapping.set_curve(config.pressure_curve);
+        for event in self.pointer_order.set_ordering(config.pointer_ordering) {
+            self.inject_pointer_event(event);
+        }
+        self.pen_smoother.set_smoothing(config.pen_smoothing);
+        self.touch_gestures.set_enabled(config.touch_gestures);
+        self.palm_rejection
+            .set_timeout(config.palm_rejection.then(|| {
+                config
+                    .palm_rejection_timeout_ms
+                    .map(|ms| Duration::from_millis(ms.into()))
+                    .unwrap_or(DEFAULT_PALM_REJECTION_TIMEOUT)
+            }));
+        if self.relative_pointer.configure(
+            config.pointer_mode,
+            config.pointer_sensitivity.unwrap_or(1.0),
+            config.relative_stylus,
+        ) {
+            // whatever is down has been pressed the other way and would stay down otherwise
+            if let Some(device) = &mut self.input_device {
+                device.release_all();
+            }
+            self.touch_gestures.reset();
+            self.palm_rejection.reset();
+        }
+        self.touch_gestures
+            .set_relative(config.pointer_mode == PointerMode::Relative);
+        let capturable = self.capturables.lock().unwrap().find(&config.capturable_id);
+        if let Some(capturable) = capturable {
+            // the client may capture this window differently than the host does by default
+            #[cfg(target_os = "linux")]
+            let capturable: Box<dyn Capturable> =
+                match (capturable.x11_window(), config.x11_window_capture) {
+                    (Some(window), Some(window_capture)) => {
+                        Box::new(window.with_window_capture(window_capture))
+                    }
+                    _ => capturable,
+                };
+            self.config.last_capturable.set(capturable.as_ref());
+            self.crop = self.scaled_crop(&config, capturable.as_ref());
             #[cfg(target_os = "linux")]
             {
                 self.capture_cursor = config.capture_cursor;
             }
 
+            // input goes where the video comes from, the portal knows where its streams are
             #[cfg(target_os = "linux")]
-            if config.uinput_support {
+            if let Some(stream) = capturable.remote_desktop() {
+                if self.input_device.as_ref().map_or(true, |d| {
+                    client_name_changed || d.device_type() != InputDeviceType::PortalDevice
+                }) {
+                    // the portal has no stylus, uinput keeps pressure and tilt if it is available
+                    self.switch_input_device(None);
+                    let stylus: Option<Box<dyn InputDevice>> = if config.uinput_support {
+                        match self.config.uinput_pool.device(
+                            capturable.clone(),
+                            &self.client_name,
+                            self.config.pen_pressure_max,
+                        ) {
+                            Ok(device) => Some(Box::new(device)),
+                            Err(err) => {
+                                warn!("Failed to create uinput device for the stylus: {err}");
+                                None
+                            }
+                        }
+                    } else {
+                        None
+                    };
+                    self.switch_input_device(Some(Box::new(
+                        crate::input::portal_device::PortalInputDevice::new(
+                            capturable.clone(),
+                            stream,
+                            stylus,
+                        ),
+                    )));
+                } else if let Some(d) = self.input_device.as_mut() {
+                    d.set_capturable(capturable.clone());
+                }
+            } else if let Some(window) = capturable.x11_window().filter(|_| config.background_input)
+            {
+                if self
+                    .input_device
+                    .as_ref()
+                    .map_or(true, |d| d.device_type() != InputDeviceType::X11WindowInput)
+                {
+                    self.switch_input_device(Some(Box::new(
+                        crate::input::x11_window_device::X11WindowInput::new(window),
+                    )));
+                } else if let Some(d) = self.input_device.as_mut() {
+                    d.set_capturable(capturable.clone());
+                }
+            } else if config.uinput_support {
                 if self.input_device.as_ref().map_or(true, |d| {
                     client_name_changed || d.device_type() != InputDeviceType::UInputDevice
                 }) {
-                    let device = crate::input::uinput_device::UInputDevice::new(
+                    // the old devices go back to the pool first, switching from autopilot back
+                    // to uinput reuses them
+                    self.switch_input_device(None);
+                    let device = self.config.uinput_pool.device(
                         capturable.clone(),
                         &self.client_name,
+                        self.config.pen_pressure_max,
                     );
                     match device {
-                        Ok(d) => self.input_device = Some(Box::new(d)),
+                        Ok(d) => {
+                            if !d.missing_devices().is_empty() {
+                                self.send_message(MessageOutbound::Error(ClientError::new(
+                                    ErrorKind::InputDeviceFailed,
+                                    format!(
+                                        "Failed to create uinput device for {}, input of this \
+                                        kind will not work!",
+                                        d.missing_devices().join(", ")
+                                    ),
+                                )));
+                            }
+                            self.switch_input_device(Some(Box::new(d)));
+                        }
                         Err(e) => {
                             error!("Failed to create uinput device: {}", e);
-                            if let CErrorCode::UInputNotAccessible = e.to_enum() {
-                                (self.on_uinput_inaccessible)();
-                            }
-                            self.send_message(MessageOutbound::ConfigError(
-                                "Failed to create uinput device!".to_string(),
-                            ));
+                            let err = if let CErrorCode::UInputNotAccessible = e.code() {
+                                use crate::input::uinput_device::{
+                                    UInputDiagnosis, UDEV_RULE, UINPUT_PATH,
+                                };
+                                let diagnosis = UInputDiagnosis::new();
+                                error!("{diagnosis}\nThe udev rule for uinput is: {UDEV_RULE}");
+                                (self.on_uinput_inaccessible)(diagnosis.to_string());
+                                ClientError::new(
+                                    ErrorKind::UInputPermissionDenied {
+                                        path: UINPUT_PATH.into(),
+                                        errno: diagnosis.errno,
+                                        groups: diagnosis.groups.clone(),
+                                        device_group: diagnosis.device_group.clone(),
+                                        hint: diagnosis.hint(),
+                                        udev_rule: UDEV_RULE.into(),
+                                    },
+                                    format!("Weylus is not allowed to access {UINPUT_PATH}!"),
+                                )
+                            } else {
+                                ClientError::new(
+                                    ErrorKind::InputDeviceFailed,
+                                    "Failed to create uinput device!",
+                                )
+                            };
+                            self.send_message(MessageOutbound::ConfigError(err));
                             return;
                         }
                     }
@@ -236,18 +942,18 @@ impl<S, R, FnUInput> WeylusClientHandler<S, R, FnUInput> {
             } else if self.input_device.as_ref().map_or(true, |d| {
                 d.device_type() != InputDeviceType::AutoPilotDevice
             }) {
-                self.input_device = Some(Box::new(
+                self.switch_input_device(Some(Box::new(
                     crate::input::autopilot_device::AutoPilotDevice::new(capturable.clone()),
-                ));
+                )));
             } else if let Some(d) = self.input_device.as_mut() {
                 d.set_capturable(capturable.clone());
             }
 
             #[cfg(target_os = "macos")]
             if self.input_device.is_none() {
-                self.input_device = Some(Box::new(
+                self.switch_input_device(Some(Box::new(
                     crate::input::autopilot_device::AutoPilotDevice::new(capturable.clone()),
-                ));
+                )));
             } else {
                 self.input_device
                     .as_mut()
@@ -255,190 +961,209 @@ impl<S, R, FnUInput> WeylusClientHandler<S, R, FnUInput> {
             }
             #[cfg(target_os = "windows")]
             if self.input_device.is_none() {
-                self.input_device = Some(Box::new(
+                self.switch_input_device(Some(Box::new(
                     crate::input::autopilot_device_win::WindowsInput::new(capturable.clone()),
-                ));
+                )));
             } else {
                 self.input_device
                     .as_mut()
                     .map(|d| d.set_capturable(capturable.clone()));
             }
 
-            self.video_sender
-                .send(VideoCommands::Start(VideoConfig {
+            if let Some(device) = &mut self.input_device {
+                device.set_pacing(config.input_pacing);
+                device.set_button_mapping(&config.button_mapping);
+                if let Err(err) = device.set_pointer_mode(config.pointer_mode) {
+                    warn!(
+                        "Failed to switch to {:?} pointer mode: {err}",
+                        config.pointer_mode
+                    );
+                    self.send_message(MessageOutbound::Error(ClientError::new(
+                        ErrorKind::InputDeviceFailed,
+                        format!("Failed to switch pointer mode: {err}"),
+                    )));
+                }
+            }
+
+            self.video_enabled = config.video;
+            if !config.video {
+                debug!(
+                    "Video is disabled, only sending input to {}.",
+                    capturable.name()
+                );
+                self.capturables.lock().unwrap().input_only = Some(capturable.id());
+                self.video.stop();
+                self.send_message(MessageOutbound::ConfigOk);
+                return;
+            }
+            self.capturables.lock().unwrap().input_only = None;
+            // creating the recorder is offloaded to the capture thread to avoid blocking the thread
+            // that is receiving messages from the websocket
+            self.video.start(
+                VideoConfig {
                     capturable,
                     capture_cursor: config.capture_cursor,
                     max_width: config.max_width,
                     max_height: config.max_height,
-                    frame_rate: config.frame_rate,
-                }))
-                .unwrap();
+                    frame_rate: config
+                        .max_fps
+                        .map_or(config.frame_rate, |max_fps| config.frame_rate.min(max_fps)),
+                    send_stats: config.send_stats,
+                    send_frame_info: config.send_frame_info,
+                    report_cursor: config.report_cursor,
+                    video_format: config.video_format,
+                    preferred_codecs: config.preferred_codecs.clone(),
+                    jpeg_quality: config.jpeg_quality.unwrap_or(DEFAULT_JPEG_QUALITY),
+                    bitrate_kbps: config.bitrate_kbps,
+                    adaptive_bitrate: config.adaptive_bitrate,
+                    crf: config.crf,
+                    intra_refresh: config.intra_refresh,
+                    crop: self.crop,
+                },
+                self.sender.clone(),
+            );
+            self.video_paused = false;
         } else {
-            error!("Got invalid id for capturable: {}", config.capturable_id);
-            self.send_message(MessageOutbound::ConfigError(
-                "Invalid id for capturable!".to_string(),
-            ));
+            error!("Capturable no longer exists: {}", config.capturable_id);
+            self.send_message(MessageOutbound::ConfigError(ClientError::new(
+                ErrorKind::CapturableLost,
+                "Capturable no longer exists!",
+            )));
         }
     }
 }
 
-fn handle_video<S: WeylusSender + Clone + 'static>(
-    receiver: mpsc::Receiver<VideoCommands>,
-    mut sender: S,
-    encoder_options: EncoderOptions,
-) {
-    const EFFECTIVE_INIFINITY: Duration = Duration::from_secs(3600 * 24 * 365 * 200);
-
-    let mut recorder: Option<Box<dyn Recorder>> = None;
-    let mut video_encoder: Option<Box<VideoEncoder>> = None;
-
-    let mut max_width = 1920;
-    let mut max_height = 1080;
-    let mut frame_duration = EFFECTIVE_INIFINITY;
-    let mut last_frame = Instant::now();
-    let mut paused = false;
+/// The native capturables as a new client would be offered them, the portal is not asked as it
+/// bothers the user.
+pub fn capturable_infos(config: &WeylusClientConfig) -> Vec<CapturableInfo> {
+    let capturables = Capturables {
+        native: native_capturables(config),
+        default: config.default_capturable.clone(),
+        last: config.last_capturable.get(),
+        ..Default::default()
+    };
+    capturables.infos()
+}
 
-    loop {
-        let now = Instant::now();
-        let elapsed = now - last_frame;
-        let frames_passed = (elapsed.as_secs_f64() / frame_duration.as_secs_f64()) as u32;
-        let next_frame = last_frame + (frames_passed + 1) * frame_duration;
-        let timeout = next_frame - now;
-        last_frame = next_frame;
+fn native_capturables(config: &WeylusClientConfig) -> Vec<Box<dyn Capturable>> {
+    #[cfg_attr(not(target_os = "linux"), allow(unused_mut))]
+    let mut capturables = get_native_capturables(
+        #[cfg(target_os = "linux")]
+        config.capture_backends,
+        #[cfg(target_os = "macos")]
+        config.logical_resolution,
+    );
+    #[cfg(target_os = "linux")]
+    if config.hide_utility_windows {
+        capturables.retain(|c| !c.is_utility_window());
+    }
+    capturables
+}
 
-        if frames_passed > 0 {
-            debug!("Dropped {frames_passed} frame(s)!");
+/// Poll the native capturables and send the client updates of its list if windows have been opened,
+/// closed, renamed, moved or resized. A change is only sent once it has been seen twice in a row,
+/// this way short lived windows like menus or tooltips and windows that are being dragged around do
+/// not flood the client with updates.
+fn watch_capturables<S: WeylusSender>(
+    stop: mpsc::Receiver<()>,
+    capturables: Arc<Mutex<Capturables>>,
+    mut sender: S,
+    config: WeylusClientConfig,
+) {
+    type Key = Vec<(String, String, Option<Geometry>)>;
+    let key = |captrs: &[Box<dyn Capturable>]| -> Key {
+        captrs
+            .iter()
+            .map(|c| (c.id(), c.name(), c.geometry().ok()))
+            .collect()
+    };
+    let mut pending: Option<Key> = None;
+    while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(CAPTURABLE_POLL_INTERVAL) {
+        let native = native_capturables(&config);
+        let new_key = key(&native);
+        if pending.as_ref() != Some(&new_key) {
+            pending = Some(new_key);
+            continue;
         }
-
-        match receiver.recv_timeout(if paused { EFFECTIVE_INIFINITY } else { timeout }) {
-            Ok(VideoCommands::Start(config)) => {
-                #[allow(unused_assignments)]
-                {
-                    // gstpipewire can not handle setting a pipeline's state to Null after another
-                    // pipeline has been created and its state has been set to Play.
-                    // This line makes sure that there always is only a single recorder and thus
-                    // single pipeline in this thread by forcing rust to call the destructor of the
-                    // current pipeline here, right before creating a new pipeline.
-                    // See: https://gitlab.freedesktop.org/pipewire/pipewire/-/issues/986
-                    //
-                    // This shouldn't affect other Recorder trait objects.
-                    recorder = None;
-                }
-                match config.capturable.recorder(config.capture_cursor) {
-                    Ok(r) => {
-                        recorder = Some(r);
-                        max_width = config.max_width;
-                        max_height = config.max_height;
-                        send_message(&mut sender, MessageOutbound::ConfigOk);
-                    }
-                    Err(err) => {
-                        warn!("Failed to init screen cast: {}!", err);
-                        send_message(
-                            &mut sender,
-                            MessageOutbound::Error("Failed to init screen cast!".into()),
-                        )
-                    }
-                }
-                last_frame = Instant::now();
-
-                // The Duration type can not handle infinity, if the frame rate is set to 0 we just
-                // set the duration between two frames to a very long one, which is effectively
-                // infinity.
-                let d = 1.0 / config.frame_rate;
-                frame_duration = if d.is_finite() {
-                    Duration::from_secs_f64(d)
-                } else {
-                    EFFECTIVE_INIFINITY
-                };
-                frame_duration = frame_duration.min(EFFECTIVE_INIFINITY);
+        let updates = {
+            let mut capturables = capturables.lock().unwrap();
+            if !capturables.requested {
+                continue;
             }
-            Ok(VideoCommands::Pause) => {
-                paused = true;
+            // the geometry of the capturables is queried when asked for, so what has been sent is
+            // compared instead of the capturables polled last
+            let mut updates = capturables.replace(None, native);
+            if !updates.is_empty() {
+                debug!("List of capturables changed, notifying client.");
             }
-            Ok(VideoCommands::Resume) => {
-                paused = false;
+            let lost = capturables.input_only.as_ref().is_some_and(|id| {
+                capturables
+                    .portal
+                    .iter()
+                    .chain(capturables.native.iter())
+                    .all(|c| c.id() != *id)
+            });
+            if lost {
+                capturables.input_only = None;
+                updates.push(MessageOutbound::CapturableLost);
             }
-            Err(RecvTimeoutError::Timeout) => {
-                if recorder.is_none() {
-                    warn!("Screen capture not initalized, can not send video frame!");
-                    continue;
-                }
-                let pixel_data = recorder.as_mut().unwrap().capture();
-                if let Err(err) = pixel_data {
-                    warn!("Error capturing screen: {}", err);
-                    continue;
-                }
-                let pixel_data = pixel_data.unwrap();
-                let (width_in, height_in) = pixel_data.size();
-                let scale =
-                    (max_width as f64 / width_in as f64).min(max_height as f64 / height_in as f64);
-                // limit video to 4K
-                let scale_max = (3840.0 / width_in as f64).min(2160.0 / height_in as f64);
-                let scale = scale.min(scale_max);
-                let mut width_out = width_in;
-                let mut height_out = height_in;
-                if scale < 1.0 {
-                    width_out = (width_out as f64 * scale) as usize;
-                    height_out = (height_out as f64 * scale) as usize;
-                }
-                // video encoder is not setup or setup for encoding the wrong size: restart it
-                if video_encoder.is_none()
-                    || !video_encoder
-                        .as_ref()
-                        .unwrap()
-                        .check_size(width_in, height_in, width_out, height_out)
-                {
-                    send_message(&mut sender, MessageOutbound::NewVideo);
-                    let mut sender = sender.clone();
-                    let res = VideoEncoder::new(
-                        width_in,
-                        height_in,
-                        width_out,
-                        height_out,
-                        move |data| {
-                            if let Err(err) = sender.send_video(data) {
-                                warn!("Failed to send video frame: {err}!");
-                            }
-                        },
-                        encoder_options,
-                    );
-                    match res {
-                        Ok(r) => video_encoder = Some(r),
-                        Err(e) => {
-                            warn!("{}", e);
-                            continue;
-                        }
-                    };
-                }
-                let video_encoder = video_encoder.as_mut().unwrap();
-                video_encoder.encode(pixel_data);
-            }
-            // stop thread once the channel is closed
-            Err(RecvTimeoutError::Disconnected) => return,
+            updates
         };
+        for update in updates {
+            send_message(&mut sender, update);
+        }
     }
 }
 
+pub(crate) fn unix_time_ms() -> f64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+        * 1000.0
+}
+
+/// How long a connection that is still open may take to hand its session over to a new one.
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Status code of the close frame sent to clients when shutting down.
+const CLOSE_GOING_AWAY: u16 = 1001;
+
+/// Bytes in front of each binary message, filled with the time it is sent at.
+const VIDEO_HEADER_LEN: usize = 8;
+
+/// Messages from a client that may be waiting for its handler, see [`queue::channel`].
+const INBOUND_QUEUE_LEN: usize = 32;
+
 pub struct WsWeylusReceiver {
-    recv: tokio::sync::mpsc::Receiver<MessageInbound>,
+    recv: InboundReceiver,
 }
 
 impl Iterator for WsWeylusReceiver {
     type Item = Result<MessageInbound, Infallible>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.recv.blocking_recv().map(Ok)
+        self.recv.recv_blocking().map(Ok)
     }
 }
 
 impl WeylusReceiver for WsWeylusReceiver {
     type Error = Infallible;
+
+    fn next_timeout(&mut self, timeout: Duration) -> Result<Self::Item, RecvTimeoutError> {
+        self.recv.recv_timeout(timeout).map(Ok)
+    }
 }
 
 pub enum WsMessage {
     Frame(Frame<'static>),
     Video(Vec<u8>),
+    // an Opus packet and the time it has been captured at
+    Audio(u64, Vec<u8>),
     MessageOutbound(MessageOutbound),
+    // not sent, kept in the connection list by the writer, which serves the connection the
+    // session is on at the moment
+    Stats(VideoStats),
 }
 
 unsafe impl Send for WsMessage {}
@@ -446,6 +1171,8 @@ unsafe impl Send for WsMessage {}
 #[derive(Clone)]
 pub struct WsWeylusSender {
     sender: tokio::sync::mpsc::Sender<WsMessage>,
+    pending_video_bytes: Arc<AtomicUsize>,
+    coalesced_pointer_events: Arc<AtomicU32>,
 }
 
 impl WeylusSender for WsWeylusSender {
@@ -457,42 +1184,478 @@ impl WeylusSender for WsWeylusSender {
     }
 
     fn send_video(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
-        self.sender.blocking_send(WsMessage::Video(bytes.to_vec()))
+        self.pending_video_bytes
+            .fetch_add(bytes.len(), Ordering::Relaxed);
+        let mut data = Vec::with_capacity(VIDEO_HEADER_LEN + bytes.len());
+        data.extend_from_slice(&[0; VIDEO_HEADER_LEN]);
+        data.extend_from_slice(bytes);
+        self.sender.blocking_send(WsMessage::Video(data))
+    }
+
+    fn send_audio(&mut self, captured_at_us: u64, bytes: &[u8]) -> Result<(), Self::Error> {
+        let mut data = Vec::with_capacity(VIDEO_HEADER_LEN + bytes.len());
+        data.extend_from_slice(&[0; VIDEO_HEADER_LEN]);
+        data.extend_from_slice(bytes);
+        // a packet arriving late is of no use, so do not wait for the video ahead of it
+        match self.sender.try_send(WsMessage::Audio(captured_at_us, data)) {
+            Err(TrySendError::Closed(msg)) => Err(SendError(msg)),
+            _ => Ok(()),
+        }
+    }
+
+    fn pending_video_bytes(&self) -> usize {
+        self.pending_video_bytes.load(Ordering::Relaxed)
+    }
+
+    fn take_coalesced_pointer_events(&self) -> u32 {
+        self.coalesced_pointer_events.swap(0, Ordering::Relaxed)
+    }
+
+    fn stats_measured(&mut self, stats: &VideoStats) {
+        // the next ones follow in a few seconds, no need to wait for the video ahead of them
+        let _ = self.sender.try_send(WsMessage::Stats(stats.clone()));
+    }
+}
+
+/// The ends of the channels to a [`WeylusClientHandler`] that belong to the websocket.
+struct SessionChannels {
+    sender_inbound: InboundSender,
+    receiver_outbound: tokio::sync::mpsc::Receiver<WsMessage>,
+    pending_video_bytes: Arc<AtomicUsize>,
+}
+
+/// Receives where to send the channels of a session once it is resumed on another connection.
+type Takeover = tokio::sync::oneshot::Receiver<tokio::sync::oneshot::Sender<SessionChannels>>;
+
+enum Session {
+    // the client may come back on a new connection before the old one is noticed to be dead, so
+    // the old one can be told to hand over the session
+    Connected(tokio::sync::oneshot::Sender<tokio::sync::oneshot::Sender<SessionChannels>>),
+    // waiting for the client until the deadline
+    Suspended(Instant, SessionChannels),
+}
+
+/// Keeps the handlers of clients whose connection dropped running for a while, so they can pick
+/// up where they left off on a new connection. A session is ended once its grace period is over,
+/// which ends the receiver of its handler.
+#[derive(Clone)]
+pub struct Sessions {
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+    // sessions are not kept at all if None
+    grace_period: Option<Duration>,
+}
+
+impl Sessions {
+    pub fn new(grace_period: Option<Duration>) -> Self {
+        Self {
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            grace_period,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.grace_period.is_some()
+    }
+
+    fn connect(&self, session_id: String) -> Takeover {
+        let (sender, takeover) = tokio::sync::oneshot::channel();
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(session_id, Session::Connected(sender));
+        takeover
+    }
+
+    fn disconnect(&self, session_id: &str) {
+        self.sessions.lock().unwrap().remove(session_id);
     }
+
+    fn suspend(&self, session_id: String, channels: SessionChannels) {
+        let Some(grace_period) = self.grace_period else {
+            return;
+        };
+        debug!("Keeping session {session_id} for {grace_period:?}.");
+        self.sessions.lock().unwrap().insert(
+            session_id,
+            Session::Suspended(Instant::now() + grace_period, channels),
+        );
+        let sessions = self.sessions.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(grace_period).await;
+            let now = Instant::now();
+            sessions
+                .lock()
+                .unwrap()
+                .retain(|session_id, session| match session {
+                    Session::Suspended(deadline, _) if *deadline <= now => {
+                        info!("Session {session_id} expired, the client did not come back.");
+                        false
+                    }
+                    _ => true,
+                });
+        });
+    }
+
+    async fn resume(&self, session_id: &str) -> Option<SessionChannels> {
+        let session = self.sessions.lock().unwrap().remove(session_id)?;
+        match session {
+            Session::Suspended(_, channels) => Some(channels),
+            Session::Connected(takeover) => {
+                let (sender, receiver) = tokio::sync::oneshot::channel();
+                takeover.send(sender).ok()?;
+                // the old connection may be stuck, in that case the client has to start over
+                match tokio::time::timeout(TAKEOVER_TIMEOUT, receiver).await {
+                    Ok(Ok(channels)) => Some(channels),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    /// End all suspended sessions, used when shutting down.
+    pub fn clear(&self) {
+        self.sessions.lock().unwrap().clear();
+    }
+}
+
+/// Handed from the reader to the writer of a websocket that dropped while a session was running.
+struct Abandoned {
+    session_id: String,
+    sender_inbound: InboundSender,
+    // the client is already back on another connection and takes the session with it
+    handover: Option<tokio::sync::oneshot::Sender<SessionChannels>>,
+}
+
+fn new_session_id() -> String {
+    format!("{:032x}", rand::random::<u128>())
 }
 
+/// Why the reader of a websocket stopped, logged once the client is disconnected.
+enum Disconnect {
+    ClientClosed,
+    Timeout(Duration),
+    Error(WebSocketError),
+    ShuttingDown,
+    ContinuedOnNewConnection,
+    WriterGone,
+}
+
+impl std::fmt::Display for Disconnect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ClientClosed => write!(f, "the client closed the connection"),
+            Self::Timeout(timeout) => write!(f, "the client did not respond for {timeout:?}"),
+            Self::Error(err) => write!(f, "websocket error: {err}"),
+            Self::ShuttingDown => write!(f, "Weylus is shutting down"),
+            Self::ContinuedOnNewConnection => write!(f, "the client continues on a new connection"),
+            Self::WriterGone => write!(f, "sending to the client failed"),
+        }
+    }
+}
+
+/// Splits the websocket into a sender and a receiver for [`WeylusClientHandler`]. If timeout is
+/// given the client is pinged regularly and the connection is closed once nothing has been heard
+/// from the client for that long, which ends the receiver just like a close by the client. Changes
+/// of host_input are passed on to the receiver as [`MessageInbound::HostInputEnabled`].
+///
+/// If webrtc is set the client may send input over a WebRTC connection it signals via the
+/// websocket, see [`RtcInput`]. That connection ends with the websocket.
+///
+/// If the connection drops instead of being closed by the client, the session is suspended in
+/// sessions and the receiver stays open. A client sending [`MessageInbound::Resume`] on a new
+/// connection takes over the suspended handler, the handler created for the new connection is
+/// ended in that case.
+///
+/// The returned task writes to the websocket, it ends once there is nothing left to send or the
+/// close frame has been sent. Both keep the entry of the connection in the connection list up
+/// to date as long as they run.
 pub fn weylus_websocket_channel(
     websocket: WebSocket<TokioIo<Upgraded>>,
+    connection: Arc<Connection>,
     semaphore_shutdown: Arc<tokio::sync::Semaphore>,
-) -> (WsWeylusSender, WsWeylusReceiver) {
+    timeout: Option<Duration>,
+    mut host_input: tokio::sync::watch::Receiver<bool>,
+    sessions: Sessions,
+    webrtc: bool,
+) -> (
+    WsWeylusSender,
+    WsWeylusReceiver,
+    tokio::task::JoinHandle<()>,
+) {
     let (rx, mut tx) = websocket.split(|ws| tokio::io::split(ws));
 
     let mut rx = FragmentCollectorRead::new(rx);
 
-    let (sender_inbound, receiver_inbound) = channel::<MessageInbound>(32);
-    let (sender_outbound, mut receiver_outbound) = channel::<WsMessage>(32);
+    let coalesced_pointer_events = Arc::new(AtomicU32::new(0));
+    let (sender_inbound, receiver_inbound) =
+        queue::channel(INBOUND_QUEUE_LEN, coalesced_pointer_events.clone());
+    let (sender_outbound, receiver_outbound) = channel::<WsMessage>(32);
+    // frames that must not wait behind queued video, like answers to pings
+    let (sender_priority, mut receiver_priority) = channel::<WsMessage>(32);
+    let pending_video_bytes = Arc::new(AtomicUsize::new(0));
+    // hands the outbound channel of a resumed session to the writer
+    let (sender_attach, mut receiver_attach) =
+        channel::<(tokio::sync::mpsc::Receiver<WsMessage>, Arc<AtomicUsize>)>(1);
+    // tells the writer to give up on a client that is gone, together with what it needs to
+    // suspend the session
+    let (sender_abandon, receiver_abandon) = tokio::sync::oneshot::channel::<Option<Abandoned>>();
 
     {
-        let sender_outbound = sender_outbound.clone();
-        tokio::spawn(async move {
+        let sessions = sessions.clone();
+        let connection = connection.clone();
+        let mut sender_inbound = sender_inbound;
+        let reader = async move {
+            let connected = unix_time_ms();
+            // a few pings per timeout so a single lost one does not end the connection
+            let mut ping_interval = timeout.map(|timeout| {
+                let mut interval =
+                    tokio::time::interval((timeout / 3).max(Duration::from_millis(1)));
+                interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                interval
+            });
+            let mut last_seen = Instant::now();
+            let mut abandon = false;
+            let mut session_id: Option<String> = None;
+            let mut takeover: Option<Takeover> = None;
+            let mut handover = None;
+            // input received via WebRTC, forwarded just like input received via the websocket
+            let (sender_rtc_inbound, mut receiver_rtc_inbound) = channel::<RtcPointerEvent>(32);
+            // answers and ICE candidates, which must not be dropped if the client is slow
+            let (sender_rtc_signal, mut receiver_rtc_signal) =
+                tokio::sync::mpsc::unbounded_channel::<MessageOutbound>();
+            let mut rtc: Option<RtcInput> = None;
+            // the pointer events forwarded so far, movements via WebRTC may have been overtaken
+            let mut rtc_order = RtcOrder::default();
+            // messages sent via the HTTP API, see Connections::send
+            let mut receiver_control = connection.control();
+
+            let capabilities = MessageOutbound::ServerCapabilities { webrtc };
+            if let Err(err) = sender_priority
+                .send(WsMessage::MessageOutbound(capabilities))
+                .await
+            {
+                warn!("Failed to send message to client: {err}.");
+            }
+
             let mut send_fn = |frame| async {
-                if let Err(err) = sender_outbound.send(WsMessage::Frame(frame)).await {
+                if let Err(err) = sender_priority.send(WsMessage::Frame(frame)).await {
                     warn!("Failed to send websocket frame while receiving fragmented frame: {err}.")
                 };
                 Ok(())
             };
 
-            loop {
+            let reason = loop {
                 let fut = rx.read_frame::<_, WebSocketError>(&mut send_fn);
+                tokio::pin!(fut);
 
-                let frame = tokio::select! {
-                    _ = semaphore_shutdown.acquire() => break,
-                    frame = fut => frame.unwrap(),
+                // keep reading the same frame while pinging, dropping the future could lose the
+                // part of the frame that has been read already
+                let frame = loop {
+                    tokio::select! {
+                        _ = semaphore_shutdown.acquire() => {
+                            // the writer stops after the close frame, ahead of any queued video
+                            for msg in [
+                                WsMessage::MessageOutbound(MessageOutbound::ServerShutdown),
+                                WsMessage::Frame(Frame::close(
+                                    CLOSE_GOING_AWAY,
+                                    b"Weylus is shutting down.",
+                                )),
+                            ] {
+                                if sender_priority.send(msg).await.is_err() {
+                                    break;
+                                }
+                            }
+                            break Err(Disconnect::ShuttingDown);
+                        },
+                        Some(timeout) = async {
+                            match &mut ping_interval {
+                                Some(interval) => {
+                                    interval.tick().await;
+                                    timeout
+                                }
+                                None => std::future::pending().await,
+                            }
+                        } => {
+                            if last_seen.elapsed() >= timeout {
+                                abandon = true;
+                                break Err(Disconnect::Timeout(timeout));
+                            }
+                            let ping = Frame::new(true, OpCode::Ping, None, Vec::new().into());
+                            if sender_priority.send(WsMessage::Frame(ping)).await.is_err() {
+                                break Err(Disconnect::WriterGone);
+                            }
+                        },
+                        reply = async {
+                            match &mut takeover {
+                                Some(takeover) => takeover.await,
+                                None => std::future::pending().await,
+                            }
+                        } => match reply {
+                            Ok(reply) => {
+                                handover = Some(reply);
+                                abandon = true;
+                                break Err(Disconnect::ContinuedOnNewConnection);
+                            }
+                            Err(_) => takeover = None,
+                        },
+                        Some(RtcPointerEvent { event, unordered }) = receiver_rtc_inbound.recv() => {
+                            last_seen = Instant::now();
+                            connection.seen();
+                            if unordered && rtc_order.is_stale(&event) {
+                                continue;
+                            }
+                            rtc_order.forwarded(&event);
+                            let msg = MessageInbound::PointerEvent(event);
+                            if let Err(err) = sender_inbound.send(msg).await {
+                                warn!("Failed to forward inbound message to WeylusClientHandler: {err}.");
+                            }
+                        },
+                        Some(msg) = receiver_rtc_signal.recv() => {
+                            if sender_priority.send(WsMessage::MessageOutbound(msg)).await.is_err() {
+                                break Err(Disconnect::WriterGone);
+                            }
+                        },
+                        Some(msg) = receiver_control.recv() => {
+                            if let MessageInbound::ApiConfig(config) = &msg {
+                                connection.configured(config);
+                            }
+                            if let Err(err) = sender_inbound.send(msg).await {
+                                warn!("Failed to forward inbound message to WeylusClientHandler: {err}.");
+                            }
+                        },
+                        Ok(()) = host_input.changed() => {
+                            let enabled = *host_input.borrow_and_update();
+                            let msg = MessageInbound::HostInputEnabled(enabled);
+                            if let Err(err) = sender_inbound.send(msg).await {
+                                warn!("Failed to forward inbound message to WeylusClientHandler: {err}.");
+                            }
+                        },
+                        frame = &mut fut => match frame {
+                            Ok(frame) => break Ok(frame),
+                            Err(err) => {
+                                abandon = true;
+                                break Err(Disconnect::Error(err));
+                            }
+                        },
+                    }
+                };
+                let frame = match frame {
+                    Ok(frame) => frame,
+                    Err(reason) => break reason,
                 };
+                // pongs are what we are waiting for but any frame shows the client is alive
+                last_seen = Instant::now();
+                connection.seen();
                 match frame.opcode {
-                    OpCode::Close => break,
+                    OpCode::Close => break Disconnect::ClientClosed,
                     OpCode::Text => match serde_json::from_slice(&frame.payload) {
+                        Ok(MessageInbound::Ping { id, client_ts }) => {
+                            let pong = MessageOutbound::Pong {
+                                id,
+                                client_ts,
+                                server_ts: unix_time_ms(),
+                            };
+                            if let Err(err) =
+                                sender_priority.send(WsMessage::MessageOutbound(pong)).await
+                            {
+                                warn!("Failed to answer ping: {err}.");
+                            }
+                        }
+                        Ok(MessageInbound::RtcOffer { sdp }) => {
+                            // the client starts over if it sends another offer
+                            if let Some(rtc) = rtc.take() {
+                                rtc.close().await;
+                            }
+                            let answer = if webrtc {
+                                let sender_signal = sender_rtc_signal.clone();
+                                RtcInput::answer(sdp, sender_signal, sender_rtc_inbound.clone())
+                                    .await
+                                    .map_err(|err| format!("Failed to set up WebRTC: {err}"))
+                            } else {
+                                Err("WebRTC is disabled on the server.".to_string())
+                            };
+                            match answer {
+                                Ok(answer) => rtc = Some(answer),
+                                Err(msg) => {
+                                    info!("{msg}");
+                                    let err = MessageOutbound::Error(ClientError::new(
+                                        ErrorKind::RtcUnavailable,
+                                        msg,
+                                    ));
+                                    if let Err(err) =
+                                        sender_priority.send(WsMessage::MessageOutbound(err)).await
+                                    {
+                                        warn!("Failed to send message to client: {err}.");
+                                    }
+                                }
+                            }
+                        }
+                        Ok(MessageInbound::RtcCandidate(candidate)) => match &rtc {
+                            Some(rtc) => rtc.add_candidate(candidate).await,
+                            None => debug!("Ignoring ICE candidate without WebRTC connection."),
+                        },
+                        Ok(MessageInbound::Resume { session_id: id }) if sessions.enabled() => {
+                            if session_id.as_ref() == Some(&id) {
+                                debug!("Session {id} is already resumed.");
+                                continue;
+                            }
+                            let Some(channels) = sessions.resume(&id).await else {
+                                info!("Session {id} can not be resumed.");
+                                let err = MessageOutbound::Error(ClientError::new(
+                                    ErrorKind::SessionExpired,
+                                    "Session expired!",
+                                ));
+                                if let Err(err) =
+                                    sender_priority.send(WsMessage::MessageOutbound(err)).await
+                                {
+                                    warn!("Failed to send message to client: {err}.");
+                                }
+                                continue;
+                            };
+                            info!("Client resumed session {id}.");
+                            // the handler of this connection is not needed anymore, it ends once
+                            // its receiver is closed
+                            sender_inbound = channels.sender_inbound;
+                            if sender_attach
+                                .send((channels.receiver_outbound, channels.pending_video_bytes))
+                                .await
+                                .is_err()
+                            {
+                                break Disconnect::WriterGone;
+                            }
+                            if let Some(own) = session_id.replace(id.clone()) {
+                                sessions.disconnect(&own);
+                            }
+                            takeover = Some(sessions.connect(id));
+                            if let Err(err) =
+                                sender_inbound.send(MessageInbound::SessionResumed).await
+                            {
+                                warn!("Failed to forward inbound message to WeylusClientHandler: {err}.");
+                            }
+                        }
                         Ok(msg) => {
+                            if let MessageInbound::Config(config) = &msg {
+                                connection.configured(config);
+                            }
+                            if let MessageInbound::PointerEvent(event) = &msg {
+                                rtc_order.forwarded(event);
+                            }
+                            if matches!(msg, MessageInbound::Config(_))
+                                && session_id.is_none()
+                                && sessions.enabled()
+                            {
+                                let id = new_session_id();
+                                let msg = MessageOutbound::Session {
+                                    session_id: id.clone(),
+                                };
+                                if let Err(err) =
+                                    sender_priority.send(WsMessage::MessageOutbound(msg)).await
+                                {
+                                    warn!("Failed to send message to client: {err}.");
+                                }
+                                takeover = Some(sessions.connect(id.clone()));
+                                session_id = Some(id);
+                            }
                             if let Err(err) = sender_inbound.send(msg).await {
                                 warn!("Failed to forward inbound message to WeylusClientHandler: {err}.");
                             }
@@ -501,55 +1664,739 @@ pub fn weylus_websocket_channel(
                     },
                     _ => {}
                 }
+            };
+            info!(
+                "Client disconnected after {}: {reason}.",
+                elapsed_since(connected)
+            );
+            if let Some(rtc) = rtc {
+                rtc.close().await;
+            }
+            match (abandon, session_id) {
+                (true, Some(session_id)) => {
+                    if let Err(err) = sender_inbound.send(MessageInbound::SessionSuspended).await {
+                        warn!("Failed to forward inbound message to WeylusClientHandler: {err}.");
+                    }
+                    let _ = sender_abandon.send(Some(Abandoned {
+                        session_id,
+                        sender_inbound,
+                        handover,
+                    }));
+                }
+                (true, None) => {
+                    let _ = sender_abandon.send(None);
+                }
+                // the client is gone for good, its handler ends with the receiver
+                (false, Some(session_id)) => sessions.disconnect(&session_id),
+                (false, None) => (),
             }
-        });
+        };
+        tokio::spawn(reader.in_current_span());
     }
 
-    tokio::spawn(async move {
-        loop {
-            let msg = if let Some(msg) = receiver_outbound.recv().await {
-                msg
-            } else {
-                break;
-            };
+    let mut receiver_outbound = receiver_outbound;
+    let mut unsent_video_bytes = pending_video_bytes.clone();
+    let writer = async move {
+        let write = async {
+            loop {
+                let msg = tokio::select! {
+                    biased;
+                    // disabled once the reader is gone
+                    Some(msg) = receiver_priority.recv() => msg,
+                    Some((receiver, pending)) = receiver_attach.recv() => {
+                        // whatever the handler of this connection sent is dropped with it
+                        receiver_outbound = receiver;
+                        unsent_video_bytes = pending;
+                        continue;
+                    },
+                    msg = receiver_outbound.recv() => match msg {
+                        Some(msg) => msg,
+                        None => break,
+                    },
+                };
 
-            match msg {
-                WsMessage::Frame(frame) => {
-                    if let Err(err) = tx.write_frame(frame).await {
-                        if let WebSocketError::ConnectionClosed = err {
+                match msg {
+                    WsMessage::Frame(frame) => {
+                        let close = matches!(frame.opcode, OpCode::Close);
+                        if let Err(err) = tx.write_frame(frame).await {
+                            if let WebSocketError::ConnectionClosed = err {
+                                break;
+                            }
+                            warn!("Failed to send frame: {err}");
+                        }
+                        // nothing may follow a close frame
+                        if close {
                             break;
                         }
-                        warn!("Failed to send frame: {err}");
                     }
-                }
-                WsMessage::Video(data) => {
-                    if let Err(err) = tx.write_frame(Frame::binary(data.into())).await {
-                        if let WebSocketError::ConnectionClosed = err {
-                            break;
+                    WsMessage::Video(mut data) => {
+                        let len = data.len() - VIDEO_HEADER_LEN;
+                        data[..VIDEO_HEADER_LEN].copy_from_slice(&unix_time_ms().to_le_bytes());
+                        let res = tx.write_frame(Frame::binary(data.into())).await;
+                        unsent_video_bytes.fetch_sub(len, Ordering::Relaxed);
+                        connection.frame_sent();
+                        if let Err(err) = res {
+                            if let WebSocketError::ConnectionClosed = err {
+                                break;
+                            }
+                            warn!("Failed to send video frame: {err}");
                         }
-                        warn!("Failed to send video frame: {err}");
                     }
-                }
-                WsMessage::MessageOutbound(msg) => {
-                    let json_string = serde_json::to_string(&msg).unwrap();
-                    let data = json_string.as_bytes();
-                    if let Err(err) = tx.write_frame(Frame::text(data.into())).await {
-                        if let WebSocketError::ConnectionClosed = err {
-                            break;
+                    WsMessage::Audio(captured_at_us, mut data) => {
+                        // the client takes the binary message right after this one as audio
+                        let msg = MessageOutbound::AudioPacket { captured_at_us };
+                        let json_string = serde_json::to_string(&msg).unwrap();
+                        data[..VIDEO_HEADER_LEN].copy_from_slice(&unix_time_ms().to_le_bytes());
+                        let mut res = tx
+                            .write_frame(Frame::text(json_string.as_bytes().into()))
+                            .await;
+                        if res.is_ok() {
+                            res = tx.write_frame(Frame::binary(data.into())).await;
+                        }
+                        if let Err(err) = res {
+                            if let WebSocketError::ConnectionClosed = err {
+                                break;
+                            }
+                            warn!("Failed to send audio: {err}");
+                        }
+                    }
+                    WsMessage::MessageOutbound(msg) => {
+                        let json_string = serde_json::to_string(&msg).unwrap();
+                        let data = json_string.as_bytes();
+                        if let Err(err) = tx.write_frame(Frame::text(data.into())).await {
+                            if let WebSocketError::ConnectionClosed = err {
+                                break;
+                            }
+                            warn!("Failed to send outbound message: {err}");
                         }
-                        warn!("Failed to send outbound message: {err}");
+                    }
+                    WsMessage::Stats(stats) => connection.stats_measured(&stats),
+                }
+            }
+        };
+        // writes to a client that is gone may block for minutes, do not wait for them
+        let abandoned = tokio::select! {
+            _ = write => None,
+            Ok(abandoned) = receiver_abandon => abandoned,
+        };
+        if let Some(abandoned) = abandoned {
+            let channels = SessionChannels {
+                sender_inbound: abandoned.sender_inbound,
+                receiver_outbound,
+                pending_video_bytes: unsent_video_bytes,
+            };
+            match abandoned.handover {
+                Some(handover) => {
+                    if let Err(channels) = handover.send(channels) {
+                        sessions.suspend(abandoned.session_id, channels);
                     }
                 }
+                None => sessions.suspend(abandoned.session_id, channels),
             }
         }
-    });
+    };
+    let writer = tokio::spawn(writer.in_current_span());
 
     (
         WsWeylusSender {
             sender: sender_outbound,
+            pending_video_bytes,
+            coalesced_pointer_events,
         },
         WsWeylusReceiver {
             recv: receiver_inbound,
         },
+        writer,
     )
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::input::device::tests::FakeInputDevice;
+    use crate::protocol::tests::pointer_event;
+    use crate::{capturable, capture, input, protocol, video};
+
+    // records the messages sent to the client
+    #[derive(Clone, Default)]
+    struct FakeSender(std::sync::Arc<std::sync::Mutex<Vec<protocol::MessageOutbound>>>);
+
+    impl protocol::WeylusSender for FakeSender {
+        type Error = std::convert::Infallible;
+
+        fn send_message(&mut self, message: protocol::MessageOutbound) -> Result<(), Self::Error> {
+            self.0.lock().unwrap().push(message);
+            Ok(())
+        }
+
+        fn send_video(&mut self, _: &[u8]) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn send_audio(&mut self, _: u64, _: &[u8]) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    struct FakeReceiver(std::vec::IntoIter<protocol::MessageInbound>);
+
+    impl Iterator for FakeReceiver {
+        type Item = Result<protocol::MessageInbound, std::convert::Infallible>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.0.next().map(Ok)
+        }
+    }
+
+    impl protocol::WeylusReceiver for FakeReceiver {
+        type Error = std::convert::Infallible;
+    }
+
+    fn keyboard_event(
+        event_type: protocol::KeyboardEventType,
+        code: &str,
+    ) -> protocol::KeyboardEvent {
+        protocol::KeyboardEvent {
+            event_type,
+            code: code.into(),
+            key: code.into(),
+            location: protocol::KeyboardLocation::STANDARD,
+            alt: false,
+            ctrl: false,
+            shift: false,
+            meta: false,
+        }
+    }
+
+    // without encoders or capture backends, so nothing is captured
+    pub(crate) fn client_config() -> WeylusClientConfig {
+        WeylusClientConfig {
+            encoder_options: video::EncoderOptions {
+                try_vaapi: false,
+                try_nvenc: false,
+                nvenc_gpu: None,
+                try_videotoolbox: false,
+                try_mediafoundation: false,
+                codec: None,
+                bitrate_kbps: None,
+                crf: None,
+                preset: video::EncoderPreset::default(),
+                gop_size: 12,
+                intra_refresh: false,
+            },
+            #[cfg(target_os = "linux")]
+            capture_backends: capturable::CaptureBackends {
+                x11: false,
+                pipewire: false,
+                x11_window_capture: capturable::X11WindowCapture::Auto,
+            },
+            #[cfg(target_os = "macos")]
+            logical_resolution: false,
+            full_frame_interval: std::time::Duration::MAX,
+            recording: video::RecordingConfig {
+                dir: std::env::temp_dir(),
+                format: video::RecordingFormat::Mp4,
+            },
+            default_capturable: None,
+            #[cfg(target_os = "linux")]
+            hide_utility_windows: false,
+            #[cfg(target_os = "linux")]
+            pen_pressure_max: input::uinput_device::DEFAULT_PRESSURE_MAX,
+            input_switch: input::switch::InputSwitch::new(),
+            last_capturable: LastCapturable::new(None),
+            connections: Connections::new(),
+            #[cfg(target_os = "linux")]
+            uinput_pool: input::uinput_pool::UInputPool::new(),
+        }
+    }
+
+    // runs a client handler on a FakeInputDevice until the messages run out, returns its log
+    fn run_client_handler(messages: Vec<protocol::MessageInbound>) -> Vec<String> {
+        let log = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let config = client_config();
+        let registry = capture::CaptureRegistry::new(
+            config.encoder_options,
+            config.full_frame_interval,
+            config.recording.clone(),
+        );
+        WeylusClientHandler::new(
+            FakeSender::default(),
+            FakeReceiver(messages.into_iter()),
+            |_| (),
+            |_| (),
+            config,
+            &registry,
+        )
+        .with_input_device(Box::new(FakeInputDevice::new(log.clone())))
+        .run();
+        std::mem::take(&mut *log.lock().unwrap())
+    }
+
+    // the client stops sending events while buttons and keys are down, like when Weylus shuts
+    // down or the browser is closed
+    #[test]
+    fn client_handler_releases_input_when_done() {
+        use protocol::KeyboardEventType::*;
+        use protocol::MessageInbound::*;
+
+        let mut mouse_down = pointer_event(
+            protocol::PointerType::Mouse,
+            protocol::PointerEventType::DOWN,
+            1,
+            0,
+        );
+        mouse_down.buttons = protocol::Button::PRIMARY.union(protocol::Button::SECONDARY);
+        let messages = vec![
+            PointerEvent(mouse_down),
+            KeyboardEvent(keyboard_event(DOWN, "KeyA")),
+            KeyboardEvent(keyboard_event(DOWN, "ShiftLeft")),
+            KeyboardEvent(keyboard_event(UP, "ShiftLeft")),
+        ];
+
+        assert_eq!(
+            run_client_handler(messages),
+            [
+                "release primary",
+                "release secondary",
+                "release KeyA",
+                "destroyed"
+            ]
+        );
+    }
+
+    // the tablet drops off the network mid-stroke, the websocket suspends the session once it
+    // stops answering pings and the stylus and fingers are lifted right away, not only once the
+    // session expires
+    #[test]
+    fn client_handler_lifts_pens_and_touches_when_suspended() {
+        use protocol::KeyboardEventType::DOWN as KEY_DOWN;
+        use protocol::MessageInbound::*;
+        use protocol::PointerEventType::*;
+        use protocol::PointerType::*;
+
+        let messages = vec![
+            PointerEvent(pointer_event(Pen, DOWN, 1, 0)),
+            PointerEvent(pointer_event(Pen, MOVE, 1, 5)),
+            PointerEvent(pointer_event(Touch, DOWN, 2, 6)),
+            PointerEvent(pointer_event(Touch, DOWN, 3, 7)),
+            PointerEvent(pointer_event(Touch, UP, 2, 8)),
+            KeyboardEvent(keyboard_event(KEY_DOWN, "KeyA")),
+            SessionSuspended,
+            SessionResumed,
+            PointerEvent(pointer_event(Touch, DOWN, 4, 20)),
+        ];
+        assert_eq!(
+            run_client_handler(messages),
+            [
+                "lift Pen 1",
+                "lift Touch 3",
+                "release KeyA",
+                "lift Touch 4",
+                "destroyed"
+            ]
+        );
+    }
+
+    // a client entering view-only mode mid-stroke drops the events that would lift the pen
+    #[test]
+    fn client_handler_releases_input_on_view_only() {
+        use protocol::MessageInbound::*;
+        use protocol::PointerEventType::*;
+        use protocol::PointerType::*;
+
+        let messages = vec![
+            PointerEvent(pointer_event(Pen, DOWN, 1, 0)),
+            PointerEvent(pointer_event(Pen, MOVE, 1, 5)),
+            SetInputEnabled(false),
+            PointerEvent(pointer_event(Pen, UP, 1, 10)),
+            SetInputEnabled(true),
+            PointerEvent(pointer_event(Touch, DOWN, 2, 20)),
+        ];
+        assert_eq!(
+            run_client_handler(messages),
+            ["lift Pen 1", "lift Touch 2", "destroyed"]
+        );
+    }
+
+    // a client that letterboxes the video sends positions on the black bars, these end up on the
+    // edges of the content and the edges themselves stay exact
+    #[test]
+    fn client_handler_clamps_pointer_positions_to_the_content() {
+        use protocol::PointerEventType::MOVE;
+
+        let positions = [
+            (0.0, 1.0),
+            (1.0, 0.0),
+            (-0.25, 0.5),
+            (0.5, 1.5),
+            (f64::NAN, 0.5),
+            (2.0, -1.0),
+        ];
+        let messages: Vec<_> = positions
+            .iter()
+            .enumerate()
+            .map(|(i, &(x, y))| {
+                let mut event = pointer_event(protocol::PointerType::Mouse, MOVE, 1, i as u64);
+                event.x = x;
+                event.y = y;
+                protocol::MessageInbound::PointerEvent(event)
+            })
+            .collect();
+        let log = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let pointer_events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let config = client_config();
+        let registry = capture::CaptureRegistry::new(
+            config.encoder_options,
+            config.full_frame_interval,
+            config.recording.clone(),
+        );
+        WeylusClientHandler::new(
+            FakeSender::default(),
+            FakeReceiver(messages.into_iter()),
+            |_| (),
+            |_| (),
+            config,
+            &registry,
+        )
+        .with_input_device(Box::new(
+            FakeInputDevice::new(log).with_pointer_events(pointer_events.clone()),
+        ))
+        .run();
+
+        let positions: Vec<_> = pointer_events
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| (e.x, e.y))
+            .collect();
+        // the event without a position is dropped
+        assert_eq!(
+            positions,
+            [(0.0, 1.0), (1.0, 0.0), (0.0, 0.5), (0.5, 1.0), (1.0, 0.0)]
+        );
+    }
+
+    fn test_capturable(width: usize) -> Box<dyn Capturable> {
+        Box::new(capturable::testsrc::TestCapturable { width, height: 100 })
+    }
+
+    // a script only sends input and asks for frames anyway
+    #[test]
+    fn client_handler_without_video_captures_nothing() {
+        use protocol::MessageInbound::*;
+
+        let config: protocol::ClientConfiguration = serde_json::from_value(serde_json::json!({
+            "uinput_support": false,
+            "capturable_id": "test:100x100",
+            "capture_cursor": false,
+            "max_width": 1920,
+            "max_height": 1080,
+            "frame_rate": 30.0,
+            "video": false,
+        }))
+        .unwrap();
+        let messages = vec![ApiConfig(config), RequestKeyFrame, StartRecording];
+        let log = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sender = FakeSender::default();
+        let config = client_config();
+        let registry = capture::CaptureRegistry::new(
+            config.encoder_options,
+            config.full_frame_interval,
+            config.recording.clone(),
+        );
+        let handler = WeylusClientHandler::new(
+            sender.clone(),
+            FakeReceiver(messages.into_iter()),
+            |_| (),
+            |_| (),
+            config,
+            &registry,
+        )
+        .with_input_device(Box::new(FakeInputDevice::new(log)));
+        let capturables = handler.capturables.clone();
+        capturables
+            .lock()
+            .unwrap()
+            .replace(None, vec![test_capturable(100)]);
+        handler.run();
+
+        let sent = std::mem::take(&mut *sender.0.lock().unwrap());
+        assert!(sent
+            .iter()
+            .any(|message| matches!(message, MessageOutbound::ConfigOk)));
+        let errors: Vec<_> = sent
+            .iter()
+            .filter_map(|message| match message {
+                MessageOutbound::Error(err) => Some(&err.kind),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(errors.len(), 2);
+        assert!(errors
+            .iter()
+            .all(|kind| matches!(kind, protocol::ErrorKind::VideoDisabled)));
+        assert_eq!(registry.capture_count(), 0);
+        // the capturable is still where input goes
+        assert_eq!(
+            capturables.lock().unwrap().input_only.as_deref(),
+            Some("test:100x100")
+        );
+    }
+
+    // a script sends the id GET /api/capturables lists for a window that has been closed and
+    // opened again in the meantime, the session knows it by another id
+    #[test]
+    fn api_ids_resolve_to_the_ids_of_the_session() {
+        let mut capturables = Capturables::default();
+        capturables.replace(None, vec![test_capturable(100), test_capturable(200)]);
+        capturables.replace(None, vec![test_capturable(200)]);
+        capturables.replace(None, vec![test_capturable(100), test_capturable(200)]);
+
+        assert_eq!(capturables.resolve_api_id("test:100x100"), "test:100x100#3");
+        assert!(capturables.find("test:100x100").is_none());
+        assert!(capturables
+            .find(&capturables.resolve_api_id("test:100x100"))
+            .is_some());
+        assert_eq!(capturables.resolve_api_id("test:200x100"), "test:200x100#2");
+        // ids of the session itself stay as they are
+        assert_eq!(
+            capturables.resolve_api_id("test:100x100#3"),
+            "test:100x100#3"
+        );
+    }
+
+    #[derive(Clone)]
+    struct FakeWindow {
+        id: String,
+        name: String,
+        x: f64,
+        monitor: Option<capturable::MonitorInfo>,
+    }
+
+    impl Capturable for FakeWindow {
+        fn name(&self) -> String {
+            self.name.clone()
+        }
+
+        fn id(&self) -> String {
+            self.id.clone()
+        }
+
+        fn geometry(&self) -> Result<capturable::Geometry, Box<dyn std::error::Error>> {
+            Ok(capturable::Geometry::Relative(
+                self.x, 0.0, 0.5, 1.0, 1.0, 1.0,
+            ))
+        }
+
+        fn monitor(&self) -> Option<capturable::MonitorInfo> {
+            self.monitor.clone()
+        }
+
+        fn before_input(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+
+        fn recorder(
+            &self,
+            _: bool,
+        ) -> Result<Box<dyn capturable::Recorder>, Box<dyn std::error::Error>> {
+            Err("FakeWindow can not be recorded".into())
+        }
+    }
+
+    fn windows(windows: &[(&str, &str)]) -> Vec<Box<dyn Capturable>> {
+        windows
+            .iter()
+            .map(|(id, name)| {
+                Box::new(FakeWindow {
+                    id: id.to_string(),
+                    name: name.to_string(),
+                    x: 0.0,
+                    monitor: None,
+                }) as Box<dyn Capturable>
+            })
+            .collect()
+    }
+
+    fn updates(updates: Vec<MessageOutbound>) -> Vec<String> {
+        updates
+            .into_iter()
+            .map(|update| match update {
+                MessageOutbound::CapturableAdded(info) => {
+                    format!("added {} {}", info.id, info.name)
+                }
+                MessageOutbound::CapturableRemoved { id } => format!("removed {id}"),
+                MessageOutbound::CapturableUpdated(info) => {
+                    format!("updated {} {}", info.id, info.name)
+                }
+                update => panic!("Not an update of the list: {update:?}"),
+            })
+            .collect()
+    }
+
+    // the X server recycles the id of a closed window for a new one, which must not be mistaken
+    // for the closed one by the client
+    #[test]
+    fn capturables_that_show_up_again_get_new_ids() {
+        let mut capturables = Capturables::default();
+        let mut replace = |list: &[(&str, &str)]| updates(capturables.replace(None, windows(list)));
+
+        assert_eq!(
+            replace(&[("a", "Editor"), ("b", "Terminal")]),
+            ["added a#1 Editor", "added b#2 Terminal"]
+        );
+        assert_eq!(
+            replace(&[("a", "Editor - file"), ("b", "Terminal")]),
+            ["updated a#1 Editor - file"]
+        );
+        assert_eq!(replace(&[("b", "Terminal")]), ["removed a#1"]);
+        assert_eq!(
+            replace(&[("a", "Browser"), ("b", "Terminal")]),
+            ["added a#3 Browser"]
+        );
+        assert_eq!(
+            replace(&[("a", "Browser - page"), ("b", "Terminal")]),
+            ["updated a#3 Browser - page"]
+        );
+        assert_eq!(replace(&[]), ["removed a#3", "removed b#2"]);
+        assert_eq!(
+            replace(&[("b", "Terminal"), ("a", "Editor")]),
+            ["added b#4 Terminal", "added a#5 Editor"]
+        );
+        assert!(replace(&[("b", "Terminal"), ("a", "Editor")]).is_empty());
+    }
+
+    // however many windows have been opened and closed in between
+    #[test]
+    fn capturables_never_get_an_id_again() {
+        let mut capturables = Capturables::default();
+        let mut seen = HashMap::new();
+        for round in 0..3 {
+            let ids: Vec<String> = (0..300).map(|i| format!("w{i}")).collect();
+            let list: Vec<(&str, &str)> = ids.iter().map(|id| (id.as_str(), "Window")).collect();
+            for update in capturables.replace(None, windows(&list)) {
+                let MessageOutbound::CapturableAdded(info) = update else {
+                    panic!("Not an added capturable: {update:?}");
+                };
+                assert_eq!(
+                    seen.insert(info.id.clone(), round),
+                    None,
+                    "{} reused",
+                    info.id
+                );
+            }
+            capturables.replace(None, Vec::new());
+            assert!(capturables.sent.is_empty());
+        }
+        assert_eq!(seen.len(), 900);
+    }
+
+    // moving a window changes its geometry, the client gets all of it again
+    #[test]
+    fn capturables_that_moved_are_sent_again() {
+        let mut capturables = Capturables::default();
+        let window = |x| {
+            vec![Box::new(FakeWindow {
+                id: "a".into(),
+                name: "Editor".into(),
+                x,
+                monitor: None,
+            }) as Box<dyn Capturable>]
+        };
+        capturables.replace(None, window(0.0));
+        assert!(capturables.replace(None, window(0.0)).is_empty());
+        let updates = capturables.replace(None, window(0.5));
+        let [MessageOutbound::CapturableUpdated(info)] = &updates[..] else {
+            panic!("Not an update of the window: {updates:?}");
+        };
+        assert_eq!(info.id, "a#1");
+        assert_eq!(
+            info.geometry,
+            Some(capturable::Geometry::Relative(0.5, 0.0, 0.5, 1.0, 1.0, 1.0))
+        );
+    }
+
+    // the client shows the mode of monitors, which must not go stale once it changed
+    #[test]
+    fn monitors_that_changed_their_mode_are_sent_again() {
+        let mut capturables = Capturables::default();
+        let monitor = |width, height| {
+            vec![Box::new(FakeWindow {
+                id: "x11:monitor:HDMI-1".into(),
+                name: "HDMI-1".into(),
+                x: 0.0,
+                monitor: Some(capturable::MonitorInfo {
+                    output: "HDMI-1".into(),
+                    x: 0,
+                    y: 0,
+                    width,
+                    height,
+                    refresh_rate: Some(60.0),
+                }),
+            }) as Box<dyn Capturable>]
+        };
+        capturables.replace(None, monitor(1920, 1080));
+        assert!(capturables.replace(None, monitor(1920, 1080)).is_empty());
+        let updates = capturables.replace(None, monitor(1280, 720));
+        let [MessageOutbound::CapturableUpdated(info)] = &updates[..] else {
+            panic!("Not an update of the monitor: {updates:?}");
+        };
+        assert_eq!(info.id, "x11:monitor:HDMI-1#1");
+        let monitor = info.monitor.as_ref().unwrap();
+        assert_eq!((monitor.width, monitor.height), (1280, 720));
+    }
+
+    fn defaults(capturables: &Capturables) -> Vec<String> {
+        capturables
+            .infos()
+            .into_iter()
+            .filter(|info| info.default)
+            .map(|info| info.name)
+            .collect()
+    }
+
+    #[test]
+    fn the_capturable_selected_last_is_the_default() {
+        let last = LastCapturable::new(None);
+        let mut capturables = Capturables::default();
+        capturables.replace(None, windows(&[("a", "Editor"), ("b", "Browser")]));
+        last.set(capturables.find("b#2").unwrap().as_ref());
+        assert_eq!(last.get().as_deref(), Some("Browser"));
+
+        capturables.last = last.get();
+        assert_eq!(defaults(&capturables), ["Browser"]);
+        // gone since
+        capturables.replace(None, windows(&[("a", "Editor")]));
+        assert!(defaults(&capturables).is_empty());
+        // a default given in the configuration comes first
+        capturables.replace(None, windows(&[("a", "Editor"), ("b", "Browser")]));
+        capturables.default = Some("edit".into());
+        assert_eq!(defaults(&capturables), ["Editor"]);
+    }
+
+    #[test]
+    fn monitors_are_remembered_by_their_output() {
+        let monitor = FakeWindow {
+            id: "x11:monitor:HDMI-1".into(),
+            name: "HDMI-1 1920x1080".into(),
+            x: 0.0,
+            monitor: Some(capturable::MonitorInfo {
+                output: "HDMI-1".into(),
+                x: 0,
+                y: 0,
+                width: 1920,
+                height: 1080,
+                refresh_rate: None,
+            }),
+        };
+        assert_eq!(remembered_name(&monitor), "HDMI-1");
+        let last = LastCapturable::new(None);
+        let mut changes = last.subscribe();
+        last.set(&monitor);
+        assert!(changes.has_changed().unwrap());
+        changes.mark_unchanged();
+        // selecting it again does not save it again
+        last.set(&monitor);
+        assert!(!changes.has_changed().unwrap());
+    }
+}
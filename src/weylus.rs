@@ -1,15 +1,29 @@
-use std::net::SocketAddr;
+use std::net::IpAddr;
 use std::sync::Arc;
-use tracing::error;
+use std::time::Duration;
+use tracing::{error, info, warn};
 
 use crate::config::Config;
-use crate::video::EncoderOptions;
+use crate::connections::Connections;
+use crate::hotkey::{Hotkey, KeyCombination};
+use crate::input::switch::InputSwitch;
+#[cfg(target_os = "linux")]
+use crate::input::uinput_pool::UInputPool;
+use crate::mdns::Advertisement;
+use crate::network::BindAddress;
+use crate::video::{EncoderOptions, RecordingConfig};
 use crate::web::{Web2UiMessage, WebServerConfig, WebStartUpMessage};
-use crate::websocket::WeylusClientConfig;
+use crate::websocket::{LastCapturable, WeylusClientConfig};
 
 pub struct Weylus {
     notify_shutdown: Arc<tokio::sync::Notify>,
     web_thread: Option<std::thread::JoinHandle<()>>,
+    mdns: Option<Advertisement>,
+    input_switch: InputSwitch,
+    input_toggle_hotkey: Option<Hotkey>,
+    connections: Connections,
+    #[cfg(target_os = "linux")]
+    uinput_pool: UInputPool,
 }
 
 impl Weylus {
@@ -17,6 +31,12 @@ impl Weylus {
         Self {
             notify_shutdown: Arc::new(tokio::sync::Notify::new()),
             web_thread: None,
+            mdns: None,
+            input_switch: InputSwitch::new(),
+            input_toggle_hotkey: None,
+            connections: Connections::new(),
+            #[cfg(target_os = "linux")]
+            uinput_pool: UInputPool::new(),
         }
     }
 
@@ -35,6 +55,10 @@ impl Weylus {
             try_nvenc: config.try_nvenc,
             #[cfg(not(any(target_os = "linux", target_os = "windows")))]
             try_nvenc: false,
+            #[cfg(any(target_os = "linux", target_os = "windows"))]
+            nvenc_gpu: config.nvenc_gpu,
+            #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+            nvenc_gpu: None,
 
             #[cfg(target_os = "macos")]
             try_videotoolbox: config.try_videotoolbox,
@@ -45,27 +69,94 @@ impl Weylus {
             try_mediafoundation: config.try_mediafoundation,
             #[cfg(not(target_os = "windows"))]
             try_mediafoundation: false,
+
+            // set per client
+            codec: None,
+            bitrate_kbps: None,
+            crf: None,
+
+            preset: config.encoder_preset,
+            gop_size: config.gop_size,
+            // chosen by the client
+            intra_refresh: false,
+        };
+
+        let tls = if config.tls {
+            match crate::tls::server_config(config.tls_cert.as_deref(), config.tls_key.as_deref()) {
+                Ok(tls) => {
+                    info!("Serving Weylus via TLS.");
+                    Some(tls)
+                }
+                Err(err) => {
+                    error!("Failed to setup TLS: {err}");
+                    return false;
+                }
+            }
+        } else {
+            None
         };
 
         let (sender_ui, mut receiver_ui) = tokio::sync::mpsc::channel(100);
         let (sender_startup, receiver_startup) = tokio::sync::oneshot::channel();
 
+        if config.webrtc && !cfg!(feature = "webrtc") {
+            warn!("Weylus has been built without WebRTC support, input is sent via the websocket.");
+        }
+
         let web_thread = crate::web::run(
             sender_ui,
             sender_startup,
             self.notify_shutdown.clone(),
             WebServerConfig {
-                bind_addr: SocketAddr::new(config.bind_address, config.web_port),
+                bind_addresses: config.bind_address.clone(),
+                port: config.web_port,
+                allowed_networks: config.allowed_networks.clone(),
                 access_code: config.access_code.clone(),
                 custom_index_html: config.custom_index_html.clone(),
                 custom_access_html: config.custom_access_html.clone(),
                 custom_style_css: config.custom_style_css.clone(),
                 custom_lib_js: config.custom_lib_js.clone(),
+                web_root: config.web_root.clone(),
+                tls,
+                // zero disables the timeout
+                client_timeout: Duration::try_from_secs_f64(config.client_timeout)
+                    .ok()
+                    .filter(|timeout| !timeout.is_zero()),
+                session_grace_period: Duration::try_from_secs_f64(config.session_grace_period)
+                    .ok()
+                    .filter(|grace_period| !grace_period.is_zero()),
+                webrtc: config.webrtc && cfg!(feature = "webrtc"),
             },
             WeylusClientConfig {
                 encoder_options,
                 #[cfg(target_os = "linux")]
-                wayland_support: config.wayland_support,
+                capture_backends: config.capture_backends(),
+                #[cfg(target_os = "macos")]
+                logical_resolution: config.logical_resolution,
+                // infinity means never sending a frame unless something changed
+                full_frame_interval: Duration::try_from_secs_f64(
+                    config.full_frame_interval.max(0.0),
+                )
+                .unwrap_or(Duration::MAX),
+                recording: RecordingConfig {
+                    dir: config
+                        .recording_dir
+                        .clone()
+                        .or_else(dirs::video_dir)
+                        .or_else(dirs::home_dir)
+                        .unwrap_or_default(),
+                    format: config.recording_format,
+                },
+                default_capturable: config.default_capturable.clone(),
+                #[cfg(target_os = "linux")]
+                hide_utility_windows: config.hide_utility_windows,
+                #[cfg(target_os = "linux")]
+                pen_pressure_max: config.pen_pressure_max,
+                input_switch: self.input_switch.clone(),
+                last_capturable: LastCapturable::new(config.capturable_name.clone()),
+                connections: self.connections.clone(),
+                #[cfg(target_os = "linux")]
+                uinput_pool: self.uinput_pool.clone(),
             },
         );
 
@@ -86,6 +177,51 @@ impl Weylus {
             }
         }
         self.web_thread = Some(web_thread);
+        if !config.no_mdns {
+            // announce all addresses if any unspecified one is bound
+            let ips: Vec<IpAddr> = if config.bind_address.0.iter().any(
+                |bind_address| matches!(bind_address, BindAddress::Ip(ip) if ip.is_unspecified()),
+            ) {
+                Vec::new()
+            } else {
+                config
+                    .bind_address
+                    .0
+                    .iter()
+                    .filter_map(|bind_address| bind_address.socket_addrs(config.web_port).ok())
+                    .flatten()
+                    .map(|addr| addr.ip())
+                    .collect()
+            };
+            match Advertisement::new(
+                &ips,
+                config.web_port,
+                config.access_code.is_some(),
+                config.tls,
+            ) {
+                Ok(mdns) => {
+                    info!("Announcing Weylus as {} via mDNS.", mdns.hostname());
+                    self.mdns = Some(mdns);
+                }
+                Err(err) => warn!("Failed to announce Weylus via mDNS: {err}"),
+            }
+        }
+        if let Some(hotkey) = &config.input_toggle_hotkey {
+            // the server is fine without the hotkey, input can still be turned off in the GUI
+            match hotkey.parse::<KeyCombination>() {
+                Ok(keys) => {
+                    let input_switch = self.input_switch.clone();
+                    match Hotkey::register(&keys, move || input_switch.toggle()) {
+                        Ok(hotkey) => {
+                            info!("Press {keys} to turn input from clients off and on again.");
+                            self.input_toggle_hotkey = Some(hotkey);
+                        }
+                        Err(err) => warn!("{err}"),
+                    }
+                }
+                Err(err) => warn!("Invalid hotkey: {err}"),
+            }
+        }
         std::thread::spawn(move || {
             while let Some(msg) = receiver_ui.blocking_recv() {
                 on_web_message(msg);
@@ -94,9 +230,29 @@ impl Weylus {
         true
     }
 
+    /// Turns input from all clients off and on, also while the server is stopped.
+    pub fn input_switch(&self) -> &InputSwitch {
+        &self.input_switch
+    }
+
+    /// Clients connected to the server.
+    pub fn connections(&self) -> &Connections {
+        &self.connections
+    }
+
+    /// Hostname the server is announced as via mDNS while it is running.
+    pub fn mdns_hostname(&self) -> Option<&str> {
+        self.mdns.as_ref().map(|mdns| mdns.hostname())
+    }
+
     pub fn stop(&mut self) {
+        // withdraw the announcement first, clients should not find a server that is going away
+        self.mdns = None;
+        self.input_toggle_hotkey = None;
         self.notify_shutdown.notify_one();
         self.wait();
+        #[cfg(target_os = "linux")]
+        self.uinput_pool.clear();
     }
 
     fn wait(&mut self) {
@@ -0,0 +1,437 @@
+//! Conversion of BGR0 frames to the YUV 4:2:0 formats encoders take as input, using BT.601
+//! limited range like ffmpeg does by default. Chroma is the average of each 2x2 block of pixels.
+//!
+//! All intermediate values fit into 16 bits, unsigned for luma and signed for chroma, this way
+//! the SIMD versions can process 8 pixels per register and produce exactly the same result as
+//! the scalar version.
+
+// either the chroma planes or the chroma of the 2 rows currently converted
+enum Chroma<'a> {
+    Planar(&'a mut [u8], &'a mut [u8]),
+    Interleaved(&'a mut [u8]),
+}
+
+impl Chroma<'_> {
+    fn row(&mut self, row: usize, chroma_width: usize) -> Chroma<'_> {
+        let range = |samples: usize| row * samples..(row + 1) * samples;
+        match self {
+            Chroma::Planar(u, v) => {
+                Chroma::Planar(&mut u[range(chroma_width)], &mut v[range(chroma_width)])
+            }
+            Chroma::Interleaved(uv) => Chroma::Interleaved(&mut uv[range(chroma_width * 2)]),
+        }
+    }
+}
+
+/// Size of the chroma planes for an image of width x height.
+pub fn chroma_size(width: usize, height: usize) -> (usize, usize) {
+    (width.div_ceil(2), height.div_ceil(2))
+}
+
+/// Convert BGR0 with rows stride bytes apart to I420: y is width x height, u and v are
+/// chroma_size(width, height), all rows are packed without padding.
+pub fn bgr0_to_i420(
+    size: (usize, usize),
+    src: &[u8],
+    stride: usize,
+    y: &mut [u8],
+    u: &mut [u8],
+    v: &mut [u8],
+) {
+    convert(size, src, stride, y, Chroma::Planar(u, v));
+}
+
+/// Convert BGR0 with rows stride bytes apart to NV12: y is width x height, uv holds interleaved
+/// u and v samples of size chroma_size(width, height), all rows are packed without padding.
+pub fn bgr0_to_nv12(size: (usize, usize), src: &[u8], stride: usize, y: &mut [u8], uv: &mut [u8]) {
+    convert(size, src, stride, y, Chroma::Interleaved(uv));
+}
+
+fn convert(
+    (width, height): (usize, usize),
+    src: &[u8],
+    stride: usize,
+    y: &mut [u8],
+    mut chroma: Chroma,
+) {
+    if width == 0 || height == 0 {
+        return;
+    }
+    let (chroma_width, chroma_height) = chroma_size(width, height);
+    assert!(src.len() >= (height - 1) * stride + width * 4);
+    assert!(y.len() >= width * height);
+    let kernel = Kernel::detect();
+    // the last row is paired with itself if height is odd
+    let mut y_scratch = Vec::new();
+    for row in 0..chroma_height {
+        let r0 = row * 2;
+        let r1 = (r0 + 1).min(height - 1);
+        let src0 = &src[r0 * stride..r0 * stride + width * 4];
+        let src1 = &src[r1 * stride..r1 * stride + width * 4];
+        let (y0, y1) = if r1 != r0 {
+            y[r0 * width..(r1 + 1) * width].split_at_mut(width)
+        } else {
+            y_scratch.resize(width, 0);
+            (
+                &mut y[r0 * width..(r0 + 1) * width],
+                y_scratch.as_mut_slice(),
+            )
+        };
+        let mut chroma_row = chroma.row(row, chroma_width);
+        let done = kernel.row_pair(src0, src1, y0, y1, &mut chroma_row);
+        scalar_row_pair(src0, src1, y0, y1, &mut chroma_row, done);
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Kernel {
+    Scalar,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Sse2,
+    #[cfg(target_arch = "aarch64")]
+    Neon,
+}
+
+impl Kernel {
+    fn detect() -> Self {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if is_x86_feature_detected!("sse2") {
+            return Kernel::Sse2;
+        }
+        #[cfg(target_arch = "aarch64")]
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            return Kernel::Neon;
+        }
+        Kernel::Scalar
+    }
+
+    /// Convert as many pixels at the start of both rows as the kernel can handle at once,
+    /// returns how many have been converted.
+    fn row_pair(
+        self,
+        src0: &[u8],
+        src1: &[u8],
+        y0: &mut [u8],
+        y1: &mut [u8],
+        chroma: &mut Chroma,
+    ) -> usize {
+        match self {
+            Kernel::Scalar => 0,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Kernel::Sse2 => unsafe { sse2::row_pair(src0, src1, y0, y1, chroma) },
+            #[cfg(target_arch = "aarch64")]
+            Kernel::Neon => unsafe { neon::row_pair(src0, src1, y0, y1, chroma) },
+        }
+    }
+}
+
+#[inline]
+fn luma(b: i32, g: i32, r: i32) -> u8 {
+    (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8
+}
+
+#[inline]
+fn uv(b: i32, g: i32, r: i32) -> (u8, u8) {
+    let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+    let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+    (u as u8, v as u8)
+}
+
+/// Convert both rows starting at pixel start, this is the reference the SIMD versions have to
+/// match.
+fn scalar_row_pair(
+    src0: &[u8],
+    src1: &[u8],
+    y0: &mut [u8],
+    y1: &mut [u8],
+    chroma: &mut Chroma,
+    start: usize,
+) {
+    let width = y0.len();
+    let pixel = |src: &[u8], x: usize| {
+        let p = &src[x * 4..x * 4 + 3];
+        (p[0] as i32, p[1] as i32, p[2] as i32)
+    };
+    for x in (start..width).step_by(2) {
+        // the last column is paired with itself if width is odd
+        let x1 = (x + 1).min(width - 1);
+        let mut sum = (0, 0, 0);
+        for (src, y_row) in [(src0, &mut *y0), (src1, &mut *y1)] {
+            for x in [x, x1] {
+                let (b, g, r) = pixel(src, x);
+                y_row[x] = luma(b, g, r);
+                sum = (sum.0 + b, sum.1 + g, sum.2 + r);
+            }
+        }
+        let (u, v) = uv((sum.0 + 2) >> 2, (sum.1 + 2) >> 2, (sum.2 + 2) >> 2);
+        match chroma {
+            Chroma::Planar(u_row, v_row) => {
+                u_row[x / 2] = u;
+                v_row[x / 2] = v;
+            }
+            Chroma::Interleaved(uv_row) => {
+                uv_row[x] = u;
+                uv_row[x + 1] = v;
+            }
+        }
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod sse2 {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    use super::Chroma;
+
+    // splits 8 BGR0 pixels into 16 bit b, g and r
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn split(a: __m128i, b: __m128i) -> (__m128i, __m128i, __m128i) {
+        let mask = _mm_set1_epi32(0xff);
+        let channel = |a: __m128i, b: __m128i| {
+            _mm_packs_epi32(_mm_and_si128(a, mask), _mm_and_si128(b, mask))
+        };
+        (
+            channel(a, b),
+            channel(_mm_srli_epi32(a, 8), _mm_srli_epi32(b, 8)),
+            channel(_mm_srli_epi32(a, 16), _mm_srli_epi32(b, 16)),
+        )
+    }
+
+    // 16 bit products wrap, but the sums fit into u16 for luma and i16 for chroma
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn weigh(
+        (b, g, r): (__m128i, __m128i, __m128i),
+        (cb, cg, cr): (i16, i16, i16),
+    ) -> __m128i {
+        _mm_add_epi16(
+            _mm_add_epi16(
+                _mm_mullo_epi16(r, _mm_set1_epi16(cr)),
+                _mm_mullo_epi16(g, _mm_set1_epi16(cg)),
+            ),
+            _mm_add_epi16(_mm_mullo_epi16(b, _mm_set1_epi16(cb)), _mm_set1_epi16(128)),
+        )
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn luma(bgr: (__m128i, __m128i, __m128i)) -> __m128i {
+        let sum = weigh(bgr, (25, 129, 66));
+        _mm_add_epi16(_mm_srli_epi16(sum, 8), _mm_set1_epi16(16))
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn chroma(bgr: (__m128i, __m128i, __m128i), coefficients: (i16, i16, i16)) -> __m128i {
+        let sum = weigh(bgr, coefficients);
+        _mm_packus_epi16(
+            _mm_add_epi16(_mm_srai_epi16(sum, 8), _mm_set1_epi16(128)),
+            _mm_setzero_si128(),
+        )
+    }
+
+    // sums horizontally adjacent pixels of 16 pixels in two registers
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn pair_sums(lo: __m128i, hi: __m128i) -> __m128i {
+        let ones = _mm_set1_epi16(1);
+        _mm_packs_epi32(_mm_madd_epi16(lo, ones), _mm_madd_epi16(hi, ones))
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn row_pair(
+        src0: &[u8],
+        src1: &[u8],
+        y0: &mut [u8],
+        y1: &mut [u8],
+        chroma_row: &mut Chroma,
+    ) -> usize {
+        let width = y0.len() / 16 * 16;
+        for x in (0..width).step_by(16) {
+            let mut channels = [[_mm_setzero_si128(); 3]; 2];
+            for (row, (src, y)) in [(src0, &mut *y0), (src1, &mut *y1)].into_iter().enumerate() {
+                let p = src.as_ptr().add(x * 4) as *const __m128i;
+                let lo = split(_mm_loadu_si128(p), _mm_loadu_si128(p.add(1)));
+                let hi = split(_mm_loadu_si128(p.add(2)), _mm_loadu_si128(p.add(3)));
+                let luma = _mm_packus_epi16(luma(lo), luma(hi));
+                _mm_storeu_si128(y.as_mut_ptr().add(x) as *mut __m128i, luma);
+                channels[row] = [
+                    pair_sums(lo.0, hi.0),
+                    pair_sums(lo.1, hi.1),
+                    pair_sums(lo.2, hi.2),
+                ];
+            }
+            let average = |i: usize| {
+                let sum = _mm_add_epi16(channels[0][i], channels[1][i]);
+                _mm_srai_epi16(_mm_add_epi16(sum, _mm_set1_epi16(2)), 2)
+            };
+            let bgr = (average(0), average(1), average(2));
+            let u = chroma(bgr, (112, -74, -38));
+            let v = chroma(bgr, (-18, -94, 112));
+            match chroma_row {
+                Chroma::Planar(u_row, v_row) => {
+                    _mm_storel_epi64(u_row.as_mut_ptr().add(x / 2) as *mut __m128i, u);
+                    _mm_storel_epi64(v_row.as_mut_ptr().add(x / 2) as *mut __m128i, v);
+                }
+                Chroma::Interleaved(uv_row) => {
+                    _mm_storeu_si128(
+                        uv_row.as_mut_ptr().add(x) as *mut __m128i,
+                        _mm_unpacklo_epi8(u, v),
+                    );
+                }
+            }
+        }
+        width
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use std::arch::aarch64::*;
+
+    use super::Chroma;
+
+    // 16 bit products wrap, but the sums fit into u16 for luma and i16 for chroma
+    #[inline]
+    #[target_feature(enable = "neon")]
+    unsafe fn weigh(
+        (b, g, r): (uint16x8_t, uint16x8_t, uint16x8_t),
+        (cb, cg, cr): (i16, i16, i16),
+    ) -> uint16x8_t {
+        let sum = vmulq_n_u16(r, cr as u16);
+        let sum = vmlaq_n_u16(sum, g, cg as u16);
+        let sum = vmlaq_n_u16(sum, b, cb as u16);
+        vaddq_u16(sum, vdupq_n_u16(128))
+    }
+
+    #[inline]
+    #[target_feature(enable = "neon")]
+    unsafe fn luma(bgr: (uint16x8_t, uint16x8_t, uint16x8_t)) -> uint8x8_t {
+        let sum = weigh(bgr, (25, 129, 66));
+        vqmovn_u16(vaddq_u16(vshrq_n_u16::<8>(sum), vdupq_n_u16(16)))
+    }
+
+    #[inline]
+    #[target_feature(enable = "neon")]
+    unsafe fn chroma(
+        bgr: (uint16x8_t, uint16x8_t, uint16x8_t),
+        coefficients: (i16, i16, i16),
+    ) -> uint8x8_t {
+        let sum = vreinterpretq_s16_u16(weigh(bgr, coefficients));
+        vqmovun_s16(vaddq_s16(vshrq_n_s16::<8>(sum), vdupq_n_s16(128)))
+    }
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn row_pair(
+        src0: &[u8],
+        src1: &[u8],
+        y0: &mut [u8],
+        y1: &mut [u8],
+        chroma_row: &mut Chroma,
+    ) -> usize {
+        let width = y0.len() / 16 * 16;
+        for x in (0..width).step_by(16) {
+            let p0 = vld4q_u8(src0.as_ptr().add(x * 4));
+            let p1 = vld4q_u8(src1.as_ptr().add(x * 4));
+            for (p, y) in [(p0, &mut *y0), (p1, &mut *y1)] {
+                let lo = (
+                    vmovl_u8(vget_low_u8(p.0)),
+                    vmovl_u8(vget_low_u8(p.1)),
+                    vmovl_u8(vget_low_u8(p.2)),
+                );
+                let hi = (
+                    vmovl_u8(vget_high_u8(p.0)),
+                    vmovl_u8(vget_high_u8(p.1)),
+                    vmovl_u8(vget_high_u8(p.2)),
+                );
+                let luma = vcombine_u8(luma(lo), luma(hi));
+                vst1q_u8(y.as_mut_ptr().add(x), luma);
+            }
+            // sums of 2x2 blocks, rounded to the average
+            let average = |a: uint8x16_t, b: uint8x16_t| {
+                vrshrq_n_u16::<2>(vaddq_u16(vpaddlq_u8(a), vpaddlq_u8(b)))
+            };
+            let bgr = (
+                average(p0.0, p1.0),
+                average(p0.1, p1.1),
+                average(p0.2, p1.2),
+            );
+            let u = chroma(bgr, (112, -74, -38));
+            let v = chroma(bgr, (-18, -94, 112));
+            match chroma_row {
+                Chroma::Planar(u_row, v_row) => {
+                    vst1_u8(u_row.as_mut_ptr().add(x / 2), u);
+                    vst1_u8(v_row.as_mut_ptr().add(x / 2), v);
+                }
+                Chroma::Interleaved(uv_row) => {
+                    vst2_u8(uv_row.as_mut_ptr().add(x), uint8x8x2_t(u, v));
+                }
+            }
+        }
+        width
+    }
+}
+
+/// Convert a single YUV sample back to RGB.
+pub fn to_rgb(y: u8, u: u8, v: u8) -> [u8; 3] {
+    let c = 298 * (y as i32 - 16);
+    let d = u as i32 - 128;
+    let e = v as i32 - 128;
+    let clamp = |x: i32| ((x + 128) >> 8).clamp(0, 255) as u8;
+    [
+        clamp(c + 409 * e),
+        clamp(c - 100 * d - 208 * e),
+        clamp(c + 516 * d),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // strips of 2 pixels are too narrow for SIMD, so they are converted by the scalar code
+    #[test]
+    fn yuv_simd_matches_scalar() {
+        const WIDTH: usize = 70;
+        const HEIGHT: usize = 9;
+        let stride = WIDTH * 4 + 8;
+        let bgr0: Vec<u8> = (0..stride * HEIGHT)
+            .map(|i| (i * 7919 % 251) as u8)
+            .collect();
+        let convert = |x: usize, width: usize| {
+            let (chroma_width, chroma_height) = chroma_size(width, HEIGHT);
+            let mut y = vec![0; width * HEIGHT];
+            let mut u = vec![0; chroma_width * chroma_height];
+            let mut v = vec![0; chroma_width * chroma_height];
+            bgr0_to_i420(
+                (width, HEIGHT),
+                &bgr0[x * 4..],
+                stride,
+                &mut y,
+                &mut u,
+                &mut v,
+            );
+            let mut uv = vec![0; chroma_width * chroma_height * 2];
+            bgr0_to_nv12((width, HEIGHT), &bgr0[x * 4..], stride, &mut y, &mut uv);
+            let interleaved: Vec<u8> = u.iter().zip(&v).flat_map(|(&u, &v)| [u, v]).collect();
+            assert_eq!(uv, interleaved);
+            (y, u, v)
+        };
+        let (y, u, v) = convert(0, WIDTH);
+        let (chroma_width, _) = chroma_size(WIDTH, HEIGHT);
+        for x in (0..WIDTH).step_by(2) {
+            let (strip_y, strip_u, strip_v) = convert(x, 2);
+            for row in 0..HEIGHT {
+                assert_eq!(&y[row * WIDTH + x..][..2], &strip_y[row * 2..][..2]);
+            }
+            for row in 0..HEIGHT.div_ceil(2) {
+                assert_eq!(u[row * chroma_width + x / 2], strip_u[row]);
+                assert_eq!(v[row * chroma_width + x / 2], strip_v[row]);
+            }
+        }
+    }
+}
@@ -66,6 +66,22 @@ function frame_rate_scale_inv(x: number) {
 }
 
 
+// codecs the server may encode the MP4 stream with, in order of preference, and how to ask the
+// browser if it can play them back
+const VIDEO_CODECS: [string, string][] = [
+    ["H264Main", 'video/mp4; codecs="avc1.4D401F"'],
+    ["H264Baseline", 'video/mp4; codecs="avc1.42E01F"'],
+    ["Hevc", 'video/mp4; codecs="hvc1.1.6.L93.B0"'],
+    ["Vp9", 'video/mp4; codecs="vp09.00.10.08"'],
+];
+
+function supported_video_codecs(): string[] {
+    let MS = window.ManagedMediaSource ? window.ManagedMediaSource : window.MediaSource;
+    if (!MS)
+        return [];
+    return VIDEO_CODECS.filter(([_, mime_type]) => MS.isTypeSupported(mime_type)).map(([codec, _]) => codec);
+}
+
 function calc_max_video_resolution(scale: number) {
     return [
         Math.round(scale * window.innerWidth * window.devicePixelRatio),
@@ -82,21 +98,79 @@ function fresh_canvas() {
     return canvas;
 }
 
+interface CapturableInfo {
+    id: string;
+    name: string;
+    geometry: object | null;
+    physical_size: { width_mm: number, height_mm: number, dpi: number } | null;
+    monitor: {
+        output: string, x: number, y: number, width: number, height: number,
+        refresh_rate: number | null
+    } | null;
+    class: string | null;
+    pid: number | null;
+    default: boolean;
+}
+
+function capturable_option(capturable: CapturableInfo): HTMLOptionElement {
+    let option = document.createElement("option");
+    option.value = capturable.id;
+    option.innerText = capturable.name;
+    let details = [];
+    if (capturable.class !== null)
+        details.push("Class: " + capturable.class);
+    if (capturable.pid !== null)
+        details.push("PID: " + capturable.pid);
+    let size = capturable.physical_size;
+    if (size)
+        details.push(Math.round(size.width_mm) + " x " + Math.round(size.height_mm) + " mm, " + Math.round(size.dpi) + " dpi");
+    if (details.length > 0)
+        option.title = details.join(", ");
+    return option;
+}
+
 class Settings {
-    webSocket: WebSocket;
+    webSocket: Connection;
     checks: Map<string, HTMLInputElement>;
     capturable_select: HTMLSelectElement;
     frame_rate_input: HTMLInputElement;
     frame_rate_output: HTMLOutputElement;
     scale_video_input: HTMLInputElement;
     scale_video_output: HTMLOutputElement;
+    jpeg_quality_input: HTMLInputElement;
+    jpeg_quality_output: HTMLOutputElement;
     range_min_pressure: HTMLInputElement;
+    pressure_gamma_input: HTMLInputElement;
+    pressure_gamma_output: HTMLOutputElement;
+    pen_smoothing_input: HTMLInputElement;
+    pen_smoothing_output: HTMLOutputElement;
     check_aggressive_seek: HTMLInputElement;
     client_name_input: HTMLInputElement;
+    bitrate_input: HTMLInputElement;
+    crf_input: HTMLInputElement;
+    min_bitrate_input: HTMLInputElement;
+    palm_rejection_timeout_input: HTMLInputElement;
+    pacing_rate_input: HTMLInputElement;
+    pen_barrel_button_select: HTMLSelectElement;
+    pointer_ordering_select: HTMLSelectElement;
+    scale_mode_select: HTMLSelectElement;
+    x11_window_capture_select: HTMLSelectElement;
+    screen_size_input: HTMLInputElement;
+    pointer_sensitivity_input: HTMLInputElement;
+    pointer_sensitivity_output: HTMLOutputElement;
+    audio_source_select: HTMLSelectElement;
+    // remembered until the list of sources arrives
+    last_audio_source: string;
     visible: boolean;
     settings: HTMLElement;
-
-    constructor(webSocket: WebSocket) {
+    capturable_lost: boolean;
+    // the host records the video while this is set, the recording is restarted after every
+    // reconfiguration
+    recording: boolean;
+    record_button: HTMLButtonElement;
+    video_codecs: string[];
+
+    constructor(webSocket: Connection) {
         this.webSocket = webSocket;
         this.checks = new Map<string, HTMLInputElement>();
         this.capturable_select = document.getElementById("window") as HTMLSelectElement;
@@ -106,8 +180,28 @@ class Settings {
         this.frame_rate_output = this.frame_rate_input.nextElementSibling as HTMLOutputElement;
         this.scale_video_input = document.getElementById("scale_video") as HTMLInputElement;
         this.scale_video_output = this.scale_video_input.nextElementSibling as HTMLOutputElement;
+        this.jpeg_quality_input = document.getElementById("jpeg_quality") as HTMLInputElement;
+        this.jpeg_quality_output = this.jpeg_quality_input.nextElementSibling as HTMLOutputElement;
         this.range_min_pressure = document.getElementById("min_pressure") as HTMLInputElement;
+        this.pressure_gamma_input = document.getElementById("pressure_gamma") as HTMLInputElement;
+        this.pressure_gamma_output = this.pressure_gamma_input.nextElementSibling as HTMLOutputElement;
+        this.pen_smoothing_input = document.getElementById("pen_smoothing") as HTMLInputElement;
+        this.pen_smoothing_output = this.pen_smoothing_input.nextElementSibling as HTMLOutputElement;
         this.client_name_input = document.getElementById("client_name") as HTMLInputElement;
+        this.bitrate_input = document.getElementById("bitrate") as HTMLInputElement;
+        this.crf_input = document.getElementById("crf") as HTMLInputElement;
+        this.min_bitrate_input = document.getElementById("min_bitrate") as HTMLInputElement;
+        this.palm_rejection_timeout_input = document.getElementById("palm_rejection_timeout") as HTMLInputElement;
+        this.pacing_rate_input = document.getElementById("pacing_rate") as HTMLInputElement;
+        this.pen_barrel_button_select = document.getElementById("pen_barrel_button") as HTMLSelectElement;
+        this.pointer_ordering_select = document.getElementById("pointer_ordering") as HTMLSelectElement;
+        this.scale_mode_select = document.getElementById("scale_mode") as HTMLSelectElement;
+        this.x11_window_capture_select = document.getElementById("x11_window_capture") as HTMLSelectElement;
+        this.screen_size_input = document.getElementById("screen_size_mm") as HTMLInputElement;
+        this.pointer_sensitivity_input = document.getElementById("pointer_sensitivity") as HTMLInputElement;
+        this.pointer_sensitivity_output = this.pointer_sensitivity_input.nextElementSibling as HTMLOutputElement;
+        this.audio_source_select = document.getElementById("audio_source") as HTMLSelectElement;
+        this.last_audio_source = "";
         this.frame_rate_input.oninput = (e) => {
             this.frame_rate_output.value = Math.round(frame_rate_scale(this.frame_rate_input.valueAsNumber)).toString();
         }
@@ -115,7 +209,27 @@ class Settings {
             let [w, h] = calc_max_video_resolution(this.scale_video_input.valueAsNumber)
             this.scale_video_output.value = w + "x" + h
         }
+        this.jpeg_quality_input.oninput = (e) => {
+            this.jpeg_quality_output.value = this.jpeg_quality_input.value;
+        }
+        this.pressure_gamma_input.oninput = (e) => {
+            this.pressure_gamma_output.value = this.pressure_gamma_input.value;
+        }
+        this.pen_smoothing_input.oninput = (e) => {
+            this.pen_smoothing_output.value = this.pen_smoothing_input.value;
+        }
+        this.pointer_sensitivity_input.oninput = (e) => {
+            this.pointer_sensitivity_output.value = this.pointer_sensitivity_input.value;
+        }
         this.visible = true;
+        this.capturable_lost = false;
+        this.recording = false;
+        this.record_button = document.getElementById("record") as HTMLButtonElement;
+        this.record_button.onclick = () => {
+            this.recording = !this.recording;
+            this.record_button.textContent = this.recording ? "Stop Recording" : "Start Recording";
+            this.webSocket.send(this.recording ? '"StartRecording"' : '"StopRecording"');
+        };
 
         // Settings UI
         this.settings = document.getElementById("settings");
@@ -128,6 +242,17 @@ class Settings {
         );
 
         this.load_settings();
+        this.video_codecs = supported_video_codecs();
+        // without MediaSource or any of the codecs there is no way to play back the MP4 stream
+        if (this.video_codecs.length == 0) {
+            this.checks.get("mjpeg").checked = true;
+            this.checks.get("mjpeg").disabled = true;
+        }
+        // lossless frames are drawn onto a canvas the video element plays back the stream of
+        if (typeof HTMLCanvasElement.prototype.captureStream !== "function") {
+            this.checks.get("lossless").checked = false;
+            this.checks.get("lossless").disabled = true;
+        }
 
         // event handling
 
@@ -145,6 +270,8 @@ class Settings {
             this.settings.classList.add("vanish");
         }
 
+        this.checks.get("fallback_desktop").onchange = () => this.save_settings();
+
         this.checks.get("stretch").onchange = (e) => {
             stretch_video();
             this.save_settings();
@@ -160,11 +287,9 @@ class Settings {
             document.getElementById("video").classList.toggle("vanish", !enabled);
             document.getElementById("canvas").classList.toggle("vanish", enabled);
             this.save_settings();
-            if (enabled) {
-                this.webSocket.send('"ResumeVideo"');
-            } else {
-                this.webSocket.send('"PauseVideo"');
-            }
+            // the server stops capturing and encoding without video and starts over once it is
+            // enabled again
+            this.send_server_config();
         }
 
         let upd_pointer = () => {
@@ -187,8 +312,67 @@ class Settings {
         let upd_server_config = () => { this.save_settings(); this.send_server_config() };
         this.checks.get("uinput_support").onchange = upd_server_config;
         this.checks.get("capture_cursor").onchange = upd_server_config;
+        this.checks.get("report_cursor").onchange = () => {
+            if (!this.checks.get("report_cursor").checked)
+                remote_cursor.hide();
+            upd_server_config();
+        };
+        this.checks.get("clipboard_sync").onchange = upd_server_config;
+        this.checks.get("touch_gestures").onchange = upd_server_config;
+        this.checks.get("palm_rejection").onchange = upd_server_config;
+        this.palm_rejection_timeout_input.onchange = upd_server_config;
+        this.pen_barrel_button_select.onchange = upd_server_config;
+        this.pointer_ordering_select.onchange = upd_server_config;
+        this.checks.get("input_pacing").onchange = upd_server_config;
+        this.pacing_rate_input.onchange = upd_server_config;
+        this.checks.get("pace_pen").onchange = upd_server_config;
+        this.scale_mode_select.onchange = upd_server_config;
+        this.x11_window_capture_select.onchange = upd_server_config;
+        this.screen_size_input.onchange = upd_server_config;
+        this.checks.get("relative_pointer").onchange = upd_server_config;
+        this.checks.get("relative_stylus").onchange = upd_server_config;
+        this.checks.get("background_input").onchange = upd_server_config;
+        this.pointer_sensitivity_input.onchange = upd_server_config;
+        this.checks.get("mjpeg").onchange = upd_server_config;
+        this.checks.get("lossless").onchange = upd_server_config;
+        this.checks.get("audio").onchange = () => {
+            audio.set_enabled(this.checks.get("audio").checked);
+            upd_server_config();
+        };
+        this.audio_source_select.onchange = () => {
+            this.last_audio_source = this.audio_source_select.value;
+            upd_server_config();
+        };
+        // the server does not need to be reconfigured, the client sets up WebRTC on its own
+        let check_webrtc = this.checks.get("webrtc_input");
+        this.webSocket.set_rtc_enabled(check_webrtc.checked);
+        check_webrtc.onchange = () => {
+            this.save_settings();
+            this.webSocket.set_rtc_enabled(check_webrtc.checked);
+        };
+        // toggling this does not require reconfiguring the video
+        this.checks.get("view_only").onchange = () => {
+            this.save_settings();
+            this.webSocket.send(JSON.stringify({ "SetInputEnabled": !this.checks.get("view_only").checked }));
+        };
+        this.jpeg_quality_input.onchange = upd_server_config;
+        this.pressure_gamma_input.onchange = upd_server_config;
+        this.pen_smoothing_input.onchange = upd_server_config;
+        let check_server_stats = this.checks.get("server_stats");
+        let upd_server_stats = () => document.getElementById("server_stats_output").classList.toggle(
+            "hide", !check_server_stats.checked);
+        upd_server_stats();
+        check_server_stats.onchange = () => {
+            upd_server_stats();
+            upd_server_config();
+        };
         this.scale_video_input.onchange = upd_server_config;
         this.client_name_input.onchange = upd_server_config;
+        this.bitrate_input.onchange = upd_server_config;
+        this.crf_input.onchange = upd_server_config;
+        this.checks.get("intra_refresh").onchange = upd_server_config;
+        this.checks.get("adaptive_bitrate").onchange = upd_server_config;
+        this.min_bitrate_input.onchange = upd_server_config;
         this.frame_rate_input.onchange = upd_server_config;
 
         document.getElementById("refresh").onclick = () => this.webSocket.send('"GetCapturableList"');
@@ -197,18 +381,97 @@ class Settings {
 
     send_server_config() {
         let config = new Object(null);
-        config["capturable_id"] = Number(this.capturable_select.value);
+        config["capturable_id"] = this.capturable_select.value;
         for (const key of [
             "uinput_support",
-            "capture_cursor"])
+            "capture_cursor",
+            "report_cursor",
+            "touch_gestures",
+            "palm_rejection",
+            "relative_stylus",
+            "background_input",
+            "intra_refresh"])
             config[key] = this.checks.get(key).checked;
+        config["pointer_mode"] = this.checks.get("relative_pointer").checked ? "Relative" : "Absolute";
+        let sensitivity = this.pointer_sensitivity_input.valueAsNumber;
+        if (sensitivity != 1)
+            config["pointer_sensitivity"] = sensitivity;
         let [w, h] = calc_max_video_resolution(this.scale_video_input.valueAsNumber);
         config["max_width"] = w;
         config["max_height"] = h;
         config["frame_rate"] = frame_rate_scale(this.frame_rate_input.valueAsNumber);
+        config["send_stats"] = this.checks.get("server_stats").checked;
+        config["clipboard_sync"] = this.checks.get("clipboard_sync").checked;
+        config["audio"] = this.checks.get("audio").checked;
+        if (this.audio_source_select.value)
+            config["audio_source"] = this.audio_source_select.value;
+        config["input_enabled"] = !this.checks.get("view_only").checked;
+        // nothing is shown anyway, so the server does not capture and encode at all, energy saving
+        // disables the video as well
+        let video = this.video_enabled();
+        config["video"] = video;
+        if (this.checks.get("lossless").checked)
+            config["video_format"] = "Tiles";
+        else
+            config["video_format"] = this.checks.get("mjpeg").checked ? "Mjpeg" : "Mp4";
+        config["preferred_codecs"] = this.video_codecs;
+        config["jpeg_quality"] = this.jpeg_quality_input.valueAsNumber;
+        let gamma = this.pressure_gamma_input.valueAsNumber;
+        if (gamma != 1)
+            config["pressure_curve"] = { "Gamma": gamma };
+        // 1€ filter, stronger smoothing lowers the cutoff from 30 Hz to the 1.6 Hz the server
+        // allows at most
+        let smoothing = this.pen_smoothing_input.valueAsNumber;
+        if (smoothing > 0)
+            config["pen_smoothing"] = { "OneEuro": { "min_cutoff": 30 * Math.pow(0.053, smoothing), "beta": 10 } };
         if (this.client_name_input.value)
             config["client_name"] = this.client_name_input.value;
+        // empty inputs leave the choice to the server
+        if (this.bitrate_input.value)
+            config["bitrate_kbps"] = this.bitrate_input.valueAsNumber;
+        if (this.crf_input.value)
+            config["crf"] = this.crf_input.valueAsNumber;
+        // the max bitrate is the upper bound when adapting it to the connection
+        if (this.checks.get("adaptive_bitrate").checked) {
+            let max_kbps = this.bitrate_input.value ? this.bitrate_input.valueAsNumber : 20000;
+            let min_kbps = this.min_bitrate_input.value ? this.min_bitrate_input.valueAsNumber : 500;
+            config["adaptive_bitrate"] = { "min_kbps": Math.min(min_kbps, max_kbps), "max_kbps": max_kbps };
+        }
+        // the host decides how to capture X11 windows unless chosen here
+        if (this.x11_window_capture_select.value)
+            config["x11_window_capture"] = this.x11_window_capture_select.value;
+        // the server crops the part of the capturable that is as large as the view
+        let mm_per_px = this.mm_per_px();
+        if (this.scale_mode_select.value == "Physical" && mm_per_px)
+            config["scale_mode"] = {
+                "Physical": {
+                    "width_mm": document.body.clientWidth * mm_per_px,
+                    "height_mm": document.body.clientHeight * mm_per_px
+                }
+            };
+        else
+            physical_video_size = null;
+        if (this.palm_rejection_timeout_input.value)
+            config["palm_rejection_timeout_ms"] = this.palm_rejection_timeout_input.valueAsNumber;
+        let ordering = this.pointer_ordering_select.value;
+        if (ordering == "DropLate")
+            config["pointer_ordering"] = "DropLate";
+        else if (ordering == "Reorder")
+            config["pointer_ordering"] = { "Reorder": { "window_ms": 20 } };
+        if (this.checks.get("input_pacing").checked) {
+            let pacing = new Object(null);
+            pacing["pace_pen"] = this.checks.get("pace_pen").checked;
+            if (this.pacing_rate_input.value)
+                pacing["rate_hz"] = this.pacing_rate_input.valueAsNumber;
+            config["input_pacing"] = pacing;
+        }
+        // the barrel button is reported as the secondary button of the pen
+        let barrel_action = this.pen_barrel_button_select.value;
+        if (barrel_action != "None")
+            config["button_mapping"] = [{ "pointer_type": "pen", "button": 2, "action": barrel_action }];
         this.webSocket.send(JSON.stringify({ "Config": config }));
+        if (this.recording && video)
+            this.webSocket.send('"StartRecording"');
     }
 
     save_settings() {
@@ -217,8 +480,23 @@ class Settings {
             settings[key] = elem.checked;
         settings["frame_rate"] = frame_rate_scale(this.frame_rate_input.valueAsNumber).toString();
         settings["scale_video"] = this.scale_video_input.value;
+        settings["jpeg_quality"] = this.jpeg_quality_input.value;
         settings["min_pressure"] = this.range_min_pressure.value;
+        settings["pressure_gamma"] = this.pressure_gamma_input.value;
+        settings["pen_smoothing"] = this.pen_smoothing_input.value;
         settings["client_name"] = this.client_name_input.value;
+        settings["bitrate"] = this.bitrate_input.value;
+        settings["crf"] = this.crf_input.value;
+        settings["min_bitrate"] = this.min_bitrate_input.value;
+        settings["palm_rejection_timeout"] = this.palm_rejection_timeout_input.value;
+        settings["pacing_rate"] = this.pacing_rate_input.value;
+        settings["pen_barrel_button"] = this.pen_barrel_button_select.value;
+        settings["pointer_ordering"] = this.pointer_ordering_select.value;
+        settings["scale_mode"] = this.scale_mode_select.value;
+        settings["x11_window_capture"] = this.x11_window_capture_select.value;
+        settings["screen_size_mm"] = this.screen_size_input.value;
+        settings["pointer_sensitivity"] = this.pointer_sensitivity_input.value;
+        settings["audio_source"] = this.last_audio_source;
         localStorage.setItem("settings", JSON.stringify(settings));
     }
 
@@ -250,10 +528,30 @@ class Settings {
             let [w, h] = calc_max_video_resolution(this.scale_video_input.valueAsNumber);
             this.scale_video_output.value = w + "x" + h;
 
+            let jpeg_quality = settings["jpeg_quality"];
+            if (jpeg_quality)
+                this.jpeg_quality_input.value = jpeg_quality;
+            this.jpeg_quality_output.value = this.jpeg_quality_input.value;
+
             let min_pressure = settings["min_pressure"];
             if (min_pressure)
                 this.range_min_pressure.value = min_pressure;
 
+            let pressure_gamma = settings["pressure_gamma"];
+            if (pressure_gamma)
+                this.pressure_gamma_input.value = pressure_gamma;
+            this.pressure_gamma_output.value = this.pressure_gamma_input.value;
+
+            let pen_smoothing = settings["pen_smoothing"];
+            if (pen_smoothing)
+                this.pen_smoothing_input.value = pen_smoothing;
+            this.pen_smoothing_output.value = this.pen_smoothing_input.value;
+
+            let pointer_sensitivity = settings["pointer_sensitivity"];
+            if (pointer_sensitivity)
+                this.pointer_sensitivity_input.value = pointer_sensitivity;
+            this.pointer_sensitivity_output.value = this.pointer_sensitivity_input.value;
+
             if (this.checks.get("lefty").checked) {
                 this.settings.classList.add("lefty");
             }
@@ -274,6 +572,29 @@ class Settings {
             if (client_name)
                 this.client_name_input.value = client_name;
 
+            if (settings["bitrate"])
+                this.bitrate_input.value = settings["bitrate"];
+            if (settings["crf"])
+                this.crf_input.value = settings["crf"];
+            if (settings["min_bitrate"])
+                this.min_bitrate_input.value = settings["min_bitrate"];
+            if (settings["palm_rejection_timeout"])
+                this.palm_rejection_timeout_input.value = settings["palm_rejection_timeout"];
+            if (settings["pacing_rate"])
+                this.pacing_rate_input.value = settings["pacing_rate"];
+            if (settings["pen_barrel_button"])
+                this.pen_barrel_button_select.value = settings["pen_barrel_button"];
+            if (settings["pointer_ordering"])
+                this.pointer_ordering_select.value = settings["pointer_ordering"];
+            if (settings["scale_mode"])
+                this.scale_mode_select.value = settings["scale_mode"];
+            if (settings["x11_window_capture"])
+                this.x11_window_capture_select.value = settings["x11_window_capture"];
+            if (settings["screen_size_mm"])
+                this.screen_size_input.value = settings["screen_size_mm"];
+            if (typeof settings["audio_source"] === "string")
+                this.last_audio_source = settings["audio_source"];
+
         } catch {
             log(LogLevel.DEBUG, "Failed to load settings.")
             return;
@@ -284,6 +605,14 @@ class Settings {
         return this.checks.get("stretch").checked
     }
 
+    // Millimeters per CSS pixel, from the size of the long side of the screen entered by the user
+    // as browsers do not tell the physical size, null if it has not been entered.
+    mm_per_px(): number {
+        if (!(this.screen_size_input.valueAsNumber > 0))
+            return null;
+        return this.screen_size_input.valueAsNumber / Math.max(screen.width, screen.height);
+    }
+
     pointer_types() {
         let ptrs = [];
         if (this.checks.get("enable_mouse").checked)
@@ -300,27 +629,98 @@ class Settings {
         this.visible = !this.visible;
     }
 
-    onCapturableList(window_names: string[]) {
+    onCapturableList(capturables: CapturableInfo[]) {
         let current_selection = undefined;
         if (this.capturable_select.selectedOptions[0])
-            current_selection = this.capturable_select.selectedOptions[0].textContent;
-        let new_index: number;
+            current_selection = this.capturable_select.selectedOptions[0].value;
+        let first_list = this.capturable_select.options.length === 0;
+        let found = false;
+        let default_id = undefined;
         this.capturable_select.innerText = "";
-        window_names.forEach((name, i) => {
-            let option = document.createElement("option");
-            option.value = String(i);
-            option.innerText = name;
-            this.capturable_select.appendChild(option);
-            if (name === current_selection)
-                new_index = i;
+        capturables.forEach((capturable) => {
+            this.capturable_select.appendChild(capturable_option(capturable));
+            if (capturable.id === current_selection)
+                found = true;
+            if (capturable.default)
+                default_id = capturable.id;
         });
-        if (new_index !== undefined)
-            this.capturable_select.value = String(new_index);
-        else if (current_selection)
+        let capturable_lost = this.capturable_lost;
+        this.capturable_lost = false;
+        if (found)
+            this.capturable_select.value = current_selection;
+        else if (first_list && default_id !== undefined) {
+            // the server has been configured to select a capturable or remembers the one selected
+            // last
+            this.capturable_select.value = default_id;
+            this.send_server_config();
+        } else if (first_list || (capturable_lost && this.checks.get("fallback_desktop").checked))
+            // first list received or the captured window vanished, the first capturable, which is
+            // the whole desktop, is selected by default
+            this.send_server_config();
+        else
             // Can't find the window, so don't select anything
             this.capturable_select.value = "";
     }
 
+    // updates of the list between two CapturableLists, ids are never reused by the server so an
+    // update always refers to the entry it was meant for
+    onCapturableAdded(capturable: CapturableInfo) {
+        let selected = this.capturable_select.value;
+        this.capturable_select.appendChild(capturable_option(capturable));
+        // the browser selects the new option if nothing has been selected
+        this.capturable_select.value = selected;
+    }
+
+    onCapturableRemoved(id: string) {
+        let option = this.find_capturable_option(id);
+        if (!option)
+            return;
+        let selected = option.selected;
+        option.remove();
+        // the server sends CapturableLost if the capturable was being captured
+        if (selected)
+            this.capturable_select.value = "";
+    }
+
+    // renamed, moved or resized
+    onCapturableUpdated(capturable: CapturableInfo) {
+        let option = this.find_capturable_option(capturable.id);
+        if (!option)
+            return;
+        let updated = capturable_option(capturable);
+        updated.selected = option.selected;
+        option.replaceWith(updated);
+    }
+
+    find_capturable_option(id: string): HTMLOptionElement {
+        for (const option of Array.from(this.capturable_select.options))
+            if (option.value === id)
+                return option;
+        return null;
+    }
+
+    onAudioSources(sources: AudioSource[]) {
+        let previous = this.audio_source_select.value;
+        this.audio_source_select.innerText = "";
+        let default_option = document.createElement("option");
+        default_option.value = "";
+        default_option.innerText = "Default Output";
+        this.audio_source_select.appendChild(default_option);
+        let found = false;
+        for (const source of sources) {
+            let option = document.createElement("option");
+            option.value = source.id;
+            option.innerText = source.name;
+            this.audio_source_select.appendChild(option);
+            if (source.id === this.last_audio_source)
+                found = true;
+        }
+        // the source may be unplugged, the default one is used until it is back
+        this.audio_source_select.value = found ? this.last_audio_source : "";
+        if (this.audio_source_select.value != previous && this.checks.get("audio").checked)
+            this.send_server_config();
+    }
+
     toggle_energysaving(energysaving: boolean) {
         let canvas = fresh_canvas();
         if (energysaving) {
@@ -346,6 +746,39 @@ class Settings {
 
 let settings: Settings;
 
+// last position on the screen of every pointer, to work out how far it moved if the browser does
+// not report it, which some do for touches
+let last_pointer_positions = new Map<number, [number, number]>();
+
+// size of the frames of the video as told by VideoConfig, null until the server sent it
+let video_content_size: [number, number] = null;
+
+// size in CSS pixels the video is shown at to be as large as on the host, set if the server
+// applied ScaleMode::Physical
+let physical_video_size: [number, number] = null;
+
+// The part of rect, the bounding box of elem, that shows the content of the video. The video is
+// letterboxed if the aspect ratio of the element differs from the one of the content, like for
+// MJPEG images shown as poster. Mirrors ContentArea in src/protocol.rs.
+function content_rect(elem: HTMLElement, rect: DOMRect): DOMRect {
+    if (elem.id != "video" || video_content_size == null)
+        return rect;
+    let [width, height] = video_content_size;
+    // the layout size, transforms like the one of stretch_video scale the content along
+    let scale = Math.min(elem.clientWidth / width, elem.clientHeight / height);
+    if (!(scale > 0) || !isFinite(scale))
+        return rect;
+    // the side that fills the element is taken as is, so its edges are exact
+    let fx = elem.clientWidth / width <= scale ? 1 : width * scale / elem.clientWidth;
+    let fy = elem.clientHeight / height <= scale ? 1 : height * scale / elem.clientHeight;
+    return new DOMRect(
+        rect.left + rect.width * (1 - fx) / 2,
+        rect.top + rect.height * (1 - fy) / 2,
+        rect.width * fx,
+        rect.height * fy
+    );
+}
+
 class PEvent {
     event_type: string;
     pointer_id: number;
@@ -364,59 +797,58 @@ class PEvent {
     twist: number;
     width: number;
     height: number;
+    is_eraser: boolean;
 
     constructor(eventType: string, event: PointerEvent, targetRect: DOMRect) {
-        let diag_len = Math.sqrt(targetRect.width * targetRect.width + targetRect.height * targetRect.height)
         this.event_type = eventType.toString();
         this.pointer_id = event.pointerId;
         this.timestamp = Math.round(event.timeStamp * 1000);
         this.is_primary = event.isPrimary;
         this.pointer_type = event.pointerType;
-        let btn = event.button;
-        // for some reason the secondary and auxiliary buttons are ordered differently for
-        // the button and buttons properties
-        if (btn == 2)
-            btn = 1;
-        else if (btn == 1)
-            btn = 2;
-        this.button = (btn < 0 ? 0 : 1 << btn);
+        // the index of the button that changed, -1 if none did, the server presses buttons by
+        // the bitmask in buttons
+        this.button = event.button;
         this.buttons = event.buttons;
-        this.x = (event.clientX - targetRect.left) / targetRect.width;
-        this.y = (event.clientY - targetRect.top) / targetRect.height;
-        this.movement_x = event.movementX ? event.movementX : 0;
-        this.movement_y = event.movementY ? event.movementY : 0;
+        // relative to the content, positions on black bars end up on its edge
+        this.x = Math.min(Math.max((event.clientX - targetRect.left) / targetRect.width, 0), 1);
+        this.y = Math.min(Math.max((event.clientY - targetRect.top) / targetRect.height, 0), 1);
+        let last = last_pointer_positions.get(event.pointerId);
+        if (event.movementX || event.movementY || !last) {
+            this.movement_x = event.movementX ? Math.round(event.movementX) : 0;
+            this.movement_y = event.movementY ? Math.round(event.movementY) : 0;
+        } else {
+            this.movement_x = Math.round(event.screenX - last[0]);
+            this.movement_y = Math.round(event.screenY - last[1]);
+        }
+        if (eventType == "pointerup" || eventType == "pointercancel")
+            last_pointer_positions.delete(event.pointerId);
+        else
+            last_pointer_positions.set(event.pointerId, [event.screenX, event.screenY]);
         this.pressure = Math.max(event.pressure, settings.range_min_pressure.valueAsNumber);
         this.tilt_x = event.tiltX;
         this.tilt_y = event.tiltY;
-        this.width = event.width / diag_len;
-        this.height = event.height / diag_len;
+        // browsers report 1x1 if the size of the contact is unknown, 0x0 tells the server that
+        let size_known = event.width > 1 || event.height > 1;
+        this.width = size_known ? event.width / targetRect.width : 0;
+        this.height = size_known ? event.height / targetRect.height : 0;
         this.twist = event.twist;
+        // browsers report the eraser end of a stylus as button 5
+        this.is_eraser = event.pointerType == "pen" && (event.button == 5 || (event.buttons & 32) != 0);
     }
 }
 
 class WEvent {
     dx: number;
     dy: number;
+    delta_mode: number;
     timestamp: number;
 
     constructor(event: WheelEvent) {
-        /* The WheelEvent can have different scrolling modes that affect how much scrolling
-         * should be done. Unfortunately there is not always a way to accurately convert the scroll
-         * distance into pixels. Thus the following is a guesstimate and scales the WheelEvent's
-         * deltaX/Y values accordingly.
-         */
-        let scale = 1;
-        switch (event.deltaMode) {
-            case 0x01: // DOM_DELTA_LINE
-                scale = 10;
-                break;
-            case 0x02: // DOM_DELTA_PAGE
-                scale = 1000;
-                break;
-            default: // DOM_DELTA_PIXEL
-        }
-        this.dx = Math.round(scale * event.deltaX);
-        this.dy = Math.round(scale * event.deltaY);
+        // The WheelEvent can have different scrolling modes that affect how much scrolling
+        // should be done, converting the deltas accordingly is done server side.
+        this.dx = event.deltaX;
+        this.dy = event.deltaY;
+        this.delta_mode = event.deltaMode;
         this.timestamp = Math.round(event.timeStamp * 1000);
     }
 }
@@ -610,10 +1042,10 @@ class Painter {
 }
 
 class PointerHandler {
-    webSocket: WebSocket;
+    webSocket: Connection;
     pointerTypes: string[];
 
-    constructor(webSocket: WebSocket) {
+    constructor(webSocket: Connection) {
         let video = document.getElementById("video");
         let canvas = document.getElementById("canvas");
         this.webSocket = webSocket;
@@ -655,10 +1087,17 @@ class PointerHandler {
 
     onEvent(event: PointerEvent, event_type: string) {
         if (this.pointerTypes.includes(event.pointerType)) {
-            let rect = (event.target as HTMLElement).getBoundingClientRect();
+            let target = event.target as HTMLElement;
+            let rect = content_rect(target, target.getBoundingClientRect());
             const events = event_type === "pointermove" && typeof event.getCoalescedEvents === 'function' ? event.getCoalescedEvents() : [event];
+            // a lost movement is made up for by the next, pressing and lifting the pointer must
+            // neither get lost nor be reordered
+            let press = event_type === "pointerdown";
+            let send = event_type === "pointermove" ?
+                (data: string) => this.webSocket.send_lossy(data) :
+                (data: string) => this.webSocket.send_contact(data, event.pointerId, press);
             for (let event of events) {
-                this.webSocket.send(
+                send(
                     JSON.stringify(
                         {
                             "PointerEvent": new PEvent(
@@ -700,9 +1139,9 @@ class KEvent {
 }
 
 class KeyboardHandler {
-    webSocket: WebSocket;
+    webSocket: Connection;
 
-    constructor(webSocket: WebSocket) {
+    constructor(webSocket: Connection) {
         this.webSocket = webSocket;
 
         let d = document;
@@ -755,8 +1194,581 @@ function frame_rate_stats() {
     setTimeout(() => frame_rate_stats(), 1500);
 }
 
+class ClipboardHandler {
+    webSocket: Connection;
+    // last text seen on either side, this avoids sending text back where it came from
+    last_text: string;
+
+    constructor(webSocket: Connection) {
+        this.webSocket = webSocket;
+        this.last_text = null;
+        // browsers only allow reading the clipboard while the page has focus
+        window.addEventListener("focus", () => this.sendClipboard());
+        document.addEventListener("copy", () => setTimeout(() => this.sendClipboard(), 0));
+        document.addEventListener("cut", () => setTimeout(() => this.sendClipboard(), 0));
+    }
+
+    enabled() {
+        return settings.checks.get("clipboard_sync").checked && !!navigator.clipboard;
+    }
+
+    sendClipboard() {
+        if (!this.enabled() || !navigator.clipboard.readText)
+            return;
+        navigator.clipboard.readText().then((text) => {
+            if (text === this.last_text)
+                return;
+            this.last_text = text;
+            this.webSocket.send(JSON.stringify({ "ClipboardSet": text }));
+        }).catch((err) => log(LogLevel.DEBUG, "Failed to read clipboard: " + err));
+    }
+
+    onHostClipboard(text: string) {
+        if (!this.enabled())
+            return;
+        this.last_text = text;
+        navigator.clipboard.writeText(text).catch(
+            (err) => log(LogLevel.WARN, "Failed to write clipboard: " + err));
+    }
+}
+
+let clipboard: ClipboardHandler;
+
+interface Pong {
+    id: number;
+    client_ts: number;
+    server_ts: number;
+}
+
+// Tells network delay and delay of the video apart. Pings measure the round trip time and
+// relate the clock of the server to ours, with that the age of a video frame is known from the
+// time the server sent it at.
+class LatencyMeter {
+    webSocket: Connection;
+    output: HTMLOutputElement;
+    next_id: number;
+    rtt: number;
+    // server clock minus client clock, assuming the network is as fast in both directions
+    clock_offset: number;
+    frame_age: number;
+
+    constructor(webSocket: Connection) {
+        this.webSocket = webSocket;
+        this.output = document.getElementById("latency") as HTMLOutputElement;
+        this.next_id = 0;
+        this.rtt = null;
+        this.clock_offset = null;
+        this.frame_age = null;
+        setInterval(() => this.ping(), 2000);
+    }
+
+    ping() {
+        if (this.webSocket.readyState != WebSocket.OPEN)
+            return;
+        this.webSocket.send(JSON.stringify(
+            { "Ping": { "id": this.next_id++, "client_ts": Date.now() } }));
+    }
+
+    onPong(pong: Pong) {
+        this.rtt = Date.now() - pong.client_ts;
+        this.clock_offset = pong.server_ts - (pong.client_ts + this.rtt / 2);
+        this.show();
+    }
+
+    onFrame(server_ts: number) {
+        if (this.clock_offset != null)
+            this.frame_age = Date.now() + this.clock_offset - server_ts;
+    }
+
+    show() {
+        let text = "RTT " + Math.round(this.rtt) + " ms";
+        if (this.frame_age != null)
+            text += ", frame age " + Math.max(0, Math.round(this.frame_age)) + " ms";
+        this.output.value = text;
+    }
+}
+
+let latency: LatencyMeter;
+
+interface CursorShape {
+    id: number;
+    png_base64: string;
+    hotspot: [number, number];
+}
+
+interface CursorPosition {
+    x: number;
+    y: number;
+    visible: boolean;
+    shape_id: number;
+}
+
+// Draws the cursor of the host over the video, from the positions and shapes the server reports
+// instead of compositing it into the frames, so it moves as often as it is reported even if the
+// video lags behind.
+class RemoteCursor {
+    img: HTMLImageElement;
+    video: HTMLVideoElement;
+    // data URL and hotspot by shape id, in the order they arrived
+    shapes: Map<number, [string, [number, number]]>;
+    shape_id: number;
+
+    constructor(video: HTMLVideoElement) {
+        this.img = document.getElementById("remote_cursor") as HTMLImageElement;
+        this.video = video;
+        this.shapes = new Map();
+        this.shape_id = null;
+    }
+
+    onShape(shape: CursorShape) {
+        let url = "data:image/png;base64," + shape.png_base64;
+        this.forget(shape.id);
+        this.shapes.set(shape.id, [url, shape.hotspot]);
+        // the server resends shapes it thinks are gone, keeping more than it does is safe
+        while (this.shapes.size > 128)
+            this.forget(this.shapes.keys().next().value);
+    }
+
+    onPosition(pos: CursorPosition) {
+        let shape = this.shapes.get(pos.shape_id);
+        if (!pos.visible || shape == null || !settings.checks.get("report_cursor").checked) {
+            this.hide();
+            return;
+        }
+        let [url, [hot_x, hot_y]] = shape;
+        if (this.shape_id != pos.shape_id) {
+            this.img.src = url;
+            this.shape_id = pos.shape_id;
+        }
+        let rect = content_rect(this.video, this.video.getBoundingClientRect());
+        this.img.style.left = (rect.left + pos.x * rect.width - hot_x) + "px";
+        this.img.style.top = (rect.top + pos.y * rect.height - hot_y) + "px";
+        this.img.classList.remove("hide");
+    }
+
+    hide() {
+        this.img.classList.add("hide");
+    }
+
+    forget(id: number) {
+        if (!this.shapes.delete(id))
+            return;
+        if (this.shape_id == id)
+            this.shape_id = null;
+    }
+}
+
+let remote_cursor: RemoteCursor;
+
+interface AudioSource {
+    id: string;
+    name: string;
+}
+
+// Plays back the Opus packets of the host via WebCodecs. Packets are scheduled by the time they
+// have been captured at, which is what the MP4 stream is timestamped with as well, so audio is
+// held back as far as the video lags behind.
+class AudioPlayer {
+    video: HTMLVideoElement;
+    context: AudioContext;
+    decoder: any;
+    // time of the audio context minus capture time in seconds, null until the first packet
+    offset: number;
+    // the offset is only moved if it is off by more than this
+    static MAX_DRIFT_S = 0.1;
+    // played back this late if there is no video to follow, to ride out jitter of the network
+    static BUFFER_S = 0.05;
+
+    constructor(video: HTMLVideoElement) {
+        this.video = video;
+        this.context = null;
+        this.decoder = null;
+        this.offset = null;
+        // browsers only start playing audio once the user interacted with the page
+        document.addEventListener("pointerdown", () => {
+            if (this.context != null && this.context.state == "suspended")
+                this.context.resume();
+        });
+    }
+
+    set_enabled(enabled: boolean) {
+        if (enabled == (this.decoder != null))
+            return;
+        if (!enabled) {
+            this.decoder.close();
+            this.context.close();
+            this.decoder = null;
+            this.context = null;
+            return;
+        }
+        let AudioDecoder = (window as any).AudioDecoder;
+        if (!AudioDecoder) {
+            log(LogLevel.WARN, "This browser can not decode Opus, audio will not be played back.");
+            return;
+        }
+        this.context = new AudioContext({ latencyHint: "interactive", sampleRate: 48000 });
+        this.decoder = new AudioDecoder({
+            output: (data: any) => this.play(data),
+            error: (err: any) => log(LogLevel.WARN, "Failed to decode audio: " + err),
+        });
+        this.decoder.configure({ codec: "opus", sampleRate: 48000, numberOfChannels: 2 });
+        this.offset = null;
+    }
+
+    onPacket(captured_at_us: number, data: ArrayBuffer) {
+        if (this.decoder == null || this.decoder.state != "configured")
+            return;
+        let EncodedAudioChunk = (window as any).EncodedAudioChunk;
+        this.decoder.decode(new EncodedAudioChunk({ type: "key", timestamp: captured_at_us, data: data }));
+    }
+
+    play(data: any) {
+        let buffer = this.context.createBuffer(data.numberOfChannels, data.numberOfFrames, data.sampleRate);
+        for (let channel = 0; channel < data.numberOfChannels; channel++)
+            data.copyTo(buffer.getChannelData(channel), { planeIndex: channel, format: "f32-planar" });
+        let captured_at = data.timestamp / 1e6;
+        data.close();
+        let now = this.context.currentTime;
+        let target: number;
+        if (this.video.src && !this.video.paused && this.video.readyState >= 2)
+            // the frame captured at the same time as the first sample is on screen
+            // captured_at - video.currentTime from now, minus the time the sound takes to come out
+            target = now - this.video.currentTime - (this.context.outputLatency || 0);
+        else
+            target = now - captured_at + AudioPlayer.BUFFER_S;
+        if (this.offset == null || Math.abs(this.offset - target) > AudioPlayer.MAX_DRIFT_S)
+            this.offset = target;
+        let at = captured_at + this.offset;
+        // too late to be in step with the video
+        if (at < now)
+            return;
+        let source = this.context.createBufferSource();
+        source.buffer = buffer;
+        source.connect(this.context.destination);
+        source.start(at);
+    }
+}
+
+let audio: AudioPlayer;
+
+interface VideoStats {
+    frames: number;
+    fps: number;
+    capture_ms: number;
+    encode_ms: number;
+    frame_bytes: number;
+    dropped_frames: number;
+    lost_frames: number;
+    congestion_skipped_frames: number;
+    target_bitrate_kbps?: number;
+    coalesced_pointer_events: number;
+    encoder?: string;
+}
+
+function show_server_stats(stats: VideoStats) {
+    document.getElementById("server_stats_output").textContent =
+        "Frames: " + stats.frames + " (" + stats.fps.toFixed(1) + " fps)\n" +
+        (stats.encoder != null ? "Encoder: " + stats.encoder + "\n" : "") +
+        "Capture: " + stats.capture_ms.toFixed(1) + " ms\n" +
+        "Encode: " + stats.encode_ms.toFixed(1) + " ms\n" +
+        "Frame size: " + (stats.frame_bytes / 1024).toFixed(1) + " KiB\n" +
+        "Dropped: " + stats.dropped_frames + "\n" +
+        "Lost (slow encoding): " + stats.lost_frames + "\n" +
+        "Skipped (slow connection): " + stats.congestion_skipped_frames + "\n" +
+        "Coalesced pointer moves: " + stats.coalesced_pointer_events +
+        (stats.target_bitrate_kbps != null ? "\nBitrate: " + stats.target_bitrate_kbps + " kbps" : "");
+}
+
+function show_host_input_enabled(enabled: boolean) {
+    // the host drops all input while it is turned off, grey out the screen to make that obvious
+    document.getElementById("main").classList.toggle("host_input_disabled", !enabled);
+    if (enabled)
+        log(LogLevel.INFO, "Input has been turned on again on the host.");
+    else
+        log(LogLevel.WARN, "Input has been turned off on the host.");
+}
+
+// errors sent by the server: a kind to react on and a message for the user
+interface ClientError {
+    kind: string;
+    message: string;
+    path?: string;
+    errno?: number;
+    groups?: string[];
+    device_group?: string;
+    hint?: string;
+    udev_rule?: string;
+}
+
+function describe_error(err: ClientError): string {
+    if (err.kind == "UInputPermissionDenied") {
+        let description = err.message + " " + err.hint;
+        if (err.groups)
+            description += " Weylus runs with the groups: " + err.groups.join(", ") + ".";
+        if (err.errno != 2)
+            description += " The udev rule to make " + err.path + " writable is: " + err.udev_rule;
+        return description + " Alternatively, uncheck \"Enable uinput\" to only simulate a mouse.";
+    }
+    return err.message;
+}
+
+// The websocket to the server. If the connection drops once the client is configured, a new one
+// is opened and the session resumed on the server, which keeps the configuration and the video
+// encoder around for a while. Messages sent while reconnecting are dropped.
+//
+// If the server allows it, messages that may as well get lost are sent over a WebRTC data channel
+// that neither retransmits nor orders them, so they are not held up by lost packets. The server
+// drops those that have been overtaken. Pressing and lifting pointers goes over a second, ordered
+// and reliable data channel, which is not held up by the video like the websocket. The WebRTC
+// connection is signaled via the websocket and set up again with each new websocket.
+class Connection {
+    url: string;
+    ws: WebSocket;
+    rtc: RTCPeerConnection;
+    // set once they are open
+    rtc_channel: RTCDataChannel;
+    rtc_contact_channel: RTCDataChannel;
+    // pointers that have been pressed via rtc_contact_channel, they are lifted the same way
+    rtc_contacts: Set<number>;
+    // the user wants WebRTC and the server offers it on the current websocket
+    rtc_enabled: boolean;
+    server_rtc: boolean;
+    // received once the server has been configured, null if there is nothing to resume
+    session_id: string;
+    // called once connected for the first time and once the session could not be resumed
+    onopen: () => void;
+    onmessage: (event: MessageEvent) => void;
+    // called once the connection is lost for good
+    onclose: (msg: string) => void;
+    closed: boolean;
+    // last time anything has been heard from the server
+    last_seen: number;
+    // pings are sent every 2s, so the connection is dead if nothing arrives for much longer
+    static STALL_TIMEOUT_MS = 8000;
+    static RECONNECT_DELAY_MS = 1000;
+    static MAX_RECONNECT_ATTEMPTS = 30;
+    reconnect_attempts: number;
+
+    constructor(url: string) {
+        this.url = url;
+        this.session_id = null;
+        this.closed = false;
+        this.reconnect_attempts = 0;
+        this.rtc = null;
+        this.rtc_channel = null;
+        this.rtc_contact_channel = null;
+        this.rtc_contacts = new Set();
+        this.rtc_enabled = false;
+        this.server_rtc = false;
+        this.connect();
+        setInterval(() => {
+            if (this.ws.readyState == WebSocket.OPEN
+                && Date.now() - this.last_seen > Connection.STALL_TIMEOUT_MS) {
+                log(LogLevel.WARN, "Server stopped responding.");
+                this.lost("Lost connection.");
+            }
+        }, 1000);
+    }
+
+    connect() {
+        let ws = new WebSocket(this.url);
+        ws.binaryType = "arraybuffer";
+        ws.onopen = () => {
+            this.last_seen = Date.now();
+            if (this.session_id == null)
+                this.onopen();
+            else
+                ws.send(JSON.stringify({ "Resume": { "session_id": this.session_id } }));
+        };
+        ws.onmessage = (event: MessageEvent) => {
+            this.last_seen = Date.now();
+            this.onmessage(event);
+        };
+        ws.onerror = () => this.lost("Lost connection.");
+        ws.onclose = () => this.lost("Connection closed.");
+        this.last_seen = Date.now();
+        this.ws = ws;
+    }
+
+    lost(msg: string) {
+        let ws = this.ws;
+        ws.onopen = ws.onmessage = ws.onerror = ws.onclose = null;
+        ws.close();
+        // the server closes its end along with the websocket
+        this.server_rtc = false;
+        this.stop_rtc();
+        if (this.closed)
+            return;
+        if (this.session_id == null || this.reconnect_attempts >= Connection.MAX_RECONNECT_ATTEMPTS) {
+            this.closed = true;
+            this.onclose(msg);
+            return;
+        }
+        this.reconnect_attempts += 1;
+        log(LogLevel.INFO, msg + " Reconnecting...");
+        // keep this.ws closed until the new connection is attempted, so nothing is sent on it
+        setTimeout(() => this.connect(), Connection.RECONNECT_DELAY_MS);
+    }
+
+    onSession(session_id: string) {
+        this.session_id = session_id;
+    }
+
+    onSessionResumed() {
+        log(LogLevel.INFO, "Reconnected.");
+        this.reconnect_attempts = 0;
+    }
+
+    onSessionExpired() {
+        log(LogLevel.INFO, "Reconnected, the session expired though, starting over.");
+        this.session_id = null;
+        this.reconnect_attempts = 0;
+        this.onopen();
+    }
+
+    // the server is going away for good, reconnecting would only fail
+    onServerShutdown() {
+        this.session_id = null;
+        this.lost("Weylus has been shut down.");
+    }
+
+    onServerCapabilities(capabilities: Object) {
+        this.server_rtc = capabilities["webrtc"];
+        this.update_rtc();
+    }
+
+    set_rtc_enabled(enabled: boolean) {
+        this.rtc_enabled = enabled;
+        this.update_rtc();
+    }
+
+    update_rtc() {
+        if (!this.rtc_enabled || !this.server_rtc || typeof RTCPeerConnection === "undefined")
+            this.stop_rtc();
+        else if (this.rtc == null)
+            this.start_rtc();
+    }
+
+    start_rtc() {
+        let rtc = new RTCPeerConnection();
+        let channel = rtc.createDataChannel("input", { ordered: false, maxRetransmits: 0 });
+        channel.onopen = () => {
+            if (this.rtc != rtc)
+                return;
+            log(LogLevel.INFO, "Sending input via WebRTC.");
+            this.rtc_channel = channel;
+        };
+        channel.onclose = () => {
+            if (this.rtc_channel == channel)
+                this.rtc_channel = null;
+        };
+        let contact_channel = rtc.createDataChannel("contact");
+        contact_channel.onopen = () => {
+            if (this.rtc == rtc)
+                this.rtc_contact_channel = contact_channel;
+        };
+        contact_channel.onclose = () => {
+            if (this.rtc_contact_channel == contact_channel)
+                this.rtc_contact_channel = null;
+        };
+        rtc.onicecandidate = (event) => {
+            // the last one is empty, the server does not need to be told
+            if (this.rtc == rtc && event.candidate && event.candidate.candidate)
+                this.send(JSON.stringify({ "RtcCandidate": event.candidate.toJSON() }));
+        };
+        rtc.onconnectionstatechange = () => {
+            if (this.rtc == rtc && rtc.connectionState == "failed")
+                this.rtc_failed("WebRTC connection failed.");
+        };
+        this.rtc = rtc;
+        rtc.createOffer()
+            .then((offer) => rtc.setLocalDescription(offer))
+            .then(() => {
+                if (this.rtc == rtc)
+                    this.send(JSON.stringify({ "RtcOffer": { "sdp": rtc.localDescription.sdp } }));
+            })
+            .catch((err) => {
+                if (this.rtc == rtc)
+                    this.rtc_failed("Failed to set up WebRTC: " + err);
+            });
+    }
+
+    onRtcAnswer(sdp: string) {
+        if (this.rtc == null)
+            return;
+        let rtc = this.rtc;
+        rtc.setRemoteDescription({ "type": "answer", "sdp": sdp }).catch((err) => {
+            if (this.rtc == rtc)
+                this.rtc_failed("Failed to set up WebRTC: " + err);
+        });
+    }
+
+    onRtcCandidate(candidate: RTCIceCandidateInit) {
+        if (this.rtc == null)
+            return;
+        this.rtc.addIceCandidate(candidate).catch(
+            (err) => log(LogLevel.DEBUG, "Failed to add ICE candidate of the server: " + err));
+    }
+
+    // keeps using the websocket until a new websocket is opened
+    rtc_failed(msg: string) {
+        log(LogLevel.WARN, msg + " Sending input via the websocket.");
+        this.server_rtc = false;
+        this.stop_rtc();
+    }
+
+    stop_rtc() {
+        if (this.rtc == null)
+            return;
+        this.rtc.close();
+        this.rtc = null;
+        this.rtc_channel = null;
+        this.rtc_contact_channel = null;
+        this.rtc_contacts.clear();
+    }
+
+    get readyState(): number {
+        return this.ws.readyState;
+    }
+
+    send(data: string) {
+        if (this.ws.readyState == WebSocket.OPEN)
+            this.ws.send(data);
+    }
+
+    // for messages that are of no use if they arrive late, they may get lost or arrive out of order
+    send_lossy(data: string) {
+        if (this.rtc_channel != null && this.rtc_channel.readyState == "open")
+            this.rtc_channel.send(data);
+        else
+            this.send(data);
+    }
+
+    // for pressing and lifting pointers, which must neither get lost nor be reordered, a pointer
+    // is lifted the same way it has been pressed, so the lift can not overtake the press
+    send_contact(data: string, pointer_id: number, press: boolean) {
+        let channel = this.rtc_contact_channel;
+        let open = channel != null && channel.readyState == "open";
+        let via_rtc = open && (press || this.rtc_contacts.has(pointer_id));
+        if (press && via_rtc)
+            this.rtc_contacts.add(pointer_id);
+        else
+            this.rtc_contacts.delete(pointer_id);
+        if (via_rtc)
+            channel.send(data);
+        else
+            this.send(data);
+    }
+
+    close() {
+        this.closed = true;
+        this.stop_rtc();
+        this.ws.close();
+    }
+}
+
 function handle_messages(
-    webSocket: WebSocket,
+    webSocket: Connection,
     video: HTMLVideoElement,
     onConfigOk: Function,
     onConfigError: Function,
@@ -765,6 +1777,80 @@ function handle_messages(
     let mediaSource: MediaSource = null;
     let sourceBuffer: SourceBuffer = null;
     let queue = [];
+    let jpeg_url: string = null;
+    // set by the server once it knows which codec the video is encoded with
+    let mime_type: string = null;
+    // the server fell back to MJPEG as none of the codecs could be encoded
+    let server_mjpeg = false;
+    // frames of VideoFormat::Tiles are drawn onto this canvas, the video element plays back its
+    // stream so it keeps handling input and sizing; null unless the server sends tiles
+    let tiles_canvas: HTMLCanvasElement = null;
+    // frames are decoded in parallel but drawn one after the other
+    let tiles_drawn: Promise<void> = Promise.resolve();
+    function show_tiles(width: number, height: number) {
+        if (mediaSource != null) {
+            mediaSource = null;
+            sourceBuffer = null;
+            queue = [];
+            video.removeAttribute("src");
+        }
+        if (jpeg_url != null) {
+            video.removeAttribute("poster");
+            URL.revokeObjectURL(jpeg_url);
+            jpeg_url = null;
+        }
+        if (tiles_canvas == null) {
+            tiles_canvas = document.createElement("canvas");
+            tiles_canvas.width = width;
+            tiles_canvas.height = height;
+            video.srcObject = tiles_canvas.captureStream();
+            return;
+        }
+        // tiles of frames before the resize are still on their way, the server sends all tiles
+        // again for the new size
+        let canvas = tiles_canvas;
+        tiles_drawn = tiles_drawn.then(() => {
+            canvas.width = width;
+            canvas.height = height;
+        });
+    }
+    function stop_tiles() {
+        if (tiles_canvas == null)
+            return;
+        tiles_canvas = null;
+        video.srcObject = null;
+    }
+    // each tile is its position and size as u16 and the length of the PNG image as u32, all
+    // little-endian, followed by the image, see TileEncoder in src/tiles.rs
+    function draw_tiles(data: ArrayBuffer) {
+        let view = new DataView(data);
+        let positions: [number, number][] = [];
+        let bitmaps: Promise<ImageBitmap>[] = [];
+        for (let offset = 0; offset + 12 <= data.byteLength;) {
+            let len = view.getUint32(offset + 8, true);
+            positions.push([view.getUint16(offset, true), view.getUint16(offset + 2, true)]);
+            let png = new Blob([data.slice(offset + 12, offset + 12 + len)], { type: "image/png" });
+            bitmaps.push(createImageBitmap(png));
+            offset += 12 + len;
+        }
+        let canvas = tiles_canvas;
+        tiles_drawn = tiles_drawn
+            .then(() => Promise.all(bitmaps))
+            .then((bitmaps) => {
+                let ctx = canvas.getContext("2d");
+                bitmaps.forEach((bitmap, i) => {
+                    ctx.drawImage(bitmap, positions[i][0], positions[i][1]);
+                    bitmap.close();
+                });
+            })
+            .catch((err) => {
+                log(LogLevel.WARN, "Failed to draw tiles: " + err);
+                // the picture is incomplete now, start over with all tiles
+                webSocket.send('"RequestKeyFrame"');
+            });
+    }
+    // capture time of the audio packet the next binary message carries, null for video
+    let pending_audio: number = null;
     const MAX_BUFFER_LENGTH = 20;  // In seconds
     function upd_buf() {
         if (sourceBuffer == null)
@@ -787,10 +1873,23 @@ function handle_messages(
                     if (sourceBuffer.updating)
                         sourceBuffer.abort();
                     sourceBuffer.remove(0, Infinity);
+                    queue = [];
+                    webSocket.send('"RequestKeyFrame"');
                 }
             }
         }
     }
+    // the source buffer needs both, the media source to be open and the MIME type from the server
+    function open_source_buffer() {
+        if (mediaSource == null || mediaSource.readyState != "open" || mime_type == null || sourceBuffer != null)
+            return;
+        let MS = window.ManagedMediaSource ? window.ManagedMediaSource : window.MediaSource;
+        sourceBuffer = mediaSource.addSourceBuffer(MS.isTypeSupported(mime_type) ? mime_type : "video/mp4");
+        sourceBuffer.addEventListener("updateend", upd_buf);
+        // try to recover from errors by restarting the video
+        sourceBuffer.onerror = () => settings.send_server_config();
+        upd_buf();
+    }
     webSocket.onmessage = (event: MessageEvent) => {
         if (typeof event.data == "string") {
             let msg = JSON.parse(event.data);
@@ -799,35 +1898,142 @@ function handle_messages(
                     let MS = window.ManagedMediaSource ? window.ManagedMediaSource : window.MediaSource;
                     mediaSource = new MS();
                     sourceBuffer = null;
+                    mime_type = null;
+                    server_mjpeg = false;
+                    stop_tiles();
                     video.src = URL.createObjectURL(mediaSource);
-                    mediaSource.addEventListener("sourceopen", (_) => {
-                        let mimeType = 'video/mp4; codecs="avc1.4D403D"';
-                        if (!MS.isTypeSupported(mimeType))
-                            mimeType = "video/mp4";
-                        sourceBuffer = mediaSource.addSourceBuffer(mimeType);
-                        sourceBuffer.addEventListener("updateend", upd_buf);
-                        // try to recover from errors by restarting the video
-                        if (sourceBuffer.onerror)
-                            sourceBuffer.onerror = () => settings.send_server_config();
-                    })
+                    mediaSource.addEventListener("sourceopen", (_) => open_source_buffer());
                 } else if (msg == "ConfigOk") {
                     onConfigOk();
+                } else if (msg == "SessionResumed") {
+                    webSocket.onSessionResumed();
+                } else if (msg == "ServerShutdown") {
+                    log(LogLevel.INFO, "Weylus has been shut down.");
+                    webSocket.onServerShutdown();
+                } else if (msg == "CapturableLost") {
+                    log(LogLevel.WARN, "The captured window has been closed.");
+                    settings.capturable_lost = true;
+                    webSocket.send('"GetCapturableList"');
                 }
             } else if (typeof msg == "object") {
                 if ("CapturableList" in msg)
                     onCapturableList(msg["CapturableList"]);
+                else if ("CapturableAdded" in msg)
+                    settings.onCapturableAdded(msg["CapturableAdded"]);
+                else if ("CapturableRemoved" in msg)
+                    settings.onCapturableRemoved(msg["CapturableRemoved"].id);
+                else if ("CapturableUpdated" in msg)
+                    settings.onCapturableUpdated(msg["CapturableUpdated"]);
+                else if ("AudioPacket" in msg)
+                    pending_audio = msg["AudioPacket"]["captured_at_us"];
+                else if ("AudioSources" in msg)
+                    settings.onAudioSources(msg["AudioSources"]);
+                else if ("VideoConfig" in msg) {
+                    let config = msg["VideoConfig"];
+                    video_content_size = [config.width, config.height];
+                    if (config.format == "Tiles") {
+                        show_tiles(config.width, config.height);
+                    } else if (config.format == "Mjpeg") {
+                        stop_tiles();
+                        if (!settings.checks.get("mjpeg").checked)
+                            log(LogLevel.WARN, "The server can not encode any of the video codecs supported by this browser, falling back to MJPEG.");
+                        server_mjpeg = true;
+                    } else {
+                        console.info("Video codec: " + config.mime_type + " encoded by " + config.encoder);
+                        mime_type = config.mime_type;
+                        open_source_buffer();
+                    }
+                }
+                else if ("Error" in msg && msg["Error"].kind == "SessionExpired") {
+                    webSocket.onSessionExpired();
+                    settings.send_server_config();
+                } else if ("Error" in msg && msg["Error"].kind == "RtcUnavailable")
+                    webSocket.rtc_failed(msg["Error"].message);
+                else if ("Error" in msg && msg["Error"].kind == "AudioFailed")
+                    // the video goes on, so do not interrupt the user
+                    log(LogLevel.WARN, msg["Error"].message);
                 else if ("Error" in msg)
-                    alert(msg["Error"]);
+                    alert(describe_error(msg["Error"]));
                 else if ("ConfigError" in msg) {
                     onConfigError(msg["ConfigError"]);
                 }
+                else if ("ScaleApplied" in msg) {
+                    let applied = msg["ScaleApplied"];
+                    if (applied.fallback)
+                        log(LogLevel.WARN, applied.fallback);
+                    // "Fit" is sent as plain string
+                    let physical = applied.scale_mode.Physical;
+                    let mm_per_px = settings.mm_per_px();
+                    physical_video_size = physical && mm_per_px ?
+                        [physical.width_mm / mm_per_px, physical.height_mm / mm_per_px] : null;
+                    stretch_video();
+                }
+                else if ("CaptureMethod" in msg)
+                    console.info("Capturing via " + msg["CaptureMethod"]);
+                else if ("Stats" in msg)
+                    show_server_stats(msg["Stats"]);
+                else if ("ClipboardContent" in msg)
+                    clipboard.onHostClipboard(msg["ClipboardContent"]);
+                else if ("Pong" in msg)
+                    latency.onPong(msg["Pong"]);
+                else if ("CursorShape" in msg)
+                    remote_cursor.onShape(msg["CursorShape"]);
+                else if ("CursorPosition" in msg)
+                    remote_cursor.onPosition(msg["CursorPosition"]);
+                else if ("HostInputEnabled" in msg)
+                    show_host_input_enabled(msg["HostInputEnabled"]);
+                else if ("Session" in msg)
+                    webSocket.onSession(msg["Session"]["session_id"]);
+                else if ("ServerCapabilities" in msg)
+                    webSocket.onServerCapabilities(msg["ServerCapabilities"]);
+                else if ("RtcAnswer" in msg)
+                    webSocket.onRtcAnswer(msg["RtcAnswer"]["sdp"]);
+                else if ("RtcCandidate" in msg)
+                    webSocket.onRtcCandidate(msg["RtcCandidate"]);
             }
 
             return;
         }
 
-        // not a string -> got a video frame
-        queue.push(event.data);
+        // not a string -> got a video frame or audio, prefixed with the time the server sent it at
+        if (pending_audio != null) {
+            audio.onPacket(pending_audio, (event.data as ArrayBuffer).slice(8));
+            pending_audio = null;
+            return;
+        }
+        latency.onFrame(new DataView(event.data).getFloat64(0, true));
+        let data = (event.data as ArrayBuffer).slice(8);
+        if (tiles_canvas != null) {
+            draw_tiles(data);
+            frame_count += 1;
+            return;
+        }
+        if (server_mjpeg || settings.checks.get("mjpeg").checked) {
+            // The JPEG frames are shown as poster of the video element, this way the video element
+            // keeps handling input and sizing.
+            if (mediaSource != null) {
+                mediaSource = null;
+                sourceBuffer = null;
+                queue = [];
+                video.removeAttribute("src");
+                video.load();
+            }
+            let old_url = jpeg_url;
+            jpeg_url = URL.createObjectURL(new Blob([data], { type: "image/jpeg" }));
+            video.poster = jpeg_url;
+            if (old_url != null)
+                URL.revokeObjectURL(old_url);
+            // the size of the poster is only known once the previous one has been shown
+            stretch_video();
+            frame_count += 1;
+            return;
+        }
+        if (jpeg_url != null) {
+            video.removeAttribute("poster");
+            URL.revokeObjectURL(jpeg_url);
+            jpeg_url = null;
+        }
+        queue.push(data);
         upd_buf();
         frame_count += 1;
 
@@ -851,7 +2057,7 @@ function check_apis() {
     let apis = [
         {
             attrs: ["MediaSource", "ManagedMediaSource"],
-            msg: "This browser doesn't support MSE/MMS required to playback video stream, try upgrading!"
+            msg: "This browser doesn't support MSE/MMS required to playback video stream, falling back to MJPEG!"
         },
         {
             attrs: ["PointerEvent"],
@@ -874,15 +2080,19 @@ function init() {
     check_apis();
 
     let protocol = document.location.protocol == "https:" ? "wss://" : "ws://";
-    let webSocket = new WebSocket(
+    let webSocket = new Connection(
         protocol + window.location.hostname + ":" +
         window.location.port + "/ws" + window.location.search
     );
-    webSocket.binaryType = "arraybuffer";
 
     settings = new Settings(webSocket);
+    clipboard = new ClipboardHandler(webSocket);
+    latency = new LatencyMeter(webSocket);
 
     let video = document.getElementById("video") as HTMLVideoElement;
+    audio = new AudioPlayer(video);
+    remote_cursor = new RemoteCursor(video);
+    audio.set_enabled(settings.checks.get("audio").checked);
     let canvas = document.getElementById("canvas") as HTMLCanvasElement;
 
     video.oncontextmenu = function(event) {
@@ -914,8 +2124,7 @@ function init() {
                 location.reload();
         }
     }
-    webSocket.onerror = () => handle_disconnect("Lost connection.");
-    webSocket.onclose = () => handle_disconnect("Connection closed.");
+    webSocket.onclose = handle_disconnect;
     window.onresize = () => {
         stretch_video();
         canvas.width = window.innerWidth * window.devicePixelRatio;
@@ -934,17 +2143,18 @@ function init() {
             is_connected = true;
         }
     },
-        (err) => alert(err),
-        (window_names) => settings.onCapturableList(window_names)
+        (err: ClientError) => alert(describe_error(err)),
+        (capturables) => settings.onCapturableList(capturables)
     );
     window.onunload = () => { webSocket.close(); }
-    webSocket.onopen = function(event) {
+    webSocket.onopen = () => {
+        // sources first, so the one chosen last time is known once the capturables arrive and
+        // the client is configured
+        webSocket.send('"GetAudioSources"');
         webSocket.send('"GetCapturableList"');
         if (!settings.video_enabled())
             webSocket.send('"PauseVideo"');
 
-        settings.send_server_config();
-
         document.onvisibilitychange = () => {
             if (document.hidden) {
                 webSocket.send('"PauseVideo"');
@@ -960,7 +2170,10 @@ function init() {
 // workaround
 function stretch_video() {
     let video = document.getElementById("video") as HTMLVideoElement;
-    if (settings.stretched_video()) {
+    if (physical_video_size) {
+        let [width, height] = physical_video_size;
+        video.style.transform = "scaleX(" + width / video.clientWidth + ") scaleY(" + height / video.clientHeight + ")";
+    } else if (settings.stretched_video()) {
         video.style.transform = "scaleX(" + document.body.clientWidth / video.clientWidth + ") scaleY(" + document.body.clientHeight / video.clientHeight + ")";
     } else {
         let scale = Math.min(document.body.clientWidth / video.clientWidth, document.body.clientHeight / video.clientHeight);